@@ -15,9 +15,12 @@ use std::os::fd::AsFd;
 use std::os::fd::BorrowedFd;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::str::FromStr;
 
 use argh::FromArgs;
+use cros_codecs::backend::vpp::Vpp;
+use cros_codecs::backend::vpp::VppStage;
 use cros_codecs::codec::h264::parser::Nalu as H264Nalu;
 use cros_codecs::codec::h265::parser::Nalu as H265Nalu;
 use cros_codecs::decoder::stateless::av1::Av1;
@@ -31,9 +34,11 @@ use cros_codecs::decoder::BlockingMode;
 use cros_codecs::decoder::DecodedHandle;
 use cros_codecs::decoder::StreamInfo;
 use cros_codecs::multiple_desc_type;
+use cros_codecs::utils::checksum::ChecksumAlgorithm;
 use cros_codecs::utils::simple_playback_loop;
 use cros_codecs::utils::simple_playback_loop_owned_frames;
 use cros_codecs::utils::simple_playback_loop_userptr_frames;
+use cros_codecs::utils::Av1TemporalUnitIterator;
 use cros_codecs::utils::DmabufFrame;
 use cros_codecs::utils::IvfIterator;
 use cros_codecs::utils::NalIterator;
@@ -42,6 +47,8 @@ use cros_codecs::DecodedFormat;
 use cros_codecs::Fourcc;
 use cros_codecs::FrameLayout;
 use cros_codecs::PlaneLayout;
+use cros_codecs::Point;
+use cros_codecs::Rect;
 use cros_codecs::Resolution;
 use matroska_demuxer::Frame;
 use matroska_demuxer::MatroskaFile;
@@ -64,11 +71,20 @@ fn export_gbm_bo<T>(obj: &gbm::BufferObject<T>) -> anyhow::Result<DmabufFrame> {
     let fd = obj.fd()?;
     let modifier = obj.modifier()?;
     let format = obj.format()?;
+    let height = obj.height()? as usize;
     let planes = (0..obj.plane_count()? as i32)
-        .map(|i| PlaneLayout {
-            buffer_index: 0,
-            offset: obj.offset(i).unwrap() as usize,
-            stride: obj.stride_for_plane(i).unwrap() as usize,
+        .map(|i| {
+            let stride = obj.stride_for_plane(i).unwrap() as usize;
+            // We only export NV12 buffers, for which planes other than the first one are
+            // vertically subsampled.
+            let num_lines = if i == 0 { height } else { height.div_ceil(2) };
+
+            PlaneLayout {
+                buffer_index: 0,
+                offset: obj.offset(i).unwrap() as usize,
+                stride,
+                size: stride * num_lines,
+            }
         })
         .collect();
     let size = Resolution::from((obj.width().unwrap(), obj.height().unwrap()));
@@ -209,6 +225,53 @@ impl FromStr for Md5Computation {
     }
 }
 
+/// Resolution given on the command line, as `<width>x<height>`.
+#[derive(Debug, Clone, Copy)]
+struct ResolutionArg(Resolution);
+
+impl FromStr for ResolutionArg {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            .ok_or("unrecognized resolution. Expected format: <width>x<height>")?;
+
+        Ok(ResolutionArg(Resolution { width, height }))
+    }
+}
+
+/// Rectangle given on the command line, as `<width>x<height>+<x>+<y>`.
+#[derive(Debug, Clone, Copy)]
+struct RectArg(Rect<u32>);
+
+impl FromStr for RectArg {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str = "unrecognized rectangle. Expected format: <width>x<height>+<x>+<y>";
+
+        let mut parts = s.split('+');
+        let ResolutionArg(size) = parts.next().ok_or(ERROR)?.parse().map_err(|_| ERROR)?;
+        let (x, y) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(x), Some(y), None) => (
+                x.parse::<u32>().map_err(|_| ERROR)?,
+                y.parse::<u32>().map_err(|_| ERROR)?,
+            ),
+            _ => return Err(ERROR),
+        };
+
+        Ok(RectArg(Rect {
+            min: Point { x, y },
+            max: Point {
+                x: x + size.width,
+                y: y + size.height,
+            },
+        }))
+    }
+}
+
 /// Simple player using cros-codecs
 #[derive(Debug, FromArgs)]
 struct Args {
@@ -232,6 +295,19 @@ struct Args {
     #[argh(option, default = "DecodedFormat::I420")]
     output_format: DecodedFormat,
 
+    /// pixel format the decoder outputs, converted into output-format using video
+    /// post-processing if different. Default: same as output-format
+    #[argh(option)]
+    decode_format: Option<DecodedFormat>,
+
+    /// part of the frames to keep, as <width>x<height>+<x>+<y>, using video post-processing
+    #[argh(option)]
+    crop: Option<RectArg>,
+
+    /// resolution to scale the frames to, as <width>x<height>, using video post-processing
+    #[argh(option)]
+    scale: Option<ResolutionArg>,
+
     /// origin of the memory for decoded buffers (managed, prime or user). Default: managed.
     #[argh(option, default = "FrameMemoryType::Managed")]
     frame_memory: FrameMemoryType,
@@ -248,6 +324,11 @@ struct Args {
     /// frame)
     #[argh(option)]
     compute_md5: Option<Md5Computation>,
+
+    /// file to write the CRC32 of each frame to, one per line, as expected by the decoder tests.
+    /// The tests decode into nv12, so this should be used with --output-format nv12
+    #[argh(option)]
+    gen_crcs: Option<PathBuf>,
 }
 
 /// Detects the container type (IVF or MKV) and returns the corresponding frame iterator.
@@ -259,6 +340,16 @@ fn create_vpx_frame_iterator(input: &[u8]) -> Box<dyn Iterator<Item = Cow<[u8]>>
     }
 }
 
+/// Detects the container type (IVF, MKV or none) of an AV1 stream and returns the corresponding
+/// frame iterator. Raw OBU streams can be either in low-overhead or Annex B format.
+fn create_av1_frame_iterator(input: &[u8]) -> Box<dyn Iterator<Item = Cow<[u8]>> + '_> {
+    if input.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) || input.starts_with(b"DKIF") {
+        create_vpx_frame_iterator(input)
+    } else {
+        Box::new(Av1TemporalUnitIterator::new(input).map(Cow::Borrowed))
+    }
+}
+
 /// Decide the output file name when multiple_output_files is set
 fn decide_output_file_name<'a>(output: &'a Path, index: i32) -> PathBuf {
     let extract_str = |s: Option<&'a OsStr>| s.and_then(|s| s.to_str()).expect("malformed file");
@@ -275,6 +366,14 @@ fn decide_output_file_name<'a>(output: &'a Path, index: i32) -> PathBuf {
     }
 }
 
+/// Waits for `handle` to be ready and returns its content.
+fn read_frame<D>(handle: &dyn DecodedHandle<Descriptor = D>) -> Vec<u8> {
+    handle.sync().unwrap();
+    let picture = handle.dyn_picture();
+    let frame_data = picture.map().unwrap().to_vec().unwrap();
+    frame_data
+}
+
 fn main() {
     env_logger::init();
 
@@ -339,6 +438,28 @@ fn main() {
     };
 
     let display = libva::Display::open().expect("failed to open libva display");
+
+    // Post-process the decoded frames if the decoder does not produce them as requested.
+    let decode_format = args.decode_format.unwrap_or(args.output_format);
+    let mut vpp_stages = Vec::new();
+    if decode_format != args.output_format {
+        vpp_stages.push(VppStage::ColorSpaceConversion(args.output_format));
+    }
+    if let Some(RectArg(rect)) = args.crop {
+        vpp_stages.push(VppStage::Crop(rect));
+    }
+    if let Some(ResolutionArg(resolution)) = args.scale {
+        vpp_stages.push(VppStage::Scale(resolution));
+    }
+    let mut vpp = if vpp_stages.is_empty() {
+        None
+    } else {
+        Some(
+            Vpp::new(Rc::clone(&display), vpp_stages)
+                .expect("failed to create the video post-processing pipeline"),
+        )
+    };
+
     let (mut decoder, frame_iter) = match args.input_format {
         EncodedFormat::H264 => {
             let frame_iter = Box::new(NalIterator::<H264Nalu>::new(&input).map(Cow::Borrowed))
@@ -383,7 +504,7 @@ fn main() {
             (decoder, frame_iter)
         }
         EncodedFormat::AV1 => {
-            let frame_iter = create_vpx_frame_iterator(&input);
+            let frame_iter = create_av1_frame_iterator(&input);
 
             let decoder = Box::new(StatelessDecoder::<Av1, _>::new_vaapi(
                 display,
@@ -395,16 +516,20 @@ fn main() {
     };
 
     let mut md5_context = md5::Context::new();
+    let mut crcs = String::new();
     let mut output_filename_idx = 0;
 
     let mut on_new_frame = |handle: Box<dyn DecodedHandle<Descriptor = _>>| {
-        if args.output.is_some() || args.compute_md5.is_some() {
-            handle.sync().unwrap();
-            let picture = handle.dyn_picture();
-            let mut handle = picture.dyn_mappable_handle().unwrap();
-            let buffer_size = handle.image_size();
-            let mut frame_data = vec![0; buffer_size];
-            handle.read(&mut frame_data).unwrap();
+        if args.output.is_some() || args.compute_md5.is_some() || args.gen_crcs.is_some() {
+            let frame_data = match &mut vpp {
+                Some(vpp) => {
+                    let processed = vpp
+                        .process(handle.as_ref())
+                        .expect("failed to post-process frame");
+                    read_frame(processed.as_ref())
+                }
+                None => read_frame(handle.as_ref()),
+            };
 
             if args.multiple_output_files {
                 let file_name = decide_output_file_name(
@@ -427,9 +552,16 @@ fn main() {
 
             match args.compute_md5 {
                 None => (),
-                Some(Md5Computation::Frame) => println!("{:x}", md5::compute(&frame_data)),
+                Some(Md5Computation::Frame) => {
+                    println!("{}", ChecksumAlgorithm::Md5.compute(&frame_data))
+                }
                 Some(Md5Computation::Stream) => md5_context.consume(&frame_data),
             }
+
+            if args.gen_crcs.is_some() {
+                crcs.push_str(&ChecksumAlgorithm::Crc32.compute(&frame_data));
+                crcs.push('\n');
+            }
         }
     };
 
@@ -461,7 +593,7 @@ fn main() {
                 }
             })
         },
-        args.output_format,
+        decode_format,
         blocking_mode,
     )
     .expect("error during playback loop");
@@ -469,4 +601,8 @@ fn main() {
     if let Some(Md5Computation::Stream) = args.compute_md5 {
         println!("{:x}", md5_context.compute());
     }
+
+    if let Some(path) = &args.gen_crcs {
+        std::fs::write(path, crcs).expect("failed to write CRC file");
+    }
 }
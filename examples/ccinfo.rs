@@ -0,0 +1,518 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! ccinfo, a stream analyzer using the parsers of cros-codecs. Prints the parsed headers of each
+//! frame of a stream (profile, level, resolution, frame types, reference structure and SEI
+//! messages) without decoding anything, so no hardware is needed.
+
+use std::fs::File;
+use std::io::Cursor;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use argh::FromArgs;
+use cros_codecs::codec::av1::parser::ObuType;
+use cros_codecs::codec::av1::parser::ParsedObu;
+use cros_codecs::codec::av1::parser::Parser as Av1Parser;
+use cros_codecs::codec::h264::parser::Nalu as H264Nalu;
+use cros_codecs::codec::h264::parser::NaluType as H264NaluType;
+use cros_codecs::codec::h264::parser::Parser as H264Parser;
+use cros_codecs::codec::h264::parser::SeiMessage as H264SeiMessage;
+use cros_codecs::codec::h265::parser::Nalu as H265Nalu;
+use cros_codecs::codec::h265::parser::NaluType as H265NaluType;
+use cros_codecs::codec::h265::parser::Parser as H265Parser;
+use cros_codecs::codec::h265::parser::SeiMessage as H265SeiMessage;
+use cros_codecs::codec::vp8::parser::Parser as Vp8Parser;
+use cros_codecs::codec::vp9::parser::Parser as Vp9Parser;
+use cros_codecs::utils::Av1TemporalUnitIterator;
+use cros_codecs::utils::IvfIterator;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum EncodedFormat {
+    H264,
+    H265,
+    VP8,
+    VP9,
+    AV1,
+}
+
+impl FromStr for EncodedFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h264" | "H264" => Ok(EncodedFormat::H264),
+            "h265" | "H265" => Ok(EncodedFormat::H265),
+            "vp8" | "VP8" => Ok(EncodedFormat::VP8),
+            "vp9" | "VP9" => Ok(EncodedFormat::VP9),
+            "av1" | "AV1" => Ok(EncodedFormat::AV1),
+            _ => Err("unrecognized input format. Valid values: h264, h265, vp8, vp9, av1"),
+        }
+    }
+}
+
+/// Prints the parsed headers of each frame of a stream
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// input file, in Annex B format for H.264 and H.265, IVF for VP8 and VP9, and IVF or raw
+    /// OBUs for AV1
+    #[argh(positional)]
+    input: PathBuf,
+
+    /// input format of the stream.
+    #[argh(option)]
+    input_format: EncodedFormat,
+}
+
+/// Returns a description of the SEI messages in `messages`, which can be of any codec.
+fn describe_sei<T, F>(messages: &[T], describe: F) -> String
+where
+    F: Fn(&T) -> String,
+{
+    messages.iter().map(describe).collect::<Vec<_>>().join(", ")
+}
+
+/// Prints the headers of the H.264 Annex B stream `input`.
+fn print_h264(input: &[u8]) {
+    let mut parser = H264Parser::default();
+    let mut cursor = Cursor::new(input);
+    let mut num_frames = 0;
+    // SEI messages received since the last frame, which apply to the next one.
+    let mut sei = Vec::new();
+
+    while let Ok(nalu) = H264Nalu::next(&mut cursor) {
+        match nalu.header.type_ {
+            H264NaluType::Sps => match parser.parse_sps(&nalu) {
+                Ok(sps) => println!(
+                    "SPS {}: profile_idc {}, level {:?}, {}-bit, resolution {}x{}, max DPB frames {}",
+                    sps.seq_parameter_set_id,
+                    sps.profile_idc,
+                    sps.level_idc,
+                    sps.bit_depth_luma_minus8 + 8,
+                    sps.visible_rectangle().size().width,
+                    sps.visible_rectangle().size().height,
+                    sps.max_dpb_frames(),
+                ),
+                Err(e) => println!("SPS: parsing error: {:#}", e),
+            },
+            H264NaluType::Pps => match parser.parse_pps(&nalu) {
+                Ok(pps) => println!(
+                    "PPS {}: SPS {}",
+                    pps.pic_parameter_set_id, pps.seq_parameter_set_id
+                ),
+                Err(e) => println!("PPS: parsing error: {:#}", e),
+            },
+            H264NaluType::Sei => match parser.parse_sei(&nalu) {
+                Ok(messages) => sei.extend(messages),
+                Err(e) => println!("SEI: parsing error: {:#}", e),
+            },
+            H264NaluType::Slice | H264NaluType::SliceDpa | H264NaluType::SliceIdr => {
+                let ref_idc = nalu.header.ref_idc;
+                let is_idr = nalu.header.idr_pic_flag;
+                let slice = match parser.parse_slice_header(nalu) {
+                    Ok(slice) => slice,
+                    Err(e) => {
+                        println!("slice: parsing error: {:#}", e);
+                        continue;
+                    }
+                };
+                let hdr = &slice.header;
+
+                // Only describe pictures once, from their first slice.
+                if hdr.first_mb_in_slice != 0 {
+                    continue;
+                }
+
+                let structure = match (hdr.field_pic_flag, hdr.bottom_field_flag) {
+                    (false, _) => "frame",
+                    (true, false) => "top field",
+                    (true, true) => "bottom field",
+                };
+                let references = if hdr.slice_type.is_b() {
+                    format!(
+                        "{} L0 / {} L1 refs",
+                        hdr.num_ref_idx_l0_active_minus1 + 1,
+                        hdr.num_ref_idx_l1_active_minus1 + 1
+                    )
+                } else if hdr.slice_type.is_p() || hdr.slice_type.is_sp() {
+                    format!("{} L0 refs", hdr.num_ref_idx_l0_active_minus1 + 1)
+                } else {
+                    String::from("no refs")
+                };
+
+                println!(
+                    "frame {}: {:?}{} {}, frame_num {}, POC LSB {}, {}, {}{}",
+                    num_frames,
+                    hdr.slice_type,
+                    if is_idr { " (IDR)" } else { "" },
+                    structure,
+                    hdr.frame_num,
+                    hdr.pic_order_cnt_lsb,
+                    if ref_idc != 0 {
+                        "reference"
+                    } else {
+                        "non-reference"
+                    },
+                    references,
+                    if sei.is_empty() {
+                        String::new()
+                    } else {
+                        format!(
+                            ", SEI: {}",
+                            describe_sei(&sei, |message| match message {
+                                H264SeiMessage::FramePackingArrangement(_) => {
+                                    String::from("frame packing arrangement")
+                                }
+                                H264SeiMessage::PicTiming(_) => String::from("picture timing"),
+                                H264SeiMessage::Unsupported(type_) => format!("type {}", type_),
+                            })
+                        )
+                    },
+                );
+
+                num_frames += 1;
+                sei.clear();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Prints the headers of the H.265 Annex B stream `input`.
+fn print_h265(input: &[u8]) {
+    let mut parser = H265Parser::default();
+    let mut cursor = Cursor::new(input);
+    let mut num_frames = 0;
+    // SEI messages received since the last frame, which apply to the next one.
+    let mut sei = Vec::new();
+
+    while let Ok(nalu) = H265Nalu::next(&mut cursor) {
+        let type_ = nalu.header.type_;
+
+        match type_ {
+            H265NaluType::VpsNut => {
+                if let Err(e) = parser.parse_vps(&nalu) {
+                    println!("VPS: parsing error: {:#}", e);
+                }
+            }
+            H265NaluType::SpsNut => match parser.parse_sps(&nalu) {
+                Ok(sps) => println!(
+                    "SPS {}: profile_idc {}, {} tier, level {:?}, {}-bit, resolution {}x{}, max DPB size {}",
+                    sps.seq_parameter_set_id,
+                    sps.profile_tier_level.general_profile_idc,
+                    if sps.profile_tier_level.general_tier_flag {
+                        "high"
+                    } else {
+                        "main"
+                    },
+                    sps.profile_tier_level.general_level_idc,
+                    sps.bit_depth_luma_minus8 + 8,
+                    sps.visible_rectangle().size().width,
+                    sps.visible_rectangle().size().height,
+                    sps.max_dpb_size(),
+                ),
+                Err(e) => println!("SPS: parsing error: {:#}", e),
+            },
+            H265NaluType::PpsNut => match parser.parse_pps(&nalu) {
+                Ok(pps) => println!(
+                    "PPS {}: SPS {}",
+                    pps.pic_parameter_set_id, pps.seq_parameter_set_id
+                ),
+                Err(e) => println!("PPS: parsing error: {:#}", e),
+            },
+            H265NaluType::PrefixSeiNut | H265NaluType::SuffixSeiNut => {
+                match parser.parse_sei(&nalu) {
+                    Ok(messages) => sei.extend(messages),
+                    Err(e) => println!("SEI: parsing error: {:#}", e),
+                }
+            }
+            _ if (type_ as u32) < 32 => {
+                let layer_id = nalu.header.nuh_layer_id;
+                let temporal_id = nalu.header.nuh_temporal_id_plus1.saturating_sub(1);
+                let slice = match parser.parse_slice_header(nalu) {
+                    Ok(slice) => slice,
+                    Err(e) => {
+                        println!("slice: parsing error: {:#}", e);
+                        continue;
+                    }
+                };
+                let hdr = &slice.header;
+
+                // Only describe pictures once, from their first slice segment.
+                if !hdr.first_slice_segment_in_pic_flag {
+                    continue;
+                }
+
+                let references = if hdr.type_.is_b() {
+                    format!(
+                        "{} L0 / {} L1 refs",
+                        hdr.num_ref_idx_l0_active_minus1 + 1,
+                        hdr.num_ref_idx_l1_active_minus1 + 1
+                    )
+                } else if hdr.type_.is_p() {
+                    format!("{} L0 refs", hdr.num_ref_idx_l0_active_minus1 + 1)
+                } else {
+                    String::from("no refs")
+                };
+
+                println!(
+                    "frame {}: {:?} {:?}, layer {}, temporal layer {}, POC LSB {}, {}, {}{}",
+                    num_frames,
+                    hdr.type_,
+                    type_,
+                    layer_id,
+                    temporal_id,
+                    hdr.pic_order_cnt_lsb,
+                    if type_.is_slnr() {
+                        "sub-layer non-reference"
+                    } else {
+                        "reference"
+                    },
+                    references,
+                    if sei.is_empty() {
+                        String::new()
+                    } else {
+                        format!(
+                            ", SEI: {}",
+                            describe_sei(&sei, |message| match message {
+                                H265SeiMessage::FramePackingArrangement(_) => {
+                                    String::from("frame packing arrangement")
+                                }
+                                H265SeiMessage::PicTiming(_) => String::from("picture timing"),
+                                H265SeiMessage::Unsupported(type_) => format!("type {}", type_),
+                            })
+                        )
+                    },
+                );
+
+                num_frames += 1;
+                sei.clear();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Prints the headers of the VP8 IVF stream `input`.
+fn print_vp8(input: &[u8]) {
+    let mut parser = Vp8Parser::default();
+
+    for (num_frames, packet) in IvfIterator::new(input).enumerate() {
+        let hdr = match parser.parse_frame(packet) {
+            Ok(frame) => frame.header,
+            Err(e) => {
+                println!("frame {}: parsing error: {:#}", num_frames, e);
+                continue;
+            }
+        };
+
+        let refreshed = [
+            (hdr.refresh_last, "last"),
+            (hdr.refresh_golden_frame, "golden"),
+            (hdr.refresh_alternate_frame, "altref"),
+        ]
+        .into_iter()
+        .filter_map(|(refreshed, name)| refreshed.then_some(name))
+        .collect::<Vec<_>>();
+
+        println!(
+            "frame {}: {}, version {}{}, {}, refreshes [{}]",
+            num_frames,
+            if hdr.key_frame {
+                "key frame"
+            } else {
+                "inter frame"
+            },
+            hdr.version,
+            if hdr.key_frame {
+                format!(", resolution {}x{}", hdr.width, hdr.height)
+            } else {
+                String::new()
+            },
+            if hdr.show_frame { "shown" } else { "hidden" },
+            refreshed.join(", "),
+        );
+    }
+}
+
+/// Prints the headers of the VP9 IVF stream `input`.
+fn print_vp9(input: &[u8]) {
+    let mut parser = Vp9Parser::default();
+    let mut num_frames = 0;
+
+    for packet in IvfIterator::new(input) {
+        let frames = match parser.parse_chunk(packet) {
+            Ok(frames) => frames,
+            Err(e) => {
+                println!("frame {}: parsing error: {:#}", num_frames, e);
+                continue;
+            }
+        };
+
+        // Frames of a superframe are described separately.
+        for frame in frames {
+            let hdr = &frame.header;
+
+            if hdr.show_existing_frame {
+                println!(
+                    "frame {}: shows reference slot {}",
+                    num_frames, hdr.frame_to_show_map_idx
+                );
+            } else {
+                println!(
+                    "frame {}: {:?}{}, {:?}, {:?}, resolution {}x{}, {}, refs {:?}, refreshes {:#010b}",
+                    num_frames,
+                    hdr.frame_type,
+                    if hdr.intra_only { " (intra only)" } else { "" },
+                    hdr.profile,
+                    hdr.bit_depth,
+                    hdr.width,
+                    hdr.height,
+                    if hdr.show_frame { "shown" } else { "hidden" },
+                    hdr.ref_frame_idx,
+                    hdr.refresh_frame_flags,
+                );
+            }
+
+            num_frames += 1;
+        }
+    }
+}
+
+/// Prints the headers of the AV1 stream `input`, either in IVF or as raw OBUs.
+fn print_av1(input: &[u8]) {
+    let temporal_units: Box<dyn Iterator<Item = &[u8]>> = if input.starts_with(b"DKIF") {
+        Box::new(IvfIterator::new(input))
+    } else {
+        Box::new(Av1TemporalUnitIterator::new(input))
+    };
+
+    let mut parser = Av1Parser::default();
+    let mut num_frames = 0;
+
+    for temporal_unit in temporal_units {
+        let mut consumed = 0;
+        // Whether metadata OBUs have been received since the last frame.
+        let mut metadata = false;
+
+        while consumed < temporal_unit.len() {
+            let obu = match parser.parse_obu(&temporal_unit[consumed..]) {
+                Ok(ParsedObu::Process(obu)) => obu,
+                Ok(ParsedObu::Drop(length)) => {
+                    consumed += length as usize;
+                    continue;
+                }
+                Err(e) => {
+                    println!("OBU: parsing error: {:#}", e);
+                    break;
+                }
+            };
+            consumed += obu.data.len();
+            let temporal_id = obu.header.temporal_id;
+
+            let frame_header = match obu.header.obu_type {
+                ObuType::SequenceHeader => {
+                    match parser.parse_sequence_header_obu(&obu) {
+                        Ok(sequence) => {
+                            let operating_point = &sequence.operating_points[0];
+                            println!(
+                                "sequence header: {:?}, level index {}, {} tier, {:?}, max resolution {}x{}, {} operating points",
+                                sequence.seq_profile,
+                                operating_point.seq_level_idx,
+                                if operating_point.seq_tier != 0 { "high" } else { "main" },
+                                sequence.bit_depth,
+                                sequence.max_frame_width_minus_1 + 1,
+                                sequence.max_frame_height_minus_1 + 1,
+                                sequence.operating_points_cnt_minus_1 + 1,
+                            );
+                        }
+                        Err(e) => println!("sequence header: parsing error: {:#}", e),
+                    }
+                    continue;
+                }
+                ObuType::TemporalDelimiter => {
+                    if let Err(e) = parser.parse_temporal_delimiter_obu(&obu) {
+                        println!("temporal delimiter: parsing error: {:#}", e);
+                    }
+                    continue;
+                }
+                ObuType::Metadata => {
+                    metadata = true;
+                    continue;
+                }
+                ObuType::TileGroup => {
+                    // Tile groups are parsed so the parser knows when the frame is complete.
+                    if let Err(e) = parser.parse_tile_group_obu(obu) {
+                        println!("tile group: parsing error: {:#}", e);
+                    }
+                    continue;
+                }
+                ObuType::FrameHeader => parser.parse_frame_header_obu(&obu),
+                ObuType::Frame => parser.parse_frame_obu(obu).map(|frame| frame.header),
+                _ => continue,
+            };
+
+            let hdr = match frame_header {
+                Ok(hdr) => hdr,
+                Err(e) => {
+                    println!("frame {}: parsing error: {:#}", num_frames, e);
+                    continue;
+                }
+            };
+
+            if hdr.show_existing_frame {
+                println!(
+                    "frame {}: shows reference slot {}, temporal layer {}",
+                    num_frames, hdr.frame_to_show_map_idx, temporal_id
+                );
+            } else {
+                println!(
+                    "frame {}: {:?}, resolution {}x{} (upscaled width {}), temporal layer {}, {}, refs {:?}, refreshes {:#010b}{}",
+                    num_frames,
+                    hdr.frame_type,
+                    hdr.frame_width,
+                    hdr.frame_height,
+                    hdr.upscaled_width,
+                    temporal_id,
+                    if hdr.show_frame { "shown" } else { "hidden" },
+                    hdr.ref_frame_idx,
+                    hdr.refresh_frame_flags,
+                    if metadata { ", metadata" } else { "" },
+                );
+            }
+
+            // The state of the references is needed to parse the next frames.
+            if let Err(e) = parser.ref_frame_update(&hdr) {
+                println!("frame {}: cannot update references: {:#}", num_frames, e);
+            }
+
+            num_frames += 1;
+            metadata = false;
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Args = argh::from_env();
+
+    let input = {
+        let mut buf = Vec::new();
+        File::open(args.input)
+            .expect("error opening input file")
+            .read_to_end(&mut buf)
+            .expect("error reading input file");
+        buf
+    };
+
+    match args.input_format {
+        EncodedFormat::H264 => print_h264(&input),
+        EncodedFormat::H265 => print_h265(&input),
+        EncodedFormat::VP8 => print_vp8(&input),
+        EncodedFormat::VP9 => print_vp9(&input),
+        EncodedFormat::AV1 => print_av1(&input),
+    }
+}
@@ -8,7 +8,33 @@
 //! VAAPI. This module contains backend-related code that is not tied to any particular codec and
 //! can be shared between various parts of this crate.
 
-#[cfg(test)]
+#[cfg(any(feature = "vaapi", test))]
+pub(crate) mod decode_time;
 pub(crate) mod dummy;
 #[cfg(feature = "vaapi")]
 pub(crate) mod vaapi;
+
+#[cfg(feature = "vaapi")]
+pub use vaapi::driver;
+#[cfg(feature = "vaapi")]
+pub use vaapi::open_drm_display;
+#[cfg(feature = "vaapi")]
+pub use vaapi::protected;
+#[cfg(feature = "vaapi")]
+pub use vaapi::query_export_modifiers;
+#[cfg(feature = "vaapi")]
+pub use vaapi::trace;
+#[cfg(feature = "vaapi")]
+pub use vaapi::vpp;
+#[cfg(feature = "vaapi")]
+pub use vaapi::PowerHint;
+#[cfg(feature = "vaapi")]
+pub use vaapi::VaapiDecodedHandleExt;
+#[cfg(feature = "vaapi")]
+pub use vaapi::VaapiExportedFrame;
+#[cfg(feature = "vaapi")]
+pub use vaapi::VaapiModifierFrame;
+#[cfg(feature = "vaapi")]
+pub use vaapi::VaapiSurfaceLease;
+#[cfg(feature = "vaapi")]
+pub use vaapi::DRM_FORMAT_MOD_LINEAR;
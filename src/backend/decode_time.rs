@@ -0,0 +1,77 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Estimation of the time it takes a backend to decode a picture.
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// Smoothing factor of [`DecodeTimeEstimator`]: each new sample contributes `1 /
+/// DECODE_TIME_SMOOTHING` of the updated estimate, so a single outlier does not skew it.
+const DECODE_TIME_SMOOTHING: u32 = 8;
+
+/// Running estimate of the time between the submission of a picture and its completion, as an
+/// exponentially weighted moving average of the measured decode times.
+///
+/// Samples must only be added when the instant of completion is actually known, i.e. when the
+/// caller was blocked waiting for the picture to complete. A picture found to be already complete
+/// by polling has completed at some unknown point before, and using it as a sample would measure
+/// the polling latency instead of the decode time.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct DecodeTimeEstimator {
+    estimate: Option<Duration>,
+}
+
+impl DecodeTimeEstimator {
+    /// Adds the decode time of a picture submitted at `submitted_at` and completed at
+    /// `completed_at` to the estimate.
+    pub(crate) fn add_sample(&mut self, submitted_at: Instant, completed_at: Instant) {
+        let measured = completed_at.saturating_duration_since(submitted_at);
+
+        self.estimate = Some(match self.estimate {
+            Some(previous) => {
+                (previous * (DECODE_TIME_SMOOTHING - 1) + measured) / DECODE_TIME_SMOOTHING
+            }
+            None => measured,
+        });
+    }
+
+    /// Returns the estimated instant at which a picture submitted at `submitted_at` will be
+    /// complete, or `None` if no sample has been added yet.
+    pub(crate) fn estimated_completion(&self, submitted_at: Instant) -> Option<Instant> {
+        self.estimate.map(|estimate| submitted_at + estimate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use super::DecodeTimeEstimator;
+
+    #[test]
+    fn decode_time_estimate() {
+        let clock = Instant::now();
+        let at = |ms| clock + Duration::from_millis(ms);
+
+        let mut estimator = DecodeTimeEstimator::default();
+        assert_eq!(estimator.estimated_completion(at(0)), None);
+
+        // The first sample is used as-is.
+        estimator.add_sample(at(0), at(8));
+        assert_eq!(estimator.estimated_completion(at(100)), Some(at(108)));
+
+        // Further samples are smoothed.
+        estimator.add_sample(at(100), at(116));
+        assert_eq!(estimator.estimated_completion(at(200)), Some(at(209)));
+
+        // A completion reported before the submission counts as an immediate one.
+        estimator.add_sample(at(300), at(200));
+        assert_eq!(
+            estimator.estimated_completion(at(400)),
+            Some(at(400) + Duration::from_micros(7875))
+        );
+    }
+}
@@ -3,32 +3,61 @@
 // found in the LICENSE file.
 
 //! This file contains a dummy backend whose only purpose is to let the decoder
-//! run so we can test it in isolation.
+//! run so we can test it in isolation, or analyze streams without decoding them.
 
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use crate::decoder::completion::CompletionWaiter;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoderBackend;
 use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::DecodedHandle;
+use crate::decoder::DpbSizeOrigin;
 use crate::decoder::DynHandle;
+use crate::decoder::FormatError;
 use crate::decoder::FramePool;
 use crate::decoder::MappableHandle;
+use crate::decoder::NumFramesBreakdown;
 use crate::decoder::StreamInfo;
 use crate::DecodedFormat;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
 use crate::Resolution;
 
 #[derive(Default)]
-pub struct BackendHandle(());
+pub struct BackendHandle {
+    descriptor: (),
+    /// Whether this picture never completes, see [`Backend::hang`].
+    hung: bool,
+}
+
+impl BackendHandle {
+    pub(crate) fn new(hung: bool) -> Self {
+        Self {
+            descriptor: (),
+            hung,
+        }
+    }
+}
 
 impl MappableHandle for BackendHandle {
     fn read(&mut self, _: &mut [u8]) -> anyhow::Result<()> {
         Ok(())
     }
 
-    fn image_size(&mut self) -> usize {
-        1
+    fn layout(&mut self) -> anyhow::Result<FrameLayout> {
+        Ok(FrameLayout {
+            format: (Fourcc::from(DecodedFormat::NV12), 0),
+            size: Resolution::from((1, 1)),
+            planes: vec![PlaneLayout {
+                buffer_index: 0,
+                offset: 0,
+                stride: 1,
+                size: 1,
+            }],
+        })
     }
 }
 
@@ -74,17 +103,28 @@ impl DecodedHandle for Handle {
     }
 
     fn is_ready(&self) -> bool {
-        true
+        !self.handle.borrow().hung
+    }
+
+    fn resource(&self) -> std::cell::Ref<'_, ()> {
+        std::cell::Ref::map(self.handle.borrow(), |h| &h.descriptor)
     }
 
-    fn resource(&self) -> std::cell::Ref<()> {
-        std::cell::Ref::map(self.handle.borrow(), |h| &h.0)
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
     }
 }
 
 /// Dummy backend that can be used for any codec.
 pub(crate) struct Backend {
     stream_info: StreamInfo,
+    /// Whether the next picture submission fails with
+    /// [`crate::decoder::stateless::StatelessBackendError::DeviceLost`], to test the recovery of
+    /// the decoder. Only the H.264 backend honors it.
+    pub(crate) lose_device: bool,
+    /// Whether the submitted pictures never become ready, to test the sync timeout of the
+    /// decoder. Syncing them still returns immediately. Only the H.264 backend honors it.
+    pub(crate) hang: bool,
 }
 
 impl Backend {
@@ -93,9 +133,17 @@ impl Backend {
             stream_info: StreamInfo {
                 format: DecodedFormat::I420,
                 min_num_frames: 4,
+                num_frames_breakdown: NumFramesBreakdown {
+                    dpb_size: 0,
+                    dpb_size_origin: DpbSizeOrigin::Codec,
+                    extra: 4,
+                },
                 coded_resolution: Resolution::from((320, 200)),
                 display_resolution: Resolution::from((320, 200)),
+                display_offset: (0, 0),
             },
+            lose_device: false,
+            hang: false,
         }
     }
 }
@@ -133,7 +181,21 @@ impl<Codec: StatelessCodec> StatelessDecoderBackendPicture<Codec> for Backend {
 impl<Codec: StatelessCodec> StatelessDecoderBackend<Codec> for Backend {
     type Handle = Handle;
 
-    fn try_format(&mut self, _: &Codec::FormatInfo, _: DecodedFormat) -> anyhow::Result<()> {
+    fn try_format(&mut self, _: &Codec::FormatInfo, _: DecodedFormat) -> Result<(), FormatError> {
+        Ok(())
+    }
+
+    fn try_change_resolution(&mut self, _: &Codec::FormatInfo) -> bool {
+        false
+    }
+
+    fn completion_waiter(&self, _: &Self::Handle) -> Option<CompletionWaiter> {
+        // Syncing dummy handles always returns immediately.
+        Some(Box::new(|| Ok(())))
+    }
+
+    fn recover(&mut self) -> anyhow::Result<()> {
+        // There is no device to recreate.
         Ok(())
     }
 
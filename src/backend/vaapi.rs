@@ -5,16 +5,29 @@
 //! Home of the [`VaapiBackend`], which can be used with [stateless
 //! decoders](crate::decoder::stateless).
 
+pub mod driver;
+pub mod protected;
+pub mod trace;
+pub mod vpp;
+
+use std::cell::Cell;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::collections::HashSet;
+use std::ffi::c_void;
 use std::fmt::Debug;
 use std::os::fd::AsRawFd;
+use std::os::fd::BorrowedFd;
 use std::rc::Rc;
+use std::rc::Weak;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::anyhow;
 use anyhow::Context as AnyhowContext;
 use byteorder::ByteOrder;
 use byteorder::LittleEndian;
+use libva::BufferType;
 use libva::Config;
 use libva::Context;
 use libva::Display;
@@ -28,7 +41,14 @@ use libva::VAConfigAttrib;
 use libva::VAConfigAttribType;
 use libva::VaError;
 
+use crate::backend::decode_time::DecodeTimeEstimator;
+use crate::backend::vaapi::driver::DriverInfo;
+use crate::backend::vaapi::protected::EncryptionParams;
+use crate::backend::vaapi::protected::ProtectedSession;
 use crate::backend::vaapi::surface_pool::SurfacePool;
+use crate::backend::vaapi::trace::TraceRecorder;
+use crate::backend::vaapi::trace::VaTrace;
+use crate::decoder::poll_ready;
 use crate::decoder::stateless::StatelessBackendError;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
@@ -36,20 +56,118 @@ use crate::decoder::stateless::StatelessDecoderBackend;
 use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::DecodedHandle as DecodedHandleTrait;
 use crate::decoder::DynHandle;
+use crate::decoder::FormatError;
 use crate::decoder::FramePool;
 use crate::decoder::MappableHandle;
+use crate::decoder::NumFramesBreakdown;
 use crate::decoder::StreamInfo;
 use crate::i4xx_copy;
 use crate::nv12_copy;
+use crate::p010_copy;
+use crate::utils::convert;
 use crate::utils::DmabufFrame;
 use crate::utils::UserPtrFrame;
 use crate::y410_to_i410;
 use crate::DecodedFormat;
 use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
+use crate::Rect;
 use crate::Resolution;
 
 pub(crate) use surface_pool::PooledSurface;
 
+/// Converts `error` into a [`StatelessBackendError`], recognizing the errors that indicate the
+/// loss of the device. Other errors keep their VA status so clients can tell them apart.
+fn va_error(error: VaError) -> StatelessBackendError {
+    match error {
+        // VA-API has no dedicated status for a lost device: the display becoming invalid, e.g.
+        // because the DRM device has gone away, is the only one that reliably signals it. Invalid
+        // contexts or configs are bugs, and `HwBusy` is transient, so neither is reported as a
+        // device loss.
+        VaError::InvalidDisplay => StatelessBackendError::DeviceLost,
+        error => StatelessBackendError::VaError(error),
+    }
+}
+
+/// Converts `error`, returned while setting up the decoding of a stream, into a
+/// [`StatelessBackendError`], recognizing the errors meaning that the driver cannot decode it.
+fn va_setup_error(error: VaError) -> StatelessBackendError {
+    match error {
+        VaError::UnsupportedProfile
+        | VaError::UnsupportedEntrypoint
+        | VaError::UnsupportedRtFormat
+        | VaError::ResolutionNotSupported => {
+            StatelessBackendError::UnsupportedProfile(error.into())
+        }
+        error => va_error(error),
+    }
+}
+
+/// Converts `error` into a [`StatelessBackendError`], preserving the one it carries if any so
+/// e.g. unsupported streams can be told apart from other failures.
+fn backend_error(error: anyhow::Error) -> StatelessBackendError {
+    match error.downcast::<StatelessBackendError>() {
+        Ok(error) => error,
+        Err(error) => StatelessBackendError::Other(error),
+    }
+}
+
+/// Checks the status returned by a libva function called directly.
+fn va_check(status: libva::VAStatus, operation: &str) -> anyhow::Result<()> {
+    if status == libva::constants::VA_STATUS_SUCCESS as libva::VAStatus {
+        Ok(())
+    } else {
+        Err(anyhow!("{} failed with VA status {:#x}", operation, status))
+    }
+}
+
+/// A VA buffer created from a raw parameter structure, destroyed when dropped.
+///
+/// The libva wrapper only supports the buffers used for decoding, so the other ones, e.g. for video
+/// processing or decryption, are created directly.
+struct RawBuffer<'a> {
+    display: &'a Display,
+    id: libva::VABufferID,
+}
+
+impl<'a> RawBuffer<'a> {
+    fn new<T>(
+        display: &'a Display,
+        context: &Context,
+        type_: libva::VABufferType::Type,
+        data: &mut T,
+    ) -> anyhow::Result<Self> {
+        let mut id = 0;
+
+        // Safe because `data` is a valid structure of the size we pass, which libva copies into
+        // the buffer.
+        va_check(
+            unsafe {
+                libva::vaCreateBuffer(
+                    display.handle(),
+                    context.id(),
+                    type_,
+                    std::mem::size_of::<T>() as u32,
+                    1,
+                    data as *mut T as *mut c_void,
+                    &mut id,
+                )
+            },
+            "vaCreateBuffer",
+        )?;
+
+        Ok(Self { display, id })
+    }
+}
+
+impl<'a> Drop for RawBuffer<'a> {
+    fn drop(&mut self) {
+        // Safe because the buffer has been created on this display and is not used anymore.
+        unsafe { libva::vaDestroyBuffer(self.display.handle(), self.id) };
+    }
+}
+
 fn va_rt_format_to_string(va_rt_format: u32) -> String {
     String::from(match va_rt_format {
         libva::constants::VA_RT_FORMAT_YUV420 => "YUV420",
@@ -74,7 +192,7 @@ struct FormatMap {
 
 /// Maps a given VA_RT_FORMAT to a compatible decoded format in an arbitrary
 /// preferred order.
-const FORMAT_MAP: [FormatMap; 10] = [
+const FORMAT_MAP: [FormatMap; 13] = [
     FormatMap {
         rt_format: libva::constants::VA_RT_FORMAT_YUV420,
         va_fourcc: libva::constants::VA_FOURCC_NV12,
@@ -85,11 +203,23 @@ const FORMAT_MAP: [FormatMap; 10] = [
         va_fourcc: libva::constants::VA_FOURCC_I420,
         decoded_format: DecodedFormat::I420,
     },
+    // Drivers that cannot map surfaces as I420 can still provide it through a conversion.
+    FormatMap {
+        rt_format: libva::constants::VA_RT_FORMAT_YUV420,
+        va_fourcc: libva::constants::VA_FOURCC_NV12,
+        decoded_format: DecodedFormat::I420,
+    },
     FormatMap {
         rt_format: libva::constants::VA_RT_FORMAT_YUV422,
         va_fourcc: libva::constants::VA_FOURCC_422H,
         decoded_format: DecodedFormat::I422,
     },
+    // Some drivers can only map 8-bit 4:2:2 surfaces as packed YUYV.
+    FormatMap {
+        rt_format: libva::constants::VA_RT_FORMAT_YUV422,
+        va_fourcc: libva::constants::VA_FOURCC_YUY2,
+        decoded_format: DecodedFormat::I422,
+    },
     FormatMap {
         rt_format: libva::constants::VA_RT_FORMAT_YUV444,
         va_fourcc: libva::constants::VA_FOURCC_444P,
@@ -100,6 +230,11 @@ const FORMAT_MAP: [FormatMap; 10] = [
         va_fourcc: libva::constants::VA_FOURCC_P010,
         decoded_format: DecodedFormat::I010,
     },
+    FormatMap {
+        rt_format: libva::constants::VA_RT_FORMAT_YUV420_10,
+        va_fourcc: libva::constants::VA_FOURCC_P010,
+        decoded_format: DecodedFormat::P010,
+    },
     FormatMap {
         rt_format: libva::constants::VA_RT_FORMAT_YUV420_12,
         va_fourcc: libva::constants::VA_FOURCC_P012,
@@ -176,7 +311,7 @@ fn supported_formats_for_rt_format(
 /// A decoded frame handle.
 pub(crate) type DecodedHandle<M> = Rc<RefCell<VaapiDecodedHandle<M>>>;
 
-impl<M: SurfaceMemoryDescriptor> DecodedHandleTrait for DecodedHandle<M> {
+impl<M: SurfaceMemoryDescriptor + 'static> DecodedHandleTrait for DecodedHandle<M> {
     type Descriptor = M;
 
     fn coded_resolution(&self) -> Resolution {
@@ -187,6 +322,10 @@ impl<M: SurfaceMemoryDescriptor> DecodedHandleTrait for DecodedHandle<M> {
         self.borrow().display_resolution
     }
 
+    fn display_offset(&self) -> (u32, u32) {
+        self.borrow().display_offset
+    }
+
     fn timestamp(&self) -> u64 {
         self.borrow().timestamp()
     }
@@ -199,12 +338,30 @@ impl<M: SurfaceMemoryDescriptor> DecodedHandleTrait for DecodedHandle<M> {
         self.borrow().is_va_ready().unwrap_or(true)
     }
 
+    fn is_corrupted(&self) -> anyhow::Result<bool> {
+        // Not all drivers support querying decoding errors, in which case we cannot tell.
+        Ok(self.borrow().has_va_decode_errors().unwrap_or(false))
+    }
+
     fn sync(&self) -> anyhow::Result<()> {
-        self.borrow_mut().sync().context("while syncing picture")?;
+        self.borrow_mut()
+            .sync()
+            .map_err(va_error)
+            .context("while syncing picture")?;
 
         Ok(())
     }
 
+    fn wait_ready(&self, timeout: Duration) -> anyhow::Result<bool> {
+        self.borrow_mut()
+            .wait_ready(timeout)
+            .context("while waiting for picture")
+    }
+
+    fn estimated_completion(&self) -> Option<Instant> {
+        self.borrow().estimated_completion()
+    }
+
     fn resource(&self) -> std::cell::Ref<M> {
         std::cell::Ref::map(self.borrow(), |r| match &r.state {
             PictureState::Ready(p) => p.surface().as_ref(),
@@ -212,6 +369,381 @@ impl<M: SurfaceMemoryDescriptor> DecodedHandleTrait for DecodedHandle<M> {
             PictureState::Invalid => unreachable!(),
         })
     }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// Extension trait giving access to the VA-API specifics of the handles returned by decoders
+/// using the VA-API backend.
+///
+/// This is useful for clients that want to render decoded frames using libva directly.
+pub trait VaapiDecodedHandleExt {
+    /// Returns the ID of the VA surface backing this handle, or `None` if this handle has not been
+    /// produced by the VA-API backend.
+    ///
+    /// The surface may still be in the process of being decoded: call [`DecodedHandleTrait::sync`]
+    /// before accessing its content.
+    fn va_surface_id(&self) -> Option<libva::VASurfaceID>;
+
+    /// Waits for this handle to be decoded, and lends its VA surface and display to the caller.
+    ///
+    /// The surface will not be returned to the frame pool, and thus not be reused by the decoder,
+    /// for as long as the returned [`VaapiSurfaceLease`] is alive, so it can safely be used for
+    /// external rendering or compositing.
+    ///
+    /// Fails if this handle has not been produced by the VA-API backend.
+    fn lend_va_surface(&self) -> anyhow::Result<VaapiSurfaceLease>;
+
+    /// Waits for this handle to be decoded, and exports its VA surface as DMA-BUF file descriptors.
+    ///
+    /// The exported buffers can be shared with other devices, e.g. turned into virtio-gpu blob
+    /// resources by a VMM so the frame can be composited by the host without being copied. The
+    /// layout of the returned frame carries the DRM format modifier of the buffers, which must be
+    /// passed along with them.
+    ///
+    /// As with [`VaapiDecodedHandleExt::lend_va_surface`], the surface will not be reused by the
+    /// decoder for as long as the returned [`VaapiExportedFrame`] is alive.
+    ///
+    /// Fails if this handle has not been produced by the VA-API backend, or if the driver cannot
+    /// export the surface with an explicit modifier.
+    fn export_dmabuf(&self) -> anyhow::Result<VaapiExportedFrame>;
+}
+
+/// A VA surface lent to the client by [`VaapiDecodedHandleExt::lend_va_surface`].
+///
+/// The surface is guaranteed to remain valid and untouched by the decoder while this lease is
+/// alive.
+pub struct VaapiSurfaceLease {
+    /// Handle owning the surface, kept alive so the surface does not return to its pool.
+    _handle: Box<dyn std::any::Any>,
+    display: Rc<Display>,
+    surface_id: libva::VASurfaceID,
+}
+
+impl VaapiSurfaceLease {
+    /// Returns the ID of the lent surface.
+    pub fn surface_id(&self) -> libva::VASurfaceID {
+        self.surface_id
+    }
+
+    /// Returns the VA display the lent surface belongs to.
+    pub fn display(&self) -> &Rc<Display> {
+        &self.display
+    }
+}
+
+/// A VA surface exported as DMA-BUF file descriptors by [`VaapiDecodedHandleExt::export_dmabuf`].
+///
+/// The content of the buffers is guaranteed to remain untouched by the decoder while this object
+/// is alive.
+pub struct VaapiExportedFrame {
+    /// Handle owning the surface, kept alive so the surface does not return to its pool.
+    _handle: Box<dyn std::any::Any>,
+    frame: DmabufFrame,
+}
+
+impl VaapiExportedFrame {
+    /// Returns the exported file descriptors and the layout of the frame within them.
+    pub fn frame(&self) -> &DmabufFrame {
+        &self.frame
+    }
+}
+
+/// DRM format modifier of buffers with a linear layout, which every device can access.
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// Value of the DRM format modifier meaning that the layout of the buffer is implicit, i.e. only
+/// known to the driver that allocated it.
+const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+/// Memory descriptor for frames allocated by the VA driver with one of a set of DRM format
+/// modifiers.
+///
+/// Passing these descriptors to the frame pool of a decoder during format negotiation makes the
+/// driver allocate its surfaces with a layout that the consumer of the frames supports, e.g. linear
+/// buffers, or one of the modifiers advertised by a Wayland compositor through dmabuf feedback, so
+/// the frames can be exported with [`VaapiDecodedHandleExt::export_dmabuf`] and used without
+/// conversion. Use [`query_export_modifiers`] to find out which modifiers the driver supports.
+#[derive(Debug, Clone)]
+pub struct VaapiModifierFrame {
+    modifiers: Vec<u64>,
+}
+
+impl VaapiModifierFrame {
+    /// Creates a descriptor for a frame using one of `modifiers`, in order of preference.
+    pub fn new(modifiers: Vec<u64>) -> Self {
+        Self { modifiers }
+    }
+
+    /// Returns the modifiers the frame can be allocated with.
+    pub fn modifiers(&self) -> &[u64] {
+        &self.modifiers
+    }
+}
+
+impl SurfaceMemoryDescriptor for VaapiModifierFrame {
+    fn add_attrs(
+        &mut self,
+        attrs: &mut Vec<libva::VASurfaceAttrib>,
+    ) -> Option<Box<dyn std::any::Any>> {
+        // The list must outlive the creation of the surface, so it is returned for the caller to
+        // keep.
+        let mut list = Box::new(libva::VADRMFormatModifierList {
+            num_modifiers: self.modifiers.len() as u32,
+            modifiers: self.modifiers.as_mut_ptr(),
+        });
+
+        let mut attrib = libva::VASurfaceAttrib::default();
+        attrib.type_ = libva::VASurfaceAttribType::VASurfaceAttribDRMFormatModifiers;
+        attrib.flags = libva::constants::VA_SURFACE_ATTRIB_SETTABLE;
+        attrib.value.type_ = libva::VAGenericValueType::VAGenericValueTypePointer;
+        attrib.value.value.p = list.as_mut() as *mut libva::VADRMFormatModifierList as *mut _;
+        attrs.push(attrib);
+
+        Some(list)
+    }
+}
+
+/// Returns the modifiers among `candidates` that decoded frames described by `stream_info` can
+/// be allocated and exported with, in the same order.
+///
+/// VA-API does not allow to list the supported modifiers, so each candidate is probed by
+/// allocating and exporting a surface. This is meant to be called during format negotiation, e.g.
+/// with the list of modifiers advertised by the compositor, before allocating the frames using
+/// [`VaapiModifierFrame`].
+pub fn query_export_modifiers(
+    display: &Rc<Display>,
+    stream_info: &StreamInfo,
+    candidates: &[u64],
+) -> anyhow::Result<Vec<u64>> {
+    let format_map = FORMAT_MAP
+        .iter()
+        .find(|map| map.decoded_format == stream_info.format)
+        .ok_or_else(|| anyhow!("format {:?} is not supported", stream_info.format))?;
+    let resolution = stream_info.coded_resolution;
+
+    let supported = candidates
+        .iter()
+        .copied()
+        .filter(|&modifier| {
+            let surfaces = match display.create_surfaces(
+                format_map.rt_format,
+                None,
+                resolution.width,
+                resolution.height,
+                Some(libva::UsageHint::USAGE_HINT_DECODER),
+                vec![VaapiModifierFrame::new(vec![modifier])],
+            ) {
+                Ok(surfaces) => surfaces,
+                // The driver cannot allocate surfaces with this modifier.
+                Err(_) => return false,
+            };
+
+            // Drivers may ignore modifiers they do not support, so check the actual one.
+            surfaces.first().map_or(false, |surface| {
+                surface.export_prime().map_or(false, |descriptor| {
+                    descriptor
+                        .objects
+                        .iter()
+                        .all(|object| object.drm_format_modifier == modifier)
+                })
+            })
+        })
+        .collect();
+
+    Ok(supported)
+}
+
+/// Opens a VA display on the DRM device behind `fd`, e.g. a render node already opened by the
+/// application or handed over by a compositor.
+///
+/// The device is reopened through procfs, so the display does not hold onto `fd` and the caller
+/// can close it afterwards. The returned display can be shared by any number of decoders and
+/// post-processing pipelines.
+///
+/// Displays obtained from a window system (X11 or Wayland) cannot be used directly as
+/// [`libva::Display`] only wraps displays it has opened itself. Their DRM device can be used
+/// instead, e.g. the one advertised by the `wl_drm` or `zwp_linux_dmabuf_feedback_v1` Wayland
+/// protocols.
+pub fn open_drm_display(fd: BorrowedFd) -> anyhow::Result<Rc<Display>> {
+    let path = format!("/proc/self/fd/{}", fd.as_raw_fd());
+
+    Display::open_drm_display(&path)
+        .with_context(|| format!("failed to open a VA display on DRM device {}", path))
+}
+
+/// Hint about how the driver should trade decoding quality and speed for power consumption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PowerHint {
+    /// Let the driver decide.
+    #[default]
+    Default,
+    /// Save power, e.g. on a laptop running on battery, at the expense of decoding speed.
+    PowerSaving,
+    /// Decode as fast as possible, regardless of power consumption.
+    Quality,
+}
+
+/// Returns the configuration attributes conveying `hint` to the driver for `profile` and
+/// `entrypoint`.
+///
+/// VA has no quality levels for decoding, so the hint is passed as the priority of the context,
+/// which lets the driver schedule it and pick clock frequencies accordingly. Nothing is returned
+/// if the driver does not support context priorities.
+fn power_hint_attribs(
+    display: &Display,
+    hint: PowerHint,
+    profile: i32,
+    entrypoint: u32,
+) -> anyhow::Result<Vec<VAConfigAttrib>> {
+    if hint == PowerHint::Default {
+        return Ok(vec![]);
+    }
+
+    let mut attrs = vec![VAConfigAttrib {
+        type_: VAConfigAttribType::VAConfigAttribContextPriority,
+        value: 0,
+    }];
+    display.get_config_attributes(profile, entrypoint, &mut attrs)?;
+
+    // The attribute reports the highest priority the driver supports.
+    let max_priority = attrs[0].value;
+    if max_priority == libva::constants::VA_ATTRIB_NOT_SUPPORTED {
+        log::debug!(
+            "the driver does not support context priorities, ignoring {:?}",
+            hint
+        );
+        return Ok(vec![]);
+    }
+
+    attrs[0].value = match hint {
+        PowerHint::Default => unreachable!(),
+        PowerHint::PowerSaving => 0,
+        PowerHint::Quality => max_priority,
+    };
+
+    Ok(attrs)
+}
+
+/// Builds the description of a frame exported as `descriptor`.
+///
+/// The surface must have been exported with composed layers, i.e. as a single layer containing
+/// all the planes of the frame.
+fn dmabuf_frame_from_prime(
+    descriptor: libva::DrmPrimeSurfaceDescriptor,
+) -> anyhow::Result<DmabufFrame> {
+    let layer = match descriptor.layers.as_slice() {
+        [layer] => layer,
+        layers => return Err(anyhow!("expected 1 exported layer, got {}", layers.len())),
+    };
+
+    // A single layout can only describe objects sharing the same modifier.
+    let modifier = match descriptor.objects.first() {
+        Some(object) => object.drm_format_modifier,
+        None => return Err(anyhow!("no object exported for the surface")),
+    };
+    if descriptor
+        .objects
+        .iter()
+        .any(|object| object.drm_format_modifier != modifier)
+    {
+        return Err(anyhow!("exported objects have different modifiers"));
+    }
+    // Buffers with an implicit layout cannot be interpreted by other devices.
+    if modifier == DRM_FORMAT_MOD_INVALID {
+        return Err(anyhow!(
+            "the driver did not report the modifier of the exported surface"
+        ));
+    }
+
+    let num_planes = layer.num_planes as usize;
+    let planes = (0..num_planes)
+        .map(|i| {
+            let buffer_index = usize::from(layer.object_index[i]);
+            let offset = layer.offset[i] as usize;
+            let object = descriptor
+                .objects
+                .get(buffer_index)
+                .ok_or_else(|| anyhow!("plane {} refers to invalid object {}", i, buffer_index))?;
+
+            // The plane extends up to the next plane of the same object, or to the end of the
+            // object.
+            let end = (0..num_planes)
+                .filter(|&j| usize::from(layer.object_index[j]) == buffer_index)
+                .map(|j| layer.offset[j] as usize)
+                .filter(|&other_offset| other_offset > offset)
+                .min()
+                .unwrap_or(object.size as usize);
+
+            Ok(PlaneLayout {
+                buffer_index,
+                offset,
+                stride: layer.pitch[i] as usize,
+                size: end.saturating_sub(offset),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(DmabufFrame {
+        fds: descriptor
+            .objects
+            .into_iter()
+            .map(|object| object.fd)
+            .collect(),
+        layout: FrameLayout {
+            format: (Fourcc::from(layer.drm_format), modifier),
+            size: Resolution::from((descriptor.width, descriptor.height)),
+            planes,
+        },
+    })
+}
+
+impl<M: SurfaceMemoryDescriptor + 'static> VaapiDecodedHandleExt
+    for dyn DecodedHandleTrait<Descriptor = M>
+{
+    fn va_surface_id(&self) -> Option<libva::VASurfaceID> {
+        self.as_any()
+            .downcast_ref::<DecodedHandle<M>>()
+            .map(|handle| handle.borrow().surface_id())
+    }
+
+    fn lend_va_surface(&self) -> anyhow::Result<VaapiSurfaceLease> {
+        let handle = self
+            .as_any()
+            .downcast_ref::<DecodedHandle<M>>()
+            .ok_or_else(|| anyhow!("handle has not been produced by the VA-API backend"))?;
+
+        handle.sync()?;
+
+        let (display, surface_id) = {
+            let inner = handle.borrow();
+            (Rc::clone(&inner.display), inner.surface_id())
+        };
+
+        Ok(VaapiSurfaceLease {
+            _handle: Box::new(Rc::clone(handle)),
+            display,
+            surface_id,
+        })
+    }
+
+    fn export_dmabuf(&self) -> anyhow::Result<VaapiExportedFrame> {
+        let handle = self
+            .as_any()
+            .downcast_ref::<DecodedHandle<M>>()
+            .ok_or_else(|| anyhow!("handle has not been produced by the VA-API backend"))?;
+
+        handle.sync()?;
+
+        let frame = handle.borrow().export_dmabuf()?;
+
+        Ok(VaapiExportedFrame {
+            _handle: Box::new(Rc::clone(handle)),
+            frame,
+        })
+    }
 }
 
 mod surface_pool {
@@ -311,6 +843,8 @@ mod surface_pool {
     pub(crate) struct SurfacePool<M: SurfaceMemoryDescriptor> {
         display: Rc<Display>,
         rt_format: u32,
+        /// Fourcc of the surfaces, or `None` to let the driver pick the best one.
+        fourcc: Option<u32>,
         usage_hint: Option<libva::UsageHint>,
         coded_resolution: Resolution,
         surfaces: VecDeque<Surface<M>>,
@@ -338,6 +872,7 @@ mod surface_pool {
             Self {
                 display,
                 rt_format,
+                fourcc: None,
                 usage_hint,
                 coded_resolution,
                 surfaces: VecDeque::new(),
@@ -345,13 +880,19 @@ mod surface_pool {
             }
         }
 
+        /// Sets the fourcc of the surfaces created from now on. Decoders leave it unset, so the
+        /// hardware picks its preferred internal format and we get the desired fourcc when
+        /// creating images.
+        pub(crate) fn with_fourcc(mut self, fourcc: u32) -> Self {
+            self.fourcc = Some(fourcc);
+            self
+        }
+
         /// Create new surfaces and add them to the pool, using `descriptors` as backing memory.
         pub(crate) fn add_surfaces(&mut self, descriptors: Vec<M>) -> Result<(), VaError> {
             let surfaces = self.display.create_surfaces(
                 self.rt_format,
-                // Let the hardware decide the best internal format - we will get the desired fourcc
-                // when creating the image.
-                None,
+                self.fourcc,
                 self.coded_resolution.width,
                 self.coded_resolution.height,
                 self.usage_hint,
@@ -480,12 +1021,13 @@ pub(crate) trait VaStreamInfo {
     fn va_profile(&self) -> anyhow::Result<i32>;
     /// Returns the RT format of the stream.
     fn rt_format(&self) -> anyhow::Result<u32>;
-    /// Returns the minimum number of surfaces required to decode the stream.
-    fn min_num_surfaces(&self) -> usize;
+    /// Returns the minimum number of surfaces required to decode the stream, and how it has been
+    /// derived.
+    fn min_num_surfaces(&self) -> NumFramesBreakdown;
     /// Returns the coded size of the surfaces required to decode the stream.
-    fn coded_size(&self) -> (u32, u32);
+    fn coded_size(&self) -> Resolution;
     /// Returns the visible rectangle within the coded size for the stream.
-    fn visible_rect(&self) -> ((u32, u32), (u32, u32));
+    fn visible_rect(&self) -> Rect<u32>;
 }
 
 pub(crate) struct ParsedStreamMetadata {
@@ -502,6 +1044,9 @@ pub(crate) struct ParsedStreamMetadata {
     /// mapping in a different format if requested and if the VA-API driver can
     /// do it.
     map_format: Rc<libva::VAImageFormat>,
+    /// The format frames are read into when mapped, as several formats can share the same
+    /// `map_format`.
+    decoded_format: DecodedFormat,
     /// The rt_format parsed from the stream.
     rt_format: u32,
     /// The profile parsed from the stream.
@@ -536,28 +1081,39 @@ impl StreamMetadataState {
         old_metadata_state: StreamMetadataState,
         old_surface_pool: Rc<RefCell<SurfacePool<M>>>,
         supports_context_reuse: bool,
+        driver_info: &DriverInfo,
+        power_hint: PowerHint,
     ) -> anyhow::Result<(StreamMetadataState, Rc<RefCell<SurfacePool<M>>>)> {
-        let va_profile = hdr.va_profile()?;
-        let rt_format = hdr.rt_format()?;
+        let va_profile = hdr
+            .va_profile()
+            .map_err(StatelessBackendError::UnsupportedProfile)?;
+        let rt_format = hdr
+            .rt_format()
+            .map_err(StatelessBackendError::UnsupportedProfile)?;
+
+        let coded_resolution = hdr.coded_size().round(crate::ResolutionRoundMode::Even);
 
-        let coded_resolution =
-            Resolution::from(hdr.coded_size()).round(crate::ResolutionRoundMode::Even);
+        let image_formats = driver_info.filter_image_formats(display.query_image_formats()?);
 
         let format_map = if let Some(format_map) = format_map {
             format_map
         } else {
-            // Pick the first one that fits
+            // Pick the first one that fits and that the hardware can map into.
             FORMAT_MAP
                 .iter()
-                .find(|&map| map.rt_format == rt_format)
-                .ok_or(anyhow!(
-                    "format {} is not supported by your hardware or by the implementation for the current codec",
-                    va_rt_format_to_string(rt_format)
-                ))?
+                .find(|&map| {
+                    map.rt_format == rt_format
+                        && image_formats.iter().any(|f| f.fourcc == map.va_fourcc)
+                })
+                .ok_or_else(|| {
+                    StatelessBackendError::UnsupportedProfile(anyhow!(
+                        "format {} is not supported by your hardware or by the implementation for the current codec",
+                        va_rt_format_to_string(rt_format)
+                    ))
+                })?
         };
 
-        let map_format = display
-            .query_image_formats()?
+        let map_format = image_formats
             .iter()
             .find(|f| f.fourcc == format_map.va_fourcc)
             .cloned()
@@ -568,14 +1124,11 @@ impl StreamMetadataState {
                 )
             })?;
 
-        let min_num_surfaces = hdr.min_num_surfaces();
+        let mut min_num_surfaces = hdr.min_num_surfaces();
+        min_num_surfaces.extra += driver_info.extra_surfaces();
 
         let visible_rect = hdr.visible_rect();
-
-        let display_resolution = Resolution {
-            width: visible_rect.1 .0 - visible_rect.0 .0,
-            height: visible_rect.1 .1 - visible_rect.0 .1,
-        };
+        let display_resolution = visible_rect.size();
 
         let (config, context, surface_pool) = match old_metadata_state {
             // Nothing has changed for VAAPI, reuse current context.
@@ -601,22 +1154,30 @@ impl StreamMetadataState {
             }
             // Create new context.
             _ => {
-                let config = display.create_config(
-                    vec![libva::VAConfigAttrib {
-                        type_: libva::VAConfigAttribType::VAConfigAttribRTFormat,
-                        value: rt_format,
-                    }],
+                let mut attrs = vec![libva::VAConfigAttrib {
+                    type_: libva::VAConfigAttribType::VAConfigAttribRTFormat,
+                    value: rt_format,
+                }];
+                attrs.extend(power_hint_attribs(
+                    display,
+                    power_hint,
                     va_profile,
                     libva::VAEntrypoint::VAEntrypointVLD,
-                )?;
-
-                let context = display.create_context::<M>(
-                    &config,
-                    coded_resolution.width,
-                    coded_resolution.height,
-                    None,
-                    true,
-                )?;
+                )?);
+
+                let config = display
+                    .create_config(attrs, va_profile, libva::VAEntrypoint::VAEntrypointVLD)
+                    .map_err(va_setup_error)?;
+
+                let context = display
+                    .create_context::<M>(
+                        &config,
+                        coded_resolution.width,
+                        coded_resolution.height,
+                        None,
+                        true,
+                    )
+                    .map_err(va_setup_error)?;
 
                 let surface_pool = Rc::new(RefCell::new(SurfacePool::new(
                     Rc::clone(display),
@@ -665,9 +1226,12 @@ impl StreamMetadataState {
                     },
                     coded_resolution,
                     display_resolution,
-                    min_num_frames: min_num_surfaces,
+                    display_offset: (visible_rect.min.x, visible_rect.min.y),
+                    min_num_frames: min_num_surfaces.total(),
+                    num_frames_breakdown: min_num_surfaces,
                 },
                 map_format: Rc::new(map_format),
+                decoded_format: format_map.decoded_format,
                 rt_format,
                 profile: va_profile,
             }),
@@ -687,46 +1251,174 @@ pub struct VaapiDecodedHandle<M: SurfaceMemoryDescriptor> {
     coded_resolution: Resolution,
     /// Actual resolution of the visible rectangle in the decoded buffer.
     display_resolution: Resolution,
+    /// Position of the visible rectangle in the decoded buffer.
+    display_offset: (u32, u32),
     /// Image format for this surface, taken from the pool it originates from.
     map_format: Rc<libva::VAImageFormat>,
+    /// Format the image is converted to when read.
+    decoded_format: DecodedFormat,
+    /// Instant at which the picture has been submitted to the hardware.
+    submitted_at: Instant,
+    /// Running estimate of the time it takes to decode a picture, shared with the backend.
+    decode_time: Rc<Cell<DecodeTimeEstimator>>,
+    /// VA display the surface of this handle belongs to.
+    display: Rc<Display>,
+    /// Handle this picture has been produced from, e.g. by video post-processing, kept alive until
+    /// this picture is ready so its surface is not reused in the meantime.
+    source: Option<Box<dyn std::any::Any>>,
+    /// Whether the picture has been decoded within a protected session, in which case its content
+    /// cannot be accessed by the CPU.
+    protected: bool,
 }
 
 impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
     /// Creates a new pending handle on `surface_id`.
+    ///
+    /// If `protected_session` is set, the picture is decoded within it and `encryption` contains
+    /// the parameters needed to decrypt it, if it is encrypted.
     fn new(
         picture: Picture<PictureNew, PooledSurface<M>>,
         metadata: &ParsedStreamMetadata,
-    ) -> anyhow::Result<Self> {
-        let picture = picture.begin()?.render()?.end()?;
+        decode_time: Rc<Cell<DecodeTimeEstimator>>,
+        display: Rc<Display>,
+        protected_session: Option<&ProtectedSession>,
+        encryption: Option<EncryptionParams>,
+    ) -> StatelessBackendResult<Self> {
+        let picture = picture.begin().map_err(va_error)?;
+        if let (Some(session), Some(params)) = (protected_session, encryption) {
+            params
+                .render(&display, &metadata.context, session)
+                .map_err(StatelessBackendError::Other)?;
+        }
+        let picture = picture
+            .render()
+            .and_then(|picture| picture.end())
+            .map_err(va_error)?;
         Ok(Self {
             state: PictureState::Pending(picture),
             coded_resolution: metadata.stream_info.coded_resolution,
             display_resolution: metadata.stream_info.display_resolution,
+            display_offset: metadata.stream_info.display_offset,
             map_format: Rc::clone(&metadata.map_format),
+            decoded_format: metadata.decoded_format,
+            submitted_at: Instant::now(),
+            decode_time,
+            display,
+            source: None,
+            protected: protected_session.is_some(),
         })
     }
 
+    /// Sets the visible part of the decoded buffer, for frames smaller than the stream's display
+    /// resolution.
+    pub(crate) fn set_display_resolution(&mut self, resolution: Resolution) {
+        self.display_resolution = resolution;
+    }
+
     fn sync(&mut self) -> Result<(), VaError> {
         let res;
 
         (self.state, res) = match std::mem::replace(&mut self.state, PictureState::Invalid) {
             state @ PictureState::Ready(_) => (state, Ok(())),
-            PictureState::Pending(picture) => match picture.sync() {
-                Ok(picture) => (PictureState::Ready(picture), Ok(())),
-                Err((e, picture)) => (PictureState::Pending(picture), Err(e)),
-            },
+            PictureState::Pending(picture) => {
+                // Only a sync that blocks until the picture is complete tells us when it
+                // completed, so only sample the decode time if the picture was still in progress.
+                let in_progress = matches!(
+                    picture.surface().query_status(),
+                    Ok(status) if status != libva::VASurfaceStatus::VASurfaceReady
+                );
+
+                match picture.sync() {
+                    Ok(picture) => {
+                        if in_progress {
+                            self.add_decode_time_sample();
+                        }
+                        self.source = None;
+                        (PictureState::Ready(picture), Ok(()))
+                    }
+                    Err((e, picture)) => (PictureState::Pending(picture), Err(e)),
+                }
+            }
             PictureState::Invalid => unreachable!(),
         };
 
         res
     }
 
+    /// Waits until the picture is complete or `timeout` has elapsed, whichever comes first, and
+    /// returns whether it is complete.
+    ///
+    /// The wait blocks in the driver, so the decode time of the picture can be sampled like in
+    /// [`VaapiDecodedHandle::sync`]. Drivers that do not support waiting with a timeout are polled
+    /// instead.
+    fn wait_ready(&mut self, timeout: Duration) -> anyhow::Result<bool> {
+        let (surface_id, in_progress) = match &self.state {
+            PictureState::Ready(_) => return Ok(true),
+            PictureState::Pending(picture) => (
+                picture.surface().id(),
+                matches!(
+                    picture.surface().query_status(),
+                    Ok(status) if status != libva::VASurfaceStatus::VASurfaceReady
+                ),
+            ),
+            PictureState::Invalid => unreachable!(),
+        };
+
+        let timeout_ns = u64::try_from(timeout.as_nanos()).unwrap_or(u64::MAX);
+        // Safe because `surface_id` belongs to `self.display` and is kept alive by `self.state`.
+        let status =
+            unsafe { libva::vaSyncSurface2(self.display.handle(), surface_id, timeout_ns) };
+        if status == libva::constants::VA_STATUS_ERROR_TIMEDOUT as libva::VAStatus {
+            return Ok(false);
+        }
+        if status == libva::constants::VA_STATUS_ERROR_UNIMPLEMENTED as libva::VAStatus {
+            if !poll_ready(|| self.is_va_ready().unwrap_or(true), timeout) {
+                return Ok(false);
+            }
+        } else {
+            va_check(status, "vaSyncSurface2")?;
+            if in_progress {
+                self.add_decode_time_sample();
+            }
+        }
+
+        // The picture is complete, so this only moves it to the ready state without sampling it
+        // again.
+        self.sync().map_err(va_error)?;
+
+        Ok(true)
+    }
+
+    /// Adds the time between the submission of this picture and now to the decode time estimate.
+    ///
+    /// Must only be called right after a wait for the picture to complete has returned.
+    fn add_decode_time_sample(&self) {
+        let mut decode_time = self.decode_time.get();
+        decode_time.add_sample(self.submitted_at, Instant::now());
+        self.decode_time.set(decode_time);
+    }
+
+    /// Returns the estimated instant at which this picture will be ready, based on the decode
+    /// time of previous pictures.
+    ///
+    /// The estimate is only updated by syncs that block until a picture is complete, so clients
+    /// that only sync pictures after polling them as ready never get one.
+    fn estimated_completion(&self) -> Option<Instant> {
+        self.decode_time
+            .get()
+            .estimated_completion(self.submitted_at)
+    }
+
     /// Returns a mapped VAImage. this maps the VASurface onto our address space.
     /// This can be used in place of "DynMappableHandle::map()" if the client
     /// wants to access the backend mapping directly for any reason.
     ///
     /// Note that DynMappableHandle is downcastable.
     fn image(&self) -> anyhow::Result<Image> {
+        if self.protected {
+            return Err(anyhow!("protected pictures cannot be mapped"));
+        }
+
         match &self.state {
             PictureState::Ready(picture) => {
                 // Map the VASurface onto our address space.
@@ -754,6 +1446,19 @@ impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
         }
     }
 
+    /// Exports the surface of this handle as DMA-BUF file descriptors. The picture must be ready.
+    fn export_dmabuf(&self) -> anyhow::Result<DmabufFrame> {
+        let picture = self
+            .picture()
+            .ok_or_else(|| anyhow!("picture is not in Ready state"))?;
+        let descriptor = picture
+            .surface()
+            .export_prime()
+            .context("failed to export the VA surface")?;
+
+        dmabuf_frame_from_prime(descriptor)
+    }
+
     /// Returns the timestamp of this handle.
     fn timestamp(&self) -> u64 {
         match &self.state {
@@ -772,6 +1477,21 @@ impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
         }
     }
 
+    /// Returns whether the driver reported macroblock errors while decoding this picture.
+    ///
+    /// Errors can only be known once the picture is ready, so this always returns `false` for
+    /// pending pictures.
+    fn has_va_decode_errors(&self) -> Result<bool, VaError> {
+        match &self.state {
+            PictureState::Ready(picture) => picture
+                .surface()
+                .query_error()
+                .map(|errors| !errors.is_empty()),
+            PictureState::Pending(_) => Ok(false),
+            PictureState::Invalid => unreachable!(),
+        }
+    }
+
     fn is_va_ready(&self) -> Result<bool, VaError> {
         match &self.state {
             PictureState::Ready(_) => Ok(true),
@@ -786,7 +1506,86 @@ impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
 
 impl<'a, M: SurfaceMemoryDescriptor> DynHandle for std::cell::Ref<'a, VaapiDecodedHandle<M>> {
     fn dyn_mappable_handle<'b>(&'b self) -> anyhow::Result<Box<dyn MappableHandle + 'b>> {
-        self.image().map(|i| Box::new(i) as Box<dyn MappableHandle>)
+        let display_offset = self.display_offset;
+        let decoded_format = self.decoded_format;
+
+        self.image().map(|image| {
+            Box::new(VaapiMapping {
+                image,
+                display_offset,
+                decoded_format,
+            }) as Box<dyn MappableHandle>
+        })
+    }
+}
+
+/// CPU mapping of a VA picture, limited to its visible rectangle.
+struct VaapiMapping<'a> {
+    image: Image<'a>,
+    /// Position of the visible rectangle in `image`.
+    display_offset: (u32, u32),
+    /// Format the image is converted to when read.
+    decoded_format: DecodedFormat,
+}
+
+impl<'a> VaapiMapping<'a> {
+    /// Returns the layout of the visible rectangle of the image, i.e. with its planes moved to the
+    /// top-left corner of the rectangle.
+    fn image_layout(&self) -> FrameLayout {
+        let image_inner = self.image.image();
+        let num_planes = (image_inner.num_planes as usize).min(image_inner.offsets.len());
+        let (x, y) = (
+            self.display_offset.0 as usize,
+            self.display_offset.1 as usize,
+        );
+
+        // Offset of the visible rectangle in each plane, as a number of bytes within a line and
+        // a number of lines.
+        let plane_offsets: &[(usize, usize)] = match image_inner.format.fourcc {
+            libva::constants::VA_FOURCC_NV12 => &[(x, y), (x, y / 2)],
+            libva::constants::VA_FOURCC_I420 => &[(x, y), (x / 2, y / 2), (x / 2, y / 2)],
+            libva::constants::VA_FOURCC_422H => &[(x, y), (x / 2, y), (x / 2, y)],
+            libva::constants::VA_FOURCC_444P => &[(x, y), (x, y), (x, y)],
+            libva::constants::VA_FOURCC_P010 | libva::constants::VA_FOURCC_P012 => {
+                &[(x * 2, y), (x * 2, y / 2)]
+            }
+            libva::constants::VA_FOURCC_YUY2 => &[(x * 2, y)],
+            libva::constants::VA_FOURCC_Y210
+            | libva::constants::VA_FOURCC_Y212
+            | libva::constants::VA_FOURCC_Y410 => &[(x * 4, y)],
+            libva::constants::VA_FOURCC_Y412 => &[(x * 8, y)],
+            _ => &[],
+        };
+
+        let planes = (0..num_planes)
+            .map(|plane| {
+                let start = image_inner.offsets[plane] as usize;
+                let stride = image_inner.pitches[plane] as usize;
+                // The plane extends until the next one, or the end of the image.
+                let end = image_inner.offsets[..num_planes]
+                    .iter()
+                    .map(|&offset| offset as usize)
+                    .filter(|&offset| offset > start)
+                    .min()
+                    .unwrap_or(image_inner.data_size as usize);
+                let (line_offset, num_lines) =
+                    plane_offsets.get(plane).copied().unwrap_or_default();
+                let offset = start + num_lines * stride + line_offset;
+
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset,
+                    stride,
+                    size: end.saturating_sub(offset),
+                }
+            })
+            .collect();
+
+        FrameLayout {
+            format: (Fourcc::from(image_inner.format.fourcc), 0),
+            size: Resolution::from(self.image.display_resolution()),
+            planes,
+        }
     }
 }
 
@@ -798,12 +1597,12 @@ enum PictureState<M: SurfaceMemoryDescriptor> {
     Invalid,
 }
 
-impl<'a> MappableHandle for Image<'a> {
+impl<'a> MappableHandle for VaapiMapping<'a> {
     fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<()> {
-        let image_size = self.image_size();
-        let image_inner = self.image();
+        let image_size = self.image_size()?;
+        let image_inner = self.image.image();
 
-        let display_resolution = self.display_resolution();
+        let display_resolution = self.image.display_resolution();
         let width = display_resolution.0 as usize;
         let height = display_resolution.1 as usize;
 
@@ -815,63 +1614,57 @@ impl<'a> MappableHandle for Image<'a> {
             ));
         }
 
-        let pitches = image_inner.pitches.map(|x| x as usize);
-        let offsets = image_inner.offsets.map(|x| x as usize);
+        let src_layout = self.image_layout();
 
         match image_inner.format.fourcc {
-            libva::constants::VA_FOURCC_NV12 => {
-                nv12_copy(self.as_ref(), buffer, width, height, pitches, offsets);
+            libva::constants::VA_FOURCC_NV12 if self.decoded_format == DecodedFormat::NV12 => {
+                nv12_copy(self.image.as_ref(), &src_layout, buffer);
             }
-            libva::constants::VA_FOURCC_I420 => {
-                i4xx_copy(
-                    self.as_ref(),
+            libva::constants::VA_FOURCC_NV12 => {
+                let mut nv12 =
+                    vec![0; crate::decoded_frame_size(DecodedFormat::NV12, width, height)];
+                nv12_copy(self.image.as_ref(), &src_layout, &mut nv12);
+                convert::convert(
+                    DecodedFormat::NV12,
+                    &nv12,
+                    self.decoded_format,
                     buffer,
                     width,
                     height,
-                    pitches,
-                    offsets,
-                    (true, true),
-                );
+                )?;
+            }
+            libva::constants::VA_FOURCC_I420 => {
+                i4xx_copy(self.image.as_ref(), &src_layout, buffer, (true, true));
             }
             libva::constants::VA_FOURCC_422H => {
-                i4xx_copy(
-                    self.as_ref(),
-                    buffer,
-                    width,
-                    height,
-                    pitches,
-                    offsets,
-                    (true, false),
-                );
+                i4xx_copy(self.image.as_ref(), &src_layout, buffer, (true, false));
             }
             libva::constants::VA_FOURCC_444P => {
-                i4xx_copy(
-                    self.as_ref(),
-                    buffer,
-                    width,
-                    height,
-                    pitches,
-                    offsets,
-                    (false, false),
-                );
+                i4xx_copy(self.image.as_ref(), &src_layout, buffer, (false, false));
+            }
+            libva::constants::VA_FOURCC_YUY2 => {
+                yuy2_to_i422(self.image.as_ref(), &src_layout, buffer);
+            }
+            libva::constants::VA_FOURCC_P010 if self.decoded_format == DecodedFormat::P010 => {
+                p010_copy(self.image.as_ref(), &src_layout, buffer);
             }
             libva::constants::VA_FOURCC_P010 => {
-                p01x_to_i01x(self.as_ref(), buffer, 10, width, height, pitches, offsets);
+                p01x_to_i01x(self.image.as_ref(), &src_layout, buffer, 10);
             }
             libva::constants::VA_FOURCC_P012 => {
-                p01x_to_i01x(self.as_ref(), buffer, 12, width, height, pitches, offsets);
+                p01x_to_i01x(self.image.as_ref(), &src_layout, buffer, 12);
             }
             libva::constants::VA_FOURCC_Y210 => {
-                y21x_to_i21x(self.as_ref(), buffer, 10, width, height, pitches, offsets);
+                y21x_to_i21x(self.image.as_ref(), &src_layout, buffer, 10);
             }
             libva::constants::VA_FOURCC_Y212 => {
-                y21x_to_i21x(self.as_ref(), buffer, 12, width, height, pitches, offsets);
+                y21x_to_i21x(self.image.as_ref(), &src_layout, buffer, 12);
             }
             libva::constants::VA_FOURCC_Y410 => {
-                y410_to_i410(self.as_ref(), buffer, width, height, pitches, offsets);
+                y410_to_i410(self.image.as_ref(), &src_layout, buffer);
             }
             libva::constants::VA_FOURCC_Y412 => {
-                y412_to_i412(self.as_ref(), buffer, width, height, pitches, offsets);
+                y412_to_i412(self.image.as_ref(), &src_layout, buffer);
             }
             _ => return Err(StatelessBackendError::UnsupportedFormat.into()),
         }
@@ -879,14 +1672,85 @@ impl<'a> MappableHandle for Image<'a> {
         Ok(())
     }
 
-    fn image_size(&mut self) -> usize {
-        let image = self.image();
-        let display_resolution = self.display_resolution();
-        crate::decoded_frame_size(
-            (&image.format).try_into().unwrap(),
+    fn layout(&mut self) -> anyhow::Result<FrameLayout> {
+        let display_resolution = self.image.display_resolution();
+        Ok(crate::decoded_frame_layout(
+            self.decoded_format,
             display_resolution.0 as usize,
             display_resolution.1 as usize,
-        )
+        ))
+    }
+
+    fn read_plane(&mut self, plane: usize, buffer: &mut [u8], stride: usize) -> anyhow::Result<()> {
+        let fourcc = self.image.image().format.fourcc;
+        let format = self.decoded_format;
+
+        let display_resolution = self.image.display_resolution();
+        let width = display_resolution.0 as usize;
+        let height = display_resolution.1 as usize;
+
+        let (line_size, num_lines) = crate::decoded_plane_size(format, plane, width, height)
+            .ok_or_else(|| anyhow!("plane {} does not exist in format {:?}", plane, format))?;
+
+        if stride < line_size || buffer.len() < stride * num_lines.saturating_sub(1) + line_size {
+            return Err(anyhow!(
+                "buffer of size {} with stride {} cannot contain plane of {} lines of {} bytes",
+                buffer.len(),
+                stride,
+                num_lines,
+                line_size
+            ));
+        }
+
+        match (fourcc, format) {
+            // These formats are mapped without any conversion, so we can copy the plane directly
+            // from the mapping.
+            (
+                libva::constants::VA_FOURCC_I420
+                | libva::constants::VA_FOURCC_422H
+                | libva::constants::VA_FOURCC_444P,
+                _,
+            )
+            | (libva::constants::VA_FOURCC_NV12, DecodedFormat::NV12)
+            | (libva::constants::VA_FOURCC_P010, DecodedFormat::P010) => {
+                let layout = self.image_layout();
+                let src_plane = layout.planes.get(plane).ok_or_else(|| {
+                    anyhow!(
+                        "plane {} does not exist in image with {} planes",
+                        plane,
+                        layout.planes.len()
+                    )
+                })?;
+
+                crate::plane_copy(
+                    &self.image.as_ref()[src_plane.offset..],
+                    src_plane.stride,
+                    buffer,
+                    stride,
+                    line_size,
+                    num_lines,
+                );
+            }
+            // Other formats need to be converted, so read the whole frame and extract the plane
+            // from it.
+            _ => {
+                let mut frame = vec![0; self.image_size()?];
+                self.read(&mut frame)?;
+
+                let plane_offset = self.layout()?.planes[plane].offset;
+
+                crate::plane_copy(
+                    &frame[plane_offset..],
+                    line_size,
+                    buffer,
+                    stride,
+                    line_size,
+                    num_lines,
+                );
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -897,6 +1761,10 @@ impl TryFrom<&libva::VAImageFormat> for DecodedFormat {
         match value.fourcc {
             libva::constants::VA_FOURCC_I420 => Ok(DecodedFormat::I420),
             libva::constants::VA_FOURCC_NV12 => Ok(DecodedFormat::NV12),
+            libva::constants::VA_FOURCC_422H | libva::constants::VA_FOURCC_YUY2 => {
+                Ok(DecodedFormat::I422)
+            }
+            libva::constants::VA_FOURCC_444P => Ok(DecodedFormat::I444),
             libva::constants::VA_FOURCC_P010 => Ok(DecodedFormat::I010),
             libva::constants::VA_FOURCC_P012 => Ok(DecodedFormat::I012),
             libva::constants::VA_FOURCC_Y210 => Ok(DecodedFormat::I210),
@@ -921,6 +1789,21 @@ where
     /// Whether the codec supports context reuse on DRC. This is only supported
     /// by VP9 and AV1.
     supports_context_reuse: bool,
+    /// Running estimate of the time it takes to decode a picture, updated by the handles we
+    /// create.
+    decode_time: Rc<Cell<DecodeTimeEstimator>>,
+    /// Protected session the pictures are decoded within, if the stream is encrypted.
+    protected_session: Option<Rc<ProtectedSession>>,
+    /// Context the protected session is currently attached to.
+    attached_context: Weak<Context>,
+    /// Parameters needed to decrypt the pictures, indexed by their timestamp.
+    encryption_params: BTreeMap<u64, EncryptionParams>,
+    /// Driver behind `display`, along with the quirks to work around.
+    driver_info: DriverInfo,
+    /// Power hint given to the driver when creating contexts.
+    power_hint: PowerHint,
+    /// Recorder of the buffers submitted for each picture, if a trace is in progress.
+    trace: Option<RefCell<TraceRecorder>>,
 }
 
 impl<M> VaapiBackend<M>
@@ -935,15 +1818,114 @@ where
             Some(libva::UsageHint::USAGE_HINT_DECODER),
             Resolution::from((16, 16)),
         )));
+        let driver_info = DriverInfo::query(&display);
 
         Self {
             display,
             surface_pool,
             metadata_state: StreamMetadataState::Unparsed,
             supports_context_reuse,
+            decode_time: Default::default(),
+            protected_session: None,
+            attached_context: Weak::new(),
+            encryption_params: Default::default(),
+            driver_info,
+            power_hint: Default::default(),
+            trace: None,
         }
     }
 
+    /// Returns information about the driver in use and its quirks.
+    pub(crate) fn driver_info(&self) -> &DriverInfo {
+        &self.driver_info
+    }
+
+    /// Sets the power hint given to the driver for the contexts created from now on.
+    pub(crate) fn set_power_hint(&mut self, hint: PowerHint) {
+        self.power_hint = hint;
+    }
+
+    /// Starts recording the buffers submitted for each picture, dropping the trace in progress if
+    /// any.
+    pub(crate) fn start_trace(&mut self) {
+        self.trace = Some(Default::default());
+    }
+
+    /// Stops recording the submitted buffers and returns the trace recorded so far, if any.
+    pub(crate) fn take_trace(&mut self) -> Option<VaTrace> {
+        self.trace
+            .take()
+            .map(|recorder| recorder.into_inner().into_trace())
+    }
+
+    /// Creates a buffer of `context` holding `buffer`, recording its contents if a trace is in
+    /// progress. Codecs must create the buffers of their pictures through this method.
+    pub(crate) fn create_buffer(
+        &self,
+        context: &Rc<Context>,
+        buffer: BufferType,
+    ) -> Result<libva::Buffer, VaError> {
+        if let Some(trace) = &self.trace {
+            trace.borrow_mut().record_buffer(&buffer);
+        }
+
+        context.create_buffer(buffer)
+    }
+
+    /// Sets the protected session the pictures are decoded within, or decodes them in the clear
+    /// if `session` is `None`.
+    pub(crate) fn set_protected_session(
+        &mut self,
+        session: Option<Rc<ProtectedSession>>,
+    ) -> anyhow::Result<()> {
+        if let (Some(old_session), Some(context)) = (
+            self.protected_session.take(),
+            self.attached_context.upgrade(),
+        ) {
+            old_session.detach(&context)?;
+        }
+        self.attached_context = Weak::new();
+        self.encryption_params.clear();
+        self.protected_session = session;
+
+        self.attach_protected_session()
+    }
+
+    /// Sets the parameters needed to decrypt the picture with `timestamp`.
+    pub(crate) fn set_encryption_params(
+        &mut self,
+        timestamp: u64,
+        params: EncryptionParams,
+    ) -> anyhow::Result<()> {
+        if self.protected_session.is_none() {
+            return Err(anyhow!("encrypted pictures require a protected session"));
+        }
+
+        self.encryption_params.insert(timestamp, params);
+
+        Ok(())
+    }
+
+    /// Attaches the protected session, if any, to the current context if it is not already.
+    fn attach_protected_session(&mut self) -> anyhow::Result<()> {
+        let (session, metadata) = match (&self.protected_session, &self.metadata_state) {
+            (Some(session), StreamMetadataState::Parsed(metadata)) => (session, metadata),
+            _ => return Ok(()),
+        };
+
+        let attached = self
+            .attached_context
+            .upgrade()
+            .map(|context| Rc::ptr_eq(&context, &metadata.context))
+            .unwrap_or(false);
+        if !attached {
+            session.attach(&metadata.context)?;
+            self.attached_context = Rc::downgrade(&metadata.context);
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn new_sequence<StreamData>(
         &mut self,
         stream_params: &StreamData,
@@ -961,7 +1943,12 @@ where
             old_metadata_state,
             Rc::clone(&self.surface_pool),
             self.supports_context_reuse,
-        )?;
+            &self.driver_info,
+            self.power_hint,
+        )
+        .map_err(backend_error)?;
+
+        self.attach_protected_session()?;
 
         Ok(())
     }
@@ -976,8 +1963,25 @@ where
     {
         let metadata = self.metadata_state.get_parsed()?;
 
+        // Parameters of pictures that have been dropped before reaching us are not needed
+        // anymore.
+        let timestamp = picture.timestamp();
+        self.encryption_params = self.encryption_params.split_off(&timestamp);
+        let encryption = self.encryption_params.remove(&timestamp);
+
+        if let Some(trace) = &self.trace {
+            trace
+                .borrow_mut()
+                .finish_picture(timestamp, picture.surface().id());
+        }
+
         Ok(Rc::new(RefCell::new(VaapiDecodedHandle::new(
-            picture, metadata,
+            picture,
+            metadata,
+            Rc::clone(&self.decode_time),
+            Rc::clone(&self.display),
+            self.protected_session.as_deref(),
+            encryption,
         )?)))
     }
 
@@ -986,19 +1990,19 @@ where
     /// is made. Only formats that are compatible with the current color space,
     /// bit depth, and chroma format are returned such that no conversion is
     /// needed.
-    fn supported_formats_for_stream(&self) -> anyhow::Result<HashSet<DecodedFormat>> {
+    fn supported_formats_for_stream(&self) -> anyhow::Result<HashSet<FormatMap>> {
         let metadata = self.metadata_state.get_parsed()?;
-        let image_formats = self.display.query_image_formats()?;
+        let image_formats = self
+            .driver_info
+            .filter_image_formats(self.display.query_image_formats()?);
 
-        let formats = supported_formats_for_rt_format(
+        supported_formats_for_rt_format(
             &self.display,
             metadata.rt_format,
             metadata.profile,
             libva::VAEntrypoint::VAEntrypointVLD,
             &image_formats,
-        )?;
-
-        Ok(formats.into_iter().map(|f| f.decoded_format).collect())
+        )
     }
 }
 
@@ -1017,20 +2021,18 @@ where
         &mut self,
         format_info: &Codec::FormatInfo,
         format: crate::DecodedFormat,
-    ) -> anyhow::Result<()> {
-        let supported_formats_for_stream = self.supported_formats_for_stream()?;
-
-        if supported_formats_for_stream.contains(&format) {
-            let map_format = FORMAT_MAP
-                .iter()
-                .find(|&map| map.decoded_format == format)
-                .ok_or_else(|| {
-                    anyhow!(
-                        "cannot find corresponding VA format for decoded format {:?}",
-                        format
-                    )
-                })?;
-
+    ) -> Result<(), FormatError> {
+        let supported_formats_for_stream = self
+            .supported_formats_for_stream()
+            .map_err(StatelessBackendError::Other)?;
+
+        // Several VA formats can map to the same decoded format: pick the first one the hardware
+        // supports, in order of preference.
+        let map_format = FORMAT_MAP.iter().find(|&map| {
+            map.decoded_format == format && supported_formats_for_stream.contains(map)
+        });
+
+        if let Some(map_format) = map_format {
             let old_metadata_state =
                 std::mem::replace(&mut self.metadata_state, StreamMetadataState::Unparsed);
 
@@ -1051,11 +2053,17 @@ where
                 old_metadata_state,
                 Rc::clone(&self.surface_pool),
                 self.supports_context_reuse,
-            )?;
+                &self.driver_info,
+                self.power_hint,
+            )
+            .map_err(backend_error)?;
+
+            self.attach_protected_session()
+                .map_err(StatelessBackendError::Other)?;
 
             Ok(())
         } else {
-            Err(anyhow!("Format {:?} is unsupported.", format))
+            Err(FormatError::UnsupportedFormat(format))
         }
     }
 
@@ -1063,6 +2071,50 @@ where
         &mut self.surface_pool
     }
 
+    fn try_change_resolution(&mut self, format_info: &Codec::FormatInfo) -> bool {
+        let metadata = match &mut self.metadata_state {
+            StreamMetadataState::Parsed(metadata) => metadata,
+            StreamMetadataState::Unparsed => return false,
+        };
+
+        let stream_info = &mut metadata.stream_info;
+        let fits_surfaces = stream_info
+            .coded_resolution
+            .can_contain(format_info.coded_size())
+            && format_info.min_num_surfaces().total() <= stream_info.min_num_frames;
+        let same_config = format_info.va_profile().ok() == Some(metadata.profile)
+            && format_info.rt_format().ok() == Some(metadata.rt_format);
+
+        if !fits_surfaces || !same_config {
+            return false;
+        }
+
+        // The surfaces and context stay as they are, only the part of the frames we display
+        // changes.
+        let visible_rect = format_info.visible_rect();
+        stream_info.display_resolution = visible_rect.size();
+        stream_info.display_offset = (visible_rect.min.x, visible_rect.min.y);
+
+        true
+    }
+
+    fn recover(&mut self) -> anyhow::Result<()> {
+        // Drop the lost config, context and surfaces. The next call to `new_sequence` creates new
+        // ones, and the client provides new frames during the negotiation that follows. The
+        // protected session, if any, is lost as well and must be set again by the client.
+        let power_hint = self.power_hint;
+        let trace = self.trace.take();
+        *self = Self::new(Rc::clone(&self.display), self.supports_context_reuse);
+        self.power_hint = power_hint;
+        // Keep recording, without the buffers of the picture that failed.
+        if let Some(trace) = &trace {
+            trace.borrow_mut().discard_picture();
+        }
+        self.trace = trace;
+
+        Ok(())
+    }
+
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.metadata_state
             .get_parsed()
@@ -1078,15 +2130,10 @@ where
 ///
 /// This function is VAAPI-specific because of the unusual the source pixels are laid out: VAAPI
 /// writes the `useful_pixels` MSBs, but software generally expects the LSBs to contain the data.
-fn p01x_to_i01x(
-    src: &[u8],
-    dst: &mut [u8],
-    useful_pixels: usize,
-    width: usize,
-    height: usize,
-    strides: [usize; 3],
-    offsets: [usize; 3],
-) {
+fn p01x_to_i01x(src: &[u8], src_layout: &FrameLayout, dst: &mut [u8], useful_pixels: usize) {
+    let width = src_layout.size.width as usize;
+    let height = src_layout.size.height as usize;
+
     let sample_shift = 16 - useful_pixels;
 
     // Copy Y.
@@ -1094,9 +2141,7 @@ fn p01x_to_i01x(
     // VAAPI's Y samples are two byte little endian with the bottom six bits ignored. We need to
     // convert that to two byte little endian with top 6 bits ignored.
 
-    let src_y_lines = src[offsets[0]..]
-        .chunks(strides[0])
-        .map(|line| &line[..width * 2]);
+    let src_y_lines = src_layout.plane_lines(src, 0, width * 2);
     let dst_y_lines = dst.chunks_mut(width * 2);
 
     for (src_line, dst_line) in src_y_lines.zip(dst_y_lines).take(height) {
@@ -1118,9 +2163,7 @@ fn p01x_to_i01x(
     // Copy U and V and deinterleave into different planes.
     //
     // We need to perform the same bit shift as luma, but also to de-interleave the data.
-    let src_uv_lines = src[offsets[1]..]
-        .chunks(strides[1])
-        .map(|line| &line[..width * 2]);
+    let src_uv_lines = src_layout.plane_lines(src, 1, width * 2);
     let (dst_u_plane, dst_v_plane) = dst[dst_u_offset..].split_at_mut(dst_u_size);
     let dst_u_lines = dst_u_plane.chunks_mut(width);
     let dst_v_lines = dst_v_plane.chunks_mut(width);
@@ -1138,6 +2181,48 @@ fn p01x_to_i01x(
     }
 }
 
+/// Copies `src` into `dst` as I422, removing all padding and changing the layout from packed to
+/// triplanar.
+///
+/// WARNING: this function could not be tested for lack of supporting hardware.
+fn yuy2_to_i422(src: &[u8], src_layout: &FrameLayout, dst: &mut [u8]) {
+    let width = src_layout.size.width as usize;
+    let height = src_layout.size.height as usize;
+
+    let uv_width = width.div_ceil(2);
+
+    // YUYV representation, i.e. 4 bytes per two Y samples.
+    let src_lines = src_layout.plane_lines(src, 0, uv_width * 4);
+
+    let dst_y_size = width * height;
+    let dst_u_size = uv_width * height;
+
+    let (dst_y_plane, dst_uv_planes) = dst.split_at_mut(dst_y_size);
+    let (dst_u_plane, dst_v_plane) = dst_uv_planes.split_at_mut(dst_u_size);
+    let dst_y_lines = dst_y_plane.chunks_mut(width);
+    let dst_u_lines = dst_u_plane.chunks_mut(uv_width);
+    let dst_v_lines = dst_v_plane.chunks_mut(uv_width);
+
+    for (src_line, (dst_y_line, (dst_u_line, dst_v_line))) in src_lines
+        .zip(dst_y_lines.zip(dst_u_lines.zip(dst_v_lines)))
+        .take(height)
+    {
+        for (src, (dst_y, (dst_u, dst_v))) in src_line.chunks(4).zip(
+            dst_y_line
+                .chunks_mut(2)
+                .zip(dst_u_line.iter_mut().zip(dst_v_line.iter_mut())),
+        ) {
+            // The last chunk of Y samples is incomplete if the width is odd.
+            dst_y[0] = src[0];
+            if let Some(y1) = dst_y.get_mut(1) {
+                *y1 = src[2];
+            }
+            *dst_u = src[1];
+            *dst_v = src[3];
+        }
+    }
+}
+
 /// Copies `src` into `dst` as I21x, removing all padding and changing the layout from packed to
 /// triplanar.
 ///
@@ -1148,15 +2233,10 @@ fn p01x_to_i01x(
 /// writes the `useful_pixels` MSBs, but software generally expects the LSBs to contain the data.
 ///
 /// WARNING: this function could not be tested for lack of supporting hardware.
-fn y21x_to_i21x(
-    src: &[u8],
-    dst: &mut [u8],
-    useful_pixels: usize,
-    width: usize,
-    height: usize,
-    strides: [usize; 3],
-    offsets: [usize; 3],
-) {
+fn y21x_to_i21x(src: &[u8], src_layout: &FrameLayout, dst: &mut [u8], useful_pixels: usize) {
+    let width = src_layout.size.width as usize;
+    let height = src_layout.size.height as usize;
+
     let sample_shift = 16 - useful_pixels;
     // Align width to 2 for U and V planes and divide by 2.
     // This should not be necessary as the sampling method requires that width is a multiple of 2
@@ -1165,9 +2245,7 @@ fn y21x_to_i21x(
 
     // YUYV representation, i.e. 4 16-bit words per two Y samples meaning we have 4 * width bytes
     // of data per line.
-    let src_lines = src[offsets[0]..]
-        .chunks(strides[0])
-        .map(|line| &line[..width * 4]);
+    let src_lines = src_layout.plane_lines(src, 0, width * 4);
 
     let dst_y_size = width * 2 * height;
     let dst_u_size = uv_width * 2 * height;
@@ -1204,17 +2282,11 @@ fn y21x_to_i21x(
 /// triplanar. Also drops the alpha channel.
 ///
 /// This function is VAAPI-specific because the samples need to be rolled somehow...
-fn y412_to_i412(
-    src: &[u8],
-    dst: &mut [u8],
-    width: usize,
-    height: usize,
-    strides: [usize; 3],
-    offsets: [usize; 3],
-) {
-    let src_lines = src[offsets[0]..]
-        .chunks(strides[0])
-        .map(|line| &line[..width * 8]);
+fn y412_to_i412(src: &[u8], src_layout: &FrameLayout, dst: &mut [u8]) {
+    let width = src_layout.size.width as usize;
+    let height = src_layout.size.height as usize;
+
+    let src_lines = src_layout.plane_lines(src, 0, width * 8);
 
     let dst_y_size = width * 2 * height;
     let dst_u_size = width * 2 * height;
@@ -1352,3 +2424,56 @@ impl libva::ExternalBufferDescriptor for DmabufFrame {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::p01x_to_i01x;
+    use crate::decoded_frame_size;
+    use crate::DecodedFormat;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+    use crate::PlaneLayout;
+    use crate::Resolution;
+
+    #[test]
+    fn p010_to_i010() {
+        // 2x2 frame with the 10 useful bits of each sample in the MSBs.
+        let src: [u8; 12] = [
+            0x40, 0x00, 0x80, 0x00, // Y line 0
+            0xc0, 0x00, 0xc0, 0xff, // Y line 1
+            0x00, 0x10, 0x00, 0x20, // UV line 0
+        ];
+
+        let src_layout = FrameLayout {
+            format: (Fourcc::from(DecodedFormat::P010), 0),
+            size: Resolution::from((2, 2)),
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: 4,
+                    size: 8,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 8,
+                    stride: 4,
+                    size: 4,
+                },
+            ],
+        };
+
+        let mut dst = vec![0u8; decoded_frame_size(DecodedFormat::I010, 2, 2)];
+        p01x_to_i01x(&src, &src_layout, &mut dst, 10);
+
+        assert_eq!(
+            dst,
+            [
+                0x01, 0x00, 0x02, 0x00, // Y line 0
+                0x03, 0x00, 0xff, 0x03, // Y line 1
+                0x40, 0x00, // U
+                0x80, 0x00, // V
+            ]
+        );
+    }
+}
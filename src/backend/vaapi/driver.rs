@@ -0,0 +1,211 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Identification of the VA driver in use and handling of its known issues.
+//!
+//! The backend looks the driver up in a table of quirks when it is created, and works around the
+//! ones that apply automatically. Clients can query them through [`DriverInfo`], e.g. to report
+//! them alongside decoding issues.
+
+use libva::Display;
+
+use crate::Fourcc;
+
+/// Version of a VA driver, as found in its vendor string.
+pub type DriverVersion = (u32, u32, u32);
+
+/// Known issue of a VA driver that the backend works around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quirk {
+    /// The driver needs more surfaces than the stream requires, e.g. because it keeps using a
+    /// surface for a while after reporting it as ready.
+    ExtraSurfaces(usize),
+    /// The driver advertises an image format that it fails to map surfaces to, given as a VA
+    /// fourcc. Frames are mapped in another format and converted instead.
+    BrokenImageFormat(u32),
+}
+
+impl std::fmt::Display for Quirk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quirk::ExtraSurfaces(num) => write!(f, "needs {} extra surfaces", num),
+            Quirk::BrokenImageFormat(fourcc) => {
+                write!(f, "cannot map surfaces as {}", Fourcc::from(*fourcc))
+            }
+        }
+    }
+}
+
+/// Entry of the quirks table.
+struct QuirkEntry {
+    /// Part of the vendor string identifying the driver.
+    vendor: &'static str,
+    /// First version of the driver without the issue, if it has been fixed.
+    fixed_in: Option<DriverVersion>,
+    quirk: Quirk,
+}
+
+/// Known issues of VA drivers.
+const QUIRKS: [QuirkEntry; 2] = [
+    QuirkEntry {
+        vendor: "Intel i965 driver",
+        fixed_in: None,
+        quirk: Quirk::ExtraSurfaces(1),
+    },
+    QuirkEntry {
+        vendor: "Mesa Gallium driver",
+        fixed_in: None,
+        quirk: Quirk::BrokenImageFormat(libva::constants::VA_FOURCC_I420),
+    },
+];
+
+/// Extracts the version of the driver from its vendor string, i.e. the first sequence of two or
+/// three dot-separated numbers.
+fn parse_version(vendor: &str) -> Option<DriverVersion> {
+    vendor.split_whitespace().find_map(|word| {
+        let word = word.trim_start_matches(|c: char| !c.is_ascii_digit());
+        let word = word.trim_end_matches(|c: char| !c.is_ascii_digit());
+        let mut numbers = word.split('.').map(|n| n.parse::<u32>().ok());
+
+        match (
+            numbers.next(),
+            numbers.next(),
+            numbers.next(),
+            numbers.next(),
+        ) {
+            (Some(Some(major)), Some(Some(minor)), None, None) => Some((major, minor, 0)),
+            (Some(Some(major)), Some(Some(minor)), Some(Some(micro)), None) => {
+                Some((major, minor, micro))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Information about the VA driver in use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverInfo {
+    vendor: String,
+    version: Option<DriverVersion>,
+    quirks: Vec<Quirk>,
+}
+
+impl DriverInfo {
+    /// Queries the driver behind `display` and looks up its quirks.
+    pub fn query(display: &Display) -> Self {
+        let vendor = display.query_vendor_string().unwrap_or_else(|e| {
+            log::warn!("failed to query the VA vendor string: {}", e);
+            String::new()
+        });
+
+        Self::from_vendor_string(vendor)
+    }
+
+    /// Builds the information of the driver identified by `vendor`.
+    fn from_vendor_string(vendor: String) -> Self {
+        let version = parse_version(&vendor);
+        let quirks = QUIRKS
+            .iter()
+            .filter(|entry| vendor.contains(entry.vendor))
+            .filter(|entry| match (version, entry.fixed_in) {
+                (Some(version), Some(fixed_in)) => version < fixed_in,
+                _ => true,
+            })
+            .map(|entry| entry.quirk)
+            .collect::<Vec<_>>();
+
+        for quirk in &quirks {
+            log::info!("working around VA driver quirk: {}", quirk);
+        }
+
+        Self {
+            vendor,
+            version,
+            quirks,
+        }
+    }
+
+    /// Returns the vendor string of the driver.
+    pub fn vendor(&self) -> &str {
+        &self.vendor
+    }
+
+    /// Returns the version of the driver, if its vendor string contains it.
+    pub fn version(&self) -> Option<DriverVersion> {
+        self.version
+    }
+
+    /// Returns the quirks of the driver that are worked around.
+    pub fn quirks(&self) -> &[Quirk] {
+        &self.quirks
+    }
+
+    /// Returns the number of surfaces to allocate on top of what the stream requires.
+    pub(crate) fn extra_surfaces(&self) -> usize {
+        self.quirks
+            .iter()
+            .map(|quirk| match quirk {
+                Quirk::ExtraSurfaces(num) => *num,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Removes the formats that the driver cannot actually map surfaces to from `image_formats`.
+    pub(crate) fn filter_image_formats(
+        &self,
+        mut image_formats: Vec<libva::VAImageFormat>,
+    ) -> Vec<libva::VAImageFormat> {
+        image_formats.retain(|format| {
+            !self
+                .quirks
+                .contains(&Quirk::BrokenImageFormat(format.fourcc))
+        });
+
+        image_formats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_version;
+    use super::DriverInfo;
+    use super::Quirk;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            parse_version("Intel iHD driver for Intel(R) Gen Graphics - 23.1.6 ()"),
+            Some((23, 1, 6))
+        );
+        assert_eq!(
+            parse_version(
+                "Mesa Gallium driver 23.0.4 for AMD Radeon RX 6600 (navi23, LLVM 15.0.7)"
+            ),
+            Some((23, 0, 4))
+        );
+        assert_eq!(
+            parse_version("Intel i965 driver for Intel(R) Kaby Lake - 2.4.1"),
+            Some((2, 4, 1))
+        );
+        assert_eq!(
+            parse_version("Splitted-Desktop Systems VDPAU backend"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_quirks_lookup() {
+        let info = DriverInfo::from_vendor_string(String::from(
+            "Intel i965 driver for Intel(R) Kaby Lake - 2.4.1",
+        ));
+        assert_eq!(info.quirks(), &[Quirk::ExtraSurfaces(1)]);
+        assert_eq!(info.extra_surfaces(), 1);
+
+        let info = DriverInfo::from_vendor_string(String::from(
+            "Intel iHD driver for Intel(R) Gen Graphics - 23.1.6 ()",
+        ));
+        assert!(info.quirks().is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Decoding of encrypted content using VA-API protected sessions.
+//!
+//! A [`ProtectedSession`] is created from the parameters negotiated with the content decryption
+//! module and set on a decoder using the VA-API backend, which attaches it to the VA contexts it
+//! creates. The parameters needed to decrypt each frame are then given to the decoder as
+//! [`EncryptionParams`] along with the timestamp of the frame. Frames decoded this way stay in
+//! protected memory: they cannot be mapped by the CPU, only exported or post-processed.
+
+use std::rc::Rc;
+
+use anyhow::Context as AnyhowContext;
+use libva::Config;
+use libva::Context;
+use libva::Display;
+
+use crate::backend::vaapi::va_check;
+use crate::backend::vaapi::RawBuffer;
+
+/// Cipher used to encrypt the content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherMode {
+    /// AES-128 in counter mode, as used by the `cenc` and `cens` common encryption schemes.
+    AesCtr,
+    /// AES-128 in cipher block chaining mode, as used by the `cbc1` and `cbcs` common encryption
+    /// schemes.
+    AesCbc,
+}
+
+/// Which part of the samples is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    /// The whole samples are encrypted.
+    Full,
+    /// Only some ranges of the samples are encrypted, e.g. the slice data but not the slice
+    /// headers.
+    Subsample,
+}
+
+/// Encrypted range of the data of a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionSegment {
+    /// Offset of the segment in the frame.
+    pub start_offset: u32,
+    /// Length of the segment, including its clear part.
+    pub length: u32,
+    /// Number of bytes of a partial AES block carried over from the previous segment.
+    pub partial_aes_block_size: u32,
+    /// Number of clear bytes at the start of the segment.
+    pub init_byte_length: u32,
+    /// Initialization vector for CBC, or initial counter for CTR. At most 64 bytes.
+    pub iv: Vec<u8>,
+}
+
+/// Parameters needed to decrypt one frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionParams {
+    /// Encrypted segments of the frame, in order.
+    pub segments: Vec<EncryptionSegment>,
+    /// Content key, wrapped by the content decryption module for the hardware.
+    pub wrapped_key: [u8; 16],
+}
+
+impl EncryptionParams {
+    /// Submits these parameters for the picture currently being rendered on `context`, using
+    /// `session`.
+    pub(crate) fn render(
+        &self,
+        display: &Display,
+        context: &Context,
+        session: &ProtectedSession,
+    ) -> anyhow::Result<()> {
+        let mut segments = self
+            .segments
+            .iter()
+            .map(|segment| {
+                let mut info = libva::VAEncryptionSegmentInfo::default();
+                if segment.iv.len() > info.aes_cbc_iv_or_ctr.len() {
+                    anyhow::bail!("initialization vector is {} bytes long", segment.iv.len());
+                }
+
+                info.segment_start_offset = segment.start_offset;
+                info.segment_length = segment.length;
+                info.partial_aes_block_size = segment.partial_aes_block_size;
+                info.init_byte_length = segment.init_byte_length;
+                info.aes_cbc_iv_or_ctr[..segment.iv.len()].copy_from_slice(&segment.iv);
+                info.aes_cbc_iv_or_ctr_size = segment.iv.len() as u32;
+
+                Ok(info)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut params = libva::VAEncryptionParameters::default();
+        params.encryption_type = session.encryption_type();
+        params.num_segments = segments.len() as u32;
+        params.segment_info = segments.as_mut_ptr();
+        params.wrapped_decrypt_blob = self.wrapped_key;
+
+        let buffer = RawBuffer::new(
+            display,
+            context,
+            libva::VABufferType::VAEncryptionParameterBufferType,
+            &mut params,
+        )?;
+        let mut buffer_id = buffer.id;
+
+        // Safe because the buffer and the segments it refers to are alive until we return.
+        va_check(
+            unsafe { libva::vaRenderPicture(display.handle(), context.id(), &mut buffer_id, 1) },
+            "vaRenderPicture",
+        )
+    }
+}
+
+/// A VA protected session, within which encrypted content can be decoded.
+pub struct ProtectedSession {
+    display: Rc<Display>,
+    /// Kept alive as long as the session.
+    _config: Config,
+    id: libva::VAProtectedSessionID,
+    cipher_mode: CipherMode,
+    sample_type: SampleType,
+}
+
+impl ProtectedSession {
+    /// Creates a protected session on `display` for content encrypted with `cipher_mode`.
+    pub fn new(
+        display: Rc<Display>,
+        cipher_mode: CipherMode,
+        sample_type: SampleType,
+    ) -> anyhow::Result<Self> {
+        let attrs = [
+            (
+                libva::VAConfigAttribType::VAConfigAttribProtectedContentCipherAlgorithm,
+                libva::constants::VA_PC_CIPHER_AES,
+            ),
+            (
+                libva::VAConfigAttribType::VAConfigAttribProtectedContentCipherBlockSize,
+                libva::constants::VA_PC_BLOCK_SIZE_128,
+            ),
+            (
+                libva::VAConfigAttribType::VAConfigAttribProtectedContentCipherMode,
+                match cipher_mode {
+                    CipherMode::AesCtr => libva::constants::VA_PC_CIPHER_MODE_CTR,
+                    CipherMode::AesCbc => libva::constants::VA_PC_CIPHER_MODE_CBC,
+                },
+            ),
+            (
+                libva::VAConfigAttribType::VAConfigAttribProtectedContentCipherSampleType,
+                match sample_type {
+                    SampleType::Full => libva::constants::VA_PC_SAMPLE_TYPE_FULLSAMPLE,
+                    SampleType::Subsample => libva::constants::VA_PC_SAMPLE_TYPE_SUBSAMPLE,
+                },
+            ),
+            (
+                libva::VAConfigAttribType::VAConfigAttribProtectedContentUsage,
+                libva::constants::VA_PC_USAGE_DEFAULT,
+            ),
+        ]
+        .into_iter()
+        .map(|(type_, value)| libva::VAConfigAttrib { type_, value })
+        .collect();
+
+        let config = display
+            .create_config(
+                attrs,
+                libva::VAProfile::VAProfileProtected,
+                libva::VAEntrypoint::VAEntrypointProtectedContent,
+            )
+            .context("protected content is not supported by the driver")?;
+
+        let mut id = 0;
+        // Safe because `config` is a valid protected content config of `display`.
+        va_check(
+            unsafe { libva::vaCreateProtectedSession(display.handle(), config.id(), &mut id) },
+            "vaCreateProtectedSession",
+        )?;
+
+        Ok(Self {
+            display,
+            _config: config,
+            id,
+            cipher_mode,
+            sample_type,
+        })
+    }
+
+    /// Returns the cipher mode of the content decoded in this session.
+    pub fn cipher_mode(&self) -> CipherMode {
+        self.cipher_mode
+    }
+
+    /// Returns which part of the samples decoded in this session is encrypted.
+    pub fn sample_type(&self) -> SampleType {
+        self.sample_type
+    }
+
+    /// Returns the VA encryption type matching the parameters of this session.
+    fn encryption_type(&self) -> u32 {
+        match (self.cipher_mode, self.sample_type) {
+            (CipherMode::AesCtr, SampleType::Full) => {
+                libva::constants::VA_ENCRYPTION_TYPE_FULLSAMPLE_CTR
+            }
+            (CipherMode::AesCbc, SampleType::Full) => {
+                libva::constants::VA_ENCRYPTION_TYPE_FULLSAMPLE_CBC
+            }
+            (CipherMode::AesCtr, SampleType::Subsample) => {
+                libva::constants::VA_ENCRYPTION_TYPE_SUBSAMPLE_CTR
+            }
+            (CipherMode::AesCbc, SampleType::Subsample) => {
+                libva::constants::VA_ENCRYPTION_TYPE_SUBSAMPLE_CBC
+            }
+        }
+    }
+
+    /// Attaches this session to `context`, so the pictures decoded on it are decrypted.
+    pub(crate) fn attach(&self, context: &Context) -> anyhow::Result<()> {
+        // Safe because both the session and the context belong to our display.
+        va_check(
+            unsafe {
+                libva::vaAttachProtectedSession(self.display.handle(), context.id(), self.id)
+            },
+            "vaAttachProtectedSession",
+        )
+    }
+
+    /// Detaches `context` from the session it is attached to.
+    pub(crate) fn detach(&self, context: &Context) -> anyhow::Result<()> {
+        // Safe because the context belongs to our display.
+        va_check(
+            unsafe { libva::vaDetachProtectedSession(self.display.handle(), context.id()) },
+            "vaDetachProtectedSession",
+        )
+    }
+}
+
+impl Drop for ProtectedSession {
+    fn drop(&mut self) {
+        // Safe because the session has been created on this display, and the backends it is set
+        // on keep a reference to it as long as it is attached to their contexts.
+        unsafe { libva::vaDestroyProtectedSession(self.display.handle(), self.id) };
+    }
+}
@@ -0,0 +1,584 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Recording and replay of the buffers submitted to VA-API, for testing without a GPU.
+//!
+//! A decoder using the VA-API backend can record a [`VaTrace`] of the contents of the parameter
+//! and data buffers it submits for each picture, which can be saved next to a test stream. The
+//! [`TraceBackend`] runs the same parameter construction code without any VA device and records
+//! the buffers it would have submitted, so comparing its trace against the saved one catches
+//! regressions of the parameter construction on machines without a GPU.
+//!
+//! Surfaces are allocated by the driver, so the same session can reference different surface IDs
+//! from one run to another. [`VaTrace::compare`] accepts such differences as long as they are
+//! consistent with the surfaces the pictures have been decoded into.
+//!
+//! The [`TraceBackend`] currently supports VP8 and VP9.
+
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::BufRead;
+use std::io::Write;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use libva::BufferType;
+use libva::IQMatrix;
+use libva::PictureParameter;
+use libva::SliceParameter;
+use libva::VASurfaceID;
+
+use crate::backend::dummy;
+use crate::decoder::completion::CompletionWaiter;
+use crate::decoder::stateless::StatelessCodec;
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessDecoderBackendPicture;
+use crate::decoder::DecodedHandle;
+use crate::decoder::DynHandle;
+use crate::decoder::FormatError;
+use crate::decoder::FramePool;
+use crate::decoder::StreamInfo;
+use crate::DecodedFormat;
+use crate::Resolution;
+
+/// Type of a buffer recorded in a [`VaTrace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceBufferKind {
+    PictureParameter,
+    SliceParameter,
+    IQMatrix,
+    Probability,
+    SliceData,
+}
+
+impl TraceBufferKind {
+    const ALL: [TraceBufferKind; 5] = [
+        TraceBufferKind::PictureParameter,
+        TraceBufferKind::SliceParameter,
+        TraceBufferKind::IQMatrix,
+        TraceBufferKind::Probability,
+        TraceBufferKind::SliceData,
+    ];
+
+    /// Returns the name of the kind in the text format of traces.
+    fn name(&self) -> &'static str {
+        match self {
+            TraceBufferKind::PictureParameter => "pic_param",
+            TraceBufferKind::SliceParameter => "slice_param",
+            TraceBufferKind::IQMatrix => "iq_matrix",
+            TraceBufferKind::Probability => "probability",
+            TraceBufferKind::SliceData => "slice_data",
+        }
+    }
+}
+
+/// Returns the memory representation of `value`, which must be a plain VA structure.
+fn raw_bytes<T>(value: &T) -> Vec<u8> {
+    // Safe because `value` is a valid reference and VA structures are plain data without
+    // references, so all their bytes can be read.
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) }
+        .to_vec()
+}
+
+/// Contents of a buffer submitted for a picture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceBuffer {
+    pub kind: TraceBufferKind,
+    pub data: Vec<u8>,
+}
+
+impl TraceBuffer {
+    /// Captures the contents of `buffer`, or returns `None` if it is of a type that is not
+    /// recorded.
+    pub fn new(buffer: &BufferType) -> Option<Self> {
+        let (kind, data) = match buffer {
+            BufferType::PictureParameter(param) => (
+                TraceBufferKind::PictureParameter,
+                match param {
+                    PictureParameter::H264(param) => raw_bytes(param.inner()),
+                    PictureParameter::HEVC(param) => raw_bytes(param.inner()),
+                    PictureParameter::HEVCRext(param) => raw_bytes(param.inner()),
+                    PictureParameter::HEVCScc(param) => raw_bytes(param.inner()),
+                    PictureParameter::VP8(param) => raw_bytes(param.inner()),
+                    PictureParameter::VP9(param) => raw_bytes(param.inner()),
+                    PictureParameter::AV1(param) => raw_bytes(param.inner()),
+                    _ => return None,
+                },
+            ),
+            BufferType::SliceParameter(param) => (
+                TraceBufferKind::SliceParameter,
+                match param {
+                    SliceParameter::H264(param) => raw_bytes(param.inner()),
+                    SliceParameter::HEVC(param) => raw_bytes(param.inner()),
+                    SliceParameter::HEVCRext(param) => raw_bytes(param.inner()),
+                    SliceParameter::VP8(param) => raw_bytes(param.inner()),
+                    SliceParameter::VP9(param) => raw_bytes(param.inner()),
+                    SliceParameter::AV1(param) => raw_bytes(param.inner()),
+                    _ => return None,
+                },
+            ),
+            BufferType::IQMatrix(matrix) => (
+                TraceBufferKind::IQMatrix,
+                match matrix {
+                    IQMatrix::H264(matrix) => raw_bytes(matrix.inner()),
+                    IQMatrix::HEVC(matrix) => raw_bytes(matrix.inner()),
+                    IQMatrix::VP8(matrix) => raw_bytes(matrix.inner()),
+                    _ => return None,
+                },
+            ),
+            BufferType::Probability(probs) => {
+                (TraceBufferKind::Probability, raw_bytes(probs.inner()))
+            }
+            BufferType::SliceData(data) => (TraceBufferKind::SliceData, data.clone()),
+            _ => return None,
+        };
+
+        Some(Self { kind, data })
+    }
+}
+
+/// Buffers submitted for a picture, in submission order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracePicture {
+    /// Timestamp of the picture.
+    pub timestamp: u64,
+    /// Surface the picture has been decoded into.
+    pub surface: VASurfaceID,
+    pub buffers: Vec<TraceBuffer>,
+}
+
+/// Buffers submitted to VA-API for each picture of a decoding session, in decoding order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VaTrace {
+    pub pictures: Vec<TracePicture>,
+}
+
+impl VaTrace {
+    /// Writes the trace in its text format: each picture starts with a `picture <timestamp>
+    /// <surface>` line, followed by one `<kind> <hexadecimal data>` line per buffer.
+    pub fn write<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        for picture in &self.pictures {
+            writeln!(writer, "picture {} {}", picture.timestamp, picture.surface)?;
+            for buffer in &picture.buffers {
+                let mut line = String::with_capacity(buffer.data.len() * 2);
+                for byte in &buffer.data {
+                    // Writing into a `String` cannot fail.
+                    let _ = write!(line, "{:02x}", byte);
+                }
+                writeln!(writer, "{} {}", buffer.kind.name(), line)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a trace written by [`VaTrace::write`].
+    pub fn read<R: BufRead>(reader: R) -> anyhow::Result<Self> {
+        let mut trace = Self::default();
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parse = || -> anyhow::Result<()> {
+                let mut fields = line.split_whitespace();
+                let tag = fields.next().unwrap_or_default();
+
+                if tag == "picture" {
+                    let mut field = || fields.next().ok_or_else(|| anyhow!("missing field"));
+                    let timestamp = field()?.parse()?;
+                    let surface = field()?.parse()?;
+                    trace.pictures.push(TracePicture {
+                        timestamp,
+                        surface,
+                        buffers: Default::default(),
+                    });
+
+                    return Ok(());
+                }
+
+                let kind = TraceBufferKind::ALL
+                    .into_iter()
+                    .find(|kind| kind.name() == tag)
+                    .ok_or_else(|| anyhow!("unknown buffer kind {:?}", tag))?;
+                let hex = fields.next().unwrap_or_default();
+                if hex.len() % 2 != 0 {
+                    return Err(anyhow!("odd number of hexadecimal digits"));
+                }
+                let data = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                trace
+                    .pictures
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("buffer before the first picture"))?
+                    .buffers
+                    .push(TraceBuffer { kind, data });
+
+                Ok(())
+            };
+
+            parse().with_context(|| format!("line {}", i + 1))?;
+        }
+
+        Ok(trace)
+    }
+
+    /// Checks that `actual` contains the same buffers as this trace.
+    ///
+    /// Buffers of parameters may differ by the surface IDs they reference, as long as each
+    /// reference of `actual` is to the surface holding the same picture as the corresponding
+    /// reference of this trace.
+    pub fn compare(&self, actual: &VaTrace) -> anyhow::Result<()> {
+        // Surface of `actual` holding the same picture as each surface of this trace.
+        let mut surfaces = HashMap::new();
+
+        for (i, (expected, actual)) in self.pictures.iter().zip(&actual.pictures).enumerate() {
+            if expected.timestamp != actual.timestamp {
+                return Err(anyhow!(
+                    "picture {}: timestamp {} instead of {}",
+                    i,
+                    actual.timestamp,
+                    expected.timestamp
+                ));
+            }
+            if expected.buffers.len() != actual.buffers.len() {
+                return Err(anyhow!(
+                    "picture {}: {} buffers instead of {}",
+                    i,
+                    actual.buffers.len(),
+                    expected.buffers.len()
+                ));
+            }
+
+            // Parameters of a picture may reference its own surface.
+            surfaces.insert(expected.surface, actual.surface);
+
+            for (j, (expected, actual)) in expected.buffers.iter().zip(&actual.buffers).enumerate()
+            {
+                if expected.kind != actual.kind {
+                    return Err(anyhow!(
+                        "picture {} buffer {}: {:?} instead of {:?}",
+                        i,
+                        j,
+                        actual.kind,
+                        expected.kind
+                    ));
+                }
+                if let Some(offset) = first_difference(&expected.data, &actual.data, |e, a| {
+                    expected.kind != TraceBufferKind::SliceData && surfaces.get(&e) == Some(&a)
+                }) {
+                    return Err(anyhow!(
+                        "picture {} buffer {} ({:?}): contents differ at offset {}",
+                        i,
+                        j,
+                        expected.kind,
+                        offset
+                    ));
+                }
+            }
+        }
+
+        if self.pictures.len() != actual.pictures.len() {
+            return Err(anyhow!(
+                "{} pictures instead of {}",
+                actual.pictures.len(),
+                self.pictures.len()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the offset of the first difference between `expected` and `actual`, ignoring the
+/// aligned 32-bit words for which `equivalent` returns `true`.
+fn first_difference<F>(expected: &[u8], actual: &[u8], equivalent: F) -> Option<usize>
+where
+    F: Fn(u32, u32) -> bool,
+{
+    if expected.len() != actual.len() {
+        return Some(std::cmp::min(expected.len(), actual.len()));
+    }
+
+    let word = |data: &[u8], offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap()))
+    };
+
+    (0..expected.len()).step_by(4).find_map(|offset| {
+        let end = std::cmp::min(offset + 4, expected.len());
+        if expected[offset..end] == actual[offset..end] {
+            return None;
+        }
+
+        match (word(expected, offset), word(actual, offset)) {
+            (Some(e), Some(a)) if equivalent(e, a) => None,
+            _ => (offset..end).find(|&i| expected[i] != actual[i]),
+        }
+    })
+}
+
+/// Records the buffers submitted for each picture.
+#[derive(Default)]
+pub(crate) struct TraceRecorder {
+    trace: VaTrace,
+    /// Buffers of the picture being submitted.
+    pending: Vec<TraceBuffer>,
+}
+
+impl TraceRecorder {
+    /// Records `buffer` as part of the picture being submitted.
+    pub(crate) fn record_buffer(&mut self, buffer: &BufferType) {
+        self.pending.extend(TraceBuffer::new(buffer));
+    }
+
+    /// Records the submission of the picture with `timestamp` into `surface`, along with the
+    /// buffers recorded since the previous one.
+    pub(crate) fn finish_picture(&mut self, timestamp: u64, surface: VASurfaceID) {
+        self.trace.pictures.push(TracePicture {
+            timestamp,
+            surface,
+            buffers: std::mem::take(&mut self.pending),
+        });
+    }
+
+    /// Drops the buffers of a picture which submission failed.
+    pub(crate) fn discard_picture(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Returns the trace recorded so far.
+    pub(crate) fn into_trace(self) -> VaTrace {
+        self.trace
+    }
+}
+
+/// Handle of a picture decoded by the [`TraceBackend`].
+#[derive(Clone)]
+pub struct TraceHandle {
+    handle: dummy::Handle,
+    surface: VASurfaceID,
+}
+
+impl TraceHandle {
+    /// Returns the fake surface the picture has been "decoded" into.
+    pub(crate) fn surface_id(&self) -> VASurfaceID {
+        self.surface
+    }
+}
+
+impl DecodedHandle for TraceHandle {
+    type Descriptor = ();
+
+    fn dyn_picture<'a>(&'a self) -> Box<dyn DynHandle + 'a> {
+        self.handle.dyn_picture()
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.handle.timestamp()
+    }
+
+    fn coded_resolution(&self) -> Resolution {
+        self.handle.coded_resolution()
+    }
+
+    fn display_resolution(&self) -> Resolution {
+        self.handle.display_resolution()
+    }
+
+    fn is_ready(&self) -> bool {
+        self.handle.is_ready()
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        self.handle.sync()
+    }
+
+    fn resource(&self) -> std::cell::Ref<'_, ()> {
+        self.handle.resource()
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+/// Backend building the VA buffers of each picture like the VA-API backend, but recording them
+/// instead of submitting them to a device. It does not produce any actual frames.
+pub struct TraceBackend {
+    backend: dummy::Backend,
+    recorder: TraceRecorder,
+    /// Coded resolution of the surfaces the VA-API backend would allocate for the stream.
+    pub(crate) coded_resolution: Resolution,
+    /// ID of the next fake surface.
+    next_surface: VASurfaceID,
+}
+
+impl TraceBackend {
+    pub(crate) fn new() -> Self {
+        Self {
+            backend: dummy::Backend::new(),
+            recorder: Default::default(),
+            coded_resolution: Default::default(),
+            next_surface: 0,
+        }
+    }
+
+    /// Records `buffers` as the buffers of a picture with `timestamp`, and returns a handle to
+    /// the fake surface it is "decoded" into.
+    pub(crate) fn submit(&mut self, timestamp: u64, buffers: &[BufferType]) -> TraceHandle {
+        let surface = self.next_surface;
+        self.next_surface = self.next_surface.wrapping_add(1);
+
+        for buffer in buffers {
+            self.recorder.record_buffer(buffer);
+        }
+        self.recorder.finish_picture(timestamp, surface);
+
+        TraceHandle {
+            handle: dummy::Handle {
+                handle: Default::default(),
+            },
+            surface,
+        }
+    }
+
+    /// Returns the trace recorded so far and starts a new one.
+    pub(crate) fn take_trace(&mut self) -> VaTrace {
+        std::mem::take(&mut self.recorder).into_trace()
+    }
+}
+
+impl<Codec: StatelessCodec> StatelessDecoderBackendPicture<Codec> for TraceBackend {
+    type Picture = ();
+}
+
+impl<Codec: StatelessCodec> StatelessDecoderBackend<Codec> for TraceBackend {
+    type Handle = TraceHandle;
+
+    fn try_format(&mut self, _: &Codec::FormatInfo, _: DecodedFormat) -> Result<(), FormatError> {
+        Ok(())
+    }
+
+    fn try_change_resolution(&mut self, _: &Codec::FormatInfo) -> bool {
+        false
+    }
+
+    fn completion_waiter(&self, _: &Self::Handle) -> Option<CompletionWaiter> {
+        // Nothing is actually decoded.
+        Some(Box::new(|| Ok(())))
+    }
+
+    fn recover(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn stream_info(&self) -> Option<&StreamInfo> {
+        StatelessDecoderBackend::<Codec>::stream_info(&self.backend)
+    }
+
+    fn frame_pool(&mut self) -> &mut dyn FramePool<()> {
+        &mut self.backend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::first_difference;
+    use super::TraceBuffer;
+    use super::TraceBufferKind;
+    use super::TracePicture;
+    use super::VaTrace;
+
+    fn picture(timestamp: u64, surface: u32, param: &[u32]) -> TracePicture {
+        TracePicture {
+            timestamp,
+            surface,
+            buffers: vec![
+                TraceBuffer {
+                    kind: TraceBufferKind::PictureParameter,
+                    data: param.iter().flat_map(|w| w.to_ne_bytes()).collect(),
+                },
+                TraceBuffer {
+                    kind: TraceBufferKind::SliceData,
+                    data: vec![0xde, 0xad, 0xbe, 0xef, 0x42],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let trace = VaTrace {
+            pictures: vec![picture(0, 4, &[4, 1]), picture(1, 5, &[5, 4])],
+        };
+
+        let mut text = Vec::new();
+        trace.write(&mut text).unwrap();
+        assert_eq!(VaTrace::read(text.as_slice()).unwrap(), trace);
+
+        assert!(VaTrace::read("slice_data 00".as_bytes()).is_err());
+        assert!(VaTrace::read("picture 0 0\nslice_data 0".as_bytes()).is_err());
+        assert!(VaTrace::read("picture 0 0\nfoo 00".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_compare_surfaces() {
+        // Pictures referencing themselves and their predecessor.
+        let expected = VaTrace {
+            pictures: vec![
+                picture(0, 0x40, &[0x40, 7]),
+                picture(1, 0x41, &[0x41, 0x40]),
+                // Surface reused for another picture.
+                picture(2, 0x40, &[0x40, 0x41]),
+            ],
+        };
+
+        let actual = VaTrace {
+            pictures: vec![
+                picture(0, 0, &[0, 7]),
+                picture(1, 1, &[1, 0]),
+                picture(2, 2, &[2, 1]),
+            ],
+        };
+        expected.compare(&actual).unwrap();
+
+        // Reference to the wrong picture.
+        let mut wrong = actual.clone();
+        wrong.pictures[2] = picture(2, 2, &[2, 0]);
+        assert!(expected.compare(&wrong).is_err());
+
+        // Other parameter changed.
+        let mut wrong = actual.clone();
+        wrong.pictures[0] = picture(0, 0, &[0, 8]);
+        assert!(expected.compare(&wrong).is_err());
+
+        // Missing picture.
+        let mut wrong = actual;
+        wrong.pictures.pop();
+        assert!(expected.compare(&wrong).is_err());
+    }
+
+    #[test]
+    fn test_first_difference() {
+        let never = |_, _| false;
+
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 2, 3], never), None);
+        assert_eq!(first_difference(&[1, 2, 3], &[1, 2], never), Some(2));
+        assert_eq!(
+            first_difference(&[1, 2, 3, 4, 5, 6], &[1, 2, 3, 4, 5, 7], never),
+            Some(5)
+        );
+        assert_eq!(
+            first_difference(&[1, 0, 0, 0, 2], &[3, 0, 0, 0, 2], |e, a| e == 1 && a == 3),
+            None
+        );
+    }
+}
@@ -0,0 +1,434 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Video post-processing of decoded frames using the VA-API video processing entrypoint.
+//!
+//! A [`Vpp`] shares the VA display of a decoder and applies a pipeline of [`VppStage`]s, e.g.
+//! color space conversion, cropping, scaling, deinterlacing and denoising, to its frames. The processed
+//! frames are written into surfaces from a pool owned by the [`Vpp`], and returned as new handles
+//! that can be mapped, exported or fed into another [`Vpp`], without the frames ever going through
+//! the CPU.
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::rc::Rc;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::Context as AnyhowContext;
+use libva::Config;
+use libva::Context;
+use libva::Display;
+use libva::Picture;
+use libva::SurfaceMemoryDescriptor;
+
+use crate::backend::decode_time::DecodeTimeEstimator;
+use crate::backend::vaapi::surface_pool::SurfacePool;
+use crate::backend::vaapi::va_check;
+use crate::backend::vaapi::va_error;
+use crate::backend::vaapi::DecodedHandle;
+use crate::backend::vaapi::FormatMap;
+use crate::backend::vaapi::PictureState;
+use crate::backend::vaapi::RawBuffer;
+use crate::backend::vaapi::VaapiDecodedHandle;
+use crate::backend::vaapi::FORMAT_MAP;
+use crate::decoder::DecodedHandle as DecodedHandleTrait;
+use crate::decoder::FieldOrder;
+use crate::DecodedFormat;
+use crate::Rect;
+use crate::Resolution;
+
+/// Number of output frames a [`Vpp`] allocates by default.
+const DEFAULT_NUM_OUTPUT_FRAMES: usize = 4;
+
+/// Deinterlacing algorithm of a [`VppStage::Deinterlace`] stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeinterlacingAlgorithm {
+    /// Interpolates the missing lines of the first field in time.
+    Bob,
+    /// Weaves both fields together, which keeps the full resolution but shows combing artifacts
+    /// on moving content.
+    Weave,
+}
+
+/// Processing step of a [`Vpp`] pipeline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VppStage {
+    /// Converts the frames to the given format. Without this stage, the frames keep the format of
+    /// the input.
+    ColorSpaceConversion(DecodedFormat),
+    /// Scales the visible part of the frames to the given resolution. Without this stage, the
+    /// frames keep the display resolution of the input, or the size of the cropping rectangle.
+    Scale(Resolution),
+    /// Only keeps the given rectangle of the visible part of the frames, before scaling.
+    Crop(Rect<u32>),
+    /// Deinterlaces the frames using the given algorithm. Progressive frames are left untouched.
+    Deinterlace(DeinterlacingAlgorithm),
+    /// Reduces the noise of the frames, with a strength between 0.0 and 1.0 that is mapped to the
+    /// range supported by the driver.
+    Denoise(f32),
+}
+
+/// Format and resolution of the processed frames, along with the resources to produce them.
+struct Output {
+    format_map: FormatMap,
+    resolution: Resolution,
+    map_format: Rc<libva::VAImageFormat>,
+    context: Rc<Context>,
+    pool: Rc<RefCell<SurfacePool<()>>>,
+    /// Range of the noise reduction values supported by the driver, if a denoising stage is
+    /// present.
+    denoise_range: Option<libva::VAProcFilterValueRange>,
+}
+
+/// Post-processing pipeline for the frames of a decoder using the VA-API backend.
+///
+/// The stages are applied in a single pass of the hardware video processor, so their order does
+/// not matter. Deinterlacing produces one frame per input frame, from its first field in time.
+pub struct Vpp {
+    display: Rc<Display>,
+    /// Kept alive as long as the contexts created from it.
+    config: Config,
+    stages: Vec<VppStage>,
+    max_num_frames: usize,
+    output: Option<Output>,
+    /// Running estimate of the time it takes to process a frame, shared with the output handles.
+    process_time: Rc<Cell<DecodeTimeEstimator>>,
+}
+
+impl Vpp {
+    /// Creates a pipeline applying `stages` to frames decoded on `display`, which must be the
+    /// display of the decoder.
+    pub fn new(display: Rc<Display>, stages: Vec<VppStage>) -> anyhow::Result<Self> {
+        let config = display
+            .create_config(
+                vec![],
+                libva::VAProfile::VAProfileNone,
+                libva::VAEntrypoint::VAEntrypointVideoProc,
+            )
+            .context("video processing is not supported by the driver")?;
+
+        Ok(Self {
+            display,
+            config,
+            stages,
+            max_num_frames: DEFAULT_NUM_OUTPUT_FRAMES,
+            output: None,
+            process_time: Default::default(),
+        })
+    }
+
+    /// Sets the maximum number of processed frames that can be alive at the same time.
+    ///
+    /// Processing fails if all of them are held by the client, so this should account for the
+    /// frames the client keeps, e.g. for display.
+    pub fn set_max_num_frames(&mut self, max_num_frames: usize) {
+        self.max_num_frames = max_num_frames;
+    }
+
+    /// Returns the stages of this pipeline.
+    pub fn stages(&self) -> &[VppStage] {
+        &self.stages
+    }
+
+    /// Returns the part of the visible rectangle of `input` that is processed.
+    fn input_rect<M: SurfaceMemoryDescriptor>(
+        &self,
+        input: &VaapiDecodedHandle<M>,
+    ) -> anyhow::Result<Rect<u32>> {
+        let visible_rect = Rect::from(input.display_resolution);
+        let crop = self.stages.iter().find_map(|stage| match stage {
+            VppStage::Crop(rect) => Some(*rect),
+            _ => None,
+        });
+
+        match crop {
+            None => Ok(visible_rect),
+            Some(rect)
+                if rect.min.x < rect.max.x
+                    && rect.min.y < rect.max.y
+                    && rect.max.x <= visible_rect.max.x
+                    && rect.max.y <= visible_rect.max.y =>
+            {
+                Ok(rect)
+            }
+            Some(rect) => Err(anyhow!(
+                "cropping rectangle {:?} does not fit in the visible part of the frame ({:?})",
+                rect,
+                input.display_resolution
+            )),
+        }
+    }
+
+    /// Returns the format and resolution of the frames produced from `input`, of which the part
+    /// `input_rect` is processed.
+    fn output_params<M: SurfaceMemoryDescriptor>(
+        &self,
+        input: &VaapiDecodedHandle<M>,
+        input_rect: Rect<u32>,
+    ) -> (DecodedFormat, Resolution) {
+        self.stages.iter().fold(
+            (input.decoded_format, input_rect.size()),
+            |(format, resolution), stage| match stage {
+                VppStage::ColorSpaceConversion(format) => (*format, resolution),
+                VppStage::Scale(resolution) => (format, *resolution),
+                VppStage::Crop(_) | VppStage::Deinterlace(_) | VppStage::Denoise(_) => {
+                    (format, resolution)
+                }
+            },
+        )
+    }
+
+    /// Makes sure the output resources match `format` and `resolution`, recreating them if
+    /// needed.
+    fn prepare_output(
+        &mut self,
+        format: DecodedFormat,
+        resolution: Resolution,
+    ) -> anyhow::Result<&Output> {
+        let output = match self.output.take() {
+            Some(output)
+                if output.format_map.decoded_format == format
+                    && output.resolution == resolution =>
+            {
+                output
+            }
+            _ => {
+                let image_formats = self.display.query_image_formats()?;
+                let (format_map, map_format) = FORMAT_MAP
+                    .iter()
+                    .filter(|map| map.decoded_format == format)
+                    .find_map(|map| {
+                        image_formats
+                            .iter()
+                            .find(|f| f.fourcc == map.va_fourcc)
+                            .map(|f| (*map, Rc::new(*f)))
+                    })
+                    .ok_or_else(|| anyhow!("cannot produce frames in format {:?}", format))?;
+
+                let context = self.display.create_context::<()>(
+                    &self.config,
+                    resolution.width,
+                    resolution.height,
+                    None,
+                    true,
+                )?;
+
+                let pool = SurfacePool::new(
+                    Rc::clone(&self.display),
+                    format_map.rt_format,
+                    Some(libva::UsageHint::USAGE_HINT_VPP_WRITE),
+                    resolution,
+                )
+                .with_fourcc(format_map.va_fourcc);
+
+                let denoise_range = if self
+                    .stages
+                    .iter()
+                    .any(|stage| matches!(stage, VppStage::Denoise(_)))
+                {
+                    let mut cap = libva::VAProcFilterCap::default();
+                    let mut num_caps = 1;
+                    // Safe because `cap` can hold the one capability we ask for.
+                    va_check(
+                        unsafe {
+                            libva::vaQueryVideoProcFilterCaps(
+                                self.display.handle(),
+                                context.id(),
+                                libva::VAProcFilterType::VAProcFilterNoiseReduction,
+                                &mut cap as *mut libva::VAProcFilterCap as *mut c_void,
+                                &mut num_caps,
+                            )
+                        },
+                        "vaQueryVideoProcFilterCaps",
+                    )
+                    .context("noise reduction is not supported by the driver")?;
+
+                    Some(cap.range)
+                } else {
+                    None
+                };
+
+                Output {
+                    format_map,
+                    resolution,
+                    map_format,
+                    context,
+                    pool: Rc::new(RefCell::new(pool)),
+                    denoise_range,
+                }
+            }
+        };
+
+        Ok(self.output.insert(output))
+    }
+
+    /// Processes `input`, a frame decoded by a decoder using the VA-API backend on the display of
+    /// this pipeline, or a frame produced by another [`Vpp`].
+    ///
+    /// The returned frame may still be processing: like decoded frames, it must be synced before
+    /// its content is accessed. `input` is kept alive until then.
+    pub fn process<M: SurfaceMemoryDescriptor + 'static>(
+        &mut self,
+        input: &dyn DecodedHandleTrait<Descriptor = M>,
+    ) -> anyhow::Result<Box<dyn DecodedHandleTrait<Descriptor = ()>>> {
+        let input_handle = input
+            .as_any()
+            .downcast_ref::<DecodedHandle<M>>()
+            .ok_or_else(|| anyhow!("handle has not been produced by the VA-API backend"))?;
+        let field_order = input.frame_info().field_order;
+
+        let input_rect = self.input_rect(&input_handle.borrow())?;
+        let (format, resolution) = self.output_params(&input_handle.borrow(), input_rect);
+        let max_num_frames = self.max_num_frames;
+        let display = Rc::clone(&self.display);
+        let stages = self.stages.clone();
+        let process_time = Rc::clone(&self.process_time);
+        let output = self.prepare_output(format, resolution)?;
+
+        let surface = {
+            let mut pool = output.pool.borrow_mut();
+            if pool.num_surfaces_left() == 0 {
+                if pool.num_managed_surfaces() >= max_num_frames {
+                    return Err(anyhow!(
+                        "all the {} output frames are in use",
+                        max_num_frames
+                    ));
+                }
+                pool.add_surfaces(vec![()])?;
+            }
+            pool.get_surface(&output.pool)
+                .ok_or_else(|| anyhow!("no output frame available"))?
+        };
+
+        // Build the filters requested by the stages.
+        let mut filters = Vec::new();
+        for stage in &stages {
+            match *stage {
+                VppStage::Deinterlace(algorithm) => {
+                    let flags = match field_order {
+                        FieldOrder::Progressive | FieldOrder::Unknown => continue,
+                        FieldOrder::TopFieldFirst => 0,
+                        FieldOrder::BottomFieldFirst => {
+                            libva::constants::VA_DEINTERLACING_BOTTOM_FIELD_FIRST
+                                | libva::constants::VA_DEINTERLACING_BOTTOM_FIELD
+                        }
+                    };
+
+                    let mut params = libva::VAProcFilterParameterBufferDeinterlacing::default();
+                    params.type_ = libva::VAProcFilterType::VAProcFilterDeinterlacing;
+                    params.algorithm = match algorithm {
+                        DeinterlacingAlgorithm::Bob => {
+                            libva::VAProcDeinterlacingType::VAProcDeinterlacingBob
+                        }
+                        DeinterlacingAlgorithm::Weave => {
+                            libva::VAProcDeinterlacingType::VAProcDeinterlacingWeave
+                        }
+                    };
+                    params.flags = flags;
+
+                    filters.push(RawBuffer::new(
+                        &display,
+                        &output.context,
+                        libva::VABufferType::VAProcFilterParameterBufferType,
+                        &mut params,
+                    )?);
+                }
+                VppStage::Denoise(strength) => {
+                    let range = output
+                        .denoise_range
+                        .context("denoise stage without queried range")?;
+
+                    let mut params = libva::VAProcFilterParameterBuffer::default();
+                    params.type_ = libva::VAProcFilterType::VAProcFilterNoiseReduction;
+                    params.value = range.min_value
+                        + strength.clamp(0.0, 1.0) * (range.max_value - range.min_value);
+
+                    filters.push(RawBuffer::new(
+                        &display,
+                        &output.context,
+                        libva::VABufferType::VAProcFilterParameterBufferType,
+                        &mut params,
+                    )?);
+                }
+                VppStage::ColorSpaceConversion(_) | VppStage::Scale(_) | VppStage::Crop(_) => (),
+            }
+        }
+        let mut filter_ids = filters.iter().map(|filter| filter.id).collect::<Vec<_>>();
+
+        // Color space conversion and scaling are implied by the output surface, and cropping by
+        // the input region.
+        let (input_surface_id, input_region, protected) = {
+            let input = input_handle.borrow();
+            let (x, y) = input.display_offset;
+            (
+                input.surface_id(),
+                libva::VARectangle {
+                    x: (x + input_rect.min.x) as i16,
+                    y: (y + input_rect.min.y) as i16,
+                    width: input_rect.width() as u16,
+                    height: input_rect.height() as u16,
+                },
+                input.protected,
+            )
+        };
+        let output_region = libva::VARectangle {
+            x: 0,
+            y: 0,
+            width: resolution.width as u16,
+            height: resolution.height as u16,
+        };
+
+        let mut pipeline = libva::VAProcPipelineParameterBuffer::default();
+        pipeline.surface = input_surface_id;
+        pipeline.surface_region = &input_region;
+        pipeline.output_region = &output_region;
+        pipeline.filters = filter_ids.as_mut_ptr();
+        pipeline.num_filters = filter_ids.len() as u32;
+
+        let pipeline = RawBuffer::new(
+            &display,
+            &output.context,
+            libva::VABufferType::VAProcPipelineParameterBufferType,
+            &mut pipeline,
+        )?;
+
+        let picture = Picture::new(input.timestamp(), Rc::clone(&output.context), surface)
+            .begin()
+            .map_err(va_error)?;
+        let mut pipeline_id = pipeline.id;
+        // Safe because the pipeline buffer and the filters it refers to are alive until after the
+        // picture is ended.
+        va_check(
+            unsafe {
+                libva::vaRenderPicture(display.handle(), output.context.id(), &mut pipeline_id, 1)
+            },
+            "vaRenderPicture",
+        )?;
+        let picture = picture
+            .render()
+            .and_then(|picture| picture.end())
+            .map_err(va_error)?;
+
+        drop(pipeline);
+        drop(filters);
+
+        let handle: DecodedHandle<()> = Rc::new(RefCell::new(VaapiDecodedHandle {
+            state: PictureState::Pending(picture),
+            coded_resolution: resolution,
+            display_resolution: resolution,
+            display_offset: (0, 0),
+            map_format: Rc::clone(&output.map_format),
+            decoded_format: format,
+            submitted_at: Instant::now(),
+            decode_time: process_time,
+            display,
+            source: Some(Box::new(Rc::clone(input_handle))),
+            protected,
+        }));
+
+        Ok(Box::new(handle))
+    }
+}
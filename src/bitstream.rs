@@ -0,0 +1,11 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Tools to generate bitstreams: a bit writer, and writers of codec-specific syntax elements.
+//!
+//! These are the building blocks of the headers generated by encoders, and can also be used to
+//! synthesize streams, e.g. malformed ones to test the parsers of the [crate::codec] module.
+
+pub mod av1;
+pub mod writer;
@@ -0,0 +1,2386 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Writers of AV1 OBUs, the counterpart of [`crate::codec::av1::parser`].
+//!
+//! The syntax elements are taken from the structures filled by the parser, so a parsed OBU can be
+//! written back as is or after being modified. Variables that the specification derives from the
+//! syntax elements (e.g. `FrameIsIntra` or `CodedLossless`) are recomputed by the writer and never
+//! read from these structures.
+
+use thiserror::Error;
+
+use crate::bitstream::writer::BitWriter;
+use crate::bitstream::writer::BitWriterError;
+use crate::codec::av1::helpers;
+use crate::codec::av1::parser::CdefParams;
+use crate::codec::av1::parser::ColorConfig;
+use crate::codec::av1::parser::ColorPrimaries;
+use crate::codec::av1::parser::FilmGrainParams;
+use crate::codec::av1::parser::FrameHeaderObu;
+use crate::codec::av1::parser::FrameRestorationType;
+use crate::codec::av1::parser::FrameType;
+use crate::codec::av1::parser::GlobalMotionParams;
+use crate::codec::av1::parser::InterpolationFilter;
+use crate::codec::av1::parser::LoopFilterParams;
+use crate::codec::av1::parser::LoopRestorationParams;
+use crate::codec::av1::parser::MatrixCoefficients;
+use crate::codec::av1::parser::ObuHeader;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::av1::parser::Profile;
+use crate::codec::av1::parser::QuantizationParams;
+use crate::codec::av1::parser::ReferenceFrameType;
+use crate::codec::av1::parser::SegmentationParams;
+use crate::codec::av1::parser::SequenceHeaderObu;
+use crate::codec::av1::parser::TileInfo;
+use crate::codec::av1::parser::TransferCharacteristics;
+use crate::codec::av1::parser::TxMode;
+use crate::codec::av1::parser::WarpModelType;
+use crate::codec::av1::parser::GM_ABS_ALPHA_BITS;
+use crate::codec::av1::parser::GM_ABS_TRANS_BITS;
+use crate::codec::av1::parser::GM_ABS_TRANS_ONLY_BITS;
+use crate::codec::av1::parser::GM_ALPHA_PREC_BITS;
+use crate::codec::av1::parser::GM_TRANS_ONLY_PREC_BITS;
+use crate::codec::av1::parser::GM_TRANS_PREC_BITS;
+use crate::codec::av1::parser::MAX_NUM_OPERATING_POINTS;
+use crate::codec::av1::parser::MAX_SEGMENTS;
+use crate::codec::av1::parser::MAX_TILE_AREA;
+use crate::codec::av1::parser::MAX_TILE_COLS;
+use crate::codec::av1::parser::MAX_TILE_ROWS;
+use crate::codec::av1::parser::MAX_TILE_WIDTH;
+use crate::codec::av1::parser::NUM_REF_FRAMES;
+use crate::codec::av1::parser::PRIMARY_REF_NONE;
+use crate::codec::av1::parser::REFS_PER_FRAME;
+use crate::codec::av1::parser::SEG_LVL_ALT_Q;
+use crate::codec::av1::parser::SEG_LVL_MAX;
+use crate::codec::av1::parser::SELECT_INTEGER_MV;
+use crate::codec::av1::parser::SELECT_SCREEN_CONTENT_TOOLS;
+use crate::codec::av1::parser::SUPERRES_DENOM_BITS;
+use crate::codec::av1::parser::SUPERRES_DENOM_MIN;
+use crate::codec::av1::parser::SUPERRES_NUM;
+use crate::codec::av1::parser::TOTAL_REFS_PER_FRAME;
+use crate::codec::av1::parser::WARPEDMODEL_PREC_BITS;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ObuWriterError {
+    #[error(transparent)]
+    BitWriter(#[from] BitWriterError),
+    #[error("invalid {0}: {1}")]
+    InvalidValue(&'static str, i64),
+}
+
+const ALL_FRAMES: u32 = (1 << NUM_REF_FRAMES) - 1;
+
+/// Loop filter deltas set by setup_past_independence().
+const DEFAULT_LOOP_FILTER_REF_DELTAS: [i32; TOTAL_REFS_PER_FRAME] = [1, 0, 0, 0, -1, 0, -1, -1];
+
+/// Global motion parameters of the identity transform.
+fn default_gm_params() -> [[i32; 6]; NUM_REF_FRAMES] {
+    [[
+        0,
+        0,
+        1 << WARPEDMODEL_PREC_BITS,
+        0,
+        0,
+        1 << WARPEDMODEL_PREC_BITS,
+    ]; NUM_REF_FRAMES]
+}
+
+/// Returns `value` coded as leb128(), using as few bytes as possible.
+pub fn leb128(mut value: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(5);
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return out;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+/// Returns `value` coded as leb128() on exactly `num_bytes` bytes, which is useful to reserve room
+/// for a size that is only known once the data following it has been written.
+pub fn leb128_fixed(value: u32, num_bytes: usize) -> Result<Vec<u8>, ObuWriterError> {
+    if !(1..=8).contains(&num_bytes) {
+        return Err(ObuWriterError::InvalidValue(
+            "leb128 length",
+            num_bytes as i64,
+        ));
+    }
+    if num_bytes < 5 && value >> (7 * num_bytes) != 0 {
+        return Err(ObuWriterError::InvalidValue("leb128 value", value.into()));
+    }
+
+    Ok((0..num_bytes)
+        .map(|i| {
+            let byte = (value.checked_shr(7 * i as u32).unwrap_or(0) & 0x7f) as u8;
+            if i + 1 < num_bytes {
+                byte | 0x80
+            } else {
+                byte
+            }
+        })
+        .collect())
+}
+
+/// Implements su(n), the inverse of [`crate::codec::av1::reader::Reader::read_su`].
+fn write_su(
+    w: &mut BitWriter,
+    value: i32,
+    num_bits: usize,
+    name: &'static str,
+) -> Result<(), ObuWriterError> {
+    let limit = 1 << (num_bits - 1);
+    if value < -limit || value >= limit {
+        return Err(ObuWriterError::InvalidValue(name, value.into()));
+    }
+
+    w.write_bits((value as u32) & ((1 << num_bits) - 1), num_bits)?;
+    Ok(())
+}
+
+/// Implements ns(n), the inverse of [`crate::codec::av1::reader::Reader::read_ns`].
+fn write_ns(
+    w: &mut BitWriter,
+    n: u32,
+    value: u32,
+    name: &'static str,
+) -> Result<(), ObuWriterError> {
+    if value >= n {
+        return Err(ObuWriterError::InvalidValue(name, value.into()));
+    }
+
+    let num_bits = helpers::floor_log2(n) as usize + 1;
+    let m = (1 << num_bits) - n;
+    if value < m {
+        w.write_bits(value, num_bits - 1)?;
+    } else {
+        w.write_bits(m + ((value - m) >> 1), num_bits - 1)?;
+        w.write_bit((value - m) & 1 != 0);
+    }
+
+    Ok(())
+}
+
+/// Implements 5.9.13: Delta quantizer syntax.
+fn write_delta_q(w: &mut BitWriter, value: i32, name: &'static str) -> Result<(), ObuWriterError> {
+    w.write_bit(value != 0);
+    if value != 0 {
+        write_su(w, value, 7, name)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the increment flags coding `value` in `[min, max]`, as done for `TileColsLog2` and
+/// `TileRowsLog2`.
+fn write_increments(
+    w: &mut BitWriter,
+    value: u32,
+    min: u32,
+    max: u32,
+    name: &'static str,
+) -> Result<(), ObuWriterError> {
+    if value < min || value > max {
+        return Err(ObuWriterError::InvalidValue(name, value.into()));
+    }
+
+    for _ in min..value {
+        w.write_bit(true);
+    }
+    if value < max {
+        w.write_bit(false);
+    }
+
+    Ok(())
+}
+
+/// The inverse of inverse_recenter(), see 5.9.29.
+fn recenter(r: i32, v: i32) -> i32 {
+    if v > 2 * r {
+        v
+    } else if v >= r {
+        (v - r) << 1
+    } else {
+        ((r - v) << 1) - 1
+    }
+}
+
+/// The inverse of decode_subexp(), see 5.9.28.
+fn encode_subexp(w: &mut BitWriter, num_syms: i32, value: i32) -> Result<(), ObuWriterError> {
+    let mut i = 0;
+    let mut mk = 0;
+    let k = 3;
+
+    loop {
+        let b2 = if i != 0 { k + i - 1 } else { k };
+        let a = 1 << b2;
+        if num_syms <= mk + 3 * a {
+            return write_ns(
+                w,
+                (num_syms - mk) as u32,
+                (value - mk) as u32,
+                "subexp_final_bits",
+            );
+        }
+
+        let subexp_more_bits = value >= mk + a;
+        w.write_bit(subexp_more_bits);
+        if subexp_more_bits {
+            i += 1;
+            mk += a;
+        } else {
+            w.write_bits((value - mk) as u32, b2 as usize)?;
+            return Ok(());
+        }
+    }
+}
+
+/// The inverse of decode_signed_subexp_with_ref(), see 5.9.26 and 5.9.27: codes `value`, which
+/// must be in `[low, high)`, relative to `r`.
+fn encode_signed_subexp_with_ref(
+    w: &mut BitWriter,
+    low: i32,
+    high: i32,
+    r: i32,
+    value: i32,
+) -> Result<(), ObuWriterError> {
+    let mx = high - low;
+    let r = r - low;
+    let x = value - low;
+
+    let v = if (r << 1) <= mx {
+        recenter(r, x)
+    } else {
+        recenter(mx - 1 - r, mx - 1 - x)
+    };
+
+    encode_subexp(w, mx, v)
+}
+
+/// Writes the obu_header() syntax element.
+pub fn write_obu_header(w: &mut BitWriter, header: &ObuHeader) -> Result<(), ObuWriterError> {
+    // obu_forbidden_bit
+    w.write_bit(false);
+    w.write_bits(header.obu_type as u32, 4)?;
+    w.write_bit(header.extension_flag);
+    w.write_bit(header.has_size_field);
+    // obu_reserved_1bit
+    w.write_bit(false);
+
+    if header.extension_flag {
+        w.write_bits(header.temporal_id, 3)?;
+        w.write_bits(header.spatial_id, 2)?;
+        // extension_header_reserved_3bits
+        w.write_bits(0, 3)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the OBU made of `header` and `payload`. The size of the payload is inserted after the
+/// header if `header.has_size_field` is set, which is required by the low-overhead bitstream
+/// format.
+pub fn write_obu(header: &ObuHeader, payload: &[u8]) -> Result<Vec<u8>, ObuWriterError> {
+    let mut w = BitWriter::new();
+    write_obu_header(&mut w, header)?;
+
+    if header.has_size_field {
+        let size = u32::try_from(payload.len())
+            .map_err(|_| ObuWriterError::InvalidValue("obu_size", payload.len() as i64))?;
+        w.write_bytes(&leb128(size));
+    }
+    w.write_bytes(payload);
+
+    Ok(w.into_bytes())
+}
+
+/// Returns whether the color config of `seq` describes the sRGB color space, in which case the
+/// range and subsampling are not coded.
+fn is_srgb(cc: &ColorConfig) -> bool {
+    cc.color_description_present_flag
+        && cc.color_primaries == ColorPrimaries::Bt709
+        && cc.transfer_characteristics == TransferCharacteristics::Srgb
+        && cc.matrix_coefficients == MatrixCoefficients::Identity
+}
+
+fn is_mono_chrome(seq: &SequenceHeaderObu) -> bool {
+    seq.seq_profile != Profile::Profile1 && seq.color_config.mono_chrome
+}
+
+fn is_twelve_bit(seq: &SequenceHeaderObu) -> bool {
+    let cc = &seq.color_config;
+    seq.seq_profile == Profile::Profile2 && cc.high_bitdepth && cc.twelve_bit
+}
+
+/// Returns the chroma subsampling of `seq`, which is inferred from the profile for most streams.
+fn subsampling(seq: &SequenceHeaderObu) -> (bool, bool) {
+    let cc = &seq.color_config;
+
+    if is_mono_chrome(seq) {
+        (true, true)
+    } else if is_srgb(cc) {
+        (false, false)
+    } else {
+        match seq.seq_profile {
+            Profile::Profile0 => (true, true),
+            Profile::Profile1 => (false, false),
+            Profile::Profile2 if is_twelve_bit(seq) => {
+                (cc.subsampling_x, cc.subsampling_x && cc.subsampling_y)
+            }
+            Profile::Profile2 => (true, false),
+        }
+    }
+}
+
+/// Implements 5.5.2: Color config syntax.
+fn write_color_config(w: &mut BitWriter, seq: &SequenceHeaderObu) -> Result<(), ObuWriterError> {
+    let cc = &seq.color_config;
+
+    w.write_bit(cc.high_bitdepth);
+    if seq.seq_profile == Profile::Profile2 && cc.high_bitdepth {
+        w.write_bit(cc.twelve_bit);
+    }
+
+    if seq.seq_profile != Profile::Profile1 {
+        w.write_bit(cc.mono_chrome);
+    }
+
+    w.write_bit(cc.color_description_present_flag);
+    if cc.color_description_present_flag {
+        w.write_bits(cc.color_primaries as u32, 8)?;
+        w.write_bits(cc.transfer_characteristics as u32, 8)?;
+        w.write_bits(cc.matrix_coefficients as u32, 8)?;
+    }
+
+    if is_mono_chrome(seq) {
+        w.write_bit(cc.color_range);
+        return Ok(());
+    }
+
+    if !is_srgb(cc) {
+        w.write_bit(cc.color_range);
+        if is_twelve_bit(seq) {
+            w.write_bit(cc.subsampling_x);
+            if cc.subsampling_x {
+                w.write_bit(cc.subsampling_y);
+            }
+        }
+
+        if subsampling(seq) == (true, true) {
+            w.write_bits(cc.chroma_sample_position as u32, 2)?;
+        }
+    }
+
+    w.write_bit(cc.separate_uv_delta_q);
+
+    Ok(())
+}
+
+/// Writes the sequence_header_obu() syntax element of `seq`.
+pub fn write_sequence_header(
+    w: &mut BitWriter,
+    seq: &SequenceHeaderObu,
+) -> Result<(), ObuWriterError> {
+    w.write_bits(seq.seq_profile as u32, 3)?;
+    w.write_bit(seq.still_picture);
+    w.write_bit(seq.reduced_still_picture_header);
+
+    if seq.reduced_still_picture_header {
+        w.write_bits(seq.operating_points[0].seq_level_idx, 5)?;
+    } else {
+        w.write_bit(seq.timing_info_present_flag);
+        let decoder_model_info_present =
+            seq.timing_info_present_flag && seq.decoder_model_info_present_flag;
+
+        if seq.timing_info_present_flag {
+            let ti = &seq.timing_info;
+            w.write_bits(ti.num_units_in_display_tick, 32)?;
+            w.write_bits(ti.time_scale, 32)?;
+            w.write_bit(ti.equal_picture_interval);
+            if ti.equal_picture_interval {
+                // uvlc() is coded the same way as ue(v).
+                w.write_ue(ti.num_ticks_per_picture_minus_1)?;
+            }
+
+            w.write_bit(seq.decoder_model_info_present_flag);
+            if seq.decoder_model_info_present_flag {
+                let dmi = &seq.decoder_model_info;
+                w.write_bits(dmi.buffer_delay_length_minus_1, 5)?;
+                w.write_bits(dmi.num_units_in_decoding_tick, 32)?;
+                w.write_bits(dmi.buffer_removal_time_length_minus_1, 5)?;
+                w.write_bits(dmi.frame_presentation_time_length_minus_1, 5)?;
+            }
+        }
+
+        w.write_bit(seq.initial_display_delay_present_flag);
+
+        let num_operating_points = seq.operating_points_cnt_minus_1 as usize + 1;
+        if num_operating_points > MAX_NUM_OPERATING_POINTS {
+            return Err(ObuWriterError::InvalidValue(
+                "operating_points_cnt_minus_1",
+                seq.operating_points_cnt_minus_1.into(),
+            ));
+        }
+        w.write_bits(seq.operating_points_cnt_minus_1, 5)?;
+
+        for op in &seq.operating_points[..num_operating_points] {
+            w.write_bits(op.idc, 12)?;
+            w.write_bits(op.seq_level_idx, 5)?;
+            if op.seq_level_idx > 7 {
+                w.write_bits(op.seq_tier, 1)?;
+            }
+
+            if decoder_model_info_present {
+                w.write_bit(op.decoder_model_present_for_this_op);
+                if op.decoder_model_present_for_this_op {
+                    let n = seq.decoder_model_info.buffer_delay_length_minus_1 as usize + 1;
+                    w.write_bits(op.decoder_buffer_delay, n)?;
+                    w.write_bits(op.encoder_buffer_delay, n)?;
+                    w.write_bit(op.low_delay_mode_flag);
+                }
+            }
+
+            if seq.initial_display_delay_present_flag {
+                w.write_bit(op.initial_display_delay_present_for_this_op);
+                if op.initial_display_delay_present_for_this_op {
+                    w.write_bits(op.initial_display_delay_minus_1, 4)?;
+                }
+            }
+        }
+    }
+
+    w.write_bits(seq.frame_width_bits_minus_1, 4)?;
+    w.write_bits(seq.frame_height_bits_minus_1, 4)?;
+    w.write_bits(
+        seq.max_frame_width_minus_1,
+        seq.frame_width_bits_minus_1 as usize + 1,
+    )?;
+    w.write_bits(
+        seq.max_frame_height_minus_1,
+        seq.frame_height_bits_minus_1 as usize + 1,
+    )?;
+
+    if !seq.reduced_still_picture_header {
+        w.write_bit(seq.frame_id_numbers_present_flag);
+        if seq.frame_id_numbers_present_flag {
+            w.write_bits(seq.delta_frame_id_length_minus_2, 4)?;
+            w.write_bits(seq.additional_frame_id_length_minus_1, 3)?;
+        }
+    }
+
+    w.write_bit(seq.use_128x128_superblock);
+    w.write_bit(seq.enable_filter_intra);
+    w.write_bit(seq.enable_intra_edge_filter);
+
+    if !seq.reduced_still_picture_header {
+        w.write_bit(seq.enable_interintra_compound);
+        w.write_bit(seq.enable_masked_compound);
+        w.write_bit(seq.enable_warped_motion);
+        w.write_bit(seq.enable_dual_filter);
+        w.write_bit(seq.enable_order_hint);
+        if seq.enable_order_hint {
+            w.write_bit(seq.enable_jnt_comp);
+            w.write_bit(seq.enable_ref_frame_mvs);
+        }
+
+        // The seq_choose_* flags are implied by the values of their seq_force_* counterparts.
+        let seq_choose_screen_content_tools =
+            seq.seq_force_screen_content_tools == SELECT_SCREEN_CONTENT_TOOLS as u32;
+        w.write_bit(seq_choose_screen_content_tools);
+        if !seq_choose_screen_content_tools {
+            w.write_bits(seq.seq_force_screen_content_tools, 1)?;
+        }
+
+        if seq.seq_force_screen_content_tools > 0 {
+            let seq_choose_integer_mv = seq.seq_force_integer_mv == SELECT_INTEGER_MV as u32;
+            w.write_bit(seq_choose_integer_mv);
+            if !seq_choose_integer_mv {
+                w.write_bits(seq.seq_force_integer_mv, 1)?;
+            }
+        }
+
+        if seq.enable_order_hint {
+            let order_hint_bits_minus_1 =
+                u32::try_from(seq.order_hint_bits_minus_1).map_err(|_| {
+                    ObuWriterError::InvalidValue(
+                        "order_hint_bits_minus_1",
+                        seq.order_hint_bits_minus_1.into(),
+                    )
+                })?;
+            w.write_bits(order_hint_bits_minus_1, 3)?;
+        }
+    }
+
+    w.write_bit(seq.enable_superres);
+    w.write_bit(seq.enable_cdef);
+    w.write_bit(seq.enable_restoration);
+    write_color_config(w, seq)?;
+    w.write_bit(seq.film_grain_params_present);
+
+    Ok(())
+}
+
+/// Returns the sequence header OBU of `seq`, including its trailing bits. The OBU header is
+/// `seq.obu_header`, with the type set to [`ObuType::SequenceHeader`].
+pub fn write_sequence_header_obu(seq: &SequenceHeaderObu) -> Result<Vec<u8>, ObuWriterError> {
+    let mut w = BitWriter::new();
+    write_sequence_header(&mut w, seq)?;
+    w.write_trailing_bits();
+
+    let header = ObuHeader {
+        obu_type: ObuType::SequenceHeader,
+        ..seq.obu_header.clone()
+    };
+
+    write_obu(&header, &w.into_bytes())
+}
+
+/// Values that the frame header syntax depends upon and which are saved for each reference frame.
+#[derive(Clone, Debug)]
+struct RefSlot {
+    frame_type: FrameType,
+    frame_id: u32,
+    order_hint: u32,
+    gm_params: [[i32; 6]; NUM_REF_FRAMES],
+    loop_filter_ref_deltas: [i32; TOTAL_REFS_PER_FRAME],
+    loop_filter_mode_deltas: [i32; 2],
+}
+
+impl Default for RefSlot {
+    fn default() -> Self {
+        Self {
+            frame_type: Default::default(),
+            frame_id: 0,
+            order_hint: 0,
+            gm_params: default_gm_params(),
+            loop_filter_ref_deltas: DEFAULT_LOOP_FILTER_REF_DELTAS,
+            loop_filter_mode_deltas: [0; 2],
+        }
+    }
+}
+
+/// Writer of frame headers.
+///
+/// Some syntax elements of the frame header are coded relative to the reference frames, so the
+/// writer keeps track of the reference frame slots, which it updates according to
+/// `refresh_frame_flags` every time a frame header is written. Frames must therefore be written in
+/// decoding order.
+///
+/// The reference frames and their size are always signaled explicitly, i.e.
+/// `frame_refs_short_signaling` and `found_ref` are never set.
+#[derive(Debug, Default)]
+pub struct FrameHeaderWriter {
+    ref_slots: [RefSlot; NUM_REF_FRAMES],
+}
+
+impl FrameHeaderWriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    fn ref_slot(&self, fh: &FrameHeaderObu, i: usize) -> Result<&RefSlot, ObuWriterError> {
+        let idx = fh.ref_frame_idx[i];
+
+        usize::try_from(idx)
+            .ok()
+            .and_then(|idx| self.ref_slots.get(idx))
+            .ok_or(ObuWriterError::InvalidValue("ref_frame_idx", idx.into()))
+    }
+
+    /// Writes the uncompressed_header() syntax element of `fh`, which belongs to the sequence
+    /// described by `seq`.
+    ///
+    /// The frame size is taken from `upscaled_width` if superres is used, and from `frame_width`
+    /// otherwise.
+    pub fn write_uncompressed_header(
+        &mut self,
+        w: &mut BitWriter,
+        seq: &SequenceHeaderObu,
+        fh: &FrameHeaderObu,
+    ) -> Result<(), ObuWriterError> {
+        let reduced_still_picture_header = seq.reduced_still_picture_header;
+        let frame_id_numbers_present =
+            !reduced_still_picture_header && seq.frame_id_numbers_present_flag;
+        let id_len = (seq.additional_frame_id_length_minus_1
+            + seq.delta_frame_id_length_minus_2
+            + 3) as usize;
+        let decoder_model_info_present = !reduced_still_picture_header
+            && seq.timing_info_present_flag
+            && seq.decoder_model_info_present_flag;
+        let equal_picture_interval = seq.timing_info.equal_picture_interval;
+        let frame_presentation_time_length =
+            seq.decoder_model_info
+                .frame_presentation_time_length_minus_1 as usize
+                + 1;
+        let enable_order_hint = !reduced_still_picture_header && seq.enable_order_hint;
+        let order_hint_bits = if enable_order_hint {
+            seq.order_hint_bits_minus_1 + 1
+        } else {
+            0
+        };
+        let (seq_force_screen_content_tools, seq_force_integer_mv) = if reduced_still_picture_header
+        {
+            (SELECT_SCREEN_CONTENT_TOOLS as u32, SELECT_INTEGER_MV as u32)
+        } else {
+            (seq.seq_force_screen_content_tools, seq.seq_force_integer_mv)
+        };
+        let mono_chrome = is_mono_chrome(seq);
+        let num_planes = if mono_chrome { 1 } else { 3 };
+        let (subsampling_x, subsampling_y) = subsampling(seq);
+
+        let frame_type;
+        let show_frame;
+        let showable_frame;
+        let error_resilient_mode;
+
+        if reduced_still_picture_header {
+            frame_type = FrameType::KeyFrame;
+            show_frame = true;
+            showable_frame = false;
+            error_resilient_mode = true;
+        } else {
+            w.write_bit(fh.show_existing_frame);
+            if fh.show_existing_frame {
+                w.write_bits(fh.frame_to_show_map_idx, 3)?;
+                if decoder_model_info_present && !equal_picture_interval {
+                    w.write_bits(fh.frame_presentation_time, frame_presentation_time_length)?;
+                }
+                if frame_id_numbers_present {
+                    w.write_bits(fh.display_frame_id, id_len)?;
+                }
+
+                // Showing a key frame refreshes all the slots with it, see 7.21.
+                let slot = self.ref_slots[fh.frame_to_show_map_idx as usize].clone();
+                if slot.frame_type == FrameType::KeyFrame {
+                    self.ref_slots = std::array::from_fn(|_| slot.clone());
+                }
+
+                return Ok(());
+            }
+
+            frame_type = fh.frame_type;
+            w.write_bits(frame_type as u32, 2)?;
+
+            show_frame = fh.show_frame;
+            w.write_bit(show_frame);
+            if show_frame && decoder_model_info_present && !equal_picture_interval {
+                w.write_bits(fh.frame_presentation_time, frame_presentation_time_length)?;
+            }
+
+            if show_frame {
+                showable_frame = frame_type != FrameType::KeyFrame;
+            } else {
+                showable_frame = fh.showable_frame;
+                w.write_bit(showable_frame);
+            }
+
+            if frame_type == FrameType::SwitchFrame
+                || (frame_type == FrameType::KeyFrame && show_frame)
+            {
+                error_resilient_mode = true;
+            } else {
+                error_resilient_mode = fh.error_resilient_mode;
+                w.write_bit(error_resilient_mode);
+            }
+        }
+
+        let frame_is_intra = matches!(frame_type, FrameType::IntraOnlyFrame | FrameType::KeyFrame);
+
+        if frame_type == FrameType::KeyFrame && show_frame {
+            for slot in &mut self.ref_slots {
+                slot.order_hint = 0;
+            }
+        }
+
+        w.write_bit(fh.disable_cdf_update);
+
+        let allow_screen_content_tools =
+            if seq_force_screen_content_tools == SELECT_SCREEN_CONTENT_TOOLS as u32 {
+                w.write_bit(fh.allow_screen_content_tools != 0);
+                fh.allow_screen_content_tools != 0
+            } else {
+                seq_force_screen_content_tools != 0
+            };
+
+        // Intra frames always use integer motion vectors, so the parser reports force_integer_mv
+        // as set for them whatever its coded value.
+        let mut force_integer_mv = false;
+        if allow_screen_content_tools {
+            if seq_force_integer_mv == SELECT_INTEGER_MV as u32 {
+                force_integer_mv = !frame_is_intra && fh.force_integer_mv != 0;
+                w.write_bit(force_integer_mv);
+            } else {
+                force_integer_mv = seq_force_integer_mv != 0;
+            }
+        }
+        if frame_is_intra {
+            force_integer_mv = true;
+        }
+
+        let current_frame_id = if frame_id_numbers_present {
+            w.write_bits(fh.current_frame_id, id_len)?;
+            fh.current_frame_id
+        } else {
+            0
+        };
+
+        let frame_size_override_flag = if frame_type == FrameType::SwitchFrame {
+            true
+        } else if reduced_still_picture_header {
+            false
+        } else {
+            w.write_bit(fh.frame_size_override_flag);
+            fh.frame_size_override_flag
+        };
+
+        let order_hint = if enable_order_hint { fh.order_hint } else { 0 };
+        w.write_bits(order_hint, order_hint_bits as usize)?;
+
+        let primary_ref_frame = if frame_is_intra || error_resilient_mode {
+            PRIMARY_REF_NONE
+        } else {
+            w.write_bits(fh.primary_ref_frame, 3)?;
+            fh.primary_ref_frame
+        };
+
+        if decoder_model_info_present {
+            w.write_bit(fh.buffer_removal_time_present_flag);
+            if fh.buffer_removal_time_present_flag {
+                let num_operating_points = seq.operating_points_cnt_minus_1 as usize + 1;
+                let operating_points = seq
+                    .operating_points
+                    .get(..num_operating_points)
+                    .unwrap_or(&[]);
+
+                for (op_num, op) in operating_points.iter().enumerate() {
+                    if op.decoder_model_present_for_this_op {
+                        let in_temporal_layer = (op.idc >> fh.obu_header.temporal_id) & 1 != 0;
+                        let in_spatial_layer = (op.idc >> (fh.obu_header.spatial_id + 8)) & 1 != 0;
+
+                        if op.idc == 0 || (in_temporal_layer && in_spatial_layer) {
+                            let n = seq.decoder_model_info.buffer_removal_time_length_minus_1
+                                as usize
+                                + 1;
+                            let buffer_removal_time =
+                                fh.buffer_removal_time.get(op_num).copied().unwrap_or(0);
+                            w.write_bits(buffer_removal_time, n)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        let refresh_frame_flags = if frame_type == FrameType::SwitchFrame
+            || (frame_type == FrameType::KeyFrame && show_frame)
+        {
+            ALL_FRAMES
+        } else {
+            w.write_bits(fh.refresh_frame_flags, 8)?;
+            fh.refresh_frame_flags
+        };
+
+        if (!frame_is_intra || refresh_frame_flags != ALL_FRAMES)
+            && error_resilient_mode
+            && enable_order_hint
+        {
+            for (slot, &ref_order_hint) in self.ref_slots.iter_mut().zip(&fh.ref_order_hint) {
+                w.write_bits(ref_order_hint, order_hint_bits as usize)?;
+                slot.order_hint = ref_order_hint;
+            }
+        }
+
+        let use_superres = seq.enable_superres && fh.use_superres;
+        let upscaled_width = if use_superres {
+            fh.upscaled_width
+        } else {
+            fh.frame_width
+        };
+
+        let frame_width;
+        let mut allow_intrabc = false;
+        let mut allow_high_precision_mv = false;
+
+        if frame_is_intra {
+            frame_width =
+                Self::write_frame_size(w, seq, fh, frame_size_override_flag, upscaled_width)?;
+            Self::write_render_size(w, fh)?;
+
+            if allow_screen_content_tools && upscaled_width == frame_width {
+                allow_intrabc = fh.allow_intrabc;
+                w.write_bit(allow_intrabc);
+            }
+        } else {
+            if enable_order_hint {
+                // frame_refs_short_signaling
+                w.write_bit(false);
+            }
+
+            for i in 0..REFS_PER_FRAME {
+                w.write_bits(fh.ref_frame_idx[i] as u32, 3)?;
+
+                if frame_id_numbers_present {
+                    let diff_len = seq.delta_frame_id_length_minus_2 as usize + 2;
+                    let ref_frame_id = self.ref_slot(fh, i)?.frame_id;
+                    let delta_frame_id =
+                        (current_frame_id + (1 << id_len) - ref_frame_id) % (1 << id_len);
+
+                    if delta_frame_id == 0 || delta_frame_id > 1 << diff_len {
+                        return Err(ObuWriterError::InvalidValue(
+                            "delta_frame_id",
+                            delta_frame_id.into(),
+                        ));
+                    }
+                    w.write_bits(delta_frame_id - 1, diff_len)?;
+                }
+            }
+
+            if frame_size_override_flag && !error_resilient_mode {
+                // found_ref
+                for _ in 0..REFS_PER_FRAME {
+                    w.write_bit(false);
+                }
+            }
+
+            frame_width =
+                Self::write_frame_size(w, seq, fh, frame_size_override_flag, upscaled_width)?;
+            Self::write_render_size(w, fh)?;
+
+            if !force_integer_mv {
+                allow_high_precision_mv = fh.allow_high_precision_mv;
+                w.write_bit(allow_high_precision_mv);
+            }
+
+            // read_interpolation_filter()
+            let is_filter_switchable = fh.interpolation_filter == InterpolationFilter::Switchable;
+            w.write_bit(is_filter_switchable);
+            if !is_filter_switchable {
+                w.write_bits(fh.interpolation_filter as u32, 2)?;
+            }
+
+            w.write_bit(fh.is_motion_mode_switchable);
+
+            if !error_resilient_mode && seq.enable_ref_frame_mvs {
+                w.write_bit(fh.use_ref_frame_mvs);
+            }
+        }
+
+        if !reduced_still_picture_header && !fh.disable_cdf_update {
+            w.write_bit(fh.disable_frame_end_update_cdf);
+        }
+
+        // load_previous() or setup_past_independence().
+        let prev_slot = if primary_ref_frame == PRIMARY_REF_NONE {
+            Default::default()
+        } else {
+            self.ref_slot(fh, primary_ref_frame as usize)?.clone()
+        };
+
+        let mi_cols = 2 * ((frame_width + 7) >> 3);
+        let mi_rows = 2 * ((fh.frame_height + 7) >> 3);
+        Self::write_tile_info(
+            w,
+            &fh.tile_info,
+            seq.use_128x128_superblock,
+            mi_cols,
+            mi_rows,
+        )?;
+
+        let separate_uv_delta_q = !mono_chrome && seq.color_config.separate_uv_delta_q;
+        let q = &fh.quantization_params;
+        Self::write_quantization_params(w, q, num_planes, separate_uv_delta_q)?;
+        Self::write_segmentation_params(w, &fh.segmentation_params, primary_ref_frame)?;
+
+        // delta_q_params()
+        let delta_q_present = q.base_q_idx > 0 && q.delta_q_present;
+        if q.base_q_idx > 0 {
+            w.write_bit(delta_q_present);
+        }
+        if delta_q_present {
+            w.write_bits(q.delta_q_res, 2)?;
+        }
+
+        // delta_lf_params()
+        let lf = &fh.loop_filter_params;
+        if delta_q_present {
+            let delta_lf_present = !allow_intrabc && lf.delta_lf_present;
+            if !allow_intrabc {
+                w.write_bit(delta_lf_present);
+            }
+            if delta_lf_present {
+                w.write_bits(lf.delta_lf_res, 2)?;
+                w.write_bits(lf.delta_lf_multi, 1)?;
+            }
+        }
+
+        let coded_lossless = Self::coded_lossless(fh, num_planes, separate_uv_delta_q);
+        let all_lossless = coded_lossless && frame_width == upscaled_width;
+
+        let (loop_filter_ref_deltas, loop_filter_mode_deltas) = if coded_lossless || allow_intrabc {
+            (DEFAULT_LOOP_FILTER_REF_DELTAS, [0; 2])
+        } else {
+            Self::write_loop_filter_params(w, lf, num_planes, &prev_slot)?
+        };
+
+        if !coded_lossless && !allow_intrabc && seq.enable_cdef {
+            Self::write_cdef_params(w, &fh.cdef_params, num_planes)?;
+        }
+
+        if !all_lossless && !allow_intrabc && seq.enable_restoration {
+            Self::write_loop_restoration_params(
+                w,
+                &fh.loop_restoration_params,
+                num_planes,
+                seq.use_128x128_superblock,
+                subsampling_x && subsampling_y,
+            )?;
+        }
+
+        // read_tx_mode()
+        if !coded_lossless {
+            w.write_bit(fh.tx_mode == TxMode::Select);
+        }
+
+        // frame_reference_mode()
+        let reference_select = !frame_is_intra && fh.reference_select;
+        if !frame_is_intra {
+            w.write_bit(reference_select);
+        }
+
+        // skip_mode_params()
+        if !frame_is_intra
+            && reference_select
+            && enable_order_hint
+            && self.skip_mode_allowed(fh, order_hint_bits, order_hint)?
+        {
+            w.write_bit(fh.skip_mode_present);
+        }
+
+        if !frame_is_intra && !error_resilient_mode && seq.enable_warped_motion {
+            w.write_bit(fh.allow_warped_motion);
+        }
+
+        w.write_bit(fh.reduced_tx_set);
+
+        let gm_params = if frame_is_intra {
+            default_gm_params()
+        } else {
+            Self::write_global_motion_params(
+                w,
+                &fh.global_motion_params,
+                allow_high_precision_mv,
+                &prev_slot.gm_params,
+            )?
+        };
+
+        if seq.film_grain_params_present && (show_frame || showable_frame) {
+            Self::write_film_grain_params(
+                w,
+                &fh.film_grain_params,
+                frame_type,
+                &fh.ref_frame_idx,
+                mono_chrome,
+                subsampling_x && subsampling_y,
+            )?;
+        }
+
+        // Reference frame update process, see 7.20.
+        let slot = RefSlot {
+            frame_type,
+            frame_id: current_frame_id,
+            order_hint,
+            gm_params,
+            loop_filter_ref_deltas,
+            loop_filter_mode_deltas,
+        };
+        for (i, ref_slot) in self.ref_slots.iter_mut().enumerate() {
+            if (refresh_frame_flags >> i) & 1 != 0 {
+                *ref_slot = slot.clone();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the frame header OBU of `fh`, including its trailing bits. The OBU header is
+    /// `fh.obu_header`, with the type set to [`ObuType::FrameHeader`].
+    pub fn write_frame_header_obu(
+        &mut self,
+        seq: &SequenceHeaderObu,
+        fh: &FrameHeaderObu,
+    ) -> Result<Vec<u8>, ObuWriterError> {
+        let mut w = BitWriter::new();
+        self.write_uncompressed_header(&mut w, seq, fh)?;
+        w.write_trailing_bits();
+
+        let header = ObuHeader {
+            obu_type: ObuType::FrameHeader,
+            ..fh.obu_header.clone()
+        };
+
+        write_obu(&header, &w.into_bytes())
+    }
+
+    /// Returns the frame OBU made of `fh` followed by `tile_group`, the content of a
+    /// tile_group_obu(). The OBU header is `fh.obu_header`, with the type set to
+    /// [`ObuType::Frame`].
+    pub fn write_frame_obu(
+        &mut self,
+        seq: &SequenceHeaderObu,
+        fh: &FrameHeaderObu,
+        tile_group: &[u8],
+    ) -> Result<Vec<u8>, ObuWriterError> {
+        let mut w = BitWriter::new();
+        self.write_uncompressed_header(&mut w, seq, fh)?;
+        // byte_alignment()
+        w.align();
+        w.write_bytes(tile_group);
+
+        let header = ObuHeader {
+            obu_type: ObuType::Frame,
+            ..fh.obu_header.clone()
+        };
+
+        write_obu(&header, &w.into_bytes())
+    }
+
+    /// Implements 5.9.5 and 5.9.8: Frame size and superres params syntax. Returns `FrameWidth`.
+    fn write_frame_size(
+        w: &mut BitWriter,
+        seq: &SequenceHeaderObu,
+        fh: &FrameHeaderObu,
+        frame_size_override_flag: bool,
+        upscaled_width: u32,
+    ) -> Result<u32, ObuWriterError> {
+        if frame_size_override_flag {
+            let frame_width_minus_1 = upscaled_width
+                .checked_sub(1)
+                .ok_or(ObuWriterError::InvalidValue("frame_width", 0))?;
+            let frame_height_minus_1 = fh
+                .frame_height
+                .checked_sub(1)
+                .ok_or(ObuWriterError::InvalidValue("frame_height", 0))?;
+
+            w.write_bits(
+                frame_width_minus_1,
+                seq.frame_width_bits_minus_1 as usize + 1,
+            )?;
+            w.write_bits(
+                frame_height_minus_1,
+                seq.frame_height_bits_minus_1 as usize + 1,
+            )?;
+        } else if upscaled_width != seq.max_frame_width_minus_1 + 1 {
+            return Err(ObuWriterError::InvalidValue(
+                "frame_width",
+                upscaled_width.into(),
+            ));
+        } else if fh.frame_height != seq.max_frame_height_minus_1 + 1 {
+            return Err(ObuWriterError::InvalidValue(
+                "frame_height",
+                fh.frame_height.into(),
+            ));
+        }
+
+        let mut superres_denom = SUPERRES_NUM as u32;
+        if seq.enable_superres {
+            w.write_bit(fh.use_superres);
+            if fh.use_superres {
+                superres_denom = fh.superres_denom;
+                let coded_denom = superres_denom
+                    .checked_sub(SUPERRES_DENOM_MIN as u32)
+                    .ok_or(ObuWriterError::InvalidValue(
+                        "superres_denom",
+                        superres_denom.into(),
+                    ))?;
+                w.write_bits(coded_denom, SUPERRES_DENOM_BITS)?;
+            }
+        }
+
+        Ok((upscaled_width * SUPERRES_NUM as u32 + (superres_denom / 2)) / superres_denom)
+    }
+
+    /// Implements 5.9.6: Render size syntax.
+    fn write_render_size(w: &mut BitWriter, fh: &FrameHeaderObu) -> Result<(), ObuWriterError> {
+        w.write_bit(fh.render_and_frame_size_different);
+        if fh.render_and_frame_size_different {
+            let render_width_minus_1 = fh
+                .render_width
+                .checked_sub(1)
+                .ok_or(ObuWriterError::InvalidValue("render_width", 0))?;
+            let render_height_minus_1 = fh
+                .render_height
+                .checked_sub(1)
+                .ok_or(ObuWriterError::InvalidValue("render_height", 0))?;
+
+            w.write_bits(render_width_minus_1, 16)?;
+            w.write_bits(render_height_minus_1, 16)?;
+        }
+
+        Ok(())
+    }
+
+    /// Implements 5.9.15: Tile info syntax.
+    fn write_tile_info(
+        w: &mut BitWriter,
+        ti: &TileInfo,
+        use_128x128_superblock: bool,
+        mi_cols: u32,
+        mi_rows: u32,
+    ) -> Result<(), ObuWriterError> {
+        let sb_shift = if use_128x128_superblock { 5 } else { 4 };
+        let sb_cols = (mi_cols + (1 << sb_shift) - 1) >> sb_shift;
+        let sb_rows = (mi_rows + (1 << sb_shift) - 1) >> sb_shift;
+        let sb_size = sb_shift + 2;
+
+        let max_tile_width_sb = MAX_TILE_WIDTH >> sb_size;
+        let mut max_tile_area_sb = MAX_TILE_AREA >> (2 * sb_size);
+        let min_log2_tile_cols = helpers::tile_log2(max_tile_width_sb, sb_cols);
+        let max_log2_tile_cols =
+            helpers::tile_log2(1, std::cmp::min(sb_cols, MAX_TILE_COLS as u32));
+        let max_log2_tile_rows =
+            helpers::tile_log2(1, std::cmp::min(sb_rows, MAX_TILE_ROWS as u32));
+        let min_log2_tiles = std::cmp::max(
+            min_log2_tile_cols,
+            helpers::tile_log2(max_tile_area_sb, sb_rows * sb_cols),
+        );
+
+        w.write_bit(ti.uniform_tile_spacing_flag);
+
+        let tile_cols_log2;
+        let tile_rows_log2;
+        let num_tiles;
+
+        if ti.uniform_tile_spacing_flag {
+            tile_cols_log2 = ti.tile_cols_log2;
+            write_increments(
+                w,
+                tile_cols_log2,
+                min_log2_tile_cols,
+                max_log2_tile_cols,
+                "tile_cols_log2",
+            )?;
+
+            let min_log2_tile_rows = min_log2_tiles.saturating_sub(tile_cols_log2);
+            tile_rows_log2 = ti.tile_rows_log2;
+            write_increments(
+                w,
+                tile_rows_log2,
+                min_log2_tile_rows,
+                max_log2_tile_rows,
+                "tile_rows_log2",
+            )?;
+
+            let tile_width_sb = (sb_cols + (1 << tile_cols_log2) - 1) >> tile_cols_log2;
+            let tile_height_sb = (sb_rows + (1 << tile_rows_log2) - 1) >> tile_rows_log2;
+            num_tiles = sb_cols.div_ceil(tile_width_sb) * sb_rows.div_ceil(tile_height_sb);
+        } else {
+            let mut widest_tile_sb = 0;
+            let mut start_sb = 0;
+            let mut tile_cols = 0;
+
+            while start_sb < sb_cols {
+                let width_in_sbs_minus_1 = *ti
+                    .width_in_sbs_minus_1
+                    .get(tile_cols)
+                    .ok_or(ObuWriterError::InvalidValue("tile_cols", tile_cols as i64))?;
+                let max_width = std::cmp::min(sb_cols - start_sb, max_tile_width_sb);
+                write_ns(w, max_width, width_in_sbs_minus_1, "width_in_sbs_minus_1")?;
+
+                let size_sb = width_in_sbs_minus_1 + 1;
+                widest_tile_sb = std::cmp::max(size_sb, widest_tile_sb);
+                start_sb += size_sb;
+                tile_cols += 1;
+            }
+
+            if min_log2_tiles > 0 {
+                max_tile_area_sb = (sb_rows * sb_cols) >> (min_log2_tiles + 1);
+            } else {
+                max_tile_area_sb = sb_rows * sb_cols;
+            }
+
+            let max_tile_height_sb = std::cmp::max(max_tile_area_sb / widest_tile_sb, 1);
+            let mut start_sb = 0;
+            let mut tile_rows = 0;
+
+            while start_sb < sb_rows {
+                let height_in_sbs_minus_1 = *ti
+                    .height_in_sbs_minus_1
+                    .get(tile_rows)
+                    .ok_or(ObuWriterError::InvalidValue("tile_rows", tile_rows as i64))?;
+                let max_height = std::cmp::min(sb_rows - start_sb, max_tile_height_sb);
+                write_ns(
+                    w,
+                    max_height,
+                    height_in_sbs_minus_1,
+                    "height_in_sbs_minus_1",
+                )?;
+
+                start_sb += height_in_sbs_minus_1 + 1;
+                tile_rows += 1;
+            }
+
+            tile_cols_log2 = helpers::tile_log2(1, tile_cols as u32);
+            tile_rows_log2 = helpers::tile_log2(1, tile_rows as u32);
+            num_tiles = (tile_cols * tile_rows) as u32;
+        }
+
+        if tile_cols_log2 > 0 || tile_rows_log2 > 0 {
+            if ti.context_update_tile_id >= num_tiles {
+                return Err(ObuWriterError::InvalidValue(
+                    "context_update_tile_id",
+                    ti.context_update_tile_id.into(),
+                ));
+            }
+            w.write_bits(
+                ti.context_update_tile_id,
+                (tile_cols_log2 + tile_rows_log2) as usize,
+            )?;
+
+            let tile_size_bytes_minus_1 =
+                ti.tile_size_bytes
+                    .checked_sub(1)
+                    .ok_or(ObuWriterError::InvalidValue(
+                        "tile_size_bytes",
+                        ti.tile_size_bytes.into(),
+                    ))?;
+            w.write_bits(tile_size_bytes_minus_1, 2)?;
+        }
+
+        Ok(())
+    }
+
+    /// Implements 5.9.12: Quantization params syntax.
+    fn write_quantization_params(
+        w: &mut BitWriter,
+        q: &QuantizationParams,
+        num_planes: u32,
+        separate_uv_delta_q: bool,
+    ) -> Result<(), ObuWriterError> {
+        w.write_bits(q.base_q_idx, 8)?;
+        write_delta_q(w, q.delta_q_y_dc, "delta_q_y_dc")?;
+
+        if num_planes > 1 {
+            let diff_uv_delta = separate_uv_delta_q && q.diff_uv_delta;
+            if separate_uv_delta_q {
+                w.write_bit(diff_uv_delta);
+            }
+
+            write_delta_q(w, q.delta_q_u_dc, "delta_q_u_dc")?;
+            write_delta_q(w, q.delta_q_u_ac, "delta_q_u_ac")?;
+            if diff_uv_delta {
+                write_delta_q(w, q.delta_q_v_dc, "delta_q_v_dc")?;
+                write_delta_q(w, q.delta_q_v_ac, "delta_q_v_ac")?;
+            }
+        }
+
+        w.write_bit(q.using_qmatrix);
+        if q.using_qmatrix {
+            w.write_bits(q.qm_y, 4)?;
+            w.write_bits(q.qm_u, 4)?;
+            if separate_uv_delta_q {
+                w.write_bits(q.qm_v, 4)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Implements 5.9.14: Segmentation params syntax.
+    fn write_segmentation_params(
+        w: &mut BitWriter,
+        s: &SegmentationParams,
+        primary_ref_frame: u32,
+    ) -> Result<(), ObuWriterError> {
+        const FEATURE_BITS: [usize; SEG_LVL_MAX] = [8, 6, 6, 6, 6, 3, 0, 0];
+        const FEATURE_SIGNED: [bool; SEG_LVL_MAX] =
+            [true, true, true, true, true, false, false, false];
+        const FEATURE_MAX: [i32; SEG_LVL_MAX] = [255, 63, 63, 63, 63, 7, 0, 0];
+
+        w.write_bit(s.segmentation_enabled);
+        if !s.segmentation_enabled {
+            return Ok(());
+        }
+
+        let segmentation_update_data = if primary_ref_frame == PRIMARY_REF_NONE {
+            true
+        } else {
+            w.write_bit(s.segmentation_update_map);
+            if s.segmentation_update_map {
+                w.write_bit(s.segmentation_temporal_update);
+            }
+            w.write_bit(s.segmentation_update_data);
+            s.segmentation_update_data
+        };
+
+        if segmentation_update_data {
+            for (feature_enabled, feature_data) in s.feature_enabled.iter().zip(&s.feature_data) {
+                #[allow(clippy::needless_range_loop)]
+                for j in 0..SEG_LVL_MAX {
+                    w.write_bit(feature_enabled[j]);
+                    if !feature_enabled[j] {
+                        continue;
+                    }
+
+                    let value = i32::from(feature_data[j]);
+                    if FEATURE_SIGNED[j] {
+                        if value.abs() > FEATURE_MAX[j] {
+                            return Err(ObuWriterError::InvalidValue(
+                                "feature_value",
+                                value.into(),
+                            ));
+                        }
+                        write_su(w, value, 1 + FEATURE_BITS[j], "feature_value")?;
+                    } else {
+                        if !(0..=FEATURE_MAX[j]).contains(&value) {
+                            return Err(ObuWriterError::InvalidValue(
+                                "feature_value",
+                                value.into(),
+                            ));
+                        }
+                        w.write_bits(value as u32, FEATURE_BITS[j])?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Computes `CodedLossless`, see 7.12.2 for the computation of the quantizer index.
+    fn coded_lossless(fh: &FrameHeaderObu, num_planes: u32, separate_uv_delta_q: bool) -> bool {
+        let q = &fh.quantization_params;
+        let s = &fh.segmentation_params;
+
+        let no_deltas = if num_planes > 1 {
+            let (delta_q_v_dc, delta_q_v_ac) = if separate_uv_delta_q && q.diff_uv_delta {
+                (q.delta_q_v_dc, q.delta_q_v_ac)
+            } else {
+                (q.delta_q_u_dc, q.delta_q_u_ac)
+            };
+
+            q.delta_q_y_dc == 0
+                && q.delta_q_u_dc == 0
+                && q.delta_q_u_ac == 0
+                && delta_q_v_dc == 0
+                && delta_q_v_ac == 0
+        } else {
+            q.delta_q_y_dc == 0
+        };
+
+        no_deltas
+            && (0..MAX_SEGMENTS).all(|segment_id| {
+                let q_index =
+                    if s.segmentation_enabled && s.feature_enabled[segment_id][SEG_LVL_ALT_Q] {
+                        let data = i32::from(s.feature_data[segment_id][SEG_LVL_ALT_Q]);
+                        helpers::clip3(0, 255, q.base_q_idx as i32 + data)
+                    } else {
+                        q.base_q_idx as i32
+                    };
+
+                q_index == 0
+            })
+    }
+
+    /// Implements 5.9.11: Loop filter params syntax. Only the deltas that differ from the ones of
+    /// `prev_slot` are coded. Returns the deltas to save for the frame.
+    fn write_loop_filter_params(
+        w: &mut BitWriter,
+        lf: &LoopFilterParams,
+        num_planes: u32,
+        prev_slot: &RefSlot,
+    ) -> Result<([i32; TOTAL_REFS_PER_FRAME], [i32; 2]), ObuWriterError> {
+        let mut ref_deltas = prev_slot.loop_filter_ref_deltas;
+        let mut mode_deltas = prev_slot.loop_filter_mode_deltas;
+
+        w.write_bits(lf.loop_filter_level[0], 6)?;
+        w.write_bits(lf.loop_filter_level[1], 6)?;
+        if num_planes > 1 && (lf.loop_filter_level[0] > 0 || lf.loop_filter_level[1] > 0) {
+            w.write_bits(lf.loop_filter_level[2], 6)?;
+            w.write_bits(lf.loop_filter_level[3], 6)?;
+        }
+
+        w.write_bits(lf.loop_filter_sharpness, 3)?;
+        w.write_bit(lf.loop_filter_delta_enabled);
+        if lf.loop_filter_delta_enabled {
+            w.write_bit(lf.loop_filter_delta_update);
+            if lf.loop_filter_delta_update {
+                for (delta, &new_delta) in ref_deltas.iter_mut().chain(mode_deltas.iter_mut()).zip(
+                    lf.loop_filter_ref_deltas
+                        .iter()
+                        .chain(&lf.loop_filter_mode_deltas),
+                ) {
+                    let update = *delta != new_delta;
+                    w.write_bit(update);
+                    if update {
+                        write_su(w, new_delta, 7, "loop_filter_delta")?;
+                        *delta = new_delta;
+                    }
+                }
+            }
+        }
+
+        Ok((ref_deltas, mode_deltas))
+    }
+
+    /// Implements 5.9.19: CDEF params syntax.
+    fn write_cdef_params(
+        w: &mut BitWriter,
+        cdef: &CdefParams,
+        num_planes: u32,
+    ) -> Result<(), ObuWriterError> {
+        // A coded secondary strength of 3 means a strength of 4.
+        let write_sec_strength = |w: &mut BitWriter, strength: u32| match strength {
+            0..=2 => w.write_bits(strength, 2),
+            4 => w.write_bits(3, 2),
+            _ => Err(BitWriterError::ValueTooLarge(strength, 2)),
+        };
+
+        let cdef_damping_minus_3 =
+            cdef.cdef_damping
+                .checked_sub(3)
+                .ok_or(ObuWriterError::InvalidValue(
+                    "cdef_damping",
+                    cdef.cdef_damping.into(),
+                ))?;
+        w.write_bits(cdef_damping_minus_3, 2)?;
+        w.write_bits(cdef.cdef_bits, 2)?;
+
+        for i in 0..(1 << cdef.cdef_bits) as usize {
+            w.write_bits(cdef.cdef_y_pri_strength[i], 4)?;
+            write_sec_strength(w, cdef.cdef_y_sec_strength[i])?;
+            if num_planes > 1 {
+                w.write_bits(cdef.cdef_uv_pri_strength[i], 4)?;
+                write_sec_strength(w, cdef.cdef_uv_sec_strength[i])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Implements 5.9.20: Loop restoration params syntax.
+    fn write_loop_restoration_params(
+        w: &mut BitWriter,
+        lr: &LoopRestorationParams,
+        num_planes: u32,
+        use_128x128_superblock: bool,
+        subsampled_420: bool,
+    ) -> Result<(), ObuWriterError> {
+        let mut uses_lr = false;
+        let mut uses_chroma_lr = false;
+
+        for (i, &lr_type) in lr.frame_restoration_type[..num_planes as usize]
+            .iter()
+            .enumerate()
+        {
+            let coded_lr_type = match lr_type {
+                FrameRestorationType::None => 0,
+                FrameRestorationType::Switchable => 1,
+                FrameRestorationType::Wiener => 2,
+                FrameRestorationType::Sgrproj => 3,
+            };
+            w.write_bits(coded_lr_type, 2)?;
+
+            if lr_type != FrameRestorationType::None {
+                uses_lr = true;
+                if i > 0 {
+                    uses_chroma_lr = true;
+                }
+            }
+        }
+
+        if uses_lr {
+            let invalid_shift =
+                ObuWriterError::InvalidValue("lr_unit_shift", lr.lr_unit_shift.into());
+            if use_128x128_superblock {
+                if !(1..=2).contains(&lr.lr_unit_shift) {
+                    return Err(invalid_shift);
+                }
+                w.write_bits(lr.lr_unit_shift - 1, 1)?;
+            } else {
+                if lr.lr_unit_shift > 2 {
+                    return Err(invalid_shift);
+                }
+                w.write_bit(lr.lr_unit_shift > 0);
+                if lr.lr_unit_shift > 0 {
+                    w.write_bits(lr.lr_unit_shift - 1, 1)?;
+                }
+            }
+
+            if subsampled_420 && uses_chroma_lr {
+                w.write_bits(lr.lr_uv_shift, 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns `skipModeAllowed`, see 5.9.22.
+    fn skip_mode_allowed(
+        &self,
+        fh: &FrameHeaderObu,
+        order_hint_bits: i32,
+        order_hint: u32,
+    ) -> Result<bool, ObuWriterError> {
+        let dist =
+            |a: u32, b: u32| helpers::get_relative_dist(true, order_hint_bits, a as i32, b as i32);
+
+        let mut forward_hint = None;
+        let mut backward_hint = None;
+        for i in 0..REFS_PER_FRAME {
+            let ref_hint = self.ref_slot(fh, i)?.order_hint;
+
+            if dist(ref_hint, order_hint) < 0 {
+                if forward_hint.is_none_or(|hint| dist(ref_hint, hint) > 0) {
+                    forward_hint = Some(ref_hint);
+                }
+            } else if dist(ref_hint, order_hint) > 0
+                && backward_hint.is_none_or(|hint| dist(ref_hint, hint) < 0)
+            {
+                backward_hint = Some(ref_hint);
+            }
+        }
+
+        Ok(match (forward_hint, backward_hint) {
+            (None, _) => false,
+            (Some(_), Some(_)) => true,
+            // Look for a second forward reference.
+            (Some(forward_hint), None) => {
+                let mut second_forward = false;
+                for i in 0..REFS_PER_FRAME {
+                    second_forward |= dist(self.ref_slot(fh, i)?.order_hint, forward_hint) < 0;
+                }
+                second_forward
+            }
+        })
+    }
+
+    /// Implements 5.9.24: Global motion params syntax. Returns the parameters to save for the
+    /// frame.
+    fn write_global_motion_params(
+        w: &mut BitWriter,
+        gm: &GlobalMotionParams,
+        allow_high_precision_mv: bool,
+        prev_gm_params: &[[i32; 6]; NUM_REF_FRAMES],
+    ) -> Result<[[i32; 6]; NUM_REF_FRAMES], ObuWriterError> {
+        let mut gm_params = default_gm_params();
+
+        for ref_frame in ReferenceFrameType::Last as usize..=ReferenceFrameType::AltRef as usize {
+            let type_ = gm.gm_type[ref_frame];
+
+            w.write_bit(type_ != WarpModelType::Identity);
+            if type_ != WarpModelType::Identity {
+                w.write_bit(type_ == WarpModelType::RotZoom);
+                if type_ != WarpModelType::RotZoom {
+                    w.write_bit(type_ == WarpModelType::Translation);
+                }
+            }
+
+            let mut idxs = vec![];
+            if type_ >= WarpModelType::RotZoom {
+                idxs.extend([2, 3]);
+                if type_ == WarpModelType::Affine {
+                    idxs.extend([4, 5]);
+                }
+            }
+            if type_ >= WarpModelType::Translation {
+                idxs.extend([0, 1]);
+            }
+
+            for idx in idxs {
+                let value = gm.gm_params[ref_frame][idx];
+                Self::write_global_param(
+                    w,
+                    type_,
+                    idx,
+                    allow_high_precision_mv,
+                    prev_gm_params[ref_frame][idx],
+                    value,
+                )?;
+                gm_params[ref_frame][idx] = value;
+            }
+
+            if type_ == WarpModelType::RotZoom {
+                gm_params[ref_frame][4] = -gm_params[ref_frame][3];
+                gm_params[ref_frame][5] = gm_params[ref_frame][2];
+            }
+        }
+
+        Ok(gm_params)
+    }
+
+    /// Implements 5.9.25: Global param syntax.
+    fn write_global_param(
+        w: &mut BitWriter,
+        type_: WarpModelType,
+        idx: usize,
+        allow_high_precision_mv: bool,
+        prev_value: i32,
+        value: i32,
+    ) -> Result<(), ObuWriterError> {
+        let mut abs_bits = GM_ABS_ALPHA_BITS;
+        let mut prec_bits = GM_ALPHA_PREC_BITS;
+        if idx < 2 {
+            if type_ == WarpModelType::Translation {
+                abs_bits = GM_ABS_TRANS_ONLY_BITS - !allow_high_precision_mv as u32;
+                prec_bits = GM_TRANS_ONLY_PREC_BITS - !allow_high_precision_mv as u32;
+            } else {
+                abs_bits = GM_ABS_TRANS_BITS;
+                prec_bits = GM_TRANS_PREC_BITS;
+            }
+        }
+
+        let prec_diff = WARPEDMODEL_PREC_BITS - prec_bits;
+        let (round, sub) = if (idx % 3) == 2 {
+            (1 << WARPEDMODEL_PREC_BITS, 1 << prec_bits)
+        } else {
+            (0, 0)
+        };
+
+        let mx = 1 << abs_bits;
+        let r = (prev_value >> prec_diff) - sub;
+        let offset = value - round;
+        let coded_value = offset >> prec_diff;
+        if coded_value << prec_diff != offset || coded_value < -mx || coded_value > mx {
+            return Err(ObuWriterError::InvalidValue("gm_params", value.into()));
+        }
+
+        encode_signed_subexp_with_ref(w, -mx, mx + 1, r, coded_value)
+    }
+
+    /// Implements 5.9.30: Film grain params syntax.
+    ///
+    /// Note that the parser loads the parameters of the reference frame when `update_grain` is
+    /// not set, so writing back a parsed frame codes them explicitly instead.
+    fn write_film_grain_params(
+        w: &mut BitWriter,
+        fg: &FilmGrainParams,
+        frame_type: FrameType,
+        ref_frame_idx: &[i32; REFS_PER_FRAME],
+        mono_chrome: bool,
+        subsampled_420: bool,
+    ) -> Result<(), ObuWriterError> {
+        w.write_bit(fg.apply_grain);
+        if !fg.apply_grain {
+            return Ok(());
+        }
+
+        w.write_bits(fg.grain_seed, 16)?;
+
+        let update_grain = if frame_type == FrameType::InterFrame {
+            w.write_bit(fg.update_grain);
+            fg.update_grain
+        } else {
+            true
+        };
+
+        if !update_grain {
+            if !ref_frame_idx.contains(&(fg.film_grain_params_ref_idx as i32)) {
+                return Err(ObuWriterError::InvalidValue(
+                    "film_grain_params_ref_idx",
+                    fg.film_grain_params_ref_idx.into(),
+                ));
+            }
+            w.write_bits(fg.film_grain_params_ref_idx, 3)?;
+            return Ok(());
+        }
+
+        let write_points =
+            |w: &mut BitWriter, num_points: u32, values: &[u32], scalings: &[u32]| {
+                w.write_bits(num_points, 4)?;
+                for (&value, &scaling) in values.iter().zip(scalings).take(num_points as usize) {
+                    w.write_bits(value, 8)?;
+                    w.write_bits(scaling, 8)?;
+                }
+                Ok::<_, BitWriterError>(())
+            };
+
+        write_points(w, fg.num_y_points, &fg.point_y_value, &fg.point_y_scaling)?;
+
+        let chroma_scaling_from_luma = !mono_chrome && fg.chroma_scaling_from_luma;
+        if !mono_chrome {
+            w.write_bit(chroma_scaling_from_luma);
+        }
+
+        let (num_cb_points, num_cr_points) = if mono_chrome
+            || chroma_scaling_from_luma
+            || (subsampled_420 && fg.num_y_points == 0)
+        {
+            (0, 0)
+        } else {
+            write_points(
+                w,
+                fg.num_cb_points,
+                &fg.point_cb_value,
+                &fg.point_cb_scaling,
+            )?;
+            write_points(
+                w,
+                fg.num_cr_points,
+                &fg.point_cr_value,
+                &fg.point_cr_scaling,
+            )?;
+            (fg.num_cb_points, fg.num_cr_points)
+        };
+
+        w.write_bits(fg.grain_scaling_minus_8, 2)?;
+        w.write_bits(fg.ar_coeff_lag, 2)?;
+
+        let num_pos_luma = (2 * fg.ar_coeff_lag * (fg.ar_coeff_lag + 1)) as usize;
+        let num_pos_chroma = if fg.num_y_points > 0 {
+            for &coeff in &fg.ar_coeffs_y_plus_128[..num_pos_luma] {
+                w.write_bits(coeff, 8)?;
+            }
+            num_pos_luma + 1
+        } else {
+            num_pos_luma
+        };
+
+        if chroma_scaling_from_luma || num_cb_points > 0 {
+            for &coeff in &fg.ar_coeffs_cb_plus_128[..num_pos_chroma] {
+                w.write_bits(coeff, 8)?;
+            }
+        }
+        if chroma_scaling_from_luma || num_cr_points > 0 {
+            for &coeff in &fg.ar_coeffs_cr_plus_128[..num_pos_chroma] {
+                w.write_bits(coeff, 8)?;
+            }
+        }
+
+        w.write_bits(fg.ar_coeff_shift_minus_6, 2)?;
+        w.write_bits(fg.grain_scale_shift, 2)?;
+
+        if num_cb_points > 0 {
+            w.write_bits(fg.cb_mult, 8)?;
+            w.write_bits(fg.cb_luma_mult, 8)?;
+            w.write_bits(fg.cb_offset, 9)?;
+        }
+        if num_cr_points > 0 {
+            w.write_bits(fg.cr_mult, 8)?;
+            w.write_bits(fg.cr_luma_mult, 8)?;
+            w.write_bits(fg.cr_offset, 9)?;
+        }
+
+        w.write_bit(fg.overlap_flag);
+        w.write_bit(fg.clip_to_restricted_range);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::leb128;
+    use super::leb128_fixed;
+    use super::write_obu;
+    use super::write_sequence_header_obu;
+    use super::FrameHeaderWriter;
+    use crate::codec::av1::parser::ChromaSamplePosition;
+    use crate::codec::av1::parser::ColorPrimaries;
+    use crate::codec::av1::parser::FilmGrainParams;
+    use crate::codec::av1::parser::FrameHeaderObu;
+    use crate::codec::av1::parser::FrameRestorationType;
+    use crate::codec::av1::parser::FrameType;
+    use crate::codec::av1::parser::InterpolationFilter;
+    use crate::codec::av1::parser::MatrixCoefficients;
+    use crate::codec::av1::parser::ObuHeader;
+    use crate::codec::av1::parser::ObuType;
+    use crate::codec::av1::parser::ParsedObu;
+    use crate::codec::av1::parser::Parser;
+    use crate::codec::av1::parser::Profile;
+    use crate::codec::av1::parser::SequenceHeaderObu;
+    use crate::codec::av1::parser::TransferCharacteristics;
+    use crate::codec::av1::parser::TxMode;
+    use crate::codec::av1::parser::WarpModelType;
+    use crate::codec::av1::parser::PRIMARY_REF_NONE;
+    use crate::codec::av1::parser::SELECT_INTEGER_MV;
+    use crate::codec::av1::parser::SELECT_SCREEN_CONTENT_TOOLS;
+    use crate::codec::av1::parser::WARPEDMODEL_PREC_BITS;
+    use crate::codec::av1::reader::Reader;
+    use crate::utils::IvfIterator;
+
+    const STREAM_TEST_25_FPS: &[u8] = include_bytes!("../codec/av1/test_data/test-25fps.ivf.av1");
+
+    fn parse_obu<'a>(parser: &mut Parser, data: &'a [u8]) -> crate::codec::av1::parser::Obu<'a> {
+        match parser.parse_obu(data).unwrap() {
+            ParsedObu::Process(obu) => obu,
+            ParsedObu::Drop(_) => panic!("unexpected dropped OBU"),
+        }
+    }
+
+    #[test]
+    fn leb128_values() {
+        assert_eq!(leb128(0), [0x00]);
+        assert_eq!(leb128(127), [0x7f]);
+        assert_eq!(leb128(128), [0x80, 0x01]);
+        assert_eq!(leb128(u32::MAX), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(leb128_fixed(1, 4).unwrap(), [0x81, 0x80, 0x80, 0x00]);
+        assert!(leb128_fixed(128, 1).is_err());
+        assert!(leb128_fixed(0, 9).is_err());
+
+        for value in [0, 1, 300, 1 << 20, u32::MAX - 1] {
+            for data in [leb128(value), leb128_fixed(value, 7).unwrap()] {
+                assert_eq!(Reader::new(&data).read_leb128().unwrap(), value);
+            }
+        }
+
+        let header = ObuHeader {
+            obu_type: ObuType::Padding,
+            extension_flag: true,
+            has_size_field: true,
+            temporal_id: 5,
+            spatial_id: 2,
+        };
+        assert_eq!(
+            write_obu(&header, &[0xaa; 3]).unwrap(),
+            [0x7e, 0xb0, 0x03, 0xaa, 0xaa, 0xaa]
+        );
+    }
+
+    /// Writes back the headers of a real stream, which must give the same bytes.
+    #[test]
+    fn rewrite_test25fps() {
+        let mut parser = Parser::default();
+        let mut writer = FrameHeaderWriter::new();
+        let mut num_frame_headers = 0;
+
+        for packet in IvfIterator::new(STREAM_TEST_25_FPS) {
+            let mut consumed = 0;
+
+            while consumed < packet.len() {
+                let obu = parse_obu(&mut parser, &packet[consumed..]);
+                let data = obu.data.clone();
+                consumed += data.len();
+
+                match obu.header.obu_type {
+                    ObuType::SequenceHeader => {
+                        let seq = parser.parse_sequence_header_obu(&obu).unwrap();
+                        assert_eq!(write_sequence_header_obu(&seq).unwrap(), data.as_ref());
+                    }
+                    ObuType::FrameHeader => {
+                        let seq = parser.sequence_header.clone().unwrap();
+                        let fh = parser.parse_frame_header_obu(&obu).unwrap();
+                        parser.ref_frame_update(&fh).unwrap();
+                        let written = writer.write_frame_header_obu(&seq, &fh).unwrap();
+                        assert_eq!(written, data.as_ref());
+                        num_frame_headers += 1;
+                    }
+                    ObuType::Frame => {
+                        let seq = parser.sequence_header.clone().unwrap();
+                        let start_offset = obu.start_offset;
+                        let frame = parser.parse_frame_obu(obu).unwrap();
+                        parser.ref_frame_update(&frame.header).unwrap();
+                        let tile_group = &data[start_offset + frame.header.header_bytes..];
+                        let written = writer
+                            .write_frame_obu(&seq, &frame.header, tile_group)
+                            .unwrap();
+                        assert_eq!(written, data.as_ref());
+                        num_frame_headers += 1;
+                    }
+                    ObuType::TileGroup => {
+                        parser.parse_tile_group_obu(obu).unwrap();
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        assert_eq!(num_frame_headers, 274);
+    }
+
+    /// Returns a sequence header using most of the optional features, with the variables derived
+    /// by the parser filled in so it can be compared with a parsed one.
+    fn synthetic_sequence() -> SequenceHeaderObu {
+        let mut seq = SequenceHeaderObu {
+            obu_header: ObuHeader {
+                obu_type: ObuType::SequenceHeader,
+                has_size_field: true,
+                ..Default::default()
+            },
+            seq_profile: Profile::Profile0,
+            frame_width_bits_minus_1: 7,
+            frame_height_bits_minus_1: 7,
+            max_frame_width_minus_1: 159,
+            max_frame_height_minus_1: 89,
+            frame_id_numbers_present_flag: true,
+            delta_frame_id_length_minus_2: 3,
+            additional_frame_id_length_minus_1: 2,
+            enable_warped_motion: true,
+            enable_order_hint: true,
+            enable_ref_frame_mvs: true,
+            seq_choose_screen_content_tools: true,
+            seq_force_screen_content_tools: SELECT_SCREEN_CONTENT_TOOLS as u32,
+            seq_choose_integer_mv: true,
+            seq_force_integer_mv: SELECT_INTEGER_MV as u32,
+            order_hint_bits_minus_1: 6,
+            order_hint_bits: 7,
+            enable_superres: true,
+            enable_cdef: true,
+            enable_restoration: true,
+            film_grain_params_present: true,
+            decoder_model_info_present_flag: true,
+            initial_display_delay_present_flag: true,
+            timing_info_present_flag: true,
+            num_planes: 3,
+            ..Default::default()
+        };
+
+        seq.timing_info.num_units_in_display_tick = 1;
+        seq.timing_info.time_scale = 30;
+        seq.decoder_model_info.buffer_delay_length_minus_1 = 9;
+        seq.decoder_model_info.num_units_in_decoding_tick = 1000;
+        seq.decoder_model_info.buffer_removal_time_length_minus_1 = 9;
+        seq.decoder_model_info
+            .frame_presentation_time_length_minus_1 = 9;
+
+        let op = &mut seq.operating_points[0];
+        op.seq_level_idx = 8;
+        op.seq_tier = 1;
+        op.decoder_model_present_for_this_op = true;
+        op.decoder_buffer_delay = 1000;
+        op.encoder_buffer_delay = 500;
+        op.initial_display_delay_present_for_this_op = true;
+        op.initial_display_delay_minus_1 = 9;
+
+        let cc = &mut seq.color_config;
+        cc.color_description_present_flag = true;
+        cc.color_primaries = ColorPrimaries::Bt709;
+        cc.transfer_characteristics = TransferCharacteristics::Bt709;
+        cc.matrix_coefficients = MatrixCoefficients::Bt709;
+        cc.subsampling_x = true;
+        cc.subsampling_y = true;
+        cc.chroma_sample_position = ChromaSamplePosition::Colocated;
+        cc.separate_uv_delta_q = true;
+
+        seq
+    }
+
+    /// Returns a key frame, an hidden inter frame, an inter frame using skip mode and a frame
+    /// showing the hidden one, using most of the optional features of `synthetic_sequence()`.
+    fn synthetic_frames() -> Vec<FrameHeaderObu> {
+        let obu_header = ObuHeader {
+            obu_type: ObuType::FrameHeader,
+            has_size_field: true,
+            ..Default::default()
+        };
+
+        let mut key = FrameHeaderObu {
+            obu_header: obu_header.clone(),
+            frame_type: FrameType::KeyFrame,
+            show_frame: true,
+            frame_presentation_time: 77,
+            allow_screen_content_tools: 1,
+            current_frame_id: 5,
+            frame_size_override_flag: true,
+            primary_ref_frame: PRIMARY_REF_NONE,
+            buffer_removal_time_present_flag: true,
+            buffer_removal_time: vec![33],
+            use_superres: true,
+            superres_denom: 16,
+            upscaled_width: 160,
+            frame_width: 80,
+            frame_height: 90,
+            render_and_frame_size_different: true,
+            render_width: 320,
+            render_height: 180,
+            tx_mode: TxMode::Select,
+            ..Default::default()
+        };
+
+        let ti = &mut key.tile_info;
+        ti.width_in_sbs_minus_1[..2].copy_from_slice(&[0, 0]);
+        ti.height_in_sbs_minus_1[0] = 1;
+        ti.context_update_tile_id = 1;
+        ti.tile_size_bytes = 4;
+
+        let q = &mut key.quantization_params;
+        q.base_q_idx = 100;
+        q.delta_q_y_dc = -3;
+        q.diff_uv_delta = true;
+        q.delta_q_u_dc = 5;
+        q.delta_q_v_ac = -64;
+        q.using_qmatrix = true;
+        q.qm_y = 4;
+        q.qm_u = 5;
+        q.qm_v = 6;
+        q.delta_q_present = true;
+        q.delta_q_res = 1;
+
+        let s = &mut key.segmentation_params;
+        s.segmentation_enabled = true;
+        s.feature_enabled[1][0] = true;
+        s.feature_data[1][0] = -20;
+        s.feature_enabled[2][5] = true;
+        s.feature_data[2][5] = 3;
+        s.feature_enabled[3][6] = true;
+
+        let lf = &mut key.loop_filter_params;
+        lf.loop_filter_level = [10, 12, 3, 4];
+        lf.loop_filter_sharpness = 2;
+        lf.loop_filter_delta_enabled = true;
+        lf.loop_filter_delta_update = true;
+        lf.loop_filter_ref_deltas = [1, 2, 0, 0, -1, 0, -1, -1];
+        lf.loop_filter_mode_deltas = [0, -3];
+        lf.delta_lf_present = true;
+        lf.delta_lf_res = 2;
+        lf.delta_lf_multi = 1;
+
+        let cdef = &mut key.cdef_params;
+        cdef.cdef_damping = 5;
+        cdef.cdef_bits = 1;
+        cdef.cdef_y_pri_strength[..2].copy_from_slice(&[3, 15]);
+        cdef.cdef_y_sec_strength[..2].copy_from_slice(&[4, 1]);
+        cdef.cdef_uv_pri_strength[..2].copy_from_slice(&[1, 0]);
+        cdef.cdef_uv_sec_strength[..2].copy_from_slice(&[2, 4]);
+
+        let lr = &mut key.loop_restoration_params;
+        lr.frame_restoration_type = [
+            FrameRestorationType::Wiener,
+            FrameRestorationType::Sgrproj,
+            FrameRestorationType::None,
+        ];
+        lr.lr_unit_shift = 2;
+        lr.lr_uv_shift = 1;
+
+        let fg = &mut key.film_grain_params;
+        fg.apply_grain = true;
+        fg.grain_seed = 1234;
+        fg.update_grain = true;
+        fg.num_y_points = 2;
+        fg.point_y_value[..2].copy_from_slice(&[16, 128]);
+        fg.point_y_scaling[..2].copy_from_slice(&[20, 40]);
+        fg.num_cb_points = 1;
+        fg.point_cb_value[0] = 64;
+        fg.point_cb_scaling[0] = 10;
+        fg.num_cr_points = 2;
+        fg.point_cr_value[..2].copy_from_slice(&[32, 96]);
+        fg.point_cr_scaling[..2].copy_from_slice(&[11, 12]);
+        fg.grain_scaling_minus_8 = 1;
+        fg.ar_coeff_lag = 1;
+        fg.ar_coeffs_y_plus_128[..4].copy_from_slice(&[120, 130, 140, 150]);
+        fg.ar_coeffs_cb_plus_128[..5].copy_from_slice(&[1, 2, 3, 4, 5]);
+        fg.ar_coeffs_cr_plus_128[..5].copy_from_slice(&[6, 7, 8, 9, 10]);
+        fg.ar_coeff_shift_minus_6 = 2;
+        fg.grain_scale_shift = 1;
+        fg.cb_mult = 100;
+        fg.cb_luma_mult = 101;
+        fg.cb_offset = 300;
+        fg.cr_mult = 102;
+        fg.cr_luma_mult = 103;
+        fg.cr_offset = 301;
+        fg.overlap_flag = true;
+
+        let mut hidden = FrameHeaderObu {
+            obu_header: obu_header.clone(),
+            frame_type: FrameType::InterFrame,
+            showable_frame: true,
+            current_frame_id: 6,
+            order_hint: 8,
+            primary_ref_frame: 0,
+            refresh_frame_flags: 0x02,
+            frame_width: 160,
+            frame_height: 90,
+            allow_high_precision_mv: true,
+            interpolation_filter: InterpolationFilter::Switchable,
+            is_motion_mode_switchable: true,
+            use_ref_frame_mvs: true,
+            reference_select: true,
+            allow_warped_motion: true,
+            reduced_tx_set: true,
+            ..Default::default()
+        };
+
+        hidden.tile_info.uniform_tile_spacing_flag = true;
+        hidden.quantization_params.base_q_idx = 80;
+        hidden.segmentation_params = key.segmentation_params.clone();
+        hidden.loop_filter_params.loop_filter_delta_enabled = true;
+        hidden.loop_filter_params.loop_filter_delta_update = true;
+        hidden.loop_filter_params.loop_filter_ref_deltas = [1, 2, -4, 0, -1, 0, -1, -1];
+        hidden.loop_filter_params.loop_filter_mode_deltas = [0, -3];
+        hidden.cdef_params.cdef_damping = 3;
+        hidden.film_grain_params.apply_grain = true;
+        hidden.film_grain_params.grain_seed = 4321;
+
+        let gm = &mut hidden.global_motion_params;
+        let identity = 1 << WARPEDMODEL_PREC_BITS;
+        gm.gm_type[1] = WarpModelType::Translation;
+        gm.gm_params[1] = [3 << 13, -(2 << 13), identity, 0, 0, identity];
+        gm.gm_type[4] = WarpModelType::RotZoom;
+        gm.gm_params[4] = [
+            5 << 10,
+            -(7 << 10),
+            identity + 200,
+            -100,
+            100,
+            identity + 200,
+        ];
+        gm.gm_type[7] = WarpModelType::Affine;
+        gm.gm_params[7] = [-(1 << 10), 0, identity - 30, 16, 50, identity + 2];
+
+        let mut skip = FrameHeaderObu {
+            obu_header: obu_header.clone(),
+            frame_type: FrameType::InterFrame,
+            show_frame: true,
+            current_frame_id: 7,
+            order_hint: 4,
+            primary_ref_frame: 1,
+            refresh_frame_flags: 0x04,
+            ref_frame_idx: [0, 1, 0, 1, 0, 1, 0],
+            frame_width: 160,
+            frame_height: 90,
+            reference_select: true,
+            skip_mode_present: true,
+            ..Default::default()
+        };
+
+        skip.tile_info.uniform_tile_spacing_flag = true;
+        skip.quantization_params.base_q_idx = 90;
+        skip.cdef_params.cdef_damping = 3;
+        skip.global_motion_params.gm_type[1] = WarpModelType::Translation;
+        skip.global_motion_params.gm_params[1] = [4 << 13, -(2 << 13), identity, 0, 0, identity];
+
+        let show_existing = FrameHeaderObu {
+            obu_header,
+            show_existing_frame: true,
+            frame_to_show_map_idx: 1,
+            frame_presentation_time: 99,
+            display_frame_id: 6,
+            ..Default::default()
+        };
+
+        vec![key, hidden, skip, show_existing]
+    }
+
+    /// Writes synthetic headers and checks that they parse to the same values, and give the same
+    /// bytes once written back. Film grain parameters loaded from a reference frame are the
+    /// exception, as the parser replaces them with the parameters of the reference frame.
+    #[test]
+    fn synthetic_roundtrip() {
+        let seq = synthetic_sequence();
+        let temporal_delimiter = write_obu(
+            &ObuHeader {
+                obu_type: ObuType::TemporalDelimiter,
+                has_size_field: true,
+                ..Default::default()
+            },
+            &[],
+        )
+        .unwrap();
+        assert_eq!(temporal_delimiter, [0x12, 0x00]);
+
+        let mut parser = Parser::default();
+        let obu = parse_obu(&mut parser, &temporal_delimiter);
+        parser.parse_temporal_delimiter_obu(&obu).unwrap();
+
+        let data = write_sequence_header_obu(&seq).unwrap();
+        let obu = parse_obu(&mut parser, &data);
+        assert_eq!(*parser.parse_sequence_header_obu(&obu).unwrap(), seq);
+
+        let mut writer = FrameHeaderWriter::new();
+        let mut rewriter = FrameHeaderWriter::new();
+        // Writes the frames with the film grain parameters they are parsed with.
+        let mut loaded_grain_writer = FrameHeaderWriter::new();
+        let mut parsed = vec![];
+
+        for fh in synthetic_frames() {
+            let obu = parse_obu(&mut parser, &temporal_delimiter);
+            parser.parse_temporal_delimiter_obu(&obu).unwrap();
+
+            let data = writer.write_frame_header_obu(&seq, &fh).unwrap();
+            let obu = parse_obu(&mut parser, &data);
+            let parsed_fh = parser.parse_frame_header_obu(&obu).unwrap();
+            parser.ref_frame_update(&parsed_fh).unwrap();
+
+            let mut loaded_grain_fh = fh.clone();
+            loaded_grain_fh.film_grain_params = parsed_fh.film_grain_params.clone();
+            let loaded_grain_data = loaded_grain_writer
+                .write_frame_header_obu(&seq, &loaded_grain_fh)
+                .unwrap();
+
+            let rewritten = rewriter.write_frame_header_obu(&seq, &parsed_fh).unwrap();
+            if fh.film_grain_params.apply_grain && !fh.film_grain_params.update_grain {
+                assert_ne!(rewritten, data);
+                assert_eq!(rewritten, loaded_grain_data);
+            } else {
+                assert_eq!(rewritten, data);
+            }
+            parsed.push(parsed_fh);
+        }
+
+        let frames = synthetic_frames();
+
+        let key = &parsed[0];
+        assert_eq!(key.frame_presentation_time, 77);
+        assert_eq!(key.buffer_removal_time, [33]);
+        assert_eq!(key.current_frame_id, 5);
+        assert_eq!((key.upscaled_width, key.frame_width), (160, 80));
+        assert_eq!((key.render_width, key.render_height), (320, 180));
+        assert_eq!((key.tile_info.tile_cols, key.tile_info.tile_rows), (2, 1));
+        assert_eq!(key.quantization_params, frames[0].quantization_params);
+        assert_eq!(
+            key.segmentation_params.feature_data,
+            frames[0].segmentation_params.feature_data
+        );
+        assert_eq!(
+            key.loop_filter_params.loop_filter_ref_deltas,
+            frames[0].loop_filter_params.loop_filter_ref_deltas
+        );
+        assert_eq!(key.cdef_params, frames[0].cdef_params);
+        assert_eq!(key.loop_restoration_params.lr_unit_shift, 2);
+        assert_eq!(key.loop_restoration_params.lr_uv_shift, 1);
+        assert_eq!(key.film_grain_params, frames[0].film_grain_params);
+
+        let hidden = &parsed[1];
+        assert!(!hidden.show_frame);
+        assert_eq!(hidden.ref_frame_idx, [0; 7]);
+        assert_eq!(
+            hidden.loop_filter_params.loop_filter_ref_deltas,
+            frames[1].loop_filter_params.loop_filter_ref_deltas
+        );
+        for i in [1, 4, 7] {
+            assert_eq!(
+                hidden.global_motion_params.gm_params[i],
+                frames[1].global_motion_params.gm_params[i]
+            );
+        }
+        assert_eq!(hidden.film_grain_params.grain_seed, 4321);
+        assert_eq!(
+            hidden.film_grain_params.point_cr_value,
+            frames[0].film_grain_params.point_cr_value
+        );
+
+        let skip = &parsed[2];
+        assert!(skip.skip_mode_present);
+        assert_eq!(skip.skip_mode_frame, [1, 2]);
+        assert_eq!(
+            skip.global_motion_params.gm_params[1],
+            frames[2].global_motion_params.gm_params[1]
+        );
+
+        let show_existing = &parsed[3];
+        assert!(show_existing.show_existing_frame);
+        assert_eq!(show_existing.frame_to_show_map_idx, 1);
+        assert_eq!(show_existing.display_frame_id, 6);
+        assert_eq!(show_existing.frame_presentation_time, 99);
+    }
+    /// Writes `frames` after `seq`, each in its own temporal unit, and parses them back.
+    fn write_and_parse(
+        seq: &SequenceHeaderObu,
+        frames: &[FrameHeaderObu],
+    ) -> anyhow::Result<Vec<FrameHeaderObu>> {
+        let temporal_delimiter = [0x12, 0x00];
+        let mut parser = Parser::default();
+        let data = write_sequence_header_obu(seq).unwrap();
+        let obu = parse_obu(&mut parser, &data);
+        parser.parse_sequence_header_obu(&obu)?;
+
+        let mut writer = FrameHeaderWriter::new();
+        let mut parsed = vec![];
+        for fh in frames {
+            let obu = parse_obu(&mut parser, &temporal_delimiter);
+            parser.parse_temporal_delimiter_obu(&obu)?;
+
+            let data = writer.write_frame_header_obu(seq, fh).unwrap();
+            let obu = parse_obu(&mut parser, &data);
+            let parsed_fh = parser.parse_frame_header_obu(&obu)?;
+            parser.ref_frame_update(&parsed_fh)?;
+            parsed.push(parsed_fh);
+        }
+
+        Ok(parsed)
+    }
+
+    /// frame_presentation_time is only coded when the pictures are not equally spaced.
+    #[test]
+    fn parse_frame_presentation_time() {
+        let mut seq = synthetic_sequence();
+        let frames = synthetic_frames();
+
+        let parsed = write_and_parse(&seq, &frames[..1]).unwrap();
+        assert_eq!(parsed[0].frame_presentation_time, 77);
+
+        seq.timing_info.equal_picture_interval = true;
+        let parsed = write_and_parse(&seq, &frames[..1]).unwrap();
+        assert_eq!(parsed[0].frame_presentation_time, 0);
+        assert_eq!(parsed[0].buffer_removal_time, [33]);
+        assert_eq!(parsed[0].quantization_params, frames[0].quantization_params);
+    }
+
+    /// buffer_removal_time holds one value per operating point when present.
+    #[test]
+    fn parse_buffer_removal_time() {
+        let seq = synthetic_sequence();
+        let mut frames = synthetic_frames();
+
+        let parsed = write_and_parse(&seq, &frames[..1]).unwrap();
+        assert!(parsed[0].buffer_removal_time_present_flag);
+        assert_eq!(parsed[0].buffer_removal_time, [33]);
+
+        frames[0].buffer_removal_time_present_flag = false;
+        frames[0].buffer_removal_time = vec![];
+        let parsed = write_and_parse(&seq, &frames[..1]).unwrap();
+        assert!(parsed[0].buffer_removal_time.is_empty());
+    }
+
+    /// Without a primary reference frame, the global motion parameters of all the references,
+    /// up to ALTREF_FRAME, are coded relative to the default ones, including the last parameter.
+    #[test]
+    fn parse_global_motion_past_independence() {
+        let seq = synthetic_sequence();
+        let mut frames = synthetic_frames();
+        frames.truncate(2);
+
+        let identity = 1 << WARPEDMODEL_PREC_BITS;
+        let inter = &mut frames[1];
+        inter.primary_ref_frame = PRIMARY_REF_NONE;
+        let gm = &mut inter.global_motion_params;
+        gm.gm_type[1] = WarpModelType::Affine;
+        gm.gm_params[1] = [1 << 10, 2 << 10, identity + 10, -20, 30, identity - 40];
+        gm.gm_type[4] = WarpModelType::Identity;
+        gm.gm_params[4] = [0, 0, identity, 0, 0, identity];
+
+        let parsed = write_and_parse(&seq, &frames).unwrap();
+        let gm = &parsed[1].global_motion_params;
+        for i in [1, 7] {
+            assert_eq!(gm.gm_type[i], frames[1].global_motion_params.gm_type[i]);
+            assert_eq!(gm.gm_params[i], frames[1].global_motion_params.gm_params[i]);
+        }
+    }
+
+    /// display_frame_id must match the frame id of the frame being shown.
+    #[test]
+    fn parse_show_existing_frame_id() {
+        let seq = synthetic_sequence();
+        let mut frames = synthetic_frames();
+
+        let parsed = write_and_parse(&seq, &frames).unwrap();
+        assert_eq!(parsed[3].display_frame_id, 6);
+
+        frames[3].display_frame_id = 5;
+        assert!(write_and_parse(&seq, &frames).is_err());
+    }
+
+    /// Film grain parameters loaded from a reference keep the values coded for the current
+    /// frame.
+    #[test]
+    fn parse_film_grain_params_ref() {
+        let seq = synthetic_sequence();
+        let mut frames = synthetic_frames();
+        frames[1].ref_frame_idx[2] = 3;
+        frames[1].film_grain_params.film_grain_params_ref_idx = 3;
+
+        let parsed = write_and_parse(&seq, &frames[..2]).unwrap();
+
+        // load_grain_params() loads every syntax element from the reference frame but the seed.
+        let expected = FilmGrainParams {
+            grain_seed: 4321,
+            ..frames[0].film_grain_params.clone()
+        };
+        assert_eq!(parsed[1].film_grain_params, expected);
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Bit-level writer, with the Exp-Golomb coding and emulation prevention used by H.264 and H.265.
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BitWriterError {
+    #[error("more than 32 ({0}) bits were requested")]
+    TooManyBitsRequested(usize),
+    #[error("value {0} does not fit in {1} bits")]
+    ValueTooLarge(u32, usize),
+    #[error("value {0} cannot be Exp-Golomb coded")]
+    NotExpGolombCodable(i64),
+}
+
+/// Inserts the emulation prevention bytes required to turn the RBSP `rbsp` into the payload of a
+/// H.264 or H.265 NAL unit, i.e. a `0x03` byte after every two consecutive zero bytes followed by
+/// a byte lower than or equal to `0x03`.
+pub fn insert_emulation_prevention(rbsp: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(rbsp.len() + rbsp.len() / 64);
+    let mut num_zeros = 0;
+
+    for &byte in rbsp {
+        if num_zeros == 2 && byte <= 0x03 {
+            out.push(0x03);
+            num_zeros = 0;
+        }
+
+        out.push(byte);
+        num_zeros = if byte == 0 { num_zeros + 1 } else { 0 };
+    }
+
+    // An RBSP ending with a zero byte must be followed by a last 0x03 byte. See 7.4.1 in the
+    // H.264 specification.
+    if rbsp.last() == Some(&0) {
+        out.push(0x03);
+    }
+
+    out
+}
+
+/// A writer of bits into a byte buffer, most significant bit first.
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    /// Bits of the current byte that have not been pushed into `data` yet, aligned to the LSB.
+    curr_byte: u8,
+    /// Number of bits written into `curr_byte`.
+    num_bits_in_curr_byte: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Writes a single bit.
+    pub fn write_bit(&mut self, bit: bool) {
+        self.curr_byte = (self.curr_byte << 1) | u8::from(bit);
+        self.num_bits_in_curr_byte += 1;
+
+        if self.num_bits_in_curr_byte == 8 {
+            self.data.push(self.curr_byte);
+            self.curr_byte = 0;
+            self.num_bits_in_curr_byte = 0;
+        }
+    }
+
+    /// Writes the `num_bits` LSBs of `value`, i.e. `f(n)` or `u(n)` in the H.264 and H.265
+    /// specifications.
+    pub fn write_bits(&mut self, value: u32, num_bits: usize) -> Result<(), BitWriterError> {
+        if num_bits > 32 {
+            return Err(BitWriterError::TooManyBitsRequested(num_bits));
+        }
+        if num_bits < 32 && value >> num_bits != 0 {
+            return Err(BitWriterError::ValueTooLarge(value, num_bits));
+        }
+
+        for i in (0..num_bits).rev() {
+            self.write_bit((value >> i) & 1 != 0);
+        }
+
+        Ok(())
+    }
+
+    /// Writes `value` as an unsigned Exp-Golomb code, i.e. `ue(v)`.
+    pub fn write_ue(&mut self, value: u32) -> Result<(), BitWriterError> {
+        let code = value
+            .checked_add(1)
+            .ok_or(BitWriterError::NotExpGolombCodable(i64::from(value)))?;
+        let num_bits = 32 - code.leading_zeros() as usize;
+
+        self.write_bits(0, num_bits - 1)?;
+        self.write_bits(code, num_bits)
+    }
+
+    /// Writes `value` as a signed Exp-Golomb code, i.e. `se(v)`.
+    pub fn write_se(&mut self, value: i32) -> Result<(), BitWriterError> {
+        let value = i64::from(value);
+        let code = if value > 0 { 2 * value - 1 } else { -2 * value };
+
+        self.write_ue(u32::try_from(code).map_err(|_| BitWriterError::NotExpGolombCodable(value))?)
+    }
+
+    /// Writes `bytes`, which is more efficient when the writer is byte-aligned.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.is_byte_aligned() {
+            self.data.extend_from_slice(bytes);
+        } else {
+            for &byte in bytes {
+                // Cannot fail as a byte always fits in 8 bits.
+                let _ = self.write_bits(u32::from(byte), 8);
+            }
+        }
+    }
+
+    /// Writes the `rbsp_trailing_bits()` syntax element, i.e. a stop bit followed by zero bits
+    /// until the writer is byte-aligned.
+    pub fn write_trailing_bits(&mut self) {
+        self.write_bit(true);
+        self.align();
+    }
+
+    /// Writes zero bits until the writer is byte-aligned.
+    pub fn align(&mut self) {
+        while !self.is_byte_aligned() {
+            self.write_bit(false);
+        }
+    }
+
+    /// Returns whether the next bit will be written at the start of a byte.
+    pub fn is_byte_aligned(&self) -> bool {
+        self.num_bits_in_curr_byte == 0
+    }
+
+    /// Returns the number of bits written so far.
+    pub fn num_bits_written(&self) -> usize {
+        self.data.len() * 8 + self.num_bits_in_curr_byte
+    }
+
+    /// Returns the bytes written, padding the last one with zero bits if the writer is not
+    /// byte-aligned.
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::insert_emulation_prevention;
+    use super::BitWriter;
+    use super::BitWriterError;
+    use crate::codec::h264::nalu_reader::NaluReader;
+
+    #[test]
+    fn write_bits() {
+        let mut writer = BitWriter::new();
+        writer.write_bit(true);
+        writer.write_bits(0b010, 3).unwrap();
+        assert_eq!(writer.num_bits_written(), 4);
+        assert!(!writer.is_byte_aligned());
+        writer.write_bytes(&[0xab]);
+        writer.write_bits(0xdeadbeef, 32).unwrap();
+        writer.write_trailing_bits();
+        assert!(writer.is_byte_aligned());
+
+        assert_eq!(writer.into_bytes(), [0xaa, 0xbd, 0xea, 0xdb, 0xee, 0xf8]);
+
+        let mut writer = BitWriter::new();
+        assert_eq!(
+            writer.write_bits(4, 2),
+            Err(BitWriterError::ValueTooLarge(4, 2))
+        );
+        assert_eq!(
+            writer.write_bits(0, 33),
+            Err(BitWriterError::TooManyBitsRequested(33))
+        );
+        assert_eq!(
+            writer.write_ue(u32::MAX),
+            Err(BitWriterError::NotExpGolombCodable(u32::MAX.into()))
+        );
+    }
+
+    #[test]
+    fn exp_golomb_roundtrip() {
+        let ue_values = [0, 1, 2, 3, 7, 8, 255, 65535, 1 << 20, (1 << 31) - 1];
+        let se_values = [0, 1, -1, 2, -2, 127, -128, 1 << 20, -(1 << 20), -(1 << 29)];
+
+        let mut writer = BitWriter::new();
+        for &value in &ue_values {
+            writer.write_ue(value).unwrap();
+        }
+        for &value in &se_values {
+            writer.write_se(value).unwrap();
+        }
+        writer.write_trailing_bits();
+        let data = writer.into_bytes();
+
+        let mut reader = NaluReader::new(&data);
+        for &value in &ue_values {
+            assert_eq!(reader.read_ue::<u32>().unwrap(), value);
+        }
+        for &value in &se_values {
+            assert_eq!(reader.read_se::<i32>().unwrap(), value);
+        }
+        assert!(!reader.has_more_rsbp_data());
+
+        // Tables 9-2 and 9-3 of the H.264 specification.
+        let mut writer = BitWriter::new();
+        writer.write_ue(3).unwrap();
+        writer.write_se(-2).unwrap();
+        assert_eq!(writer.into_bytes(), [0b0010_0001, 0b0100_0000]);
+    }
+
+    #[test]
+    fn emulation_prevention() {
+        assert_eq!(
+            insert_emulation_prevention(&[0, 0, 0, 0, 0, 1, 0, 0, 4, 0, 0]),
+            [0, 0, 3, 0, 0, 3, 0, 1, 0, 0, 4, 0, 0, 3]
+        );
+        assert_eq!(insert_emulation_prevention(&[0, 0, 2]), [0, 0, 3, 2]);
+
+        // The reader must see the original RBSP.
+        let rbsp = [0x12, 0, 0, 1, 0, 0, 0, 0x80];
+        let payload = insert_emulation_prevention(&rbsp);
+        let mut reader = NaluReader::new(&payload);
+        for &byte in &rbsp {
+            assert_eq!(reader.read_bits::<u8>(8).unwrap(), byte);
+        }
+        assert_eq!(reader.num_epb(), 2);
+    }
+}
@@ -14,5 +14,8 @@
 pub mod av1;
 pub mod h264;
 pub mod h265;
+pub mod index;
+pub mod nal_framing;
+pub mod rtp;
 pub mod vp8;
 pub mod vp9;
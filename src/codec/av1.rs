@@ -2,6 +2,6 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-mod helpers;
+pub(crate) mod helpers;
 pub mod parser;
 pub mod reader;
@@ -1157,7 +1157,7 @@ pub struct Parser {
 impl Parser {
     /// Probes the input data for the Annex B format. Anything other than
     /// Ok(true) refers to data in "low-overhead" format instead, as we are trying to parse
-    fn annexb_probe(data: &[u8]) -> anyhow::Result<bool> {
+    pub(crate) fn annexb_probe(data: &[u8]) -> anyhow::Result<bool> {
         let mut r = Reader::new(data);
         let mut seen_sequence = false;
         let mut seen_frame = false;
@@ -1525,8 +1525,8 @@ impl Parser {
         // Both "low-overhead" and Annex B are now at the same point, i.e.: a
         // open_bitstream_unit() follows.
         let header = Self::parse_obu_header(&mut reader)?;
-        if matches!(self.stream_format, StreamFormat::LowOverhead) {
-            assert!(header.has_size_field);
+        if matches!(self.stream_format, StreamFormat::LowOverhead) && !header.has_size_field {
+            return Err(anyhow!("OBU without a size field in a low-overhead stream"));
         }
 
         let obu_size = if header.has_size_field {
@@ -2762,9 +2762,9 @@ impl Parser {
         if fh.primary_ref_frame == PRIMARY_REF_NONE {
             // setup_past_independence()
             #[allow(clippy::needless_range_loop)]
-            for ref_frame in ReferenceFrameType::Last as usize..ReferenceFrameType::AltRef as usize
+            for ref_frame in ReferenceFrameType::Last as usize..=ReferenceFrameType::AltRef as usize
             {
-                for i in 0..5 {
+                for i in 0..6 {
                     prev_gm_params[ref_frame][i] = if i % 3 == 2 {
                         1 << WARPEDMODEL_PREC_BITS
                     } else {
@@ -2905,17 +2905,17 @@ impl Parser {
         }
 
         if !fg.update_grain {
-            fg.film_grain_params_ref_idx = r.read_bits(3)?;
+            let film_grain_params_ref_idx = r.read_bits(3)?;
             let temp_grain_seed = fg.grain_seed;
 
             if !fh.ref_frame_idx.iter().any(|&ref_frame_idx| {
-                ref_frame_idx == i32::try_from(fg.film_grain_params_ref_idx).unwrap()
+                ref_frame_idx == i32::try_from(film_grain_params_ref_idx).unwrap()
             }) {
                 return Err(anyhow!("Invalid film_grain_params_ref_idx"));
             }
 
             // load_grain_params()
-            *fg = self.ref_info[fg.film_grain_params_ref_idx as usize]
+            *fg = self.ref_info[film_grain_params_ref_idx as usize]
                 .film_grain_params
                 .clone();
 
@@ -3113,7 +3113,7 @@ impl Parser {
                         return Err(anyhow!("Invalid id_len {}", id_len));
                     }
                     fh.display_frame_id = r.read_bits(id_len.try_into().unwrap())?;
-                    if ref_frame.display_frame_id != fh.display_frame_id || !ref_frame.ref_valid {
+                    if ref_frame.ref_frame_id != fh.display_frame_id || !ref_frame.ref_valid {
                         return Err(anyhow!("Invalid display_frame_id"));
                     }
                 }
@@ -3171,7 +3171,7 @@ impl Parser {
 
             fh.show_frame = r.read_bit()?;
 
-            if fh.show_frame && decoder_model_info_present_flag && equal_picture_interval {
+            if fh.show_frame && decoder_model_info_present_flag && !equal_picture_interval {
                 fh.frame_presentation_time =
                     r.read_bits(u8::try_from(frame_presentation_time_length_minus_1).unwrap() + 1)?;
             }
@@ -3301,6 +3301,7 @@ impl Parser {
         if decoder_model_info_present_flag {
             fh.buffer_removal_time_present_flag = r.read_bit()?;
             if fh.buffer_removal_time_present_flag {
+                fh.buffer_removal_time = vec![0; operating_points_cnt_minus_1 as usize + 1];
                 #[allow(clippy::needless_range_loop)]
                 for op_num in 0..=operating_points_cnt_minus_1 as usize {
                     if operating_points[op_num].decoder_model_present_for_this_op {
@@ -3408,6 +3409,25 @@ impl Parser {
                 Self::parse_render_size(&mut fh, &mut r)?;
             }
 
+            // 7.9: references can be up to twice as large and 16 times as small as the frame.
+            for idx in fh.ref_frame_idx {
+                let rf = &self.ref_info[usize::try_from(idx).unwrap()];
+                if rf.ref_valid
+                    && (2 * fh.frame_width < rf.ref_upscaled_width
+                        || 2 * fh.frame_height < rf.ref_frame_height
+                        || fh.frame_width > 16 * rf.ref_upscaled_width
+                        || fh.frame_height > 16 * rf.ref_frame_height)
+                {
+                    return Err(anyhow!(
+                        "Invalid reference scaling from {}x{} to {}x{}",
+                        rf.ref_upscaled_width,
+                        rf.ref_frame_height,
+                        fh.frame_width,
+                        fh.frame_height
+                    ));
+                }
+            }
+
             if fh.force_integer_mv > 0 {
                 fh.allow_high_precision_mv = false;
             } else {
@@ -3839,6 +3859,7 @@ impl Clone for Parser {
 #[cfg(test)]
 mod tests {
     use crate::codec::av1::parser::{ParsedObu, Parser, StreamFormat};
+    use crate::utils::Av1TemporalUnitIterator;
     use crate::utils::IvfIterator;
 
     use super::ObuType;
@@ -3905,6 +3926,19 @@ mod tests {
         assert!(matches!(parser.stream_format, StreamFormat::AnnexB { .. }));
     }
 
+    #[test]
+    /// Test that the temporal units of raw streams in both "low-overhead" and
+    /// Annex B formats are split the same way as in their IVF container.
+    fn split_temporal_units() {
+        for stream in [STREAM_TEST_25_FPS, STREAM_ANNEXB] {
+            let packets = IvfIterator::new(stream).collect::<Vec<_>>();
+            let raw = packets.concat();
+            let temporal_units = Av1TemporalUnitIterator::new(&raw).collect::<Vec<_>>();
+
+            assert_eq!(temporal_units, packets);
+        }
+    }
+
     #[test]
     /// Test that we can correctly identify streams in both "low-overhead" and
     /// Annex B formats and identify all the OBUs in the stream until the end.
@@ -376,6 +376,18 @@ impl<T: Clone> Dpb<T> {
         pics
     }
 
+    /// Bumps all the pictures that are needed for output, while keeping the reference pictures in
+    /// the DPB.
+    pub fn bump_all(&mut self) -> Vec<DpbEntry<T>> {
+        let mut pics = vec![];
+
+        while let Some(pic) = self.bump(false) {
+            pics.push(pic);
+        }
+
+        pics
+    }
+
     /// Clears the DPB, dropping all the pictures.
     pub fn clear(&mut self) {
         debug!("Clearing the DPB");
@@ -13,11 +13,17 @@ use anyhow::anyhow;
 use anyhow::Context;
 use bytes::Buf;
 use enumn::N;
+use log::debug;
 
 use crate::codec::h264::nalu;
 use crate::codec::h264::nalu::Header;
 use crate::codec::h264::nalu_reader::NaluReader;
 use crate::codec::h264::picture::Field;
+use crate::AspectRatio;
+use crate::Point;
+use crate::Rect;
+use crate::Resolution;
+use crate::MB_SIZE;
 
 pub type Nalu<'a> = nalu::Nalu<'a, NaluHeader>;
 
@@ -47,18 +53,6 @@ const MAX_SPS_COUNT: u8 = 32;
 /// The maximum number of pictures in the DPB, as per A.3.1, clause h)
 const DPB_MAX_SIZE: usize = 16;
 
-#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
-pub struct Point<T> {
-    pub x: T,
-    pub y: T,
-}
-
-#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
-pub struct Rect<T> {
-    pub min: Point<T>,
-    pub max: Point<T>,
-}
-
 #[derive(N, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum NaluType {
     Unknown = 0,
@@ -78,9 +72,21 @@ pub enum NaluType {
     PrefixUnit = 14,
     SubsetSps = 15,
     DepthSps = 16,
+    Reserved17 = 17,
+    Reserved18 = 18,
     SliceAux = 19,
     SliceExt = 20,
     SliceDepth = 21,
+    Reserved22 = 22,
+    Reserved23 = 23,
+    Unspecified24 = 24,
+    Unspecified25 = 25,
+    Unspecified26 = 26,
+    Unspecified27 = 27,
+    Unspecified28 = 28,
+    Unspecified29 = 29,
+    Unspecified30 = 30,
+    Unspecified31 = 31,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -301,6 +307,11 @@ pub struct SliceHeader {
     /// of the deblocking filter shall be computed using 7-33.
     pub slice_beta_offset_div2: i8,
 
+    /// Used to derive the number of slice group map units in slice group 0,
+    /// when flexible macroblock ordering is in use with
+    /// `slice_group_map_type` 3 to 5.
+    pub slice_group_change_cycle: u32,
+
     /// Same as `MaxPicNum` in the specification.
     pub max_pic_num: u32,
 
@@ -613,15 +624,23 @@ impl Sps {
         1 << (self.log2_max_frame_num_minus4 + 4)
     }
 
+    /// Returns the sample aspect ratio signaled in the VUI, if any.
+    pub fn sample_aspect_ratio(&self) -> Option<AspectRatio> {
+        let vui = &self.vui_parameters;
+        if !self.vui_parameters_present_flag || !vui.aspect_ratio_info_present_flag {
+            return None;
+        }
+
+        AspectRatio::from_h26x_idc(
+            u32::from(vui.aspect_ratio_idc),
+            u32::from(vui.sar_width),
+            u32::from(vui.sar_height),
+        )
+    }
+
     pub fn visible_rectangle(&self) -> Rect<u32> {
         if !self.frame_cropping_flag {
-            return Rect {
-                min: Point { x: 0, y: 0 },
-                max: Point {
-                    x: self.width,
-                    y: self.height,
-                },
-            };
+            return Rect::from(Resolution::from((self.width, self.height)));
         }
 
         let crop_unit_x;
@@ -647,8 +666,8 @@ impl Sps {
                 y: crop_top,
             },
             max: Point {
-                x: self.width - crop_left - crop_right,
-                y: self.height - crop_top - crop_bottom,
+                x: self.width - crop_right,
+                y: self.height - crop_bottom,
             },
         }
     }
@@ -690,21 +709,26 @@ impl Sps {
             Level::L6_2 => 696320,
         };
 
-        let width_mb = self.width / 16;
-        let height_mb = self.height / 16;
+        let size_in_mbs = Resolution::from((self.width, self.height)).in_blocks(MB_SIZE);
 
         let max_dpb_frames =
-            std::cmp::min(max_dpb_mbs / (width_mb * height_mb), DPB_MAX_SIZE as u32) as usize;
+            std::cmp::min(max_dpb_mbs / size_in_mbs.area(), DPB_MAX_SIZE as u32) as usize;
 
         let mut max_dpb_frames = std::cmp::max(max_dpb_frames, self.max_num_ref_frames as usize);
 
-        if self.vui_parameters_present_flag && self.vui_parameters.bitstream_restriction_flag {
+        if self.has_max_dec_frame_buffering() {
             max_dpb_frames = std::cmp::max(1, self.vui_parameters.max_dec_frame_buffering as usize);
         }
 
         max_dpb_frames
     }
 
+    /// Returns whether the DPB size is explicitly signaled through the VUI's
+    /// `max_dec_frame_buffering` instead of being derived from the level.
+    pub fn has_max_dec_frame_buffering(&self) -> bool {
+        self.vui_parameters_present_flag && self.vui_parameters.bitstream_restriction_flag
+    }
+
     pub fn max_num_order_frames(&self) -> u32 {
         let vui = &self.vui_parameters;
         let present = self.vui_parameters_present_flag && vui.bitstream_restriction_flag;
@@ -1037,7 +1061,7 @@ impl Default for VuiParams {
 /// A H264 Picture Parameter Set. A syntax structure containing syntax elements
 /// that apply to zero or more entire coded pictures as determined by the
 /// `pic_parameter_set_id` syntax element found in each slice header.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Pps {
     /// Identifies the picture parameter set that is referred to in the slice header.
     pub pic_parameter_set_id: u8,
@@ -1069,6 +1093,18 @@ pub struct Pps {
     /// `num_slice_groups_minus1` is specified in Annex A.
     pub num_slice_groups_minus1: u32,
 
+    /// Specifies how the mapping of slice group map units to slice groups is
+    /// coded, when flexible macroblock ordering is in use, i.e. when
+    /// `num_slice_groups_minus1` is greater than 0.
+    pub slice_group_map_type: u8,
+
+    /// Used with `slice_group_map_type` 3 to 5 to refine the map type.
+    pub slice_group_change_direction_flag: bool,
+
+    /// Plus 1 specifies the value of the variable `SliceGroupChangeRate`,
+    /// used with `slice_group_map_type` 3 to 5.
+    pub slice_group_change_rate_minus1: u32,
+
     /// Specifies how `num_ref_idx_l0_active_minus1` is inferred for P, SP, and
     /// B slices with `num_ref_idx_active_override_flag` not set.
     pub num_ref_idx_l0_default_active_minus1: u8,
@@ -1158,10 +1194,75 @@ pub struct Pps {
     pub sps: Rc<Sps>,
 }
 
+/// Frame packing arrangement SEI message. See Annex D of the specification.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FramePackingArrangement {
+    /// Identifies the arrangement. Only one arrangement can be active at a
+    /// time in the streams we support.
+    pub frame_packing_arrangement_id: u32,
+    /// If true, cancels the persistence of any previous frame packing
+    /// arrangement SEI message, in which case none of the other fields are
+    /// present.
+    pub frame_packing_arrangement_cancel_flag: bool,
+    /// Type of packing arrangement of the frames, as specified in Table D-8.
+    pub frame_packing_arrangement_type: u8,
+    /// Whether each color component plane of each constituent frame is
+    /// quincunx sampled.
+    pub quincunx_sampling_flag: bool,
+    /// Intended interpretation of the constituent frames, as specified in
+    /// Table D-9: 1 means that frame 0 is the left view, 2 that it is the
+    /// right view.
+    pub content_interpretation_type: u8,
+    /// Whether one of the two constituent frames is spatially flipped.
+    pub spatial_flipping_flag: bool,
+    /// Whether frame 0 is the flipped one, if `spatial_flipping_flag` is set.
+    pub frame0_flipped_flag: bool,
+    /// Whether the constituent frames are the fields of the decoded frame.
+    pub field_views_flag: bool,
+    /// For temporal interleaving, whether the current frame is frame 0.
+    pub current_frame_is_frame0_flag: bool,
+    /// Whether frame 0 can be decoded without referencing frame 1.
+    pub frame0_self_contained_flag: bool,
+    /// Whether frame 1 can be decoded without referencing frame 0.
+    pub frame1_self_contained_flag: bool,
+    pub frame0_grid_position_x: u8,
+    pub frame0_grid_position_y: u8,
+    pub frame1_grid_position_x: u8,
+    pub frame1_grid_position_y: u8,
+    /// 0 if the arrangement only applies to the current frame, otherwise
+    /// specifies for how long it persists.
+    pub frame_packing_arrangement_repetition_period: u32,
+    pub frame_packing_arrangement_extension_flag: bool,
+}
+
+/// A picture timing SEI message. See D.1.3 in the specification.
+///
+/// Only `pic_struct` is extracted: the CPB and DPB output delays and the clock timestamps are
+/// skipped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PicTiming {
+    /// Indicates whether the picture should be displayed as a frame or as one or more fields, see
+    /// table D-1 in the specification. `None` if the SPS does not signal it.
+    pub pic_struct: Option<u8>,
+}
+
+/// A SEI message. See 7.3.2.3.1 in the specification.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SeiMessage {
+    /// A picture timing message, interpreted against the last SPS parsed.
+    PicTiming(PicTiming),
+    FramePackingArrangement(FramePackingArrangement),
+    /// A message that we do not parse, with its payload type.
+    Unsupported(u32),
+}
+
 #[derive(Debug, Default)]
 pub struct Parser {
     active_spses: BTreeMap<u8, Rc<Sps>>,
     active_ppses: BTreeMap<u8, Rc<Pps>>,
+    /// ID of the last SPS parsed. Picture timing SEI messages depend on the SPS of the picture
+    /// they precede, which is usually this one.
+    last_sps_id: Option<u8>,
 }
 
 impl Parser {
@@ -1387,6 +1488,46 @@ impl Parser {
         Ok(())
     }
 
+    /// Parses the slice group map of a PPS using flexible macroblock
+    /// ordering. Only the fields needed to parse slice headers are kept, as
+    /// no backend supports decoding such streams.
+    fn parse_slice_group_map(r: &mut NaluReader, pps: &mut Pps) -> anyhow::Result<()> {
+        pps.slice_group_map_type = r.read_ue_max(6)?;
+
+        match pps.slice_group_map_type {
+            0 => {
+                for _ in 0..=pps.num_slice_groups_minus1 {
+                    // run_length_minus1
+                    r.read_ue::<u32>()?;
+                }
+            }
+            2 => {
+                for _ in 0..pps.num_slice_groups_minus1 {
+                    // top_left and bottom_right
+                    r.read_ue::<u32>()?;
+                    r.read_ue::<u32>()?;
+                }
+            }
+            3..=5 => {
+                pps.slice_group_change_direction_flag = r.read_bit()?;
+                pps.slice_group_change_rate_minus1 = r.read_ue()?;
+            }
+            6 => {
+                // Ceil(Log2(num_slice_groups_minus1 + 1))
+                let num_bits = (u32::BITS - pps.num_slice_groups_minus1.leading_zeros()) as usize;
+                let pic_size_in_map_units_minus1: u32 = r.read_ue()?;
+
+                for _ in 0..=pic_size_in_map_units_minus1 {
+                    // slice_group_id
+                    r.read_bits::<u32>(num_bits)?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
     fn parse_hrd(r: &mut NaluReader, hrd: &mut HrdParams) -> anyhow::Result<()> {
         hrd.cpb_cnt_minus1 = r.read_ue_max(31)?;
         hrd.bit_rate_scale = r.read_bits(4)?;
@@ -1614,9 +1755,9 @@ impl Parser {
             Parser::parse_vui(&mut r, &mut sps)?;
         }
 
-        let mut width = (sps.pic_width_in_mbs_minus1 + 1) * 16;
+        let mut width = (sps.pic_width_in_mbs_minus1 + 1) * MB_SIZE;
         let mut height = (sps.pic_height_in_map_units_minus1 + 1)
-            * 16
+            * MB_SIZE
             * (2 - u32::from(sps.frame_mbs_only_flag));
 
         sps.width = width;
@@ -1650,7 +1791,20 @@ impl Parser {
         }
 
         let key = sps.seq_parameter_set_id;
-        self.active_spses.insert(key, Rc::new(sps));
+        match self.active_spses.get(&key) {
+            // The SPS is just being repeated: keep the current one so PPSs and pictures keep
+            // sharing it.
+            Some(current) if **current == sps => (),
+            Some(_) => {
+                let sps = Rc::new(sps);
+                self.rebind_ppses(&sps);
+                self.active_spses.insert(key, sps);
+            }
+            None => {
+                self.active_spses.insert(key, Rc::new(sps));
+            }
+        }
+        self.last_sps_id = Some(key);
 
         if self.active_spses.keys().len() > MAX_SPS_COUNT as usize {
             return Err(anyhow!(
@@ -1661,6 +1815,42 @@ impl Parser {
         Ok(self.get_sps(key).unwrap())
     }
 
+    /// Makes the PPSs referring to the id of `sps`, which redefines a
+    /// previous SPS, use it from now on. PPSs whose content cannot be
+    /// re-derived from the new SPS without parsing them again are removed, so
+    /// the stream needs to redefine them before they can be used.
+    fn rebind_ppses(&mut self, sps: &Rc<Sps>) {
+        self.active_ppses.retain(|_, pps| {
+            if pps.seq_parameter_set_id != sps.seq_parameter_set_id {
+                return true;
+            }
+
+            // The parsing of the PPS depends on these.
+            if pps.sps.chroma_format_idc != sps.chroma_format_idc
+                || pps.sps.bit_depth_luma_minus8 != sps.bit_depth_luma_minus8
+                || (pps.pic_scaling_matrix_present_flag
+                    && (pps.sps.seq_scaling_matrix_present_flag
+                        || sps.seq_scaling_matrix_present_flag))
+            {
+                debug!(
+                    "Dropping PPS {} as its SPS {} has been redefined",
+                    pps.pic_parameter_set_id, sps.seq_parameter_set_id
+                );
+                return false;
+            }
+
+            let mut new_pps = Pps::clone(pps);
+            if !new_pps.pic_scaling_matrix_present_flag {
+                new_pps.scaling_lists_4x4 = sps.scaling_lists_4x4;
+                new_pps.scaling_lists_8x8 = sps.scaling_lists_8x8;
+            }
+            new_pps.sps = Rc::clone(sps);
+            *pps = Rc::new(new_pps);
+
+            true
+        });
+    }
+
     pub fn parse_pps(&mut self, nalu: &Nalu) -> anyhow::Result<&Pps> {
         if !matches!(nalu.header.type_, NaluType::Pps) {
             return Err(anyhow!(
@@ -1687,6 +1877,9 @@ impl Parser {
             entropy_coding_mode_flag: Default::default(),
             bottom_field_pic_order_in_frame_present_flag: Default::default(),
             num_slice_groups_minus1: Default::default(),
+            slice_group_map_type: Default::default(),
+            slice_group_change_direction_flag: Default::default(),
+            slice_group_change_rate_minus1: Default::default(),
             num_ref_idx_l0_default_active_minus1: Default::default(),
             num_ref_idx_l1_default_active_minus1: Default::default(),
             weighted_pred_flag: Default::default(),
@@ -1707,7 +1900,7 @@ impl Parser {
         pps.num_slice_groups_minus1 = r.read_ue_max(7)?;
 
         if pps.num_slice_groups_minus1 > 0 {
-            return Err(anyhow!("Stream contain unsupported/unimplemented NALs"));
+            Parser::parse_slice_group_map(&mut r, &mut pps)?;
         }
 
         pps.num_ref_idx_l0_default_active_minus1 = r.read_ue_max(31)?;
@@ -2117,8 +2310,20 @@ impl Parser {
             }
         }
 
-        if pps.num_slice_groups_minus1 > 0 {
-            return Err(anyhow!("Stream contain unsupported/unimplemented NALs"));
+        if pps.num_slice_groups_minus1 > 0 && (3..=5).contains(&pps.slice_group_map_type) {
+            let pic_size_in_map_units =
+                (sps.pic_width_in_mbs_minus1 + 1) * (sps.pic_height_in_map_units_minus1 + 1);
+            let slice_group_change_rate = pps.slice_group_change_rate_minus1 + 1;
+
+            // Ceil(Log2(PicSizeInMapUnits ÷ SliceGroupChangeRate + 1)), see 7.4.3.
+            let mut num_bits = 0;
+            while (u64::from(slice_group_change_rate) << num_bits)
+                < u64::from(pic_size_in_map_units) + u64::from(slice_group_change_rate)
+            {
+                num_bits += 1;
+            }
+
+            header.slice_group_change_cycle = r.read_bits(num_bits)?;
         }
 
         let epb = r.num_epb();
@@ -2129,6 +2334,138 @@ impl Parser {
         Ok(Slice { header, nalu })
     }
 
+    fn parse_frame_packing_arrangement(
+        r: &mut NaluReader,
+    ) -> anyhow::Result<FramePackingArrangement> {
+        let mut fpa = FramePackingArrangement {
+            frame_packing_arrangement_id: r.read_ue()?,
+            frame_packing_arrangement_cancel_flag: r.read_bit()?,
+            ..Default::default()
+        };
+
+        if !fpa.frame_packing_arrangement_cancel_flag {
+            fpa.frame_packing_arrangement_type = r.read_bits(7)?;
+            fpa.quincunx_sampling_flag = r.read_bit()?;
+            fpa.content_interpretation_type = r.read_bits(6)?;
+            fpa.spatial_flipping_flag = r.read_bit()?;
+            fpa.frame0_flipped_flag = r.read_bit()?;
+            fpa.field_views_flag = r.read_bit()?;
+            fpa.current_frame_is_frame0_flag = r.read_bit()?;
+            fpa.frame0_self_contained_flag = r.read_bit()?;
+            fpa.frame1_self_contained_flag = r.read_bit()?;
+
+            if !fpa.quincunx_sampling_flag && fpa.frame_packing_arrangement_type != 5 {
+                fpa.frame0_grid_position_x = r.read_bits(4)?;
+                fpa.frame0_grid_position_y = r.read_bits(4)?;
+                fpa.frame1_grid_position_x = r.read_bits(4)?;
+                fpa.frame1_grid_position_y = r.read_bits(4)?;
+            }
+
+            // frame_packing_arrangement_reserved_byte
+            r.skip_bits(8)?;
+            fpa.frame_packing_arrangement_repetition_period = r.read_ue_max(16384)?;
+        }
+
+        fpa.frame_packing_arrangement_extension_flag = r.read_bit()?;
+
+        Ok(fpa)
+    }
+
+    fn parse_pic_timing(r: &mut NaluReader, sps: &Sps) -> anyhow::Result<PicTiming> {
+        let vui = &sps.vui_parameters;
+        let mut pic_timing = PicTiming::default();
+
+        // CpbDpbDelaysPresentFlag
+        let hrd = if vui.nal_hrd_parameters_present_flag {
+            Some(&vui.nal_hrd_parameters)
+        } else if vui.vcl_hrd_parameters_present_flag {
+            Some(&vui.vcl_hrd_parameters)
+        } else {
+            None
+        };
+
+        if let Some(hrd) = hrd {
+            // cpb_removal_delay and dpb_output_delay
+            r.skip_bits(usize::from(hrd.cpb_removal_delay_length_minus1) + 1)?;
+            r.skip_bits(usize::from(hrd.dpb_output_delay_length_minus1) + 1)?;
+        }
+
+        if vui.pic_struct_present_flag {
+            let pic_struct = r.read_bits(4)?;
+            if pic_struct > 8 {
+                return Err(anyhow!("Invalid pic_struct {}", pic_struct));
+            }
+            pic_timing.pic_struct = Some(pic_struct);
+        }
+
+        Ok(pic_timing)
+    }
+
+    /// Parses the messages of a SEI NALU. Messages we do not support are
+    /// reported as `SeiMessage::Unsupported`, as are picture timing messages
+    /// if no SPS has been parsed yet.
+    pub fn parse_sei(&self, nalu: &Nalu) -> anyhow::Result<Vec<SeiMessage>> {
+        if !matches!(nalu.header.type_, NaluType::Sei) {
+            return Err(anyhow!(
+                "Invalid NALU type, expected {:?}, got {:?}",
+                NaluType::Sei,
+                nalu.header.type_
+            ));
+        }
+
+        let data = &nalu.as_ref()[nalu.header.len()..];
+        let mut r = NaluReader::new(data);
+        // Position in bits within the RBSP, i.e. without the emulation
+        // prevention bytes.
+        let rbsp_pos = |r: &NaluReader| (data.len() - r.num_epb()) * 8 - r.num_bits_left();
+
+        let mut messages = vec![];
+        while r.has_more_rsbp_data() {
+            let mut payload_type = 0u32;
+            loop {
+                let byte = r.read_bits::<u32>(8)?;
+                payload_type += byte;
+                if byte != 0xff {
+                    break;
+                }
+            }
+
+            let mut payload_size = 0usize;
+            loop {
+                let byte = r.read_bits::<usize>(8)?;
+                payload_size += byte;
+                if byte != 0xff {
+                    break;
+                }
+            }
+
+            let payload_start = rbsp_pos(&r);
+            let last_sps = self.last_sps_id.and_then(|id| self.get_sps(id));
+            let message = match (payload_type, last_sps) {
+                (1, Some(sps)) => SeiMessage::PicTiming(Self::parse_pic_timing(&mut r, sps)?),
+                (45, _) => SeiMessage::FramePackingArrangement(
+                    Self::parse_frame_packing_arrangement(&mut r)?,
+                ),
+                _ => SeiMessage::Unsupported(payload_type),
+            };
+
+            // Skip whatever we did not parse, including any payload extension.
+            let parsed = rbsp_pos(&r) - payload_start;
+            let payload_bits = payload_size * 8;
+            if parsed > payload_bits {
+                return Err(anyhow!(
+                    "Broken Data: SEI message of type {} larger than its payload",
+                    payload_type
+                ));
+            }
+            r.skip_bits(payload_bits - parsed)?;
+
+            messages.push(message);
+        }
+
+        Ok(messages)
+    }
+
     pub fn get_sps(&self, sps_id: u8) -> Option<&Rc<Sps>> {
         self.active_spses.get(&sps_id)
     }
@@ -2155,10 +2492,6 @@ impl Header for NaluHeader {
 
         let type_ = NaluType::n(byte & 0x1f).ok_or(anyhow!("Broken Data"))?;
 
-        if let NaluType::SliceExt = type_ {
-            return Err(anyhow!("Stream contain unsupported/unimplemented NALs"));
-        }
-
         let ref_idc = (byte & 0x60) >> 5;
         let idr_pic_flag = matches!(type_, NaluType::SliceIdr);
 
@@ -2181,13 +2514,22 @@ impl Header for NaluHeader {
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
+    use std::rc::Rc;
 
+    use crate::codec::h264::parser::FramePackingArrangement;
     use crate::codec::h264::parser::Level;
     use crate::codec::h264::parser::Nalu;
     use crate::codec::h264::parser::NaluType;
     use crate::codec::h264::parser::Parser;
+    use crate::codec::h264::parser::PicTiming;
+    use crate::codec::h264::parser::SeiMessage;
+    use crate::codec::h264::parser::Sps;
+    use crate::Point;
 
     const STREAM_TEST_25_FPS: &[u8] = include_bytes!("test_data/test-25fps.h264");
+    const STREAM_TEST_25_FPS_INTERLACED: &[u8] =
+        include_bytes!("test_data/test-25fps-interlaced.h264");
+    const STREAM_64X64_I_P: &[u8] = include_bytes!("test_data/64x64-I-P.h264");
     const STREAM_TEST_25_FPS_NUM_NALUS: usize = 759;
 
     const STREAM_TEST_25_FPS_SLICE_0: &[u8] =
@@ -2534,4 +2876,193 @@ mod tests {
             parser.parse_sps(&nalu).unwrap_err();
         }
     }
+
+    #[test]
+    fn parse_pps_with_slice_groups() {
+        // PPS using flexible macroblock ordering with slice_group_map_type 4,
+        // which is not supported by the decoders but must still be parsed
+        // correctly so it can be reported.
+        let fmo_pps = vec![0x00, 0x00, 0x01, 0x68, 0xc4, 0x5b, 0xc7, 0x90];
+
+        let mut parser = Parser::default();
+        let mut cursor = Cursor::new(STREAM_TEST_25_FPS);
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            if nalu.header.type_ == NaluType::Sps {
+                parser.parse_sps(&nalu).unwrap();
+                break;
+            }
+        }
+
+        let mut cursor = Cursor::new(fmo_pps.as_ref());
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        let pps = parser.parse_pps(&nalu).unwrap();
+
+        assert_eq!(pps.num_slice_groups_minus1, 1);
+        assert_eq!(pps.slice_group_map_type, 4);
+        assert!(pps.slice_group_change_direction_flag);
+        assert_eq!(pps.slice_group_change_rate_minus1, 2);
+        assert_eq!(pps.num_ref_idx_l0_default_active_minus1, 0);
+        assert!(pps.deblocking_filter_control_present_flag);
+        assert!(!pps.redundant_pic_cnt_present_flag);
+    }
+
+    #[test]
+    fn parse_extension_and_reserved_nalus() {
+        // A prefix NAL unit, a coded slice extension and a NAL unit of
+        // unspecified type. Their headers must parse so the decoder can skip
+        // them.
+        let stream = vec![
+            0x00, 0x00, 0x01, 0x6e, 0x80, 0x00, 0x00, //
+            0x00, 0x00, 0x01, 0x74, 0x80, 0x00, 0x00, //
+            0x00, 0x00, 0x01, 0x18, 0xff, 0xff,
+        ];
+
+        let mut cursor = Cursor::new(stream.as_ref());
+        let types = std::iter::from_fn(|| Nalu::next(&mut cursor).ok())
+            .map(|nalu| nalu.header.type_)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            types,
+            vec![
+                NaluType::PrefixUnit,
+                NaluType::SliceExt,
+                NaluType::Unspecified24
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_frame_packing_sei() {
+        // SEI with an unregistered user data message followed by a
+        // side-by-side frame packing arrangement, left view first, repeated
+        // until further notice.
+        let sei = [
+            0x00, 0x00, 0x01, 0x06, 0x05, 0x02, 0xaa, 0xbb, 0x2d, 0x07, 0x81, 0x81, 0x00, 0x00,
+            0x03, 0x00, 0x01, 0x20, 0x80,
+        ];
+
+        let parser = Parser::default();
+        let mut cursor = Cursor::new(sei.as_ref());
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        let messages = parser.parse_sei(&nalu).unwrap();
+
+        assert_eq!(
+            messages,
+            vec![
+                SeiMessage::Unsupported(5),
+                SeiMessage::FramePackingArrangement(FramePackingArrangement {
+                    frame_packing_arrangement_type: 3,
+                    content_interpretation_type: 1,
+                    frame_packing_arrangement_repetition_period: 1,
+                    ..Default::default()
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn sps_redefinition() {
+        let mut parser = Parser::default();
+
+        let mut cursor = Cursor::new(STREAM_TEST_25_FPS);
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            match nalu.header.type_ {
+                NaluType::Sps => {
+                    parser.parse_sps(&nalu).unwrap();
+                }
+                NaluType::Pps => {
+                    parser.parse_pps(&nalu).unwrap();
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        let sps = Rc::clone(parser.get_sps(0).unwrap());
+        let pps = Rc::clone(parser.get_pps(0).unwrap());
+        assert!(Rc::ptr_eq(&pps.sps, &sps));
+
+        let first_sps = |stream| {
+            let mut cursor = Cursor::new(stream);
+            std::iter::from_fn(|| Nalu::next(&mut cursor).ok())
+                .find(|nalu| nalu.header.type_ == NaluType::Sps)
+                .unwrap()
+        };
+
+        // Repeating the same SPS must not change anything.
+        parser.parse_sps(&first_sps(STREAM_TEST_25_FPS)).unwrap();
+        assert!(Rc::ptr_eq(parser.get_sps(0).unwrap(), &sps));
+        assert!(Rc::ptr_eq(parser.get_pps(0).unwrap(), &pps));
+
+        // Redefining SPS 0 with different content must make PPS 0 use it.
+        parser.parse_sps(&first_sps(STREAM_64X64_I_P)).unwrap();
+        let new_sps = parser.get_sps(0).unwrap();
+        assert_ne!(**new_sps, *sps);
+        assert!(Rc::ptr_eq(&parser.get_pps(0).unwrap().sps, new_sps));
+    }
+
+    #[test]
+    fn visible_rectangle_with_offsets() {
+        // 1080p stream coded as 1920x1088, with the crop window shifted by 4
+        // pixels to the right.
+        let sps = Sps {
+            width: 1920,
+            height: 1088,
+            chroma_format_idc: 1,
+            chroma_array_type: 1,
+            frame_mbs_only_flag: true,
+            frame_cropping_flag: true,
+            frame_crop_left_offset: 2,
+            frame_crop_right_offset: 0,
+            frame_crop_top_offset: 0,
+            frame_crop_bottom_offset: 4,
+            ..Default::default()
+        };
+
+        let rect = sps.visible_rectangle();
+        assert_eq!(rect.min, Point { x: 4, y: 0 });
+        assert_eq!(rect.max, Point { x: 1920, y: 1080 });
+    }
+
+    #[test]
+    fn parse_pic_timing_sei() {
+        let mut parser = Parser::default();
+        let mut pic_structs = vec![];
+
+        let mut cursor = Cursor::new(STREAM_TEST_25_FPS_INTERLACED);
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            match nalu.header.type_ {
+                NaluType::Sps => {
+                    parser.parse_sps(&nalu).unwrap();
+                }
+                NaluType::Sei => {
+                    for message in parser.parse_sei(&nalu).unwrap() {
+                        if let SeiMessage::PicTiming(PicTiming { pic_struct }) = message {
+                            pic_structs.push(pic_struct.unwrap());
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        // Every frame signals its fields, 46 of them top field first.
+        assert_eq!(pic_structs.len(), 250);
+        assert_eq!(pic_structs.iter().filter(|&&p| p == 3).count(), 46);
+        assert_eq!(pic_structs.iter().filter(|&&p| p == 4).count(), 204);
+
+        // Picture timing cannot be interpreted without an SPS.
+        let mut cursor = Cursor::new(STREAM_TEST_25_FPS_INTERLACED);
+        let parser = Parser::default();
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            if matches!(nalu.header.type_, NaluType::Sei) {
+                assert!(!parser
+                    .parse_sei(&nalu)
+                    .unwrap()
+                    .iter()
+                    .any(|message| matches!(message, SeiMessage::PicTiming(_))));
+            }
+        }
+    }
 }
@@ -8,6 +8,7 @@ use std::rc::Weak;
 
 use log::debug;
 
+use crate::codec::h264::parser::FramePackingArrangement;
 use crate::codec::h264::parser::RefPicMarking;
 use crate::codec::h264::parser::Slice;
 use crate::codec::h264::parser::SliceType;
@@ -88,12 +89,24 @@ pub struct PictureData {
     // Not for decode or output.
     pub nonexisting: bool,
 
+    // Whether the picture has been decoded from missing or corrupted
+    // references, and thus likely contains visible errors.
+    pub corrupted: bool,
+
     pub field: Field,
 
     // Values from slice_hdr to be used during reference marking and
     // memory management after finishing this picture.
     pub ref_pic_marking: RefPicMarking,
 
+    // Frame packing arrangement applying to this picture, as signaled by the
+    // SEI messages of its access unit or persisting from a previous one.
+    pub frame_packing: Option<FramePackingArrangement>,
+
+    // pic_struct of this picture, as signaled by the picture timing SEI
+    // message of its access unit.
+    pub pic_struct: Option<u8>,
+
     is_second_field: bool,
     other_field: Option<Weak<RefCell<Self>>>,
 
@@ -178,12 +191,7 @@ impl PictureData {
         let height = sps.height;
         let coded_resolution = Resolution { width, height };
 
-        let visible_rect = sps.visible_rectangle();
-
-        let display_resolution = Resolution {
-            width: visible_rect.max.x - visible_rect.min.x,
-            height: visible_rect.max.y - visible_rect.min.y,
-        };
+        let display_resolution = sps.visible_rectangle().size();
 
         PictureData {
             pic_order_cnt_type: sps.pic_order_cnt_type,
@@ -294,6 +302,7 @@ impl PictureData {
             frame_num: pic.frame_num,
             reference: pic.reference,
             nonexisting: pic.nonexisting,
+            corrupted: pic.corrupted,
             pic_order_cnt,
             field: pic.field.opposite(),
             is_second_field: true,
@@ -353,8 +362,11 @@ impl std::fmt::Debug for PictureData {
             .field("needed_for_output", &self.needed_for_output)
             .field("has_mmco_5", &self.has_mmco_5)
             .field("nonexisting", &self.nonexisting)
+            .field("corrupted", &self.corrupted)
             .field("field", &self.field)
             .field("ref_pic_marking", &self.ref_pic_marking)
+            .field("frame_packing", &self.frame_packing)
+            .field("pic_struct", &self.pic_struct)
             .field("is_second_field", &self.is_second_field)
             .field("other_field", &self.other_field)
             .finish()
@@ -17,8 +17,10 @@ use enumn::N;
 use crate::codec::h264::nalu;
 use crate::codec::h264::nalu::Header;
 use crate::codec::h264::nalu_reader::NaluReader;
-use crate::codec::h264::parser::Point;
-use crate::codec::h264::parser::Rect;
+use crate::AspectRatio;
+use crate::Point;
+use crate::Rect;
+use crate::Resolution;
 
 // Given the max VPS id.
 const MAX_VPS_COUNT: usize = 16;
@@ -113,6 +115,22 @@ pub enum NaluType {
     RsvNvcl45 = 45,
     RsvNvcl46 = 46,
     RsvNvcl47 = 47,
+    UnspecNvcl48 = 48,
+    UnspecNvcl49 = 49,
+    UnspecNvcl50 = 50,
+    UnspecNvcl51 = 51,
+    UnspecNvcl52 = 52,
+    UnspecNvcl53 = 53,
+    UnspecNvcl54 = 54,
+    UnspecNvcl55 = 55,
+    UnspecNvcl56 = 56,
+    UnspecNvcl57 = 57,
+    UnspecNvcl58 = 58,
+    UnspecNvcl59 = 59,
+    UnspecNvcl60 = 60,
+    UnspecNvcl61 = 61,
+    UnspecNvcl62 = 62,
+    UnspecNvcl63 = 63,
 }
 
 impl NaluType {
@@ -853,6 +871,12 @@ impl Sps {
         max as usize
     }
 
+    /// Returns the DPB size signaled by the SPS for the highest temporal sub-layer, i.e.
+    /// `sps_max_dec_pic_buffering_minus1[sps_max_sub_layers_minus1] + 1`.
+    pub fn max_dec_pic_buffering(&self) -> usize {
+        usize::from(self.max_dec_pic_buffering_minus1[usize::from(self.max_sub_layers_minus1)]) + 1
+    }
+
     pub fn width(&self) -> u16 {
         self.pic_width_in_luma_samples
     }
@@ -861,19 +885,26 @@ impl Sps {
         self.pic_height_in_luma_samples
     }
 
+    /// Returns the sample aspect ratio signaled in the VUI, if any.
+    pub fn sample_aspect_ratio(&self) -> Option<AspectRatio> {
+        let vui = &self.vui_parameters;
+        if !self.vui_parameters_present_flag || !vui.aspect_ratio_info_present_flag {
+            return None;
+        }
+
+        AspectRatio::from_h26x_idc(vui.aspect_ratio_idc, vui.sar_width, vui.sar_height)
+    }
+
     pub fn visible_rectangle(&self) -> Rect<u32> {
         // From the specification:
         // NOTE 3 – The conformance cropping window offset parameters are
         // only applied at the output. All internal decoding processes are
         // applied to the uncropped picture size.
         if !self.conformance_window_flag {
-            return Rect {
-                min: Point { x: 0, y: 0 },
-                max: Point {
-                    x: u32::from(self.width()),
-                    y: u32::from(self.height()),
-                },
-            };
+            return Rect::from(Resolution::from((
+                u32::from(self.width()),
+                u32::from(self.height()),
+            )));
         }
         const SUB_HEIGHT_C: [u32; 5] = [1, 2, 1, 1, 1];
         const SUB_WIDTH_C: [u32; 5] = [1, 2, 2, 1, 1];
@@ -891,8 +922,8 @@ impl Sps {
                 y: crop_top,
             },
             max: Point {
-                x: u32::from(self.width()) - crop_left - crop_right,
-                y: u32::from(self.height()) - crop_top - crop_bottom,
+                x: u32::from(self.width()) - crop_right,
+                y: u32::from(self.height()) - crop_bottom,
             },
         }
     }
@@ -2133,11 +2164,84 @@ impl Default for VuiParams {
     }
 }
 
+/// Frame packing arrangement SEI message. See D.2.16 and D.3.16 in the
+/// specification.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FramePackingArrangement {
+    /// Identifies the arrangement.
+    pub fpa_id: u32,
+    /// If true, cancels the persistence of any previous frame packing
+    /// arrangement SEI message, in which case none of the other fields but
+    /// `upsampled_aspect_ratio_flag` are present.
+    pub fpa_cancel_flag: bool,
+    /// Type of packing arrangement of the frames, as specified in Table D.8.
+    pub fpa_type: u8,
+    /// Whether each color component plane of each constituent frame is
+    /// quincunx sampled.
+    pub quincunx_sampling_flag: bool,
+    /// Intended interpretation of the constituent frames, as specified in
+    /// Table D.9: 1 means that frame 0 is the left view, 2 that it is the
+    /// right view.
+    pub content_interpretation_type: u8,
+    /// Whether one of the two constituent frames is spatially flipped.
+    pub spatial_flipping_flag: bool,
+    /// Whether frame 0 is the flipped one, if `spatial_flipping_flag` is set.
+    pub frame0_flipped_flag: bool,
+    /// Whether the constituent frames are the fields of the decoded frame.
+    pub field_views_flag: bool,
+    /// For temporal interleaving, whether the current picture is frame 0.
+    pub current_frame_is_frame0_flag: bool,
+    /// Whether frame 0 can be decoded without referencing frame 1.
+    pub frame0_self_contained_flag: bool,
+    /// Whether frame 1 can be decoded without referencing frame 0.
+    pub frame1_self_contained_flag: bool,
+    pub frame0_grid_position_x: u8,
+    pub frame0_grid_position_y: u8,
+    pub frame1_grid_position_x: u8,
+    pub frame1_grid_position_y: u8,
+    /// If false, the arrangement only applies to the current picture.
+    /// Otherwise, it persists until the end of the CLVS or until another frame
+    /// packing arrangement SEI message.
+    pub fpa_persistence_flag: bool,
+    pub upsampled_aspect_ratio_flag: bool,
+}
+
+/// A picture timing SEI message. See D.2.3 in the specification.
+///
+/// Only the frame-field information is extracted: the CPB and DPB output
+/// delays are skipped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PicTiming {
+    /// Indicates whether the picture should be displayed as a frame or as one
+    /// or more fields, see table D.2 in the specification. `None` if the SPS
+    /// does not signal frame-field information.
+    pub pic_struct: Option<u8>,
+    /// 0 if the source of the picture is interlaced, 1 if it is progressive,
+    /// 2 if it is unknown. Only meaningful if `pic_struct` is present.
+    pub source_scan_type: u8,
+    /// Whether the picture is a repetition of the previous one in output
+    /// order. Only meaningful if `pic_struct` is present.
+    pub duplicate_flag: bool,
+}
+
+/// A SEI message. See 7.3.5 in the specification.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SeiMessage {
+    /// A picture timing message, interpreted against the last SPS parsed.
+    PicTiming(PicTiming),
+    FramePackingArrangement(FramePackingArrangement),
+    /// A message that we do not parse, with its payload type.
+    Unsupported(u32),
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Parser {
     active_vpses: BTreeMap<u8, Vps>,
     active_spses: BTreeMap<u8, Sps>,
     active_ppses: BTreeMap<u8, Pps>,
+    /// ID of the last SPS parsed. Picture timing SEI messages depend on the
+    /// SPS of the picture they precede, which is usually this one.
+    last_sps_id: Option<u8>,
 }
 
 impl Parser {
@@ -3096,12 +3200,14 @@ impl Parser {
             sps.min_cb_log2_size_y + u32::from(sps.log2_diff_max_min_luma_coding_block_size);
         // (7-12)
         sps.ctb_size_y = 1 << sps.ctb_log2_size_y;
-        // (7-17)
-        sps.pic_height_in_ctbs_y =
-            (sps.pic_height_in_luma_samples as f64 / sps.ctb_size_y as f64).ceil() as u32;
-        // (7-15)
-        sps.pic_width_in_ctbs_y =
-            (sps.pic_width_in_luma_samples as f64 / sps.ctb_size_y as f64).ceil() as u32;
+        // (7-15) and (7-17)
+        let size_in_ctbs = Resolution::from((
+            u32::from(sps.pic_width_in_luma_samples),
+            u32::from(sps.pic_height_in_luma_samples),
+        ))
+        .in_blocks(sps.ctb_size_y);
+        sps.pic_height_in_ctbs_y = size_in_ctbs.height;
+        sps.pic_width_in_ctbs_y = size_in_ctbs.width;
 
         sps.max_tb_log2_size_y = u32::from(
             sps.log2_min_luma_transform_block_size_minus2
@@ -3219,6 +3325,7 @@ impl Parser {
 
         let key = sps.seq_parameter_set_id;
         self.active_spses.insert(key, sps);
+        self.last_sps_id = Some(key);
 
         if self.active_spses.keys().len() > MAX_SPS_COUNT {
             return Err(anyhow!(
@@ -3631,7 +3738,7 @@ impl Parser {
     }
 
     /// Parses a slice header from a slice NALU.
-    pub fn parse_slice_header<'a>(&mut self, nalu: Nalu<'a>) -> anyhow::Result<Slice<'a>> {
+    pub fn parse_slice_header<'a>(&self, nalu: Nalu<'a>) -> anyhow::Result<Slice<'a>> {
         if !matches!(
             nalu.header.type_,
             NaluType::TrailN
@@ -3776,7 +3883,15 @@ impl Parser {
                         MAX_LONG_TERM_REF_PIC_SETS as u32 - u32::from(hdr.num_long_term_sps),
                     )?;
 
+                    // 7.4.7.1: the long-term entries cannot exceed the size of the DPB.
                     let num_lt = hdr.num_long_term_sps + hdr.num_long_term_pics;
+                    if usize::from(num_lt) > hdr.poc_lsb_lt.len() {
+                        return Err(anyhow!(
+                            "Invalid number of long-term reference pictures {}",
+                            num_lt
+                        ));
+                    }
+
                     for i in 0..usize::from(num_lt) {
                         // The variables `PocLsbLt[ i ]` and `UsedByCurrPicLt[ i ]` are derived as follows:
                         //
@@ -4043,6 +4158,128 @@ impl Parser {
         Ok(Slice { header: hdr, nalu })
     }
 
+    fn parse_frame_packing_arrangement(
+        r: &mut NaluReader,
+    ) -> anyhow::Result<FramePackingArrangement> {
+        let mut fpa = FramePackingArrangement {
+            fpa_id: r.read_ue()?,
+            fpa_cancel_flag: r.read_bit()?,
+            ..Default::default()
+        };
+
+        if !fpa.fpa_cancel_flag {
+            fpa.fpa_type = r.read_bits(7)?;
+            fpa.quincunx_sampling_flag = r.read_bit()?;
+            fpa.content_interpretation_type = r.read_bits(6)?;
+            fpa.spatial_flipping_flag = r.read_bit()?;
+            fpa.frame0_flipped_flag = r.read_bit()?;
+            fpa.field_views_flag = r.read_bit()?;
+            fpa.current_frame_is_frame0_flag = r.read_bit()?;
+            fpa.frame0_self_contained_flag = r.read_bit()?;
+            fpa.frame1_self_contained_flag = r.read_bit()?;
+
+            if !fpa.quincunx_sampling_flag && fpa.fpa_type != 5 {
+                fpa.frame0_grid_position_x = r.read_bits(4)?;
+                fpa.frame0_grid_position_y = r.read_bits(4)?;
+                fpa.frame1_grid_position_x = r.read_bits(4)?;
+                fpa.frame1_grid_position_y = r.read_bits(4)?;
+            }
+
+            // fpa_reserved_byte
+            r.skip_bits(8)?;
+            fpa.fpa_persistence_flag = r.read_bit()?;
+        }
+
+        fpa.upsampled_aspect_ratio_flag = r.read_bit()?;
+
+        Ok(fpa)
+    }
+
+    fn parse_pic_timing(r: &mut NaluReader, sps: &Sps) -> anyhow::Result<PicTiming> {
+        let mut pic_timing = PicTiming::default();
+
+        if sps.vui_parameters.frame_field_info_present_flag {
+            let pic_struct = r.read_bits(4)?;
+            if pic_struct > 12 {
+                return Err(anyhow!("Invalid pic_struct {}", pic_struct));
+            }
+            pic_timing.pic_struct = Some(pic_struct);
+            pic_timing.source_scan_type = r.read_bits(2)?;
+            pic_timing.duplicate_flag = r.read_bit()?;
+        }
+
+        Ok(pic_timing)
+    }
+
+    /// Parse a prefix or suffix SEI NALU. Messages we do not support are
+    /// reported as `SeiMessage::Unsupported`, as are picture timing messages
+    /// if no SPS has been parsed yet.
+    pub fn parse_sei(&self, nalu: &Nalu) -> anyhow::Result<Vec<SeiMessage>> {
+        if !matches!(
+            nalu.header.type_,
+            NaluType::PrefixSeiNut | NaluType::SuffixSeiNut
+        ) {
+            return Err(anyhow!(
+                "Invalid NALU type, expected a SEI NALU, got {:?}",
+                nalu.header.type_
+            ));
+        }
+
+        let data = &nalu.as_ref()[nalu.header.len()..];
+        let mut r = NaluReader::new(data);
+        // Position in bits within the RBSP, i.e. without the emulation
+        // prevention bytes.
+        let rbsp_pos = |r: &NaluReader| (data.len() - r.num_epb()) * 8 - r.num_bits_left();
+
+        let mut messages = vec![];
+        while r.has_more_rsbp_data() {
+            let mut payload_type = 0u32;
+            loop {
+                let byte = r.read_bits::<u32>(8)?;
+                payload_type += byte;
+                if byte != 0xff {
+                    break;
+                }
+            }
+
+            let mut payload_size = 0usize;
+            loop {
+                let byte = r.read_bits::<usize>(8)?;
+                payload_size += byte;
+                if byte != 0xff {
+                    break;
+                }
+            }
+
+            let payload_start = rbsp_pos(&r);
+            let last_sps = self.last_sps_id.and_then(|id| self.get_sps(id));
+            let message = match (nalu.header.type_, payload_type, last_sps) {
+                (NaluType::PrefixSeiNut, 1, Some(sps)) => {
+                    SeiMessage::PicTiming(Self::parse_pic_timing(&mut r, sps)?)
+                }
+                (NaluType::PrefixSeiNut, 45, _) => SeiMessage::FramePackingArrangement(
+                    Self::parse_frame_packing_arrangement(&mut r)?,
+                ),
+                _ => SeiMessage::Unsupported(payload_type),
+            };
+
+            // Skip whatever we did not parse, including any payload extension.
+            let parsed = rbsp_pos(&r) - payload_start;
+            let payload_bits = payload_size * 8;
+            if parsed > payload_bits {
+                return Err(anyhow!(
+                    "Broken Data: SEI message of type {} larger than its payload",
+                    payload_type
+                ));
+            }
+            r.skip_bits(payload_bits - parsed)?;
+
+            messages.push(message);
+        }
+
+        Ok(messages)
+    }
+
     /// Returns a previously parsed vps given `vps_id`, if any.
     pub fn get_vps(&self, vps_id: u8) -> Option<&Vps> {
         self.active_vpses.get(&vps_id)
@@ -4064,11 +4301,15 @@ mod tests {
     use std::io::Cursor;
 
     use crate::codec::h264::nalu::Nalu;
+    use crate::codec::h265::parser::FramePackingArrangement;
     use crate::codec::h265::parser::Level;
     use crate::codec::h265::parser::NaluHeader;
     use crate::codec::h265::parser::NaluType;
     use crate::codec::h265::parser::Parser;
+    use crate::codec::h265::parser::PicTiming;
+    use crate::codec::h265::parser::SeiMessage;
     use crate::codec::h265::parser::SliceType;
+    use crate::codec::h265::parser::Sps;
 
     const STREAM_BEAR: &[u8] = include_bytes!("test_data/bear.h265");
     const STREAM_BEAR_NUM_NALUS: usize = 35;
@@ -4250,6 +4491,8 @@ mod tests {
             assert_eq!(sps.max_num_reorder_pics[i], 0);
             assert_eq!(sps.max_latency_increase_plus1[i], 0);
         }
+        assert_eq!(sps.max_dec_pic_buffering(), 5);
+        assert_eq!(sps.max_dpb_size(), 12);
         assert_eq!(sps.log2_min_luma_coding_block_size_minus3, 0);
         assert_eq!(sps.log2_diff_max_min_luma_coding_block_size, 3);
         assert_eq!(sps.log2_min_luma_transform_block_size_minus2, 0);
@@ -4738,4 +4981,61 @@ mod tests {
         // Subtract 2 bytes to account for the header size.
         assert_eq!(hdr.header_bit_size - 16, 80);
     }
+
+    #[test]
+    fn parse_frame_packing_sei() {
+        // Prefix SEI with a persistent top-bottom frame packing arrangement,
+        // right view first.
+        let sei = [
+            0x00, 0x00, 0x01, 0x4e, 0x01, 0x2d, 0x07, 0x82, 0x02, 0x00, 0x00, 0x03, 0x00, 0x02,
+            0x80, 0x80,
+        ];
+
+        let parser = Parser::default();
+        let mut cursor = Cursor::new(sei.as_ref());
+        let nalu = Nalu::<NaluHeader>::next(&mut cursor).unwrap();
+        let messages = parser.parse_sei(&nalu).unwrap();
+
+        assert_eq!(
+            messages,
+            vec![SeiMessage::FramePackingArrangement(
+                FramePackingArrangement {
+                    fpa_type: 4,
+                    content_interpretation_type: 2,
+                    fpa_persistence_flag: true,
+                    ..Default::default()
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn parse_pic_timing_sei() {
+        // Prefix SEI with a picture timing message signaling an interlaced
+        // frame, top field first.
+        let sei = [0x00, 0x00, 0x01, 0x4e, 0x01, 0x01, 0x01, 0x31, 0x80];
+
+        let mut cursor = Cursor::new(sei.as_ref());
+        let nalu = Nalu::<NaluHeader>::next(&mut cursor).unwrap();
+
+        // Picture timing cannot be interpreted without an SPS.
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.parse_sei(&nalu).unwrap(),
+            vec![SeiMessage::Unsupported(1)]
+        );
+
+        let mut sps = Sps::default();
+        sps.vui_parameters.frame_field_info_present_flag = true;
+        parser.active_spses.insert(0, sps);
+        parser.last_sps_id = Some(0);
+        assert_eq!(
+            parser.parse_sei(&nalu).unwrap(),
+            vec![SeiMessage::PicTiming(PicTiming {
+                pic_struct: Some(3),
+                source_scan_type: 0,
+                duplicate_flag: false,
+            })]
+        );
+    }
 }
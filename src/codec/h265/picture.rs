@@ -2,7 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use crate::codec::h265::parser::FramePackingArrangement;
 use crate::codec::h265::parser::NaluType;
+use crate::codec::h265::parser::PicTiming;
 use crate::codec::h265::parser::Pps;
 use crate::codec::h265::parser::Slice;
 
@@ -28,6 +30,8 @@ pub struct PictureData {
     pub pic_order_cnt_msb: i32,
     pub pic_order_cnt_val: i32,
     pub no_output_of_prior_pics_flag: bool,
+    /// TemporalId of the picture, i.e. nuh_temporal_id_plus1 - 1.
+    pub temporal_id: u8,
 
     // Internal state.
     pub is_irap: bool,
@@ -36,6 +40,14 @@ pub struct PictureData {
     pub pic_latency_cnt: i32,
     pub needed_for_output: bool,
     pub short_term_ref_pic_set_size_bits: u32,
+    // Whether the picture has been decoded from missing or corrupted
+    // references, and thus likely contains visible errors.
+    pub corrupted: bool,
+    // Frame packing arrangement applying to this picture, as signaled by the
+    // SEI messages of its access unit or persisting from a previous one.
+    pub frame_packing: Option<FramePackingArrangement>,
+    // Picture timing signaled by the SEI messages of its access unit.
+    pub pic_timing: Option<PicTiming>,
 }
 
 impl PictureData {
@@ -134,12 +146,16 @@ impl PictureData {
             pic_order_cnt_msb,
             // Equation (8-2)
             pic_order_cnt_val: pic_order_cnt_msb + slice_pic_order_cnt_lsb,
+            temporal_id: slice.nalu.header.nuh_temporal_id_plus1.saturating_sub(1),
             is_irap,
             first_picture_after_eos,
             reference: Default::default(),
             pic_latency_cnt: 0,
             needed_for_output: false,
             short_term_ref_pic_set_size_bits: hdr.st_rps_bits,
+            corrupted: false,
+            frame_packing: None,
+            pic_timing: None,
         }
     }
 
@@ -0,0 +1,380 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Lightweight indexing of encoded streams.
+//!
+//! Building a [`StreamIndex`] only parses the headers required to locate the random access points
+//! of a stream and its resolution changes, without decoding anything. This is fast enough to be
+//! done before playback starts, so players can seek using the index.
+
+use std::io::Cursor;
+
+use log::debug;
+
+use crate::codec::h264::parser::Nalu as H264Nalu;
+use crate::codec::h264::parser::NaluType as H264NaluType;
+use crate::codec::h264::parser::Parser as H264Parser;
+use crate::codec::h265::parser::Nalu as H265Nalu;
+use crate::codec::h265::parser::NaluType as H265NaluType;
+use crate::codec::h265::parser::Parser as H265Parser;
+use crate::codec::vp8::parser::Parser as Vp8Parser;
+use crate::codec::vp9::parser::FrameType as Vp9FrameType;
+use crate::codec::vp9::parser::Parser as Vp9Parser;
+use crate::Resolution;
+
+/// A frame of a stream that is not a byte stream (e.g. VP8 or VP9), as extracted from its
+/// container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet<'a> {
+    /// Offset of the packet within the container, in bytes.
+    pub offset: usize,
+    /// Timestamp of the packet, as given by the container.
+    pub timestamp: u64,
+    /// Encoded data of the packet.
+    pub data: &'a [u8],
+}
+
+/// Entry of a [`StreamIndex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Offset in bytes at which the input must be submitted to the decoder.
+    pub offset: usize,
+    /// Timestamp of the frame starting at `offset`.
+    ///
+    /// Annex B streams (H.264 and H.265) do not carry timestamps, so for them this is the index of
+    /// the frame in decoding order.
+    pub timestamp: u64,
+    /// Display resolution of the stream from this frame on.
+    pub resolution: Resolution,
+}
+
+/// Index of the random access points and resolution changes of a stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamIndex {
+    /// Frames from which decoding can start, in stream order.
+    pub random_access_points: Vec<IndexEntry>,
+    /// Frames with a different resolution from the previous one, in stream order. The first frame
+    /// of the stream is always included.
+    pub resolution_changes: Vec<IndexEntry>,
+    /// Number of frames in the stream.
+    pub num_frames: u64,
+}
+
+impl StreamIndex {
+    /// Returns the random access point from which decoding must start in order to reach the
+    /// frame with timestamp `timestamp`, i.e. the last one at or before it.
+    pub fn seek_point(&self, timestamp: u64) -> Option<&IndexEntry> {
+        self.random_access_points
+            .iter()
+            .take_while(|entry| entry.timestamp <= timestamp)
+            .last()
+    }
+
+    /// Records a new frame starting at `offset`, with resolution `resolution`.
+    fn add_frame(&mut self, offset: usize, timestamp: u64, resolution: Resolution, rap: bool) {
+        let entry = IndexEntry {
+            offset,
+            timestamp,
+            resolution,
+        };
+
+        if rap {
+            self.random_access_points.push(entry);
+        }
+
+        if self.resolution_changes.last().map(|e| e.resolution) != Some(resolution) {
+            self.resolution_changes.push(entry);
+        }
+
+        self.num_frames += 1;
+    }
+
+    /// Indexes the H.264 Annex B stream `stream`.
+    ///
+    /// IDR pictures are the only random access points reported. NAL units that cannot be parsed,
+    /// e.g. slices preceding the first parameter sets, are ignored.
+    pub fn from_h264(stream: &[u8]) -> Self {
+        let mut index = Self::default();
+        let mut parser = H264Parser::default();
+        let mut cursor = Cursor::new(stream);
+        // Offset of the first NAL unit of the current access unit, if we have seen any.
+        let mut au_offset = None;
+        // Frame number and parity of the last picture, if it was a first field.
+        let mut last_first_field = None;
+
+        while let Ok(nalu) = H264Nalu::next(&mut cursor) {
+            match nalu.header.type_ {
+                H264NaluType::Sps => {
+                    if let Err(e) = parser.parse_sps(&nalu) {
+                        debug!("Failed to parse SPS: {:#}", e);
+                    }
+                }
+                H264NaluType::Pps => {
+                    if let Err(e) = parser.parse_pps(&nalu) {
+                        debug!("Failed to parse PPS: {:#}", e);
+                    }
+                }
+                _ => (),
+            }
+
+            match nalu.header.type_ {
+                // These start a new access unit when they follow a picture. See 7.4.1.2.3.
+                H264NaluType::Sei
+                | H264NaluType::Sps
+                | H264NaluType::Pps
+                | H264NaluType::AuDelimiter
+                | H264NaluType::PrefixUnit
+                | H264NaluType::SubsetSps
+                | H264NaluType::DepthSps
+                | H264NaluType::Reserved17
+                | H264NaluType::Reserved18 => {
+                    au_offset.get_or_insert(nalu.sc_offset);
+                }
+                H264NaluType::Slice | H264NaluType::SliceDpa | H264NaluType::SliceIdr => {
+                    let offset = au_offset.take().unwrap_or(nalu.sc_offset);
+                    let is_idr = nalu.header.idr_pic_flag;
+
+                    let slice = match parser.parse_slice_header(nalu) {
+                        Ok(slice) => slice,
+                        Err(e) => {
+                            debug!("Failed to parse slice header: {:#}", e);
+                            continue;
+                        }
+                    };
+                    let hdr = &slice.header;
+
+                    if hdr.first_mb_in_slice != 0 {
+                        continue;
+                    }
+
+                    // The second field of a frame does not start a new frame.
+                    let field = hdr
+                        .field_pic_flag
+                        .then_some((hdr.frame_num, hdr.bottom_field_flag));
+                    match (last_first_field.take(), field) {
+                        (Some((frame_num, bottom)), Some((cur_frame_num, cur_bottom)))
+                            if frame_num == cur_frame_num && bottom != cur_bottom =>
+                        {
+                            continue;
+                        }
+                        _ => last_first_field = field,
+                    }
+
+                    let Some(sps) = parser.get_pps(hdr.pic_parameter_set_id).map(|pps| &pps.sps)
+                    else {
+                        continue;
+                    };
+                    let resolution = sps.visible_rectangle().size();
+
+                    index.add_frame(offset, index.num_frames, resolution, is_idr);
+                }
+                _ => (),
+            }
+        }
+
+        index
+    }
+
+    /// Indexes the H.265 Annex B stream `stream`.
+    ///
+    /// IRAP pictures are the only random access points reported. NAL units that cannot be parsed,
+    /// e.g. slices preceding the first parameter sets, are ignored, as well as the units of layers
+    /// other than the base one.
+    pub fn from_h265(stream: &[u8]) -> Self {
+        let mut index = Self::default();
+        let mut parser = H265Parser::default();
+        let mut cursor = Cursor::new(stream);
+        // Offset of the first NAL unit of the current access unit, if we have seen any.
+        let mut au_offset = None;
+
+        while let Ok(nalu) = H265Nalu::next(&mut cursor) {
+            if nalu.header.nuh_layer_id > 0 {
+                continue;
+            }
+
+            let type_ = nalu.header.type_;
+            let res = match type_ {
+                H265NaluType::VpsNut => parser.parse_vps(&nalu).map(|_| ()),
+                H265NaluType::SpsNut => parser.parse_sps(&nalu).map(|_| ()),
+                H265NaluType::PpsNut => parser.parse_pps(&nalu).map(|_| ()),
+                _ => Ok(()),
+            };
+            if let Err(e) = res {
+                debug!("Failed to parse {:?}: {:#}", type_, e);
+            }
+
+            match type_ as u32 {
+                // These start a new access unit when they follow a picture. See 7.4.2.4.4.
+                32..=35 | 39 | 41..=44 | 48..=55 => {
+                    au_offset.get_or_insert(nalu.sc_offset);
+                }
+                // VCL NAL units.
+                0..=31 => {
+                    let offset = au_offset.take().unwrap_or(nalu.sc_offset);
+
+                    let slice = match parser.parse_slice_header(nalu) {
+                        Ok(slice) => slice,
+                        Err(e) => {
+                            debug!("Failed to parse slice header: {:#}", e);
+                            continue;
+                        }
+                    };
+
+                    if !slice.header.first_slice_segment_in_pic_flag {
+                        continue;
+                    }
+
+                    let Some(sps) = parser
+                        .get_pps(slice.header.pic_parameter_set_id)
+                        .and_then(|pps| parser.get_sps(pps.seq_parameter_set_id))
+                    else {
+                        continue;
+                    };
+                    let resolution = sps.visible_rectangle().size();
+
+                    index.add_frame(offset, index.num_frames, resolution, type_.is_irap());
+                }
+                _ => (),
+            }
+        }
+
+        index
+    }
+
+    /// Indexes the VP8 stream made of `packets`.
+    ///
+    /// Key frames are the random access points. Packets that cannot be parsed are ignored.
+    pub fn from_vp8<'a>(packets: impl IntoIterator<Item = Packet<'a>>) -> Self {
+        let mut index = Self::default();
+        let mut parser = Vp8Parser::default();
+
+        for packet in packets {
+            let frame = match parser.parse_frame(packet.data) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    debug!("Failed to parse VP8 frame: {:#}", e);
+                    continue;
+                }
+            };
+
+            let resolution = if frame.header.key_frame {
+                Resolution {
+                    width: u32::from(frame.header.width),
+                    height: u32::from(frame.header.height),
+                }
+            } else if let Some(last) = index.resolution_changes.last() {
+                last.resolution
+            } else {
+                // Inter frames cannot be decoded before the first key frame.
+                continue;
+            };
+
+            index.add_frame(
+                packet.offset,
+                packet.timestamp,
+                resolution,
+                frame.header.key_frame,
+            );
+        }
+
+        index
+    }
+
+    /// Indexes the VP9 stream made of `packets`.
+    ///
+    /// Key frames are the random access points. Packets that cannot be parsed are ignored.
+    /// Superframes count as a single frame.
+    pub fn from_vp9<'a>(packets: impl IntoIterator<Item = Packet<'a>>) -> Self {
+        let mut index = Self::default();
+        let mut parser = Vp9Parser::default();
+
+        for packet in packets {
+            let frames = match parser.parse_chunk(packet.data) {
+                Ok(frames) => frames,
+                Err(e) => {
+                    debug!("Failed to parse VP9 frame: {:#}", e);
+                    continue;
+                }
+            };
+
+            // Only the last frame of a superframe is shown, but all of them can change the
+            // resolution.
+            let Some(last_frame) = frames.last() else {
+                continue;
+            };
+            let hdr = &last_frame.header;
+            let resolution = if hdr.show_existing_frame {
+                match index.resolution_changes.last() {
+                    Some(last) => last.resolution,
+                    None => continue,
+                }
+            } else {
+                Resolution {
+                    width: hdr.width,
+                    height: hdr.height,
+                }
+            };
+            let is_key_frame = frames
+                .first()
+                .is_some_and(|frame| frame.header.frame_type == Vp9FrameType::KeyFrame);
+
+            index.add_frame(packet.offset, packet.timestamp, resolution, is_key_frame);
+        }
+
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexEntry;
+    use super::StreamIndex;
+    use crate::utils::IvfIterator;
+    use crate::Resolution;
+
+    #[test]
+    fn index_h264() {
+        let index = StreamIndex::from_h264(include_bytes!("h264/test_data/test-25fps.h264"));
+
+        assert_eq!(index.num_frames, 250);
+        assert_eq!(
+            index.random_access_points.first(),
+            Some(&IndexEntry {
+                offset: 0,
+                timestamp: 0,
+                resolution: Resolution {
+                    width: 320,
+                    height: 240
+                },
+            })
+        );
+        assert_eq!(index.resolution_changes.len(), 1);
+        assert_eq!(
+            index.seek_point(index.num_frames - 1),
+            index.random_access_points.last()
+        );
+    }
+
+    #[test]
+    fn index_h265() {
+        let index = StreamIndex::from_h265(include_bytes!("h265/test_data/test-25fps.h265"));
+
+        assert_eq!(index.num_frames, 250);
+        assert_eq!(index.random_access_points[0].offset, 0);
+        assert_eq!(index.resolution_changes.len(), 1);
+    }
+
+    #[test]
+    fn index_vp9_resolution_changes() {
+        let stream = include_bytes!("vp9/test_data/resolution_change_500frames-vp9.ivf");
+        let index = StreamIndex::from_vp9(IvfIterator::new(stream).packets());
+
+        assert_eq!(index.num_frames, 500);
+        assert!(index.resolution_changes.len() > 1);
+        // Resolution changes happen on key frames in this stream.
+        for change in &index.resolution_changes {
+            assert!(index.random_access_points.contains(change));
+        }
+    }
+}
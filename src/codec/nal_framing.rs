@@ -0,0 +1,696 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Conversion of H.264 and H.265 streams between the two ways of framing NAL units.
+//!
+//! Elementary streams use the Annex B byte stream format, where each NAL unit is preceded by a
+//! start code. The MP4 family of containers instead prefixes each NAL unit with its length, and
+//! moves the parameter sets out of band into a decoder configuration record (`avcC` for H.264,
+//! `hvcC` for H.265, see ISO/IEC 14496-15). The decoders of this crate expect Annex B, so
+//! length-prefixed input must be converted and its parameter sets injected before it can be
+//! decoded. See also [`crate::decoder::stateless::StatelessDecoder::set_input_framing`].
+
+use std::io::Cursor;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use byteorder::BigEndian;
+use byteorder::ByteOrder;
+
+use crate::codec::h264::parser::Nalu as H264Nalu;
+use crate::codec::h264::parser::Parser as H264Parser;
+use crate::codec::h265::parser::Nalu as H265Nalu;
+use crate::codec::h265::parser::Parser as H265Parser;
+
+/// Start code inserted before each NAL unit when converting to Annex B.
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+const H264_NALU_TYPE_SPS: u8 = 7;
+const H264_NALU_TYPE_PPS: u8 = 8;
+const H264_NALU_TYPE_SPS_EXT: u8 = 13;
+
+const H265_NALU_TYPE_VPS: u8 = 32;
+const H265_NALU_TYPE_SPS: u8 = 33;
+const H265_NALU_TYPE_PPS: u8 = 34;
+
+/// How NAL units are delimited in a bitstream.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NalFraming {
+    /// Each NAL unit is preceded by a start code, as in elementary streams.
+    #[default]
+    AnnexB,
+    /// Each NAL unit is preceded by its length as a big-endian integer of the given size in bytes,
+    /// i.e. 1, 2 or 4, as in MP4 files.
+    LengthPrefixed(usize),
+}
+
+fn check_length_size(length_size: usize) -> anyhow::Result<()> {
+    match length_size {
+        1 | 2 | 4 => Ok(()),
+        _ => Err(anyhow!("invalid NAL unit length size {}", length_size)),
+    }
+}
+
+/// Returns the NAL units of the Annex B stream `data`, without their start codes. Zero bytes
+/// preceding a start code are not considered part of the previous NAL unit.
+pub fn annexb_nalus(data: &[u8]) -> Vec<&[u8]> {
+    let start_codes = data
+        .windows(3)
+        .enumerate()
+        .filter(|(_, window)| *window == [0, 0, 1])
+        .map(|(pos, _)| pos)
+        .collect::<Vec<_>>();
+
+    start_codes
+        .iter()
+        .enumerate()
+        .map(|(i, &start_code)| {
+            let start = start_code + 3;
+            let mut end = start_codes.get(i + 1).copied().unwrap_or(data.len());
+            while end > start && data[end - 1] == 0 {
+                end -= 1;
+            }
+
+            &data[start..end]
+        })
+        .collect()
+}
+
+/// Returns the NAL units of the length-prefixed stream `data`, along with the offset at which
+/// each of them ends in `data`. Parsing stops at the first truncated NAL unit, so the end of the
+/// last returned NAL unit is the length of the complete part of `data`.
+pub fn length_prefixed_nalus(
+    data: &[u8],
+    length_size: usize,
+) -> anyhow::Result<Vec<(&[u8], usize)>> {
+    check_length_size(length_size)?;
+
+    let mut nalus = vec![];
+    let mut pos = 0;
+
+    while data.len() - pos >= length_size {
+        let len = BigEndian::read_uint(&data[pos..], length_size) as usize;
+        let start = pos + length_size;
+        if data.len() - start < len {
+            break;
+        }
+
+        pos = start + len;
+        nalus.push((&data[start..pos], pos));
+    }
+
+    Ok(nalus)
+}
+
+/// Converts the Annex B stream `data` into a stream of NAL units prefixed by their length on
+/// `length_size` bytes.
+pub fn annexb_to_length_prefixed(data: &[u8], length_size: usize) -> anyhow::Result<Vec<u8>> {
+    check_length_size(length_size)?;
+
+    let mut out = Vec::with_capacity(data.len());
+    for nalu in annexb_nalus(data) {
+        if length_size < 4 && nalu.len() >> (8 * length_size) != 0 {
+            return Err(anyhow!(
+                "NAL unit of {} bytes does not fit a {}-byte length",
+                nalu.len(),
+                length_size
+            ));
+        }
+
+        let mut len = [0u8; 4];
+        BigEndian::write_uint(&mut len, nalu.len() as u64, length_size);
+        out.extend_from_slice(&len[..length_size]);
+        out.extend_from_slice(nalu);
+    }
+
+    Ok(out)
+}
+
+/// Converts the stream `data` of NAL units prefixed by their length on `length_size` bytes into
+/// an Annex B stream. A truncated last NAL unit is an error.
+pub fn length_prefixed_to_annexb(data: &[u8], length_size: usize) -> anyhow::Result<Vec<u8>> {
+    let nalus = length_prefixed_nalus(data, length_size)?;
+
+    let complete_len = nalus.last().map(|(_, end)| *end).unwrap_or(0);
+    if complete_len != data.len() {
+        return Err(anyhow!(
+            "truncated NAL unit at offset {} of {}",
+            complete_len,
+            data.len()
+        ));
+    }
+
+    Ok(to_annexb(nalus.into_iter().map(|(nalu, _)| nalu)))
+}
+
+/// Returns the Annex B stream made of `nalus`.
+fn to_annexb<'a, I: IntoIterator<Item = &'a [u8]>>(nalus: I) -> Vec<u8> {
+    let mut out = vec![];
+    for nalu in nalus {
+        out.extend_from_slice(&START_CODE);
+        out.extend_from_slice(nalu);
+    }
+
+    out
+}
+
+/// Returns the first `len` bytes of the RBSP of `nalu`, i.e. without emulation prevention bytes.
+fn rbsp_prefix(nalu: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut rbsp = Vec::with_capacity(len);
+    let mut num_zeros = 0;
+
+    for &byte in nalu {
+        if rbsp.len() == len {
+            break;
+        }
+
+        if num_zeros == 2 && byte == 0x03 {
+            num_zeros = 0;
+            continue;
+        }
+
+        rbsp.push(byte);
+        num_zeros = if byte == 0 { num_zeros + 1 } else { 0 };
+    }
+
+    if rbsp.len() < len {
+        return Err(anyhow!("NAL unit is too short"));
+    }
+
+    Ok(rbsp)
+}
+
+/// Reads the parameter sets preceded by their 16-bit length out of a configuration record.
+fn read_parameter_sets(
+    data: &[u8],
+    pos: &mut usize,
+    num_parameter_sets: usize,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut parameter_sets = Vec::with_capacity(num_parameter_sets);
+
+    for _ in 0..num_parameter_sets {
+        let len = data
+            .get(*pos..*pos + 2)
+            .map(BigEndian::read_u16)
+            .context("truncated parameter set length")? as usize;
+        let parameter_set = data
+            .get(*pos + 2..*pos + 2 + len)
+            .context("truncated parameter set")?;
+
+        parameter_sets.push(parameter_set.to_vec());
+        *pos += 2 + len;
+    }
+
+    Ok(parameter_sets)
+}
+
+/// Writes `parameter_sets` preceded by their 16-bit length into a configuration record.
+fn write_parameter_sets(out: &mut Vec<u8>, parameter_sets: &[Vec<u8>]) -> anyhow::Result<()> {
+    for parameter_set in parameter_sets {
+        let len = u16::try_from(parameter_set.len()).map_err(|_| {
+            anyhow!(
+                "parameter set of {} bytes is too large",
+                parameter_set.len()
+            )
+        })?;
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(parameter_set);
+    }
+
+    Ok(())
+}
+
+/// Fields of an [`AvcDecoderConfigurationRecord`] only present for the high profiles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcHighProfileFields {
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    /// Sequence parameter set extension NAL units.
+    pub sps_ext: Vec<Vec<u8>>,
+}
+
+/// H.264 decoder configuration record, i.e. the content of an `avcC` box.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub profile_indication: u8,
+    /// The byte of the SPS containing the `constraint_setN_flag`s.
+    pub profile_compatibility: u8,
+    pub level_indication: u8,
+    /// Size of the NAL unit lengths of the stream, in bytes.
+    pub length_size: usize,
+    /// SPS NAL units.
+    pub sps: Vec<Vec<u8>>,
+    /// PPS NAL units.
+    pub pps: Vec<Vec<u8>>,
+    /// Only present for the high profiles, and optional even then as many muxers omit it.
+    pub high_profile_fields: Option<AvcHighProfileFields>,
+}
+
+impl AvcDecoderConfigurationRecord {
+    /// Returns whether `profile_idc` is one of the profiles for which the record has
+    /// [`AvcHighProfileFields`].
+    fn is_high_profile(profile_idc: u8) -> bool {
+        matches!(profile_idc, 100 | 110 | 122 | 144)
+    }
+
+    /// Parses the content of an `avcC` box.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < 6 {
+            return Err(anyhow!("avcC record is too short ({} bytes)", data.len()));
+        }
+        if data[0] != 1 {
+            return Err(anyhow!("unsupported avcC version {}", data[0]));
+        }
+
+        let length_size = usize::from(data[4] & 0x3) + 1;
+        check_length_size(length_size)?;
+
+        let mut pos = 6;
+        let sps = read_parameter_sets(data, &mut pos, usize::from(data[5] & 0x1f))?;
+        let num_pps = *data.get(pos).context("missing PPS count")?;
+        pos += 1;
+        let pps = read_parameter_sets(data, &mut pos, usize::from(num_pps))?;
+
+        let high_profile_fields = if Self::is_high_profile(data[1]) && data.len() >= pos + 4 {
+            let num_sps_ext = usize::from(data[pos + 3]);
+            pos += 4;
+
+            Some(AvcHighProfileFields {
+                chroma_format_idc: data[pos - 4] & 0x3,
+                bit_depth_luma_minus8: data[pos - 3] & 0x7,
+                bit_depth_chroma_minus8: data[pos - 2] & 0x7,
+                sps_ext: read_parameter_sets(data, &mut pos, num_sps_ext)?,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            profile_indication: data[1],
+            profile_compatibility: data[2],
+            level_indication: data[3],
+            length_size,
+            sps,
+            pps,
+            high_profile_fields,
+        })
+    }
+
+    /// Returns the content of the `avcC` box for this record.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        check_length_size(self.length_size)?;
+        if self.sps.len() > 0x1f || self.pps.len() > 0xff {
+            return Err(anyhow!(
+                "too many parameter sets ({} SPS, {} PPS)",
+                self.sps.len(),
+                self.pps.len()
+            ));
+        }
+
+        let mut out = vec![
+            1,
+            self.profile_indication,
+            self.profile_compatibility,
+            self.level_indication,
+            0xfc | (self.length_size as u8 - 1),
+            0xe0 | self.sps.len() as u8,
+        ];
+        write_parameter_sets(&mut out, &self.sps)?;
+        out.push(self.pps.len() as u8);
+        write_parameter_sets(&mut out, &self.pps)?;
+
+        if let Some(fields) = &self.high_profile_fields {
+            let num_sps_ext = u8::try_from(fields.sps_ext.len())
+                .map_err(|_| anyhow!("too many SPS extensions ({})", fields.sps_ext.len()))?;
+            out.extend_from_slice(&[
+                0xfc | fields.chroma_format_idc,
+                0xf8 | fields.bit_depth_luma_minus8,
+                0xf8 | fields.bit_depth_chroma_minus8,
+                num_sps_ext,
+            ]);
+            write_parameter_sets(&mut out, &fields.sps_ext)?;
+        }
+
+        Ok(out)
+    }
+
+    /// Builds a record from the parameter sets found in the Annex B stream `data`, for a
+    /// length-prefixed stream using `length_size` bytes lengths.
+    pub fn from_annexb(data: &[u8], length_size: usize) -> anyhow::Result<Self> {
+        check_length_size(length_size)?;
+
+        let nalus = annexb_nalus(data);
+        let of_type = |type_: u8| {
+            nalus
+                .iter()
+                .filter(move |nalu| nalu.first().map(|header| header & 0x1f) == Some(type_))
+                .map(|nalu| nalu.to_vec())
+                .collect::<Vec<_>>()
+        };
+
+        let sps = of_type(H264_NALU_TYPE_SPS);
+        let pps = of_type(H264_NALU_TYPE_PPS);
+        let first_sps = sps.first().context("no SPS found in the stream")?;
+        let profile = rbsp_prefix(first_sps, 4)?;
+
+        let high_profile_fields = if Self::is_high_profile(profile[1]) {
+            let annexb = to_annexb([first_sps.as_slice()]);
+            let nalu = H264Nalu::next(&mut Cursor::new(annexb.as_slice()))?;
+            let mut parser = H264Parser::default();
+            let parsed = parser.parse_sps(&nalu)?;
+
+            Some(AvcHighProfileFields {
+                chroma_format_idc: parsed.chroma_format_idc,
+                bit_depth_luma_minus8: parsed.bit_depth_luma_minus8,
+                bit_depth_chroma_minus8: parsed.bit_depth_chroma_minus8,
+                sps_ext: of_type(H264_NALU_TYPE_SPS_EXT),
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            profile_indication: profile[1],
+            profile_compatibility: profile[2],
+            level_indication: profile[3],
+            length_size,
+            sps,
+            pps,
+            high_profile_fields,
+        })
+    }
+
+    /// Returns the parameter sets of this record as an Annex B stream, to be submitted to the
+    /// decoder before the first frame.
+    pub fn to_annexb(&self) -> Vec<u8> {
+        let sps_ext = self
+            .high_profile_fields
+            .iter()
+            .flat_map(|fields| fields.sps_ext.iter());
+
+        to_annexb(
+            self.sps
+                .iter()
+                .chain(sps_ext)
+                .chain(self.pps.iter())
+                .map(Vec::as_slice),
+        )
+    }
+}
+
+/// NAL units of the same type in an [`HevcDecoderConfigurationRecord`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcNaluArray {
+    /// Whether all the NAL units of this type are in the array, rather than also in the stream.
+    pub array_completeness: bool,
+    pub nal_unit_type: u8,
+    pub nalus: Vec<Vec<u8>>,
+}
+
+/// H.265 decoder configuration record, i.e. the content of an `hvcC` box.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcDecoderConfigurationRecord {
+    /// The general part of the `profile_tier_level()` of the SPS, from `general_profile_space` to
+    /// `general_level_idc`, as it is coded.
+    pub general_profile_tier_level: [u8; 12],
+    pub min_spatial_segmentation_idc: u16,
+    pub parallelism_type: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    /// Average frame rate in frames per 256 seconds, or 0 if unspecified.
+    pub avg_frame_rate: u16,
+    pub constant_frame_rate: u8,
+    pub num_temporal_layers: u8,
+    pub temporal_id_nested: bool,
+    /// Size of the NAL unit lengths of the stream, in bytes.
+    pub length_size: usize,
+    pub arrays: Vec<HevcNaluArray>,
+}
+
+impl HevcDecoderConfigurationRecord {
+    /// Size of the record up to and including `numOfArrays`.
+    const HEADER_SIZE: usize = 23;
+
+    /// Parses the content of an `hvcC` box.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < Self::HEADER_SIZE {
+            return Err(anyhow!("hvcC record is too short ({} bytes)", data.len()));
+        }
+        if data[0] != 1 {
+            return Err(anyhow!("unsupported hvcC version {}", data[0]));
+        }
+
+        let mut general_profile_tier_level = [0u8; 12];
+        general_profile_tier_level.copy_from_slice(&data[1..13]);
+
+        let length_size = usize::from(data[21] & 0x3) + 1;
+        check_length_size(length_size)?;
+
+        let mut pos = Self::HEADER_SIZE;
+        let mut arrays = vec![];
+        for _ in 0..data[22] {
+            let header = data.get(pos..pos + 3).context("truncated hvcC array")?;
+            let num_nalus = usize::from(BigEndian::read_u16(&header[1..3]));
+            pos += 3;
+
+            arrays.push(HevcNaluArray {
+                array_completeness: header[0] & 0x80 != 0,
+                nal_unit_type: header[0] & 0x3f,
+                nalus: read_parameter_sets(data, &mut pos, num_nalus)?,
+            });
+        }
+
+        Ok(Self {
+            general_profile_tier_level,
+            min_spatial_segmentation_idc: BigEndian::read_u16(&data[13..15]) & 0x0fff,
+            parallelism_type: data[15] & 0x3,
+            chroma_format_idc: data[16] & 0x3,
+            bit_depth_luma_minus8: data[17] & 0x7,
+            bit_depth_chroma_minus8: data[18] & 0x7,
+            avg_frame_rate: BigEndian::read_u16(&data[19..21]),
+            constant_frame_rate: data[21] >> 6,
+            num_temporal_layers: (data[21] >> 3) & 0x7,
+            temporal_id_nested: data[21] & 0x4 != 0,
+            length_size,
+            arrays,
+        })
+    }
+
+    /// Returns the content of the `hvcC` box for this record.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        check_length_size(self.length_size)?;
+        let num_arrays = u8::try_from(self.arrays.len())
+            .map_err(|_| anyhow!("too many NAL unit arrays ({})", self.arrays.len()))?;
+
+        let mut out = vec![1];
+        out.extend_from_slice(&self.general_profile_tier_level);
+        out.extend_from_slice(&(0xf000 | self.min_spatial_segmentation_idc).to_be_bytes());
+        out.extend_from_slice(&[
+            0xfc | self.parallelism_type,
+            0xfc | self.chroma_format_idc,
+            0xf8 | self.bit_depth_luma_minus8,
+            0xf8 | self.bit_depth_chroma_minus8,
+        ]);
+        out.extend_from_slice(&self.avg_frame_rate.to_be_bytes());
+        out.push(
+            (self.constant_frame_rate << 6)
+                | (self.num_temporal_layers << 3)
+                | (u8::from(self.temporal_id_nested) << 2)
+                | (self.length_size as u8 - 1),
+        );
+        out.push(num_arrays);
+
+        for array in &self.arrays {
+            let num_nalus = u16::try_from(array.nalus.len())
+                .map_err(|_| anyhow!("too many NAL units ({})", array.nalus.len()))?;
+            out.push((u8::from(array.array_completeness) << 7) | array.nal_unit_type);
+            out.extend_from_slice(&num_nalus.to_be_bytes());
+            write_parameter_sets(&mut out, &array.nalus)?;
+        }
+
+        Ok(out)
+    }
+
+    /// Builds a record from the parameter sets found in the Annex B stream `data`, for a
+    /// length-prefixed stream using `length_size` bytes lengths. The arrays are marked complete,
+    /// and the fields that cannot be derived from the parameter sets are left unspecified.
+    pub fn from_annexb(data: &[u8], length_size: usize) -> anyhow::Result<Self> {
+        check_length_size(length_size)?;
+
+        let nalus = annexb_nalus(data);
+        let arrays = [H265_NALU_TYPE_VPS, H265_NALU_TYPE_SPS, H265_NALU_TYPE_PPS]
+            .into_iter()
+            .map(|nal_unit_type| HevcNaluArray {
+                array_completeness: true,
+                nal_unit_type,
+                nalus: nalus
+                    .iter()
+                    .filter(|nalu| {
+                        nalu.first().map(|header| (header >> 1) & 0x3f) == Some(nal_unit_type)
+                    })
+                    .map(|nalu| nalu.to_vec())
+                    .collect(),
+            })
+            .filter(|array| !array.nalus.is_empty())
+            .collect::<Vec<_>>();
+
+        let first_sps = arrays
+            .iter()
+            .find(|array| array.nal_unit_type == H265_NALU_TYPE_SPS)
+            .and_then(|array| array.nalus.first())
+            .context("no SPS found in the stream")?;
+
+        // The general profile_tier_level() is byte-aligned, right after the 2 bytes of the NAL
+        // unit header and the byte with sps_video_parameter_set_id, sps_max_sub_layers_minus1 and
+        // sps_temporal_id_nesting_flag.
+        let rbsp = rbsp_prefix(first_sps, 15)?;
+        let mut general_profile_tier_level = [0u8; 12];
+        general_profile_tier_level.copy_from_slice(&rbsp[3..15]);
+
+        let annexb = to_annexb([first_sps.as_slice()]);
+        let nalu = H265Nalu::next(&mut Cursor::new(annexb.as_slice()))?;
+        let mut parser = H265Parser::default();
+        let sps = parser.parse_sps(&nalu)?;
+
+        Ok(Self {
+            general_profile_tier_level,
+            min_spatial_segmentation_idc: 0,
+            parallelism_type: 0,
+            chroma_format_idc: sps.chroma_format_idc,
+            bit_depth_luma_minus8: sps.bit_depth_luma_minus8,
+            bit_depth_chroma_minus8: sps.bit_depth_chroma_minus8,
+            avg_frame_rate: 0,
+            constant_frame_rate: 0,
+            num_temporal_layers: sps.max_sub_layers_minus1 + 1,
+            temporal_id_nested: sps.temporal_id_nesting_flag,
+            length_size,
+            arrays,
+        })
+    }
+
+    /// Returns the parameter sets of this record as an Annex B stream, to be submitted to the
+    /// decoder before the first frame.
+    pub fn to_annexb(&self) -> Vec<u8> {
+        to_annexb(
+            self.arrays
+                .iter()
+                .flat_map(|array| array.nalus.iter())
+                .map(Vec::as_slice),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::annexb_nalus;
+    use super::annexb_to_length_prefixed;
+    use super::length_prefixed_nalus;
+    use super::length_prefixed_to_annexb;
+    use super::AvcDecoderConfigurationRecord;
+    use super::HevcDecoderConfigurationRecord;
+
+    const H264_STREAM: &[u8] = include_bytes!("h264/test_data/64x64-I-P-B-P-high.h264");
+    const H265_STREAM: &[u8] = include_bytes!("h265/test_data/64x64-I-P-B-P.h265");
+
+    #[test]
+    fn framing_roundtrip() {
+        let annexb = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0, 0, 0, 0, 1, 0x65, 0x88,
+        ];
+        assert_eq!(
+            annexb_nalus(&annexb),
+            [&[0x67, 0x42][..], &[0x68], &[0x65, 0x88]]
+        );
+
+        let length_prefixed = annexb_to_length_prefixed(&annexb, 2).unwrap();
+        assert_eq!(
+            length_prefixed,
+            [0, 2, 0x67, 0x42, 0, 1, 0x68, 0, 2, 0x65, 0x88]
+        );
+        assert_eq!(
+            length_prefixed_to_annexb(&length_prefixed, 2).unwrap(),
+            [0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0, 0, 0, 1, 0x65, 0x88]
+        );
+
+        // A truncated last NAL unit is not returned.
+        let nalus = length_prefixed_nalus(&length_prefixed[..10], 2).unwrap();
+        assert_eq!(nalus, [(&[0x67, 0x42][..], 4), (&[0x68][..], 7)]);
+        assert!(length_prefixed_to_annexb(&length_prefixed[..10], 2).is_err());
+
+        assert!(annexb_to_length_prefixed(&annexb, 3).is_err());
+        assert!(annexb_to_length_prefixed(&[0, 0, 1, 0x65, 0x88], 4).is_ok());
+
+        for stream in [H264_STREAM, H265_STREAM] {
+            let length_prefixed = annexb_to_length_prefixed(stream, 4).unwrap();
+            let annexb = length_prefixed_to_annexb(&length_prefixed, 4).unwrap();
+            assert_eq!(annexb_nalus(&annexb), annexb_nalus(stream));
+        }
+    }
+
+    #[test]
+    fn avcc() {
+        let record = AvcDecoderConfigurationRecord::from_annexb(H264_STREAM, 4).unwrap();
+        assert_eq!(record.profile_indication, 100);
+        assert_eq!(record.length_size, 4);
+        assert_eq!((record.sps.len(), record.pps.len()), (1, 1));
+        let fields = record.high_profile_fields.as_ref().unwrap();
+        assert_eq!(fields.chroma_format_idc, 1);
+        assert_eq!(fields.bit_depth_luma_minus8, 0);
+
+        let bytes = record.to_bytes().unwrap();
+        assert_eq!(
+            &bytes[..6],
+            [
+                1,
+                100,
+                record.profile_compatibility,
+                record.level_indication,
+                0xff,
+                0xe1
+            ]
+        );
+        assert_eq!(
+            AvcDecoderConfigurationRecord::parse(&bytes).unwrap(),
+            record
+        );
+
+        // The injected parameter sets are the ones of the stream, after its access unit
+        // delimiter.
+        let nalus = annexb_nalus(H264_STREAM);
+        assert_eq!(annexb_nalus(&record.to_annexb()), nalus[1..3]);
+
+        assert!(AvcDecoderConfigurationRecord::parse(&bytes[..8]).is_err());
+        assert!(AvcDecoderConfigurationRecord::from_annexb(&[0, 0, 1, 0x65, 0x88], 4).is_err());
+    }
+
+    #[test]
+    fn hvcc() {
+        let record = HevcDecoderConfigurationRecord::from_annexb(H265_STREAM, 4).unwrap();
+        // Main profile.
+        assert_eq!(record.general_profile_tier_level[0] & 0x1f, 1);
+        assert_eq!(record.chroma_format_idc, 1);
+        assert_eq!(
+            record
+                .arrays
+                .iter()
+                .map(|array| (array.nal_unit_type, array.nalus.len()))
+                .collect::<Vec<_>>(),
+            [(32, 1), (33, 1), (34, 1)]
+        );
+
+        let bytes = record.to_bytes().unwrap();
+        assert_eq!(bytes[21] & 0x3, 3);
+        assert_eq!(
+            HevcDecoderConfigurationRecord::parse(&bytes).unwrap(),
+            record
+        );
+
+        let nalus = annexb_nalus(H265_STREAM);
+        assert_eq!(annexb_nalus(&record.to_annexb()), nalus[..3]);
+
+        assert!(HevcDecoderConfigurationRecord::parse(&bytes[..30]).is_err());
+    }
+}
@@ -0,0 +1,539 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reassembly of AV1 and VP9 temporal units from RTP packets.
+//!
+//! Real-time receivers (e.g. WebRTC) get their frames split over several RTP packets. The
+//! depacketizers of this module collect the payloads of these packets and return complete
+//! temporal units, in a format that can be submitted to a decoder as-is:
+//!
+//! * AV1 temporal units are made of low-overhead OBUs, starting with a temporal delimiter.
+//! * VP9 temporal units are single frames, or superframes if the stream has several spatial
+//!   layers.
+//!
+//! Packets must be pushed in sequence number order, i.e. after going through a jitter buffer. A
+//! temporal unit missing any of its packets cannot be decoded and is dropped.
+
+use std::borrow::Cow;
+
+use anyhow::anyhow;
+use log::warn;
+
+use crate::codec::av1::parser::ObuType;
+use crate::codec::vp9::parser::MAX_FRAMES_IN_SUPERFRAME;
+use crate::codec::vp9::parser::SUPERFRAME_MARKER;
+
+/// An RTP packet, as received from the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpPacket<'a> {
+    /// Sequence number of the packet, used to detect losses.
+    pub sequence_number: u16,
+    /// Timestamp of the packet, shared by all the packets of a temporal unit.
+    pub timestamp: u32,
+    /// Marker bit, set on the last packet of a temporal unit.
+    pub marker: bool,
+    /// Payload of the packet, i.e. what follows the RTP header and its extensions.
+    pub payload: &'a [u8],
+}
+
+/// A complete temporal unit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemporalUnit {
+    /// RTP timestamp of the temporal unit.
+    pub timestamp: u32,
+    /// Encoded data of the temporal unit.
+    pub data: Vec<u8>,
+}
+
+/// Tracking of the packets of the temporal unit being assembled, common to all codecs.
+#[derive(Debug, Default)]
+struct Assembly {
+    /// Timestamp of the temporal unit being assembled, if any.
+    timestamp: Option<u32>,
+    /// Sequence number expected for the next packet.
+    next_sequence_number: Option<u16>,
+    /// Whether packets of the current temporal unit have been lost or were invalid.
+    broken: bool,
+    /// Number of temporal units dropped so far.
+    num_dropped: u64,
+}
+
+impl Assembly {
+    /// Accounts for `packet` and returns whether it starts a new temporal unit.
+    fn start_packet(&mut self, packet: &RtpPacket) -> bool {
+        let new_unit = self.timestamp != Some(packet.timestamp);
+        if new_unit {
+            if let Some(timestamp) = self.timestamp {
+                warn!(
+                    "Dropping temporal unit {} without its last packet",
+                    timestamp
+                );
+                self.num_dropped += 1;
+            }
+
+            self.timestamp = Some(packet.timestamp);
+            self.broken = false;
+        }
+
+        // We cannot tell which temporal unit lost packets belonged to, so assume the worst.
+        if self
+            .next_sequence_number
+            .is_some_and(|expected| expected != packet.sequence_number)
+        {
+            self.broken = true;
+        }
+        self.next_sequence_number = Some(packet.sequence_number.wrapping_add(1));
+
+        new_unit
+    }
+
+    /// Ends the current temporal unit and returns its timestamp if it can be decoded.
+    fn finish(&mut self) -> Option<u32> {
+        let timestamp = self.timestamp.take()?;
+
+        if self.broken {
+            warn!("Dropping incomplete temporal unit {}", timestamp);
+            self.num_dropped += 1;
+            None
+        } else {
+            Some(timestamp)
+        }
+    }
+}
+
+/// Reads a leb128 value from the start of `data`, returning it along with its size in bytes.
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0u64;
+
+    for (i, byte) in data.iter().take(8).enumerate() {
+        value |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((usize::try_from(value).ok()?, i + 1));
+        }
+    }
+
+    None
+}
+
+/// Appends `value` to `out` as a leb128.
+fn write_leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+/// Assembles AV1 temporal units from RTP payloads, as per the "RTP Payload Format For AV1"
+/// specification.
+#[derive(Debug, Default)]
+pub struct Av1Depacketizer {
+    assembly: Assembly,
+    /// OBUs of the current temporal unit, in low-overhead format.
+    data: Vec<u8>,
+    /// Start of an OBU to be continued by the next packet.
+    fragment: Option<Vec<u8>>,
+}
+
+impl Av1Depacketizer {
+    /// Temporal delimiter OBU, which senders drop and receivers must restore.
+    const TEMPORAL_DELIMITER: [u8; 2] = [(ObuType::TemporalDelimiter as u8) << 3 | 0x2, 0];
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of temporal units dropped so far because they were incomplete.
+    pub fn num_dropped(&self) -> u64 {
+        self.assembly.num_dropped
+    }
+
+    /// Adds `packet` to the current temporal unit, and returns the latter if `packet` completes it.
+    pub fn push(&mut self, packet: RtpPacket) -> Option<TemporalUnit> {
+        if self.assembly.start_packet(&packet) {
+            self.data.clear();
+            self.data.extend_from_slice(&Self::TEMPORAL_DELIMITER);
+            self.fragment = None;
+        }
+
+        if let Err(e) = self.parse_payload(packet.payload) {
+            warn!("Invalid AV1 RTP payload: {:#}", e);
+            self.assembly.broken = true;
+            self.fragment = None;
+        }
+
+        if !packet.marker {
+            return None;
+        }
+
+        let timestamp = self.assembly.finish()?;
+        Some(TemporalUnit {
+            timestamp,
+            data: std::mem::take(&mut self.data),
+        })
+    }
+
+    fn parse_payload(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let (&aggregation_header, mut elements) =
+            payload.split_first().ok_or(anyhow!("Empty payload"))?;
+
+        let continues_fragment = aggregation_header & 0x80 != 0; // Z
+        let ends_with_fragment = aggregation_header & 0x40 != 0; // Y
+        let num_elements = usize::from((aggregation_header >> 4) & 0x3); // W
+
+        if !continues_fragment && self.fragment.take().is_some() {
+            return Err(anyhow!("Missing end of fragmented OBU"));
+        }
+
+        let mut index = 0;
+        while !elements.is_empty() {
+            index += 1;
+
+            // Unless their number is given, all elements are prefixed by their size. Otherwise
+            // the last one takes the rest of the payload.
+            let element = if num_elements == 0 || index < num_elements {
+                let (size, size_len) =
+                    read_leb128(elements).ok_or(anyhow!("Invalid OBU element size"))?;
+                let rest = &elements[size_len..];
+                if size > rest.len() {
+                    return Err(anyhow!("OBU element larger than the payload"));
+                }
+
+                let (element, rest) = rest.split_at(size);
+                elements = rest;
+                element
+            } else {
+                std::mem::take(&mut elements)
+            };
+
+            let obu = if index == 1 && continues_fragment {
+                let mut fragment = self
+                    .fragment
+                    .take()
+                    .ok_or(anyhow!("Missing start of fragmented OBU"))?;
+                fragment.extend_from_slice(element);
+                Cow::Owned(fragment)
+            } else {
+                Cow::Borrowed(element)
+            };
+
+            if elements.is_empty() && ends_with_fragment {
+                self.fragment = Some(obu.into_owned());
+            } else {
+                self.append_obu(&obu)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends a complete OBU to the temporal unit, adding its size field if needed.
+    fn append_obu(&mut self, obu: &[u8]) -> anyhow::Result<()> {
+        let header = *obu.first().ok_or(anyhow!("Empty OBU"))?;
+        let obu_type = (header >> 3) & 0xf;
+        let extension_flag = header & 0x4 != 0;
+        let has_size_field = header & 0x2 != 0;
+
+        // Temporal delimiters are restored at the start of the temporal unit, and tile lists are
+        // not allowed in RTP streams.
+        if obu_type == ObuType::TemporalDelimiter as u8 || obu_type == ObuType::TileList as u8 {
+            return Ok(());
+        }
+
+        if has_size_field {
+            self.data.extend_from_slice(obu);
+            return Ok(());
+        }
+
+        let header_len = 1 + usize::from(extension_flag);
+        if obu.len() < header_len {
+            return Err(anyhow!("Truncated OBU header"));
+        }
+
+        self.data.push(header | 0x2);
+        self.data.extend_from_slice(&obu[1..header_len]);
+        write_leb128(&mut self.data, obu.len() - header_len);
+        self.data.extend_from_slice(&obu[header_len..]);
+
+        Ok(())
+    }
+}
+
+/// Assembles VP9 temporal units from RTP payloads, as per the "RTP Payload Format for VP9 Video"
+/// specification.
+#[derive(Debug, Default)]
+pub struct Vp9Depacketizer {
+    assembly: Assembly,
+    /// Frames of the current temporal unit, one per spatial layer.
+    frames: Vec<Vec<u8>>,
+    /// Whether the last frame of `frames` expects more packets.
+    in_frame: bool,
+}
+
+impl Vp9Depacketizer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of temporal units dropped so far because they were incomplete.
+    pub fn num_dropped(&self) -> u64 {
+        self.assembly.num_dropped
+    }
+
+    /// Adds `packet` to the current temporal unit, and returns the latter if `packet` completes it.
+    pub fn push(&mut self, packet: RtpPacket) -> Option<TemporalUnit> {
+        if self.assembly.start_packet(&packet) {
+            self.frames.clear();
+            self.in_frame = false;
+        }
+
+        if let Err(e) = self.parse_payload(packet.payload) {
+            warn!("Invalid VP9 RTP payload: {:#}", e);
+            self.assembly.broken = true;
+        }
+
+        if !packet.marker {
+            return None;
+        }
+
+        if self.in_frame {
+            self.assembly.broken = true;
+        }
+
+        let frames = std::mem::take(&mut self.frames);
+        let data = match Self::assemble(frames) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Cannot assemble VP9 temporal unit: {:#}", e);
+                self.assembly.broken = true;
+                vec![]
+            }
+        };
+
+        let timestamp = self.assembly.finish()?;
+        Some(TemporalUnit { timestamp, data })
+    }
+
+    fn parse_payload(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let header_len = Self::parse_payload_descriptor(payload)?;
+        let flags = payload[0];
+        let start_of_frame = flags & 0x08 != 0; // B
+        let end_of_frame = flags & 0x04 != 0; // E
+
+        if start_of_frame {
+            if self.in_frame {
+                return Err(anyhow!("Missing end of frame"));
+            }
+            self.frames.push(Vec::new());
+            self.in_frame = true;
+        } else if !self.in_frame {
+            return Err(anyhow!("Missing start of frame"));
+        }
+
+        if let Some(frame) = self.frames.last_mut() {
+            frame.extend_from_slice(&payload[header_len..]);
+        }
+        self.in_frame = !end_of_frame;
+
+        Ok(())
+    }
+
+    /// Returns the size of the payload descriptor at the start of `payload`.
+    fn parse_payload_descriptor(payload: &[u8]) -> anyhow::Result<usize> {
+        let mut bytes = payload.iter().copied();
+        let mut next = || bytes.next().ok_or(anyhow!("Truncated payload descriptor"));
+
+        let flags = next()?;
+        let picture_id_present = flags & 0x80 != 0; // I
+        let inter_picture_predicted = flags & 0x40 != 0; // P
+        let layer_indices_present = flags & 0x20 != 0; // L
+        let flexible_mode = flags & 0x10 != 0; // F
+        let scalability_structure_present = flags & 0x02 != 0; // V
+
+        // The picture ID is 15 bits long if M is set, 7 bits otherwise.
+        if picture_id_present && next()? & 0x80 != 0 {
+            next()?;
+        }
+
+        // TL0PICIDX is only present in non-flexible mode.
+        if layer_indices_present {
+            next()?;
+            if !flexible_mode {
+                next()?;
+            }
+        }
+
+        // Up to 3 reference indices, each followed by another one if N is set.
+        if flexible_mode && inter_picture_predicted {
+            for _ in 0..3 {
+                if next()? & 0x1 == 0 {
+                    break;
+                }
+            }
+        }
+
+        if scalability_structure_present {
+            let ss = next()?;
+            let num_spatial_layers = usize::from(ss >> 5) + 1;
+
+            // Width and height of each spatial layer.
+            if ss & 0x10 != 0 {
+                for _ in 0..num_spatial_layers * 4 {
+                    next()?;
+                }
+            }
+
+            // Picture group description.
+            if ss & 0x08 != 0 {
+                for _ in 0..next()? {
+                    let num_refs = (next()? >> 2) & 0x3;
+                    for _ in 0..num_refs {
+                        next()?;
+                    }
+                }
+            }
+        }
+
+        Ok(payload.len() - bytes.len())
+    }
+
+    /// Turns the frames of a temporal unit into a single chunk of data, i.e. a superframe if there
+    /// is more than one of them. See Annex B of the VP9 specification.
+    fn assemble(mut frames: Vec<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+        if frames.len() <= 1 {
+            return Ok(frames.pop().unwrap_or_default());
+        }
+
+        if frames.len() > MAX_FRAMES_IN_SUPERFRAME {
+            return Err(anyhow!(
+                "Too many frames in temporal unit: {}",
+                frames.len()
+            ));
+        }
+
+        let max_size = frames.iter().map(Vec::len).max().unwrap_or_default();
+        let bytes_per_framesize = match max_size {
+            0..=0xff => 1,
+            0x100..=0xffff => 2,
+            0x10000..=0xffffff => 3,
+            _ => 4,
+        };
+
+        let marker = (SUPERFRAME_MARKER as u8) << 5
+            | (bytes_per_framesize as u8 - 1) << 3
+            | (frames.len() as u8 - 1);
+
+        let mut data = frames.concat();
+        data.push(marker);
+        for frame in &frames {
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes()[..bytes_per_framesize]);
+        }
+        data.push(marker);
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Av1Depacketizer;
+    use super::RtpPacket;
+    use super::Vp9Depacketizer;
+    use crate::codec::vp9::parser::Parser as Vp9Parser;
+    use crate::utils::IvfIterator;
+
+    fn packet(sequence_number: u16, marker: bool, payload: &[u8]) -> RtpPacket<'_> {
+        RtpPacket {
+            sequence_number,
+            timestamp: 3000,
+            marker,
+            payload,
+        }
+    }
+
+    #[test]
+    fn av1_fragmented_obu() {
+        let mut depacketizer = Av1Depacketizer::new();
+
+        // A sequence header followed by the start of a frame OBU, without size fields.
+        assert_eq!(
+            depacketizer.push(packet(
+                1,
+                false,
+                &[0x60, 0x02, 0x08, 0xaa, 0x30, 0x01, 0x02]
+            )),
+            None
+        );
+        // The end of the frame OBU.
+        let temporal_unit = depacketizer
+            .push(packet(2, true, &[0x90, 0x03, 0x04]))
+            .unwrap();
+
+        assert_eq!(temporal_unit.timestamp, 3000);
+        assert_eq!(
+            temporal_unit.data,
+            [0x12, 0x00, 0x0a, 0x01, 0xaa, 0x32, 0x04, 0x01, 0x02, 0x03, 0x04]
+        );
+        assert_eq!(depacketizer.num_dropped(), 0);
+    }
+
+    #[test]
+    fn av1_lost_packet() {
+        let mut depacketizer = Av1Depacketizer::new();
+
+        assert_eq!(
+            depacketizer.push(packet(1, false, &[0x50, 0x30, 0x01])),
+            None
+        );
+        assert_eq!(depacketizer.push(packet(3, true, &[0x90, 0x02])), None);
+        assert_eq!(depacketizer.num_dropped(), 1);
+
+        // The next temporal unit is not affected.
+        let next = RtpPacket {
+            timestamp: 6000,
+            ..packet(4, true, &[0x10, 0x30, 0x01])
+        };
+        assert!(depacketizer.push(next).is_some());
+    }
+
+    #[test]
+    fn vp9_spatial_layers() {
+        let stream = include_bytes!("vp9/test_data/test-25fps.vp9");
+        let frames = IvfIterator::new(stream).take(2).collect::<Vec<_>>();
+        let mut depacketizer = Vp9Depacketizer::new();
+
+        // Send each frame as a spatial layer over two packets, with the picture ID and layer
+        // indices present.
+        let mut sequence_number = 0;
+        let mut temporal_unit = None;
+        for (layer, frame) in frames.iter().enumerate() {
+            let (first, second) = frame.split_at(frame.len() / 2);
+            for (start, data) in [(true, first), (false, second)] {
+                let end = !start;
+                let flags = 0xa0 | if start { 0x08 } else { 0 } | if end { 0x04 } else { 0 };
+                let mut payload = vec![flags, 0x12, (layer as u8) << 1, 0x00];
+                payload.extend_from_slice(data);
+
+                sequence_number += 1;
+                let marker = end && layer == frames.len() - 1;
+                temporal_unit = depacketizer.push(packet(sequence_number, marker, &payload));
+            }
+        }
+
+        let temporal_unit = temporal_unit.unwrap();
+        let parsed = Vp9Parser::default()
+            .parse_chunk(&temporal_unit.data)
+            .unwrap();
+        assert_eq!(parsed.len(), frames.len());
+        for (parsed, frame) in parsed.iter().zip(&frames) {
+            assert_eq!(parsed.as_ref(), *frame);
+        }
+    }
+}
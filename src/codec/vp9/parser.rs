@@ -245,24 +245,13 @@ impl Segmentation {
             let chroma_dc_quant_scale = i16::try_from(hdr.get_dc_quant(segment_id, false)?)?;
             let chroma_ac_quant_scale = i16::try_from(hdr.get_ac_quant(segment_id, false)?)?;
 
-            let mut lvl_seg = i32::from(lf.level);
             let mut lvl_lookup: [[u8; MAX_MODE_LF_DELTAS]; MAX_REF_FRAMES];
 
-            if lvl_seg == 0 {
+            if lf.level == 0 {
                 lvl_lookup = Default::default()
             } else {
                 // 8.8.1 Loop filter frame init process
-                if hdr.seg_feature_active(segment_id, SEG_LVL_ALT_L as u8) {
-                    if seg.abs_or_delta_update {
-                        lvl_seg =
-                            i32::from(seg.feature_data[usize::from(segment_id)][SEG_LVL_ALT_L]);
-                    } else {
-                        lvl_seg +=
-                            i32::from(seg.feature_data[usize::from(segment_id)][SEG_LVL_ALT_L]);
-                    }
-
-                    lvl_seg = clamp(lvl_seg, 0, MAX_LOOP_FILTER as i32);
-                }
+                let lvl_seg = i32::from(hdr.get_lvl_seg(segment_id));
 
                 if !lf.delta_enabled {
                     lvl_lookup = [[u8::try_from(lvl_seg)?; MAX_MODE_LF_DELTAS]; MAX_REF_FRAMES]
@@ -412,7 +401,8 @@ pub struct Header {
     /// stored for reference by future frames. If unset, indicates that the
     /// probabilities should be discarded at the end of the frame.
     pub refresh_frame_context: bool,
-    /// Whether parallel decoding mode is enabled.
+    /// Whether parallel decoding mode is enabled. If set, the probabilities are not adapted to the
+    /// symbols decoded in this frame, so the frame context it saves only depends on its headers.
     pub frame_parallel_decoding_mode: bool,
     /// Indicates the frame context to use.
     pub frame_context_idx: u8,
@@ -440,6 +430,20 @@ pub struct Header {
 }
 
 impl Header {
+    /// Returns whether the probabilities are adapted to the symbols decoded in this frame, as per
+    /// the refresh_probs() process of section 6.1.2.
+    pub fn adapts_probabilities(&self) -> bool {
+        !self.error_resilient_mode && !self.frame_parallel_decoding_mode
+    }
+
+    /// Returns whether the frame context saved by this frame depends on the result of decoding it.
+    ///
+    /// When this is false, the next frames can be submitted before this one has completed, even
+    /// if they load the same frame context.
+    pub fn saves_adapted_frame_context(&self) -> bool {
+        self.refresh_frame_context && self.adapts_probabilities()
+    }
+
     /// An implementation of seg_feature_active as per "6.4.9 Segmentation feature active syntax"
     pub fn seg_feature_active(&self, segment_id: u8, feature: u8) -> bool {
         let feature_enabled = self.seg.feature_enabled;
@@ -463,6 +467,24 @@ impl Header {
         }
     }
 
+    /// Returns the loop filter level of a segment before the reference frame and mode deltas are
+    /// applied, i.e. lvlSeg as per "8.8.1 General".
+    pub fn get_lvl_seg(&self, segment_id: u8) -> u8 {
+        let level = i32::from(self.lf.level);
+
+        if self.seg_feature_active(segment_id, SEG_LVL_ALT_L as u8) {
+            let mut data = i32::from(self.seg.feature_data[usize::from(segment_id)][SEG_LVL_ALT_L]);
+
+            if !self.seg.abs_or_delta_update {
+                data += level;
+            }
+
+            clamp(data, 0, MAX_LOOP_FILTER as i32) as u8
+        } else {
+            level as u8
+        }
+    }
+
     /// An implementation of get_dc_quant as per "8.6.1 Dequantization functions"
     pub fn get_dc_quant(&self, segment_id: u8, luma: bool) -> anyhow::Result<i32> {
         let delta_q_dc = if luma {
@@ -1130,10 +1152,12 @@ mod tests {
     use crate::codec::vp9::parser::BitDepth;
     use crate::codec::vp9::parser::ColorSpace;
     use crate::codec::vp9::parser::FrameType;
+    use crate::codec::vp9::parser::Header;
     use crate::codec::vp9::parser::InterpolationFilter;
     use crate::codec::vp9::parser::Parser;
     use crate::codec::vp9::parser::Profile;
     use crate::codec::vp9::parser::MAX_SEGMENTS;
+    use crate::codec::vp9::parser::SEG_LVL_ALT_L;
     use crate::codec::vp9::parser::SEG_LVL_MAX;
     use crate::utils::IvfIterator;
 
@@ -1214,6 +1238,8 @@ mod tests {
                 assert!(h.refresh_frame_context);
                 assert!(h.frame_parallel_decoding_mode);
                 assert_eq!(h.frame_context_idx, 0);
+                assert!(!h.adapts_probabilities());
+                assert!(!h.saves_adapted_frame_context());
 
                 let lf = &h.lf;
                 assert_eq!(lf.level, 9);
@@ -1427,4 +1453,24 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_lvl_seg() {
+        let mut hdr = Header::default();
+        hdr.lf.level = 32;
+        hdr.seg.enabled = true;
+        hdr.seg.feature_enabled[1][SEG_LVL_ALT_L] = true;
+        hdr.seg.feature_data[1][SEG_LVL_ALT_L] = 40;
+        hdr.seg.feature_enabled[2][SEG_LVL_ALT_L] = true;
+        hdr.seg.feature_data[2][SEG_LVL_ALT_L] = -40;
+
+        assert_eq!(hdr.get_lvl_seg(0), 32);
+        assert_eq!(hdr.get_lvl_seg(1), 63);
+        assert_eq!(hdr.get_lvl_seg(2), 0);
+
+        hdr.seg.abs_or_delta_update = true;
+        assert_eq!(hdr.get_lvl_seg(0), 32);
+        assert_eq!(hdr.get_lvl_seg(1), 40);
+        assert_eq!(hdr.get_lvl_seg(2), 0);
+    }
 }
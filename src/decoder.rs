@@ -9,14 +9,53 @@
 //!
 //! At the moment, only a [stateless] decoder interface is provided.
 
+pub mod analyzer;
+#[cfg(feature = "tokio")]
+pub mod async_worker;
+pub mod completion;
+pub mod fallback;
+pub mod frame_channel;
+pub mod frame_rate;
+pub mod manager;
+pub mod readback;
 pub mod stateful;
 pub mod stateless;
+pub mod worker;
 
 use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::Duration;
+use std::time::Instant;
 
+use thiserror::Error;
+
+use crate::decoder::stateless::StatelessBackendError;
 use crate::DecodedFormat;
+use crate::FrameLayout;
 use crate::Resolution;
 
+/// Interval at which [`DecodedHandle::wait_ready`] polls a handle for completion by default.
+const WAIT_READY_POLL_INTERVAL: Duration = Duration::from_micros(500);
+
+/// Polls `is_ready` until it returns `true` or `timeout` has elapsed, and returns whether it
+/// returned `true`. This is how [`DecodedHandle::wait_ready`] waits by default.
+pub(crate) fn poll_ready(is_ready: impl Fn() -> bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if is_ready() {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+
+        std::thread::sleep(std::cmp::min(WAIT_READY_POLL_INTERVAL, deadline - now));
+    }
+}
+
 /// Trait for a pool of frames in a particular format.
 ///
 /// This is mostly useful for the decoder where the user is expected to manage how the decoded
@@ -63,12 +102,308 @@ pub struct StreamInfo {
     pub coded_resolution: Resolution,
     /// Display resolution of the stream, i.e. the part of the decoded frames we want to display.
     pub display_resolution: Resolution,
+    /// Position of the top-left corner of the displayed part within the decoded frames.
+    pub display_offset: (u32, u32),
     /// Minimum number of output frames required for decoding to proceed.
     ///
     /// Codecs keep some frames as references and cannot decode immediately into them again after
     /// they are returned. Allocating at least this number of frames guarantees that the decoder
     /// won't starve from output frames.
     pub min_num_frames: usize,
+    /// How `min_num_frames` has been derived from the stream.
+    pub num_frames_breakdown: NumFramesBreakdown,
+}
+
+/// Where the size of the decoded picture buffer of a stream comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DpbSizeOrigin {
+    /// The stream explicitly signals its DPB size, e.g. through H.264's `max_dec_frame_buffering`
+    /// or H.265's `sps_max_dec_pic_buffering_minus1`.
+    Stream,
+    /// The DPB size is the maximum allowed by the level of the stream for its resolution.
+    Level,
+    /// The DPB size is fixed by the codec, e.g. the number of reference slots of VP8 or VP9.
+    Codec,
+}
+
+/// Breakdown of the minimum number of frames required to decode a stream.
+///
+/// This lets integrators with tight memory budgets know where each frame of the allocation comes
+/// from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumFramesBreakdown {
+    /// Number of frames that can be held by the decoded picture buffer.
+    pub dpb_size: usize,
+    /// Where `dpb_size` has been derived from.
+    pub dpb_size_origin: DpbSizeOrigin,
+    /// Additional frames on top of the DPB, for the frame being decoded and those waiting to be
+    /// returned to the client.
+    pub extra: usize,
+}
+
+impl NumFramesBreakdown {
+    /// Returns the total number of frames required by this breakdown.
+    pub fn total(&self) -> usize {
+        self.dpb_size + self.extra
+    }
+}
+
+/// Colorimetry of a stream, using the code points defined in ISO/IEC 23091-4 (or ITU-T H.273).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colorimetry {
+    /// Chromaticity coordinates of the source primaries.
+    pub color_primaries: u8,
+    /// Opto-electronic transfer characteristic of the source.
+    pub transfer_characteristics: u8,
+    /// Matrix coefficients used to derive luma and chroma from the primaries.
+    pub matrix_coefficients: u8,
+    /// Whether the samples use the full range of values instead of the video range.
+    pub full_range: bool,
+}
+
+impl Default for Colorimetry {
+    /// Returns the colorimetry of a stream that does not signal it, i.e. unspecified.
+    fn default() -> Self {
+        Self {
+            color_primaries: 2,
+            transfer_characteristics: 2,
+            matrix_coefficients: 2,
+            full_range: false,
+        }
+    }
+}
+
+/// Location of the chroma samples relative to the luma samples of a frame.
+///
+/// The values follow the `chroma_sample_loc_type` semantics of H.264 and H.265, as described in
+/// figure E-1 of the H.264 specification.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSiting {
+    /// The stream does not specify the chroma siting.
+    #[default]
+    Unspecified,
+    /// Chroma samples are vertically centered between, and horizontally aligned with, the
+    /// leftmost luma samples (MPEG-2 style).
+    Left,
+    /// Chroma samples are centered between the luma samples (MPEG-1 style).
+    Center,
+    /// Chroma samples are co-sited with the top-left luma sample.
+    TopLeft,
+    /// Chroma samples are horizontally centered on the top luma samples.
+    Top,
+    /// Chroma samples are co-sited with the bottom-left luma sample.
+    BottomLeft,
+    /// Chroma samples are horizontally centered on the bottom luma samples.
+    Bottom,
+}
+
+impl ChromaSiting {
+    /// Converts a `chroma_sample_loc_type` value from the H.264 or H.265 VUI parameters.
+    pub fn from_chroma_sample_loc_type(chroma_sample_loc_type: u32) -> Self {
+        match chroma_sample_loc_type {
+            0 => ChromaSiting::Left,
+            1 => ChromaSiting::Center,
+            2 => ChromaSiting::TopLeft,
+            3 => ChromaSiting::Top,
+            4 => ChromaSiting::BottomLeft,
+            5 => ChromaSiting::Bottom,
+            _ => ChromaSiting::Unspecified,
+        }
+    }
+}
+
+/// How the lines of a frame have been scanned.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FieldOrder {
+    /// The frame is progressive.
+    #[default]
+    Progressive,
+    /// The frame is made of two interlaced fields, the top one coming first in time.
+    TopFieldFirst,
+    /// The frame is made of two interlaced fields, the bottom one coming first in time.
+    BottomFieldFirst,
+    /// The stream does not say whether the frame is progressive or interlaced, or in which order
+    /// its fields come.
+    Unknown,
+}
+
+/// How the two views of a stereoscopic frame are packed into a decoded frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramePackingType {
+    /// Samples of the two views alternate in a checkerboard pattern.
+    Checkerboard,
+    /// Columns of the two views alternate.
+    ColumnInterleaved,
+    /// Rows of the two views alternate.
+    RowInterleaved,
+    /// The first view is on the left half of the frame, the second one on the right half.
+    SideBySide,
+    /// The first view is on the top half of the frame, the second one on the bottom half.
+    TopBottom,
+    /// Frames alternate between the two views.
+    FrameAlternation,
+}
+
+/// Stereoscopic frame packing arrangement of a decoded frame, as signaled by the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramePacking {
+    /// How the views are packed.
+    pub packing_type: FramePackingType,
+    /// Whether the first view (i.e. left, top, or first in time) is the left one, or `None` if
+    /// the stream does not say.
+    pub left_view_first: Option<bool>,
+    /// Whether the views are quincunx sampled.
+    pub quincunx_sampling: bool,
+    /// For [`FramePackingType::FrameAlternation`], whether the frame contains the first view.
+    pub is_first_view: bool,
+}
+
+/// Parameters of a segment of a decoded frame.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentParams {
+    /// Quantizer index used by the blocks of the segment.
+    pub qindex: u8,
+    /// Loop filter level of the segment, before the reference frame and mode deltas are applied.
+    pub loop_filter_level: u8,
+    /// Reference frame used by all the blocks of the segment, if the segment forces one.
+    pub reference_frame: Option<u8>,
+    /// Whether the blocks of the segment are coded without residual.
+    pub skip: bool,
+}
+
+/// Segmentation parameters of a decoded frame, as signaled by the stream.
+///
+/// The per-block segment ids are not included, as the hardware decoders keep the segmentation
+/// map to themselves.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SegmentationInfo {
+    /// Whether the frame updated the segmentation map, as opposed to reusing the one of a previous
+    /// frame.
+    pub map_updated: bool,
+    /// Parameters of each segment, indexed by segment id.
+    pub segments: Vec<SegmentParams>,
+}
+
+/// Adjustments of the loop filter level of a decoded frame, as signaled by the stream.
+///
+/// The loop filter level of each block is adjusted by the delta of the reference frame it is
+/// predicted from, and then by the delta of its prediction mode.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoopFilterDeltas {
+    /// Deltas indexed by reference frame: intra, last, golden and alt-ref.
+    pub ref_frame: [i8; 4],
+    /// Deltas indexed by prediction mode, in the order of the codec. For VP8 these are `B_PRED`,
+    /// `ZEROMV`, the other whole-macroblock motion vector modes, and `SPLITMV`.
+    pub mode: Vec<i8>,
+}
+
+/// Codec-specific identifier of a decoded picture.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PictureId {
+    /// The decoder did not provide an identifier for this picture.
+    #[default]
+    Unknown,
+    /// Picture order count of the picture, for H.264 and H.265.
+    PicOrderCnt(i32),
+    /// Index of the frame in display order since the start of the stream, for VP8, VP9 and AV1.
+    FrameIndex(u64),
+}
+
+/// Unit of the timestamps passed to a decoder, expressed as a rational number of seconds.
+///
+/// Timestamps are opaque to decoders, which only pass them from the input to the decoded frames.
+/// Setting a time base on a decoder allows clients to convert them from and to other time bases
+/// without having to carry that information separately. For instance, timestamps coming from an
+/// MPEG-TS demuxer can be submitted as-is with [`TimeBase::MPEG_TS`], while MP4 demuxers will use
+/// the timescale of the track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeBase {
+    num: u32,
+    den: u32,
+}
+
+impl TimeBase {
+    /// Time base of MPEG-TS presentation and decode timestamps, i.e. 90 kHz.
+    pub const MPEG_TS: TimeBase = TimeBase {
+        num: 1,
+        den: 90_000,
+    };
+    /// Time base of timestamps expressed in microseconds.
+    pub const MICROSECONDS: TimeBase = TimeBase {
+        num: 1,
+        den: 1_000_000,
+    };
+    /// Time base of timestamps expressed in nanoseconds.
+    pub const NANOSECONDS: TimeBase = TimeBase {
+        num: 1,
+        den: 1_000_000_000,
+    };
+
+    /// Creates a time base in which one unit is `num / den` seconds. Returns `None` if either
+    /// value is zero.
+    pub fn new(num: u32, den: u32) -> Option<Self> {
+        if num == 0 || den == 0 {
+            None
+        } else {
+            Some(Self { num, den })
+        }
+    }
+
+    pub fn num(&self) -> u32 {
+        self.num
+    }
+
+    pub fn den(&self) -> u32 {
+        self.den
+    }
+
+    /// Converts `timestamp`, expressed in this time base, into time base `to`, rounding to the
+    /// nearest unit and saturating at `u64::MAX`.
+    pub fn rescale(&self, timestamp: u64, to: TimeBase) -> u64 {
+        // Cannot overflow: the product of a `u64` and two `u32`s fits in 128 bits.
+        let num = timestamp as u128 * self.num as u128 * to.den as u128;
+        let den = self.den as u128 * to.num as u128;
+        let rescaled = num / den + u128::from(2 * (num % den) >= den);
+
+        u64::try_from(rescaled).unwrap_or(u64::MAX)
+    }
+
+    /// Converts `timestamp`, expressed in this time base, into a [`Duration`].
+    pub fn to_duration(&self, timestamp: u64) -> Duration {
+        Duration::from_nanos(self.rescale(timestamp, Self::NANOSECONDS))
+    }
+
+    /// Converts `duration` into a timestamp expressed in this time base.
+    pub fn from_duration(&self, duration: Duration) -> u64 {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        Self::NANOSECONDS.rescale(nanos, *self)
+    }
+}
+
+/// Properties of a decoded frame, as parsed from the bitstream by the decoder.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// Location of the chroma samples relative to the luma samples.
+    pub chroma_siting: ChromaSiting,
+    /// Whether the frame is progressive or interlaced, and in the latter case the order of its
+    /// fields.
+    pub field_order: FieldOrder,
+    /// Identifier of the picture within the stream.
+    pub picture_id: PictureId,
+    /// Temporal layer the picture belongs to, for codecs and streams supporting temporal
+    /// scalability.
+    pub temporal_id: Option<u32>,
+    /// Whether the decoder knows the frame to be corrupted, e.g. because some of its reference
+    /// frames were missing from the stream. See [`DecodedHandle::is_corrupted`] for a check that
+    /// also takes errors reported by the backend into account.
+    pub corrupted: bool,
+    /// Stereoscopic frame packing arrangement of the frame, for codecs and streams signaling one.
+    pub frame_packing: Option<FramePacking>,
+    /// Segmentation parameters of the frame, for codecs and streams using segmentation.
+    pub segmentation: Option<SegmentationInfo>,
+    /// Reference frame and prediction mode adjustments of the loop filter level, for VP8 streams
+    /// enabling them.
+    pub loop_filter_deltas: Option<LoopFilterDeltas>,
 }
 
 /// Trait for objects allowing to negotiate the output format of a decoder.
@@ -84,7 +419,21 @@ pub trait DecoderFormatNegotiator<'a, M> {
     fn stream_info(&self) -> &StreamInfo;
     /// Returns the frame pool in use for the decoder, set up for the new format.
     fn frame_pool(&mut self) -> &mut dyn FramePool<M>;
-    fn try_format(&mut self, format: DecodedFormat) -> anyhow::Result<()>;
+    fn try_format(&mut self, format: DecodedFormat) -> Result<(), FormatError>;
+}
+
+/// Error returned by [`DecoderFormatNegotiator::try_format`].
+///
+/// More variants may be added in the future, so this enum is not exhaustive.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FormatError {
+    #[error("format {0:?} is not supported for this stream")]
+    UnsupportedFormat(DecodedFormat),
+    #[error("the format can only be changed while a format change is pending")]
+    NotNegotiating,
+    #[error("backend error: {0}")]
+    BackendError(#[from] StatelessBackendError),
 }
 
 /// Events that can be retrieved using the `next_event` method of a decoder.
@@ -93,12 +442,128 @@ pub enum DecoderEvent<'a, M> {
     FrameReady(Box<dyn DecodedHandle<Descriptor = M>>),
     /// The format of the stream has changed and action is required.
     FormatChanged(Box<dyn DecoderFormatNegotiator<'a, M> + 'a>),
+    /// Decoding has been stalled for the given duration waiting for free output buffers, and has
+    /// now resumed. A client receiving this event often may want to add more frames to the pool.
+    OutputStalled(Duration),
+    /// The display resolution of the stream has changed to the given one. Frames emitted after
+    /// this event have the new resolution.
+    ResolutionChanged(Resolution),
+    /// The colorimetry of the stream has changed. Frames emitted after this event use the new
+    /// colorimetry.
+    ColorimetryChanged(Colorimetry),
+    /// The frame with the given timestamp will not be output, e.g. because it could not be
+    /// decoded properly. This event may be reported before frames that precede the dropped one in
+    /// display order.
+    FrameDropped(u64),
+    /// An error occurred while decoding the frame with the given timestamp, but decoding could
+    /// continue. The frame may be corrupted or dropped as a result.
+    NonFatalError(u64, anyhow::Error),
+    /// The device used for decoding has been lost, e.g. because the GPU has been reset after a
+    /// hang, and its resources have been recreated. The frames that had not been output yet are
+    /// lost. Decoding resumes at the next key frame, after the format has been negotiated again
+    /// through a [`DecoderEvent::FormatChanged`] event.
+    DeviceReset,
+    /// Part of the input has been ignored by the decoder. Only reported if requested, e.g. with
+    /// [`stateless::StatelessDecoder::set_report_skipped_data`].
+    DataSkipped(SkippedData),
+    /// The decoder has switched to another backend because the previous one cannot decode the
+    /// stream. Only reported by [`fallback::FallbackDecoder`].
+    BackendSwitched(fallback::BackendSwitch),
+}
+
+/// Why a decoder ignored part of its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// A unit of a type that the decoder does not support, e.g. an H.264 auxiliary picture or a
+    /// reserved NAL unit.
+    UnsupportedUnit,
+    /// A unit belonging to a layer that is not decoded, e.g. an H.265 enhancement layer or an AV1
+    /// OBU outside of the selected operating point.
+    OtherLayer,
+    /// An SEI message that could not be parsed. SEI messages are not needed for decoding.
+    InvalidSei,
+    /// Filler data, which carries no information.
+    FillerData,
+    /// Data that could not be parsed as units of the stream, e.g. trailing garbage after the OBUs
+    /// of an AV1 temporal unit.
+    Unparsable,
+}
+
+/// Part of the input ignored by a decoder, see [`DecoderEvent::DataSkipped`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedData {
+    /// Timestamp of the input the data belongs to.
+    pub timestamp: u64,
+    /// Position of the data within the input buffer. For length-prefixed H.264 and H.265 input,
+    /// it refers to the Annex B conversion of the buffer, where each length is replaced by a
+    /// 4-byte start code.
+    pub range: Range<usize>,
+    /// Why the data has been ignored.
+    pub reason: SkipReason,
 }
 
 pub trait DynHandle {
     /// Gets an CPU mapping to the memory backing the handle.
     /// Assumes that this picture is backed by a handle and panics if not the case.
     fn dyn_mappable_handle<'a>(&'a self) -> anyhow::Result<Box<dyn MappableHandle + 'a>>;
+
+    /// Maps the memory backing the handle into the client's address space.
+    ///
+    /// The memory stays mapped for as long as the returned [`MappedFrame`] is alive.
+    fn map(&self) -> anyhow::Result<MappedFrame<'_>> {
+        MappedFrame::new(self.dyn_mappable_handle()?)
+    }
+}
+
+/// A CPU mapping of a decoded frame, obtained through [`DynHandle::map`].
+///
+/// The frame is mapped for as long as this guard is alive, and is unmapped when it is dropped.
+pub struct MappedFrame<'a> {
+    mapping: Box<dyn MappableHandle + 'a>,
+    /// Size in bytes of the whole frame, as returned by [`MappedFrame::read`].
+    size: usize,
+}
+
+impl<'a> MappedFrame<'a> {
+    /// Wraps `mapping` into a guard, failing if its size cannot be determined.
+    pub fn new(mut mapping: Box<dyn MappableHandle + 'a>) -> anyhow::Result<Self> {
+        let size = mapping.image_size()?;
+
+        Ok(Self { mapping, size })
+    }
+
+    /// Returns the size of the buffer required to read the whole frame.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the layout of the frame data written by [`MappedFrame::read`].
+    pub fn layout(&mut self) -> anyhow::Result<FrameLayout> {
+        self.mapping.layout()
+    }
+
+    /// Read the whole frame into `buffer`, which must be exactly [`MappedFrame::size`] bytes.
+    pub fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<()> {
+        self.mapping.read(buffer)
+    }
+
+    /// Read plane `plane` of the frame into `buffer`, using `stride` bytes per line.
+    pub fn read_plane(
+        &mut self,
+        plane: usize,
+        buffer: &mut [u8],
+        stride: usize,
+    ) -> anyhow::Result<()> {
+        self.mapping.read_plane(plane, buffer, stride)
+    }
+
+    /// Read the whole frame into a newly allocated buffer.
+    pub fn to_vec(&mut self) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = vec![0; self.size];
+        self.read(&mut buffer)?;
+
+        Ok(buffer)
+    }
 }
 
 /// A trait for types that can be mapped into the client's address space.
@@ -108,8 +573,53 @@ pub trait MappableHandle {
     /// The size of `buffer` must be equal to `image_size()`, or an error will be returned.
     fn read(&mut self, buffer: &mut [u8]) -> anyhow::Result<()>;
 
+    /// Returns the layout of the data written by `read`, i.e. its format, size and the position
+    /// of each plane within `buffer`.
+    fn layout(&mut self) -> anyhow::Result<FrameLayout>;
+
     /// Returns the size of the `buffer` argument required to call `read` on this handle.
-    fn image_size(&mut self) -> usize;
+    fn image_size(&mut self) -> anyhow::Result<usize> {
+        Ok(self
+            .layout()?
+            .planes
+            .iter()
+            .map(|plane| plane.offset + plane.size)
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Read plane `plane` of `self` into `buffer`, using `stride` bytes per line.
+    ///
+    /// The plane has the same format as in the data returned by `read`, and its dimensions are
+    /// given by [`crate::decoded_plane_size`]. This allows clients with separate destinations
+    /// for each plane, or that only need some of the planes, to avoid copying the whole frame.
+    ///
+    /// The default implementation returns an error, for backends that cannot read planes
+    /// individually.
+    fn read_plane(
+        &mut self,
+        _plane: usize,
+        _buffer: &mut [u8],
+        _stride: usize,
+    ) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!(
+            "reading individual planes is not supported by this backend"
+        ))
+    }
+}
+
+/// A decoded frame that has been copied out of its backend memory, and is thus not tied to the
+/// decoder's frame pool anymore.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedFrame {
+    /// Timestamp of the frame.
+    pub timestamp: u64,
+    /// Resolution of the visible rectangle of the frame, which is also the resolution of `data`.
+    pub display_resolution: Resolution,
+    /// Format of `data`, as decided during format negotiation.
+    pub format: DecodedFormat,
+    /// Frame data, laid out the same way as the output of [`MappableHandle::read`].
+    pub data: Vec<u8>,
 }
 
 /// The handle type used by the decoder backend. The only requirement from implementors is that
@@ -126,19 +636,197 @@ pub trait DecodedHandle {
     /// Returns the timestamp of the picture.
     fn timestamp(&self) -> u64;
 
+    /// Returns the time base of [`DecodedHandle::timestamp`], if one has been set on the decoder.
+    fn time_base(&self) -> Option<TimeBase> {
+        None
+    }
+
+    /// Returns the timestamp of the picture converted into a [`Duration`], if its time base is
+    /// known.
+    fn presentation_time(&self) -> Option<Duration> {
+        self.time_base()
+            .map(|time_base| time_base.to_duration(self.timestamp()))
+    }
+
     /// Returns the coded resolution at the time this handle was decoded.
     fn coded_resolution(&self) -> Resolution;
 
     /// Returns the display resolution at the time this handle was decoded.
     fn display_resolution(&self) -> Resolution;
 
+    /// Returns the position of the top-left corner of the displayed part within the decoded
+    /// frame, as signaled by the cropping window of the stream.
+    ///
+    /// Mappings of the handle are already limited to the displayed part. This is useful to
+    /// clients that access the backing memory directly, e.g. after exporting it.
+    fn display_offset(&self) -> (u32, u32) {
+        (0, 0)
+    }
+
+    /// Returns the properties of the frame as parsed from the bitstream.
+    ///
+    /// Backends do not have this information, so the default implementation returns the
+    /// default properties. Handles obtained from a decoder's events have it filled.
+    fn frame_info(&self) -> FrameInfo {
+        Default::default()
+    }
+
     /// Returns `true` if this handle has been completely decoded.
     fn is_ready(&self) -> bool;
 
+    /// Returns `true` if the content of this handle is known to be corrupted, either because the
+    /// decoder had to continue past an error in the stream or because the backend reported
+    /// decoding errors. Players can use this to decide whether to display the frame.
+    ///
+    /// Backends may only be able to report errors once the handle is ready, so this should be
+    /// called after [`DecodedHandle::sync`]. The default implementation only considers
+    /// [`FrameInfo::corrupted`].
+    fn is_corrupted(&self) -> anyhow::Result<bool> {
+        Ok(self.frame_info().corrupted)
+    }
+
     /// Wait until this handle has been completely rendered.
     fn sync(&self) -> anyhow::Result<()>;
 
+    /// Wait until this handle has been completely rendered, or until `timeout` has elapsed,
+    /// whichever comes first.
+    ///
+    /// Returns `true` if the handle is ready, `false` if `timeout` elapsed before that. Real-time
+    /// clients can use this to drop late frames instead of stalling in [`DecodedHandle::sync`].
+    ///
+    /// The default implementation polls [`DecodedHandle::is_ready`] until the deadline is reached.
+    fn wait_ready(&self, timeout: Duration) -> anyhow::Result<bool> {
+        Ok(poll_ready(|| self.is_ready(), timeout))
+    }
+
+    /// Returns the estimated instant at which this handle will be completely rendered, or `None`
+    /// if the backend cannot provide an estimate.
+    ///
+    /// The returned instant may be in the past, and is only a hint: [`DecodedHandle::is_ready`]
+    /// remains the authoritative way to know whether the handle has completed.
+    fn estimated_completion(&self) -> Option<Instant> {
+        None
+    }
+
     fn resource(&self) -> std::cell::Ref<Self::Descriptor>;
+
+    /// Returns `self` as [`std::any::Any`], allowing clients to downcast it into the
+    /// backend-specific handle type.
+    ///
+    /// Backends may also provide more convenient ways to access their specifics, like the
+    /// `VaapiDecodedHandleExt` extension trait.
+    fn as_any(&self) -> &dyn std::any::Any;
+
+    /// Wait for this handle to be ready, and copy its content into a newly allocated
+    /// [`OwnedFrame`].
+    ///
+    /// This is useful for clients that need to keep frames for a long time: once the frame is
+    /// copied, the handle can be dropped so its backing memory is returned to the frame pool
+    /// instead of starving the decoder.
+    fn to_owned_frame(&self) -> anyhow::Result<OwnedFrame> {
+        self.sync()?;
+
+        let picture = self.dyn_picture();
+        let mut mapping = picture.map()?;
+        let fourcc = mapping.layout()?.format.0;
+        let format = DecodedFormat::try_from(fourcc)
+            .map_err(|e| anyhow::anyhow!("cannot own a frame of format {}: {}", fourcc, e))?;
+        let data = mapping.to_vec()?;
+
+        Ok(OwnedFrame {
+            timestamp: self.timestamp(),
+            display_resolution: self.display_resolution(),
+            format,
+            data,
+        })
+    }
+}
+
+/// A decoded handle along with the properties of its frame, as returned to the client.
+///
+/// This is the type of the handles stored in the ready queue of decoders, which can fill the
+/// frame properties that backends are not aware of.
+struct ReadyFrame<H> {
+    handle: H,
+    info: FrameInfo,
+    time_base: Option<TimeBase>,
+}
+
+impl<H> ReadyFrame<H> {
+    fn new(handle: H, info: FrameInfo) -> Self {
+        Self {
+            handle,
+            info,
+            time_base: None,
+        }
+    }
+
+    /// Sets the time base of the frame's timestamp, as the decoder's is only known when the frame
+    /// is returned to the client.
+    fn with_time_base(mut self, time_base: Option<TimeBase>) -> Self {
+        self.time_base = time_base;
+        self
+    }
+}
+
+impl<H: DecodedHandle> DecodedHandle for ReadyFrame<H> {
+    type Descriptor = H::Descriptor;
+
+    fn dyn_picture<'a>(&'a self) -> Box<dyn DynHandle + 'a> {
+        self.handle.dyn_picture()
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.handle.timestamp()
+    }
+
+    fn time_base(&self) -> Option<TimeBase> {
+        self.time_base
+    }
+
+    fn coded_resolution(&self) -> Resolution {
+        self.handle.coded_resolution()
+    }
+
+    fn display_resolution(&self) -> Resolution {
+        self.handle.display_resolution()
+    }
+
+    fn display_offset(&self) -> (u32, u32) {
+        self.handle.display_offset()
+    }
+
+    fn frame_info(&self) -> FrameInfo {
+        self.info.clone()
+    }
+
+    fn is_ready(&self) -> bool {
+        self.handle.is_ready()
+    }
+
+    fn is_corrupted(&self) -> anyhow::Result<bool> {
+        Ok(self.info.corrupted || self.handle.is_corrupted()?)
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        self.handle.sync()
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> anyhow::Result<bool> {
+        self.handle.wait_ready(timeout)
+    }
+
+    fn estimated_completion(&self) -> Option<Instant> {
+        self.handle.estimated_completion()
+    }
+
+    fn resource(&self) -> std::cell::Ref<'_, Self::Descriptor> {
+        self.handle.resource()
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self.handle.as_any()
+    }
 }
 
 /// Instructs the decoder on whether it should block on the decode operations.
@@ -157,6 +845,9 @@ impl Default for BlockingMode {
 
 /// A queue where decoding jobs wait until they are completed, at which point they can be
 /// retrieved.
+///
+/// The handles it contains are bound to the decoding thread, so it is not shared with other
+/// threads: clients forward frames to them using [`frame_channel`] instead.
 struct ReadyFramesQueue<T> {
     /// Queue of all the frames waiting to be sent to the client.
     queue: VecDeque<T>,
@@ -171,10 +862,30 @@ impl<T> Default for ReadyFramesQueue<T> {
 }
 
 impl<T> ReadyFramesQueue<T> {
+    /// Returns `true` if no frame is waiting in the queue.
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Returns the oldest frame waiting in the queue, if any.
+    fn front(&self) -> Option<&T> {
+        self.queue.front()
+    }
+
+    /// Returns an iterator over the frames waiting in the queue, oldest first.
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.queue.iter()
+    }
+
     /// Push `handle` to the back of the queue.
     fn push(&mut self, handle: T) {
         self.queue.push_back(handle)
     }
+
+    /// Drop all the frames waiting in the queue.
+    fn clear(&mut self) {
+        self.queue.clear()
+    }
 }
 
 impl<T> Extend<T> for ReadyFramesQueue<T> {
@@ -193,3 +904,42 @@ impl<'a, T> Iterator for &'a mut ReadyFramesQueue<T> {
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::TimeBase;
+
+    #[test]
+    fn test_time_base_rescale() {
+        let mp4 = TimeBase::new(1, 30_000).unwrap();
+
+        assert_eq!(TimeBase::MPEG_TS.rescale(90_000, mp4), 30_000);
+        assert_eq!(mp4.rescale(1001, TimeBase::MPEG_TS), 3003);
+        // 1 / 90000 s is 11.1 us.
+        assert_eq!(TimeBase::MPEG_TS.rescale(1, TimeBase::MICROSECONDS), 11);
+        // 2 / 90000 s is 22.2 us.
+        assert_eq!(TimeBase::MPEG_TS.rescale(2, TimeBase::MICROSECONDS), 22);
+        // 5 / 90000 s is 55.6 us.
+        assert_eq!(TimeBase::MPEG_TS.rescale(5, TimeBase::MICROSECONDS), 56);
+        assert_eq!(
+            TimeBase::NANOSECONDS.rescale(u64::MAX, TimeBase::new(1, u32::MAX).unwrap()),
+            u64::MAX
+        );
+        assert_eq!(TimeBase::new(0, 1), None);
+        assert_eq!(TimeBase::new(1, 0), None);
+    }
+
+    #[test]
+    fn test_time_base_duration() {
+        assert_eq!(
+            TimeBase::MPEG_TS.to_duration(45_000),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            TimeBase::MPEG_TS.from_duration(Duration::from_secs(2)),
+            180_000
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Stream conformance analysis.
+//!
+//! [`analyze`] runs a stateless decoder over a stream with a backend that does not decode
+//! anything, so only the parsers and reference management state machines of the crate are
+//! exercised. No hardware is needed, which makes it usable as a validator: the report lists the
+//! violations of the specification the decoder detected, the order and identity of the frames it
+//! would output, and the resources needed to decode each sequence of the stream.
+
+use crate::decoder::stateful::EncodedFormat;
+use crate::decoder::stateless::av1::Av1;
+use crate::decoder::stateless::h264::H264;
+use crate::decoder::stateless::h265::H265;
+use crate::decoder::stateless::vp8::Vp8;
+use crate::decoder::stateless::vp9::Vp9;
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::DecoderStats;
+use crate::decoder::stateless::StatelessCodec;
+use crate::decoder::stateless::StatelessDecoder;
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::BlockingMode;
+use crate::decoder::DecoderEvent;
+use crate::decoder::FrameInfo;
+use crate::decoder::SkippedData;
+use crate::decoder::StreamInfo;
+use crate::DecodedFormat;
+use crate::Resolution;
+
+/// Frame that would be output by a decoder of the analyzed stream.
+#[derive(Debug, Clone)]
+pub struct AnalyzedFrame {
+    /// Index of the input the frame was decoded from.
+    pub timestamp: u64,
+    /// Resolution of the visible part of the frame.
+    pub display_resolution: Resolution,
+    /// Information about the frame, e.g. its identifier within the stream and temporal layer.
+    pub info: FrameInfo,
+}
+
+/// Result of the analysis of a stream.
+#[derive(Default)]
+pub struct StreamReport {
+    /// Parameters of each sequence of the stream, in order, including the number of frames
+    /// required to decode it and how it has been derived.
+    pub sequences: Vec<StreamInfo>,
+    /// Frames of the stream in display order. Comparing it with the order of the timestamps
+    /// shows how frames are reordered.
+    pub frames: Vec<AnalyzedFrame>,
+    /// Timestamps of the frames that would not be output, e.g. because their references are
+    /// missing.
+    pub dropped_frames: Vec<u64>,
+    /// Errors that decoding could recover from, along with the timestamp of the input they
+    /// occurred on. This includes the violations of the specification that the decoder repairs.
+    pub violations: Vec<(u64, anyhow::Error)>,
+    /// Parts of the input that are ignored by the decoder.
+    pub skipped_data: Vec<SkippedData>,
+    /// Statistics of the decoder at the end of the stream.
+    pub stats: DecoderStats,
+    /// Error that stopped the analysis before the end of the stream, if any.
+    pub error: Option<DecodeError>,
+}
+
+/// Analyzes the stream made of `inputs`, each of which is a unit that would be passed to a
+/// decoder of `format`, e.g. an access unit for H.264 and H.265 or a frame of an IVF file for the
+/// other codecs. H.264 and H.265 input must be in Annex B format.
+pub fn analyze<'a, I>(format: EncodedFormat, inputs: I) -> StreamReport
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let blocking_mode = BlockingMode::Blocking;
+
+    match format {
+        EncodedFormat::H264 => analyze_with(
+            StatelessDecoder::<H264, _>::new_dummy(blocking_mode),
+            inputs,
+        ),
+        EncodedFormat::H265 => analyze_with(
+            StatelessDecoder::<H265, _>::new_dummy(blocking_mode),
+            inputs,
+        ),
+        EncodedFormat::VP8 => {
+            analyze_with(StatelessDecoder::<Vp8, _>::new_dummy(blocking_mode), inputs)
+        }
+        EncodedFormat::VP9 => {
+            analyze_with(StatelessDecoder::<Vp9, _>::new_dummy(blocking_mode), inputs)
+        }
+        EncodedFormat::AV1 => {
+            analyze_with(StatelessDecoder::<Av1, _>::new_dummy(blocking_mode), inputs)
+        }
+    }
+}
+
+/// Runs `decoder` over `inputs` and reports what happened.
+fn analyze_with<'a, C, B, I>(mut decoder: StatelessDecoder<C, B>, inputs: I) -> StreamReport
+where
+    C: StatelessCodec,
+    B: StatelessDecoderBackend<C>,
+    StatelessDecoder<C, B>: StatelessVideoDecoder<()>,
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut report = StreamReport::default();
+    decoder.set_report_skipped_data(true);
+
+    let res = (|| {
+        for (timestamp, input) in inputs.into_iter().enumerate() {
+            let mut bitstream = input;
+            while !bitstream.is_empty() {
+                match decoder.decode(timestamp as u64, bitstream) {
+                    Ok(consumed) => bitstream = &bitstream[consumed..],
+                    Err(DecodeError::CheckEvents | DecodeError::NotEnoughOutputBuffers(_)) => (),
+                    Err(e) => return Err(e),
+                }
+                collect_events(&mut decoder, &mut report);
+            }
+        }
+
+        decoder.flush()
+    })();
+
+    collect_events(&mut decoder, &mut report);
+    report.stats = decoder.stats().clone();
+    report.error = res.err();
+
+    report
+}
+
+/// Adds the pending events of `decoder` to `report`.
+fn collect_events<D>(decoder: &mut D, report: &mut StreamReport)
+where
+    D: StatelessVideoDecoder<()>,
+{
+    while let Some(event) = decoder.next_event() {
+        match event {
+            DecoderEvent::FrameReady(frame) => report.frames.push(AnalyzedFrame {
+                timestamp: frame.timestamp(),
+                display_resolution: frame.display_resolution(),
+                info: frame.frame_info(),
+            }),
+            DecoderEvent::FormatChanged(mut negotiator) => {
+                report.sequences.push(negotiator.stream_info().clone());
+                // The backend does not produce any frame, so any format will do.
+                let _ = negotiator.try_format(DecodedFormat::NV12);
+            }
+            DecoderEvent::FrameDropped(timestamp) => report.dropped_frames.push(timestamp),
+            DecoderEvent::NonFatalError(timestamp, e) => report.violations.push((timestamp, e)),
+            DecoderEvent::DataSkipped(data) => report.skipped_data.push(data),
+            DecoderEvent::OutputStalled(_)
+            | DecoderEvent::ResolutionChanged(_)
+            | DecoderEvent::ColorimetryChanged(_)
+            | DecoderEvent::DeviceReset
+            | DecoderEvent::BackendSwitched(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::analyze;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateful::EncodedFormat;
+    use crate::decoder::stateless::h264::tests::DECODE_64X64_PROGRESSIVE_I_P_B_P;
+    use crate::decoder::stateless::vp8::tests::DECODE_TEST_25FPS;
+    use crate::utils::IvfIterator;
+    use crate::utils::NalIterator;
+
+    #[test]
+    fn analyze_h264() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let report = analyze(EncodedFormat::H264, NalIterator::<Nalu>::new(test.stream));
+
+        assert!(report.error.is_none());
+        assert_eq!(report.sequences.len(), 1);
+        assert_eq!(report.frames.len(), test.crcs.lines().count());
+        assert!(report.violations.is_empty());
+    }
+
+    #[test]
+    fn analyze_truncated_vp8() {
+        // Cut the second frame in the middle.
+        let frames = IvfIterator::new(DECODE_TEST_25FPS.stream)
+            .take(2)
+            .enumerate()
+            .map(|(i, frame)| if i == 1 { &frame[..10] } else { frame })
+            .collect::<Vec<_>>();
+        let report = analyze(EncodedFormat::VP8, frames);
+
+        assert!(report.error.is_some());
+        assert_eq!(report.frames.len(), 1);
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Async interface to a [`DecoderWorker`], for use with the tokio runtime.
+//!
+//! Async media servers cannot block their executor waiting for frames or for room in the input
+//! queue of a decoder. [`AsyncDecoderWorker`] wraps a [`DecoderWorker`] so that both become
+//! futures: frames are awaited through a channel fed by a helper thread blocking on the worker's
+//! output, and input backpressure is applied with a [`Semaphore`] whose permits are only returned
+//! once the worker has accepted the input.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use tokio::sync::mpsc;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::worker::DecoderWorker;
+use crate::decoder::worker::WorkerCommand;
+use crate::decoder::worker::WorkerEvent;
+use crate::decoder::worker::INPUT_QUEUE_SIZE;
+use crate::decoder::StreamInfo;
+use crate::DecodedFormat;
+
+/// A [`DecoderWorker`] driven from async code.
+///
+/// Dropping it cancels the worker like dropping a [`DecoderWorker`] does, without waiting for it.
+pub struct AsyncDecoderWorker {
+    /// Only `None` once the worker is being finished.
+    input: Option<mpsc::UnboundedSender<(WorkerCommand, OwnedSemaphorePermit)>>,
+    input_permits: Arc<Semaphore>,
+    output: mpsc::UnboundedReceiver<WorkerEvent>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl AsyncDecoderWorker {
+    /// Starts a worker, see [`DecoderWorker::spawn`] for the meaning of the arguments.
+    ///
+    /// This does not block and can be called from within the runtime.
+    pub fn spawn<D, M, F, A>(
+        create_decoder: F,
+        output_format: DecodedFormat,
+        allocate_frames: A,
+    ) -> anyhow::Result<Self>
+    where
+        D: StatelessVideoDecoder<M> + 'static,
+        M: 'static,
+        F: FnOnce() -> anyhow::Result<D> + Send + 'static,
+        A: FnMut(&StreamInfo, usize) -> anyhow::Result<Vec<M>> + Send + 'static,
+    {
+        let worker = DecoderWorker::spawn(create_decoder, output_format, allocate_frames)?;
+        let worker_input = worker.input();
+        let (worker_output, cancelled) = worker.into_parts();
+
+        let (input, mut input_receiver) = mpsc::unbounded_channel();
+        let (output_sender, output) = mpsc::unbounded_channel();
+
+        // Forward the input to the worker, only releasing the permit of a command once the worker
+        // has room for it.
+        std::thread::Builder::new()
+            .name("decoder-worker-input".into())
+            .spawn(move || {
+                while let Some((command, _permit)) = input_receiver.blocking_recv() {
+                    if worker_input.send(command).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        // Wake up the async side when the worker produces events.
+        std::thread::Builder::new()
+            .name("decoder-worker-output".into())
+            .spawn(move || {
+                for event in worker_output {
+                    if output_sender.send(event).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            input: Some(input),
+            input_permits: Arc::new(Semaphore::new(INPUT_QUEUE_SIZE)),
+            output,
+            cancelled,
+        })
+    }
+
+    async fn send(&self, command: WorkerCommand) -> anyhow::Result<()> {
+        let permit = Arc::clone(&self.input_permits).acquire_owned().await?;
+
+        // Cannot fail as `input` is only taken when the worker is being finished.
+        self.input
+            .as_ref()
+            .unwrap()
+            .send((command, permit))
+            .map_err(|_| anyhow!("the decoder worker has stopped"))
+    }
+
+    /// Queues `bitstream` for decoding, waiting for room in the input queue if it is full.
+    pub async fn decode(&self, timestamp: u64, bitstream: Vec<u8>) -> anyhow::Result<()> {
+        self.send(WorkerCommand::Decode {
+            timestamp,
+            bitstream,
+        })
+        .await
+    }
+
+    /// Queues a flush, which completes with [`WorkerEvent::FlushCompleted`].
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        self.send(WorkerCommand::Flush).await
+    }
+
+    /// Waits for the next event of the worker. Returns `None` once the worker has stopped.
+    pub async fn next_event(&mut self) -> Option<WorkerEvent> {
+        self.output.recv().await
+    }
+
+    /// Decodes all the pending input, then stops the worker and returns the events that have not
+    /// been received yet.
+    pub async fn finish(mut self) -> Vec<WorkerEvent> {
+        self.input.take();
+
+        let mut events = Vec::new();
+        while let Some(event) = self.output.recv().await {
+            events.push(event);
+        }
+
+        events
+    }
+}
+
+impl Drop for AsyncDecoderWorker {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncDecoderWorker;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::worker::WorkerEvent;
+    use crate::decoder::BlockingMode;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+
+    const STREAM: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P-B-P.h264");
+    const CRCS: &str = include_str!("../codec/h264/test_data/64x64-I-P-B-P.h264.crc");
+
+    #[tokio::test]
+    async fn decode_async() {
+        let mut worker = AsyncDecoderWorker::spawn(
+            || {
+                Ok(StatelessDecoder::<H264, _>::new_dummy(
+                    BlockingMode::Blocking,
+                ))
+            },
+            DecodedFormat::NV12,
+            simple_playback_loop_owned_frames,
+        )
+        .unwrap();
+
+        // Submit more input than the queue can hold to exercise backpressure.
+        const NUM_REPEATS: usize = 3;
+        let nalus = NalIterator::<Nalu>::new(STREAM).collect::<Vec<_>>();
+        for (timestamp, nalu) in nalus
+            .iter()
+            .cycle()
+            .take(nalus.len() * NUM_REPEATS)
+            .enumerate()
+        {
+            worker
+                .decode(timestamp as u64, nalu.to_vec())
+                .await
+                .unwrap();
+        }
+        worker.flush().await.unwrap();
+
+        let mut num_frames = 0;
+        loop {
+            match worker.next_event().await.unwrap() {
+                WorkerEvent::Frame(_) => num_frames += 1,
+                WorkerEvent::FlushCompleted => break,
+                WorkerEvent::Error(e) => panic!("{}", e),
+                _ => (),
+            }
+        }
+        assert_eq!(num_frames, CRCS.lines().count() * NUM_REPEATS);
+
+        assert!(worker
+            .finish()
+            .await
+            .iter()
+            .all(|event| !matches!(event, WorkerEvent::Frame(_) | WorkerEvent::Error(_))));
+    }
+
+    #[tokio::test]
+    async fn decoder_error() {
+        let mut worker = AsyncDecoderWorker::spawn(
+            || {
+                Ok(StatelessDecoder::<H264, _>::new_dummy(
+                    BlockingMode::Blocking,
+                ))
+            },
+            DecodedFormat::NV12,
+            simple_playback_loop_owned_frames,
+        )
+        .unwrap();
+
+        // An SPS with an invalid profile.
+        worker
+            .decode(0, vec![0, 0, 0, 1, 0x67, 0xff, 0xff])
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            worker.next_event().await,
+            Some(WorkerEvent::Error(_))
+        ));
+        assert!(worker.next_event().await.is_none());
+    }
+}
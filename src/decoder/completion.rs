@@ -0,0 +1,73 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Signaling the completion of submitted pictures from a dedicated thread.
+//!
+//! Latency-critical clients want to know when a picture has been decoded by the hardware as soon
+//! as it happens, without polling its handle. When a [`CompletionCallback`] is set on a decoder,
+//! each submitted picture is handed to a dedicated thread along with a [`CompletionWaiter`]
+//! provided by the backend, which blocks until the hardware is done with it. The callback is
+//! invoked from that thread right after.
+//!
+//! The callback only signals completion: the frame itself is still obtained from the decoder's
+//! events, in display order.
+
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+/// Function blocking until a submitted picture has been decoded, callable from any thread.
+pub type CompletionWaiter = Box<dyn FnOnce() -> anyhow::Result<()> + Send>;
+
+/// Callback receiving the timestamp of a submitted picture, and the result of waiting for its
+/// decoding to complete.
+pub type CompletionCallback = Box<dyn FnMut(u64, anyhow::Result<()>) + Send>;
+
+/// Thread waiting for pictures to complete in submission order, and invoking a callback for each.
+pub(crate) struct CompletionThread {
+    /// Only `None` once the thread is being dropped.
+    jobs: Option<Sender<(u64, CompletionWaiter)>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CompletionThread {
+    /// Starts a thread invoking `callback` for each completed picture.
+    pub(crate) fn new(mut callback: CompletionCallback) -> anyhow::Result<Self> {
+        let (jobs, job_receiver) = mpsc::channel::<(u64, CompletionWaiter)>();
+
+        let thread = std::thread::Builder::new()
+            .name("decoder-completion".into())
+            .spawn(move || {
+                for (timestamp, waiter) in job_receiver {
+                    callback(timestamp, waiter());
+                }
+            })?;
+
+        Ok(Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        })
+    }
+
+    /// Waits for the picture with `timestamp` to complete using `waiter`, then invokes the
+    /// callback.
+    pub(crate) fn submit(&self, timestamp: u64, waiter: CompletionWaiter) {
+        // Cannot fail unless the callback panicked, in which case there is nobody to notify
+        // anymore.
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send((timestamp, waiter));
+        }
+    }
+}
+
+impl Drop for CompletionThread {
+    fn drop(&mut self) {
+        // The waiters may refer to resources of the backend, so make sure they have all returned
+        // before the backend can be dropped.
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
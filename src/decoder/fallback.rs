@@ -0,0 +1,481 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Automatic fallback to other backends for streams the preferred one cannot decode.
+//!
+//! Hardware decoders often support only a subset of the profiles and resolutions of a codec, which
+//! is only known once the stream has been parsed. [`FallbackDecoder`] decodes with a preferred
+//! decoder, e.g. a VA-API one, and switches to the next registered one, e.g. a software decoder,
+//! as soon as it rejects the stream. The client keeps using the same decoder object, and the
+//! output format it negotiated is requested from the new decoder.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
+use std::rc::Rc;
+
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::ReadinessNotifier;
+use crate::decoder::stateless::StatelessBackendError;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::DecoderEvent;
+use crate::decoder::DecoderFormatNegotiator;
+use crate::decoder::FormatError;
+use crate::decoder::FramePool;
+use crate::decoder::StreamInfo;
+use crate::DecodedFormat;
+
+/// Description of a switch of backend, see [`DecoderEvent::BackendSwitched`].
+#[derive(Debug)]
+pub struct BackendSwitch {
+    /// Name of the decoder that could not decode the stream.
+    pub from: String,
+    /// Name of the decoder now in use.
+    pub to: String,
+    /// Error returned by the previous decoder.
+    pub reason: DecodeError,
+}
+
+/// Function creating a fallback decoder.
+type CreateDecoder<M> = Box<dyn FnOnce() -> anyhow::Result<Box<dyn StatelessVideoDecoder<M>>>>;
+
+/// Returns whether `error` means that the backend cannot decode the stream, as opposed to the
+/// stream being invalid or the decoder needing attention.
+///
+/// Only the errors typed as such are considered: other backend errors, like driver failures, are
+/// returned to the client as they would be without a fallback.
+fn is_unsupported_by_backend(error: &DecodeError) -> bool {
+    matches!(
+        error,
+        DecodeError::UnsupportedFeature(_)
+            | DecodeError::BackendError(
+                StatelessBackendError::UnsupportedFormat
+                    | StatelessBackendError::UnsupportedProfile(_)
+            )
+    )
+}
+
+/// Format negotiator recording the format chosen by the client.
+struct FallbackNegotiator<'a, M> {
+    inner: Box<dyn DecoderFormatNegotiator<'a, M> + 'a>,
+    format: Rc<Cell<Option<DecodedFormat>>>,
+}
+
+impl<'a, M> DecoderFormatNegotiator<'a, M> for FallbackNegotiator<'a, M> {
+    fn stream_info(&self) -> &StreamInfo {
+        self.inner.stream_info()
+    }
+
+    fn frame_pool(&mut self) -> &mut dyn FramePool<M> {
+        self.inner.frame_pool()
+    }
+
+    fn try_format(&mut self, format: DecodedFormat) -> Result<(), FormatError> {
+        self.inner.try_format(format)?;
+        self.format.set(Some(format));
+
+        Ok(())
+    }
+}
+
+/// Decoder switching to fallback decoders when the current one cannot decode the stream.
+///
+/// A switch happens when the current decoder rejects the stream as unsupported, e.g. because of
+/// its profile or resolution, before it has output any frame. The input received since the start
+/// of the stream is then replayed into the next decoder that can be created, a
+/// [`DecoderEvent::BackendSwitched`] event is reported, and [`DecodeError::CheckEvents`] is
+/// returned so the client submits the rejected input again. The new decoder reports a
+/// [`DecoderEvent::FormatChanged`] event as usual, with the format previously negotiated by the
+/// client already applied if it supports it.
+///
+/// Once a frame has been output, errors are returned as usual until the decoder is flushed or
+/// reset, after which the stream is expected to restart with a key frame preceded by its
+/// parameters.
+///
+/// Unlike other decoders, the readiness file descriptor may become readable spuriously after
+/// [`StatelessVideoDecoder::decode`] or [`StatelessVideoDecoder::flush`].
+pub struct FallbackDecoder<M> {
+    /// Name of the current decoder.
+    name: String,
+    decoder: Box<dyn StatelessVideoDecoder<M>>,
+    /// Decoders to fall back to, in order of preference.
+    fallbacks: VecDeque<(String, CreateDecoder<M>)>,
+    /// Input received since the start of the stream, or `None` if the current decoder has output
+    /// frames and falling back is not possible anymore.
+    recorded: Option<Vec<(u64, Vec<u8>)>>,
+    /// Input that remains to be replayed into the current decoder after a switch.
+    replay: VecDeque<(u64, Vec<u8>)>,
+    /// Last format successfully negotiated by the client.
+    format: Rc<Cell<Option<DecodedFormat>>>,
+    /// Format to apply to the next format change of the current decoder, after a switch.
+    forced_format: Option<DecodedFormat>,
+    /// Switch that has not been reported yet.
+    pending_switch: Option<BackendSwitch>,
+    readiness: Option<ReadinessNotifier>,
+}
+
+impl<M> FallbackDecoder<M> {
+    /// Creates a decoder using `decoder`, which is named `name` in the switch events.
+    pub fn new(name: impl Into<String>, decoder: Box<dyn StatelessVideoDecoder<M>>) -> Self {
+        Self {
+            name: name.into(),
+            decoder,
+            fallbacks: Default::default(),
+            recorded: Some(Vec::new()),
+            replay: Default::default(),
+            format: Default::default(),
+            forced_format: None,
+            pending_switch: None,
+            readiness: None,
+        }
+    }
+
+    /// Adds a decoder to fall back to, after the ones already added. It is created by
+    /// `create_decoder` when needed.
+    pub fn add_fallback<F>(&mut self, name: impl Into<String>, create_decoder: F)
+    where
+        F: FnOnce() -> anyhow::Result<Box<dyn StatelessVideoDecoder<M>>> + 'static,
+    {
+        self.fallbacks
+            .push_back((name.into(), Box::new(create_decoder)));
+    }
+
+    /// Returns the name of the decoder currently in use.
+    pub fn current_decoder(&self) -> &str {
+        &self.name
+    }
+
+    /// Switches to the next fallback decoder that can be created, or returns `reason` if there
+    /// is none.
+    fn fall_back(&mut self, reason: DecodeError) -> DecodeError {
+        let (name, decoder) = loop {
+            let Some((name, create_decoder)) = self.fallbacks.pop_front() else {
+                return reason;
+            };
+
+            match create_decoder() {
+                Ok(decoder) => break (name, decoder),
+                Err(e) => log::warn!("failed to create fallback decoder {}: {:#}", name, e),
+            }
+        };
+
+        log::info!(
+            "switching from decoder {} to {}: {}",
+            self.name,
+            name,
+            reason
+        );
+
+        self.decoder = decoder;
+        self.replay = self.recorded.iter().flatten().cloned().collect();
+        self.forced_format = self.format.get();
+        self.pending_switch = Some(BackendSwitch {
+            from: std::mem::replace(&mut self.name, name),
+            to: self.name.clone(),
+            reason,
+        });
+
+        DecodeError::CheckEvents
+    }
+
+    /// Submits the input remaining to be replayed to the current decoder.
+    fn replay(&mut self) -> Result<(), DecodeError> {
+        while let Some((timestamp, bitstream)) = self.replay.front_mut() {
+            let consumed = self.decoder.decode(*timestamp, bitstream)?;
+            bitstream.drain(..consumed);
+            if bitstream.is_empty() {
+                self.replay.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Falls back to the next decoder if `res` is an error meaning that the current one cannot
+    /// decode the stream.
+    fn check<T>(&mut self, res: Result<T, DecodeError>) -> Result<T, DecodeError> {
+        match res {
+            Err(e) if self.recorded.is_some() && is_unsupported_by_backend(&e) => {
+                Err(self.fall_back(e))
+            }
+            res => res,
+        }
+    }
+
+    /// Updates the readiness file descriptor, if any.
+    fn set_ready(&mut self, ready: bool) {
+        if let Some(readiness) = &mut self.readiness {
+            if let Err(e) = readiness.set(ready) {
+                log::warn!("failed to update the readiness fd: {}", e);
+            }
+        }
+    }
+}
+
+impl<M: 'static> StatelessVideoDecoder<M> for FallbackDecoder<M> {
+    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        let res = self
+            .replay()
+            .and_then(|()| self.decoder.decode(timestamp, bitstream));
+        let res = self.check(res);
+        self.set_ready(true);
+
+        let consumed = res?;
+        if let Some(recorded) = &mut self.recorded {
+            recorded.push((timestamp, bitstream[..consumed].to_vec()));
+        }
+
+        Ok(consumed)
+    }
+
+    fn flush(&mut self) -> Result<(), DecodeError> {
+        let res = self.replay().and_then(|()| self.decoder.flush());
+        let res = self.check(res);
+        self.set_ready(true);
+
+        res?;
+        self.recorded = Some(Vec::new());
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.decoder.reset();
+        self.replay.clear();
+        self.recorded = Some(Vec::new());
+        self.set_ready(self.pending_switch.is_some());
+    }
+
+    fn frame_pool(&mut self) -> &mut dyn FramePool<M> {
+        self.decoder.frame_pool()
+    }
+
+    fn stream_info(&self) -> Option<&StreamInfo> {
+        self.decoder.stream_info()
+    }
+
+    fn ready_for_input(&mut self) -> bool {
+        self.pending_switch.is_none() && self.decoder.ready_for_input()
+    }
+
+    fn next_event(&mut self) -> Option<DecoderEvent<'_, M>> {
+        if let Some(switch) = self.pending_switch.take() {
+            return Some(DecoderEvent::BackendSwitched(switch));
+        }
+
+        let Self {
+            decoder,
+            recorded,
+            format,
+            forced_format,
+            readiness,
+            ..
+        } = self;
+
+        match decoder.next_event() {
+            Some(DecoderEvent::FrameReady(frame)) => {
+                *recorded = None;
+                Some(DecoderEvent::FrameReady(frame))
+            }
+            Some(DecoderEvent::FormatChanged(mut negotiator)) => {
+                if let Some(format) = forced_format.take() {
+                    if let Err(e) = negotiator.try_format(format) {
+                        log::warn!("failed to keep output format {:?}: {}", format, e);
+                    }
+                }
+
+                Some(DecoderEvent::FormatChanged(Box::new(FallbackNegotiator {
+                    inner: negotiator,
+                    format: Rc::clone(format),
+                })))
+            }
+            Some(event) => Some(event),
+            None => {
+                if let Some(readiness) = readiness {
+                    if let Err(e) = readiness.set(false) {
+                        log::warn!("failed to update the readiness fd: {}", e);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+        if self.readiness.is_none() {
+            self.readiness = Some(ReadinessNotifier::new()?);
+            self.set_ready(true);
+        }
+
+        // Cannot fail as we just made sure it is set.
+        Ok(self.readiness.as_ref().unwrap().reader.as_fd())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::BorrowedFd;
+
+    use super::FallbackDecoder;
+    use crate::backend::dummy::Backend;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::tests::DECODE_64X64_PROGRESSIVE_I_P_B_P;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::DecodeError;
+    use crate::decoder::stateless::StatelessBackendError;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::stateless::StatelessVideoDecoder;
+    use crate::decoder::BlockingMode;
+    use crate::decoder::DecoderEvent;
+    use crate::decoder::FramePool;
+    use crate::decoder::StreamInfo;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+
+    /// Decoder failing with the error returned by `error` after accepting `num_inputs` inputs.
+    struct RejectingDecoder {
+        inner: StatelessDecoder<H264, Backend>,
+        num_inputs: usize,
+        error: fn() -> StatelessBackendError,
+    }
+
+    impl StatelessVideoDecoder<()> for RejectingDecoder {
+        fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+            if self.num_inputs == 0 {
+                return Err(DecodeError::BackendError((self.error)()));
+            }
+            self.num_inputs -= 1;
+            self.inner.decode(timestamp, bitstream)
+        }
+
+        fn flush(&mut self) -> Result<(), DecodeError> {
+            self.inner.flush()
+        }
+
+        fn reset(&mut self) {
+            self.inner.reset()
+        }
+
+        fn frame_pool(&mut self) -> &mut dyn FramePool<()> {
+            self.inner.frame_pool()
+        }
+
+        fn stream_info(&self) -> Option<&StreamInfo> {
+            self.inner.stream_info()
+        }
+
+        fn ready_for_input(&mut self) -> bool {
+            self.inner.ready_for_input()
+        }
+
+        fn next_event(&mut self) -> Option<DecoderEvent<'_, ()>> {
+            self.inner.next_event()
+        }
+
+        fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+            self.inner.readiness_fd()
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        // Let the parameter sets through, so they need to be replayed into the fallback decoder.
+        let mut decoder = FallbackDecoder::new(
+            "rejecting",
+            Box::new(RejectingDecoder {
+                inner: StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking),
+                num_inputs: 2,
+                error: || StatelessBackendError::UnsupportedFormat,
+            }),
+        );
+        decoder.add_fallback("unavailable", || Err(anyhow::anyhow!("no device")));
+        decoder.add_fallback("dummy", || {
+            Ok(Box::new(StatelessDecoder::<H264, _>::new_dummy(
+                BlockingMode::Blocking,
+            )))
+        });
+
+        let mut switches = Vec::new();
+        let mut formats = Vec::new();
+        let mut num_frames = 0;
+        for (timestamp, nalu) in NalIterator::<Nalu>::new(test.stream).enumerate() {
+            let mut bitstream = nalu;
+            while !bitstream.is_empty() {
+                match decoder.decode(timestamp as u64, bitstream) {
+                    Ok(consumed) => bitstream = &bitstream[consumed..],
+                    Err(DecodeError::CheckEvents) => (),
+                    Err(e) => panic!("{}", e),
+                }
+
+                while let Some(event) = decoder.next_event() {
+                    match event {
+                        DecoderEvent::FrameReady(_) => num_frames += 1,
+                        DecoderEvent::FormatChanged(mut negotiator) => {
+                            // Only negotiate the first time, the format must be kept afterwards.
+                            if formats.is_empty() {
+                                negotiator.try_format(DecodedFormat::NV12).unwrap();
+                            }
+                            let frames = simple_playback_loop_owned_frames(
+                                negotiator.stream_info(),
+                                negotiator.stream_info().min_num_frames,
+                            )
+                            .unwrap();
+                            negotiator.frame_pool().add_frames(frames).unwrap();
+                            formats.push(negotiator.stream_info().clone());
+                        }
+                        DecoderEvent::BackendSwitched(switch) => switches.push(switch),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        decoder.flush().unwrap();
+        while let Some(event) = decoder.next_event() {
+            if let DecoderEvent::FrameReady(_) = event {
+                num_frames += 1;
+            }
+        }
+
+        assert_eq!(switches.len(), 1);
+        assert_eq!(switches[0].from, "rejecting");
+        assert_eq!(switches[0].to, "dummy");
+        assert!(matches!(
+            switches[0].reason,
+            DecodeError::BackendError(StatelessBackendError::UnsupportedFormat)
+        ));
+        assert_eq!(decoder.current_decoder(), "dummy");
+        assert_eq!(num_frames, test.crcs.lines().count());
+    }
+
+    #[test]
+    fn test_no_fallback_on_other_errors() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = FallbackDecoder::new(
+            "failing",
+            Box::new(RejectingDecoder {
+                inner: StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking),
+                num_inputs: 0,
+                error: || StatelessBackendError::Other(anyhow::anyhow!("driver failure")),
+            }),
+        );
+        decoder.add_fallback("dummy", || {
+            Ok(Box::new(StatelessDecoder::<H264, _>::new_dummy(
+                BlockingMode::Blocking,
+            )))
+        });
+
+        let nalu = NalIterator::<Nalu>::new(test.stream).next().unwrap();
+        assert!(matches!(
+            decoder.decode(0, nalu),
+            Err(DecodeError::BackendError(StatelessBackendError::Other(_)))
+        ));
+        assert_eq!(decoder.current_decoder(), "failing");
+        while let Some(event) = decoder.next_event() {
+            assert!(!matches!(event, DecoderEvent::BackendSwitched(_)));
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A channel carrying decoded frames from a sender to a receiver thread.
+//!
+//! This is a utility for clients, separate from the queue decoders keep their output in: that one
+//! holds decoded handles, which are bound to the decoding thread, and frames are retrieved from it
+//! through [`crate::decoder::stateless::StatelessVideoDecoder::next_event`] as before. Pipelines
+//! that display or encode frames on another thread can forward them through the channel returned
+//! by [`frame_channel`], so the decoding thread keeps going while the receiver receives them.
+//! The frames must then be converted into something that can be sent to other threads, like
+//! [`crate::decoder::OwnedFrame`].
+//!
+//! Frames are received in the order they have been sent, i.e. in display order when sent from a
+//! decoder's events. Unlike with [`std::sync::mpsc`], the sender can also take back the oldest
+//! frames that the receiver has not received yet, e.g. to implement a drop-oldest policy when it
+//! runs out of output buffers.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+struct QueueState<T> {
+    frames: VecDeque<T>,
+    /// Set once the sender has been dropped.
+    closed: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<QueueState<T>>,
+    /// Signaled when a frame is pushed or the channel is closed.
+    frame_available: Condvar,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, QueueState<T>> {
+        // The state is consistent at all times, so we can keep using it even if another thread
+        // panicked while holding the lock.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Creates a frame channel, returning its sending and receiving ends.
+pub fn frame_channel<T>() -> (FrameSender<T>, FrameReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState {
+            frames: Default::default(),
+            closed: false,
+        }),
+        frame_available: Condvar::new(),
+    });
+
+    (
+        FrameSender {
+            shared: Arc::clone(&shared),
+        },
+        FrameReceiver { shared },
+    )
+}
+
+/// Sending end of a frame channel. Dropping it closes the channel.
+pub struct FrameSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> FrameSender<T> {
+    /// Pushes `frame` at the back of the channel.
+    pub fn push(&self, frame: T) {
+        self.shared.lock().frames.push_back(frame);
+        self.shared.frame_available.notify_one();
+    }
+
+    /// Takes back the oldest frame that has not been popped by the receiver yet, if any.
+    pub fn take_oldest(&self) -> Option<T> {
+        self.shared.lock().frames.pop_front()
+    }
+
+    /// Returns the number of frames waiting to be popped.
+    pub fn len(&self) -> usize {
+        self.shared.lock().frames.len()
+    }
+
+    /// Returns `true` if no frame is waiting to be popped.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Drop for FrameSender<T> {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.frame_available.notify_all();
+    }
+}
+
+/// Receiving end of a frame channel.
+///
+/// Iterating over it blocks until the next frame is available, and ends once the sender has
+/// been dropped and all the frames have been popped.
+pub struct FrameReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> FrameReceiver<T> {
+    /// Pops the oldest frame without blocking.
+    pub fn try_pop(&self) -> Option<T> {
+        self.shared.lock().frames.pop_front()
+    }
+
+    /// Waits for a frame to be available and pops it. Returns `None` if the sender has been
+    /// dropped and the channel is empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.shared.lock();
+
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+
+            state = self
+                .shared
+                .frame_available
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Same as [`FrameReceiver::pop`], but gives up and returns `None` after `timeout`.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                return Some(frame);
+            }
+
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                return None;
+            }
+
+            state = self
+                .shared
+                .frame_available
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Returns the number of frames waiting to be popped.
+    pub fn len(&self) -> usize {
+        self.shared.lock().frames.len()
+    }
+
+    /// Returns `true` if no frame is waiting to be popped.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the sender has been dropped. Frames may still be waiting to be popped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().closed
+    }
+}
+
+impl<T> Iterator for FrameReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::frame_channel;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::BlockingMode;
+    use crate::decoder::OwnedFrame;
+    use crate::utils::simple_playback_loop;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+
+    const STREAM: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P-B-P.h264");
+    const CRCS: &str = include_str!("../codec/h264/test_data/64x64-I-P-B-P.h264.crc");
+
+    #[test]
+    fn receiver_thread() {
+        let (sender, receiver) = frame_channel::<OwnedFrame>();
+
+        let receiver_thread =
+            std::thread::spawn(move || receiver.map(|frame| frame.timestamp).collect::<Vec<_>>());
+
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut timestamps = vec![];
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(STREAM),
+            &mut |handle| {
+                let frame = handle.to_owned_frame().unwrap();
+                assert_eq!(frame.format, DecodedFormat::NV12);
+                timestamps.push(frame.timestamp);
+                sender.push(frame);
+            },
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        drop(sender);
+
+        assert_eq!(timestamps.len(), CRCS.lines().count());
+        assert_eq!(receiver_thread.join().unwrap(), timestamps);
+    }
+
+    #[test]
+    fn take_oldest() {
+        let (sender, receiver) = frame_channel();
+
+        for i in 0..4 {
+            sender.push(i);
+        }
+        assert_eq!(sender.take_oldest(), Some(0));
+        assert_eq!(receiver.try_pop(), Some(1));
+        assert_eq!(sender.take_oldest(), Some(2));
+        assert_eq!(receiver.len(), 1);
+
+        assert_eq!(receiver.pop_timeout(Duration::from_millis(1)), Some(3));
+        assert_eq!(receiver.pop_timeout(Duration::from_millis(1)), None);
+        assert!(!receiver.is_closed());
+
+        drop(sender);
+        assert!(receiver.is_closed());
+        assert_eq!(receiver.pop(), None);
+    }
+}
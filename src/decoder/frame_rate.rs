@@ -0,0 +1,389 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Conversion of decoded streams to a fixed output frame rate.
+//!
+//! [`FrameRateConverter`] drops or repeats decoded frames so they come out at a target rate, e.g.
+//! to feed a 30 fps consumer from a 60 fps stream. Each output slot shows the latest frame whose
+//! timestamp is not after it, and frames are retimed to the timestamp of their slot.
+//!
+//! No frame is copied: output frames are the decoded handles themselves, wrapped into a
+//! [`RetimedHandle`] carrying their new timestamp. Repeated frames are clones of the same handle.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::decoder::DecodedHandle;
+use crate::decoder::DynHandle;
+use crate::decoder::FrameInfo;
+use crate::decoder::TimeBase;
+use crate::Resolution;
+
+/// A decoded handle with a rewritten timestamp, as output by [`FrameRateConverter`].
+#[derive(Clone)]
+pub struct RetimedHandle<H> {
+    handle: H,
+    timestamp: u64,
+}
+
+impl<H> RetimedHandle<H> {
+    /// Returns the handle this frame has been produced from, which reports the original
+    /// timestamp.
+    pub fn inner(&self) -> &H {
+        &self.handle
+    }
+
+    /// Returns the handle this frame has been produced from.
+    pub fn into_inner(self) -> H {
+        self.handle
+    }
+}
+
+impl<H: DecodedHandle> DecodedHandle for RetimedHandle<H> {
+    type Descriptor = H::Descriptor;
+
+    fn dyn_picture<'a>(&'a self) -> Box<dyn DynHandle + 'a> {
+        self.handle.dyn_picture()
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn time_base(&self) -> Option<TimeBase> {
+        self.handle.time_base()
+    }
+
+    fn coded_resolution(&self) -> Resolution {
+        self.handle.coded_resolution()
+    }
+
+    fn display_resolution(&self) -> Resolution {
+        self.handle.display_resolution()
+    }
+
+    fn display_offset(&self) -> (u32, u32) {
+        self.handle.display_offset()
+    }
+
+    fn frame_info(&self) -> FrameInfo {
+        self.handle.frame_info()
+    }
+
+    fn is_ready(&self) -> bool {
+        self.handle.is_ready()
+    }
+
+    fn is_corrupted(&self) -> anyhow::Result<bool> {
+        self.handle.is_corrupted()
+    }
+
+    fn sync(&self) -> anyhow::Result<()> {
+        self.handle.sync()
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> anyhow::Result<bool> {
+        self.handle.wait_ready(timeout)
+    }
+
+    fn estimated_completion(&self) -> Option<Instant> {
+        self.handle.estimated_completion()
+    }
+
+    fn resource(&self) -> std::cell::Ref<'_, Self::Descriptor> {
+        self.handle.resource()
+    }
+
+    fn as_any(&self) -> &dyn std::any::Any {
+        self.handle.as_any()
+    }
+}
+
+/// Drops or repeats decoded frames to output them at a fixed frame rate.
+///
+/// Frames must be pushed in display order, i.e. in the order the decoder outputs them, with
+/// increasing timestamps. [`FrameRateConverter::reset`] must be called after seeking.
+pub struct FrameRateConverter<H> {
+    /// Length of an output slot, in units of the time base, as a fraction.
+    period_num: u128,
+    period_den: u128,
+    /// Timestamp of the first output slot.
+    start: Option<u64>,
+    /// Index of the next output slot.
+    next_slot: u64,
+    /// Latest frame pushed, which is shown by the next slots until a newer frame arrives, and
+    /// whether it has been output yet.
+    pending: Option<(H, bool)>,
+    /// Interval between the last two input frames, used to estimate how long the last frame
+    /// lasts when flushing.
+    last_interval: u64,
+    /// Number of input frames that have not been output at all.
+    num_dropped: u64,
+    /// Number of output frames repeating an already output input frame.
+    num_repeated: u64,
+}
+
+impl<H: DecodedHandle + Clone> FrameRateConverter<H> {
+    /// Creates a converter outputting `rate_num / rate_den` frames per second, from frames which
+    /// timestamps are expressed in `time_base`. Returns `None` if either value is zero.
+    pub fn new(time_base: TimeBase, rate_num: u32, rate_den: u32) -> Option<Self> {
+        if rate_num == 0 || rate_den == 0 {
+            return None;
+        }
+
+        Some(Self {
+            // One slot is `rate_den / rate_num` seconds, i.e. this many units of the time base.
+            period_num: rate_den as u128 * time_base.den() as u128,
+            period_den: rate_num as u128 * time_base.num() as u128,
+            start: None,
+            next_slot: 0,
+            pending: None,
+            last_interval: 0,
+            num_dropped: 0,
+            num_repeated: 0,
+        })
+    }
+
+    /// Returns the timestamp of output slot `slot`, rounded to the nearest unit.
+    fn slot_timestamp(&self, start: u64, slot: u64) -> u64 {
+        let num = slot as u128 * self.period_num;
+        let offset =
+            num / self.period_den + u128::from(2 * (num % self.period_den) >= self.period_den);
+
+        start.saturating_add(u64::try_from(offset).unwrap_or(u64::MAX))
+    }
+
+    /// Outputs the pending frame for all the slots before `end`.
+    fn fill_slots_until(&mut self, end: u64, output: &mut Vec<RetimedHandle<H>>) {
+        let (start, (handle, mut output_once)) = match (self.start, self.pending.take()) {
+            (Some(start), Some(pending)) => (start, pending),
+            (_, pending) => {
+                self.pending = pending;
+                return;
+            }
+        };
+
+        loop {
+            let timestamp = self.slot_timestamp(start, self.next_slot);
+            if timestamp >= end {
+                break;
+            }
+
+            if output_once {
+                self.num_repeated += 1;
+            }
+            output_once = true;
+            output.push(RetimedHandle {
+                handle: handle.clone(),
+                timestamp,
+            });
+            self.next_slot += 1;
+        }
+
+        self.pending = Some((handle, output_once));
+    }
+
+    /// Pushes `handle`, the next frame in display order, and returns the frames to output as a
+    /// result, in order.
+    ///
+    /// Since a frame is shown until a newer one arrives, output lags input by one frame. Frames
+    /// with a timestamp not after the previous one are dropped.
+    pub fn push(&mut self, handle: H) -> Vec<RetimedHandle<H>> {
+        let timestamp = handle.timestamp();
+        let mut output = Vec::new();
+
+        if let Some((previous, _)) = &self.pending {
+            let previous = previous.timestamp();
+            if timestamp <= previous {
+                log::debug!(
+                    "dropping frame with timestamp {} not after the previous one ({})",
+                    timestamp,
+                    previous
+                );
+                self.num_dropped += 1;
+                return output;
+            }
+            self.last_interval = timestamp - previous;
+        }
+
+        self.start.get_or_insert(timestamp);
+        self.fill_slots_until(timestamp, &mut output);
+
+        if let Some((_, false)) = self.pending.replace((handle, false)) {
+            self.num_dropped += 1;
+        }
+
+        output
+    }
+
+    /// Returns the frames to output for the last frame pushed, which is assumed to last as long
+    /// as the interval between the last two frames. Call at the end of the stream.
+    ///
+    /// The converter can be used for another stream afterwards, as with
+    /// [`FrameRateConverter::reset`].
+    pub fn flush(&mut self) -> Vec<RetimedHandle<H>> {
+        let mut output = Vec::new();
+
+        if let Some((handle, _)) = &self.pending {
+            let end = handle.timestamp().saturating_add(self.last_interval);
+            self.fill_slots_until(end, &mut output);
+
+            if let Some((handle, false)) = self.pending.take() {
+                if self.next_slot == 0 {
+                    // Keep the only frame of the stream, even if its duration is unknown.
+                    let timestamp = handle.timestamp();
+                    output.push(RetimedHandle { handle, timestamp });
+                } else {
+                    self.num_dropped += 1;
+                }
+            }
+        }
+
+        self.reset();
+
+        output
+    }
+
+    /// Drops the pending frame and starts a new timeline with the next frame pushed, e.g. after
+    /// seeking.
+    pub fn reset(&mut self) {
+        self.start = None;
+        self.next_slot = 0;
+        self.pending = None;
+        self.last_interval = 0;
+    }
+
+    /// Returns the number of input frames that have not been output at all.
+    pub fn num_dropped(&self) -> u64 {
+        self.num_dropped
+    }
+
+    /// Returns the number of output frames that repeat an input frame already output.
+    pub fn num_repeated(&self) -> u64 {
+        self.num_repeated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::FrameRateConverter;
+    use crate::decoder::DecodedHandle;
+    use crate::decoder::DynHandle;
+    use crate::decoder::TimeBase;
+    use crate::Resolution;
+
+    /// Handle only carrying a timestamp.
+    #[derive(Clone)]
+    struct TestHandle {
+        timestamp: u64,
+        resource: Rc<RefCell<()>>,
+    }
+
+    impl TestHandle {
+        fn new(timestamp: u64) -> Self {
+            Self {
+                timestamp,
+                resource: Default::default(),
+            }
+        }
+    }
+
+    impl DecodedHandle for TestHandle {
+        type Descriptor = ();
+
+        fn dyn_picture<'a>(&'a self) -> Box<dyn DynHandle + 'a> {
+            unimplemented!()
+        }
+
+        fn timestamp(&self) -> u64 {
+            self.timestamp
+        }
+
+        fn coded_resolution(&self) -> Resolution {
+            Default::default()
+        }
+
+        fn display_resolution(&self) -> Resolution {
+            Default::default()
+        }
+
+        fn is_ready(&self) -> bool {
+            true
+        }
+
+        fn sync(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn resource(&self) -> std::cell::Ref<'_, ()> {
+            self.resource.borrow()
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+    }
+
+    /// Converts frames with `timestamps` and returns the output as pairs of (original, new)
+    /// timestamps.
+    fn convert(
+        converter: &mut FrameRateConverter<TestHandle>,
+        timestamps: &[u64],
+    ) -> Vec<(u64, u64)> {
+        let mut output = Vec::new();
+        for &timestamp in timestamps {
+            output.extend(converter.push(TestHandle::new(timestamp)));
+        }
+        output.extend(converter.flush());
+
+        output
+            .iter()
+            .map(|frame| (frame.inner().timestamp(), frame.timestamp()))
+            .collect()
+    }
+
+    #[test]
+    fn test_halve_frame_rate() {
+        let time_base = TimeBase::new(1, 60).unwrap();
+        let mut converter = FrameRateConverter::new(time_base, 30, 1).unwrap();
+
+        let output = convert(&mut converter, &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(output, vec![(0, 0), (2, 2), (4, 4)]);
+        assert_eq!(converter.num_dropped(), 3);
+        assert_eq!(converter.num_repeated(), 0);
+    }
+
+    #[test]
+    fn test_double_frame_rate() {
+        let mut converter = FrameRateConverter::new(TimeBase::MPEG_TS, 60, 1).unwrap();
+
+        let output = convert(&mut converter, &[3000, 6000, 9000]);
+        assert_eq!(
+            output,
+            vec![
+                (3000, 3000),
+                (3000, 4500),
+                (6000, 6000),
+                (6000, 7500),
+                (9000, 9000),
+                (9000, 10500),
+            ]
+        );
+        assert_eq!(converter.num_dropped(), 0);
+        assert_eq!(converter.num_repeated(), 3);
+    }
+
+    #[test]
+    fn test_non_increasing_timestamps() {
+        let time_base = TimeBase::new(1, 30).unwrap();
+        let mut converter = FrameRateConverter::new(time_base, 30, 1).unwrap();
+
+        let output = convert(&mut converter, &[0, 1, 1, 0, 2]);
+        assert_eq!(output, vec![(0, 0), (1, 1), (2, 2)]);
+        assert_eq!(converter.num_dropped(), 2);
+    }
+}
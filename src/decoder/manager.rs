@@ -0,0 +1,485 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Management of many decoders sharing the same device.
+//!
+//! Services like SFUs or recorders decode dozens of streams on a single GPU. Creating a VA display
+//! per stream wastes resources, and the total amount of memory used by the decoded surfaces is
+//! what ultimately limits how many streams can be decoded at once. [`DecoderManager`] owns the
+//! shared device context (e.g. an `Rc<libva::Display>`), hands it to the decoders it creates, and
+//! keeps track of the memory and activity of each of them.
+//!
+//! Streams can be given priorities, so that when the device is saturated the least important ones
+//! are throttled first.
+
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::DecoderEvent;
+
+/// Identifier of a stream within a [`DecoderManager`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId(u64);
+
+/// What happens to the input of a stream that is being throttled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottlePolicy {
+    /// Reject the input with [`ManagerError::Throttled`]. The client is expected to submit it
+    /// again later, so no frame is lost at the cost of latency.
+    #[default]
+    Delay,
+    /// Discard the input, reporting it as fully consumed. The decoder is flushed when it starts
+    /// dropping, so once the stream is not throttled anymore decoding resumes from the next key
+    /// frame (see [`crate::decoder::stateless::ResumePolicy`]).
+    Drop,
+}
+
+/// Error returned by the methods of [`DecoderManager`].
+#[derive(Debug, Error)]
+pub enum ManagerError {
+    #[error("unknown stream {0:?}")]
+    UnknownStream(StreamId),
+    #[error("stream is throttled until the device is less busy")]
+    Throttled,
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+}
+
+/// Statistics about a stream of a [`DecoderManager`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StreamStats {
+    /// Number of bytes of bitstream consumed by the decoder.
+    pub bytes_decoded: u64,
+    /// Number of frames output by the decoder.
+    pub frames_output: u64,
+    /// Number of frames reported as dropped by the decoder.
+    pub frames_dropped: u64,
+    /// Number of non-fatal errors reported by the decoder.
+    pub non_fatal_errors: u64,
+    /// Number of fatal errors returned by the decoder.
+    pub decode_errors: u64,
+    /// Number of inputs delayed or dropped because the stream was throttled.
+    pub inputs_throttled: u64,
+    /// Number of frames currently managed by the frame pool of the decoder.
+    pub num_frames: usize,
+    /// Estimated memory used by these frames, in bytes.
+    pub surface_memory: usize,
+}
+
+struct ManagedStream<M> {
+    decoder: Box<dyn StatelessVideoDecoder<M>>,
+    stats: StreamStats,
+    priority: u32,
+    throttle_policy: ThrottlePolicy,
+    /// Whether the input of the stream is currently being dropped.
+    dropping: bool,
+}
+
+impl<M> ManagedStream<M> {
+    /// Updates the frame pool statistics of the stream.
+    fn update_memory(&mut self) {
+        let format = self.decoder.stream_info().map(|info| info.format);
+        let pool = self.decoder.frame_pool();
+        let num_frames = pool.num_managed_frames();
+        let resolution = pool.coded_resolution();
+
+        self.stats.num_frames = num_frames;
+        self.stats.surface_memory = format.map_or(0, |format| {
+            num_frames
+                * crate::decoded_frame_size(
+                    format,
+                    resolution.width as usize,
+                    resolution.height as usize,
+                )
+        });
+    }
+}
+
+/// Multiplexes many decoders over one shared device context `C`.
+///
+/// With VA-API, `C` is typically `Rc<libva::Display>` and streams are added with e.g.
+/// `manager.add_stream(|display| Ok(Box::new(StatelessDecoder::<H264, _>::new_vaapi(display.clone(), BlockingMode::NonBlocking))))`.
+/// Decoders of other backends can be managed with `C = ()`.
+///
+/// The device is considered saturated when the surface memory of all the streams exceeds the
+/// budget set with [`DecoderManager::set_surface_memory_budget`], or when the client reports it
+/// with [`DecoderManager::set_saturated`], e.g. because decoding latency increases. While it is,
+/// the input of every stream with a lower priority than the most important stream is throttled
+/// according to the [`ThrottlePolicy`] of the stream.
+pub struct DecoderManager<M, C> {
+    context: C,
+    streams: BTreeMap<StreamId, ManagedStream<M>>,
+    next_id: u64,
+    surface_memory_budget: Option<usize>,
+    saturated: bool,
+}
+
+impl<M, C> DecoderManager<M, C> {
+    /// Creates a manager sharing `context` between its decoders.
+    pub fn new(context: C) -> Self {
+        Self {
+            context,
+            streams: Default::default(),
+            next_id: 0,
+            surface_memory_budget: None,
+            saturated: false,
+        }
+    }
+
+    /// Sets the surface memory, in bytes, above which the device is considered saturated, or
+    /// `None` for no limit.
+    pub fn set_surface_memory_budget(&mut self, budget: Option<usize>) {
+        self.surface_memory_budget = budget;
+    }
+
+    /// Sets whether the device is saturated regardless of the surface memory budget.
+    pub fn set_saturated(&mut self, saturated: bool) {
+        self.saturated = saturated;
+    }
+
+    /// Returns whether the device is currently saturated.
+    pub fn is_saturated(&mut self) -> bool {
+        self.saturated
+            || self
+                .surface_memory_budget
+                .is_some_and(|budget| self.total_surface_memory() > budget)
+    }
+
+    /// Sets the priority of stream `id`. Streams with higher values are throttled last. All
+    /// streams start with priority 0.
+    pub fn set_priority(&mut self, id: StreamId, priority: u32) -> Result<(), ManagerError> {
+        self.stream(id)?.priority = priority;
+        Ok(())
+    }
+
+    /// Sets what happens to the input of stream `id` while it is throttled.
+    pub fn set_throttle_policy(
+        &mut self,
+        id: StreamId,
+        policy: ThrottlePolicy,
+    ) -> Result<(), ManagerError> {
+        self.stream(id)?.throttle_policy = policy;
+        Ok(())
+    }
+
+    /// Returns whether the input of stream `id` is currently throttled.
+    pub fn is_throttled(&mut self, id: StreamId) -> Result<bool, ManagerError> {
+        let priority = self.stream(id)?.priority;
+        let max_priority = self.streams.values().map(|s| s.priority).max();
+
+        Ok(max_priority.is_some_and(|max| priority < max) && self.is_saturated())
+    }
+
+    /// Returns the context shared by the decoders.
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    /// Adds a stream decoded by the decoder returned by `create_decoder`, which receives the
+    /// shared context.
+    pub fn add_stream<F>(&mut self, create_decoder: F) -> anyhow::Result<StreamId>
+    where
+        F: FnOnce(&C) -> anyhow::Result<Box<dyn StatelessVideoDecoder<M>>>,
+    {
+        let decoder = create_decoder(&self.context)?;
+        let id = StreamId(self.next_id);
+        self.next_id += 1;
+
+        self.streams.insert(
+            id,
+            ManagedStream {
+                decoder,
+                stats: Default::default(),
+                priority: 0,
+                throttle_policy: Default::default(),
+                dropping: false,
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Removes a stream, returning its decoder.
+    pub fn remove_stream(&mut self, id: StreamId) -> Option<Box<dyn StatelessVideoDecoder<M>>> {
+        self.streams.remove(&id).map(|stream| stream.decoder)
+    }
+
+    /// Returns the identifiers of all the streams, in the order they were added.
+    pub fn streams(&self) -> impl Iterator<Item = StreamId> + '_ {
+        self.streams.keys().copied()
+    }
+
+    /// Returns the number of streams.
+    pub fn num_streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Returns the decoder of stream `id`, e.g. to configure it or to add frames to its pool.
+    pub fn decoder(&mut self, id: StreamId) -> Option<&mut dyn StatelessVideoDecoder<M>> {
+        self.streams
+            .get_mut(&id)
+            .map(|stream| stream.decoder.as_mut() as &mut dyn StatelessVideoDecoder<M>)
+    }
+
+    fn stream(&mut self, id: StreamId) -> Result<&mut ManagedStream<M>, ManagerError> {
+        self.streams
+            .get_mut(&id)
+            .ok_or(ManagerError::UnknownStream(id))
+    }
+
+    /// Calls [`StatelessVideoDecoder::decode`] on the decoder of stream `id`, unless the stream is
+    /// throttled.
+    pub fn decode(
+        &mut self,
+        id: StreamId,
+        timestamp: u64,
+        bitstream: &[u8],
+    ) -> Result<usize, ManagerError> {
+        let throttled = self.is_throttled(id)?;
+        let stream = self.stream(id)?;
+
+        if throttled {
+            stream.stats.inputs_throttled += 1;
+            return match stream.throttle_policy {
+                ThrottlePolicy::Delay => Err(ManagerError::Throttled),
+                ThrottlePolicy::Drop => {
+                    if !stream.dropping {
+                        stream.decoder.flush()?;
+                        stream.dropping = true;
+                    }
+                    Ok(bitstream.len())
+                }
+            };
+        }
+        stream.dropping = false;
+
+        let res = stream.decoder.decode(timestamp, bitstream);
+        match &res {
+            Ok(consumed) => stream.stats.bytes_decoded += *consumed as u64,
+            Err(DecodeError::CheckEvents | DecodeError::NotEnoughOutputBuffers(_)) => (),
+            Err(_) => stream.stats.decode_errors += 1,
+        }
+
+        Ok(res?)
+    }
+
+    /// Calls [`StatelessVideoDecoder::flush`] on the decoder of stream `id`.
+    pub fn flush(&mut self, id: StreamId) -> Result<(), ManagerError> {
+        Ok(self.stream(id)?.decoder.flush()?)
+    }
+
+    /// Calls [`StatelessVideoDecoder::next_event`] on the decoder of stream `id`.
+    pub fn next_event(&mut self, id: StreamId) -> Option<DecoderEvent<'_, M>> {
+        let stream = self.streams.get_mut(&id)?;
+        let event = stream.decoder.next_event()?;
+
+        match &event {
+            DecoderEvent::FrameReady(_) => stream.stats.frames_output += 1,
+            DecoderEvent::FrameDropped(_) => stream.stats.frames_dropped += 1,
+            DecoderEvent::NonFatalError(..) => stream.stats.non_fatal_errors += 1,
+            _ => (),
+        }
+
+        Some(event)
+    }
+
+    /// Returns the statistics of stream `id`.
+    pub fn stats(&mut self, id: StreamId) -> Option<StreamStats> {
+        let stream = self.streams.get_mut(&id)?;
+        stream.update_memory();
+
+        Some(stream.stats.clone())
+    }
+
+    /// Returns the estimated memory used by the frames of all the streams, in bytes.
+    pub fn total_surface_memory(&mut self) -> usize {
+        self.streams
+            .values_mut()
+            .map(|stream| {
+                stream.update_memory();
+                stream.stats.surface_memory
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecoderManager;
+    use super::ManagerError;
+    use super::StreamId;
+    use super::ThrottlePolicy;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::DecodeError;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::stateless::StatelessVideoDecoder;
+    use crate::decoder::BlockingMode;
+    use crate::decoder::DecoderEvent;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+
+    const STREAM: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P.h264");
+    const CRCS: &str = include_str!("../codec/h264/test_data/64x64-I-P.h264.crc");
+
+    fn new_manager(num_streams: usize) -> (DecoderManager<(), ()>, Vec<StreamId>) {
+        let mut manager = DecoderManager::new(());
+        let ids = (0..num_streams)
+            .map(|_| {
+                manager
+                    .add_stream(|_| {
+                        Ok(Box::new(StatelessDecoder::<H264, _>::new_dummy(
+                            BlockingMode::Blocking,
+                        ))
+                            as Box<dyn StatelessVideoDecoder<_>>)
+                    })
+                    .unwrap()
+            })
+            .collect();
+
+        (manager, ids)
+    }
+
+    /// Submits `nalu` to stream `id`, processing all the events it produces.
+    fn decode_nalu(
+        manager: &mut DecoderManager<(), ()>,
+        id: StreamId,
+        timestamp: u64,
+        nalu: &[u8],
+    ) -> Result<(), ManagerError> {
+        let mut bitstream = nalu;
+        while !bitstream.is_empty() {
+            match manager.decode(id, timestamp, bitstream) {
+                Ok(consumed) => bitstream = &bitstream[consumed..],
+                Err(ManagerError::DecodeError(DecodeError::CheckEvents)) => (),
+                Err(e) => return Err(e),
+            }
+
+            while let Some(event) = manager.next_event(id) {
+                if let DecoderEvent::FormatChanged(mut negotiator) = event {
+                    negotiator.try_format(DecodedFormat::NV12).unwrap();
+                    let frames = simple_playback_loop_owned_frames(
+                        negotiator.stream_info(),
+                        negotiator.stream_info().min_num_frames,
+                    )
+                    .unwrap();
+                    negotiator.frame_pool().add_frames(frames).unwrap();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(manager: &mut DecoderManager<(), ()>, id: StreamId) {
+        manager.flush(id).unwrap();
+        while manager.next_event(id).is_some() {}
+    }
+
+    #[test]
+    fn interleaved_streams() {
+        let (mut manager, ids) = new_manager(2);
+        assert_eq!(manager.streams().collect::<Vec<_>>(), ids);
+
+        let nalus = NalIterator::<Nalu>::new(STREAM).collect::<Vec<_>>();
+        for (timestamp, nalu) in nalus.iter().enumerate() {
+            for &id in &ids {
+                decode_nalu(&mut manager, id, timestamp as u64, nalu).unwrap();
+            }
+        }
+
+        for &id in &ids {
+            finish(&mut manager, id);
+
+            let stats = manager.stats(id).unwrap();
+            assert_eq!(
+                stats.bytes_decoded as usize,
+                nalus.iter().map(|n| n.len()).sum()
+            );
+            assert_eq!(stats.frames_output as usize, CRCS.lines().count());
+            assert_eq!(stats.decode_errors, 0);
+            assert_eq!(stats.inputs_throttled, 0);
+            assert!(stats.num_frames > 0);
+        }
+
+        let stream_memory = manager.stats(ids[0]).unwrap().surface_memory;
+        assert_eq!(manager.total_surface_memory(), 2 * stream_memory);
+
+        assert!(manager.remove_stream(ids[0]).is_some());
+        assert!(matches!(
+            manager.decode(ids[0], 0, STREAM),
+            Err(ManagerError::UnknownStream(_))
+        ));
+        assert_eq!(manager.num_streams(), 1);
+        assert_eq!(manager.total_surface_memory(), stream_memory);
+    }
+
+    #[test]
+    fn priorities() {
+        let (mut manager, ids) = new_manager(3);
+        let (high, delayed, dropped) = (ids[0], ids[1], ids[2]);
+        manager.set_priority(high, 1).unwrap();
+        manager
+            .set_throttle_policy(dropped, ThrottlePolicy::Drop)
+            .unwrap();
+
+        let nalus = NalIterator::<Nalu>::new(STREAM).collect::<Vec<_>>();
+        let (first_nalus, other_nalus) = nalus.split_at(nalus.len() / 2);
+
+        // Nothing is throttled as long as the device is not saturated.
+        for (timestamp, nalu) in first_nalus.iter().enumerate() {
+            for &id in &ids {
+                decode_nalu(&mut manager, id, timestamp as u64, nalu).unwrap();
+            }
+        }
+
+        // Exceeding the memory budget saturates the device.
+        let memory = manager.total_surface_memory();
+        assert!(memory > 0);
+        manager.set_surface_memory_budget(Some(memory));
+        assert!(!manager.is_saturated());
+        manager.set_surface_memory_budget(Some(memory - 1));
+        assert!(manager.is_saturated());
+        assert!(!manager.is_throttled(high).unwrap());
+        assert!(manager.is_throttled(delayed).unwrap());
+
+        for (timestamp, nalu) in other_nalus.iter().enumerate() {
+            let timestamp = (first_nalus.len() + timestamp) as u64;
+            decode_nalu(&mut manager, high, timestamp, nalu).unwrap();
+            decode_nalu(&mut manager, dropped, timestamp, nalu).unwrap();
+            assert!(matches!(
+                decode_nalu(&mut manager, delayed, timestamp, nalu),
+                Err(ManagerError::Throttled)
+            ));
+        }
+
+        // Delayed input can be submitted once the device is not saturated anymore.
+        manager.set_surface_memory_budget(None);
+        for (timestamp, nalu) in other_nalus.iter().enumerate() {
+            let timestamp = (first_nalus.len() + timestamp) as u64;
+            decode_nalu(&mut manager, delayed, timestamp, nalu).unwrap();
+        }
+
+        for &id in &ids {
+            finish(&mut manager, id);
+        }
+
+        let num_frames = CRCS.lines().count() as u64;
+        let high_stats = manager.stats(high).unwrap();
+        assert_eq!(high_stats.frames_output, num_frames);
+        assert_eq!(high_stats.inputs_throttled, 0);
+
+        let delayed_stats = manager.stats(delayed).unwrap();
+        assert_eq!(delayed_stats.frames_output, num_frames);
+        assert_eq!(delayed_stats.inputs_throttled, other_nalus.len() as u64);
+
+        let dropped_stats = manager.stats(dropped).unwrap();
+        assert!(dropped_stats.frames_output < num_frames);
+        assert_eq!(dropped_stats.inputs_throttled, other_nalus.len() as u64);
+    }
+}
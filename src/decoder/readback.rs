@@ -0,0 +1,354 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reading decoded frames back to the CPU on a pool of threads.
+//!
+//! Copying a 4K frame out of device memory takes milliseconds, and doing it on the decoding
+//! thread serializes the whole pipeline. [`ReadbackService`] moves these copies to worker threads:
+//! the decoding thread submits each frame along with a [`ReadbackSource`], a `Send` description
+//! of its memory, and keeps decoding while the copy takes place. The handle of the frame is kept
+//! alive until the copy completes, so its memory cannot be reused by the decoder in the meantime.
+//!
+//! Decoded handles and the VA display are bound to the decoding thread, so sources can only be
+//! built for memory that is accessible from any thread, like the user-allocated frames of
+//! [`UserPtrFrame`].
+
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+use anyhow::anyhow;
+
+use crate::decoder::DecodedHandle;
+use crate::decoder::OwnedFrame;
+use crate::utils::UserPtrFrame;
+use crate::DecodedFormat;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::Resolution;
+
+/// Memory of a decoded frame that can be read from a worker thread of a [`ReadbackService`].
+pub trait ReadbackSource: Send + 'static {
+    /// Reads the visible part of the frame, laid out the same way as the output of
+    /// [`crate::decoder::MappableHandle::read`].
+    fn read(self: Box<Self>) -> anyhow::Result<Vec<u8>>;
+
+    /// Returns the format of the data returned by [`ReadbackSource::read`].
+    fn format(&self) -> DecodedFormat;
+}
+
+/// Readback of the NV12 content of a [`UserPtrFrame`].
+struct UserPtrReadback {
+    data: *const u8,
+    len: usize,
+    /// Layout of the frame, with the size of the part to read.
+    layout: FrameLayout,
+}
+
+// SAFETY: the memory of a `UserPtrFrame` can be read from any thread, and `ReadbackService`
+// keeps the frame alive until the readback completes.
+unsafe impl Send for UserPtrReadback {}
+
+impl ReadbackSource for UserPtrReadback {
+    fn read(self: Box<Self>) -> anyhow::Result<Vec<u8>> {
+        // SAFETY: `data` points to `len` bytes of memory allocated by `UserPtrFrame`, which the
+        // service keeps alive until this function returns.
+        let src = unsafe { std::slice::from_raw_parts(self.data, self.len) };
+        let mut dst = vec![
+            0;
+            crate::decoded_frame_size(
+                DecodedFormat::NV12,
+                self.layout.size.width as usize,
+                self.layout.size.height as usize,
+            )
+        ];
+        crate::nv12_copy(src, &self.layout, &mut dst);
+
+        Ok(dst)
+    }
+
+    fn format(&self) -> DecodedFormat {
+        DecodedFormat::NV12
+    }
+}
+
+/// A readback job, sent to the worker threads.
+struct Job {
+    id: u64,
+    source: Box<dyn ReadbackSource>,
+}
+
+/// Frame metadata kept on the submitting thread while its readback is in progress.
+struct PendingFrame<M> {
+    /// Kept alive so the memory being read is not reused.
+    _handle: Box<dyn DecodedHandle<Descriptor = M>>,
+    timestamp: u64,
+    display_resolution: Resolution,
+    format: DecodedFormat,
+    /// Result of the readback, once it has completed.
+    data: Option<anyhow::Result<Vec<u8>>>,
+}
+
+/// Reads frames back on a pool of worker threads, and returns them in submission order.
+///
+/// `M` is the memory descriptor type of the decoded handles submitted to the service.
+pub struct ReadbackService<M> {
+    /// Only `None` once the service is being dropped.
+    jobs: Option<Sender<Job>>,
+    results: Receiver<(u64, anyhow::Result<Vec<u8>>)>,
+    threads: Vec<JoinHandle<()>>,
+    pending: BTreeMap<u64, PendingFrame<M>>,
+    next_id: u64,
+}
+
+impl<M> ReadbackService<M> {
+    /// Starts a service with `num_threads` worker threads.
+    pub fn new(num_threads: usize) -> anyhow::Result<Self> {
+        if num_threads == 0 {
+            return Err(anyhow!("a readback service needs at least one thread"));
+        }
+
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let threads = (0..num_threads)
+            .map(|i| {
+                let job_receiver = Arc::clone(&job_receiver);
+                let result_sender = result_sender.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("readback-{}", i))
+                    .spawn(move || loop {
+                        // Only hold the lock while waiting for a job, so other threads can pick
+                        // the next one while we copy.
+                        let job = match job_receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => break,
+                        };
+                        let Ok(job) = job else {
+                            break;
+                        };
+
+                        if result_sender.send((job.id, job.source.read())).is_err() {
+                            break;
+                        }
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            jobs: Some(jobs),
+            results,
+            threads,
+            pending: Default::default(),
+            next_id: 0,
+        })
+    }
+
+    /// Reads the content of `handle` from `source` on a worker thread.
+    ///
+    /// The handle is synced first, and kept alive until its frame is returned by
+    /// [`ReadbackService::next_frame`] or [`ReadbackService::try_next_frame`].
+    pub fn submit<S: ReadbackSource>(
+        &mut self,
+        handle: Box<dyn DecodedHandle<Descriptor = M>>,
+        source: S,
+    ) -> anyhow::Result<()> {
+        handle.sync()?;
+
+        let id = self.next_id;
+        let format = source.format();
+        // Cannot fail as `jobs` is only taken when the service is dropped.
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send(Job {
+                id,
+                source: Box::new(source),
+            })
+            .map_err(|_| anyhow!("the readback threads have stopped"))?;
+
+        self.next_id += 1;
+        self.pending.insert(
+            id,
+            PendingFrame {
+                timestamp: handle.timestamp(),
+                display_resolution: handle.display_resolution(),
+                format,
+                _handle: handle,
+                data: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Returns the number of frames submitted and not returned yet.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Records the result of a completed job.
+    fn complete(&mut self, (id, data): (u64, anyhow::Result<Vec<u8>>)) {
+        if let Some(frame) = self.pending.get_mut(&id) {
+            frame.data = Some(data);
+        }
+    }
+
+    /// Returns the oldest submitted frame if its readback has completed, releasing its handle.
+    fn take_completed(&mut self) -> Option<anyhow::Result<OwnedFrame>> {
+        let entry = self.pending.first_entry()?;
+        entry.get().data.as_ref()?;
+
+        let frame = entry.remove();
+        // Cannot fail as we just checked `data` is `Some`.
+        Some(frame.data.unwrap().map(|data| OwnedFrame {
+            timestamp: frame.timestamp,
+            display_resolution: frame.display_resolution,
+            format: frame.format,
+            data,
+        }))
+    }
+
+    /// Returns the next frame in submission order if its readback has completed, without
+    /// blocking.
+    pub fn try_next_frame(&mut self) -> Option<anyhow::Result<OwnedFrame>> {
+        while let Ok(result) = self.results.try_recv() {
+            self.complete(result);
+        }
+
+        self.take_completed()
+    }
+
+    /// Waits for the readback of the next frame in submission order to complete, and returns it.
+    /// Returns `None` if no frame is pending.
+    pub fn next_frame(&mut self) -> Option<anyhow::Result<OwnedFrame>> {
+        loop {
+            if let Some(frame) = self.try_next_frame() {
+                return Some(frame);
+            }
+            if self.pending.is_empty() {
+                return None;
+            }
+
+            match self.results.recv() {
+                Ok(result) => self.complete(result),
+                Err(_) => return Some(Err(anyhow!("the readback threads have stopped"))),
+            }
+        }
+    }
+}
+
+impl ReadbackService<UserPtrFrame> {
+    /// Reads the content of `handle`, which must be in NV12 format, on a worker thread.
+    pub fn submit_user_ptr(
+        &mut self,
+        handle: Box<dyn DecodedHandle<Descriptor = UserPtrFrame>>,
+    ) -> anyhow::Result<()> {
+        let source = {
+            let frame = handle.resource();
+            if u32::from(frame.layout.format.0) != u32::from(Fourcc::from(b"NV12"))
+                || frame.buffers.len() != 1
+            {
+                return Err(anyhow!(
+                    "only single-buffer NV12 frames can be read back, got {:?}",
+                    frame.layout
+                ));
+            }
+
+            let display_resolution = handle.display_resolution();
+            UserPtrReadback {
+                data: frame.buffers[0],
+                len: frame.mem_layout.size(),
+                layout: FrameLayout {
+                    size: display_resolution,
+                    ..frame.layout.clone()
+                },
+            }
+        };
+
+        self.submit(handle, source)
+    }
+}
+
+impl<M> Drop for ReadbackService<M> {
+    fn drop(&mut self) {
+        // The pending handles must outlive the jobs reading from them, so wait for the threads to
+        // complete the jobs already submitted.
+        self.jobs.take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ReadbackService;
+    use super::ReadbackSource;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::BlockingMode;
+    use crate::utils::simple_playback_loop;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+
+    const STREAM: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P-B-P.h264");
+    const CRCS: &str = include_str!("../codec/h264/test_data/64x64-I-P-B-P.h264.crc");
+
+    /// Source returning its timestamp after a delay, so jobs complete out of order.
+    struct DelayedSource(u64);
+
+    impl ReadbackSource for DelayedSource {
+        fn read(self: Box<Self>) -> anyhow::Result<Vec<u8>> {
+            std::thread::sleep(Duration::from_millis(10 * (self.0 % 3)));
+            Ok(vec![self.0 as u8])
+        }
+
+        fn format(&self) -> DecodedFormat {
+            DecodedFormat::I420
+        }
+    }
+
+    #[test]
+    fn readback_in_order() {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut service = ReadbackService::new(4).unwrap();
+        let mut timestamps = vec![];
+
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(STREAM),
+            &mut |handle| {
+                timestamps.push(handle.timestamp());
+                let source = DelayedSource(handle.timestamp());
+                service.submit(handle, source).unwrap();
+            },
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        assert_eq!(timestamps.len(), CRCS.lines().count());
+        assert_eq!(service.num_pending(), timestamps.len());
+
+        for timestamp in timestamps {
+            let frame = service.next_frame().unwrap().unwrap();
+            assert_eq!(frame.timestamp, timestamp);
+            assert_eq!(frame.format, DecodedFormat::I420);
+            assert_eq!(frame.data, vec![timestamp as u8]);
+        }
+        assert!(service.next_frame().is_none());
+        assert_eq!(service.num_pending(), 0);
+    }
+}
@@ -17,25 +17,66 @@ pub mod h265;
 pub mod vp8;
 pub mod vp9;
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Range;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
 use thiserror::Error;
 
+use crate::codec::nal_framing;
+use crate::codec::nal_framing::NalFraming;
+use crate::decoder::completion::CompletionCallback;
+use crate::decoder::completion::CompletionThread;
+use crate::decoder::completion::CompletionWaiter;
 use crate::decoder::BlockingMode;
+use crate::decoder::Colorimetry;
 use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
 use crate::decoder::DecoderFormatNegotiator;
+use crate::decoder::FormatError;
 use crate::decoder::FramePool;
+use crate::decoder::ReadyFrame;
 use crate::decoder::ReadyFramesQueue;
+use crate::decoder::SkipReason;
+use crate::decoder::SkippedData;
 use crate::decoder::StreamInfo;
+use crate::decoder::TimeBase;
 use crate::DecodedFormat;
 use crate::Resolution;
 
 /// Error returned by stateless backend methods.
+///
+/// Some variants only exist with some backend features, e.g. `VaError` with `vaapi`, and more may
+/// be added in the future, so this enum is not exhaustive.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum StatelessBackendError {
     #[error("not enough resources to proceed with the operation now")]
     OutOfResources,
     #[error("this format is not supported")]
     UnsupportedFormat,
+    /// The backend cannot decode the profile of the stream, or its combination of bit depth and
+    /// chroma format.
+    #[error("this stream is not supported: {0:#}")]
+    UnsupportedProfile(anyhow::Error),
+    #[error("the device has been lost, e.g. after a GPU reset")]
+    DeviceLost,
+    /// Error reported by the VA-API driver. Some of them are transient, e.g. `HwBusy`, in which
+    /// case the operation can be retried later.
+    #[cfg(feature = "vaapi")]
+    #[error("VA-API driver error: {0}")]
+    VaError(libva::VaError),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -68,12 +109,413 @@ pub enum DecodeError {
     NotEnoughOutputBuffers(usize),
     #[error("cannot accept more input until pending events are processed")]
     CheckEvents,
+    #[error("frame needs to be reordered, which is not possible in low-latency mode")]
+    ReorderingInLowLatencyMode,
+    #[error("stream requires a DPB of {0} frames, which exceeds the limit of {1}")]
+    DpbSizeLimitExceeded(usize, usize),
+    #[error("stream coded resolution {0:?} exceeds the limit of {1:?}")]
+    ResolutionLimitExceeded(Resolution, Resolution),
+    #[error("picture has {0} slices, which exceeds the limit of {1}")]
+    SliceLimitExceeded(usize, usize),
+    #[error("frame has {0} tiles, which exceeds the limit of {1}")]
+    TileLimitExceeded(usize, usize),
+    #[error("stream uses an unsupported feature: {0}")]
+    UnsupportedFeature(&'static str),
+    #[error("stream violates its specification: {0:#}")]
+    SpecViolation(anyhow::Error),
+    #[error("failed to parse the stream: {0:#}")]
+    ParseError(anyhow::Error),
     #[error("decoder error: {0}")]
     DecoderError(#[from] anyhow::Error),
     #[error("backend error: {0}")]
     BackendError(#[from] StatelessBackendError),
 }
 
+/// What a [`StatelessDecoder`] does when it runs out of output buffers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputExhaustionPolicy {
+    /// Stop decoding and return [`DecodeError::NotEnoughOutputBuffers`], until the client returns
+    /// enough frames to the pool. No frame is lost, at the cost of latency.
+    #[default]
+    Stall,
+    /// Drop the oldest decoded frames that have not been retrieved by the client yet, until
+    /// enough output buffers are available. Each dropped frame is reported with a
+    /// [`DecoderEvent::FrameDropped`] event. If dropping all undisplayed frames is not enough,
+    /// e.g. because the buffers are held by the client or used as references, decoding stalls
+    /// as with [`OutputExhaustionPolicy::Stall`].
+    ///
+    /// This is suited to real-time clients that favor latency over completeness.
+    DropOldest,
+}
+
+/// Frames from which a [`StatelessDecoder`] can resume decoding after a flush, e.g. when seeking
+/// or joining a stream midway.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResumePolicy {
+    /// Wait for a frame that does not depend on any previous one, e.g. an IDR picture or a key
+    /// frame. No frame is output until then, which can take long on streams with sparse key
+    /// frames.
+    #[default]
+    KeyFrame,
+    /// Also resume from intra-coded frames that are not random access points, e.g. non-IDR I
+    /// pictures in H.264 or CRA pictures and I pictures in H.265. The following frames may
+    /// reference frames from before the resume point, in which case they are flagged as
+    /// corrupted (see [`crate::decoder::FrameInfo::corrupted`]).
+    ///
+    /// This favors showing video quickly over showing it without artifacts. Only the H.264 and
+    /// H.265 decoders support it, the other ones behave as with [`ResumePolicy::KeyFrame`].
+    IntraFrame,
+}
+
+/// How strictly a [`StatelessDecoder`] enforces the specification of the stream.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Strictness {
+    /// Reject any violation of the specification that the decoder detects, by failing with
+    /// [`DecodeError::SpecViolation`]. This is useful to validation tools.
+    Strict,
+    /// Accept the common real-world deviations from the specification that the decoder knows how
+    /// to repair, e.g. more H.264 reference frames than the declared `max_num_ref_frames`, or
+    /// trailing garbage after the OBUs of an AV1 temporal unit. Each repaired violation is
+    /// reported with a [`DecoderEvent::NonFatalError`] event.
+    #[default]
+    Permissive,
+}
+
+/// Limits that a stream must respect in order to be accepted by a [`StatelessDecoder`].
+///
+/// Streams exceeding the DPB size or resolution limits are rejected at negotiation time, before
+/// any resources are allocated for them. The slice and tile limits are checked as pictures are
+/// parsed, before they are submitted to the backend.
+///
+/// By default the coded resolution is limited to 8192x8192, so a hostile header cannot make the
+/// decoder allocate gigabytes of frames, and the other values are not limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderLimits {
+    /// Maximum number of frames the stream's DPB is allowed to hold, or `None` for no limit.
+    ///
+    /// The DPB size counts the reference frames plus, for codecs that reorder frames, the frames
+    /// held until they can be output. It does not include the frame being decoded nor the frames
+    /// waiting to be returned to the client, i.e. it is the `dpb_size` member of the
+    /// [`crate::decoder::NumFramesBreakdown`] of the stream. H.264 and H.265 use the DPB size
+    /// signaled by the stream, or derived from its level, capped at 16 frames. VP8, VP9 and AV1
+    /// use their fixed number of reference frame slots.
+    pub max_dpb_size: Option<usize>,
+    /// Maximum coded resolution of the stream, or `None` for no limit.
+    pub max_coded_resolution: Option<Resolution>,
+    /// Maximum number of slices (slice segments for H.265) in a picture, or `None` for no limit.
+    /// Only applies to H.264 and H.265.
+    pub max_num_slices: Option<usize>,
+    /// Maximum number of tiles in a frame, or `None` for no limit. Only applies to H.265, VP9 and
+    /// AV1.
+    pub max_num_tiles: Option<usize>,
+}
+
+impl Default for DecoderLimits {
+    fn default() -> Self {
+        Self {
+            max_dpb_size: None,
+            max_coded_resolution: Some(Resolution::from((8192, 8192))),
+            max_num_slices: None,
+            max_num_tiles: None,
+        }
+    }
+}
+
+/// Statistics about the operation of a [`StatelessDecoder`].
+///
+/// The output buffers statistics can help clients size their frame pools according to actual
+/// usage.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DecoderStats {
+    /// Number of times decoding stalled because no output buffer was available.
+    pub num_output_stalls: u64,
+    /// Total time spent waiting for output buffers to become available.
+    pub total_output_stall_time: Duration,
+    /// Longest time spent waiting for an output buffer to become available.
+    pub max_output_stall_time: Duration,
+    /// Number of NAL units that were skipped because the decoder does not support their type,
+    /// e.g. prefix, auxiliary picture or reserved NAL units, or because they are not needed for
+    /// decoding, e.g. filler data.
+    pub num_skipped_nalus: u64,
+    /// Number of SEI NAL units that were ignored because they could not be parsed.
+    pub num_invalid_seis: u64,
+    /// Total number of bytes of input that were ignored, see [`SkipReason`].
+    pub num_skipped_bytes: u64,
+    /// Number of frames whose content differed between two readbacks, when output verification
+    /// is enabled.
+    pub num_readback_mismatches: u64,
+}
+
+/// Coding type of a frame, as accounted for in [`BitstreamStats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FrameCodingType {
+    /// The frame is only predicted from itself, e.g. H.264 I pictures or VP8 key frames.
+    Intra,
+    /// The frame can be predicted from other frames.
+    Inter,
+    /// The frame can be predicted from two reference frames at once. Only H.264 and H.265 B
+    /// pictures are accounted as such, other codecs report all their predicted frames as
+    /// [`FrameCodingType::Inter`].
+    Bidirectional,
+}
+
+/// Sizes of the coded frames of a given type.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FrameSizeStats {
+    /// Number of frames.
+    pub num_frames: u64,
+    /// Total size of the frames, in bytes.
+    pub total_bytes: u64,
+    /// Size of the smallest frame, in bytes.
+    pub min_bytes: u64,
+    /// Size of the largest frame, in bytes.
+    pub max_bytes: u64,
+}
+
+impl FrameSizeStats {
+    fn add(&mut self, size: u64) {
+        if self.num_frames == 0 {
+            self.min_bytes = size;
+        }
+        self.num_frames += 1;
+        self.total_bytes += size;
+        self.min_bytes = std::cmp::min(self.min_bytes, size);
+        self.max_bytes = std::cmp::max(self.max_bytes, size);
+    }
+
+    /// Returns the average size of the frames, in bytes.
+    pub fn average_bytes(&self) -> Option<u64> {
+        self.total_bytes.checked_div(self.num_frames)
+    }
+}
+
+/// Statistics about the stream decoded by a [`StatelessDecoder`], collected as it is parsed so
+/// they can be used e.g. for quality of experience monitoring without parsing the stream a second
+/// time. See [`StatelessDecoder::set_bitstream_stats_interval`].
+///
+/// Frame sizes only include the data the frames are decoded from, e.g. the slices of H.264 and
+/// H.265 pictures, not the parameter sets or SEI messages.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BitstreamStats {
+    /// Width, in units of timestamps, of the intervals over which `bytes_per_interval` is
+    /// accumulated.
+    pub interval: u64,
+    /// Number of bytes of coded frames received during each interval, keyed by the first
+    /// timestamp of the interval. Dividing it by the duration of the interval, e.g. using the
+    /// decoder's [`TimeBase`], gives the bitrate of the stream over time.
+    pub bytes_per_interval: BTreeMap<u64, u64>,
+    /// Sizes of the intra frames.
+    pub intra_frames: FrameSizeStats,
+    /// Sizes of the inter frames.
+    pub inter_frames: FrameSizeStats,
+    /// Sizes of the bidirectional frames.
+    pub bidirectional_frames: FrameSizeStats,
+    /// Number of groups of pictures of each length, a group of pictures being made of an intra
+    /// frame and the frames following it in decoding order until the next intra frame. The group
+    /// currently being received is not accounted for.
+    pub gop_lengths: BTreeMap<u64, u64>,
+    /// Number of frames per quantization parameter. The parameter is the one of the first slice
+    /// of the frame for H.264 and H.265, and the base quantizer index for the other codecs.
+    pub qp_histogram: BTreeMap<i32, u64>,
+    /// Number of frames received since the last intra frame, if one has been received.
+    current_gop_length: Option<u64>,
+}
+
+impl BitstreamStats {
+    fn new(interval: u64) -> Self {
+        Self {
+            interval,
+            ..Default::default()
+        }
+    }
+
+    /// Accounts for a frame of `size` bytes and type `coding_type`, quantized with `qp`.
+    fn add_frame(&mut self, timestamp: u64, coding_type: FrameCodingType, size: usize, qp: i32) {
+        let size = size as u64;
+
+        let interval_start = timestamp - timestamp % self.interval;
+        *self.bytes_per_interval.entry(interval_start).or_default() += size;
+
+        match coding_type {
+            FrameCodingType::Intra => self.intra_frames.add(size),
+            FrameCodingType::Inter => self.inter_frames.add(size),
+            FrameCodingType::Bidirectional => self.bidirectional_frames.add(size),
+        }
+
+        if coding_type == FrameCodingType::Intra {
+            if let Some(length) = self.current_gop_length.replace(1) {
+                *self.gop_lengths.entry(length).or_default() += 1;
+            }
+        } else if let Some(length) = self.current_gop_length.as_mut() {
+            *length += 1;
+        }
+
+        *self.qp_histogram.entry(qp).or_default() += 1;
+    }
+}
+
+/// Maximum number of inputs whose submission time is remembered while their frame has not been
+/// output, so inputs that never produce a frame do not accumulate.
+const MAX_TRACKED_INPUTS: usize = 256;
+
+/// Timing of one frame through a [`StatelessDecoder`], see [`LatencyStats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLatency {
+    /// Timestamp of the frame.
+    pub timestamp: u64,
+    /// Time between the first submission of the frame's input and the completion of its decoding,
+    /// if the decoder observed it. Frames retrieved in non-blocking mode before they are ready
+    /// complete outside of the decoder's sight.
+    pub decode: Option<Duration>,
+    /// Time between the first submission of the frame's input and its retrieval by the client.
+    pub total: Duration,
+}
+
+/// Percentiles of a set of latencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    /// Number of latencies the percentiles are computed from.
+    pub num_samples: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyPercentiles {
+    /// Computes the percentiles of `samples`, or returns `None` if there are none.
+    fn new(mut samples: Vec<Duration>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+
+        Some(Self {
+            num_samples: samples.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+/// Latencies of the frames output by a [`StatelessDecoder`], measured from the submission of
+/// their input to their retrieval by the client. See [`StatelessDecoder::set_latency_tracking`].
+#[derive(Debug, Default, Clone)]
+pub struct LatencyStats {
+    /// Timing of every frame output since tracking was enabled, in output order.
+    pub frames: Vec<FrameLatency>,
+    /// Instant at which the input of each frame that has not been output yet was first submitted.
+    submitted: BTreeMap<u64, Instant>,
+    /// Instant at which each frame that has not been output yet was observed to be complete.
+    completed: BTreeMap<u64, Instant>,
+}
+
+impl LatencyStats {
+    /// Remembers that input with `timestamp` has been submitted, unless it already was.
+    fn input_submitted(&mut self, timestamp: u64) {
+        self.submitted.entry(timestamp).or_insert_with(Instant::now);
+        while self.submitted.len() > MAX_TRACKED_INPUTS {
+            self.submitted.pop_first();
+        }
+    }
+
+    /// Remembers that the frame with `timestamp` has completed, unless it already was.
+    fn frame_completed(&mut self, timestamp: u64) {
+        if self.submitted.contains_key(&timestamp) {
+            self.completed.entry(timestamp).or_insert_with(Instant::now);
+        }
+    }
+
+    /// Accounts for the retrieval of the frame with `timestamp`.
+    fn frame_retrieved(&mut self, timestamp: u64) {
+        let completed = self.completed.remove(&timestamp);
+        let Some(submitted) = self.submitted.remove(&timestamp) else {
+            return;
+        };
+
+        self.frames.push(FrameLatency {
+            timestamp,
+            decode: completed.map(|completed| completed - submitted),
+            total: submitted.elapsed(),
+        });
+    }
+
+    /// Returns the percentiles of the time between the submission of the frames and the
+    /// completion of their decoding.
+    pub fn decode_latency(&self) -> Option<LatencyPercentiles> {
+        LatencyPercentiles::new(self.frames.iter().filter_map(|f| f.decode).collect())
+    }
+
+    /// Returns the percentiles of the time between the completion of the frames and their
+    /// retrieval by the client, i.e. how long decoded frames wait in the decoder.
+    pub fn retrieval_latency(&self) -> Option<LatencyPercentiles> {
+        LatencyPercentiles::new(
+            self.frames
+                .iter()
+                .filter_map(|f| f.decode.map(|decode| f.total - decode))
+                .collect(),
+        )
+    }
+
+    /// Returns the percentiles of the time between the submission of the frames and their
+    /// retrieval by the client.
+    pub fn total_latency(&self) -> Option<LatencyPercentiles> {
+        LatencyPercentiles::new(self.frames.iter().map(|f| f.total).collect())
+    }
+}
+
+/// Notification waiting to be reported to the client through
+/// [`StatelessVideoDecoder::next_event`].
+///
+/// This only holds the events that do not borrow the decoder, so they can be queued.
+enum PendingEvent {
+    OutputStalled(Duration),
+    ResolutionChanged(Resolution),
+    ColorimetryChanged(Colorimetry),
+    FrameDropped(u64),
+    NonFatalError(u64, anyhow::Error),
+    DeviceReset,
+    DataSkipped(SkippedData),
+}
+
+impl PendingEvent {
+    fn into_event<'a, M>(self) -> DecoderEvent<'a, M> {
+        match self {
+            PendingEvent::OutputStalled(duration) => DecoderEvent::OutputStalled(duration),
+            PendingEvent::ResolutionChanged(resolution) => {
+                DecoderEvent::ResolutionChanged(resolution)
+            }
+            PendingEvent::ColorimetryChanged(colorimetry) => {
+                DecoderEvent::ColorimetryChanged(colorimetry)
+            }
+            PendingEvent::FrameDropped(timestamp) => DecoderEvent::FrameDropped(timestamp),
+            PendingEvent::NonFatalError(timestamp, error) => {
+                DecoderEvent::NonFatalError(timestamp, error)
+            }
+            PendingEvent::DeviceReset => DecoderEvent::DeviceReset,
+            PendingEvent::DataSkipped(data) => DecoderEvent::DataSkipped(data),
+        }
+    }
+}
+
+/// Syncs `handle`, reads its content back and returns its hash.
+fn readback_hash<H: DecodedHandle>(handle: &H) -> anyhow::Result<u64> {
+    handle.sync()?;
+
+    let picture = handle.dyn_picture();
+    let mut mappable = picture.dyn_mappable_handle()?;
+    let mut buffer = vec![0; mappable.image_size()?];
+    mappable.read(&mut buffer)?;
+
+    let mut hasher = DefaultHasher::new();
+    buffer.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
 mod private {
     use super::*;
 
@@ -82,7 +524,7 @@ mod private {
     pub(super) trait StatelessVideoDecoder {
         /// Try to apply `format` to output frames. If successful, all frames emitted after the
         /// call will be in the new format.
-        fn try_format(&mut self, format: DecodedFormat) -> anyhow::Result<()>;
+        fn try_format(&mut self, format: DecodedFormat) -> Result<(), FormatError>;
     }
 }
 
@@ -120,7 +562,145 @@ pub trait StatelessDecoderBackend<Codec: StatelessCodec>:
         &mut self,
         format_info: &Codec::FormatInfo,
         format: DecodedFormat,
-    ) -> anyhow::Result<()>;
+    ) -> Result<(), FormatError>;
+
+    /// Try switching to the resolution of `format_info` while keeping the current frames.
+    ///
+    /// This is possible when the frames are large enough and the other stream parameters are
+    /// unchanged. On success the display resolution of the stream info is updated and `true` is
+    /// returned, otherwise a full renegotiation through `new_sequence` is required.
+    fn try_change_resolution(&mut self, format_info: &Codec::FormatInfo) -> bool;
+
+    /// Returns a function blocking until `handle`, which has just been submitted, is decoded, and
+    /// that can be called from any thread.
+    ///
+    /// The default implementation returns `None`, for backends that can only sync their handles
+    /// from the decoding thread.
+    fn completion_waiter(&self, _handle: &Self::Handle) -> Option<CompletionWaiter> {
+        None
+    }
+
+    /// Tears down and recreates the hardware resources of the backend after it has reported
+    /// [`StatelessBackendError::DeviceLost`].
+    ///
+    /// The backend returns to the state it was in before the first `new_sequence`, and the frames
+    /// of its pool are released: new ones are provided by the client during the next format
+    /// negotiation. The default implementation fails, for backends that cannot lose their device.
+    fn recover(&mut self) -> anyhow::Result<()> {
+        Err(anyhow!(
+            "backend cannot recover from the loss of its device"
+        ))
+    }
+}
+
+/// Converts an error returned by a [`DecodedHandle`] into a [`DecodeError`], preserving the
+/// backend errors so the decoder can e.g. recover from the loss of the device.
+fn handle_error(error: anyhow::Error) -> DecodeError {
+    match error.downcast::<StatelessBackendError>() {
+        Ok(error) => DecodeError::BackendError(error),
+        Err(error) => DecodeError::DecoderError(error),
+    }
+}
+
+/// Processes all the units contained in `bitstream` by calling `decode_unit` on its remaining
+/// part, along with the offset of that part, until it is entirely consumed. `decode_unit` must
+/// process a single unit and return the number of bytes it took.
+///
+/// If decoding needs to pause after some units have been processed, the number of bytes processed
+/// so far is returned so the caller can resubmit the rest later, at which point it will receive
+/// the error.
+fn decode_units<F>(bitstream: &[u8], mut decode_unit: F) -> Result<usize, DecodeError>
+where
+    F: FnMut(usize, &[u8]) -> Result<usize, DecodeError>,
+{
+    let mut consumed = 0;
+
+    while consumed < bitstream.len() {
+        match decode_unit(consumed, &bitstream[consumed..]) {
+            Ok(0) => break,
+            Ok(len) => consumed += len,
+            Err(DecodeError::CheckEvents | DecodeError::NotEnoughOutputBuffers(_))
+                if consumed > 0 =>
+            {
+                break
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(consumed)
+}
+
+/// Returns the length of the leading part of `bitstream` that is made of complete Annex B NAL
+/// units.
+///
+/// The end of a NAL unit is only known once the start code of the next one is seen, so this is
+/// the offset of the last start code of `bitstream`, or zero if it contains less than two of them.
+fn complete_nalus_len(bitstream: &[u8]) -> usize {
+    let last_start_code = bitstream
+        .windows(3)
+        .enumerate()
+        .filter(|(_, window)| *window == [0, 0, 1])
+        .map(|(pos, _)| pos)
+        .skip(1)
+        .last();
+
+    match last_start_code {
+        // Include the leading zero byte of 4-byte start codes.
+        Some(pos) if pos > 0 && bitstream[pos - 1] == 0 => pos - 1,
+        Some(pos) => pos,
+        None => 0,
+    }
+}
+
+/// Decodes `bitstream`, made of NAL units framed as `framing`, by passing it to `decode` after
+/// converting it to Annex B if needed. `decode` returns the number of bytes of Annex B it consumed,
+/// and this returns the corresponding number of bytes of `bitstream`.
+///
+/// If `partial` is set, only the complete NAL units of `bitstream` are decoded.
+fn decode_framed_nalus<F>(
+    framing: NalFraming,
+    bitstream: &[u8],
+    partial: bool,
+    decode: F,
+) -> Result<usize, DecodeError>
+where
+    F: FnOnce(&[u8]) -> Result<usize, DecodeError>,
+{
+    let length_size = match framing {
+        NalFraming::AnnexB if partial => {
+            return decode(&bitstream[..complete_nalus_len(bitstream)])
+        }
+        NalFraming::AnnexB => return decode(bitstream),
+        NalFraming::LengthPrefixed(length_size) => length_size,
+    };
+
+    let nalus = nal_framing::length_prefixed_nalus(bitstream, length_size)?;
+    let complete_len = nalus.last().map_or(0, |(_, end)| *end);
+    if !partial && complete_len != bitstream.len() {
+        return Err(DecodeError::DecoderError(anyhow!(
+            "truncated NAL unit at offset {}",
+            complete_len
+        )));
+    }
+
+    // Offset at which each NAL unit starts in the Annex B stream, and ends in `bitstream`.
+    let mut annexb = Vec::with_capacity(complete_len + 4 * nalus.len());
+    let mut offsets = Vec::with_capacity(nalus.len());
+    for (nalu, end) in nalus {
+        annexb.extend_from_slice(&[0, 0, 0, 1]);
+        offsets.push((annexb.len(), end));
+        annexb.extend_from_slice(nalu);
+    }
+
+    // `decode` stops at NAL unit boundaries, so the NAL units it consumed are the ones it went
+    // past the start of.
+    let consumed = decode(&annexb)?;
+    Ok(offsets
+        .iter()
+        .take_while(|(start, _)| *start < consumed)
+        .last()
+        .map_or(0, |(_, end)| *end))
 }
 
 /// Helper to implement [`DecoderFormatNegotiator`] for stateless decoders.
@@ -167,7 +747,7 @@ where
 {
     /// Try to apply `format` to output frames. If successful, all frames emitted after the
     /// call will be in the new format.
-    fn try_format(&mut self, format: DecodedFormat) -> anyhow::Result<()> {
+    fn try_format(&mut self, format: DecodedFormat) -> Result<(), FormatError> {
         self.decoder.try_format(format)
     }
 
@@ -214,12 +794,29 @@ pub trait StatelessVideoDecoder<M> {
     /// been addressed, the client is responsible for calling this method again with the same data.
     ///
     /// The return value is the number of bytes in `bitstream` that have been processed. Usually
-    /// this will be equal to the length of `bitstream`, but some codecs may only do partial
-    /// processing if e.g. several units are sent at the same time. It is the responsibility of the
-    /// caller to check that all submitted input has been processed, and to resubmit the
-    /// unprocessed part if it hasn't. See the documentation of each codec for their expectations.
+    /// this will be equal to the length of `bitstream`, but codecs accepting several units at the
+    /// same time may stop before the end of the input, e.g. if events need to be processed before
+    /// decoding can continue. It is the responsibility of the caller to check that all submitted
+    /// input has been processed, and to resubmit the unprocessed part if it hasn't. See the
+    /// documentation of each codec for their expectations.
     fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError>;
 
+    /// Same as [`decode`], but `bitstream` is allowed to end with an incomplete unit, which is
+    /// left unprocessed.
+    ///
+    /// This is useful for clients feeding the decoder from a ring buffer or a socket, without
+    /// knowing where units end: they can submit whatever data they have, drop the number of bytes
+    /// returned, and submit the rest again along with the next data they receive. Once the end of
+    /// the stream is reached, the remaining data must be submitted using [`decode`].
+    ///
+    /// The default implementation is only valid for codecs that process whole frames, for which
+    /// the input has to be framed anyway, and just calls [`decode`].
+    ///
+    /// [`decode`]: StatelessVideoDecoder::decode
+    fn decode_partial(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        self.decode(timestamp, bitstream)
+    }
+
     /// Flush the decoder i.e. finish processing all pending decode requests and make sure the
     /// resulting frames are ready to be retrieved via [`next_event`].
     ///
@@ -228,14 +825,53 @@ pub trait StatelessVideoDecoder<M> {
     /// [`next_event`]: StatelessVideoDecoder::next_event
     fn flush(&mut self) -> Result<(), DecodeError>;
 
+    /// Abandon all pending work, e.g. when seeking or before tearing the decoder down.
+    ///
+    /// Unlike [`flush`], the picture being decoded is not submitted and the frames that have not
+    /// been retrieved through [`next_event`] are dropped without being waited for, so this
+    /// returns immediately. Frames already held by the client are not affected. As after a
+    /// flush, a key frame must be submitted before decoding can resume.
+    ///
+    /// [`flush`]: StatelessVideoDecoder::flush
+    /// [`next_event`]: StatelessVideoDecoder::next_event
+    fn reset(&mut self);
+
     /// Returns the frame pool in use with the decoder. Useful to add new frames as decode.
     /// targets.
     fn frame_pool(&mut self) -> &mut dyn FramePool<M>;
 
     fn stream_info(&self) -> Option<&StreamInfo>;
 
+    /// Returns whether [`decode`] can currently make progress.
+    ///
+    /// This returns `false` if a format change is awaiting acknowledgement, or if no output
+    /// resources are available and none can be reclaimed, i.e. in the cases where [`decode`] is known to return
+    /// [`DecodeError::CheckEvents`] or [`DecodeError::NotEnoughOutputBuffers`] without processing
+    /// any input. Event loops can use it to wait for events or returned frames before submitting
+    /// input, instead of submitting it and re-queueing it on failure.
+    ///
+    /// Returning `true` does not guarantee that [`decode`] will process all the input, as a unit
+    /// may require more than one output frame, or trigger a format change itself.
+    ///
+    /// [`decode`]: StatelessVideoDecoder::decode
+    fn ready_for_input(&mut self) -> bool;
+
     /// Returns the next event, if there is any pending.
     fn next_event(&mut self) -> Option<DecoderEvent<M>>;
+
+    /// Returns a file descriptor that is readable while events are pending, so the decoder can be
+    /// integrated into `epoll` or `mio`-based event loops.
+    ///
+    /// The descriptor becomes readable when a call to [`decode`], [`flush`] or [`reset`] leaves
+    /// events pending, and stops being readable once [`next_event`] has returned `None`. It is
+    /// created on the first call and remains the same for the lifetime of the decoder. It must only
+    /// be polled for readability, never read from.
+    ///
+    /// [`decode`]: StatelessVideoDecoder::decode
+    /// [`flush`]: StatelessVideoDecoder::flush
+    /// [`reset`]: StatelessVideoDecoder::reset
+    /// [`next_event`]: StatelessVideoDecoder::next_event
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>>;
 }
 
 pub trait StatelessCodec {
@@ -247,6 +883,40 @@ pub trait StatelessCodec {
     type DecoderState<B: StatelessDecoderBackend<Self>>;
 }
 
+/// Socket pair whose reader holds one byte while the decoder has events pending.
+pub(crate) struct ReadinessNotifier {
+    pub(crate) reader: UnixStream,
+    writer: UnixStream,
+    signaled: bool,
+}
+
+impl ReadinessNotifier {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+
+        Ok(Self {
+            reader,
+            writer,
+            signaled: false,
+        })
+    }
+
+    /// Makes the reader readable if `ready` is `true`, or drains it otherwise.
+    pub(crate) fn set(&mut self, ready: bool) -> std::io::Result<()> {
+        match (self.signaled, ready) {
+            (false, true) => self.writer.write_all(&[0])?,
+            (true, false) => {
+                self.reader.read_exact(&mut [0])?;
+            }
+            _ => return Ok(()),
+        }
+        self.signaled = ready;
+
+        Ok(())
+    }
+}
+
 /// A struct that serves as a basis to implement a stateless decoder.
 ///
 /// A stateless decoder is defined by three generic parameters:
@@ -277,7 +947,65 @@ where
     /// Whether the decoder should block on decode operations.
     blocking_mode: BlockingMode,
 
-    ready_queue: ReadyFramesQueue<B::Handle>,
+    /// Maximum time to wait for a picture to complete in blocking mode, if any.
+    sync_timeout: Option<Duration>,
+
+    /// Whether frames should be output as soon as they are decoded, without waiting for the DPB
+    /// to bump them. Only valid for streams that do not reorder frames.
+    low_latency: bool,
+
+    /// Time base of the timestamps passed to the decoder, if known.
+    time_base: Option<TimeBase>,
+
+    /// What to do when output buffers are exhausted.
+    output_exhaustion_policy: OutputExhaustionPolicy,
+
+    /// Which frames decoding can resume from after a flush.
+    resume_policy: ResumePolicy,
+
+    /// How deviations from the specification are handled.
+    strictness: Strictness,
+
+    /// Limits that the stream must respect to be decoded.
+    limits: DecoderLimits,
+
+    /// How the NAL units of H.264 and H.265 input are delimited.
+    input_framing: NalFraming,
+
+    /// Output buffers usage statistics.
+    stats: DecoderStats,
+
+    /// Whether ignored input is reported with [`DecoderEvent::DataSkipped`] events.
+    report_skipped_data: bool,
+
+    /// Statistics about the decoded stream, if their collection is enabled.
+    bitstream_stats: Option<BitstreamStats>,
+
+    /// Whether the content of output frames is verified before they are returned.
+    verify_output: bool,
+
+    /// Latencies of the output frames, if their tracking is enabled.
+    latency_stats: Option<LatencyStats>,
+
+    /// Instant at which decoding stalled because of a lack of output buffers, if it is currently
+    /// stalled.
+    output_stall_start: Option<Instant>,
+
+    /// Events waiting to be reported to the client, in the order they happened.
+    pending_events: VecDeque<PendingEvent>,
+
+    /// Signals pending events on a file descriptor, once the client has requested it.
+    readiness: Option<ReadinessNotifier>,
+
+    /// Thread invoking the completion callback, if one is set. Declared before `backend` so it
+    /// is dropped, and the waiters it runs return, before the backend goes away.
+    completion: Option<CompletionThread>,
+
+    /// Display resolution and colorimetry of the current stream, as last applied after format
+    /// negotiation.
+    stream_params: Option<(Resolution, Colorimetry)>,
+
+    ready_queue: ReadyFramesQueue<ReadyFrame<B::Handle>>,
 
     decoding_state: DecodingState<C::FormatInfo>,
 
@@ -298,6 +1026,24 @@ where
         Self {
             backend,
             blocking_mode,
+            sync_timeout: None,
+            low_latency: false,
+            time_base: None,
+            output_exhaustion_policy: Default::default(),
+            resume_policy: Default::default(),
+            strictness: Default::default(),
+            limits: Default::default(),
+            input_framing: Default::default(),
+            stats: Default::default(),
+            report_skipped_data: false,
+            bitstream_stats: None,
+            verify_output: false,
+            latency_stats: None,
+            output_stall_start: None,
+            pending_events: Default::default(),
+            readiness: None,
+            completion: None,
+            stream_params: None,
             coded_resolution: Default::default(),
             decoding_state: Default::default(),
             ready_queue: Default::default(),
@@ -318,6 +1064,572 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn ready_for_input(&mut self) -> bool {
+        match self.decoding_state {
+            DecodingState::AwaitingFormat(_) => false,
+            DecodingState::Decoding => {
+                self.backend.frame_pool().num_free_frames() > 0
+                    || (self.output_exhaustion_policy == OutputExhaustionPolicy::DropOldest
+                        && !self.ready_queue.is_empty())
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns whether the decoder currently blocks on decode operations.
+    pub fn blocking_mode(&self) -> BlockingMode {
+        self.blocking_mode
+    }
+
+    /// Switch between blocking and non-blocking operation, e.g. to block during the preroll
+    /// following a seek and stop blocking once steady-state playback is reached.
+    ///
+    /// The new mode applies to the frames submitted after this call. When switching to
+    /// [`BlockingMode::Blocking`], this also waits for the frames already waiting to be retrieved
+    /// through [`StatelessVideoDecoder::next_event`], so they can be used right away. Frames that
+    /// are still held by the decoder for reordering are not waited for.
+    pub fn set_blocking_mode(&mut self, blocking_mode: BlockingMode) -> anyhow::Result<()> {
+        if blocking_mode == BlockingMode::Blocking && self.blocking_mode != blocking_mode {
+            for frame in self.ready_queue.iter() {
+                frame.sync()?;
+            }
+        }
+
+        self.blocking_mode = blocking_mode;
+
+        Ok(())
+    }
+
+    /// Set the maximum time to wait for a submitted picture to complete in
+    /// [`BlockingMode::Blocking`], or `None` to wait for as long as it takes.
+    ///
+    /// This is a watchdog against buggy drivers that never complete some pictures: instead of
+    /// hanging the calling thread, a picture that is not ready in time is flagged as corrupted and
+    /// reported with a [`DecoderEvent::NonFatalError`] event, and decoding carries on without
+    /// waiting for it. Syncing the frame later may still hang if the device never completes it.
+    ///
+    /// The timeout has no effect in [`BlockingMode::NonBlocking`], where the decoder never waits
+    /// for the pictures it submits: clients wait for them with their own deadline instead, e.g.
+    /// using [`StatelessDecoder::poll_timeout`].
+    pub fn set_sync_timeout(&mut self, timeout: Option<Duration>) {
+        self.sync_timeout = timeout;
+    }
+
+    /// Enable or disable low-latency mode.
+    ///
+    /// In low-latency mode, every frame is made available through
+    /// [`StatelessVideoDecoder::next_event`] as soon as it has been submitted, instead of waiting
+    /// for the DPB bumping process to output it. This is only valid for streams that never reorder
+    /// frames, like all-intra or IPPP streams: if a frame is found to come before an already
+    /// output frame in display order, decoding fails with
+    /// [`DecodeError::ReorderingInLowLatencyMode`].
+    ///
+    /// This setting only affects codecs that can reorder frames, i.e. H.264 and H.265.
+    pub fn set_low_latency(&mut self, low_latency: bool) {
+        self.low_latency = low_latency;
+    }
+
+    /// Sets a callback to invoke on a dedicated thread as soon as each submitted picture has been
+    /// decoded by the hardware, or removes it if `callback` is `None`.
+    ///
+    /// This lets latency-critical clients learn about completion without polling. The callback
+    /// receives the timestamp of the picture, and is invoked in submission order, i.e. in decoding
+    /// order. With backends that cannot wait for completion from another thread, pictures are
+    /// synced on the decoding thread at submission time, as in [`BlockingMode::Blocking`], and the
+    /// callback is invoked right after.
+    ///
+    /// Removing or replacing the callback waits for the pictures already submitted to complete.
+    pub fn set_completion_callback(
+        &mut self,
+        callback: Option<CompletionCallback>,
+    ) -> anyhow::Result<()> {
+        self.completion = None;
+        self.completion = callback.map(CompletionThread::new).transpose()?;
+
+        Ok(())
+    }
+
+    /// Set the time base of the timestamps passed to [`StatelessVideoDecoder::decode`].
+    ///
+    /// The decoder does not interpret timestamps, but reports the time base through
+    /// [`DecodedHandle::time_base`] on the frames it outputs, which allows clients to convert
+    /// their timestamps using [`DecodedHandle::presentation_time`] or [`TimeBase::rescale`].
+    pub fn set_time_base(&mut self, time_base: Option<TimeBase>) {
+        self.time_base = time_base;
+    }
+
+    /// Returns the time base of the timestamps passed to this decoder, if one has been set.
+    pub fn time_base(&self) -> Option<TimeBase> {
+        self.time_base
+    }
+
+    /// Set what the decoder should do when it runs out of output buffers.
+    pub fn set_output_exhaustion_policy(&mut self, policy: OutputExhaustionPolicy) {
+        self.output_exhaustion_policy = policy;
+    }
+
+    /// Set which frames the decoder can resume decoding from after a flush.
+    pub fn set_resume_policy(&mut self, policy: ResumePolicy) {
+        self.resume_policy = policy;
+    }
+
+    /// Set how strictly the decoder enforces the specification of the stream.
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.strictness = strictness;
+    }
+
+    /// Set the limits that streams must respect in order to be decoded.
+    ///
+    /// This should be called right after the decoder is created. Any stream requiring a larger DPB
+    /// or coded resolution than allowed by `limits` will fail to decode with
+    /// [`DecodeError::DpbSizeLimitExceeded`] or [`DecodeError::ResolutionLimitExceeded`].
+    pub fn set_limits(&mut self, limits: DecoderLimits) {
+        self.limits = limits;
+    }
+
+    /// Set how the NAL units of the input are delimited, for the H.264 and H.265 decoders. Other
+    /// codecs ignore this setting.
+    ///
+    /// Length-prefixed input, e.g. from MP4 files, is converted to Annex B before being decoded.
+    /// Its parameter sets are usually stored out of band: they must be submitted first, e.g.
+    /// using [`crate::codec::nal_framing::AvcDecoderConfigurationRecord::to_annexb`] with this
+    /// setting temporarily set to [`NalFraming::AnnexB`].
+    pub fn set_input_framing(&mut self, framing: NalFraming) {
+        self.input_framing = framing;
+    }
+
+    /// Set whether the parts of the input that the decoder ignores, e.g. unsupported NAL units or
+    /// filler data, are reported with [`DecoderEvent::DataSkipped`] events.
+    ///
+    /// This helps diagnosing streams that do not decode as expected. Skipped data is counted in
+    /// the [`DecoderStats`] regardless of this setting.
+    pub fn set_report_skipped_data(&mut self, report: bool) {
+        self.report_skipped_data = report;
+    }
+
+    /// Set whether statistics about the decoded stream are collected, and if so the width, in
+    /// units of timestamps, of the intervals over which the bitrate is measured.
+    ///
+    /// Passing `None` disables the collection. Passing a new interval resets the statistics
+    /// collected so far.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn set_bitstream_stats_interval(&mut self, interval: Option<u64>) {
+        assert_ne!(interval, Some(0), "the bitrate interval cannot be empty");
+        self.bitstream_stats = interval.map(BitstreamStats::new);
+    }
+
+    /// Returns the statistics collected about the decoded stream, if their collection has been
+    /// enabled with [`StatelessDecoder::set_bitstream_stats_interval`].
+    pub fn bitstream_stats(&self) -> Option<&BitstreamStats> {
+        self.bitstream_stats.as_ref()
+    }
+
+    /// Accounts for a frame of `size` bytes received with `timestamp`, if bitstream statistics are
+    /// being collected.
+    fn frame_received(
+        &mut self,
+        timestamp: u64,
+        coding_type: FrameCodingType,
+        size: usize,
+        qp: i32,
+    ) {
+        if let Some(stats) = self.bitstream_stats.as_mut() {
+            stats.add_frame(timestamp, coding_type, size, qp);
+        }
+    }
+
+    /// Set whether the content of each frame is verified before it is returned to the client.
+    ///
+    /// In this mode the decoder reads every output frame back twice, syncing it again in between,
+    /// and compares the hashes of both reads. Frames whose content changed, e.g. because the
+    /// hardware signaled completion too early, are flagged as corrupted and reported with a
+    /// [`DecoderEvent::NonFatalError`] event. This is slow and only meant to qualify drivers and
+    /// hardware during bring-up.
+    pub fn set_verify_output(&mut self, verify: bool) {
+        self.verify_output = verify;
+    }
+
+    /// Set whether the latency of each frame is tracked, from the submission of its input to its
+    /// retrieval by the client through the completion of its decoding.
+    ///
+    /// Enabling tracking again discards the latencies tracked so far.
+    pub fn set_latency_tracking(&mut self, enabled: bool) {
+        self.latency_stats = enabled.then(Default::default);
+    }
+
+    /// Returns the latencies tracked since [`StatelessDecoder::set_latency_tracking`] has been
+    /// called, if tracking is enabled.
+    pub fn latency_stats(&self) -> Option<&LatencyStats> {
+        self.latency_stats.as_ref()
+    }
+
+    /// Remembers when input with `timestamp` has been submitted, if latencies are tracked.
+    fn input_submitted(&mut self, timestamp: u64) {
+        if let Some(stats) = self.latency_stats.as_mut() {
+            stats.input_submitted(timestamp);
+        }
+    }
+
+    /// Remembers when the frame with `timestamp` has been observed to be complete, if latencies
+    /// are tracked.
+    fn frame_completed(&mut self, timestamp: u64) {
+        if let Some(stats) = self.latency_stats.as_mut() {
+            stats.frame_completed(timestamp);
+        }
+    }
+
+    /// Checks that a stream with the given DPB size and coded resolution is within the limits set
+    /// by the client.
+    fn check_limits(
+        &self,
+        dpb_size: usize,
+        coded_resolution: Resolution,
+    ) -> Result<(), DecodeError> {
+        if let Some(max_dpb_size) = self.limits.max_dpb_size {
+            if dpb_size > max_dpb_size {
+                return Err(DecodeError::DpbSizeLimitExceeded(dpb_size, max_dpb_size));
+            }
+        }
+
+        if let Some(max_coded_resolution) = self.limits.max_coded_resolution {
+            if !max_coded_resolution.can_contain(coded_resolution) {
+                return Err(DecodeError::ResolutionLimitExceeded(
+                    coded_resolution,
+                    max_coded_resolution,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a picture made of `num_slices` slices respects the decoder limits.
+    fn check_num_slices(&self, num_slices: usize) -> Result<(), DecodeError> {
+        match self.limits.max_num_slices {
+            Some(max_num_slices) if num_slices > max_num_slices => {
+                Err(DecodeError::SliceLimitExceeded(num_slices, max_num_slices))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a frame made of `num_tiles` tiles respects the decoder limits.
+    fn check_num_tiles(&self, num_tiles: usize) -> Result<(), DecodeError> {
+        match self.limits.max_num_tiles {
+            Some(max_num_tiles) if num_tiles > max_num_tiles => {
+                Err(DecodeError::TileLimitExceeded(num_tiles, max_num_tiles))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the statistics of this decoder.
+    pub fn stats(&self) -> &DecoderStats {
+        &self.stats
+    }
+
+    /// Returns the number of free output buffers, after trying to make at least `num_needed` of
+    /// them available according to the output exhaustion policy.
+    fn num_free_frames(&mut self, num_needed: usize) -> usize {
+        let mut num_free = self.backend.frame_pool().num_free_frames();
+
+        if self.output_exhaustion_policy == OutputExhaustionPolicy::DropOldest {
+            while num_free < num_needed {
+                let Some(frame) = (&mut self.ready_queue).next() else {
+                    break;
+                };
+                let timestamp = frame.timestamp();
+                // Release the frame so its buffer can return to the pool.
+                drop(frame);
+                self.frame_dropped(timestamp);
+                num_free = self.backend.frame_pool().num_free_frames();
+            }
+        }
+
+        num_free
+    }
+
+    /// Returns the error signaling that `num_missing` more output buffers are needed, and starts
+    /// timing the stall if it is not already ongoing.
+    fn output_buffers_exhausted(&mut self, num_missing: usize) -> DecodeError {
+        self.output_stall_start.get_or_insert_with(Instant::now);
+        DecodeError::NotEnoughOutputBuffers(num_missing)
+    }
+
+    /// Signals that output buffers are available for decoding. If decoding was stalled, update
+    /// the statistics and queue a [`DecoderEvent::OutputStalled`] event.
+    fn output_buffers_available(&mut self) {
+        if let Some(stall_start) = self.output_stall_start.take() {
+            let waited = stall_start.elapsed();
+
+            self.stats.num_output_stalls += 1;
+            self.stats.total_output_stall_time += waited;
+            self.stats.max_output_stall_time =
+                std::cmp::max(self.stats.max_output_stall_time, waited);
+
+            self.pending_events
+                .push_back(PendingEvent::OutputStalled(waited));
+        }
+    }
+
+    /// Signals that the format of a new stream has been applied, and queues the
+    /// [`DecoderEvent::ResolutionChanged`] and [`DecoderEvent::ColorimetryChanged`] events if
+    /// its parameters differ from the ones of the previous stream.
+    fn stream_params_applied(&mut self, colorimetry: Colorimetry) {
+        let display_resolution = match self.backend.stream_info() {
+            Some(stream_info) => stream_info.display_resolution,
+            None => return,
+        };
+
+        if let Some((prev_resolution, prev_colorimetry)) = self.stream_params {
+            if display_resolution != prev_resolution {
+                self.pending_events
+                    .push_back(PendingEvent::ResolutionChanged(display_resolution));
+            }
+            if colorimetry != prev_colorimetry {
+                self.pending_events
+                    .push_back(PendingEvent::ColorimetryChanged(colorimetry));
+            }
+        }
+
+        self.stream_params = Some((display_resolution, colorimetry));
+    }
+
+    /// Prepares `frame` to be returned to the client, verifying its content first if requested.
+    fn output_frame(&mut self, frame: ReadyFrame<B::Handle>) -> ReadyFrame<B::Handle> {
+        let mut frame = frame.with_time_base(self.time_base);
+
+        if let Some(stats) = self.latency_stats.as_mut() {
+            let timestamp = frame.timestamp();
+            if frame.is_ready() {
+                stats.frame_completed(timestamp);
+            }
+            stats.frame_retrieved(timestamp);
+        }
+
+        if self.verify_output {
+            let timestamp = frame.timestamp();
+            match readback_hash(&frame).and_then(|hash| Ok((hash, readback_hash(&frame)?))) {
+                Ok((first, second)) if first != second => {
+                    self.stats.num_readback_mismatches += 1;
+                    frame.info.corrupted = true;
+                    self.non_fatal_error(
+                        timestamp,
+                        anyhow!("frame content changed between two readbacks"),
+                    );
+                }
+                Ok(_) => (),
+                Err(e) => self.non_fatal_error(
+                    timestamp,
+                    e.context("failed to read the frame back for verification"),
+                ),
+            }
+        }
+
+        frame
+    }
+
+    /// Signals that the frame with `timestamp` has been dropped.
+    fn frame_dropped(&mut self, timestamp: u64) {
+        self.pending_events
+            .push_back(PendingEvent::FrameDropped(timestamp));
+    }
+
+    /// Signals that `error` occurred while decoding the frame with `timestamp`, but that decoding
+    /// can continue.
+    fn non_fatal_error(&mut self, timestamp: u64, error: anyhow::Error) {
+        log::warn!("non-fatal error on frame {}: {:#}", timestamp, error);
+        self.pending_events
+            .push_back(PendingEvent::NonFatalError(timestamp, error));
+    }
+
+    /// Signals that the bytes at `range` of the input with `timestamp` have been ignored for
+    /// `reason`.
+    fn data_skipped(&mut self, timestamp: u64, range: Range<usize>, reason: SkipReason) {
+        log::debug!(
+            "skipping bytes {:?} of frame {}: {:?}",
+            range,
+            timestamp,
+            reason
+        );
+        self.stats.num_skipped_bytes += range.len() as u64;
+        if self.report_skipped_data {
+            self.pending_events
+                .push_back(PendingEvent::DataSkipped(SkippedData {
+                    timestamp,
+                    range,
+                    reason,
+                }));
+        }
+    }
+
+    /// Signals that the frame with `timestamp` violates the specification as described by `error`.
+    ///
+    /// In [`Strictness::Strict`] mode this returns the error to fail decoding with, otherwise the
+    /// violation is reported as a non-fatal error and the caller is expected to repair it.
+    fn spec_violation(&mut self, timestamp: u64, error: anyhow::Error) -> Result<(), DecodeError> {
+        match self.strictness {
+            Strictness::Strict => Err(DecodeError::SpecViolation(error)),
+            Strictness::Permissive => {
+                self.non_fatal_error(timestamp, error);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the events that can be retrieved without waiting for more than `timeout`.
+    ///
+    /// This waits, up to `timeout`, for the frames waiting to be retrieved to complete, and returns
+    /// the pending notifications followed by the frames that completed in time, in display order.
+    /// The frames that did not complete remain in the decoder, so this never returns a frame that
+    /// is not ready, even in [`BlockingMode::NonBlocking`]. Real-time pipelines can use it to bound
+    /// the time spent waiting for output, e.g. to the frame interval.
+    ///
+    /// Format changes are not reported by this method: once it returns no frame, use
+    /// [`StatelessVideoDecoder::next_event`] to check whether one is pending.
+    pub fn poll_timeout<'a>(
+        &mut self,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<DecoderEvent<'a, <B::Handle as DecodedHandle>::Descriptor>>>
+    where
+        B::Handle: 'static,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut events = std::iter::from_fn(|| self.take_pending_event()).collect::<Vec<_>>();
+
+        while let Some(frame) = self.ready_queue.front() {
+            if !frame.wait_ready(deadline.saturating_duration_since(Instant::now()))? {
+                break;
+            }
+            let timestamp = frame.timestamp();
+            self.frame_completed(timestamp);
+
+            // Cannot fail as we just checked that the queue is not empty.
+            let frame = (&mut self.ready_queue).next().unwrap();
+            events.push(DecoderEvent::FrameReady(Box::new(self.output_frame(frame))));
+        }
+        self.update_readiness();
+
+        Ok(events)
+    }
+
+    /// Waits for `handle`, which has just been submitted to the backend, to be decoded if the
+    /// decoder is in blocking mode.
+    ///
+    /// Returns `false` if the sync timeout elapsed before the picture completed, in which case
+    /// the error has been reported and the caller should flag the picture as corrupted.
+    fn sync_picture(&mut self, handle: &B::Handle) -> Result<bool, DecodeError> {
+        if self.blocking_mode != BlockingMode::Blocking {
+            return Ok(true);
+        }
+
+        let Some(timeout) = self.sync_timeout else {
+            handle.sync().map_err(handle_error)?;
+            self.frame_completed(handle.timestamp());
+            return Ok(true);
+        };
+
+        if handle.wait_ready(timeout)? {
+            // Complete the sync, e.g. for the backend to update its state.
+            handle.sync().map_err(handle_error)?;
+            self.frame_completed(handle.timestamp());
+            Ok(true)
+        } else {
+            self.non_fatal_error(
+                handle.timestamp(),
+                anyhow!(
+                    "picture did not complete within {:?}, the device may be hung",
+                    timeout
+                ),
+            );
+            Ok(false)
+        }
+    }
+
+    /// Recovers from the loss of the backend's device, e.g. after a GPU reset, if `res` reports
+    /// it.
+    ///
+    /// The backend recreates its resources and the decoder is cleared with `reset`, which must
+    /// also make the codec renegotiate its format at the next key frame. The loss is reported with
+    /// a [`DecoderEvent::DeviceReset`] event, and [`DecodeError::CheckEvents`] is returned so the
+    /// client retrieves it before submitting the input again.
+    fn recover_if_device_lost<T>(
+        &mut self,
+        res: Result<T, DecodeError>,
+        reset: impl FnOnce(&mut Self),
+    ) -> Result<T, DecodeError> {
+        if !matches!(
+            res,
+            Err(DecodeError::BackendError(StatelessBackendError::DeviceLost))
+        ) {
+            return res;
+        }
+
+        log::warn!("decoding device lost, recreating its resources");
+        reset(self);
+        self.backend.recover()?;
+        self.coded_resolution = Default::default();
+        self.pending_events.push_back(PendingEvent::DeviceReset);
+
+        Err(DecodeError::CheckEvents)
+    }
+
+    /// Hands `handle`, which has just been submitted to the backend, to the completion thread if a
+    /// completion callback is set.
+    fn picture_submitted(&self, handle: &B::Handle) -> anyhow::Result<()> {
+        let Some(completion) = &self.completion else {
+            return Ok(());
+        };
+
+        let waiter = match self.backend.completion_waiter(handle) {
+            Some(waiter) => waiter,
+            None => {
+                handle.sync()?;
+                Box::new(|| Ok(()))
+            }
+        };
+        completion.submit(handle.timestamp(), waiter);
+
+        Ok(())
+    }
+
+    /// Returns the readiness file descriptor, creating it if needed.
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+        if self.readiness.is_none() {
+            self.readiness = Some(ReadinessNotifier::new()?);
+            self.update_readiness();
+        }
+
+        // Cannot fail as we just created the notifier if it was missing.
+        Ok(self.readiness.as_ref().unwrap().reader.as_fd())
+    }
+
+    /// Updates the readiness file descriptor, if any, according to whether events are pending.
+    fn update_readiness(&mut self) {
+        let Some(readiness) = &mut self.readiness else {
+            return;
+        };
+
+        let ready = !self.pending_events.is_empty()
+            || !self.ready_queue.is_empty()
+            || matches!(self.decoding_state, DecodingState::AwaitingFormat(_));
+        if let Err(e) = readiness.set(ready) {
+            log::warn!("failed to update the readiness fd: {}", e);
+        }
+    }
+
+    /// Returns the oldest pending event that does not involve a frame or format change, if any.
+    fn take_pending_event<'a>(
+        &mut self,
+    ) -> Option<DecoderEvent<'a, <B::Handle as DecodedHandle>::Descriptor>> {
+        self.pending_events
+            .pop_front()
+            .map(PendingEvent::into_event)
+    }
 }
 
 impl<C, B> private::StatelessVideoDecoder for StatelessDecoder<C, B>
@@ -325,20 +1637,116 @@ where
     C: StatelessCodec,
     B: StatelessDecoderBackend<C>,
 {
-    fn try_format(&mut self, format: crate::DecodedFormat) -> anyhow::Result<()> {
+    fn try_format(&mut self, format: crate::DecodedFormat) -> Result<(), FormatError> {
         match &self.decoding_state {
             DecodingState::AwaitingFormat(sps) => self.backend.try_format(sps, format),
-            _ => Err(anyhow::anyhow!(
-                "current decoder state does not allow format change"
-            )),
+            _ => Err(FormatError::NotNegotiating),
         }
     }
 }
 
+#[cfg(feature = "vaapi")]
+impl<C, M> StatelessDecoder<C, crate::backend::vaapi::VaapiBackend<M>>
+where
+    C: StatelessCodec,
+    M: libva::SurfaceMemoryDescriptor + 'static,
+    crate::backend::vaapi::VaapiBackend<M>: StatelessDecoderBackend<C>,
+{
+    /// Decodes the stream within `session`, for encrypted content, or in the clear if `session`
+    /// is `None`.
+    ///
+    /// The frames decoded within a protected session cannot be mapped, and the parameters needed
+    /// to decrypt each of them must be given with [`Self::set_encryption_params`].
+    pub fn set_protected_session(
+        &mut self,
+        session: Option<std::rc::Rc<crate::backend::vaapi::protected::ProtectedSession>>,
+    ) -> anyhow::Result<()> {
+        self.backend.set_protected_session(session)
+    }
+
+    /// Sets the parameters needed to decrypt the frame with `timestamp`, which must be called
+    /// before the frame is passed to [`StatelessVideoDecoder::decode`]. Frames without parameters
+    /// are considered clear.
+    pub fn set_encryption_params(
+        &mut self,
+        timestamp: u64,
+        params: crate::backend::vaapi::protected::EncryptionParams,
+    ) -> anyhow::Result<()> {
+        self.backend.set_encryption_params(timestamp, params)
+    }
+
+    /// Sets how the driver should trade decoding speed for power consumption, if it supports it.
+    ///
+    /// The hint applies to the VA contexts created afterwards, so it should be set before the
+    /// first call to [`StatelessVideoDecoder::decode`].
+    pub fn set_power_hint(&mut self, hint: crate::backend::vaapi::PowerHint) {
+        self.backend.set_power_hint(hint)
+    }
+
+    /// Returns information about the VA driver in use, including the quirks the decoder works
+    /// around.
+    pub fn driver_info(&self) -> &crate::backend::vaapi::driver::DriverInfo {
+        self.backend.driver_info()
+    }
+
+    /// Starts recording the contents of the buffers submitted to VA-API for each picture,
+    /// dropping the trace in progress if any. See [`crate::backend::trace`].
+    pub fn start_va_trace(&mut self) {
+        self.backend.start_trace()
+    }
+
+    /// Stops recording the buffers submitted to VA-API and returns the trace recorded since
+    /// [`Self::start_va_trace`], or `None` if no trace is in progress.
+    pub fn take_va_trace(&mut self) -> Option<crate::backend::vaapi::trace::VaTrace> {
+        self.backend.take_trace()
+    }
+}
+
+#[cfg(feature = "vaapi")]
+impl<C> StatelessDecoder<C, crate::backend::vaapi::trace::TraceBackend>
+where
+    C: StatelessCodec,
+    crate::backend::vaapi::trace::TraceBackend: StatelessDecoderBackend<C>,
+{
+    /// Returns the buffers that would have been submitted to VA-API for the pictures decoded
+    /// so far, and starts a new trace.
+    pub fn take_va_trace(&mut self) -> crate::backend::vaapi::trace::VaTrace {
+        self.backend.take_trace()
+    }
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use crate::backend::dummy::Backend;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::tests::DECODE_64X64_PROGRESSIVE_I_P;
+    use crate::decoder::stateless::h264::tests::DECODE_64X64_PROGRESSIVE_I_P_B_P;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::DecodeError;
+    use crate::decoder::stateless::LatencyPercentiles;
+    use crate::decoder::stateless::StatelessCodec;
+    use crate::decoder::stateless::StatelessDecoder;
     use crate::decoder::stateless::StatelessVideoDecoder;
+    use crate::decoder::BlockingMode;
     use crate::decoder::DecodedHandle;
+    use crate::decoder::DecoderEvent;
+    use crate::decoder::DecoderFormatNegotiator;
+    use crate::decoder::SkipReason;
+    use crate::utils::checksum::ChecksumAlgorithm;
+    use crate::utils::checksum::ChecksumChecker;
+    #[cfg(feature = "debug-dump")]
+    use crate::utils::dump::DumpFormat;
+    #[cfg(feature = "debug-dump")]
+    use crate::utils::dump::FrameDumper;
+    use crate::utils::simple_playback_loop;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
 
     /// Stream that can be used in tests, along with the CRC32 of all of its frames.
     pub struct TestStream {
@@ -355,14 +1763,15 @@ pub(crate) mod tests {
     /// against the existing result. We may want to set this to false when using a decoder backend
     /// that does not produce actual frames.
     ///
-    /// `dump_yuv` will dump all the decoded frames into `/tmp/framexxx.yuv`. Set this to true in
-    /// order to debug the output of the test.
+    /// `dump_frames` will dump all the decoded frames into `/tmp/framexxx.png`, or as raw
+    /// `/tmp/framexxx.yuv` files if the `debug-dump` feature is disabled. Set this to true in order
+    /// to debug the output of the test.
     pub fn test_decode_stream<D, M, L>(
         decoding_loop: L,
         mut decoder: D,
         test: &TestStream,
         check_crcs: bool,
-        dump_yuv: bool,
+        dump_frames: bool,
     ) where
         D: StatelessVideoDecoder<M>,
         L: Fn(
@@ -371,33 +1780,544 @@ pub(crate) mod tests {
             &mut dyn FnMut(Box<dyn DecodedHandle<Descriptor = M>>),
         ) -> anyhow::Result<()>,
     {
-        let mut crcs = test.crcs.lines().enumerate();
+        let mut checker = ChecksumChecker::new(ChecksumAlgorithm::Crc32, test.crcs);
+        let mut num_frames = 0;
+        #[cfg(feature = "debug-dump")]
+        let mut dumper = FrameDumper::new("/tmp", "frame", DumpFormat::Png);
 
         decoding_loop(&mut decoder, test.stream, &mut |handle| {
-            let (frame_num, expected_crc) = crcs.next().expect("decoded more frames than expected");
+            if dump_frames {
+                #[cfg(feature = "debug-dump")]
+                dumper.consume(handle.as_ref()).unwrap();
+                #[cfg(not(feature = "debug-dump"))]
+                std::fs::write(
+                    format!("/tmp/frame{:03}.yuv", num_frames),
+                    handle.to_owned_frame().unwrap().data,
+                )
+                .unwrap();
+            }
+
+            if check_crcs {
+                checker.consume(handle.as_ref()).unwrap();
+            }
+
+            num_frames += 1;
+        })
+        .unwrap();
+
+        if check_crcs {
+            checker.finish().unwrap();
+        } else {
+            assert_eq!(
+                num_frames,
+                test.crcs.lines().count(),
+                "decoded a different number of frames than expected"
+            );
+        }
+    }
 
-            if check_crcs || dump_yuv {
-                handle.sync().unwrap();
-                let picture = handle.dyn_picture();
-                let mut backend_handle = picture.dyn_mappable_handle().unwrap();
+    /// A codec which test streams can be decoded by [`test_codec_stream`].
+    pub trait TestCodec: StatelessCodec {
+        /// Splits `stream` into the units submitted to the decoder, e.g. NAL units or IVF frames.
+        fn split_stream(stream: &[u8]) -> Box<dyn Iterator<Item = &[u8]> + '_>;
+    }
 
-                let buffer_size = backend_handle.image_size();
-                let mut nv12 = vec![0; buffer_size];
+    /// Decode `test` with `decoder`, which has been created for `blocking_mode`.
+    ///
+    /// `output_format` and `check_crcs` have the same meaning as for [`test_decode_stream`].
+    pub fn test_codec_stream<C, D>(
+        decoder: D,
+        blocking_mode: BlockingMode,
+        test: &TestStream,
+        output_format: DecodedFormat,
+        check_crcs: bool,
+    ) where
+        C: TestCodec,
+        D: StatelessVideoDecoder<()>,
+    {
+        test_decode_stream(
+            |d, s, c| {
+                simple_playback_loop(
+                    d,
+                    C::split_stream(s),
+                    c,
+                    &mut simple_playback_loop_owned_frames,
+                    output_format,
+                    blocking_mode,
+                )
+            },
+            decoder,
+            test,
+            check_crcs,
+            false,
+        );
+    }
 
-                backend_handle.read(&mut nv12).unwrap();
+    /// Declares, for each `name => stream` pair, a module `name` with a `block` and a `nonblock`
+    /// test decoding `stream` with codec `codec`, using the decoder returned by
+    /// `new_decoder(blocking_mode)`. Each stream and mode is thus reported as a separate test.
+    ///
+    /// `output_format` and `check_crcs` have the same meaning as for [`test_decode_stream`].
+    macro_rules! codec_stream_tests {
+        (
+            $codec:ty,
+            $new_decoder:expr,
+            $output_format:expr,
+            $check_crcs:expr,
+            { $($name:ident => $stream:expr),* $(,)? }
+        ) => {
+            $(
+                mod $name {
+                    use super::*;
+
+                    #[test]
+                    fn block() {
+                        let blocking_mode = $crate::decoder::BlockingMode::Blocking;
+                        $crate::decoder::stateless::tests::test_codec_stream::<$codec, _>(
+                            ($new_decoder)(blocking_mode),
+                            blocking_mode,
+                            &$stream,
+                            $output_format,
+                            $check_crcs,
+                        );
+                    }
 
-                if dump_yuv {
-                    std::fs::write(format!("/tmp/frame{:03}.yuv", frame_num), &nv12).unwrap();
+                    #[test]
+                    fn nonblock() {
+                        let blocking_mode = $crate::decoder::BlockingMode::NonBlocking;
+                        $crate::decoder::stateless::tests::test_codec_stream::<$codec, _>(
+                            ($new_decoder)(blocking_mode),
+                            blocking_mode,
+                            &$stream,
+                            $output_format,
+                            $check_crcs,
+                        );
+                    }
                 }
+            )*
+        };
+    }
+    pub(crate) use codec_stream_tests;
 
-                if check_crcs {
-                    let frame_crc = format!("{:08x}", crc32fast::hash(&nv12));
-                    assert_eq!(frame_crc, expected_crc, "at frame {}", frame_num);
+    /// Submits each of `inputs` to `decoder`, using its index as timestamp, and calls `drain` after
+    /// each call to [`StatelessVideoDecoder::decode`] so the events it produced can be retrieved.
+    ///
+    /// Inputs that cannot be fully consumed at once, e.g. because the decoder needs the client to
+    /// check its events first, are submitted again until they are.
+    pub fn decode_inputs<D, M, I>(decoder: &mut D, inputs: I, mut drain: impl FnMut(&mut D))
+    where
+        D: StatelessVideoDecoder<M>,
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        for (timestamp, input) in inputs.into_iter().enumerate() {
+            let mut bitstream = input.as_ref();
+            while !bitstream.is_empty() {
+                match decoder.decode(timestamp as u64, bitstream) {
+                    Ok(consumed) => bitstream = &bitstream[consumed..],
+                    Err(DecodeError::CheckEvents) => (),
+                    Err(e) => panic!("{}", e),
                 }
+                drain(decoder);
             }
-        })
+        }
+    }
+
+    /// Negotiates the NV12 format with `negotiator`, and provides it with the frames it needs.
+    pub fn negotiate_nv12(negotiator: &mut dyn DecoderFormatNegotiator<()>) {
+        negotiator.try_format(DecodedFormat::NV12).unwrap();
+        let frames = simple_playback_loop_owned_frames(
+            negotiator.stream_info(),
+            negotiator.stream_info().min_num_frames,
+        )
+        .unwrap();
+        negotiator.frame_pool().add_frames(frames).unwrap();
+    }
+
+    #[test]
+    fn test_poll_timeout() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+
+        let mut expected_timestamps = vec![];
+        simple_playback_loop(
+            &mut StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking),
+            NalIterator::<Nalu>::new(test.stream),
+            &mut |handle| expected_timestamps.push(handle.timestamp()),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::NonBlocking);
+        let mut timestamps = vec![];
+        let mut poll = |decoder: &mut StatelessDecoder<H264, _>| {
+            for event in decoder.poll_timeout(Duration::from_millis(1)).unwrap() {
+                if let DecoderEvent::FrameReady(handle) = event {
+                    timestamps.push(handle.timestamp());
+                }
+            }
+            // Format changes are only reported by `next_event`.
+            if let Some(DecoderEvent::FormatChanged(mut negotiator)) = decoder.next_event() {
+                negotiate_nv12(negotiator.as_mut());
+            }
+        };
+
+        decode_inputs(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut poll,
+        );
+        decoder.flush().unwrap();
+        poll(&mut decoder);
+
+        assert_eq!(timestamps, expected_timestamps);
+        assert!(decoder.next_event().is_none());
+    }
+
+    #[test]
+    fn test_readiness_fd() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut fd = UnixStream::from(
+            decoder
+                .readiness_fd()
+                .unwrap()
+                .try_clone_to_owned()
+                .unwrap(),
+        );
+
+        // Checks that the fd is signaled if and only if events are pending, and drains them.
+        let mut num_frames = 0;
+        let mut drain = |decoder: &mut StatelessDecoder<H264, _>| {
+            let signaled = decoder.readiness.as_ref().unwrap().signaled;
+            let mut num_events = 0;
+            while let Some(event) = decoder.next_event() {
+                num_events += 1;
+                match event {
+                    DecoderEvent::FrameReady(_) => num_frames += 1,
+                    DecoderEvent::FormatChanged(mut negotiator) => {
+                        negotiate_nv12(negotiator.as_mut())
+                    }
+                    _ => (),
+                }
+            }
+            assert_eq!(signaled, num_events > 0);
+        };
+
+        decode_inputs(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            |decoder| {
+                drain(decoder);
+                assert_eq!(
+                    fd.read(&mut [0]).unwrap_err().kind(),
+                    std::io::ErrorKind::WouldBlock
+                );
+            },
+        );
+
+        decoder.flush().unwrap();
+        assert_eq!(fd.read(&mut [0]).unwrap(), 1);
+        drain(&mut decoder);
+        assert_eq!(num_frames, test.crcs.lines().count());
+    }
+
+    #[test]
+    fn test_completion_callback() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::NonBlocking);
+
+        let (sender, receiver) = mpsc::channel();
+        decoder
+            .set_completion_callback(Some(Box::new(move |timestamp, result| {
+                result.unwrap();
+                sender.send(timestamp).unwrap();
+            })))
+            .unwrap();
+
+        let mut num_frames = 0;
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::NonBlocking,
+        )
         .unwrap();
 
-        assert_eq!(crcs.next(), None, "decoded less frames than expected");
+        // Removing the callback waits for all the pictures to complete.
+        decoder.set_completion_callback(None).unwrap();
+        assert_eq!(receiver.iter().count(), num_frames);
+        assert_eq!(num_frames, test.crcs.lines().count());
+    }
+
+    #[test]
+    fn test_device_loss() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.backend.lose_device = true;
+
+        // Decodes the whole stream and returns the number of frames output, format changes and
+        // device resets.
+        let decode_stream = |decoder: &mut StatelessDecoder<H264, _>| {
+            let mut counts = (0, 0, 0);
+            let mut drain = |decoder: &mut StatelessDecoder<H264, _>| {
+                while let Some(event) = decoder.next_event() {
+                    match event {
+                        DecoderEvent::FrameReady(_) => counts.0 += 1,
+                        DecoderEvent::FormatChanged(mut negotiator) => {
+                            negotiator.try_format(DecodedFormat::NV12).unwrap();
+                            counts.1 += 1;
+                        }
+                        DecoderEvent::DeviceReset => counts.2 += 1,
+                        _ => (),
+                    }
+                }
+            };
+
+            decode_inputs(decoder, NalIterator::<Nalu>::new(test.stream), &mut drain);
+            decoder.flush().unwrap();
+            drain(decoder);
+
+            counts
+        };
+
+        // The loss happens when submitting the only IDR picture of the stream, so nothing else
+        // can be decoded.
+        assert_eq!(decode_stream(&mut decoder), (0, 1, 1));
+        // Decoding resumes at the next IDR picture, after negotiating the format again.
+        assert_eq!(
+            decode_stream(&mut decoder),
+            (test.crcs.lines().count(), 1, 0)
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+
+        // An SPS NAL unit without any payload.
+        assert!(matches!(
+            decoder.decode(0, &[0, 0, 0, 1, 0x67]),
+            Err(DecodeError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_skipped_data() {
+        const FILLER: [u8; 7] = [0, 0, 0, 1, 0x0c, 0xff, 0x80];
+        let test = &DECODE_64X64_PROGRESSIVE_I_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_report_skipped_data(true);
+
+        // Follow each NAL unit with filler data.
+        let inputs = NalIterator::<Nalu>::new(test.stream)
+            .map(|nalu| [nalu, &FILLER].concat())
+            .collect::<Vec<_>>();
+        let num_nalus = inputs.len();
+
+        let mut skipped = vec![];
+        decode_inputs(&mut decoder, inputs, |decoder| {
+            while let Some(event) = decoder.next_event() {
+                match event {
+                    DecoderEvent::FormatChanged(mut negotiator) => {
+                        negotiator.try_format(DecodedFormat::NV12).unwrap()
+                    }
+                    DecoderEvent::DataSkipped(data) => skipped.push(data),
+                    _ => (),
+                }
+            }
+        });
+
+        // The filler NAL units are reported without their start code, except the one following
+        // the initial access unit delimiter, as input is ignored until the SPS is seen.
+        let num_fillers = num_nalus - 1;
+        assert_eq!(skipped.len(), num_fillers);
+        assert!(skipped
+            .iter()
+            .all(|data| data.reason == SkipReason::FillerData && data.range.len() == 3));
+        assert_eq!(decoder.stats().num_skipped_nalus, num_fillers as u64);
+        assert_eq!(decoder.stats().num_skipped_bytes, 3 * num_fillers as u64);
+    }
+
+    #[test]
+    fn test_bitstream_stats() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_bitstream_stats_interval(Some(4));
+
+        decode_inputs(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            |decoder| {
+                while let Some(event) = decoder.next_event() {
+                    if let DecoderEvent::FormatChanged(mut negotiator) = event {
+                        negotiator.try_format(DecodedFormat::NV12).unwrap()
+                    }
+                }
+            },
+        );
+        decoder.flush().unwrap();
+
+        let stats = decoder.bitstream_stats().unwrap();
+        let frames = [
+            &stats.intra_frames,
+            &stats.inter_frames,
+            &stats.bidirectional_frames,
+        ];
+        let num_frames = frames.iter().map(|f| f.num_frames).sum::<u64>();
+        let total_bytes = frames.iter().map(|f| f.total_bytes).sum::<u64>();
+
+        assert_eq!(num_frames, test.crcs.lines().count() as u64);
+        assert_eq!(stats.intra_frames.num_frames, 1);
+        assert_eq!(stats.inter_frames.num_frames, num_frames - 1);
+        assert_eq!(stats.bytes_per_interval.values().sum::<u64>(), total_bytes);
+        assert!(stats.bytes_per_interval.keys().all(|start| start % 4 == 0));
+        assert_eq!(stats.qp_histogram.values().sum::<u64>(), num_frames);
+        // The only group of pictures is still open.
+        assert!(stats.gop_lengths.is_empty());
+    }
+
+    #[test]
+    fn test_verify_output() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_verify_output(true);
+
+        let mut num_frames = 0;
+        let mut drain = |decoder: &mut StatelessDecoder<H264, _>| {
+            while let Some(event) = decoder.next_event() {
+                match event {
+                    DecoderEvent::FormatChanged(mut negotiator) => {
+                        negotiator.try_format(DecodedFormat::NV12).unwrap()
+                    }
+                    DecoderEvent::FrameReady(handle) => {
+                        assert!(!handle.is_corrupted().unwrap());
+                        num_frames += 1;
+                    }
+                    DecoderEvent::NonFatalError(_, e) => panic!("{:#}", e),
+                    _ => (),
+                }
+            }
+        };
+
+        decode_inputs(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut drain,
+        );
+        decoder.flush().unwrap();
+        drain(&mut decoder);
+
+        assert_eq!(num_frames, test.crcs.lines().count());
+        assert_eq!(decoder.stats().num_readback_mismatches, 0);
+    }
+
+    /// Decodes the `DECODE_64X64_PROGRESSIVE_I_P_B_P` test stream with latency tracking enabled.
+    fn decode_with_latency_tracking(
+        blocking_mode: BlockingMode,
+    ) -> StatelessDecoder<H264, Backend> {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(blocking_mode);
+        decoder.set_latency_tracking(true);
+
+        // Nothing has been decoded yet.
+        let stats = decoder.latency_stats().unwrap();
+        assert_eq!(stats.decode_latency(), None);
+        assert_eq!(stats.retrieval_latency(), None);
+        assert_eq!(stats.total_latency(), None);
+
+        decode_inputs(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            |decoder| {
+                while let Some(event) = decoder.next_event() {
+                    if let DecoderEvent::FormatChanged(mut negotiator) = event {
+                        negotiator.try_format(DecodedFormat::NV12).unwrap();
+                    }
+                }
+            },
+        );
+        decoder.flush().unwrap();
+        while decoder.next_event().is_some() {}
+
+        decoder
+    }
+
+    #[test]
+    fn test_latency_tracking() {
+        let decoder = decode_with_latency_tracking(BlockingMode::Blocking);
+
+        // The dummy backend reports the same timestamp for all frames, so we cannot match each of
+        // them with its input and only check the consistency of what has been recorded.
+        let stats = decoder.latency_stats().unwrap();
+        assert!(!stats.frames.is_empty());
+        let num_frames = stats.frames.len();
+        // Frames are synced at submission in blocking mode, so their completion is always known.
+        for frame in &stats.frames {
+            assert!(frame.decode.unwrap() <= frame.total);
+        }
+        assert_eq!(stats.decode_latency().unwrap().num_samples, num_frames);
+        let total = stats.total_latency().unwrap();
+        assert_eq!(total.num_samples, num_frames);
+        assert!(total.p50 <= total.p90 && total.p90 <= total.p99 && total.p99 <= total.max);
+    }
+
+    #[test]
+    fn test_latency_tracking_non_blocking() {
+        let decoder = decode_with_latency_tracking(BlockingMode::NonBlocking);
+
+        let stats = decoder.latency_stats().unwrap();
+        let num_frames = stats.frames.len();
+        assert!(num_frames > 0);
+        // Only the frames observed to be complete account for the decode and retrieval latencies.
+        let num_completed = stats.frames.iter().filter(|f| f.decode.is_some()).count();
+        let num_samples = |l: Option<LatencyPercentiles>| l.map_or(0, |l| l.num_samples);
+        assert_eq!(num_samples(stats.decode_latency()), num_completed);
+        assert_eq!(num_samples(stats.retrieval_latency()), num_completed);
+        assert_eq!(stats.total_latency().unwrap().num_samples, num_frames);
+    }
+
+    #[test]
+    fn test_sync_timeout() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.backend.hang = true;
+        decoder.set_sync_timeout(Some(Duration::from_millis(1)));
+
+        let mut num_frames = 0;
+        let mut num_timeouts = 0;
+        let mut drain = |decoder: &mut StatelessDecoder<H264, _>| {
+            while let Some(event) = decoder.next_event() {
+                match event {
+                    DecoderEvent::FrameReady(handle) => {
+                        // The pictures that did not complete in time are flagged as corrupted.
+                        assert!(handle.is_corrupted().unwrap());
+                        num_frames += 1;
+                    }
+                    DecoderEvent::FormatChanged(mut negotiator) => {
+                        negotiator.try_format(DecodedFormat::NV12).unwrap()
+                    }
+                    DecoderEvent::NonFatalError(_, error) => {
+                        assert!(error.to_string().contains("did not complete within"));
+                        num_timeouts += 1;
+                    }
+                    _ => (),
+                }
+            }
+        };
+
+        decode_inputs(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut drain,
+        );
+        decoder.flush().unwrap();
+        drain(&mut decoder);
+
+        assert_eq!(num_frames, test.crcs.lines().count());
+        assert_eq!(num_timeouts, num_frames);
     }
 }
@@ -2,11 +2,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::os::fd::BorrowedFd;
 use std::rc::Rc;
 
 use anyhow::anyhow;
 use anyhow::Context;
 
+use crate::codec::av1::parser::ChromaSamplePosition;
 use crate::codec::av1::parser::FrameHeaderObu;
 use crate::codec::av1::parser::FrameObu;
 use crate::codec::av1::parser::FrameType;
@@ -20,18 +22,23 @@ use crate::Resolution;
 use crate::codec::av1::parser::TileGroupObu;
 use crate::decoder::stateless::DecoderEvent;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameCodingType;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessDecoderBackend;
 use crate::decoder::stateless::StatelessDecoderFormatNegotiator;
 use crate::decoder::stateless::StatelessVideoDecoder;
-use crate::decoder::BlockingMode;
+use crate::decoder::ChromaSiting;
+use crate::decoder::Colorimetry;
 use crate::decoder::DecodedHandle;
+use crate::decoder::FrameInfo;
+use crate::decoder::PictureId;
+use crate::decoder::ReadyFrame;
+use crate::decoder::SkipReason;
 
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
 
-#[cfg(test)]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
@@ -61,7 +68,14 @@ pub trait StatelessAV1DecoderBackend: StatelessDecoderBackend<Av1> {
     /// Called when the decoder wants the backend to finish the decoding
     /// operations for `picture`. At this point, `decode_tile` has been called
     /// for all tiles.
-    fn submit_picture(&mut self, picture: Self::Picture) -> StatelessBackendResult<Self::Handle>;
+    ///
+    /// `hdr` gives the size of the frame, which can be smaller than the maximum size of the
+    /// sequence the frames are allocated for.
+    fn submit_picture(
+        &mut self,
+        picture: Self::Picture,
+        hdr: &FrameHeaderObu,
+    ) -> StatelessBackendResult<Self::Handle>;
 }
 
 /// State of the picture being currently decoded.
@@ -101,12 +115,18 @@ pub struct AV1DecoderState<B: StatelessDecoderBackend<Av1>> {
     /// calls to `decode`.
     current_pic: Option<CurrentPicState<B>>,
 
+    /// Size in bytes of the OBUs of the current picture received so far.
+    coded_size: usize,
+
     /// Keep track of the number of frames we've processed for logging purposes.
     frame_count: u32,
 
     /// For SVC streams, we only want to output the highest layer possible given
     /// the choice of operating point.
     highest_spatial_layer: Option<u32>,
+
+    /// Number of frames output so far, used as index of the next output frame.
+    num_shown_frames: u64,
 }
 
 impl<B> Default for AV1DecoderState<B>
@@ -120,12 +140,30 @@ where
             reference_frames: Default::default(),
             sequence: Default::default(),
             current_pic: Default::default(),
+            coded_size: 0,
             frame_count: Default::default(),
             highest_spatial_layer: Default::default(),
+            num_shown_frames: 0,
         }
     }
 }
 
+/// Returns the colorimetry signaled in the color config of `sequence`.
+fn colorimetry(sequence: &SequenceHeaderObu) -> Colorimetry {
+    let color_config = &sequence.color_config;
+    Colorimetry {
+        color_primaries: color_config.color_primaries as u8,
+        transfer_characteristics: color_config.transfer_characteristics as u8,
+        matrix_coefficients: color_config.matrix_coefficients as u8,
+        full_range: color_config.color_range,
+    }
+}
+
+/// Returns the number of tiles of the frame described by `header`.
+fn num_tiles(header: &FrameHeaderObu) -> usize {
+    header.tile_info.tile_cols as usize * header.tile_info.tile_rows as usize
+}
+
 /// [`StatelessCodec`] structure to use in order to create a AV1 stateless decoder.
 ///
 /// # Accepted input
@@ -234,6 +272,27 @@ where
         Ok(())
     }
 
+    /// Returns the properties of the frame described by `header`, to be output as the next frame.
+    fn frame_info(&self, header: &FrameHeaderObu) -> FrameInfo {
+        let chroma_siting = match &self.codec.sequence {
+            Some(sequence) => match sequence.color_config.chroma_sample_position {
+                ChromaSamplePosition::Vertical => ChromaSiting::Left,
+                ChromaSamplePosition::Colocated => ChromaSiting::TopLeft,
+                ChromaSamplePosition::Unknown | ChromaSamplePosition::Reserved => {
+                    ChromaSiting::Unspecified
+                }
+            },
+            None => ChromaSiting::Unspecified,
+        };
+
+        FrameInfo {
+            chroma_siting,
+            picture_id: PictureId::FrameIndex(self.codec.num_shown_frames),
+            temporal_id: Some(header.obu_header.temporal_id),
+            ..Default::default()
+        }
+    }
+
     fn submit_frame(&mut self, timestamp: u64) -> anyhow::Result<()> {
         log::debug!(
             "Finishing frame {} with timestamp: {}",
@@ -242,20 +301,31 @@ where
         );
 
         let picture = self.codec.current_pic.take();
+        let coded_size = std::mem::take(&mut self.codec.coded_size);
 
-        let (handle, header) = match picture {
+        let (handle, header, completed) = match picture {
             Some(CurrentPicState::RegularFrame {
                 header,
                 backend_picture,
             }) => {
-                let handle = self.backend.submit_picture(backend_picture)?;
-
-                if self.blocking_mode == BlockingMode::Blocking {
-                    handle.sync()?;
-                }
-                (handle, header)
+                let coding_type = if header.frame_is_intra {
+                    FrameCodingType::Intra
+                } else {
+                    FrameCodingType::Inter
+                };
+                self.frame_received(
+                    timestamp,
+                    coding_type,
+                    coded_size,
+                    header.quantization_params.base_q_idx as i32,
+                );
+
+                let handle = self.backend.submit_picture(backend_picture, &header)?;
+                self.picture_submitted(&handle)?;
+                let completed = self.sync_picture(&handle)?;
+                (handle, header, completed)
             }
-            Some(CurrentPicState::ShowExistingFrame { header, handle }) => (handle, header),
+            Some(CurrentPicState::ShowExistingFrame { header, handle }) => (handle, header, true),
             None => return Err(anyhow!("Broken stream: no picture to submit")),
         };
 
@@ -286,18 +356,24 @@ where
 
         let show_existing_frame = header.show_existing_frame;
         if header.show_frame || show_existing_frame {
-            match self.codec.highest_spatial_layer {
-                None => self.ready_queue.push(handle),
+            let output = match self.codec.highest_spatial_layer {
+                None => true,
                 Some(highest_spatial_layer) => {
-                    if header.obu_header.spatial_id >= highest_spatial_layer {
-                        self.ready_queue.push(handle);
-                    } else {
-                        log::debug!(
-                            "Dropping frame with spatial_id {}",
-                            header.obu_header.spatial_id
-                        );
-                    }
+                    header.obu_header.spatial_id >= highest_spatial_layer
                 }
+            };
+
+            if output {
+                let mut info = self.frame_info(&header);
+                info.corrupted |= !completed;
+                let frame = ReadyFrame::new(handle, info);
+                self.codec.num_shown_frames += 1;
+                self.ready_queue.push(frame);
+            } else {
+                log::debug!(
+                    "Dropping frame with spatial_id {}",
+                    header.obu_header.spatial_id
+                );
             }
         }
 
@@ -305,31 +381,36 @@ where
         self.codec.frame_count += 1;
         Ok(())
     }
-}
 
-impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor> for StatelessDecoder<Av1, B>
-where
-    B: StatelessAV1DecoderBackend,
-    B::Handle: Clone + 'static,
-{
-    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, super::DecodeError> {
+    /// Decodes the OBUs in `bitstream`.
+    fn decode_obus(
+        &mut self,
+        timestamp: u64,
+        bitstream: &[u8],
+    ) -> Result<usize, super::DecodeError> {
         let mut consumed = 0;
 
         let nframes = self.count_frames(bitstream);
-        let num_free_frames = self.backend.frame_pool().num_free_frames();
-
-        if matches!(self.decoding_state, DecodingState::Decoding) && num_free_frames < nframes {
-            return Err(DecodeError::NotEnoughOutputBuffers(
-                nframes - num_free_frames,
-            ));
+        if matches!(self.decoding_state, DecodingState::Decoding) {
+            let num_free_frames = self.num_free_frames(nframes);
+            if num_free_frames < nframes {
+                return Err(self.output_buffers_exhausted(nframes - num_free_frames));
+            }
         }
+        self.output_buffers_available();
 
         while let Ok(obu) = self.codec.parser.parse_obu(&bitstream[consumed..]) {
             let obu = match obu {
                 ParsedObu::Process(obu) => obu,
                 // This OBU should be dropped.
                 ParsedObu::Drop(length) => {
-                    consumed += usize::try_from(length).context("OBU length too large")?;
+                    let length = usize::try_from(length).context("OBU length too large")?;
+                    self.data_skipped(
+                        timestamp,
+                        consumed..consumed + length,
+                        SkipReason::OtherLayer,
+                    );
+                    consumed += length;
                     continue;
                 }
             };
@@ -359,18 +440,22 @@ where
 
                         let is_key_frame = match obu.header.obu_type {
                             ObuType::Frame => {
-                                let frame = parser.parse_frame_obu(obu.clone())?;
+                                let frame = parser
+                                    .parse_frame_obu(obu.clone())
+                                    .map_err(DecodeError::ParseError)?;
                                 frame.header.frame_type == FrameType::KeyFrame
                             }
                             ObuType::FrameHeader => {
-                                let fh = parser.parse_frame_header_obu(&obu)?;
+                                let fh = parser
+                                    .parse_frame_header_obu(&obu)
+                                    .map_err(DecodeError::ParseError)?;
                                 fh.frame_type == FrameType::KeyFrame
                             }
                             _ => false,
                         };
 
-                        /* we can only resume from key frames */
-                        if !is_key_frame {
+                        /* we can only resume from key frames, once the sequence is known */
+                        if !is_key_frame || self.codec.sequence.is_none() {
                             consumed += obu_length;
                             continue;
                         } else {
@@ -382,7 +467,11 @@ where
 
             match obu.header.obu_type {
                 ObuType::SequenceHeader => {
-                    let sequence = self.codec.parser.parse_sequence_header_obu(&obu)?;
+                    let sequence = self
+                        .codec
+                        .parser
+                        .parse_sequence_header_obu(&obu)
+                        .map_err(DecodeError::ParseError)?;
                     let sequence_differs = match &self.codec.sequence {
                         Some(old_sequence) => **old_sequence != *sequence,
                         None => true,
@@ -397,36 +486,60 @@ where
                             )));
                         }
 
-                        /* make sure we sync *before* we clear any state in the backend */
-                        for f in &mut self.ready_queue.queue {
-                            /* TODO: this fixes av1-1-b8-03-sizeup on Intel
-                             * gen12, but we apparently do not do the same in
-                             * VP9. How is it that we do not get similar crashes there?
-                             *
-                             * TODO: syncing before calling new_sequence() in VP9 may fix some tests
-                             */
-                            f.sync()?;
-                        }
+                        if matches!(
+                            self.decoding_state,
+                            DecodingState::Decoding | DecodingState::Reset
+                        ) && self.backend.try_change_resolution(&sequence)
+                        {
+                            // The new sequence fits in the current frames, e.g. because an
+                            // adaptive stream has scaled down. Keep decoding into them without
+                            // renegotiating.
+                            self.stream_params_applied(colorimetry(&sequence));
+                            self.codec.sequence = Some(sequence);
+                            self.codec.highest_spatial_layer =
+                                self.codec.parser.highest_operating_point();
+                        } else {
+                            /* make sure we sync *before* we clear any state in the backend */
+                            for f in &mut self.ready_queue.queue {
+                                /* TODO: this fixes av1-1-b8-03-sizeup on Intel
+                                 * gen12, but we apparently do not do the same in
+                                 * VP9. How is it that we do not get similar crashes there?
+                                 *
+                                 * TODO: syncing before calling new_sequence() in VP9 may fix some tests
+                                 */
+                                f.sync()?;
+                            }
 
-                        log::debug!(
-                            "Found new sequence, resolution: {:?}, profile: {:?}, bit depth: {:?}",
-                            Resolution::from((
-                                sequence.max_frame_width_minus_1 + 1,
-                                sequence.max_frame_height_minus_1 + 1
-                            )),
-                            sequence.seq_profile,
-                            sequence.bit_depth
-                        );
-                        /* there is nothing to drain, much like vp8 and vp9 */
-                        self.backend.new_sequence(&sequence)?;
-                        self.decoding_state = DecodingState::AwaitingFormat(sequence);
-                        self.codec.highest_spatial_layer =
-                            self.codec.parser.highest_operating_point();
+                            log::debug!(
+                                "Found new sequence, resolution: {:?}, profile: {:?}, bit depth: {:?}",
+                                Resolution::from((
+                                    sequence.max_frame_width_minus_1 + 1,
+                                    sequence.max_frame_height_minus_1 + 1
+                                )),
+                                sequence.seq_profile,
+                                sequence.bit_depth
+                            );
+                            self.check_limits(
+                                NUM_REF_FRAMES,
+                                Resolution::from((
+                                    sequence.max_frame_width_minus_1 + 1,
+                                    sequence.max_frame_height_minus_1 + 1,
+                                )),
+                            )?;
+
+                            /* there is nothing to drain, much like vp8 and vp9 */
+                            self.backend.new_sequence(&sequence)?;
+                            self.decoding_state = DecodingState::AwaitingFormat(sequence);
+                            self.codec.highest_spatial_layer =
+                                self.codec.parser.highest_operating_point();
+                        }
                     }
                 }
-                ObuType::TemporalDelimiter => {
-                    self.codec.parser.parse_temporal_delimiter_obu(&obu)?
-                }
+                ObuType::TemporalDelimiter => self
+                    .codec
+                    .parser
+                    .parse_temporal_delimiter_obu(&obu)
+                    .map_err(DecodeError::ParseError)?,
                 ObuType::FrameHeader => {
                     if self.codec.current_pic.is_some() {
                         /* submit this frame immediately, as we need to update the
@@ -434,15 +547,32 @@ where
                          * next frame */
                         self.submit_frame(timestamp)?;
                     }
-                    let frame_header = self.codec.parser.parse_frame_header_obu(&obu)?;
+                    self.codec.coded_size += obu_length;
+                    let frame_header = self
+                        .codec
+                        .parser
+                        .parse_frame_header_obu(&obu)
+                        .map_err(DecodeError::ParseError)?;
+                    self.check_num_tiles(num_tiles(&frame_header))?;
                     self.decode_frame_header(frame_header, timestamp)?;
                 }
                 ObuType::TileGroup => {
-                    let tile_group = self.codec.parser.parse_tile_group_obu(obu)?;
+                    self.codec.coded_size += obu_length;
+                    let tile_group = self
+                        .codec
+                        .parser
+                        .parse_tile_group_obu(obu)
+                        .map_err(DecodeError::ParseError)?;
                     self.decode_tile_group(tile_group)?;
                 }
                 ObuType::Frame => {
-                    let frame = self.codec.parser.parse_frame_obu(obu)?;
+                    self.codec.coded_size += obu_length;
+                    let frame = self
+                        .codec
+                        .parser
+                        .parse_frame_obu(obu)
+                        .map_err(DecodeError::ParseError)?;
+                    self.check_num_tiles(num_tiles(&frame.header))?;
                     self.decode_frame(frame, timestamp)?;
                     /* submit this frame immediately, as we need to update the
                      * DPB and the reference info state *before* processing the
@@ -468,8 +598,40 @@ where
             self.submit_frame(timestamp)?;
         }
 
+        if consumed < bitstream.len() {
+            self.spec_violation(
+                timestamp,
+                anyhow!(
+                    "{} bytes of data that cannot be parsed as OBUs",
+                    bitstream.len() - consumed
+                ),
+            )?;
+            // Skip the garbage so it does not get submitted again.
+            self.data_skipped(timestamp, consumed..bitstream.len(), SkipReason::Unparsable);
+            consumed = bitstream.len();
+        }
+
         Ok(consumed)
     }
+}
+
+impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor> for StatelessDecoder<Av1, B>
+where
+    B: StatelessAV1DecoderBackend,
+    B::Handle: Clone + 'static,
+{
+    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, super::DecodeError> {
+        self.input_submitted(timestamp);
+        let res = self.decode_obus(timestamp, bitstream);
+        let res = self.recover_if_device_lost(res, |decoder| {
+            decoder.reset();
+            // Renegotiate the format at the next sequence header.
+            decoder.codec.sequence = None;
+        });
+        self.update_readiness();
+
+        res
+    }
 
     fn flush(&mut self) -> Result<(), super::DecodeError> {
         // Note: all the submitted frames are already in the ready queue.
@@ -479,6 +641,16 @@ where
         Ok(())
     }
 
+    fn reset(&mut self) {
+        // The current picture has not been submitted yet.
+        self.codec.current_pic = None;
+        self.codec.coded_size = 0;
+        self.codec.reference_frames = Default::default();
+        self.ready_queue.clear();
+        self.decoding_state = DecodingState::Reset;
+        self.update_readiness();
+    }
+
     fn frame_pool(
         &mut self,
     ) -> &mut dyn crate::decoder::FramePool<<B::Handle as DecodedHandle>::Descriptor> {
@@ -489,14 +661,31 @@ where
         self.backend.stream_info()
     }
 
+    fn ready_for_input(&mut self) -> bool {
+        self.ready_for_input()
+    }
+
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+        self.readiness_fd()
+    }
+
     fn next_event(
         &mut self,
     ) -> Option<crate::decoder::DecoderEvent<<B::Handle as DecodedHandle>::Descriptor>> {
+        // Clear the readiness fd once all the events have been retrieved.
+        self.update_readiness();
+
+        // Report pending notifications first, e.g. so the client can react to the end of an
+        // output buffers stall before it happens again.
+        if let Some(event) = self.take_pending_event() {
+            return Some(event);
+        }
+
         // The next event is either the next frame, or, if we are awaiting negotiation, the format
         // change event that will allow us to keep going.
         (&mut self.ready_queue)
             .next()
-            .map(|handle| DecoderEvent::FrameReady(Box::new(handle)))
+            .map(|frame| DecoderEvent::FrameReady(Box::new(self.output_frame(frame))))
             .or_else(|| {
                 if let DecodingState::AwaitingFormat(sequence) = &self.decoding_state {
                     Some(DecoderEvent::FormatChanged(Box::new(
@@ -505,6 +694,7 @@ where
                             sequence.clone(),
                             |decoder, sequence| {
                                 decoder.codec.sequence = Some(Rc::clone(sequence));
+                                decoder.stream_params_applied(colorimetry(sequence));
                                 decoder.decoding_state = DecodingState::Decoding;
                             },
                         ),
@@ -519,37 +709,34 @@ where
 #[cfg(test)]
 pub mod tests {
     use crate::decoder::stateless::av1::Av1;
-    use crate::decoder::stateless::tests::test_decode_stream;
+    use crate::decoder::stateless::tests::codec_stream_tests;
+    use crate::decoder::stateless::tests::TestCodec;
     use crate::decoder::stateless::tests::TestStream;
+    use crate::decoder::stateless::DecodeError;
     use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::stateless::Strictness;
     use crate::decoder::BlockingMode;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
     use crate::utils::IvfIterator;
     use crate::DecodedFormat;
 
-    /// Run `test` using the dummy decoder, in both blocking and non-blocking modes.
-    fn test_decoder_dummy(test: &TestStream, blocking_mode: BlockingMode) {
-        let decoder = StatelessDecoder::<Av1, _>::new_dummy(blocking_mode);
-
-        test_decode_stream(
-            |d, s, f| {
-                simple_playback_loop(
-                    d,
-                    IvfIterator::new(s),
-                    f,
-                    &mut simple_playback_loop_owned_frames,
-                    DecodedFormat::NV12,
-                    blocking_mode,
-                )
-            },
-            decoder,
-            test,
-            false,
-            false,
-        );
+    impl TestCodec for Av1 {
+        fn split_stream(stream: &[u8]) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+            Box::new(IvfIterator::new(stream))
+        }
     }
 
+    codec_stream_tests!(
+        Av1,
+        StatelessDecoder::<Av1, _>::new_dummy,
+        DecodedFormat::NV12,
+        false,
+        {
+            test_25fps => DECODE_TEST_25FPS,
+        }
+    );
+
     /// Same as Chromium's test-25fps.av1.ivf
     pub const DECODE_TEST_25FPS: TestStream = TestStream {
         stream: include_bytes!("../../codec/av1/test_data/test-25fps.ivf.av1"),
@@ -557,12 +744,40 @@ pub mod tests {
     };
 
     #[test]
-    fn test_25fps_block() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_25fps_nonblock() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
+    fn test_trailing_garbage() {
+        let frames = IvfIterator::new(DECODE_TEST_25FPS.stream)
+            .map(|frame| [frame, &[0xde, 0xad, 0xbe, 0xef]].concat())
+            .collect::<Vec<_>>();
+
+        // The garbage is skipped in permissive mode.
+        let mut decoder = StatelessDecoder::<Av1, _>::new_dummy(BlockingMode::Blocking);
+        let mut num_frames = 0;
+        simple_playback_loop(
+            &mut decoder,
+            frames.iter(),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        assert_eq!(num_frames, DECODE_TEST_25FPS.crcs.lines().count());
+
+        // And rejected in strict mode.
+        let mut decoder = StatelessDecoder::<Av1, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_strictness(Strictness::Strict);
+        let err = simple_playback_loop(
+            &mut decoder,
+            frames.iter(),
+            &mut |_| (),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::SpecViolation(_))
+        ));
     }
 }
@@ -3,7 +3,7 @@
 // found in the LICENSE file.
 
 //! This file contains a dummy backend whose only purpose is to let the decoder
-//! run so we can test it in isolation.
+//! run so we can test it in isolation, or analyze streams without decoding them.
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -44,6 +44,7 @@ impl StatelessAV1DecoderBackend for Backend {
     fn submit_picture(
         &mut self,
         _: Self::Picture,
+        _: &crate::codec::av1::parser::FrameHeaderObu,
     ) -> crate::decoder::stateless::StatelessBackendResult<Self::Handle> {
         Ok(Handle {
             handle: Rc::new(RefCell::new(Default::default())),
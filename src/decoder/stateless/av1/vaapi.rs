@@ -32,9 +32,10 @@ use crate::decoder::stateless::StatelessBackendError;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::BlockingMode;
-
-/// The number of surfaces to allocate for this codec.
-const NUM_SURFACES: usize = 16;
+use crate::decoder::DpbSizeOrigin;
+use crate::decoder::NumFramesBreakdown;
+use crate::Rect;
+use crate::Resolution;
 
 impl VaStreamInfo for &Rc<SequenceHeaderObu> {
     fn va_profile(&self) -> anyhow::Result<i32> {
@@ -84,19 +85,23 @@ impl VaStreamInfo for &Rc<SequenceHeaderObu> {
         }
     }
 
-    fn min_num_surfaces(&self) -> usize {
-        NUM_SURFACES
+    fn min_num_surfaces(&self) -> NumFramesBreakdown {
+        NumFramesBreakdown {
+            dpb_size: NUM_REF_FRAMES,
+            dpb_size_origin: DpbSizeOrigin::Codec,
+            extra: 8,
+        }
     }
 
-    fn coded_size(&self) -> (u32, u32) {
-        (
+    fn coded_size(&self) -> Resolution {
+        Resolution::from((
             self.max_frame_width_minus_1 + 1,
             self.max_frame_height_minus_1 + 1,
-        )
+        ))
     }
 
-    fn visible_rect(&self) -> ((u32, u32), (u32, u32)) {
-        ((0, 0), self.coded_size())
+    fn visible_rect(&self) -> Rect<u32> {
+        Rect::from(self.coded_size())
     }
 }
 
@@ -595,9 +600,8 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessAV1DecoderBackend for VaapiB
 
         let pic_param = build_pic_param(hdr, sequence, surface_id, reference_frames)
             .context("Failed to build picture parameter")?;
-        let pic_param = metadata
-            .context
-            .create_buffer(pic_param)
+        let pic_param = self
+            .create_buffer(&metadata.context, pic_param)
             .context("Failed to create picture parameter buffer")?;
         picture.add_buffer(pic_param);
 
@@ -616,15 +620,15 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessAV1DecoderBackend for VaapiB
         let context = &metadata.context;
 
         for slice_param in slice_params {
-            let buffer = context
-                .create_buffer(slice_param)
+            let buffer = self
+                .create_buffer(context, slice_param)
                 .context("Failed to create slice parameter buffer")?;
 
             picture.add_buffer(buffer)
         }
 
-        let buffer = context
-            .create_buffer(slice_data)
+        let buffer = self
+            .create_buffer(context, slice_data)
             .context("Failed to create slice data buffer")?;
 
         picture.add_buffer(buffer);
@@ -635,8 +639,19 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessAV1DecoderBackend for VaapiB
     fn submit_picture(
         &mut self,
         picture: Self::Picture,
+        hdr: &FrameHeaderObu,
     ) -> crate::decoder::stateless::StatelessBackendResult<Self::Handle> {
-        self.process_picture::<Av1>(picture)
+        let handle = self.process_picture::<Av1>(picture)?;
+
+        // Frames within a sequence can have different sizes, e.g. when the encoder adapts its
+        // resolution without sending a new sequence header. The surfaces are large enough for the
+        // maximum size of the sequence, so only the visible part of this frame changes.
+        handle.borrow_mut().set_display_resolution(Resolution {
+            width: hdr.upscaled_width,
+            height: hdr.frame_height,
+        });
+
+        Ok(handle)
     }
 }
 
@@ -2,26 +2,31 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
 
 use std::cell::RefCell;
 use std::io::Cursor;
+use std::ops::Range;
+use std::os::fd::BorrowedFd;
 use std::rc::Rc;
 
 use anyhow::anyhow;
 use anyhow::Context;
 use log::debug;
+use log::warn;
 
 use crate::codec::h264::dpb::Dpb;
 use crate::codec::h264::dpb::DpbEntry;
+use crate::codec::h264::parser::FramePackingArrangement;
 use crate::codec::h264::parser::Nalu;
 use crate::codec::h264::parser::NaluType;
 use crate::codec::h264::parser::Parser;
+use crate::codec::h264::parser::PicTiming;
 use crate::codec::h264::parser::Pps;
 use crate::codec::h264::parser::RefPicListModification;
+use crate::codec::h264::parser::SeiMessage;
 use crate::codec::h264::parser::Slice;
 use crate::codec::h264::parser::SliceHeader;
 use crate::codec::h264::parser::SliceType;
@@ -30,21 +35,52 @@ use crate::codec::h264::picture::Field;
 use crate::codec::h264::picture::IsIdr;
 use crate::codec::h264::picture::PictureData;
 use crate::codec::h264::picture::Reference;
+use crate::decoder::stateless::decode_framed_nalus;
+use crate::decoder::stateless::decode_units;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameCodingType;
+use crate::decoder::stateless::ResumePolicy;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackend;
 use crate::decoder::stateless::StatelessDecoderFormatNegotiator;
 use crate::decoder::stateless::StatelessVideoDecoder;
-use crate::decoder::BlockingMode;
+use crate::decoder::ChromaSiting;
+use crate::decoder::Colorimetry;
 use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
+use crate::decoder::DpbSizeOrigin;
+use crate::decoder::FieldOrder;
+use crate::decoder::FrameInfo;
+use crate::decoder::FramePacking;
+use crate::decoder::FramePackingType;
 use crate::decoder::FramePool;
+use crate::decoder::PictureId;
+use crate::decoder::ReadyFrame;
+use crate::decoder::SkipReason;
 use crate::decoder::StreamInfo;
 use crate::Resolution;
 
+/// Maximum number of frames a DPB can hold, as per Table A-1 of the specification.
+const MAX_DPB_SIZE: usize = 16;
+
+/// Returns the number of frames the DPB must be able to hold to decode a stream using `sps`, and
+/// where this number comes from.
+///
+/// This includes the reference frames and the frames held for reordering, but not the frame being
+/// decoded. It is the size checked against the decoder limits and allocated by the backends.
+pub(crate) fn dpb_size(sps: &Sps) -> (usize, DpbSizeOrigin) {
+    let origin = if sps.has_max_dec_frame_buffering() {
+        DpbSizeOrigin::Stream
+    } else {
+        DpbSizeOrigin::Level
+    };
+
+    (std::cmp::min(sps.max_dpb_frames(), MAX_DPB_SIZE), origin)
+}
+
 type DpbPicList<H> = Vec<DpbEntry<H>>;
 
 fn get_raster_from_zigzag_8x8(src: [u8; 64], dst: &mut [u8; 64]) {
@@ -236,6 +272,28 @@ struct CurrentPicState<B: StatelessDecoderBackend<H264>> {
     backend_pic: B::Picture,
     /// List of reference pictures, used once per slice.
     ref_pic_lists: ReferencePicLists<B::Handle>,
+    /// Address of the first macroblock of the last slice of the picture, used to detect arbitrary
+    /// slice order.
+    last_first_mb_in_slice: u32,
+    /// Number of slices of the picture processed so far.
+    num_slices: usize,
+    /// Coding type of the picture, i.e. the highest coding type of its slices so far.
+    coding_type: FrameCodingType,
+    /// Size in bytes of the slices of the picture processed so far.
+    coded_size: usize,
+    /// QP of the first slice of the picture.
+    qp: i32,
+}
+
+impl SliceType {
+    /// Returns the coding type of a picture made of slices of this type.
+    fn coding_type(&self) -> FrameCodingType {
+        match self {
+            SliceType::I | SliceType::Si => FrameCodingType::Intra,
+            SliceType::P | SliceType::Sp => FrameCodingType::Inter,
+            SliceType::B => FrameCodingType::Bidirectional,
+        }
+    }
 }
 
 /// State of the H.264 decoder.
@@ -269,6 +327,29 @@ pub struct H264DecoderState<B: StatelessDecoderBackend<H264>> {
     /// The picture currently being decoded. We need to preserve it between calls to `decode`
     /// because multiple slices will be processed in different calls to `decode`.
     current_pic: Option<CurrentPicState<B>>,
+
+    /// POC of the last picture output in low-latency mode. Used to detect streams that require
+    /// reordering.
+    last_output_poc: Option<i32>,
+
+    /// Chroma siting of the current sequence, as signaled in the VUI parameters of its SPS.
+    chroma_siting: ChromaSiting,
+
+    /// SPS whose parameters are currently applied to the decoder.
+    active_sps: Option<Rc<Sps>>,
+
+    /// Frame packing arrangement signaled by a SEI message for the next picture.
+    pending_frame_packing: Option<FramePackingArrangement>,
+    /// Frame packing arrangement persisting from a previous picture.
+    frame_packing: Option<FramePackingArrangement>,
+    /// Picture timing signaled by a SEI message for the next picture.
+    pending_pic_timing: Option<PicTiming>,
+
+    /// Whether decoding is resuming from a non-IDR intra picture.
+    resuming_from_intra: bool,
+    /// POC of the non-IDR intra picture decoding resumed from. Pictures preceding it in output
+    /// order may reference pictures we never received.
+    resume_poc: Option<i32>,
 }
 
 impl<B> Default for H264DecoderState<B>
@@ -286,19 +367,73 @@ where
             max_long_term_frame_idx: Default::default(),
             last_field: Default::default(),
             current_pic: None,
+            last_output_poc: None,
+            chroma_siting: Default::default(),
+            active_sps: None,
+            pending_frame_packing: None,
+            frame_packing: None,
+            pending_pic_timing: None,
+            resuming_from_intra: false,
+            resume_poc: None,
         }
     }
 }
 
+/// Returns the frame packing described by `fpa`, if it is one we can report.
+fn frame_packing(fpa: &FramePackingArrangement) -> Option<FramePacking> {
+    let packing_type = match fpa.frame_packing_arrangement_type {
+        0 => FramePackingType::Checkerboard,
+        1 => FramePackingType::ColumnInterleaved,
+        2 => FramePackingType::RowInterleaved,
+        3 => FramePackingType::SideBySide,
+        4 => FramePackingType::TopBottom,
+        5 => FramePackingType::FrameAlternation,
+        // 6 signals non-stereoscopic content, and other values are reserved.
+        _ => return None,
+    };
+
+    Some(FramePacking {
+        packing_type,
+        left_view_first: match fpa.content_interpretation_type {
+            1 => Some(true),
+            2 => Some(false),
+            _ => None,
+        },
+        quincunx_sampling: fpa.quincunx_sampling_flag,
+        is_first_view: fpa.current_frame_is_frame0_flag,
+    })
+}
+
+/// Returns the colorimetry signaled in the VUI of `sps`.
+fn colorimetry(sps: &Sps) -> Colorimetry {
+    if !sps.vui_parameters_present_flag {
+        return Default::default();
+    }
+
+    let vui = &sps.vui_parameters;
+    Colorimetry {
+        color_primaries: vui.colour_primaries,
+        transfer_characteristics: vui.transfer_characteristics,
+        matrix_coefficients: vui.matrix_coefficients,
+        full_range: vui.video_full_range_flag,
+    }
+}
+
 /// [`StatelessCodec`] structure to use in order to create a H.264 stateless decoder.
 ///
 /// # Accepted input
 ///
-/// A decoder using this codec processes exactly one NAL unit of input per call to
-/// [`StatelessDecoder::decode`], and returns the number of bytes until the end of this NAL unit.
-/// This makes it possible to call [`Decode`](StatelessDecoder::decode) repeatedly on some unsplit
-/// Annex B stream and shrinking it by the number of bytes processed after each call, until the
-/// stream ends up being empty.
+/// A decoder using this codec accepts input containing any number of complete NAL units, e.g. a
+/// whole access unit or a larger chunk of an Annex B stream. [`StatelessDecoder::decode`]
+/// processes as many of them as possible and returns the number of bytes until the end of the last
+/// NAL unit processed. If decoding needs to pause before the end of the input, e.g. because events
+/// need to be processed, the remaining part must be submitted again afterwards. This makes it
+/// possible to call [`Decode`](StatelessDecoder::decode) repeatedly on some unsplit Annex B stream
+/// and shrinking it by the number of bytes processed after each call, until the stream ends up
+/// being empty.
+///
+/// Input that may end with an incomplete NAL unit, e.g. data received from a socket, can be
+/// submitted using [`StatelessVideoDecoder::decode_partial`].
 pub struct H264;
 
 impl StatelessCodec for H264 {
@@ -523,23 +658,97 @@ where
         Ok(())
     }
 
-    /// Returns an iterator of the handles of the frames that need to be bumped into the ready
-    /// queue.
-    fn bump_as_needed(&mut self, current_pic: &PictureData) -> impl Iterator<Item = B::Handle> {
-        self.dpb
-            .bump_as_needed(current_pic)
-            .into_iter()
-            .filter_map(|p| p.1)
+    /// Returns the properties of the frame output for `pic`.
+    fn frame_info(&self, pic: &PictureData) -> FrameInfo {
+        let field_order = match (pic.field, pic.pic_struct) {
+            // Fields are output starting with the one with the lowest POC, i.e. the first one.
+            (Field::Top, _) => FieldOrder::TopFieldFirst,
+            (Field::Bottom, _) => FieldOrder::BottomFieldFirst,
+            // How the frame is to be displayed, see table D-1 of the specification.
+            (Field::Frame, Some(0 | 7 | 8)) => FieldOrder::Progressive,
+            (Field::Frame, Some(3 | 5)) => FieldOrder::TopFieldFirst,
+            (Field::Frame, Some(4 | 6)) => FieldOrder::BottomFieldFirst,
+            // Without picture timing, guess from how the frame has been coded.
+            (Field::Frame, _) if !self.dpb.interlaced() => FieldOrder::Progressive,
+            (Field::Frame, _) => {
+                if pic.top_field_order_cnt <= pic.bottom_field_order_cnt {
+                    FieldOrder::TopFieldFirst
+                } else {
+                    FieldOrder::BottomFieldFirst
+                }
+            }
+        };
+
+        // A frame made of two fields is corrupted if either of them is.
+        let corrupted = pic.corrupted
+            || pic
+                .other_field()
+                .is_some_and(|other_field| other_field.borrow().corrupted);
+
+        FrameInfo {
+            chroma_siting: self.chroma_siting,
+            field_order,
+            picture_id: PictureId::PicOrderCnt(pic.pic_order_cnt),
+            corrupted,
+            frame_packing: pic.frame_packing.as_ref().and_then(frame_packing),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the frame packing arrangement applying to the picture being started, and updates
+    /// the persisting one.
+    fn next_frame_packing(&mut self, is_idr: bool) -> Option<FramePackingArrangement> {
+        // Arrangements do not persist beyond the end of the coded video sequence.
+        if is_idr {
+            self.frame_packing = None;
+        }
+
+        match self.pending_frame_packing.take() {
+            Some(fpa) if fpa.frame_packing_arrangement_cancel_flag => {
+                self.frame_packing = None;
+                None
+            }
+            Some(fpa) => {
+                self.frame_packing = if fpa.frame_packing_arrangement_repetition_period > 0 {
+                    Some(fpa.clone())
+                } else {
+                    None
+                };
+                Some(fpa)
+            }
+            None => self.frame_packing.clone(),
+        }
+    }
+
+    /// Returns the frame to output for the DPB entry `entry`, if it has a handle.
+    fn ready_frame(&self, entry: DpbEntry<B::Handle>) -> Option<ReadyFrame<B::Handle>> {
+        let info = self.frame_info(&entry.0.borrow());
+
+        entry.1.map(|handle| ReadyFrame::new(handle, info))
+    }
+
+    /// Returns the frames that need to be bumped into the ready queue.
+    fn bump_as_needed(&mut self, current_pic: &PictureData) -> Vec<ReadyFrame<B::Handle>> {
+        let pics = self.dpb.bump_as_needed(current_pic);
+
+        pics.into_iter()
+            .filter_map(|p| self.ready_frame(p))
+            .collect()
     }
 
-    /// Returns an iterator of the handles of all the frames still present in the DPB.
-    fn drain(&mut self) -> impl Iterator<Item = B::Handle> {
+    /// Returns all the frames still present in the DPB.
+    fn drain(&mut self) -> Vec<ReadyFrame<B::Handle>> {
         let pics = self.dpb.drain();
+        let frames = pics
+            .into_iter()
+            .filter_map(|p| self.ready_frame(p))
+            .collect();
 
         self.dpb.clear();
         self.last_field = None;
+        self.last_output_poc = None;
 
-        pics.into_iter().filter_map(|h| h.1)
+        frames
     }
 
     /// Find the first field for the picture started by `slice`, if any.
@@ -621,20 +830,36 @@ where
         *old_negotiation_info != negotiation_info
     }
 
-    fn renegotiate_if_needed(&mut self, sps: &Rc<Sps>) -> anyhow::Result<()> {
+    fn renegotiate_if_needed(&mut self, sps: &Rc<Sps>) -> Result<(), DecodeError> {
         if Self::negotiation_possible(sps, &self.codec.negotiation_info) {
+            self.check_limits(dpb_size(sps).0, Resolution::from((sps.width, sps.height)))?;
             // Make sure all the frames we decoded so far are in the ready queue.
             self.drain()?;
             self.backend.new_sequence(sps)?;
             self.decoding_state = DecodingState::AwaitingFormat(sps.clone());
+        } else if matches!(
+            self.decoding_state,
+            DecodingState::Decoding | DecodingState::Reset
+        ) && !self
+            .codec
+            .active_sps
+            .as_ref()
+            .is_some_and(|active_sps| Rc::ptr_eq(active_sps, sps))
+        {
+            // The SPS has been redefined, but the new one can be decoded with the current format.
+            // Just update the parameters that depend on it. The parser keeps the same SPS when it
+            // is merely repeated, so this only happens on actual changes.
+            self.apply_sps(sps);
+            self.stream_params_applied(colorimetry(sps));
         }
 
         Ok(())
     }
 
     // Apply the parameters of `sps` to the decoder.
-    fn apply_sps(&mut self, sps: &Sps) {
-        self.codec.negotiation_info = NegotiationInfo::from(sps);
+    fn apply_sps(&mut self, sps: &Rc<Sps>) {
+        self.codec.negotiation_info = NegotiationInfo::from(sps.as_ref());
+        self.codec.active_sps = Some(Rc::clone(sps));
 
         let max_dpb_frames = sps.max_dpb_frames();
         let interlaced = !sps.frame_mbs_only_flag;
@@ -652,13 +877,23 @@ where
 
         self.coded_resolution = resolution;
 
+        let vui = &sps.vui_parameters;
+        self.codec.chroma_siting =
+            if sps.vui_parameters_present_flag && vui.chroma_loc_info_present_flag {
+                ChromaSiting::from_chroma_sample_loc_type(u32::from(
+                    vui.chroma_sample_loc_type_top_field,
+                ))
+            } else {
+                ChromaSiting::Unspecified
+            };
+
         self.codec
             .dpb
             .set_limits(max_dpb_frames, max_num_reorder_frames);
         self.codec.dpb.set_interlaced(interlaced);
     }
 
-    fn drain(&mut self) -> anyhow::Result<()> {
+    fn drain(&mut self) -> Result<(), DecodeError> {
         // Finish the current picture if there is one pending.
         if let Some(cur_pic) = self.codec.current_pic.take() {
             self.finish_picture(cur_pic)?;
@@ -1237,7 +1472,8 @@ where
         if matches!(pic.field, Field::Frame) {
             assert!(self.codec.last_field.is_none());
 
-            self.ready_queue.push(handle);
+            let info = self.codec.frame_info(&pic);
+            self.ready_queue.push(ReadyFrame::new(handle, info));
         } else {
             match &self.codec.last_field {
                 None => {
@@ -1254,7 +1490,8 @@ where
                 {
                     if let Some((field_pic, field_handle)) = self.codec.last_field.take() {
                         field_pic.borrow_mut().set_second_field_to(&pic_rc);
-                        self.ready_queue.push(field_handle);
+                        let info = self.codec.frame_info(&field_pic.borrow());
+                        self.ready_queue.push(ReadyFrame::new(field_handle, info));
                     }
                 }
                 _ => {
@@ -1265,16 +1502,72 @@ where
         }
     }
 
-    fn finish_picture(&mut self, pic: CurrentPicState<B>) -> anyhow::Result<()> {
+    /// Checks that marking `pic` as reference does not leave more reference frames than allowed by
+    /// `max_num_ref_frames` (7.4.3.3).
+    ///
+    /// Streams with a wrong `max_num_ref_frames` are common, so in permissive mode the oldest
+    /// short-term references are unmarked as in the sliding window process to repair them.
+    fn check_num_ref_frames(
+        &mut self,
+        pic: &mut PictureData,
+        pps: &Pps,
+    ) -> Result<(), DecodeError> {
+        if pic.is_second_field() {
+            return Ok(());
+        }
+
+        // The current picture is not in the DPB yet.
+        let num_ref_frames = self.codec.dpb.num_ref_frames() + 1;
+        let max_num_ref_frames =
+            usize::try_from(std::cmp::max(1, pps.sps.max_num_ref_frames)).unwrap();
+        if num_ref_frames <= max_num_ref_frames {
+            return Ok(());
+        }
+
+        self.spec_violation(
+            pic.timestamp,
+            anyhow!(
+                "{} reference frames exceed max_num_ref_frames ({})",
+                num_ref_frames,
+                max_num_ref_frames
+            ),
+        )?;
+        self.codec.sliding_window_marking(pic, pps)?;
+
+        Ok(())
+    }
+
+    fn finish_picture(&mut self, mut pic: CurrentPicState<B>) -> Result<(), DecodeError> {
         debug!("Finishing picture POC {:?}", pic.pic.pic_order_cnt);
 
+        // In low-latency mode all the previous pictures have already been output, so the current
+        // one must come after them in display order. Check this before submitting the picture so
+        // the decoder state is left untouched if it does not. Like IDR pictures, pictures with
+        // MMCO 5 are only output after all the previous ones.
+        let has_mmco_5 = pic
+            .pic
+            .ref_pic_marking
+            .inner
+            .iter()
+            .any(|marking| marking.memory_management_control_operation == 5);
+        if self.low_latency
+            && !pic.pic.is_second_field()
+            && !has_mmco_5
+            && matches!(self.codec.last_output_poc, Some(poc) if poc >= pic.pic.pic_order_cnt)
+        {
+            return Err(DecodeError::ReorderingInLowLatencyMode);
+        }
+
+        self.frame_received(pic.pic.timestamp, pic.coding_type, pic.coded_size, pic.qp);
+
         // Submit the picture to the backend.
-        let handle = self.submit_picture(pic.backend_pic)?;
+        let handle = self.submit_picture(pic.backend_pic, &mut pic.pic)?;
         let pps = pic.pps;
         let mut pic = pic.pic;
 
         if matches!(pic.reference(), Reference::ShortTerm | Reference::LongTerm) {
             self.reference_pic_marking(&mut pic, &pps)?;
+            self.check_num_ref_frames(&mut pic, &pps)?;
             self.fill_prev_ref_info(&pic);
         }
 
@@ -1294,6 +1587,10 @@ where
         // Bump the DPB as per C.4.5.3 to cover clauses 1, 4, 5 and 6.
         self.ready_queue.extend(self.codec.bump_as_needed(&pic));
 
+        if self.low_latency && !pic.is_second_field() {
+            self.codec.last_output_poc = Some(pic.pic_order_cnt);
+        }
+
         let pic_rc = Rc::new(RefCell::new(pic));
         let pic = pic_rc.borrow();
 
@@ -1347,6 +1644,12 @@ where
             self.add_to_ready_queue(pic_rc, handle);
         }
 
+        if self.low_latency {
+            let pics = self.codec.dpb.bump_all();
+            self.ready_queue
+                .extend(pics.into_iter().filter_map(|p| self.codec.ready_frame(p)));
+        }
+
         Ok(())
     }
 
@@ -1436,6 +1739,14 @@ where
         let max_frame_num = sps.max_frame_num() as i32;
 
         let mut pic = PictureData::new_from_slice(slice, &sps, timestamp);
+        pic.frame_packing = self
+            .codec
+            .next_frame_packing(slice.nalu.header.idr_pic_flag);
+        pic.pic_struct = self
+            .codec
+            .pending_pic_timing
+            .take()
+            .and_then(|pic_timing| pic_timing.pic_struct);
 
         if let Some(first_field) = first_field {
             pic.set_first_field_to(first_field);
@@ -1443,6 +1754,22 @@ where
 
         self.codec.compute_pic_order_count(&mut pic, &sps)?;
 
+        if matches!(pic.is_idr, IsIdr::Yes { .. }) || self.codec.prev_ref_pic_info.has_mmco_5 {
+            self.codec.resume_poc = None;
+        }
+        if std::mem::take(&mut self.codec.resuming_from_intra) {
+            self.codec.resume_poc = Some(pic.pic_order_cnt);
+        } else if first_field.is_none()
+            && self
+                .codec
+                .resume_poc
+                .is_some_and(|resume_poc| pic.pic_order_cnt < resume_poc)
+        {
+            // Leading pictures of the intra picture we resumed from are likely to reference
+            // pictures from before it.
+            pic.corrupted = true;
+        }
+
         if matches!(pic.is_idr, IsIdr::Yes { .. }) {
             // C.4.5.3 "Bumping process"
             // The bumping process is invoked in the following cases:
@@ -1458,6 +1785,7 @@ where
                 // emptied without output of the pictures they contain, and DPB
                 // fullness is set to 0.
                 self.codec.dpb.clear();
+                self.codec.last_output_poc = None;
             }
         }
 
@@ -1496,9 +1824,10 @@ where
             return Err(DecodeError::CheckEvents);
         }
 
-        if self.backend.frame_pool().num_free_frames() == 0 {
-            return Err(DecodeError::NotEnoughOutputBuffers(1));
+        if self.num_free_frames(1) == 0 {
+            return Err(self.output_buffers_exhausted(1));
         }
+        self.output_buffers_available();
 
         if frame_num != self.codec.prev_ref_pic_info.frame_num
             && frame_num
@@ -1530,11 +1859,18 @@ where
             &slice.header,
         )?;
 
+        let qp = 26 + i32::from(pps.pic_init_qp_minus26) + i32::from(hdr.slice_qp_delta);
+
         Ok(CurrentPicState {
             pic,
             pps,
             backend_pic,
             ref_pic_lists,
+            last_first_mb_in_slice: hdr.first_mb_in_slice,
+            num_slices: 0,
+            coding_type: hdr.slice_type.coding_type(),
+            coded_size: 0,
+            qp,
         })
     }
 
@@ -1800,6 +2136,25 @@ where
             ref_pic_list1,
         } = self.create_ref_pic_lists(&cur_pic.pic, &slice.header, &cur_pic.ref_pic_lists)?;
 
+        // Predicting from pictures created to fill a frame_num gap, or from pictures that are
+        // themselves corrupted, propagates the errors to the current picture.
+        let mut refs = ref_pic_list0.iter().chain(ref_pic_list1.iter());
+        if refs.clone().any(|p| p.0.borrow().nonexisting) {
+            // Only report the error once per picture.
+            if !cur_pic.pic.corrupted {
+                self.non_fatal_error(
+                    cur_pic.pic.timestamp,
+                    anyhow!(
+                        "picture with POC {} references frames missing from the stream",
+                        cur_pic.pic.pic_order_cnt
+                    ),
+                );
+            }
+            cur_pic.pic.corrupted = true;
+        } else if refs.any(|p| p.0.borrow().corrupted) {
+            cur_pic.pic.corrupted = true;
+        }
+
         self.backend.decode_slice(
             &mut cur_pic.backend_pic,
             slice,
@@ -1813,32 +2168,95 @@ where
         Ok(())
     }
 
-    /// Submits the picture to the accelerator.
-    fn submit_picture(&mut self, backend_pic: B::Picture) -> Result<B::Handle, DecodeError> {
+    /// Submits the picture to the accelerator, flagging `pic` as corrupted if it did not complete
+    /// in time.
+    fn submit_picture(
+        &mut self,
+        backend_pic: B::Picture,
+        pic: &mut PictureData,
+    ) -> Result<B::Handle, DecodeError> {
         let handle = self.backend.submit_picture(backend_pic)?;
+        self.picture_submitted(&handle)?;
 
-        if self.blocking_mode == BlockingMode::Blocking {
-            handle.sync()?;
+        if !self.sync_picture(&handle)? {
+            pic.corrupted = true;
         }
 
         Ok(handle)
     }
 
-    fn process_nalu(&mut self, timestamp: u64, nalu: Nalu) -> Result<(), DecodeError> {
+    /// Processes `nalu`, located at `range` in the input.
+    fn process_nalu(
+        &mut self,
+        timestamp: u64,
+        range: Range<usize>,
+        nalu: Nalu,
+    ) -> Result<(), DecodeError> {
         match nalu.header.type_ {
             NaluType::Sps => {
-                self.codec.parser.parse_sps(&nalu)?;
+                self.codec
+                    .parser
+                    .parse_sps(&nalu)
+                    .map_err(DecodeError::ParseError)?;
             }
             NaluType::Pps => {
-                self.codec.parser.parse_pps(&nalu)?;
+                self.codec
+                    .parser
+                    .parse_pps(&nalu)
+                    .map_err(DecodeError::ParseError)?;
+            }
+            NaluType::Sei => {
+                // SEI messages are not required for decoding, so don't fail because of them.
+                match self.codec.parser.parse_sei(&nalu) {
+                    Ok(messages) => {
+                        for message in messages {
+                            match message {
+                                SeiMessage::FramePackingArrangement(fpa) => {
+                                    self.codec.pending_frame_packing = Some(fpa);
+                                }
+                                SeiMessage::PicTiming(pic_timing) => {
+                                    self.codec.pending_pic_timing = Some(pic_timing);
+                                }
+                                SeiMessage::Unsupported(_) => (),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to parse SEI NAL unit: {:#}", e);
+                        self.stats.num_invalid_seis += 1;
+                        self.data_skipped(timestamp, range, SkipReason::InvalidSei);
+                    }
+                }
+            }
+            NaluType::FillerData => {
+                self.stats.num_skipped_nalus += 1;
+                self.data_skipped(timestamp, range, SkipReason::FillerData);
             }
             NaluType::Slice
             | NaluType::SliceDpa
             | NaluType::SliceDpb
             | NaluType::SliceDpc
-            | NaluType::SliceIdr
-            | NaluType::SliceExt => {
-                let slice = self.codec.parser.parse_slice_header(nalu)?;
+            | NaluType::SliceIdr => {
+                let slice = self
+                    .codec
+                    .parser
+                    .parse_slice_header(nalu)
+                    .map_err(DecodeError::ParseError)?;
+
+                // No backend supports flexible macroblock ordering, so fail before we submit
+                // anything that would produce garbage.
+                let num_slice_groups_minus1 = self
+                    .codec
+                    .parser
+                    .get_pps(slice.header.pic_parameter_set_id)
+                    .map(|pps| pps.num_slice_groups_minus1)
+                    .unwrap_or(0);
+                if num_slice_groups_minus1 > 0 {
+                    return Err(DecodeError::UnsupportedFeature(
+                        "flexible macroblock ordering (FMO)",
+                    ));
+                }
+
                 let mut cur_pic = match self.codec.current_pic.take() {
                     // No current picture, start a new one.
                     None => self.begin_picture(timestamp, &slice)?,
@@ -1855,13 +2273,55 @@ where
                         self.finish_picture(cur_pic)?;
                         self.begin_picture(timestamp, &slice)?
                     }
-                    // This slice is part of the current picture.
-                    Some(cur_pic) => cur_pic,
+                    // This slice is part of the current picture. Slices are expected in
+                    // increasing macroblock order, as arbitrary slice order would also break our
+                    // detection of new pictures.
+                    Some(cur_pic) => {
+                        if slice.header.first_mb_in_slice < cur_pic.last_first_mb_in_slice {
+                            // Put the picture back so it can still be flushed.
+                            self.codec.current_pic = Some(cur_pic);
+                            return Err(DecodeError::UnsupportedFeature(
+                                "arbitrary slice order (ASO)",
+                            ));
+                        }
+                        cur_pic
+                    }
                 };
 
+                cur_pic.last_first_mb_in_slice = slice.header.first_mb_in_slice;
+                cur_pic.num_slices += 1;
+                cur_pic.coding_type =
+                    std::cmp::max(cur_pic.coding_type, slice.header.slice_type.coding_type());
+                cur_pic.coded_size += range.len();
+                self.check_num_slices(cur_pic.num_slices)?;
                 self.handle_slice(&mut cur_pic, &slice)?;
                 self.codec.current_pic = Some(cur_pic);
             }
+            // Units belonging to the MVC/SVC extensions, auxiliary pictures, or with a type we
+            // don't know about. None of them are needed to decode the primary coded pictures.
+            NaluType::Unknown
+            | NaluType::PrefixUnit
+            | NaluType::SubsetSps
+            | NaluType::DepthSps
+            | NaluType::Reserved17
+            | NaluType::Reserved18
+            | NaluType::SliceAux
+            | NaluType::SliceExt
+            | NaluType::SliceDepth
+            | NaluType::Reserved22
+            | NaluType::Reserved23
+            | NaluType::Unspecified24
+            | NaluType::Unspecified25
+            | NaluType::Unspecified26
+            | NaluType::Unspecified27
+            | NaluType::Unspecified28
+            | NaluType::Unspecified29
+            | NaluType::Unspecified30
+            | NaluType::Unspecified31 => {
+                warn!("Skipping unsupported NAL unit type {:?}", nalu.header.type_);
+                self.stats.num_skipped_nalus += 1;
+                self.data_skipped(timestamp, range, SkipReason::UnsupportedUnit);
+            }
             other => {
                 debug!("Unsupported NAL unit type {:?}", other,);
             }
@@ -1869,20 +2329,26 @@ where
 
         Ok(())
     }
-}
 
-impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor>
-    for StatelessDecoder<H264, B>
-where
-    B: StatelessH264DecoderBackend,
-    B::Handle: Clone + 'static,
-{
-    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+    /// Decodes the first NAL unit of `bitstream`, located at `offset` in the input, and returns
+    /// the number of bytes until its end.
+    fn decode_nalu(
+        &mut self,
+        timestamp: u64,
+        offset: usize,
+        bitstream: &[u8],
+    ) -> Result<usize, DecodeError> {
         let mut cursor = Cursor::new(bitstream);
-        let nalu = Nalu::next(&mut cursor)?;
+        let nalu = Nalu::next(&mut cursor).map_err(DecodeError::ParseError)?;
+        let range = offset + nalu.offset..offset + nalu.offset + nalu.size;
 
         if nalu.header.type_ == NaluType::Sps {
-            let sps = self.codec.parser.parse_sps(&nalu)?.clone();
+            let sps = self
+                .codec
+                .parser
+                .parse_sps(&nalu)
+                .map_err(DecodeError::ParseError)?
+                .clone();
             if matches!(self.decoding_state, DecodingState::AwaitingStreamInfo) {
                 // If more SPS come along we will renegotiate in begin_picture().
                 self.renegotiate_if_needed(&sps)?;
@@ -1894,8 +2360,25 @@ where
             let mut cursor = Cursor::new(bitstream);
 
             while let Ok(nalu) = Nalu::next(&mut cursor) {
-                // In the Reset state we can resume decoding from any key frame.
-                if matches!(nalu.header.type_, NaluType::SliceIdr) {
+                // In the Reset state we can resume decoding from any key frame, or from any intra
+                // picture if the resume policy allows it.
+                let nalu_is_idr = nalu.header.idr_pic_flag;
+                let can_resume = match nalu.header.type_ {
+                    NaluType::SliceIdr => true,
+                    NaluType::Slice if self.resume_policy == ResumePolicy::IntraFrame => self
+                        .codec
+                        .parser
+                        .parse_slice_header(nalu)
+                        .is_ok_and(|slice| {
+                            slice.header.first_mb_in_slice == 0
+                                && (slice.header.slice_type.is_i()
+                                    || slice.header.slice_type.is_si())
+                        }),
+                    _ => false,
+                };
+
+                if can_resume {
+                    self.codec.resuming_from_intra = !nalu_is_idr;
                     self.decoding_state = DecodingState::Decoding;
                     break;
                 }
@@ -1909,32 +2392,98 @@ where
             // from the stream.
             DecodingState::AwaitingStreamInfo | DecodingState::Reset => {
                 if matches!(nalu.header.type_, NaluType::Pps) {
-                    self.process_nalu(timestamp, nalu)?;
+                    self.process_nalu(timestamp, range, nalu)?;
                 }
             }
             // Ask the client to confirm the format before we can process this.
             DecodingState::AwaitingFormat(_) => return Err(DecodeError::CheckEvents),
             DecodingState::Decoding => {
-                self.process_nalu(timestamp, nalu)?;
+                self.process_nalu(timestamp, range, nalu)?;
             }
         }
 
         Ok(nalu_len)
     }
+}
+
+/// Resets `decoder` after the loss of its backend's device, so the format is negotiated again at
+/// the next IDR picture.
+fn reset_after_device_loss<B>(decoder: &mut StatelessDecoder<H264, B>)
+where
+    B: StatelessH264DecoderBackend,
+    B::Handle: Clone + 'static,
+{
+    decoder.reset();
+    decoder.codec.negotiation_info = Default::default();
+}
+
+impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor>
+    for StatelessDecoder<H264, B>
+where
+    B: StatelessH264DecoderBackend,
+    B::Handle: Clone + 'static,
+{
+    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        self.input_submitted(timestamp);
+        let res = decode_framed_nalus(self.input_framing, bitstream, false, |bitstream| {
+            decode_units(bitstream, |offset, nalu| {
+                self.decode_nalu(timestamp, offset, nalu)
+            })
+        });
+        let res = self.recover_if_device_lost(res, reset_after_device_loss);
+        self.update_readiness();
+
+        res
+    }
+
+    fn decode_partial(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        self.input_submitted(timestamp);
+        let res = decode_framed_nalus(self.input_framing, bitstream, true, |bitstream| {
+            decode_units(bitstream, |offset, nalu| {
+                self.decode_nalu(timestamp, offset, nalu)
+            })
+        });
+        let res = self.recover_if_device_lost(res, reset_after_device_loss);
+        self.update_readiness();
+
+        res
+    }
 
     fn flush(&mut self) -> Result<(), DecodeError> {
-        self.drain()?;
+        let res = self.drain();
+        let res = self.recover_if_device_lost(res, reset_after_device_loss);
+        self.update_readiness();
+        res?;
         self.decoding_state = DecodingState::Reset;
 
         Ok(())
     }
 
+    fn reset(&mut self) {
+        // The current picture has not been submitted yet, and the pictures of the DPB are
+        // dropped instead of being output.
+        self.codec.current_pic = None;
+        drop(self.codec.drain());
+        self.ready_queue.clear();
+        self.decoding_state = DecodingState::Reset;
+        self.update_readiness();
+    }
+
     fn next_event(&mut self) -> Option<DecoderEvent<<B::Handle as DecodedHandle>::Descriptor>> {
+        // Clear the readiness fd once all the events have been retrieved.
+        self.update_readiness();
+
+        // Report pending notifications first, e.g. so the client can react to the end of an
+        // output buffers stall before it happens again.
+        if let Some(event) = self.take_pending_event() {
+            return Some(event);
+        }
+
         // The next event is either the next frame, or, if we are awaiting negotiation, the format
         // change event that will allow us to keep going.
         (&mut self.ready_queue)
             .next()
-            .map(|handle| DecoderEvent::FrameReady(Box::new(handle)))
+            .map(|frame| DecoderEvent::FrameReady(Box::new(self.output_frame(frame))))
             .or_else(|| {
                 if let DecodingState::AwaitingFormat(sps) = &self.decoding_state {
                     Some(DecoderEvent::FormatChanged(Box::new(
@@ -1942,6 +2491,7 @@ where
                             // Apply the SPS settings to the decoder so we don't enter the AwaitingFormat state
                             // on the next decode() call.
                             decoder.apply_sps(sps);
+                            decoder.stream_params_applied(colorimetry(sps));
                             decoder.decoding_state = DecodingState::Decoding;
                         }),
                     )))
@@ -1958,43 +2508,73 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.stream_info()
     }
+
+    fn ready_for_input(&mut self) -> bool {
+        self.ready_for_input()
+    }
+
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+        self.readiness_fd()
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
     use crate::codec::h264::parser::Nalu;
+    use crate::codec::h264::parser::NaluType;
+    use crate::codec::h264::parser::Parser;
+    use crate::codec::h264::parser::Pps;
+    use crate::codec::h264::parser::Sps;
+    use crate::codec::h264::picture::PictureData;
+    use crate::codec::h264::picture::Reference;
+    use crate::codec::nal_framing::annexb_to_length_prefixed;
+    use crate::codec::nal_framing::NalFraming;
     use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::tests::codec_stream_tests;
+    use crate::decoder::stateless::tests::decode_inputs;
+    use crate::decoder::stateless::tests::negotiate_nv12;
     use crate::decoder::stateless::tests::test_decode_stream;
+    use crate::decoder::stateless::tests::TestCodec;
     use crate::decoder::stateless::tests::TestStream;
+    use crate::decoder::stateless::DecodeError;
+    use crate::decoder::stateless::DecoderLimits;
     use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::stateless::StatelessVideoDecoder;
+    use crate::decoder::stateless::Strictness;
     use crate::decoder::BlockingMode;
+    use crate::decoder::DecoderEvent;
+    use crate::decoder::FieldOrder;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
     use crate::utils::NalIterator;
     use crate::DecodedFormat;
+    use crate::Resolution;
 
-    /// Run `test` using the dummy decoder, in both blocking and non-blocking modes.
-    fn test_decoder_dummy(test: &TestStream, blocking_mode: BlockingMode) {
-        let decoder = StatelessDecoder::<H264, _>::new_dummy(blocking_mode);
-
-        test_decode_stream(
-            |d, s, f| {
-                simple_playback_loop(
-                    d,
-                    NalIterator::<Nalu>::new(s),
-                    f,
-                    &mut simple_playback_loop_owned_frames,
-                    DecodedFormat::NV12,
-                    blocking_mode,
-                )
-            },
-            decoder,
-            test,
-            false,
-            false,
-        );
+    impl TestCodec for H264 {
+        fn split_stream(stream: &[u8]) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+            Box::new(NalIterator::<Nalu>::new(stream))
+        }
     }
 
+    codec_stream_tests!(
+        H264,
+        StatelessDecoder::<H264, _>::new_dummy,
+        DecodedFormat::NV12,
+        false,
+        {
+            test_64x64_progressive_i => DECODE_64X64_PROGRESSIVE_I,
+            test_64x64_progressive_i_p => DECODE_64X64_PROGRESSIVE_I_P,
+            test_64x64_progressive_i_p_b_p => DECODE_64X64_PROGRESSIVE_I_P_B_P,
+            test_64x64_progressive_i_p_b_p_high => DECODE_64X64_PROGRESSIVE_I_P_B_P_HIGH,
+            test_25fps => DECODE_TEST_25FPS,
+            test_25fps_interlaced => DECODE_TEST_25FPS_INTERLACED,
+        }
+    );
+
     /// A 64x64 progressive byte-stream encoded I-frame to make it easier to
     /// spot errors on the libva trace.
     /// Encoded with the following GStreamer pipeline:
@@ -2005,16 +2585,6 @@ pub mod tests {
         crcs: include_str!("../../codec/h264/test_data/64x64-I.h264.crc"),
     };
 
-    #[test]
-    fn test_64x64_progressive_i_block() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_64x64_progressive_i_nonblock() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I, BlockingMode::NonBlocking);
-    }
-
     /// A 64x64 progressive byte-stream encoded I-frame and P-frame to make
     /// it easier to spot errors on the libva trace.
     /// Encoded with the following GStreamer pipeline:
@@ -2024,16 +2594,6 @@ pub mod tests {
         crcs: include_str!("../../codec/h264/test_data/64x64-I-P.h264.crc"),
     };
 
-    #[test]
-    fn test_64x64_progressive_i_p_block() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_64x64_progressive_i_p_nonblock() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P, BlockingMode::NonBlocking);
-    }
-
     /// A 64x64 progressive byte-stream encoded I-P-B-P sequence to make it
     /// easier to it easier to spot errors on the libva trace.
     /// Encoded with the following GStreamer pipeline:
@@ -2044,13 +2604,77 @@ pub mod tests {
     };
 
     #[test]
-    fn test_64x64_progressive_i_p_b_p_block() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P_B_P, BlockingMode::Blocking);
+    fn test_64x64_progressive_i_p_b_p_partial() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut num_frames = 0;
+
+        let mut check_events = |decoder: &mut StatelessDecoder<H264, _>| {
+            while let Some(event) = decoder.next_event() {
+                match event {
+                    DecoderEvent::FrameReady(_) => num_frames += 1,
+                    DecoderEvent::FormatChanged(mut format_setter) => {
+                        format_setter.try_format(DecodedFormat::NV12).unwrap()
+                    }
+                    _ => (),
+                }
+            }
+        };
+
+        // Feed the stream in chunks that do not match NAL unit boundaries, keeping the data that
+        // has not been consumed yet.
+        let mut pending = vec![];
+        for chunk in test.stream.chunks(100) {
+            pending.extend_from_slice(chunk);
+            loop {
+                match decoder.decode_partial(0, &pending) {
+                    Ok(0) => break,
+                    Ok(len) => drop(pending.drain(..len)),
+                    Err(DecodeError::CheckEvents) => (),
+                    Err(e) => panic!("{}", e),
+                }
+                check_events(&mut decoder);
+            }
+        }
+
+        // The last NAL unit is complete now that the stream has ended.
+        while !pending.is_empty() {
+            match decoder.decode(0, &pending) {
+                Ok(len) => drop(pending.drain(..len)),
+                Err(DecodeError::CheckEvents) => (),
+                Err(e) => panic!("{}", e),
+            }
+            check_events(&mut decoder);
+        }
+        decoder.flush().unwrap();
+        check_events(&mut decoder);
+
+        assert_eq!(num_frames, test.crcs.lines().count());
     }
 
     #[test]
-    fn test_64x64_progressive_i_p_b_p_nonblock() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P_B_P, BlockingMode::NonBlocking);
+    fn test_64x64_progressive_i_p_b_p_single_buffer() {
+        // Submit the whole stream at once and let the decoder split it into NAL units.
+        for blocking_mode in [BlockingMode::Blocking, BlockingMode::NonBlocking] {
+            let decoder = StatelessDecoder::<H264, _>::new_dummy(blocking_mode);
+
+            test_decode_stream(
+                |d, s, f| {
+                    simple_playback_loop(
+                        d,
+                        std::iter::once(s),
+                        f,
+                        &mut simple_playback_loop_owned_frames,
+                        DecodedFormat::NV12,
+                        blocking_mode,
+                    )
+                },
+                decoder,
+                &DECODE_64X64_PROGRESSIVE_I_P_B_P,
+                false,
+                false,
+            );
+        }
     }
 
     /// A 64x64 progressive byte-stream encoded I-P-B-P sequence to make it
@@ -2064,38 +2688,12 @@ pub mod tests {
         crcs: include_str!("../../codec/h264/test_data/64x64-I-P-B-P-high.h264.crc"),
     };
 
-    #[test]
-    fn test_64x64_progressive_i_p_b_p_high_block() {
-        test_decoder_dummy(
-            &DECODE_64X64_PROGRESSIVE_I_P_B_P_HIGH,
-            BlockingMode::Blocking,
-        );
-    }
-
-    #[test]
-    fn test_64x64_progressive_i_p_b_p_high_nonblock() {
-        test_decoder_dummy(
-            &DECODE_64X64_PROGRESSIVE_I_P_B_P_HIGH,
-            BlockingMode::NonBlocking,
-        );
-    }
-
     /// Same as Chromium's test-25fps.h264
     pub const DECODE_TEST_25FPS: TestStream = TestStream {
         stream: include_bytes!("../../codec/h264/test_data/test-25fps.h264"),
         crcs: include_str!("../../codec/h264/test_data/test-25fps.h264.crc"),
     };
 
-    #[test]
-    fn test_25fps_block() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_25fps_nonblock() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
-    }
-
     // Adapted from Chromium's test-25fps.h264. Same file, but encoded as
     // interlaced instead using the following ffmpeg command:
     // ffmpeg -i
@@ -2110,13 +2708,313 @@ pub mod tests {
         crcs: include_str!("../../codec/h264/test_data/test-25fps-interlaced.h264.crc"),
     };
 
+    /// Decode `test` in low-latency mode using the dummy decoder, returning the number of frames
+    /// output.
+    ///
+    /// Also checks that all the frames received by the decoder have been output, i.e. that a frame
+    /// requiring reordering is rejected before being submitted.
+    fn decode_low_latency(test: &TestStream) -> anyhow::Result<usize> {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_low_latency(true);
+        decoder.set_bitstream_stats_interval(Some(1));
+
+        let mut num_frames = 0;
+        let res = simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        );
+
+        let stats = decoder.bitstream_stats().unwrap();
+        let num_received = stats.intra_frames.num_frames
+            + stats.inter_frames.num_frames
+            + stats.bidirectional_frames.num_frames;
+        assert_eq!(num_received, num_frames as u64);
+
+        res.map(|()| num_frames)
+    }
+
+    #[test]
+    fn test_64x64_progressive_i_p_low_latency() {
+        let num_frames = decode_low_latency(&DECODE_64X64_PROGRESSIVE_I_P).unwrap();
+        assert_eq!(
+            num_frames,
+            DECODE_64X64_PROGRESSIVE_I_P.crcs.lines().count()
+        );
+    }
+
     #[test]
-    fn test_25fps_interlaced_block() {
-        test_decoder_dummy(&DECODE_TEST_25FPS_INTERLACED, BlockingMode::Blocking);
+    fn test_64x64_progressive_i_p_b_p_high_low_latency() {
+        let err = decode_low_latency(&DECODE_64X64_PROGRESSIVE_I_P_B_P_HIGH).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::ReorderingInLowLatencyMode)
+        ));
+    }
+
+    fn decode_with_limits(test: &TestStream, limits: DecoderLimits) -> anyhow::Result<()> {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_limits(limits);
+
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut |_| (),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+    }
+
+    #[test]
+    fn test_64x64_progressive_i_p_limits() {
+        decode_with_limits(
+            &DECODE_64X64_PROGRESSIVE_I_P,
+            DecoderLimits {
+                max_dpb_size: Some(16),
+                max_coded_resolution: Some(Resolution::from((64, 64))),
+                max_num_slices: Some(1),
+                max_num_tiles: Some(1),
+            },
+        )
+        .unwrap();
+
+        let err = decode_with_limits(
+            &DECODE_64X64_PROGRESSIVE_I_P,
+            DecoderLimits {
+                max_dpb_size: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::DpbSizeLimitExceeded(_, 1))
+        ));
+
+        let err = decode_with_limits(
+            &DECODE_64X64_PROGRESSIVE_I_P,
+            DecoderLimits {
+                max_coded_resolution: Some(Resolution::from((32, 32))),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::ResolutionLimitExceeded(..))
+        ));
+
+        let err = decode_with_limits(
+            &DECODE_64X64_PROGRESSIVE_I_P,
+            DecoderLimits {
+                max_num_slices: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::SliceLimitExceeded(1, 0))
+        ));
+    }
+
+    #[test]
+    fn test_64x64_progressive_i_p_length_prefixed() {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_input_framing(NalFraming::LengthPrefixed(4));
+
+        let test = &DECODE_64X64_PROGRESSIVE_I_P;
+        let mut num_frames = 0;
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream)
+                .map(|nalu| annexb_to_length_prefixed(nalu, 4).unwrap()),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        assert_eq!(num_frames, test.crcs.lines().count());
+
+        // Only the complete NAL units are consumed by partial decoding.
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_input_framing(NalFraming::LengthPrefixed(4));
+        let stream = annexb_to_length_prefixed(test.stream, 4).unwrap();
+        // The stream starts with an access unit delimiter, followed by the SPS.
+        let aud_len = 4 + usize::from(stream[3]);
+        assert_eq!(
+            decoder.decode_partial(0, &stream[..aud_len + 6]).unwrap(),
+            aud_len
+        );
+        assert!(decoder.decode(0, &stream[..aud_len + 6]).is_err());
+    }
+
+    #[test]
+    fn test_reset_drops_pending_frames() {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+        let num_frames = test.crcs.lines().count();
+
+        // Submit the whole stream without flushing, so the last frames remain in the DPB.
+        let mut num_output_frames = 0;
+        decode_inputs(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            |decoder| {
+                while let Some(event) = decoder.next_event() {
+                    match event {
+                        DecoderEvent::FrameReady(_) => num_output_frames += 1,
+                        DecoderEvent::FormatChanged(mut negotiator) => {
+                            negotiate_nv12(negotiator.as_mut())
+                        }
+                        _ => (),
+                    }
+                }
+            },
+        );
+        assert!(num_output_frames < num_frames);
+
+        // The pending frames are dropped instead of being output.
+        decoder.reset();
+        assert!(decoder.next_event().is_none());
+
+        // Decoding resumes from the next IDR picture.
+        num_output_frames = 0;
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut |_| num_output_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        assert_eq!(num_output_frames, num_frames);
+    }
+
+    fn decode_field_orders(test: &TestStream) -> Vec<FieldOrder> {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+
+        let mut field_orders = vec![];
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut |handle| field_orders.push(handle.frame_info().field_order),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        field_orders
+    }
+
+    #[test]
+    fn test_field_order_from_pic_struct() {
+        let decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let field_order = |pic_struct| {
+            let mut pic = PictureData::default();
+            pic.pic_struct = pic_struct;
+            decoder.codec.frame_info(&pic).field_order
+        };
+
+        assert_eq!(field_order(None), FieldOrder::Progressive);
+        assert_eq!(field_order(Some(0)), FieldOrder::Progressive);
+        assert_eq!(field_order(Some(3)), FieldOrder::TopFieldFirst);
+        assert_eq!(field_order(Some(4)), FieldOrder::BottomFieldFirst);
+        assert_eq!(field_order(Some(5)), FieldOrder::TopFieldFirst);
+        assert_eq!(field_order(Some(6)), FieldOrder::BottomFieldFirst);
+        assert_eq!(field_order(Some(7)), FieldOrder::Progressive);
+    }
+
+    /// Builds a DPB holding as many short-term references as `max_num_ref_frames` allows, and
+    /// checks how adding the current picture as one more is handled.
+    #[test]
+    fn test_check_num_ref_frames() {
+        let mut parser = Parser::default();
+        let mut cursor = Cursor::new(DECODE_64X64_PROGRESSIVE_I_P.stream);
+        let pps = loop {
+            let nalu = Nalu::next(&mut cursor).unwrap();
+            match nalu.header.type_ {
+                NaluType::Sps => {
+                    parser.parse_sps(&nalu).unwrap();
+                }
+                NaluType::Pps => break parser.parse_pps(&nalu).unwrap().clone(),
+                _ => (),
+            }
+        };
+        let pps = Pps {
+            sps: Rc::new(Sps {
+                max_num_ref_frames: 2,
+                ..Default::default()
+            }),
+            ..pps
+        };
+
+        let new_decoder = |strictness| {
+            let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+            decoder.set_strictness(strictness);
+            decoder.codec.dpb.set_limits(4, 0);
+            for frame_num in 0..2 {
+                let mut pic = PictureData::default();
+                pic.frame_num = frame_num;
+                pic.frame_num_wrap = frame_num;
+                pic.set_reference(Reference::ShortTerm, false);
+                decoder
+                    .codec
+                    .dpb
+                    .store_picture(Rc::new(RefCell::new(pic)), None)
+                    .unwrap();
+            }
+            decoder
+        };
+        let references = |decoder: &StatelessDecoder<H264, _>| {
+            decoder
+                .codec
+                .dpb
+                .pictures()
+                .map(|pic| *pic.reference())
+                .collect::<Vec<_>>()
+        };
+
+        // Strict mode rejects the picture and leaves the DPB untouched.
+        let mut decoder = new_decoder(Strictness::Strict);
+        let mut pic = PictureData::default();
+        assert!(matches!(
+            decoder.check_num_ref_frames(&mut pic, &pps),
+            Err(DecodeError::SpecViolation(_))
+        ));
+        assert_eq!(
+            references(&decoder),
+            [Reference::ShortTerm, Reference::ShortTerm]
+        );
+
+        // Permissive mode evicts the oldest short-term reference to make room for the picture.
+        let mut decoder = new_decoder(Strictness::Permissive);
+        let mut pic = PictureData::default();
+        decoder.check_num_ref_frames(&mut pic, &pps).unwrap();
+        assert_eq!(
+            references(&decoder),
+            [Reference::None, Reference::ShortTerm]
+        );
     }
 
     #[test]
-    fn test_25fps_interlaced_nonblock() {
-        test_decoder_dummy(&DECODE_TEST_25FPS_INTERLACED, BlockingMode::NonBlocking);
+    fn test_25fps_field_order() {
+        let field_orders = decode_field_orders(&DECODE_TEST_25FPS);
+        assert!(field_orders
+            .iter()
+            .all(|order| *order == FieldOrder::Progressive));
+
+        let field_orders = decode_field_orders(&DECODE_TEST_25FPS_INTERLACED);
+        assert!(!field_orders.is_empty());
+        assert!(field_orders
+            .iter()
+            .all(|order| *order != FieldOrder::Progressive));
     }
 }
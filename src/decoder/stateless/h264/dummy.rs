@@ -3,7 +3,7 @@
 // found in the LICENSE file.
 
 //! This file contains a dummy backend whose only purpose is to let the decoder
-//! run so we can test it in isolation.
+//! run so we can test it in isolation, or analyze streams without decoding them.
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -18,6 +18,7 @@ use crate::codec::h264::parser::Sps;
 use crate::codec::h264::picture::PictureData;
 use crate::decoder::stateless::h264::StatelessH264DecoderBackend;
 use crate::decoder::stateless::h264::H264;
+use crate::decoder::stateless::StatelessBackendError;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::BlockingMode;
@@ -62,8 +63,12 @@ impl StatelessH264DecoderBackend for Backend {
     }
 
     fn submit_picture(&mut self, _: Self::Picture) -> StatelessBackendResult<Self::Handle> {
+        if std::mem::take(&mut self.lose_device) {
+            return Err(StatelessBackendError::DeviceLost);
+        }
+
         Ok(Handle {
-            handle: Rc::new(RefCell::new(Default::default())),
+            handle: Rc::new(RefCell::new(BackendHandle::new(self.hang))),
         })
     }
 
@@ -31,6 +31,7 @@ use crate::codec::h264::parser::Sps;
 use crate::codec::h264::picture::Field;
 use crate::codec::h264::picture::PictureData;
 use crate::codec::h264::picture::Reference;
+use crate::decoder::stateless::h264::dpb_size;
 use crate::decoder::stateless::h264::StatelessH264DecoderBackend;
 use crate::decoder::stateless::h264::H264;
 use crate::decoder::stateless::StatelessBackendError;
@@ -39,6 +40,9 @@ use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::BlockingMode;
 use crate::decoder::DecodedHandle;
+use crate::decoder::NumFramesBreakdown;
+use crate::Rect;
+use crate::Resolution;
 
 impl VaStreamInfo for &Rc<Sps> {
     fn va_profile(&self) -> anyhow::Result<i32> {
@@ -66,6 +70,9 @@ impl VaStreamInfo for &Rc<Sps> {
                     ))
                 }
             }
+            // VA-API has no dedicated profiles for High 10 and High 4:2:2. Drivers supporting
+            // these streams accept them with the High profile and the matching RT format, which is
+            // checked when creating the config.
             Profile::High | Profile::High422P | Profile::High10 => {
                 Ok(libva::VAProfile::VAProfileH264High)
             }
@@ -73,7 +80,7 @@ impl VaStreamInfo for &Rc<Sps> {
     }
 
     fn rt_format(&self) -> anyhow::Result<u32> {
-        let bit_depth_luma = self.bit_depth_chroma_minus8 + 8;
+        let bit_depth_luma = self.bit_depth_luma_minus8 + 8;
         let chroma_format_idc = self.chroma_format_idc;
 
         match (bit_depth_luma, chroma_format_idc) {
@@ -94,18 +101,22 @@ impl VaStreamInfo for &Rc<Sps> {
         }
     }
 
-    fn min_num_surfaces(&self) -> usize {
-        self.max_dpb_frames() + 4
-    }
+    fn min_num_surfaces(&self) -> NumFramesBreakdown {
+        let (dpb_size, dpb_size_origin) = dpb_size(self);
 
-    fn coded_size(&self) -> (u32, u32) {
-        (self.width, self.height)
+        NumFramesBreakdown {
+            dpb_size,
+            dpb_size_origin,
+            extra: 4,
+        }
     }
 
-    fn visible_rect(&self) -> ((u32, u32), (u32, u32)) {
-        let rect = self.visible_rectangle();
+    fn coded_size(&self) -> Resolution {
+        Resolution::from((self.width, self.height))
+    }
 
-        ((rect.min.x, rect.min.y), (rect.max.x, rect.max.y))
+    fn visible_rect(&self) -> Rect<u32> {
+        self.visible_rectangle()
     }
 }
 
@@ -119,7 +130,22 @@ fn surface_id<M: SurfaceMemoryDescriptor>(
     }
 }
 
-/// Fills the internal `va_pic` picture parameter with data from `h264_pic`
+/// Returns whether the other field of `pic` has the same reference marking, in which case both
+/// fields can be described by a single VA picture.
+fn other_field_is_same_reference(pic: &PictureData) -> bool {
+    match pic.other_field() {
+        Some(other_field) => {
+            let other_field = other_field.borrow();
+            other_field.reference() == pic.reference()
+        }
+        None => false,
+    }
+}
+
+/// Fills the internal `va_pic` picture parameter with data from `h264_pic`.
+///
+/// If `merge_other_field` is set and the other field of `h264_pic` has the same reference marking,
+/// the VA picture describes both fields. Otherwise it only describes the field of `h264_pic`.
 fn fill_va_h264_pic(
     h264_pic: &PictureData,
     surface_id: libva::VASurfaceID,
@@ -147,7 +173,7 @@ fn fill_va_h264_pic(
         }
         Field::Top => {
             match (merge_other_field, h264_pic.other_field()) {
-                (true, Some(other_field)) => {
+                (true, Some(other_field)) if other_field_is_same_reference(h264_pic) => {
                     bottom_field_order_cnt = other_field.borrow().bottom_field_order_cnt
                 }
                 (_, _) => {
@@ -160,7 +186,7 @@ fn fill_va_h264_pic(
         }
         Field::Bottom => {
             match (merge_other_field, h264_pic.other_field()) {
-                (true, Some(other_field)) => {
+                (true, Some(other_field)) if other_field_is_same_reference(h264_pic) => {
                     top_field_order_cnt = other_field.borrow().top_field_order_cnt
                 }
                 (_, _) => {
@@ -225,10 +251,12 @@ fn build_pic_param<M: SurfaceMemoryDescriptor>(
     let mut refs = vec![];
     let mut va_refs = vec![];
 
+    // Second fields are described along with their first field, unless only one of them is used
+    // for reference.
     dpb.get_short_term_refs(&mut refs);
     refs.retain(|handle| {
         let pic = handle.0.borrow();
-        !pic.nonexisting && !pic.is_second_field()
+        !pic.nonexisting && !(pic.is_second_field() && other_field_is_same_reference(&pic))
     });
 
     for handle in &refs {
@@ -243,7 +271,7 @@ fn build_pic_param<M: SurfaceMemoryDescriptor>(
     dpb.get_long_term_refs(&mut refs);
     refs.retain(|handle| {
         let pic = handle.0.borrow();
-        !pic.is_second_field()
+        !(pic.is_second_field() && other_field_is_same_reference(&pic))
     });
 
     for handle in &refs {
@@ -490,13 +518,13 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH264DecoderBackend for Vaapi
         let surface_id = picture.surface().id();
 
         let pic_param = build_pic_param(hdr, picture_data, surface_id, dpb, sps, pps)?;
-        let pic_param = context
-            .create_buffer(pic_param)
+        let pic_param = self
+            .create_buffer(context, pic_param)
             .context("while creating picture parameter buffer")?;
 
         let iq_matrix = build_iq_matrix(pps);
-        let iq_matrix = context
-            .create_buffer(iq_matrix)
+        let iq_matrix = self
+            .create_buffer(context, iq_matrix)
             .context("while creating IQ matrix buffer")?;
 
         picture.add_buffer(pic_param);
@@ -518,21 +546,27 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH264DecoderBackend for Vaapi
         let metadata = self.metadata_state.get_parsed()?;
         let context = &metadata.context;
 
-        let slice_param = context
-            .create_buffer(build_slice_param(
-                &slice.header,
-                slice.nalu.size,
-                ref_pic_list0,
-                ref_pic_list1,
-                sps,
-                pps,
-            )?)
+        let slice_param = self
+            .create_buffer(
+                context,
+                build_slice_param(
+                    &slice.header,
+                    slice.nalu.size,
+                    ref_pic_list0,
+                    ref_pic_list1,
+                    sps,
+                    pps,
+                )?,
+            )
             .context("while creating slice params buffer")?;
 
         picture.add_buffer(slice_param);
 
-        let slice_data = context
-            .create_buffer(BufferType::SliceData(Vec::from(slice.nalu.as_ref())))
+        let slice_data = self
+            .create_buffer(
+                context,
+                BufferType::SliceData(Vec::from(slice.nalu.as_ref())),
+            )
             .context("while creating slice data buffer")?;
 
         picture.add_buffer(slice_data);
@@ -2,13 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
 
 use std::cell::RefCell;
 use std::io::Cursor;
+use std::ops::Range;
+use std::os::fd::BorrowedFd;
 use std::rc::Rc;
 
 use anyhow::anyhow;
@@ -16,33 +17,69 @@ use anyhow::Context;
 
 use crate::codec::h265::dpb::Dpb;
 use crate::codec::h265::dpb::DpbEntry;
+use crate::codec::h265::parser::FramePackingArrangement;
 use crate::codec::h265::parser::Nalu;
 use crate::codec::h265::parser::NaluType;
 use crate::codec::h265::parser::Parser;
+use crate::codec::h265::parser::PicTiming;
 use crate::codec::h265::parser::Pps;
+use crate::codec::h265::parser::SeiMessage;
 use crate::codec::h265::parser::ShortTermRefPicSet;
 use crate::codec::h265::parser::Slice;
 use crate::codec::h265::parser::SliceHeader;
+use crate::codec::h265::parser::SliceType;
 use crate::codec::h265::parser::Sps;
 use crate::codec::h265::picture::PictureData;
 use crate::codec::h265::picture::Reference;
+use crate::decoder::stateless::decode_framed_nalus;
+use crate::decoder::stateless::decode_units;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameCodingType;
+use crate::decoder::stateless::ResumePolicy;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackend;
 use crate::decoder::stateless::StatelessDecoderFormatNegotiator;
 use crate::decoder::stateless::StatelessVideoDecoder;
-use crate::decoder::BlockingMode;
+use crate::decoder::ChromaSiting;
+use crate::decoder::Colorimetry;
 use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
+use crate::decoder::DpbSizeOrigin;
+use crate::decoder::FieldOrder;
+use crate::decoder::FrameInfo;
+use crate::decoder::FramePacking;
+use crate::decoder::FramePackingType;
 use crate::decoder::FramePool;
+use crate::decoder::PictureId;
+use crate::decoder::ReadyFrame;
+use crate::decoder::SkipReason;
 use crate::decoder::StreamInfo;
 use crate::Resolution;
 
+/// Maximum number of pictures a DPB can hold, as per Equation A-2 of the specification.
 const MAX_DPB_SIZE: usize = 16;
 
+/// Returns the number of pictures the DPB must be able to hold to decode a stream using `sps`, and
+/// where this number comes from.
+///
+/// This includes the reference pictures and the pictures held for reordering, but not the picture
+/// being decoded. It is the size checked against the decoder limits and allocated by the backends.
+pub(crate) fn dpb_size(sps: &Sps) -> (usize, DpbSizeOrigin) {
+    // The decoder never holds more than the signaled number of pictures before bumping, but the
+    // level limit still applies to non-conforming streams signaling more.
+    let max_dpb_size = std::cmp::min(sps.max_dpb_size(), MAX_DPB_SIZE);
+    let max_dec_pic_buffering = sps.max_dec_pic_buffering();
+
+    if max_dec_pic_buffering <= max_dpb_size {
+        (max_dec_pic_buffering, DpbSizeOrigin::Stream)
+    } else {
+        (max_dpb_size, DpbSizeOrigin::Level)
+    }
+}
+
 // Equation 5-8
 pub(crate) fn clip3(x: i32, y: i32, z: i32) -> i32 {
     if z < x {
@@ -247,6 +284,27 @@ struct CurrentPicState<B: StatelessDecoderBackend<H265>> {
     backend_pic: B::Picture,
     /// List of reference pictures, used once per slice.
     ref_pic_lists: ReferencePicLists<B::Handle>,
+    /// Number of slice segments of the picture processed so far.
+    num_slices: usize,
+    /// Timestamp of the input the picture started in.
+    timestamp: u64,
+    /// Coding type of the picture, i.e. the highest coding type of its slices so far.
+    coding_type: FrameCodingType,
+    /// Size in bytes of the slice segments of the picture processed so far.
+    coded_size: usize,
+    /// QP of the first slice of the picture.
+    qp: i32,
+}
+
+impl SliceType {
+    /// Returns the coding type of a picture made of slices of this type.
+    fn coding_type(&self) -> FrameCodingType {
+        match self {
+            SliceType::I => FrameCodingType::Intra,
+            SliceType::P => FrameCodingType::Inter,
+            SliceType::B => FrameCodingType::Bidirectional,
+        }
+    }
 }
 
 /// All the reference picture lists used to decode a stream.
@@ -311,6 +369,24 @@ pub struct H265DecoderState<B: StatelessDecoderBackend<H265>> {
     current_pic: Option<CurrentPicState<B>>,
 
     pending_pps: Vec<Vec<u8>>,
+
+    /// POC of the last picture output in low-latency mode. Used to detect streams that require
+    /// reordering.
+    last_output_poc: Option<i32>,
+
+    /// Chroma siting of the current sequence, as signaled in the VUI parameters of its SPS.
+    chroma_siting: ChromaSiting,
+
+    /// Frame packing arrangement signaled by a SEI message for the next picture.
+    pending_frame_packing: Option<FramePackingArrangement>,
+    /// Frame packing arrangement persisting from a previous picture.
+    frame_packing: Option<FramePackingArrangement>,
+
+    /// Field order of the pictures of the current sequence that do not come with a picture timing
+    /// SEI message, as signaled by the profile of its SPS.
+    sequence_field_order: FieldOrder,
+    /// Picture timing signaled by a SEI message for the next picture.
+    pending_pic_timing: Option<PicTiming>,
 }
 
 impl<B> Default for H265DecoderState<B>
@@ -334,19 +410,139 @@ where
             last_independent_slice_header: Default::default(),
             current_pic: Default::default(),
             pending_pps: Default::default(),
+            last_output_poc: Default::default(),
+            chroma_siting: Default::default(),
+            pending_frame_packing: None,
+            frame_packing: None,
+            sequence_field_order: FieldOrder::Unknown,
+            pending_pic_timing: None,
         }
     }
 }
 
+impl<B> H265DecoderState<B>
+where
+    B: StatelessDecoderBackend<H265>,
+{
+    /// Returns the frame packing arrangement applying to the picture being started, and updates
+    /// the persisting one.
+    fn next_frame_packing(&mut self, pic: &PictureData) -> Option<FramePackingArrangement> {
+        // Arrangements do not persist beyond the end of the coded layer-wise video sequence.
+        if pic.is_irap && pic.no_rasl_output_flag {
+            self.frame_packing = None;
+        }
+
+        match self.pending_frame_packing.take() {
+            Some(fpa) if fpa.fpa_cancel_flag => {
+                self.frame_packing = None;
+                None
+            }
+            Some(fpa) => {
+                self.frame_packing = if fpa.fpa_persistence_flag {
+                    Some(fpa.clone())
+                } else {
+                    None
+                };
+                Some(fpa)
+            }
+            None => self.frame_packing.clone(),
+        }
+    }
+
+    /// Returns the frame to output for the DPB entry `entry`.
+    fn ready_frame(&self, entry: DpbEntry<B::Handle>) -> ReadyFrame<B::Handle> {
+        let info = {
+            let pic = entry.0.borrow();
+            FrameInfo {
+                chroma_siting: self.chroma_siting,
+                picture_id: PictureId::PicOrderCnt(pic.pic_order_cnt_val),
+                temporal_id: Some(u32::from(pic.temporal_id)),
+                corrupted: pic.corrupted,
+                field_order: field_order(pic.pic_timing.as_ref(), self.sequence_field_order),
+                frame_packing: pic.frame_packing.as_ref().and_then(frame_packing),
+                ..Default::default()
+            }
+        };
+
+        ReadyFrame::new(entry.1, info)
+    }
+}
+
+/// Returns the field order of a picture with picture timing `pic_timing`, or `default` if the
+/// picture timing does not tell.
+///
+/// Fields are output as separate pictures in H.265. Like for H.264, a field is reported as coming
+/// first if it is a top field, unless the picture timing says which field it is paired with.
+fn field_order(pic_timing: Option<&PicTiming>, default: FieldOrder) -> FieldOrder {
+    let Some(pic_timing) = pic_timing else {
+        return default;
+    };
+
+    // See table D.2 of the specification.
+    match (pic_timing.pic_struct, pic_timing.source_scan_type) {
+        (Some(0 | 7 | 8), 0) => FieldOrder::Unknown,
+        (Some(0 | 7 | 8), 1) => FieldOrder::Progressive,
+        (Some(1 | 3 | 5 | 10 | 11), _) => FieldOrder::TopFieldFirst,
+        (Some(2 | 4 | 6 | 9 | 12), _) => FieldOrder::BottomFieldFirst,
+        _ => default,
+    }
+}
+
+/// Returns the frame packing described by `fpa`, if it is one we can report.
+fn frame_packing(fpa: &FramePackingArrangement) -> Option<FramePacking> {
+    let packing_type = match fpa.fpa_type {
+        0 => FramePackingType::Checkerboard,
+        1 => FramePackingType::ColumnInterleaved,
+        2 => FramePackingType::RowInterleaved,
+        3 => FramePackingType::SideBySide,
+        4 => FramePackingType::TopBottom,
+        5 => FramePackingType::FrameAlternation,
+        // 6 signals non-stereoscopic content, and other values are reserved.
+        _ => return None,
+    };
+
+    Some(FramePacking {
+        packing_type,
+        left_view_first: match fpa.content_interpretation_type {
+            1 => Some(true),
+            2 => Some(false),
+            _ => None,
+        },
+        quincunx_sampling: fpa.quincunx_sampling_flag,
+        is_first_view: fpa.current_frame_is_frame0_flag,
+    })
+}
+
+/// Returns the colorimetry signaled in the VUI of `sps`.
+fn colorimetry(sps: &Sps) -> Colorimetry {
+    if !sps.vui_parameters_present_flag {
+        return Default::default();
+    }
+
+    let vui = &sps.vui_parameters;
+    Colorimetry {
+        color_primaries: vui.colour_primaries as u8,
+        transfer_characteristics: vui.transfer_characteristics as u8,
+        matrix_coefficients: vui.matrix_coeffs as u8,
+        full_range: vui.video_full_range_flag,
+    }
+}
+
 /// [`StatelessCodec`] structure to use in order to create a H.265 stateless decoder.
 ///
 /// # Accepted input
 ///
-/// A decoder using this codec processes exactly one NAL unit of input per call to
-/// [`StatelessDecoder::decode`], and returns the number of bytes until the end of this NAL unit.
-/// This makes it possible to call [`Decode`](StatelessDecoder::decode) repeatedly on some unsplit
-/// Annex B stream and shrinking it by the number of bytes processed after each call, until the
-/// stream ends up being empty.
+/// A decoder using this codec accepts input containing any number of complete NAL units, e.g. a
+/// whole access unit or a larger chunk of an Annex B stream. [`StatelessDecoder::decode`]
+/// processes as many of them as possible and returns the number of bytes until the end of the last
+/// NAL unit processed. If decoding needs to pause before the end of the input, e.g. because events
+/// need to be processed, the remaining part must be submitted again afterwards. This makes it
+/// possible to call [`Decode`](StatelessDecoder::decode) repeatedly on some unsplit Annex B stream
+/// and shrinking it by the number of bytes processed after each call, until the stream ends up
+/// being empty.
+///
+/// Input that may end with an incomplete NAL unit, e.g. data received from a socket, can be
+/// submitted using [`StatelessVideoDecoder::decode_partial`].
 pub struct H265;
 
 impl StatelessCodec for H265 {
@@ -366,7 +562,7 @@ where
         old_negotiation_info: &NegotiationInfo,
     ) -> bool {
         let negotiation_info = NegotiationInfo::from(sps);
-        let max_dpb_size = std::cmp::min(sps.max_dpb_size(), 16);
+        let max_dpb_size = std::cmp::min(sps.max_dpb_size(), MAX_DPB_SIZE);
         let prev_max_dpb_size = dpb.max_num_pics();
 
         *old_negotiation_info != negotiation_info || prev_max_dpb_size != max_dpb_size
@@ -377,8 +573,25 @@ where
         self.drain()?;
         self.codec.negotiation_info = NegotiationInfo::from(sps);
 
-        let max_dpb_size = std::cmp::min(sps.max_dpb_size(), 16);
+        let max_dpb_size = std::cmp::min(sps.max_dpb_size(), MAX_DPB_SIZE);
         self.codec.dpb.set_max_num_pics(max_dpb_size);
+
+        let vui = &sps.vui_parameters;
+        self.codec.chroma_siting =
+            if sps.vui_parameters_present_flag && vui.chroma_loc_info_present_flag {
+                ChromaSiting::from_chroma_sample_loc_type(vui.chroma_sample_loc_type_top_field)
+            } else {
+                ChromaSiting::Unspecified
+            };
+        let ptl = &sps.profile_tier_level;
+        self.codec.sequence_field_order = if ptl.general_progressive_source_flag
+            && !ptl.general_interlaced_source_flag
+            && !(sps.vui_parameters_present_flag && vui.field_seq_flag)
+        {
+            FieldOrder::Progressive
+        } else {
+            FieldOrder::Unknown
+        };
         self.coded_resolution = Resolution {
             width: u32::from(sps.width()),
             height: u32::from(sps.height()),
@@ -588,7 +801,7 @@ where
         // to currPicLayerId are marked as "unused for reference"
         for dpb_pic in self.codec.dpb.entries() {
             let find_predicate = |p: &Option<DpbEntry<B::Handle>>| match p {
-                Some(p) => p.0.borrow().pic_order_cnt_val == dpb_pic.0.borrow().pic_order_cnt_val,
+                Some(p) => Rc::ptr_eq(&p.0, &dpb_pic.0),
                 None => false,
             };
 
@@ -812,7 +1025,7 @@ where
     }
 
     /// Drain the decoder, processing all pending frames.
-    fn drain(&mut self) -> anyhow::Result<()> {
+    fn drain(&mut self) -> Result<(), DecodeError> {
         log::debug!("Draining the decoder");
 
         // Finish the current picture if there is one pending.
@@ -834,8 +1047,10 @@ where
             pics.iter().map(|p| p.0.borrow()).collect::<Vec<_>>()
         );
 
-        self.ready_queue.extend(pics.into_iter().map(|h| h.1));
+        self.ready_queue
+            .extend(pics.into_iter().map(|p| self.codec.ready_frame(p)));
         self.codec.dpb.clear();
+        self.codec.last_output_poc = None;
 
         Ok(())
     }
@@ -846,8 +1061,10 @@ where
         }
 
         self.codec.rps.ref_pic_set_lt_curr = Default::default();
+        self.codec.rps.ref_pic_set_lt_foll = Default::default();
         self.codec.rps.ref_pic_set_st_curr_after = Default::default();
         self.codec.rps.ref_pic_set_st_curr_before = Default::default();
+        self.codec.rps.ref_pic_set_st_foll = Default::default();
 
         self.codec.rps.num_poc_lt_curr = Default::default();
         self.codec.rps.num_poc_lt_foll = Default::default();
@@ -889,6 +1106,7 @@ where
         if cur_pic.is_irap && cur_pic.no_rasl_output_flag && !self.codec.first_picture_after_eos {
             if cur_pic.no_output_of_prior_pics_flag {
                 self.codec.dpb.clear();
+                self.codec.last_output_poc = None;
             } else {
                 self.drain()?;
             }
@@ -909,8 +1127,8 @@ where
                 bumped.iter().map(|p| p.0.borrow()).collect::<Vec<_>>()
             );
 
-            let bumped = bumped.into_iter().map(|p| p.1).collect::<Vec<_>>();
-            self.ready_queue.extend(bumped);
+            self.ready_queue
+                .extend(bumped.into_iter().map(|p| self.codec.ready_frame(p)));
         }
 
         Ok(())
@@ -922,17 +1140,25 @@ where
         timestamp: u64,
         slice: &Slice,
     ) -> Result<Option<CurrentPicState<B>>, DecodeError> {
-        if self.backend.frame_pool().num_free_frames() == 0 {
-            return Err(DecodeError::NotEnoughOutputBuffers(1));
+        if self.num_free_frames(1) == 0 {
+            return Err(self.output_buffers_exhausted(1));
         }
+        self.output_buffers_available();
 
         let pps = self
             .codec
             .parser
             .get_pps(slice.header.pic_parameter_set_id)
             .context("Invalid PPS in handle_picture")?;
+        let qp = 26 + i32::from(pps.init_qp_minus26) + i32::from(slice.header.qp_delta);
 
         let pps_id = pps.pic_parameter_set_id;
+        if pps.tiles_enabled_flag {
+            self.check_num_tiles(
+                (usize::from(pps.num_tile_columns_minus1) + 1)
+                    * (usize::from(pps.num_tile_rows_minus1) + 1),
+            )?;
+        }
         self.update_current_set_ids(pps_id)?;
         self.renegotiate_if_needed(RenegotiationType::CurrentSps)?;
 
@@ -941,7 +1167,7 @@ where
             return Err(DecodeError::CheckEvents);
         }
 
-        let pic = PictureData::new_from_slice(
+        let mut pic = PictureData::new_from_slice(
             slice,
             self.codec
                 .parser
@@ -954,6 +1180,8 @@ where
             timestamp,
         );
 
+        pic.frame_packing = self.codec.next_frame_packing(&pic);
+        pic.pic_timing = self.codec.pending_pic_timing.take();
         self.codec.first_picture_after_eos = false;
         self.codec.first_picture_in_bitstream = false;
 
@@ -972,12 +1200,40 @@ where
                 pic.pic_order_cnt_val
             );
 
+            if slice.header.first_slice_segment_in_pic_flag {
+                self.frame_dropped(timestamp);
+            }
+
             return Ok(None);
         }
 
         log::debug!("Decode picture POC {}", pic.pic_order_cnt_val);
 
         self.decode_rps(slice, &pic)?;
+
+        // Predicting from missing or corrupted references propagates the errors to the current
+        // picture.
+        let rps = &self.codec.rps;
+        let curr_refs = rps.ref_pic_set_st_curr_before[..rps.num_poc_st_curr_before]
+            .iter()
+            .chain(&rps.ref_pic_set_st_curr_after[..rps.num_poc_st_curr_after])
+            .chain(&rps.ref_pic_set_lt_curr[..rps.num_poc_lt_curr]);
+        let missing_refs = curr_refs.clone().any(Option::is_none);
+        pic.corrupted = missing_refs
+            || curr_refs
+                .flatten()
+                .any(|reference| reference.0.borrow().corrupted);
+
+        if missing_refs {
+            self.non_fatal_error(
+                timestamp,
+                anyhow!(
+                    "missing reference pictures for POC {}",
+                    pic.pic_order_cnt_val
+                ),
+            );
+        }
+
         self.update_dpb_before_decoding(&pic)?;
 
         let mut backend_pic = self.backend.new_picture(&pic, timestamp)?;
@@ -1002,6 +1258,11 @@ where
             pic,
             backend_pic,
             ref_pic_lists: Default::default(),
+            num_slices: 0,
+            timestamp,
+            coding_type: slice.header.type_.coding_type(),
+            coded_size: 0,
+            qp,
         }))
     }
 
@@ -1054,13 +1315,29 @@ where
         Ok(())
     }
 
-    fn finish_picture(&mut self, pic: CurrentPicState<B>) -> anyhow::Result<()> {
+    fn finish_picture(&mut self, mut pic: CurrentPicState<B>) -> Result<(), DecodeError> {
         log::debug!("Finishing picture POC {:?}", pic.pic.pic_order_cnt_val);
 
+        // In low-latency mode all the previous pictures have already been output, so the current
+        // one must come after them in display order. Check this before submitting the picture so
+        // the decoder state is left untouched if it does not.
+        if self.low_latency
+            && pic.pic.pic_output_flag
+            && matches!(self.codec.last_output_poc, Some(poc) if poc >= pic.pic.pic_order_cnt_val)
+        {
+            return Err(DecodeError::ReorderingInLowLatencyMode);
+        }
+
+        self.frame_received(pic.timestamp, pic.coding_type, pic.coded_size, pic.qp);
+
         // Submit the picture to the backend.
-        let handle = self.submit_picture(pic.backend_pic)?;
+        let handle = self.submit_picture(pic.backend_pic, &mut pic.pic)?;
         let pic = pic.pic;
 
+        if self.low_latency && pic.pic_output_flag {
+            self.codec.last_output_poc = Some(pic.pic_order_cnt_val);
+        }
+
         // 8.3.1
         if pic.valid_for_prev_tid0_pic {
             self.codec.prev_tid_0_pic = Some(pic.clone());
@@ -1088,8 +1365,15 @@ where
             bumped.iter().map(|p| p.0.borrow()).collect::<Vec<_>>()
         );
 
-        let bumped = bumped.into_iter().map(|p| p.1).collect::<Vec<_>>();
-        self.ready_queue.extend(bumped);
+        self.ready_queue
+            .extend(bumped.into_iter().map(|p| self.codec.ready_frame(p)));
+
+        if self.low_latency {
+            while let Some(pic) = self.codec.dpb.bump(false) {
+                let frame = self.codec.ready_frame(pic);
+                self.ready_queue.push(frame);
+            }
+        }
 
         Ok(())
     }
@@ -1097,7 +1381,7 @@ where
     fn renegotiate_if_needed(
         &mut self,
         renegotiation_type: RenegotiationType,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), DecodeError> {
         let sps = match renegotiation_type {
             RenegotiationType::CurrentSps => self
                 .codec
@@ -1108,6 +1392,10 @@ where
         };
 
         if Self::negotiation_possible(sps, &self.codec.dpb, &self.codec.negotiation_info) {
+            self.check_limits(
+                dpb_size(sps).0,
+                Resolution::from((u32::from(sps.width()), u32::from(sps.height()))),
+            )?;
             // Make sure all the frames we decoded so far are in the ready queue.
             self.drain()?;
             let sps = match renegotiation_type {
@@ -1125,7 +1413,13 @@ where
         Ok(())
     }
 
-    fn process_nalu(&mut self, timestamp: u64, nalu: Nalu) -> Result<(), DecodeError> {
+    /// Processes `nalu`, located at `range` in the input.
+    fn process_nalu(
+        &mut self,
+        timestamp: u64,
+        range: Range<usize>,
+        nalu: Nalu,
+    ) -> Result<(), DecodeError> {
         log::debug!(
             "Processing NALU {:?}, length is {}",
             nalu.header.type_,
@@ -1134,16 +1428,24 @@ where
 
         match nalu.header.type_ {
             NaluType::VpsNut => {
-                self.codec.parser.parse_vps(&nalu)?;
+                self.codec
+                    .parser
+                    .parse_vps(&nalu)
+                    .map_err(DecodeError::ParseError)?;
             }
             NaluType::SpsNut => {
-                let sps = self.codec.parser.parse_sps(&nalu)?;
+                let sps = self
+                    .codec
+                    .parser
+                    .parse_sps(&nalu)
+                    .map_err(DecodeError::ParseError)?;
                 self.codec.max_pic_order_cnt_lsb = 1 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
 
                 // Try parsing the PPS again.
                 for pending_pps in self.codec.pending_pps.clone().iter().enumerate() {
                     let mut cursor: Cursor<&[u8]> = Cursor::new(pending_pps.1.as_ref());
-                    let nalu = crate::codec::h265::parser::Nalu::next(&mut cursor)?;
+                    let nalu = crate::codec::h265::parser::Nalu::next(&mut cursor)
+                        .map_err(DecodeError::ParseError)?;
                     if self.codec.parser.parse_pps(&nalu).is_ok() {
                         self.codec.pending_pps.remove(pending_pps.0);
                     }
@@ -1173,7 +1475,11 @@ where
             | NaluType::RaslN
             | NaluType::RaslR
             | NaluType::CraNut => {
-                let mut slice = self.codec.parser.parse_slice_header(nalu)?;
+                let mut slice = self
+                    .codec
+                    .parser
+                    .parse_slice_header(nalu)
+                    .map_err(DecodeError::ParseError)?;
 
                 let first_slice_segment_in_pic_flag = slice.header.first_slice_segment_in_pic_flag;
 
@@ -1196,11 +1502,45 @@ where
 
                 // Picture may have been dropped during begin_picture()
                 if let Some(mut cur_pic) = cur_pic {
+                    cur_pic.num_slices += 1;
+                    cur_pic.coding_type =
+                        std::cmp::max(cur_pic.coding_type, slice.header.type_.coding_type());
+                    cur_pic.coded_size += range.len();
+                    self.check_num_slices(cur_pic.num_slices)?;
                     self.handle_slice(&mut cur_pic, &slice)?;
                     self.codec.current_pic = Some(cur_pic);
                 }
             }
 
+            NaluType::PrefixSeiNut => {
+                // SEI messages are not required for decoding, so don't fail because of them.
+                match self.codec.parser.parse_sei(&nalu) {
+                    Ok(messages) => {
+                        for message in messages {
+                            match message {
+                                SeiMessage::FramePackingArrangement(fpa) => {
+                                    self.codec.pending_frame_packing = Some(fpa);
+                                }
+                                SeiMessage::PicTiming(pic_timing) => {
+                                    self.codec.pending_pic_timing = Some(pic_timing);
+                                }
+                                SeiMessage::Unsupported(_) => (),
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to parse SEI NAL unit: {:#}", e);
+                        self.stats.num_invalid_seis += 1;
+                        self.data_skipped(timestamp, range, SkipReason::InvalidSei);
+                    }
+                }
+            }
+
+            NaluType::FdNut => {
+                self.stats.num_skipped_nalus += 1;
+                self.data_skipped(timestamp, range, SkipReason::FillerData);
+            }
+
             NaluType::EosNut => {
                 self.codec.first_picture_after_eos = true;
             }
@@ -1209,6 +1549,51 @@ where
                 self.codec.first_picture_in_bitstream = true;
             }
 
+            // Reserved or unspecified units, which we have no way to interpret.
+            NaluType::RsvVclN10
+            | NaluType::RsvVclR11
+            | NaluType::RsvVclN12
+            | NaluType::RsvVclR13
+            | NaluType::RsvVclN14
+            | NaluType::RsvVclR15
+            | NaluType::RsvIrapVcl22
+            | NaluType::RsvIrapVcl23
+            | NaluType::RsvVcl24
+            | NaluType::RsvVcl25
+            | NaluType::RsvVcl26
+            | NaluType::RsvVcl27
+            | NaluType::RsvVcl28
+            | NaluType::RsvVcl29
+            | NaluType::RsvVcl30
+            | NaluType::RsvVcl31
+            | NaluType::RsvNvcl41
+            | NaluType::RsvNvcl42
+            | NaluType::RsvNvcl43
+            | NaluType::RsvNvcl44
+            | NaluType::RsvNvcl45
+            | NaluType::RsvNvcl46
+            | NaluType::RsvNvcl47
+            | NaluType::UnspecNvcl48
+            | NaluType::UnspecNvcl49
+            | NaluType::UnspecNvcl50
+            | NaluType::UnspecNvcl51
+            | NaluType::UnspecNvcl52
+            | NaluType::UnspecNvcl53
+            | NaluType::UnspecNvcl54
+            | NaluType::UnspecNvcl55
+            | NaluType::UnspecNvcl56
+            | NaluType::UnspecNvcl57
+            | NaluType::UnspecNvcl58
+            | NaluType::UnspecNvcl59
+            | NaluType::UnspecNvcl60
+            | NaluType::UnspecNvcl61
+            | NaluType::UnspecNvcl62
+            | NaluType::UnspecNvcl63 => {
+                log::warn!("Skipping unsupported NAL unit type {:?}", nalu.header.type_);
+                self.stats.num_skipped_nalus += 1;
+                self.data_skipped(timestamp, range, SkipReason::UnsupportedUnit);
+            }
+
             other => {
                 log::debug!("Unsupported NAL unit type {:?}", other,);
             }
@@ -1217,30 +1602,39 @@ where
         Ok(())
     }
 
-    /// Submits the picture to the accelerator.
-    fn submit_picture(&mut self, backend_pic: B::Picture) -> Result<B::Handle, DecodeError> {
-        let handle = self.backend.submit_picture(backend_pic)?;
+    /// Decodes the first NAL unit of `bitstream`, located at `offset` in the input, and returns
+    /// the number of bytes until its end.
+    fn decode_nalu(
+        &mut self,
+        timestamp: u64,
+        offset: usize,
+        bitstream: &[u8],
+    ) -> Result<usize, DecodeError> {
+        let mut cursor = Cursor::new(bitstream);
+        let nalu = Nalu::next(&mut cursor).map_err(DecodeError::ParseError)?;
+        let range = offset + nalu.offset..offset + nalu.offset + nalu.size;
 
-        if self.blocking_mode == BlockingMode::Blocking {
-            handle.sync()?;
+        // Units of the enhancement or auxiliary layers (e.g. MV-HEVC, SHVC, alpha channel) are not
+        // needed to decode the base layer, which is all we support. Streams with an alpha channel
+        // carry one such unit per picture, so don't warn about each of them.
+        if nalu.header.nuh_layer_id > 0 {
+            log::debug!(
+                "Skipping NAL unit {:?} of layer {}",
+                nalu.header.type_,
+                nalu.header.nuh_layer_id
+            );
+            self.stats.num_skipped_nalus += 1;
+            self.data_skipped(timestamp, range, SkipReason::OtherLayer);
+            return Ok(nalu.offset + nalu.size);
         }
 
-        Ok(handle)
-    }
-}
-
-impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor>
-    for StatelessDecoder<H265, B>
-where
-    B: StatelessH265DecoderBackend,
-    B::Handle: Clone + 'static,
-{
-    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
-        let mut cursor = Cursor::new(bitstream);
-        let nalu = Nalu::next(&mut cursor)?;
-
         if nalu.header.type_ == NaluType::SpsNut {
-            let sps = self.codec.parser.parse_sps(&nalu)?.clone();
+            let sps = self
+                .codec
+                .parser
+                .parse_sps(&nalu)
+                .map_err(DecodeError::ParseError)?
+                .clone();
             if matches!(self.decoding_state, DecodingState::AwaitingStreamInfo) {
                 // If more SPS come along we will renegotiate in begin_picture().
                 self.renegotiate_if_needed(RenegotiationType::NewSps(&sps))?;
@@ -1252,8 +1646,39 @@ where
             let mut cursor = Cursor::new(bitstream);
 
             while let Ok(nalu) = Nalu::next(&mut cursor) {
-                // In the Reset state we can resume decoding from any key frame.
-                if nalu.header.type_.is_idr() {
+                // Only pictures of the base layer can be resumed from, since the other layers are
+                // skipped.
+                if nalu.header.nuh_layer_id > 0 {
+                    continue;
+                }
+
+                // In the Reset state we can resume decoding from any key frame, or from any intra
+                // picture if the resume policy allows it.
+                let is_cra = nalu.header.type_.is_cra();
+                let can_resume = if nalu.header.type_.is_idr() {
+                    true
+                } else if self.resume_policy == ResumePolicy::IntraFrame
+                    && (nalu.header.type_ as u32) <= NaluType::CraNut as u32
+                {
+                    nalu.header.type_.is_irap()
+                        || self
+                            .codec
+                            .parser
+                            .parse_slice_header(nalu)
+                            .is_ok_and(|slice| {
+                                slice.header.first_slice_segment_in_pic_flag
+                                    && slice.header.type_.is_i()
+                            })
+                } else {
+                    false
+                };
+
+                if can_resume {
+                    // Handle a CRA picture like the start of a new sequence, so its RASL pictures,
+                    // which reference pictures from before it, are dropped.
+                    if is_cra {
+                        self.codec.first_picture_after_eos = true;
+                    }
                     self.decoding_state = DecodingState::Decoding;
                     break;
                 }
@@ -1270,32 +1695,116 @@ where
                     nalu.header.type_,
                     NaluType::VpsNut | NaluType::SpsNut | NaluType::PpsNut
                 ) {
-                    self.process_nalu(timestamp, nalu)?;
+                    self.process_nalu(timestamp, range, nalu)?;
                 }
             }
             // Ask the client to confirm the format before we can process this.
             DecodingState::AwaitingFormat(_) => return Err(DecodeError::CheckEvents),
             DecodingState::Decoding => {
-                self.process_nalu(timestamp, nalu)?;
+                self.process_nalu(timestamp, range, nalu)?;
             }
         }
 
         Ok(nalu_len)
     }
 
+    /// Submits the picture to the accelerator, flagging `pic` as corrupted if it did not complete
+    /// in time.
+    fn submit_picture(
+        &mut self,
+        backend_pic: B::Picture,
+        pic: &mut PictureData,
+    ) -> Result<B::Handle, DecodeError> {
+        let handle = self.backend.submit_picture(backend_pic)?;
+        self.picture_submitted(&handle)?;
+
+        if !self.sync_picture(&handle)? {
+            pic.corrupted = true;
+        }
+
+        Ok(handle)
+    }
+}
+
+/// Resets `decoder` after the loss of its backend's device, so the format is negotiated again at
+/// the next IRAP picture.
+fn reset_after_device_loss<B>(decoder: &mut StatelessDecoder<H265, B>)
+where
+    B: StatelessH265DecoderBackend,
+    B::Handle: Clone + 'static,
+{
+    decoder.reset();
+    decoder.codec.negotiation_info = Default::default();
+}
+
+impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor>
+    for StatelessDecoder<H265, B>
+where
+    B: StatelessH265DecoderBackend,
+    B::Handle: Clone + 'static,
+{
+    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        self.input_submitted(timestamp);
+        let res = decode_framed_nalus(self.input_framing, bitstream, false, |bitstream| {
+            decode_units(bitstream, |offset, nalu| {
+                self.decode_nalu(timestamp, offset, nalu)
+            })
+        });
+        let res = self.recover_if_device_lost(res, reset_after_device_loss);
+        self.update_readiness();
+
+        res
+    }
+
+    fn decode_partial(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        self.input_submitted(timestamp);
+        let res = decode_framed_nalus(self.input_framing, bitstream, true, |bitstream| {
+            decode_units(bitstream, |offset, nalu| {
+                self.decode_nalu(timestamp, offset, nalu)
+            })
+        });
+        let res = self.recover_if_device_lost(res, reset_after_device_loss);
+        self.update_readiness();
+
+        res
+    }
+
     fn flush(&mut self) -> Result<(), DecodeError> {
-        self.drain()?;
+        let res = self.drain();
+        let res = self.recover_if_device_lost(res, reset_after_device_loss);
+        self.update_readiness();
+        res?;
         self.decoding_state = DecodingState::Reset;
 
         Ok(())
     }
 
+    fn reset(&mut self) {
+        // The current picture has not been submitted yet, and the pictures of the DPB are
+        // dropped instead of being output.
+        self.codec.current_pic = None;
+        self.codec.dpb.clear();
+        self.codec.last_output_poc = None;
+        self.ready_queue.clear();
+        self.decoding_state = DecodingState::Reset;
+        self.update_readiness();
+    }
+
     fn next_event(&mut self) -> Option<DecoderEvent<<B::Handle as DecodedHandle>::Descriptor>> {
+        // Clear the readiness fd once all the events have been retrieved.
+        self.update_readiness();
+
+        // Report pending notifications first, e.g. so the client can react to the end of an
+        // output buffers stall before it happens again.
+        if let Some(event) = self.take_pending_event() {
+            return Some(event);
+        }
+
         // The next event is either the next frame, or, if we are awaiting negotiation, the format
         // change event that will allow us to keep going.
         (&mut self.ready_queue)
             .next()
-            .map(|handle| DecoderEvent::FrameReady(Box::new(handle)))
+            .map(|frame| DecoderEvent::FrameReady(Box::new(self.output_frame(frame))))
             .or_else(|| {
                 if let DecodingState::AwaitingFormat(sps) = &self.decoding_state {
                     Some(DecoderEvent::FormatChanged(Box::new(
@@ -1304,6 +1813,7 @@ where
                             // on the next decode() call.
                             // TODO: unwrap this for now, but ideally change this closure to return Result
                             decoder.apply_sps(sps).unwrap();
+                            decoder.stream_params_applied(colorimetry(sps));
                             decoder.decoding_state = DecodingState::Decoding;
                         }),
                     )))
@@ -1320,44 +1830,62 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn ready_for_input(&mut self) -> bool {
+        self.ready_for_input()
+    }
+
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+        self.readiness_fd()
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use std::io::Cursor;
 
     use crate::codec::h265::parser::Nalu;
+    use crate::codec::h265::parser::NaluType;
+    use crate::codec::h265::parser::Parser;
+    use crate::codec::h265::parser::PicTiming;
+    use crate::decoder::stateless::h265::dpb_size;
+    use crate::decoder::stateless::h265::field_order;
     use crate::decoder::stateless::h265::H265;
-    use crate::decoder::stateless::tests::test_decode_stream;
+    use crate::decoder::stateless::tests::codec_stream_tests;
+    use crate::decoder::stateless::tests::TestCodec;
     use crate::decoder::stateless::tests::TestStream;
+    use crate::decoder::stateless::DecodeError;
+    use crate::decoder::stateless::DecoderLimits;
     use crate::decoder::stateless::StatelessDecoder;
     use crate::decoder::BlockingMode;
+    use crate::decoder::DpbSizeOrigin;
+    use crate::decoder::FieldOrder;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
     use crate::utils::NalIterator;
     use crate::DecodedFormat;
 
-    /// Run `test` using the dummy decoder, in both blocking and non-blocking modes.
-    fn test_decoder_dummy(test: &TestStream, blocking_mode: BlockingMode) {
-        let decoder = StatelessDecoder::<H265, _>::new_dummy(blocking_mode);
-
-        test_decode_stream(
-            |d, s, f| {
-                simple_playback_loop(
-                    d,
-                    NalIterator::<Nalu>::new(s),
-                    f,
-                    &mut simple_playback_loop_owned_frames,
-                    DecodedFormat::NV12,
-                    blocking_mode,
-                )
-            },
-            decoder,
-            test,
-            false,
-            false,
-        );
+    impl TestCodec for H265 {
+        fn split_stream(stream: &[u8]) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+            Box::new(NalIterator::<Nalu>::new(stream))
+        }
     }
 
+    codec_stream_tests!(
+        H265,
+        StatelessDecoder::<H265, _>::new_dummy,
+        DecodedFormat::NV12,
+        false,
+        {
+            test_64x64_progressive_i => DECODE_64X64_PROGRESSIVE_I,
+            test_64x64_progressive_i_p => DECODE_64X64_PROGRESSIVE_I_P,
+            test_64x64_progressive_i_p_b_p => DECODE_64X64_PROGRESSIVE_I_P_B_P,
+            test_25fps => DECODE_TEST_25FPS,
+            test_bear => DECODE_BEAR,
+            test_bbb => DECODE_BBB,
+        }
+    );
+
     /// A 64x64 progressive byte-stream encoded I-frame to make it easier to
     /// spot errors on the libva trace.
     /// Encoded with the following GStreamer pipeline:
@@ -1368,16 +1896,6 @@ pub mod tests {
         crcs: include_str!("../../codec/h265/test_data/64x64-I.h265.crc"),
     };
 
-    #[test]
-    fn test_64x64_progressive_i_block() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_64x64_progressive_i_nonblock() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I, BlockingMode::NonBlocking);
-    }
-
     /// A 64x64 progressive byte-stream encoded I-frame and P-frame to make
     /// it easier to spot errors on the libva trace.
     /// Encoded with the following GStreamer pipeline:
@@ -1387,16 +1905,6 @@ pub mod tests {
         crcs: include_str!("../../codec/h265/test_data/64x64-I-P.h265.crc"),
     };
 
-    #[test]
-    fn test_64x64_progressive_i_p_block() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_64x64_progressive_i_p_nonblock() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P, BlockingMode::NonBlocking);
-    }
-
     /// A 64x64 progressive byte-stream encoded I-P-B-P sequence to make it
     /// easier to it easier to spot errors on the libva trace.
     /// Encoded with the following GStreamer pipeline:
@@ -1406,61 +1914,150 @@ pub mod tests {
         crcs: include_str!("../../codec/h265/test_data/64x64-I-P-B-P.h265.crc"),
     };
 
-    #[test]
-    fn test_64x64_progressive_i_p_b_p_block() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P_B_P, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_64x64_progressive_i_p_b_p_nonblock() {
-        test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P_B_P, BlockingMode::NonBlocking);
-    }
-
     /// Same as Chromium's test-25fps.h265
     pub const DECODE_TEST_25FPS: TestStream = TestStream {
         stream: include_bytes!("../../codec/h265/test_data/test-25fps.h265"),
         crcs: include_str!("../../codec/h265/test_data/test-25fps.h265.crc"),
     };
 
-    #[test]
-    fn test_25fps_block() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_25fps_nonblock() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
-    }
-
     /// Same as Chromium's bear.h265
     pub const DECODE_BEAR: TestStream = TestStream {
         stream: include_bytes!("../../codec/h265/test_data/bear.h265"),
         crcs: include_str!("../../codec/h265/test_data/bear.h265.crc"),
     };
 
-    #[test]
-    fn test_bear_block() {
-        test_decoder_dummy(&DECODE_BEAR, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_bear_nonblock() {
-        test_decoder_dummy(&DECODE_BEAR, BlockingMode::NonBlocking);
-    }
-
     /// Same as Chromium's bbb.h265
     pub const DECODE_BBB: TestStream = TestStream {
         stream: include_bytes!("../../codec/h265/test_data/bbb.h265"),
         crcs: include_str!("../../codec/h265/test_data/bbb.h265.crc"),
     };
 
+    /// Decode `test` in low-latency mode using the dummy decoder, returning the number of frames
+    /// output.
+    ///
+    /// Also checks that all the frames received by the decoder have been output, i.e. that a frame
+    /// requiring reordering is rejected before being submitted.
+    fn decode_low_latency(test: &TestStream) -> anyhow::Result<usize> {
+        let mut decoder = StatelessDecoder::<H265, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_low_latency(true);
+        decoder.set_bitstream_stats_interval(Some(1));
+
+        let mut num_frames = 0;
+        let res = simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(test.stream),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        );
+
+        let stats = decoder.bitstream_stats().unwrap();
+        let num_received = stats.intra_frames.num_frames
+            + stats.inter_frames.num_frames
+            + stats.bidirectional_frames.num_frames;
+        assert_eq!(num_received, num_frames as u64);
+
+        res.map(|()| num_frames)
+    }
+
     #[test]
-    fn test_bbb_block() {
-        test_decoder_dummy(&DECODE_BBB, BlockingMode::Blocking);
+    fn test_64x64_progressive_i_p_low_latency() {
+        let num_frames = decode_low_latency(&DECODE_64X64_PROGRESSIVE_I_P).unwrap();
+        assert_eq!(
+            num_frames,
+            DECODE_64X64_PROGRESSIVE_I_P.crcs.lines().count()
+        );
     }
 
     #[test]
-    fn test_bbb_nonblock() {
-        test_decoder_dummy(&DECODE_BBB, BlockingMode::NonBlocking);
+    fn test_64x64_progressive_i_p_b_p_low_latency() {
+        let err = decode_low_latency(&DECODE_64X64_PROGRESSIVE_I_P_B_P).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::ReorderingInLowLatencyMode)
+        ));
+    }
+
+    #[test]
+    fn test_dpb_size_limit() {
+        let test = &DECODE_64X64_PROGRESSIVE_I_P_B_P;
+
+        let mut parser = Parser::default();
+        let mut cursor = Cursor::new(test.stream);
+        let (size, origin) = loop {
+            let nalu = Nalu::next(&mut cursor).unwrap();
+            if nalu.header.type_ == NaluType::SpsNut {
+                let sps = parser.parse_sps(&nalu).unwrap();
+                assert!(sps.max_dec_pic_buffering() < sps.max_dpb_size());
+                break dpb_size(sps);
+            }
+        };
+        // The signaled DPB size is used rather than the larger one derived from the level.
+        assert_eq!(origin, DpbSizeOrigin::Stream);
+
+        let decode_with_max_dpb_size = |max_dpb_size| {
+            let mut decoder = StatelessDecoder::<H265, _>::new_dummy(BlockingMode::Blocking);
+            decoder.set_limits(DecoderLimits {
+                max_dpb_size: Some(max_dpb_size),
+                ..Default::default()
+            });
+
+            simple_playback_loop(
+                &mut decoder,
+                NalIterator::<Nalu>::new(test.stream),
+                &mut |_| (),
+                &mut simple_playback_loop_owned_frames,
+                DecodedFormat::NV12,
+                BlockingMode::Blocking,
+            )
+        };
+
+        decode_with_max_dpb_size(size).unwrap();
+        let err = decode_with_max_dpb_size(size - 1).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::DpbSizeLimitExceeded(s, m)) if *s == size && *m == size - 1
+        ));
+    }
+
+    #[test]
+    fn test_field_order() {
+        let pic_timing = |pic_struct, source_scan_type| PicTiming {
+            pic_struct,
+            source_scan_type,
+            duplicate_flag: false,
+        };
+
+        for default in [FieldOrder::Progressive, FieldOrder::Unknown] {
+            assert_eq!(field_order(None, default), default);
+            assert_eq!(field_order(Some(&pic_timing(None, 0)), default), default);
+            assert_eq!(field_order(Some(&pic_timing(Some(0), 2)), default), default);
+        }
+
+        let expected = [
+            (0, 1, FieldOrder::Progressive),
+            (7, 1, FieldOrder::Progressive),
+            (0, 0, FieldOrder::Unknown),
+            (1, 0, FieldOrder::TopFieldFirst),
+            (2, 0, FieldOrder::BottomFieldFirst),
+            (3, 0, FieldOrder::TopFieldFirst),
+            (4, 0, FieldOrder::BottomFieldFirst),
+            (9, 0, FieldOrder::BottomFieldFirst),
+            (10, 0, FieldOrder::TopFieldFirst),
+            (11, 0, FieldOrder::TopFieldFirst),
+            (12, 0, FieldOrder::BottomFieldFirst),
+        ];
+        for (pic_struct, source_scan_type, order) in expected {
+            assert_eq!(
+                field_order(
+                    Some(&pic_timing(Some(pic_struct), source_scan_type)),
+                    FieldOrder::Progressive
+                ),
+                order,
+                "pic_struct {}",
+                pic_struct
+            );
+        }
     }
 }
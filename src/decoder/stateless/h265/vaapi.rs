@@ -32,6 +32,7 @@ use crate::codec::h265::parser::Sps;
 use crate::codec::h265::picture::PictureData;
 use crate::codec::h265::picture::Reference;
 use crate::decoder::stateless::h265::clip3;
+use crate::decoder::stateless::h265::dpb_size;
 use crate::decoder::stateless::h265::RefPicListEntry;
 use crate::decoder::stateless::h265::RefPicSet;
 use crate::decoder::stateless::h265::StatelessH265DecoderBackend;
@@ -41,6 +42,9 @@ use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::BlockingMode;
+use crate::decoder::NumFramesBreakdown;
+use crate::Rect;
+use crate::Resolution;
 
 enum ScalingListType {
     Sps,
@@ -121,18 +125,22 @@ impl VaStreamInfo for &Sps {
         }
     }
 
-    fn min_num_surfaces(&self) -> usize {
-        self.max_dpb_size() + 4
-    }
+    fn min_num_surfaces(&self) -> NumFramesBreakdown {
+        let (dpb_size, dpb_size_origin) = dpb_size(self);
 
-    fn coded_size(&self) -> (u32, u32) {
-        (self.width().into(), self.height().into())
+        NumFramesBreakdown {
+            dpb_size,
+            dpb_size_origin,
+            extra: 4,
+        }
     }
 
-    fn visible_rect(&self) -> ((u32, u32), (u32, u32)) {
-        let rect = self.visible_rectangle();
+    fn coded_size(&self) -> Resolution {
+        Resolution::from((self.width().into(), self.height().into()))
+    }
 
-        ((rect.min.x, rect.min.y), (rect.max.x, rect.max.y))
+    fn visible_rect(&self) -> Rect<u32> {
+        self.visible_rectangle()
     }
 }
 
@@ -548,18 +556,18 @@ impl<M: SurfaceMemoryDescriptor + 'static> VaapiBackend<M> {
             let picture = &mut picture.picture;
 
             let slice_param = BufferType::SliceParameter(SliceParameter::HEVC(last_slice.0));
-            let slice_param = context.create_buffer(slice_param)?;
+            let slice_param = self.create_buffer(context, slice_param)?;
             picture.add_buffer(slice_param);
 
             if let Some(slice_param_rext) = last_slice.1 {
                 let slice_param_rext =
                     BufferType::SliceParameter(SliceParameter::HEVCRext(slice_param_rext));
-                let slice_param_rext = context.create_buffer(slice_param_rext)?;
+                let slice_param_rext = self.create_buffer(context, slice_param_rext)?;
                 picture.add_buffer(slice_param_rext);
             }
 
             let slice_data = BufferType::SliceData(last_slice.2);
-            let slice_data = context.create_buffer(slice_data)?;
+            let slice_data = self.create_buffer(context, slice_data)?;
             picture.add_buffer(slice_data);
         }
 
@@ -633,16 +641,16 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH265DecoderBackend for Vaapi
 
         let picture = &mut picture.picture;
 
-        let pic_param = context
-            .create_buffer(pic_param)
+        let pic_param = self
+            .create_buffer(context, pic_param)
             .context("while creating picture parameter buffer")?;
 
         picture.add_buffer(pic_param);
 
         if !matches!(find_scaling_list(sps, pps), ScalingListType::None) {
             let iq_matrix = build_iq_matrix(sps, pps);
-            let iq_matrix = context
-                .create_buffer(iq_matrix)
+            let iq_matrix = self
+                .create_buffer(context, iq_matrix)
                 .context("while creating IQ matrix buffer")?;
 
             picture.add_buffer(iq_matrix);
@@ -651,16 +659,16 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH265DecoderBackend for Vaapi
         let va_profile = sps.va_profile()?;
         if is_range_extension_profile(va_profile) || is_scc_ext_profile(va_profile) {
             let rext = build_picture_rext(sps, pps)?;
-            let rext = context
-                .create_buffer(rext)
+            let rext = self
+                .create_buffer(context, rext)
                 .context("while creating picture parameter range extension buffer")?;
 
             picture.add_buffer(rext);
 
             if is_scc_ext_profile(va_profile) {
                 let scc = build_picture_scc(sps, pps)?;
-                let scc = context
-                    .create_buffer(scc)
+                let scc = self
+                    .create_buffer(context, scc)
                     .context("while creating picture screen content coding buffer")?;
 
                 picture.add_buffer(scc);
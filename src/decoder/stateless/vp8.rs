@@ -2,11 +2,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
 
+use std::os::fd::BorrowedFd;
+use std::rc::Rc;
+
 use crate::codec::vp8::parser::Frame;
 use crate::codec::vp8::parser::Header;
 use crate::codec::vp8::parser::MbLfAdjustments;
@@ -14,23 +16,33 @@ use crate::codec::vp8::parser::Parser;
 use crate::codec::vp8::parser::Segmentation;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameCodingType;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackend;
 use crate::decoder::stateless::StatelessDecoderFormatNegotiator;
 use crate::decoder::stateless::StatelessVideoDecoder;
-use crate::decoder::BlockingMode;
+use crate::decoder::Colorimetry;
 use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
+use crate::decoder::FrameInfo;
 use crate::decoder::FramePool;
+use crate::decoder::LoopFilterDeltas;
+use crate::decoder::PictureId;
+use crate::decoder::ReadyFrame;
+use crate::decoder::SegmentParams;
+use crate::decoder::SegmentationInfo;
 use crate::decoder::StreamInfo;
 use crate::Resolution;
 
+/// Number of reference frames kept by VP8: the last, golden and alt-ref frames.
+const NUM_REF_FRAMES: usize = 3;
+
 /// Stateless backend methods specific to VP8.
 pub trait StatelessVp8DecoderBackend: StatelessDecoderBackend<Vp8> {
     /// Called when new stream parameters are found.
-    fn new_sequence(&mut self, header: &Header) -> StatelessBackendResult<()>;
+    fn new_sequence(&mut self, header: &Rc<Header>) -> StatelessBackendResult<()>;
 
     /// Called when the decoder wants the backend to finish the decoding
     /// operations for `picture`.
@@ -61,6 +73,9 @@ pub struct Vp8DecoderState<B: StatelessDecoderBackend<Vp8>> {
     golden_ref_picture: Option<B::Handle>,
     /// The picture used as the alternate reference picture.
     alt_ref_picture: Option<B::Handle>,
+
+    /// Number of frames shown so far, used as index of the next shown frame.
+    num_shown_frames: u64,
 }
 
 impl<B: StatelessDecoderBackend<Vp8>> Default for Vp8DecoderState<B> {
@@ -70,10 +85,64 @@ impl<B: StatelessDecoderBackend<Vp8>> Default for Vp8DecoderState<B> {
             last_picture: Default::default(),
             golden_ref_picture: Default::default(),
             alt_ref_picture: Default::default(),
+            num_shown_frames: 0,
         }
     }
 }
 
+/// Returns the colorimetry signaled by the color space of `hdr`.
+///
+/// VP8 only defines the BT.601 matrix, the other color space value being reserved.
+fn colorimetry(hdr: &Header) -> Colorimetry {
+    if hdr.color_space {
+        return Default::default();
+    }
+
+    Colorimetry {
+        matrix_coefficients: 6,
+        ..Default::default()
+    }
+}
+
+/// Returns the segmentation parameters of a frame with header `hdr`, if `seg` enables
+/// segmentation.
+fn segmentation_info(hdr: &Header, seg: &Segmentation) -> Option<SegmentationInfo> {
+    if !seg.segmentation_enabled {
+        return None;
+    }
+
+    let segments = (0..seg.quantizer_update_value.len())
+        .map(|i| {
+            let mut qindex = i16::from(seg.quantizer_update_value[i]);
+            let mut loop_filter_level = i16::from(seg.lf_update_value[i]);
+            // The segment values are deltas unless they are absolute.
+            if !seg.segment_feature_mode {
+                qindex += i16::from(hdr.quant_indices.y_ac_qi);
+                loop_filter_level += i16::from(hdr.loop_filter_level);
+            }
+
+            SegmentParams {
+                qindex: qindex.clamp(0, 127) as u8,
+                loop_filter_level: loop_filter_level.clamp(0, 63) as u8,
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    Some(SegmentationInfo {
+        map_updated: seg.update_mb_segmentation_map,
+        segments,
+    })
+}
+
+/// Returns the loop filter adjustments in `adj`, if they are enabled.
+fn loop_filter_deltas(adj: &MbLfAdjustments) -> Option<LoopFilterDeltas> {
+    adj.loop_filter_adj_enable.then(|| LoopFilterDeltas {
+        ref_frame: adj.ref_frame_delta,
+        mode: adj.mb_mode_delta.to_vec(),
+    })
+}
+
 /// [`StatelessCodec`] structure to use in order to create a VP8 stateless decoder.
 ///
 /// # Accepted input
@@ -85,7 +154,7 @@ impl<B: StatelessDecoderBackend<Vp8>> Default for Vp8DecoderState<B> {
 pub struct Vp8;
 
 impl StatelessCodec for Vp8 {
-    type FormatInfo = Header;
+    type FormatInfo = Rc<Header>;
     type DecoderState<B: StatelessDecoderBackend<Self>> = Vp8DecoderState<B>;
 }
 
@@ -169,12 +238,25 @@ where
 {
     /// Handle a single frame.
     fn handle_frame(&mut self, frame: Frame, timestamp: u64) -> Result<(), DecodeError> {
-        if self.backend.frame_pool().num_free_frames() == 0 {
-            return Err(DecodeError::NotEnoughOutputBuffers(1));
+        if self.num_free_frames(1) == 0 {
+            return Err(self.output_buffers_exhausted(1));
         }
+        self.output_buffers_available();
 
         let show_frame = frame.header.show_frame;
 
+        let coding_type = if frame.header.key_frame {
+            FrameCodingType::Intra
+        } else {
+            FrameCodingType::Inter
+        };
+        self.frame_received(
+            timestamp,
+            coding_type,
+            frame.header.frame_len(),
+            i32::from(frame.header.quant_indices.y_ac_qi),
+        );
+
         let decoded_handle = self.backend.submit_picture(
             &frame.header,
             self.codec.last_picture.as_ref(),
@@ -185,17 +267,24 @@ where
             self.codec.parser.mb_lf_adjust(),
             timestamp,
         )?;
-
-        if self.blocking_mode == BlockingMode::Blocking {
-            decoded_handle.sync()?;
-        }
+        self.picture_submitted(&decoded_handle)?;
+        let completed = self.sync_picture(&decoded_handle)?;
 
         // Do DPB management
         self.codec
             .update_references(&frame.header, &decoded_handle)?;
 
         if show_frame {
-            self.ready_queue.push(decoded_handle);
+            let info = FrameInfo {
+                picture_id: PictureId::FrameIndex(self.codec.num_shown_frames),
+                corrupted: !completed,
+                segmentation: segmentation_info(&frame.header, self.codec.parser.segmentation()),
+                loop_filter_deltas: loop_filter_deltas(self.codec.parser.mb_lf_adjust()),
+                ..Default::default()
+            };
+            self.codec.num_shown_frames += 1;
+
+            self.ready_queue.push(ReadyFrame::new(decoded_handle, info));
         }
 
         Ok(())
@@ -209,20 +298,27 @@ where
 
         width != coded_resolution.width || height != coded_resolution.height
     }
-}
 
-impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor> for StatelessDecoder<Vp8, B>
-where
-    B: StatelessVp8DecoderBackend,
-    B::Handle: Clone + 'static,
-{
-    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
-        let frame = self.codec.parser.parse_frame(bitstream)?;
+    /// Decodes the frame in `bitstream`.
+    fn decode_frame(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        let frame = self
+            .codec
+            .parser
+            .parse_frame(bitstream)
+            .map_err(DecodeError::ParseError)?;
 
         if frame.header.key_frame {
             if self.negotiation_possible(&frame) {
-                self.backend.new_sequence(&frame.header)?;
-                self.decoding_state = DecodingState::AwaitingFormat(frame.header.clone());
+                self.check_limits(
+                    NUM_REF_FRAMES,
+                    Resolution::from((
+                        u32::from(frame.header.width),
+                        u32::from(frame.header.height),
+                    )),
+                )?;
+                let header = Rc::new(frame.header.clone());
+                self.backend.new_sequence(&header)?;
+                self.decoding_state = DecodingState::AwaitingFormat(header);
             } else if matches!(self.decoding_state, DecodingState::Reset) {
                 // We can resume decoding since the decoding parameters have not changed.
                 self.decoding_state = DecodingState::Decoding;
@@ -241,6 +337,23 @@ where
             }
         }
     }
+}
+
+impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor> for StatelessDecoder<Vp8, B>
+where
+    B: StatelessVp8DecoderBackend,
+    B::Handle: Clone + 'static,
+{
+    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        self.input_submitted(timestamp);
+        let res = self.decode_frame(timestamp, bitstream);
+        // The coded resolution is cleared on device loss, which makes the next key frame
+        // renegotiate the format.
+        let res = self.recover_if_device_lost(res, |decoder| decoder.reset());
+        self.update_readiness();
+
+        res
+    }
 
     fn flush(&mut self) -> Result<(), DecodeError> {
         // Note: all the submitted frames are already in the ready queue.
@@ -252,22 +365,45 @@ where
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.codec.last_picture = Default::default();
+        self.codec.golden_ref_picture = Default::default();
+        self.codec.alt_ref_picture = Default::default();
+        self.ready_queue.clear();
+        self.decoding_state = DecodingState::Reset;
+        self.update_readiness();
+    }
+
     fn next_event(&mut self) -> Option<DecoderEvent<<B::Handle as DecodedHandle>::Descriptor>> {
+        // Clear the readiness fd once all the events have been retrieved.
+        self.update_readiness();
+
+        // Report pending notifications first, e.g. so the client can react to the end of an
+        // output buffers stall before it happens again.
+        if let Some(event) = self.take_pending_event() {
+            return Some(event);
+        }
+
         // The next event is either the next frame, or, if we are awaiting negotiation, the format
         // change event that will allow us to keep going.
         (&mut self.ready_queue)
             .next()
-            .map(|handle| DecoderEvent::FrameReady(Box::new(handle)))
+            .map(|frame| DecoderEvent::FrameReady(Box::new(self.output_frame(frame))))
             .or_else(|| {
                 if let DecodingState::AwaitingFormat(hdr) = &self.decoding_state {
                     Some(DecoderEvent::FormatChanged(Box::new(
-                        StatelessDecoderFormatNegotiator::new(self, hdr.clone(), |decoder, hdr| {
-                            decoder.coded_resolution = Resolution {
-                                width: hdr.width as u32,
-                                height: hdr.height as u32,
-                            };
-                            decoder.decoding_state = DecodingState::Decoding;
-                        }),
+                        StatelessDecoderFormatNegotiator::new(
+                            self,
+                            Rc::clone(hdr),
+                            |decoder, hdr| {
+                                decoder.coded_resolution = Resolution {
+                                    width: hdr.width as u32,
+                                    height: hdr.height as u32,
+                                };
+                                decoder.stream_params_applied(colorimetry(hdr));
+                                decoder.decoding_state = DecodingState::Decoding;
+                            },
+                        ),
                     )))
                 } else {
                     None
@@ -282,42 +418,50 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn ready_for_input(&mut self) -> bool {
+        self.ready_for_input()
+    }
+
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+        self.readiness_fd()
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::decoder::stateless::tests::test_decode_stream;
+    use crate::codec::vp8::parser::Header;
+    use crate::codec::vp8::parser::Segmentation;
+    use crate::decoder::stateless::tests::codec_stream_tests;
+    use crate::decoder::stateless::tests::TestCodec;
     use crate::decoder::stateless::tests::TestStream;
+    use crate::decoder::stateless::vp8::segmentation_info;
     use crate::decoder::stateless::vp8::Vp8;
     use crate::decoder::stateless::StatelessDecoder;
     use crate::decoder::BlockingMode;
+    use crate::decoder::LoopFilterDeltas;
+    use crate::decoder::PictureId;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
     use crate::utils::IvfIterator;
     use crate::DecodedFormat;
 
-    /// Run `test` using the dummy decoder, in both blocking and non-blocking modes.
-    fn test_decoder_dummy(test: &TestStream, blocking_mode: BlockingMode) {
-        let decoder = StatelessDecoder::<Vp8, _>::new_dummy(blocking_mode);
-
-        test_decode_stream(
-            |d, s, c| {
-                simple_playback_loop(
-                    d,
-                    IvfIterator::new(s),
-                    c,
-                    &mut simple_playback_loop_owned_frames,
-                    DecodedFormat::NV12,
-                    blocking_mode,
-                )
-            },
-            decoder,
-            test,
-            false,
-            false,
-        );
+    impl TestCodec for Vp8 {
+        fn split_stream(stream: &[u8]) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+            Box::new(IvfIterator::new(stream))
+        }
     }
 
+    codec_stream_tests!(
+        Vp8,
+        StatelessDecoder::<Vp8, _>::new_dummy,
+        DecodedFormat::NV12,
+        false,
+        {
+            test_25fps => DECODE_TEST_25FPS,
+        }
+    );
+
     /// Same as Chromium's test-25fps.vp8
     pub const DECODE_TEST_25FPS: TestStream = TestStream {
         stream: include_bytes!("../../codec/vp8/test_data/test-25fps.vp8"),
@@ -325,12 +469,88 @@ pub mod tests {
     };
 
     #[test]
-    fn test_25fps_block() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::Blocking);
+    fn test_25fps_loop_filter_deltas() {
+        let mut decoder = StatelessDecoder::<Vp8, _>::new_dummy(BlockingMode::Blocking);
+
+        let mut infos = vec![];
+        simple_playback_loop(
+            &mut decoder,
+            IvfIterator::new(DECODE_TEST_25FPS.stream),
+            &mut |handle| infos.push(handle.frame_info()),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        assert!(!infos.is_empty());
+        for info in infos {
+            assert_eq!(info.segmentation, None);
+            assert_eq!(
+                info.loop_filter_deltas,
+                Some(LoopFilterDeltas {
+                    ref_frame: [2, 0, -2, -2],
+                    mode: vec![4, -2, 2, 4],
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_segmentation_info() {
+        let mut hdr = Header::default();
+        hdr.quant_indices.y_ac_qi = 60;
+        hdr.loop_filter_level = 20;
+        let mut seg = Segmentation {
+            segmentation_enabled: true,
+            update_mb_segmentation_map: true,
+            quantizer_update_value: [-10, 0, 10, 100],
+            lf_update_value: [-30, 0, 5, 50],
+            ..Default::default()
+        };
+
+        // Deltas over the frame values.
+        let info = segmentation_info(&hdr, &seg).unwrap();
+        assert!(info.map_updated);
+        let values = info
+            .segments
+            .iter()
+            .map(|s| (s.qindex, s.loop_filter_level))
+            .collect::<Vec<_>>();
+        assert_eq!(values, [(50, 0), (60, 20), (70, 25), (127, 63)]);
+
+        // Absolute values.
+        seg.segment_feature_mode = true;
+        let info = segmentation_info(&hdr, &seg).unwrap();
+        let values = info
+            .segments
+            .iter()
+            .map(|s| (s.qindex, s.loop_filter_level))
+            .collect::<Vec<_>>();
+        assert_eq!(values, [(0, 0), (0, 0), (10, 5), (100, 50)]);
+
+        seg.segmentation_enabled = false;
+        assert_eq!(segmentation_info(&hdr, &seg), None);
     }
 
     #[test]
-    fn test_25fps_nonblock() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
+    fn test_25fps_frame_index() {
+        let mut decoder = StatelessDecoder::<Vp8, _>::new_dummy(BlockingMode::Blocking);
+
+        let mut picture_ids = vec![];
+        simple_playback_loop(
+            &mut decoder,
+            IvfIterator::new(DECODE_TEST_25FPS.stream),
+            &mut |handle| picture_ids.push(handle.frame_info().picture_id),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        assert!(!picture_ids.is_empty());
+        for (i, picture_id) in picture_ids.into_iter().enumerate() {
+            assert_eq!(picture_id, PictureId::FrameIndex(i as u64));
+        }
     }
 }
@@ -14,6 +14,7 @@ use libva::Picture as VaPicture;
 use libva::ProbabilityDataBufferVP8;
 use libva::SurfaceMemoryDescriptor;
 
+use crate::backend::vaapi::trace::TraceBackend;
 use crate::backend::vaapi::VaStreamInfo;
 use crate::backend::vaapi::VaapiBackend;
 use crate::codec::vp8::parser::Header;
@@ -21,17 +22,18 @@ use crate::codec::vp8::parser::MbLfAdjustments;
 use crate::codec::vp8::parser::Segmentation;
 use crate::decoder::stateless::vp8::StatelessVp8DecoderBackend;
 use crate::decoder::stateless::vp8::Vp8;
+use crate::decoder::stateless::vp8::NUM_REF_FRAMES;
 use crate::decoder::stateless::StatelessBackendError;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::BlockingMode;
+use crate::decoder::DpbSizeOrigin;
+use crate::decoder::NumFramesBreakdown;
+use crate::Rect;
 use crate::Resolution;
 
-/// The number of surfaces to allocate for this codec. Same as GStreamer's vavp8dec.
-const NUM_SURFACES: usize = 7;
-
-impl VaStreamInfo for &Header {
+impl VaStreamInfo for &Rc<Header> {
     fn va_profile(&self) -> anyhow::Result<i32> {
         Ok(libva::VAProfile::VAProfileVP8Version0_3)
     }
@@ -40,16 +42,21 @@ impl VaStreamInfo for &Header {
         Ok(libva::constants::VA_RT_FORMAT_YUV420)
     }
 
-    fn min_num_surfaces(&self) -> usize {
-        NUM_SURFACES
+    fn min_num_surfaces(&self) -> NumFramesBreakdown {
+        // Same total as GStreamer's vavp8dec.
+        NumFramesBreakdown {
+            dpb_size: NUM_REF_FRAMES,
+            dpb_size_origin: DpbSizeOrigin::Codec,
+            extra: 4,
+        }
     }
 
-    fn coded_size(&self) -> (u32, u32) {
-        (self.width as u32, self.height as u32)
+    fn coded_size(&self) -> Resolution {
+        Resolution::from((self.width as u32, self.height as u32))
     }
 
-    fn visible_rect(&self) -> ((u32, u32), (u32, u32)) {
-        ((0, 0), self.coded_size())
+    fn visible_rect(&self) -> Rect<u32> {
+        Rect::from(self.coded_size())
     }
 }
 
@@ -203,12 +210,42 @@ fn build_slice_param(frame_hdr: &Header, slice_size: usize) -> anyhow::Result<li
     ))
 }
 
+/// Builds the buffers describing `picture`, in the order they are submitted.
+#[allow(clippy::too_many_arguments)]
+fn build_buffers(
+    picture: &Header,
+    coded_resolution: &Resolution,
+    segmentation: &Segmentation,
+    mb_lf_adjust: &MbLfAdjustments,
+    last_ref: libva::VASurfaceID,
+    golden_ref: libva::VASurfaceID,
+    alt_ref: libva::VASurfaceID,
+    bitstream: &[u8],
+) -> anyhow::Result<[BufferType; 5]> {
+    Ok([
+        build_iq_matrix(picture, segmentation).context("while building IQ matrix")?,
+        build_probability_table(picture),
+        build_pic_param(
+            picture,
+            coded_resolution,
+            segmentation,
+            mb_lf_adjust,
+            last_ref,
+            golden_ref,
+            alt_ref,
+        )
+        .context("while building pic params")?,
+        build_slice_param(picture, bitstream.len()).context("while building slice params")?,
+        libva::BufferType::SliceData(Vec::from(bitstream)),
+    ])
+}
+
 impl<M: SurfaceMemoryDescriptor + 'static> StatelessDecoderBackendPicture<Vp8> for VaapiBackend<M> {
     type Picture = ();
 }
 
 impl<M: SurfaceMemoryDescriptor + 'static> StatelessVp8DecoderBackend for VaapiBackend<M> {
-    fn new_sequence(&mut self, header: &Header) -> StatelessBackendResult<()> {
+    fn new_sequence(&mut self, header: &Rc<Header>) -> StatelessBackendResult<()> {
         self.new_sequence(header)
     }
 
@@ -223,55 +260,30 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessVp8DecoderBackend for VaapiB
         mb_lf_adjust: &MbLfAdjustments,
         timestamp: u64,
     ) -> StatelessBackendResult<Self::Handle> {
-        let last_ref = if let Some(last_ref) = last_ref {
-            last_ref.borrow().surface_id()
-        } else {
-            libva::constants::VA_INVALID_SURFACE
-        };
-
-        let golden_ref = if let Some(golden_ref) = golden_ref {
-            golden_ref.borrow().surface_id()
-        } else {
-            libva::constants::VA_INVALID_SURFACE
-        };
-
-        let alt_ref = if let Some(alt_ref) = alt_ref {
-            alt_ref.borrow().surface_id()
-        } else {
-            libva::constants::VA_INVALID_SURFACE
+        let surface_id = |handle: Option<&Self::Handle>| {
+            handle
+                .map(|h| h.borrow().surface_id())
+                .unwrap_or(libva::constants::VA_INVALID_SURFACE)
         };
 
         let metadata = self.metadata_state.get_parsed()?;
         let context = &metadata.context;
         let coded_resolution = self.surface_pool.borrow().coded_resolution();
 
-        let iq_buffer = context
-            .create_buffer(build_iq_matrix(picture, segmentation)?)
-            .context("while creating IQ matrix buffer")?;
-
-        let probs = context
-            .create_buffer(build_probability_table(picture))
-            .context("while creating probability table buffer")?;
-
-        let pic_param = context
-            .create_buffer(build_pic_param(
-                picture,
-                &coded_resolution,
-                segmentation,
-                mb_lf_adjust,
-                last_ref,
-                golden_ref,
-                alt_ref,
-            )?)
-            .context("while creating pic params buffer")?;
-
-        let slice_param = context
-            .create_buffer(build_slice_param(picture, bitstream.len())?)
-            .context("while creating slice params buffer")?;
-
-        let slice_data = context
-            .create_buffer(libva::BufferType::SliceData(Vec::from(bitstream)))
-            .context("while creating slice data buffer")?;
+        let buffers = build_buffers(
+            picture,
+            &coded_resolution,
+            segmentation,
+            mb_lf_adjust,
+            surface_id(last_ref),
+            surface_id(golden_ref),
+            surface_id(alt_ref),
+            bitstream,
+        )?
+        .into_iter()
+        .map(|buffer| self.create_buffer(context, buffer))
+        .collect::<Result<Vec<_>, _>>()
+        .context("while creating buffers")?;
 
         let surface = self
             .surface_pool
@@ -282,16 +294,53 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessVp8DecoderBackend for VaapiB
         let mut va_picture = VaPicture::new(timestamp, Rc::clone(context), surface);
 
         // Add buffers with the parsed data.
-        va_picture.add_buffer(iq_buffer);
-        va_picture.add_buffer(probs);
-        va_picture.add_buffer(pic_param);
-        va_picture.add_buffer(slice_param);
-        va_picture.add_buffer(slice_data);
+        for buffer in buffers {
+            va_picture.add_buffer(buffer);
+        }
 
         self.process_picture::<Vp8>(va_picture)
     }
 }
 
+impl StatelessVp8DecoderBackend for TraceBackend {
+    fn new_sequence(&mut self, header: &Rc<Header>) -> StatelessBackendResult<()> {
+        self.coded_resolution = header.coded_size().round(crate::ResolutionRoundMode::Even);
+
+        Ok(())
+    }
+
+    fn submit_picture(
+        &mut self,
+        picture: &Header,
+        last_ref: Option<&Self::Handle>,
+        golden_ref: Option<&Self::Handle>,
+        alt_ref: Option<&Self::Handle>,
+        bitstream: &[u8],
+        segmentation: &Segmentation,
+        mb_lf_adjust: &MbLfAdjustments,
+        timestamp: u64,
+    ) -> StatelessBackendResult<Self::Handle> {
+        let surface_id = |handle: Option<&Self::Handle>| {
+            handle
+                .map(|h| h.surface_id())
+                .unwrap_or(libva::constants::VA_INVALID_SURFACE)
+        };
+
+        let buffers = build_buffers(
+            picture,
+            &self.coded_resolution,
+            segmentation,
+            mb_lf_adjust,
+            surface_id(last_ref),
+            surface_id(golden_ref),
+            surface_id(alt_ref),
+            bitstream,
+        )?;
+
+        Ok(self.submit(timestamp, &buffers))
+    }
+}
+
 impl<M: SurfaceMemoryDescriptor + 'static> StatelessDecoder<Vp8, VaapiBackend<M>> {
     // Creates a new instance of the decoder using the VAAPI backend.
     pub fn new_vaapi<S>(display: Rc<Display>, blocking_mode: BlockingMode) -> Self
@@ -303,6 +352,14 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessDecoder<Vp8, VaapiBackend<M>
     }
 }
 
+impl StatelessDecoder<Vp8, TraceBackend> {
+    // Creates a new instance of the decoder recording the VA buffers of the stream without
+    // decoding it.
+    pub fn new_va_trace(blocking_mode: BlockingMode) -> Self {
+        Self::new(TraceBackend::new(), blocking_mode)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use libva::BufferType;
@@ -311,10 +368,14 @@ mod tests {
     use libva::PictureParameter;
     use libva::SliceParameter;
 
+    use crate::backend::vaapi::trace::TraceBufferKind;
+    use crate::backend::vaapi::trace::VaTrace;
     use crate::codec::vp8::parser::Parser;
     use crate::decoder::stateless::tests::test_decode_stream;
     use crate::decoder::stateless::tests::TestStream;
     use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::stateless::StatelessDecoderBackend;
+    use crate::decoder::stateless::StatelessVideoDecoder;
     use crate::decoder::BlockingMode;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
@@ -375,6 +436,86 @@ mod tests {
         );
     }
 
+    /// Returns the VA trace recorded while decoding `stream` with `decoder`.
+    fn record_va_trace<B>(
+        mut decoder: StatelessDecoder<Vp8, B>,
+        stream: &[u8],
+        take_trace: impl FnOnce(&mut StatelessDecoder<Vp8, B>) -> VaTrace,
+    ) -> VaTrace
+    where
+        B: StatelessDecoderBackend<Vp8>,
+        StatelessDecoder<Vp8, B>: StatelessVideoDecoder<()>,
+    {
+        simple_playback_loop(
+            &mut decoder,
+            IvfIterator::new(stream),
+            &mut |_| (),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        take_trace(&mut decoder)
+    }
+
+    #[test]
+    fn test_25fps_va_trace() {
+        use crate::decoder::stateless::vp8::tests::DECODE_TEST_25FPS;
+
+        let trace = record_va_trace(
+            StatelessDecoder::<Vp8, _>::new_va_trace(BlockingMode::Blocking),
+            DECODE_TEST_25FPS.stream,
+            |d| d.take_va_trace(),
+        );
+
+        assert!(!trace.pictures.is_empty());
+        for picture in &trace.pictures {
+            let kinds = picture.buffers.iter().map(|b| b.kind).collect::<Vec<_>>();
+            assert_eq!(
+                kinds,
+                [
+                    TraceBufferKind::IQMatrix,
+                    TraceBufferKind::Probability,
+                    TraceBufferKind::PictureParameter,
+                    TraceBufferKind::SliceParameter,
+                    TraceBufferKind::SliceData,
+                ]
+            );
+        }
+
+        let mut text = Vec::new();
+        trace.write(&mut text).unwrap();
+        VaTrace::read(text.as_slice())
+            .unwrap()
+            .compare(&trace)
+            .unwrap();
+    }
+
+    #[test]
+    // Ignore this test by default as it requires libva-compatible hardware.
+    #[ignore]
+    fn test_25fps_va_trace_replay() {
+        use crate::decoder::stateless::vp8::tests::DECODE_TEST_25FPS;
+
+        // The buffers built without a GPU must match the ones submitted to the driver.
+        let display = Display::open().unwrap();
+        let mut decoder =
+            StatelessDecoder::<Vp8, _>::new_vaapi::<()>(display, BlockingMode::Blocking);
+        decoder.start_va_trace();
+        let recorded = record_va_trace(decoder, DECODE_TEST_25FPS.stream, |d| {
+            d.take_va_trace().unwrap()
+        });
+
+        let replayed = record_va_trace(
+            StatelessDecoder::<Vp8, _>::new_va_trace(BlockingMode::Blocking),
+            DECODE_TEST_25FPS.stream,
+            |d| d.take_va_trace(),
+        );
+
+        recorded.compare(&replayed).unwrap();
+    }
+
     #[test]
     /// Check that we are able to build the VA picture parameters from the stream properly.
     fn build_pic_params() {
@@ -2,40 +2,53 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
 
+use std::os::fd::BorrowedFd;
+use std::rc::Rc;
+
 use log::debug;
 
 use crate::codec::vp9::parser::BitDepth;
+use crate::codec::vp9::parser::ColorRange;
+use crate::codec::vp9::parser::ColorSpace;
 use crate::codec::vp9::parser::Frame;
+use crate::codec::vp9::parser::FrameType;
 use crate::codec::vp9::parser::Header;
 use crate::codec::vp9::parser::Parser;
 use crate::codec::vp9::parser::Profile;
 use crate::codec::vp9::parser::Segmentation;
 use crate::codec::vp9::parser::MAX_SEGMENTS;
 use crate::codec::vp9::parser::NUM_REF_FRAMES;
+use crate::codec::vp9::parser::SEG_LVL_REF_FRAME;
+use crate::codec::vp9::parser::SEG_LVL_SKIP;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameCodingType;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackend;
 use crate::decoder::stateless::StatelessDecoderFormatNegotiator;
 use crate::decoder::stateless::StatelessVideoDecoder;
-use crate::decoder::BlockingMode;
+use crate::decoder::Colorimetry;
 use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
+use crate::decoder::FrameInfo;
 use crate::decoder::FramePool;
+use crate::decoder::PictureId;
+use crate::decoder::ReadyFrame;
+use crate::decoder::SegmentParams;
+use crate::decoder::SegmentationInfo;
 use crate::decoder::StreamInfo;
 use crate::Resolution;
 
 /// Stateless backend methods specific to VP9.
 pub trait StatelessVp9DecoderBackend: StatelessDecoderBackend<Vp9> {
     /// Called when new stream parameters are found.
-    fn new_sequence(&mut self, header: &Header) -> StatelessBackendResult<()>;
+    fn new_sequence(&mut self, header: &Rc<Header>) -> StatelessBackendResult<()>;
 
     /// Called when the decoder wants the backend to finish the decoding
     /// operations for `picture`.
@@ -62,8 +75,15 @@ pub struct Vp9DecoderState<B: StatelessDecoderBackend<Vp9>> {
     /// Per-segment data.
     segmentation: [Segmentation; MAX_SEGMENTS],
 
+    /// Segmentation parameters of the reference frames, reported again if they are shown through
+    /// `show_existing_frame`.
+    reference_segmentation: [Option<SegmentationInfo>; NUM_REF_FRAMES],
+
     /// Keeps track of the last values seen for negotiation purposes.
     negotiation_info: NegotiationInfo,
+
+    /// Number of frames shown so far, used as index of the next shown frame.
+    num_shown_frames: u64,
 }
 
 impl<B: StatelessDecoderBackend<Vp9>> Default for Vp9DecoderState<B> {
@@ -72,7 +92,9 @@ impl<B: StatelessDecoderBackend<Vp9>> Default for Vp9DecoderState<B> {
             parser: Default::default(),
             reference_frames: Default::default(),
             segmentation: Default::default(),
+            reference_segmentation: Default::default(),
             negotiation_info: Default::default(),
+            num_shown_frames: 0,
         }
     }
 }
@@ -101,6 +123,54 @@ impl From<&Header> for NegotiationInfo {
     }
 }
 
+/// Returns the colorimetry signaled by the color space of `hdr`.
+///
+/// VP9 only signals the matrix coefficients, so the primaries and transfer characteristics are
+/// left unspecified.
+fn colorimetry(hdr: &Header) -> Colorimetry {
+    let matrix_coefficients = match hdr.color_space {
+        ColorSpace::Bt601 | ColorSpace::Smpte170 => 6,
+        ColorSpace::Bt709 => 1,
+        ColorSpace::Smpte240 => 7,
+        ColorSpace::Bt2020 => 9,
+        ColorSpace::CsSrgb => 0,
+        ColorSpace::Unknown | ColorSpace::Reserved2 => 2,
+    };
+
+    Colorimetry {
+        matrix_coefficients,
+        full_range: hdr.color_range == ColorRange::FullSwing,
+        ..Default::default()
+    }
+}
+
+/// Returns the segmentation parameters of `hdr`, if it uses segmentation.
+fn segmentation_info(hdr: &Header) -> Option<SegmentationInfo> {
+    if !hdr.seg.enabled {
+        return None;
+    }
+
+    let segments = (0..MAX_SEGMENTS as u8)
+        .map(|segment_id| {
+            let feature_data = &hdr.seg.feature_data[usize::from(segment_id)];
+
+            SegmentParams {
+                qindex: hdr.get_qindex(segment_id) as u8,
+                loop_filter_level: hdr.get_lvl_seg(segment_id),
+                reference_frame: hdr
+                    .seg_feature_active(segment_id, SEG_LVL_REF_FRAME as u8)
+                    .then_some(feature_data[SEG_LVL_REF_FRAME] as u8),
+                skip: hdr.seg_feature_active(segment_id, SEG_LVL_SKIP as u8),
+            }
+        })
+        .collect();
+
+    Some(SegmentationInfo {
+        map_updated: hdr.seg.update_map,
+        segments,
+    })
+}
+
 /// [`StatelessCodec`] structure to use in order to create a VP9 stateless decoder.
 ///
 /// # Accepted input
@@ -111,7 +181,7 @@ impl From<&Header> for NegotiationInfo {
 pub struct Vp9;
 
 impl StatelessCodec for Vp9 {
-    type FormatInfo = Header;
+    type FormatInfo = Rc<Header>;
     type DecoderState<B: StatelessDecoderBackend<Self>> = Vp9DecoderState<B>;
 }
 
@@ -122,7 +192,9 @@ where
 {
     fn update_references(
         reference_frames: &mut [Option<B::Handle>; NUM_REF_FRAMES],
+        reference_segmentation: &mut [Option<SegmentationInfo>; NUM_REF_FRAMES],
         picture: &B::Handle,
+        segmentation: &Option<SegmentationInfo>,
         mut refresh_frame_flags: u8,
     ) -> anyhow::Result<()> {
         #[allow(clippy::needless_range_loop)]
@@ -130,6 +202,7 @@ where
             if (refresh_frame_flags & 1) == 1 {
                 debug!("Replacing reference frame {}", i);
                 reference_frames[i] = Some(picture.clone());
+                reference_segmentation[i] = segmentation.clone();
             }
 
             refresh_frame_flags >>= 1;
@@ -140,7 +213,7 @@ where
 
     /// Handle a single frame.
     fn handle_frame(&mut self, frame: &Frame, timestamp: u64) -> Result<(), DecodeError> {
-        let decoded_handle = if frame.header.show_existing_frame {
+        let (decoded_handle, segmentation, completed) = if frame.header.show_existing_frame {
             // Frame to be shown. Because the spec mandates that frame_to_show_map_idx references a
             // valid entry in the DPB, an non-existing index means that the stream is invalid.
             let idx = usize::from(frame.header.frame_to_show_map_idx);
@@ -150,15 +223,36 @@ where
                 .get(idx)
                 .ok_or_else(|| anyhow::anyhow!("invalid reference frame index in header"))?
                 .as_ref()
-                .ok_or_else(|| {
-                    anyhow::anyhow!("empty reference frame referenced in frame header")
-                })?;
+                .ok_or_else(|| anyhow::anyhow!("empty reference frame referenced in frame header"))?
+                .clone();
+
+            // The frame may not have been waited for if it was hidden when decoded.
+            let completed = self.sync_picture(&ref_frame)?;
 
             // We are done, no further processing needed.
-            ref_frame.clone()
+            (
+                ref_frame,
+                self.codec.reference_segmentation[idx].clone(),
+                completed,
+            )
         } else {
             // Otherwise, we must actually arrange to decode a frame
             let refresh_frame_flags = frame.header.refresh_frame_flags;
+            self.check_num_tiles(1 << (frame.header.tile_cols_log2 + frame.header.tile_rows_log2))?;
+
+            let coding_type = if matches!(frame.header.frame_type, FrameType::KeyFrame)
+                || frame.header.intra_only
+            {
+                FrameCodingType::Intra
+            } else {
+                FrameCodingType::Inter
+            };
+            self.frame_received(
+                timestamp,
+                coding_type,
+                frame.as_ref().len(),
+                i32::from(frame.header.quant.base_q_idx),
+            );
 
             Segmentation::update_segmentation(&mut self.codec.segmentation, &frame.header)?;
             let decoded_handle = self.backend.submit_picture(
@@ -168,24 +262,42 @@ where
                 timestamp,
                 &self.codec.segmentation,
             )?;
+            self.picture_submitted(&decoded_handle)?;
 
-            if self.blocking_mode == BlockingMode::Blocking {
-                decoded_handle.sync()?;
-            }
+            // Hidden frames, e.g. the alt-ref frame of a superframe, can stay in flight alongside
+            // the next frames unless the probabilities those will load depend on their decoding.
+            let completed = if frame.header.show_frame || frame.header.saves_adapted_frame_context()
+            {
+                self.sync_picture(&decoded_handle)?
+            } else {
+                true
+            };
+
+            let segmentation = segmentation_info(&frame.header);
 
             // Do DPB management
             Self::update_references(
                 &mut self.codec.reference_frames,
+                &mut self.codec.reference_segmentation,
                 &decoded_handle,
+                &segmentation,
                 refresh_frame_flags,
             )?;
 
-            decoded_handle
+            (decoded_handle, segmentation, completed)
         };
 
         let show_existing_frame = frame.header.show_existing_frame;
         if frame.header.show_frame || show_existing_frame {
-            self.ready_queue.push(decoded_handle);
+            let info = FrameInfo {
+                picture_id: PictureId::FrameIndex(self.codec.num_shown_frames),
+                segmentation,
+                corrupted: !completed,
+                ..Default::default()
+            };
+            self.codec.num_shown_frames += 1;
+
+            self.ready_queue.push(ReadyFrame::new(decoded_handle, info));
         }
 
         Ok(())
@@ -202,23 +314,22 @@ where
             *old_negotiation_info != negotiation_info
         }
     }
-}
 
-impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor> for StatelessDecoder<Vp9, B>
-where
-    B: StatelessVp9DecoderBackend,
-    B::Handle: Clone + 'static,
-{
-    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
-        let frames = self.codec.parser.parse_chunk(bitstream)?;
-
-        let num_free_frames = self.backend.frame_pool().num_free_frames();
-        if matches!(self.decoding_state, DecodingState::Decoding) && num_free_frames < frames.len()
-        {
-            return Err(DecodeError::NotEnoughOutputBuffers(
-                frames.len() - num_free_frames,
-            ));
+    /// Decodes the frames in `bitstream`.
+    fn decode_frame(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        let frames = self
+            .codec
+            .parser
+            .parse_chunk(bitstream)
+            .map_err(DecodeError::ParseError)?;
+
+        if matches!(self.decoding_state, DecodingState::Decoding) {
+            let num_free_frames = self.num_free_frames(frames.len());
+            if num_free_frames < frames.len() {
+                return Err(self.output_buffers_exhausted(frames.len() - num_free_frames));
+            }
         }
+        self.output_buffers_available();
 
         // With SVC, the first frame will usually be a key-frame, with
         // inter-frames carrying the other layers.
@@ -243,8 +354,26 @@ where
 
         if let Some(frame) = largest_in_superframe {
             if self.negotiation_possible(&frame.header, &self.codec.negotiation_info) {
-                self.backend.new_sequence(&frame.header)?;
-                self.decoding_state = DecodingState::AwaitingFormat(frame.header.clone());
+                let header = Rc::new(frame.header.clone());
+
+                if matches!(
+                    self.decoding_state,
+                    DecodingState::Decoding | DecodingState::Reset
+                ) && self.backend.try_change_resolution(&header)
+                {
+                    // The new resolution fits in the current frames, e.g. because an adaptive
+                    // stream has scaled down. Keep decoding into them without renegotiating.
+                    self.codec.negotiation_info = NegotiationInfo::from(header.as_ref());
+                    self.stream_params_applied(colorimetry(&header));
+                    self.decoding_state = DecodingState::Decoding;
+                } else {
+                    self.check_limits(
+                        NUM_REF_FRAMES,
+                        Resolution::from((frame.header.width, frame.header.height)),
+                    )?;
+                    self.backend.new_sequence(&header)?;
+                    self.decoding_state = DecodingState::AwaitingFormat(header);
+                }
             } else if matches!(self.decoding_state, DecodingState::Reset) {
                 // We can resume decoding since the decoding parameters have not changed.
                 self.decoding_state = DecodingState::Decoding;
@@ -263,28 +392,70 @@ where
 
         Ok(bitstream.len())
     }
+}
+
+impl<B> StatelessVideoDecoder<<B::Handle as DecodedHandle>::Descriptor> for StatelessDecoder<Vp9, B>
+where
+    B: StatelessVp9DecoderBackend,
+    B::Handle: Clone + 'static,
+{
+    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        self.input_submitted(timestamp);
+        let res = self.decode_frame(timestamp, bitstream);
+        let res = self.recover_if_device_lost(res, |decoder| {
+            decoder.reset();
+            decoder.codec.negotiation_info = Default::default();
+        });
+        self.update_readiness();
+
+        res
+    }
 
     fn flush(&mut self) -> Result<(), DecodeError> {
         // Note: all the submitted frames are already in the ready queue.
         self.codec.reference_frames = Default::default();
+        self.codec.reference_segmentation = Default::default();
         self.decoding_state = DecodingState::Reset;
 
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.codec.reference_frames = Default::default();
+        self.codec.reference_segmentation = Default::default();
+        self.ready_queue.clear();
+        self.decoding_state = DecodingState::Reset;
+        self.update_readiness();
+    }
+
     fn next_event(&mut self) -> Option<DecoderEvent<<B::Handle as DecodedHandle>::Descriptor>> {
+        // Clear the readiness fd once all the events have been retrieved.
+        self.update_readiness();
+
+        // Report pending notifications first, e.g. so the client can react to the end of an
+        // output buffers stall before it happens again.
+        if let Some(event) = self.take_pending_event() {
+            return Some(event);
+        }
+
         // The next event is either the next frame, or, if we are awaiting negotiation, the format
         // change event that will allow us to keep going.
         (&mut self.ready_queue)
             .next()
-            .map(|handle| DecoderEvent::FrameReady(Box::new(handle)))
+            .map(|frame| DecoderEvent::FrameReady(Box::new(self.output_frame(frame))))
             .or_else(|| {
                 if let DecodingState::AwaitingFormat(hdr) = &self.decoding_state {
                     Some(DecoderEvent::FormatChanged(Box::new(
-                        StatelessDecoderFormatNegotiator::new(self, hdr.clone(), |decoder, hdr| {
-                            decoder.codec.negotiation_info = NegotiationInfo::from(hdr);
-                            decoder.decoding_state = DecodingState::Decoding;
-                        }),
+                        StatelessDecoderFormatNegotiator::new(
+                            self,
+                            Rc::clone(hdr),
+                            |decoder, hdr| {
+                                decoder.codec.negotiation_info =
+                                    NegotiationInfo::from(hdr.as_ref());
+                                decoder.stream_params_applied(colorimetry(hdr));
+                                decoder.decoding_state = DecodingState::Decoding;
+                            },
+                        ),
                     )))
                 } else {
                     None
@@ -299,58 +470,51 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn ready_for_input(&mut self) -> bool {
+        self.ready_for_input()
+    }
+
+    fn readiness_fd(&mut self) -> anyhow::Result<BorrowedFd<'_>> {
+        self.readiness_fd()
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::decoder::stateless::tests::test_decode_stream;
+    use crate::decoder::stateless::tests::codec_stream_tests;
+    use crate::decoder::stateless::tests::TestCodec;
     use crate::decoder::stateless::tests::TestStream;
     use crate::decoder::stateless::vp9::Vp9;
     use crate::decoder::stateless::StatelessDecoder;
-    use crate::decoder::BlockingMode;
-    use crate::utils::simple_playback_loop;
-    use crate::utils::simple_playback_loop_owned_frames;
     use crate::utils::IvfIterator;
     use crate::DecodedFormat;
 
-    /// Run `test` using the dummy decoder, in both blocking and non-blocking modes.
-    fn test_decoder_dummy(test: &TestStream, blocking_mode: BlockingMode) {
-        let decoder = StatelessDecoder::<Vp9, _>::new_dummy(blocking_mode);
-
-        test_decode_stream(
-            |d, s, c| {
-                simple_playback_loop(
-                    d,
-                    IvfIterator::new(s),
-                    c,
-                    &mut simple_playback_loop_owned_frames,
-                    DecodedFormat::NV12,
-                    blocking_mode,
-                )
-            },
-            decoder,
-            test,
-            false,
-            false,
-        );
+    impl TestCodec for Vp9 {
+        fn split_stream(stream: &[u8]) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+            Box::new(IvfIterator::new(stream))
+        }
     }
 
+    codec_stream_tests!(
+        Vp9,
+        StatelessDecoder::<Vp9, _>::new_dummy,
+        DecodedFormat::NV12,
+        false,
+        {
+            test_25fps => DECODE_TEST_25FPS,
+            test_show_existing_frame => DECODE_TEST_25FPS_SHOW_EXISTING_FRAME,
+            test_show_existing_frame2 => DECODE_TEST_25FPS_SHOW_EXISTING_FRAME2,
+            test_resolution_change_500frames => DECODE_RESOLUTION_CHANGE_500FRAMES,
+        }
+    );
+
     /// Same as Chromium's test-25fps.vp8
     pub const DECODE_TEST_25FPS: TestStream = TestStream {
         stream: include_bytes!("../../codec/vp9/test_data/test-25fps.vp9"),
         crcs: include_str!("../../codec/vp9/test_data/test-25fps.vp9.crc"),
     };
 
-    #[test]
-    fn test_25fps_block() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_25fps_nonblock() {
-        test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
-    }
-
     // Remuxed from the original matroska source in libvpx using ffmpeg:
     // ffmpeg -i vp90-2-10-show-existing-frame.webm/vp90-2-10-show-existing-frame.webm -c:v copy /tmp/vp90-2-10-show-existing-frame.vp9.ivf
     pub const DECODE_TEST_25FPS_SHOW_EXISTING_FRAME: TestStream = TestStream {
@@ -358,43 +522,11 @@ pub mod tests {
         crcs: include_str!("../../codec/vp9/test_data/vp90-2-10-show-existing-frame.vp9.ivf.crc"),
     };
 
-    #[test]
-    fn show_existing_frame_block() {
-        test_decoder_dummy(
-            &DECODE_TEST_25FPS_SHOW_EXISTING_FRAME,
-            BlockingMode::Blocking,
-        );
-    }
-
-    #[test]
-    fn show_existing_frame_nonblock() {
-        test_decoder_dummy(
-            &DECODE_TEST_25FPS_SHOW_EXISTING_FRAME,
-            BlockingMode::NonBlocking,
-        );
-    }
-
     pub const DECODE_TEST_25FPS_SHOW_EXISTING_FRAME2: TestStream = TestStream {
         stream: include_bytes!("../../codec/vp9/test_data/vp90-2-10-show-existing-frame2.vp9.ivf"),
         crcs: include_str!("../../codec/vp9/test_data/vp90-2-10-show-existing-frame2.vp9.ivf.crc"),
     };
 
-    #[test]
-    fn show_existing_frame2_block() {
-        test_decoder_dummy(
-            &DECODE_TEST_25FPS_SHOW_EXISTING_FRAME2,
-            BlockingMode::Blocking,
-        );
-    }
-
-    #[test]
-    fn show_existing_frame2_nonblock() {
-        test_decoder_dummy(
-            &DECODE_TEST_25FPS_SHOW_EXISTING_FRAME2,
-            BlockingMode::NonBlocking,
-        );
-    }
-
     // Remuxed from the original matroska source in libvpx using ffmpeg:
     // ffmpeg -i vp90-2-10-show-existing-frame.webm/vp90-2-10-show-existing-frame.webm -c:v copy /tmp/vp90-2-10-show-existing-frame.vp9.ivf
     // There are some weird padding issues introduced by GStreamer for
@@ -404,14 +536,4 @@ pub mod tests {
         stream: include_bytes!("../../codec/vp9/test_data/resolution_change_500frames-vp9.ivf"),
         crcs: include_str!("../../codec/vp9/test_data/resolution_change_500frames-vp9.ivf.crc"),
     };
-
-    #[test]
-    fn test_resolution_change_500frames_block() {
-        test_decoder_dummy(&DECODE_RESOLUTION_CHANGE_500FRAMES, BlockingMode::Blocking);
-    }
-
-    #[test]
-    fn test_resolution_change_500frames_nonblock() {
-        test_decoder_dummy(&DECODE_RESOLUTION_CHANGE_500FRAMES, BlockingMode::Blocking);
-    }
 }
@@ -3,7 +3,7 @@
 // found in the LICENSE file.
 
 //! This file contains a dummy backend whose only purpose is to let the decoder
-//! run so we can test it in isolation.
+//! run so we can test it in isolation, or analyze streams without decoding them.
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -20,7 +20,7 @@ use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::BlockingMode;
 
 impl StatelessVp9DecoderBackend for Backend {
-    fn new_sequence(&mut self, _: &Header) -> StatelessBackendResult<()> {
+    fn new_sequence(&mut self, _: &Rc<Header>) -> StatelessBackendResult<()> {
         Ok(())
     }
 
@@ -11,6 +11,7 @@ use libva::Picture as VaPicture;
 use libva::SegmentParameterVP9;
 use libva::SurfaceMemoryDescriptor;
 
+use crate::backend::vaapi::trace::TraceBackend;
 use crate::backend::vaapi::VaStreamInfo;
 use crate::backend::vaapi::VaapiBackend;
 use crate::codec::vp9::parser::BitDepth;
@@ -29,9 +30,10 @@ use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessDecoder;
 use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::BlockingMode;
-
-/// The number of surfaces to allocate for this codec.
-const NUM_SURFACES: usize = 12;
+use crate::decoder::DpbSizeOrigin;
+use crate::decoder::NumFramesBreakdown;
+use crate::Rect;
+use crate::Resolution;
 
 /// Returns the RT format matching the input parameters.
 fn get_rt_format(
@@ -98,7 +100,7 @@ fn get_rt_format(
     }
 }
 
-impl VaStreamInfo for &Header {
+impl VaStreamInfo for &Rc<Header> {
     fn va_profile(&self) -> anyhow::Result<i32> {
         Ok(match self.profile {
             Profile::Profile0 => libva::VAProfile::VAProfileVP9Profile0,
@@ -117,16 +119,20 @@ impl VaStreamInfo for &Header {
         )
     }
 
-    fn min_num_surfaces(&self) -> usize {
-        NUM_SURFACES
+    fn min_num_surfaces(&self) -> NumFramesBreakdown {
+        NumFramesBreakdown {
+            dpb_size: NUM_REF_FRAMES,
+            dpb_size_origin: DpbSizeOrigin::Codec,
+            extra: 4,
+        }
     }
 
-    fn coded_size(&self) -> (u32, u32) {
-        (self.width, self.height)
+    fn coded_size(&self) -> Resolution {
+        Resolution::from((self.width, self.height))
     }
 
-    fn visible_rect(&self) -> ((u32, u32), (u32, u32)) {
-        ((0, 0), self.coded_size())
+    fn visible_rect(&self) -> Rect<u32> {
+        Rect::from(self.coded_size())
     }
 }
 
@@ -233,12 +239,26 @@ fn build_slice_param(
     ))
 }
 
+/// Builds the buffers describing `picture`, in the order they are submitted.
+fn build_buffers(
+    picture: &Header,
+    reference_frames: [libva::VASurfaceID; NUM_REF_FRAMES],
+    bitstream: &[u8],
+    segmentation: &[Segmentation; MAX_SEGMENTS],
+) -> anyhow::Result<[libva::BufferType; 3]> {
+    Ok([
+        build_pic_param(picture, reference_frames).context("while building pic params")?,
+        build_slice_param(segmentation, bitstream.len()).context("while building slice params")?,
+        libva::BufferType::SliceData(Vec::from(bitstream)),
+    ])
+}
+
 impl<M: SurfaceMemoryDescriptor + 'static> StatelessDecoderBackendPicture<Vp9> for VaapiBackend<M> {
     type Picture = ();
 }
 
 impl<M: SurfaceMemoryDescriptor + 'static> StatelessVp9DecoderBackend for VaapiBackend<M> {
-    fn new_sequence(&mut self, header: &Header) -> StatelessBackendResult<()> {
+    fn new_sequence(&mut self, header: &Rc<Header>) -> StatelessBackendResult<()> {
         self.new_sequence(header)
     }
 
@@ -266,17 +286,11 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessVp9DecoderBackend for VaapiB
         let metadata = self.metadata_state.get_parsed()?;
         let context = &metadata.context;
 
-        let pic_param = context
-            .create_buffer(build_pic_param(picture, reference_frames)?)
-            .context("while creating pic params buffer")?;
-
-        let slice_param = context
-            .create_buffer(build_slice_param(segmentation, bitstream.len())?)
-            .context("while creating slice params buffer")?;
-
-        let slice_data = context
-            .create_buffer(libva::BufferType::SliceData(Vec::from(bitstream)))
-            .context("while creating slice data buffer")?;
+        let buffers = build_buffers(picture, reference_frames, bitstream, segmentation)?
+            .into_iter()
+            .map(|buffer| self.create_buffer(context, buffer))
+            .collect::<Result<Vec<_>, _>>()
+            .context("while creating buffers")?;
 
         let surface = self
             .surface_pool
@@ -287,14 +301,46 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessVp9DecoderBackend for VaapiB
         let mut va_picture = VaPicture::new(timestamp, Rc::clone(context), surface);
 
         // Add buffers with the parsed data.
-        va_picture.add_buffer(pic_param);
-        va_picture.add_buffer(slice_param);
-        va_picture.add_buffer(slice_data);
+        for buffer in buffers {
+            va_picture.add_buffer(buffer);
+        }
 
         self.process_picture::<Vp9>(va_picture)
     }
 }
 
+impl StatelessVp9DecoderBackend for TraceBackend {
+    fn new_sequence(&mut self, _: &Rc<Header>) -> StatelessBackendResult<()> {
+        Ok(())
+    }
+
+    fn submit_picture(
+        &mut self,
+        picture: &Header,
+        reference_frames: &[Option<Self::Handle>; NUM_REF_FRAMES],
+        bitstream: &[u8],
+        timestamp: u64,
+        segmentation: &[Segmentation; MAX_SEGMENTS],
+    ) -> StatelessBackendResult<Self::Handle> {
+        let reference_frames: [u32; NUM_REF_FRAMES] = reference_frames
+            .iter()
+            .map(|h| {
+                if let Some(h) = h {
+                    h.surface_id()
+                } else {
+                    libva::constants::VA_INVALID_SURFACE
+                }
+            })
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap();
+
+        let buffers = build_buffers(picture, reference_frames, bitstream, segmentation)?;
+
+        Ok(self.submit(timestamp, &buffers))
+    }
+}
+
 impl<M: SurfaceMemoryDescriptor + 'static> StatelessDecoder<Vp9, VaapiBackend<M>> {
     // Creates a new instance of the decoder using the VAAPI backend.
     pub fn new_vaapi<S>(display: Rc<Display>, blocking_mode: BlockingMode) -> Self
@@ -306,6 +352,14 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessDecoder<Vp9, VaapiBackend<M>
     }
 }
 
+impl StatelessDecoder<Vp9, TraceBackend> {
+    // Creates a new instance of the decoder recording the VA buffers of the stream without
+    // decoding it.
+    pub fn new_va_trace(blocking_mode: BlockingMode) -> Self {
+        Self::new(TraceBackend::new(), blocking_mode)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use libva::BufferType;
@@ -313,12 +367,16 @@ mod tests {
     use libva::PictureParameter;
     use libva::SliceParameter;
 
+    use crate::backend::vaapi::trace::TraceBufferKind;
+    use crate::backend::vaapi::trace::VaTrace;
     use crate::codec::vp9::parser::Parser;
     use crate::codec::vp9::parser::MAX_SEGMENTS;
     use crate::codec::vp9::parser::NUM_REF_FRAMES;
     use crate::decoder::stateless::tests::test_decode_stream;
     use crate::decoder::stateless::tests::TestStream;
     use crate::decoder::stateless::vp9::Segmentation;
+    use crate::decoder::stateless::StatelessDecoderBackend;
+    use crate::decoder::stateless::StatelessVideoDecoder;
     use crate::decoder::BlockingMode;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
@@ -480,6 +538,84 @@ mod tests {
         );
     }
 
+    /// Returns the VA trace recorded while decoding `stream` with `decoder`.
+    fn record_va_trace<B>(
+        mut decoder: StatelessDecoder<Vp9, B>,
+        stream: &[u8],
+        take_trace: impl FnOnce(&mut StatelessDecoder<Vp9, B>) -> VaTrace,
+    ) -> VaTrace
+    where
+        B: StatelessDecoderBackend<Vp9>,
+        StatelessDecoder<Vp9, B>: StatelessVideoDecoder<()>,
+    {
+        simple_playback_loop(
+            &mut decoder,
+            IvfIterator::new(stream),
+            &mut |_| (),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        take_trace(&mut decoder)
+    }
+
+    #[test]
+    fn test_25fps_va_trace() {
+        use crate::decoder::stateless::vp9::tests::DECODE_TEST_25FPS;
+
+        let trace = record_va_trace(
+            StatelessDecoder::<Vp9, _>::new_va_trace(BlockingMode::Blocking),
+            DECODE_TEST_25FPS.stream,
+            |d| d.take_va_trace(),
+        );
+
+        assert!(!trace.pictures.is_empty());
+        for picture in &trace.pictures {
+            let kinds = picture.buffers.iter().map(|b| b.kind).collect::<Vec<_>>();
+            assert_eq!(
+                kinds,
+                [
+                    TraceBufferKind::PictureParameter,
+                    TraceBufferKind::SliceParameter,
+                    TraceBufferKind::SliceData,
+                ]
+            );
+        }
+
+        let mut text = Vec::new();
+        trace.write(&mut text).unwrap();
+        VaTrace::read(text.as_slice())
+            .unwrap()
+            .compare(&trace)
+            .unwrap();
+    }
+
+    #[test]
+    // Ignore this test by default as it requires libva-compatible hardware.
+    #[ignore]
+    fn test_25fps_va_trace_replay() {
+        use crate::decoder::stateless::vp9::tests::DECODE_TEST_25FPS;
+
+        // The buffers built without a GPU must match the ones submitted to the driver.
+        let display = Display::open().unwrap();
+        let mut decoder =
+            StatelessDecoder::<Vp9, _>::new_vaapi::<()>(display, BlockingMode::Blocking);
+        decoder.start_va_trace();
+        let recorded = record_va_trace(decoder, DECODE_TEST_25FPS.stream, |d| {
+            d.take_va_trace().unwrap()
+        });
+
+        let replayed = record_va_trace(
+            StatelessDecoder::<Vp9, _>::new_va_trace(BlockingMode::Blocking),
+            DECODE_TEST_25FPS.stream,
+            |d| d.take_va_trace(),
+        );
+
+        recorded.compare(&replayed).unwrap();
+    }
+
     #[test]
     /// Check that we are able to build the VA picture parameters from the stream properly.
     fn build_pic_params() {
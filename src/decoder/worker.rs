@@ -0,0 +1,450 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Running a decoder on a dedicated thread.
+//!
+//! Decoders and their frames are not `Send`, so applications processing streams from several
+//! threads usually end up confining each decoder to a thread of its own and talking to it through
+//! channels. [`DecoderWorker`] does exactly that: the decoder is created on the worker thread,
+//! bitstream goes in through an input channel, and frames and events come out of an output
+//! channel. Format negotiation and [`DecodeError::CheckEvents`] are handled by the worker, and
+//! decoded frames are copied into [`OwnedFrame`]s so their buffers go back to the pool right away.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+use crate::decoder::fallback::BackendSwitch;
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::Colorimetry;
+use crate::decoder::DecoderEvent;
+use crate::decoder::OwnedFrame;
+use crate::decoder::SkippedData;
+use crate::decoder::StreamInfo;
+use crate::DecodedFormat;
+use crate::Resolution;
+
+/// Number of commands that can be queued on the input channel before [`DecoderWorker::decode`]
+/// blocks.
+pub const INPUT_QUEUE_SIZE: usize = 16;
+
+/// Command sent to a [`DecoderWorker`] through its input channel.
+pub enum WorkerCommand {
+    /// Decode `bitstream`, which must contain whole units as accepted by
+    /// [`StatelessVideoDecoder::decode`].
+    Decode { timestamp: u64, bitstream: Vec<u8> },
+    /// Output all the frames decoded so far, then report [`WorkerEvent::FlushCompleted`].
+    Flush,
+}
+
+/// Output of a [`DecoderWorker`], received through its output channel.
+pub enum WorkerEvent {
+    /// A frame has been decoded, in display order.
+    Frame(OwnedFrame),
+    /// The stream format has been negotiated using the output format of the worker.
+    FormatChanged(StreamInfo),
+    /// See [`DecoderEvent::OutputStalled`].
+    OutputStalled(Duration),
+    /// See [`DecoderEvent::ResolutionChanged`].
+    ResolutionChanged(Resolution),
+    /// See [`DecoderEvent::ColorimetryChanged`].
+    ColorimetryChanged(Colorimetry),
+    /// See [`DecoderEvent::FrameDropped`].
+    FrameDropped(u64),
+    /// See [`DecoderEvent::NonFatalError`].
+    NonFatalError(u64, anyhow::Error),
+    /// See [`DecoderEvent::DeviceReset`].
+    DeviceReset,
+    /// See [`DecoderEvent::DataSkipped`].
+    DataSkipped(SkippedData),
+    /// See [`DecoderEvent::BackendSwitched`].
+    BackendSwitched(BackendSwitch),
+    /// All the frames decoded before the last [`WorkerCommand::Flush`] have been output.
+    FlushCompleted,
+    /// Decoding failed and the worker stopped. This is always the last event.
+    Error(anyhow::Error),
+}
+
+/// A decoder running on its own thread.
+///
+/// Dropping the worker cancels it: the input that has not been decoded yet is discarded, and the
+/// decoder is reset (see [`StatelessVideoDecoder::reset`]) instead of flushed, so the thread stops
+/// after the unit it is currently decoding without waiting for the pending frames. Use
+/// [`DecoderWorker::finish`] to decode everything before stopping.
+pub struct DecoderWorker {
+    /// Only `None` once the worker is being stopped.
+    input: Option<SyncSender<WorkerCommand>>,
+    output: Receiver<WorkerEvent>,
+    thread: Option<JoinHandle<()>>,
+    /// Set to request the worker thread to stop as soon as possible.
+    cancelled: Arc<AtomicBool>,
+}
+
+impl DecoderWorker {
+    /// Starts a worker thread running the decoder returned by `create_decoder`.
+    ///
+    /// Frames are output in `output_format`, and `allocate_frames` is called on the worker thread
+    /// to provide the decoder with as many frames as requested by the stream (see
+    /// [`crate::utils::simple_playback_loop_owned_frames`] for decoders allocating their own
+    /// frames).
+    pub fn spawn<D, M, F, A>(
+        create_decoder: F,
+        output_format: DecodedFormat,
+        allocate_frames: A,
+    ) -> anyhow::Result<Self>
+    where
+        D: StatelessVideoDecoder<M> + 'static,
+        M: 'static,
+        F: FnOnce() -> anyhow::Result<D> + Send + 'static,
+        A: FnMut(&StreamInfo, usize) -> anyhow::Result<Vec<M>> + Send + 'static,
+    {
+        let (input_sender, input) = mpsc::sync_channel(INPUT_QUEUE_SIZE);
+        let (output_sender, output) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let worker_cancelled = Arc::clone(&cancelled);
+        let thread = std::thread::Builder::new()
+            .name("decoder-worker".into())
+            .spawn(move || {
+                let mut worker_loop = WorkerLoop {
+                    output: output_sender,
+                    output_format,
+                    allocate_frames,
+                    cancelled: worker_cancelled,
+                };
+
+                let res =
+                    create_decoder().and_then(|mut decoder| worker_loop.run(&mut decoder, &input));
+                if let Err(e) = res {
+                    let _ = worker_loop.output.send(WorkerEvent::Error(e));
+                }
+            })?;
+
+        Ok(Self {
+            input: Some(input_sender),
+            output,
+            thread: Some(thread),
+            cancelled,
+        })
+    }
+
+    fn send(&self, command: WorkerCommand) -> anyhow::Result<()> {
+        // Cannot fail as `input` is only taken when the worker is being stopped.
+        self.input
+            .as_ref()
+            .unwrap()
+            .send(command)
+            .map_err(|_| anyhow!("the decoder worker has stopped"))
+    }
+
+    /// Queues `bitstream` for decoding, blocking if the input queue is full.
+    pub fn decode(&self, timestamp: u64, bitstream: Vec<u8>) -> anyhow::Result<()> {
+        self.send(WorkerCommand::Decode {
+            timestamp,
+            bitstream,
+        })
+    }
+
+    /// Queues a flush, which completes with [`WorkerEvent::FlushCompleted`].
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.send(WorkerCommand::Flush)
+    }
+
+    /// Returns a sender for the input channel, e.g. to feed the worker from another thread.
+    pub fn input(&self) -> SyncSender<WorkerCommand> {
+        // Cannot fail for the same reason as in `send`.
+        self.input.as_ref().unwrap().clone()
+    }
+
+    /// Returns the output channel of the worker. It disconnects once the worker has stopped.
+    pub fn output(&self) -> &Receiver<WorkerEvent> {
+        &self.output
+    }
+
+    /// Returns the output channel, detaching the worker from this handle. The worker keeps running
+    /// as long as clones of the input sender (see [`DecoderWorker::input`]) exist, and the channel
+    /// disconnects once they are all dropped and the pending input has been decoded.
+    pub fn into_output(self) -> Receiver<WorkerEvent> {
+        self.into_parts().0
+    }
+
+    /// Same as [`DecoderWorker::into_output`], but also returns the flag that cancels the worker
+    /// when set.
+    pub(crate) fn into_parts(mut self) -> (Receiver<WorkerEvent>, Arc<AtomicBool>) {
+        self.input.take();
+        self.thread.take();
+        let output = std::mem::replace(&mut self.output, mpsc::channel().1);
+        let cancelled = std::mem::replace(&mut self.cancelled, Arc::new(AtomicBool::new(false)));
+
+        (output, cancelled)
+    }
+
+    /// Decodes all the pending input, then stops the worker and returns the events that have not
+    /// been received yet. Clones of the input sender must have been dropped for this to return.
+    pub fn finish(mut self) -> anyhow::Result<Vec<WorkerEvent>> {
+        self.input.take();
+        let events = self.output.iter().collect();
+
+        // Cannot fail as `thread` is only taken by `finish` and `drop`.
+        self.thread
+            .take()
+            .unwrap()
+            .join()
+            .map_err(|_| anyhow!("the decoder worker panicked"))?;
+
+        Ok(events)
+    }
+}
+
+impl Drop for DecoderWorker {
+    fn drop(&mut self) {
+        // The thread checks the flag between units and stops as soon as it fails to send its
+        // next event or the input channel disconnects, so there is no need to wait for it.
+        self.cancelled.store(true, Ordering::Release);
+        self.input.take();
+    }
+}
+
+/// State of the worker thread.
+struct WorkerLoop<A> {
+    output: Sender<WorkerEvent>,
+    output_format: DecodedFormat,
+    allocate_frames: A,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<A> WorkerLoop<A> {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    fn send(&self, event: WorkerEvent) -> anyhow::Result<()> {
+        self.output
+            .send(event)
+            .map_err(|_| anyhow!("the decoder worker output has been dropped"))
+    }
+
+    /// Processes commands until the input channel disconnects, at which point the decoder is
+    /// flushed, or until the worker is cancelled, at which point the decoder is reset.
+    fn run<D, M>(&mut self, decoder: &mut D, input: &Receiver<WorkerCommand>) -> anyhow::Result<()>
+    where
+        D: StatelessVideoDecoder<M>,
+        A: FnMut(&StreamInfo, usize) -> anyhow::Result<Vec<M>>,
+    {
+        while let Ok(command) = input.recv() {
+            if self.is_cancelled() {
+                decoder.reset();
+                return Ok(());
+            }
+
+            match command {
+                WorkerCommand::Decode {
+                    timestamp,
+                    bitstream,
+                } => self.decode(decoder, timestamp, &bitstream)?,
+                WorkerCommand::Flush => {
+                    decoder.flush()?;
+                    self.process_events(decoder)?;
+                    self.send(WorkerEvent::FlushCompleted)?;
+                }
+            }
+        }
+
+        if self.is_cancelled() {
+            decoder.reset();
+            return Ok(());
+        }
+
+        decoder.flush()?;
+        self.process_events(decoder)?;
+
+        Ok(())
+    }
+
+    fn decode<D, M>(
+        &mut self,
+        decoder: &mut D,
+        timestamp: u64,
+        bitstream: &[u8],
+    ) -> anyhow::Result<()>
+    where
+        D: StatelessVideoDecoder<M>,
+        A: FnMut(&StreamInfo, usize) -> anyhow::Result<Vec<M>>,
+    {
+        let mut bitstream = bitstream;
+
+        while !bitstream.is_empty() && !self.is_cancelled() {
+            let res = decoder.decode(timestamp, bitstream);
+            let num_events = self.process_events(decoder)?;
+
+            match res {
+                Ok(0) if num_events == 0 => {
+                    return Err(anyhow!("decoder did not consume any input"));
+                }
+                Ok(consumed) => bitstream = &bitstream[consumed..],
+                // All the frames are held by the decoder, so give it more.
+                Err(DecodeError::NotEnoughOutputBuffers(num_frames)) if num_events == 0 => {
+                    let stream_info = decoder
+                        .stream_info()
+                        .ok_or_else(|| anyhow!("decoder needs frames before knowing the stream"))?
+                        .clone();
+                    let frames = (self.allocate_frames)(&stream_info, num_frames)?;
+                    decoder.frame_pool().add_frames(frames)?;
+                }
+                Err(DecodeError::CheckEvents) if num_events == 0 => {
+                    return Err(anyhow!("decoder is stalled without any event to process"));
+                }
+                Err(DecodeError::CheckEvents | DecodeError::NotEnoughOutputBuffers(_)) => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forwards all the pending events of `decoder` to the output channel, negotiating the format
+    /// along the way. Returns the number of events processed.
+    fn process_events<D, M>(&mut self, decoder: &mut D) -> anyhow::Result<usize>
+    where
+        D: StatelessVideoDecoder<M>,
+        A: FnMut(&StreamInfo, usize) -> anyhow::Result<Vec<M>>,
+    {
+        let mut num_events = 0;
+
+        while let Some(event) = decoder.next_event() {
+            num_events += 1;
+
+            let event = match event {
+                DecoderEvent::FrameReady(handle) => WorkerEvent::Frame(handle.to_owned_frame()?),
+                DecoderEvent::FormatChanged(mut negotiator) => {
+                    negotiator.try_format(self.output_format)?;
+
+                    let stream_info = negotiator.stream_info().clone();
+                    let num_frames = negotiator.frame_pool().num_managed_frames();
+                    if num_frames < stream_info.min_num_frames {
+                        let frames = (self.allocate_frames)(
+                            &stream_info,
+                            stream_info.min_num_frames - num_frames,
+                        )?;
+                        negotiator.frame_pool().add_frames(frames)?;
+                    }
+
+                    WorkerEvent::FormatChanged(stream_info)
+                }
+                DecoderEvent::OutputStalled(duration) => WorkerEvent::OutputStalled(duration),
+                DecoderEvent::ResolutionChanged(resolution) => {
+                    WorkerEvent::ResolutionChanged(resolution)
+                }
+                DecoderEvent::ColorimetryChanged(colorimetry) => {
+                    WorkerEvent::ColorimetryChanged(colorimetry)
+                }
+                DecoderEvent::FrameDropped(timestamp) => WorkerEvent::FrameDropped(timestamp),
+                DecoderEvent::NonFatalError(timestamp, e) => {
+                    WorkerEvent::NonFatalError(timestamp, e)
+                }
+                DecoderEvent::DeviceReset => WorkerEvent::DeviceReset,
+                DecoderEvent::DataSkipped(data) => WorkerEvent::DataSkipped(data),
+                DecoderEvent::BackendSwitched(switch) => WorkerEvent::BackendSwitched(switch),
+            };
+
+            self.send(event)?;
+        }
+
+        Ok(num_events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecoderWorker;
+    use super::WorkerCommand;
+    use super::WorkerEvent;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::BlockingMode;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+
+    const STREAM: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P-B-P.h264");
+    const CRCS: &str = include_str!("../codec/h264/test_data/64x64-I-P-B-P.h264.crc");
+
+    #[test]
+    fn decode_from_another_thread() {
+        let worker = DecoderWorker::spawn(
+            || {
+                Ok(StatelessDecoder::<H264, _>::new_dummy(
+                    BlockingMode::NonBlocking,
+                ))
+            },
+            DecodedFormat::NV12,
+            simple_playback_loop_owned_frames,
+        )
+        .unwrap();
+
+        // Feed the worker from a thread of its own, with a flush in the middle.
+        let input = worker.input();
+        let feeder = std::thread::spawn(move || {
+            for (timestamp, nalu) in NalIterator::<Nalu>::new(STREAM).enumerate() {
+                input
+                    .send(WorkerCommand::Decode {
+                        timestamp: timestamp as u64,
+                        bitstream: nalu.to_vec(),
+                    })
+                    .unwrap();
+            }
+            input.send(WorkerCommand::Flush).unwrap();
+        });
+        feeder.join().unwrap();
+
+        let mut num_frames = 0;
+        let mut num_format_changes = 0;
+        for event in worker.output().iter() {
+            match event {
+                WorkerEvent::Frame(_) => num_frames += 1,
+                WorkerEvent::FormatChanged(_) => num_format_changes += 1,
+                WorkerEvent::FlushCompleted => break,
+                WorkerEvent::Error(e) => panic!("{:#}", e),
+                _ => (),
+            }
+        }
+        assert_eq!(num_frames, CRCS.lines().count());
+        assert_eq!(num_format_changes, 1);
+
+        // Nothing is left to output after the flush.
+        assert!(worker.finish().unwrap().is_empty());
+    }
+
+    #[test]
+    fn decoder_error() {
+        let worker = DecoderWorker::spawn(
+            || {
+                Ok(StatelessDecoder::<H264, _>::new_dummy(
+                    BlockingMode::Blocking,
+                ))
+            },
+            DecodedFormat::NV12,
+            simple_playback_loop_owned_frames,
+        )
+        .unwrap();
+
+        // An SPS with an invalid profile.
+        worker
+            .decode(0, vec![0, 0, 0, 1, 0x67, 0xff, 0xff])
+            .unwrap();
+
+        let events = worker.finish().unwrap();
+        assert!(matches!(events.last(), Some(WorkerEvent::Error(_))));
+    }
+}
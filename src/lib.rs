@@ -8,6 +8,9 @@
 //! The [codec] module contains tools to parse encoded video streams like H.264 or VP9 and extract
 //! the information useful in order to perform e.g. hardware-accelerated decoding.
 //!
+//! The [bitstream] module contains tools to write encoded video streams, which is the reverse
+//! operation.
+//!
 //! The [backend] module contains common backend code. A backend is a provider of some way to
 //! decode or encode a particular codec, like VAAPI.
 //!
@@ -18,6 +21,7 @@
 //! crate and didn't fit any of the modules above.
 
 pub mod backend;
+pub mod bitstream;
 pub mod codec;
 pub mod decoder;
 pub mod utils;
@@ -34,8 +38,16 @@ pub use libva;
 pub enum ResolutionRoundMode {
     /// Rounds component-wise to the next even value.
     Even,
+    /// Rounds component-wise to the next multiple of the H.264 and VP8 macroblock size.
+    Macroblock,
+    /// Rounds component-wise to the next multiple of the given block size, which must be a power
+    /// of two. Useful for H.265 coding tree blocks or VP9/AV1 superblocks.
+    Block(u32),
 }
 
+/// Size in pixels of the macroblocks used by H.264 and VP8.
+pub const MB_SIZE: u32 = 16;
+
 /// A frame resolution in pixels.
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct Resolution {
@@ -50,20 +62,38 @@ impl Resolution {
     }
 
     /// Rounds `self` according to `rnd_mode`.
-    pub fn round(mut self, rnd_mode: ResolutionRoundMode) -> Self {
-        match rnd_mode {
-            ResolutionRoundMode::Even => {
-                if self.width % 2 != 0 {
-                    self.width += 1;
-                }
+    pub fn round(self, rnd_mode: ResolutionRoundMode) -> Self {
+        let alignment = match rnd_mode {
+            ResolutionRoundMode::Even => 2,
+            ResolutionRoundMode::Macroblock => MB_SIZE,
+            ResolutionRoundMode::Block(size) => size,
+        };
+        debug_assert!(alignment.is_power_of_two());
 
-                if self.height % 2 != 0 {
-                    self.height += 1;
-                }
-            }
+        Self {
+            width: (self.width + alignment - 1) & !(alignment - 1),
+            height: (self.height + alignment - 1) & !(alignment - 1),
         }
+    }
 
-        self
+    /// Returns the number of blocks of `block_size` pixels needed to cover `self` in each
+    /// dimension, e.g. the size in macroblocks of a frame if `block_size` is `MB_SIZE`.
+    pub fn in_blocks(&self, block_size: u32) -> Self {
+        Self {
+            width: self.width.div_ceil(block_size),
+            height: self.height.div_ceil(block_size),
+        }
+    }
+
+    /// Returns the number of pixels in a frame of this resolution.
+    pub fn area(&self) -> u32 {
+        self.width * self.height
+    }
+
+    /// Returns the display aspect ratio of a frame of this resolution made of samples with an
+    /// aspect ratio of `sar`.
+    pub fn display_aspect_ratio(&self, sar: AspectRatio) -> AspectRatio {
+        AspectRatio::new(self.width * sar.num, self.height * sar.den)
     }
 }
 
@@ -82,6 +112,119 @@ impl From<Resolution> for (u32, u32) {
     }
 }
 
+/// A point within a frame.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Point<T> {
+    pub x: T,
+    pub y: T,
+}
+
+/// A rectangle within a frame, from its top-left corner `min` (inclusive) to its bottom-right
+/// corner `max` (exclusive).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rect<T> {
+    pub min: Point<T>,
+    pub max: Point<T>,
+}
+
+impl Rect<u32> {
+    pub fn width(&self) -> u32 {
+        self.max.x - self.min.x
+    }
+
+    pub fn height(&self) -> u32 {
+        self.max.y - self.min.y
+    }
+
+    /// Returns the size of the rectangle, e.g. the display resolution if `self` is the visible
+    /// rectangle of a frame.
+    pub fn size(&self) -> Resolution {
+        Resolution {
+            width: self.width(),
+            height: self.height(),
+        }
+    }
+}
+
+/// The rectangle covering the whole of a frame of this resolution.
+impl From<Resolution> for Rect<u32> {
+    fn from(value: Resolution) -> Self {
+        Self {
+            min: Point { x: 0, y: 0 },
+            max: Point {
+                x: value.width,
+                y: value.height,
+            },
+        }
+    }
+}
+
+/// An aspect ratio `num:den`, always kept in its reduced form.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AspectRatio {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl AspectRatio {
+    /// Square samples.
+    pub const SQUARE: Self = Self { num: 1, den: 1 };
+
+    /// Creates the reduced form of `num:den`.
+    pub fn new(num: u32, den: u32) -> Self {
+        let (mut a, mut b) = (num, den);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+
+        match a {
+            0 => Self { num, den },
+            gcd => Self {
+                num: num / gcd,
+                den: den / gcd,
+            },
+        }
+    }
+
+    /// Returns the sample aspect ratio signaled by `aspect_ratio_idc` as per Table E-1 of the
+    /// H.264 and H.265 specifications, `sar_width` and `sar_height` being used for
+    /// `Extended_SAR`. Returns `None` if the aspect ratio is unspecified or reserved.
+    pub fn from_h26x_idc(aspect_ratio_idc: u32, sar_width: u32, sar_height: u32) -> Option<Self> {
+        const TABLE_E1: [(u32, u32); 17] = [
+            (0, 0),
+            (1, 1),
+            (12, 11),
+            (10, 11),
+            (16, 11),
+            (40, 33),
+            (24, 11),
+            (20, 11),
+            (32, 11),
+            (80, 33),
+            (18, 11),
+            (15, 11),
+            (64, 33),
+            (160, 99),
+            (4, 3),
+            (3, 2),
+            (2, 1),
+        ];
+
+        let (num, den) = match aspect_ratio_idc {
+            255 => (sar_width, sar_height),
+            idc => TABLE_E1.get(idc as usize).copied()?,
+        };
+
+        (num != 0 && den != 0).then(|| Self::new(num, den))
+    }
+}
+
+impl Default for AspectRatio {
+    fn default() -> Self {
+        Self::SQUARE
+    }
+}
+
 /// Wrapper around u32 when they are meant to be a fourcc.
 ///
 /// Provides conversion and display/debug implementations useful when dealing with fourcc codes.
@@ -149,6 +292,9 @@ pub enum DecodedFormat {
     I444,
     /// Y, U and V planes, 4:2:0 sampling, 16 bits per sample, LE. Only the 10 LSBs are used.
     I010,
+    /// One Y and one interleaved UV plane, 4:2:0 sampling, 16 bits per sample, LE. Only the 10
+    /// MSBs are used.
+    P010,
     /// Y, U and V planes, 4:2:0 sampling, 16 bits per sample, LE. Only the 12 LSBs are used.
     I012,
     /// Y, U and V planes, 4:2:2 sampling, 16 bits per sample, LE. Only the 10 LSBs are used.
@@ -161,6 +307,25 @@ pub enum DecodedFormat {
     I412,
 }
 
+/// Fourcc codes of the decoded formats, following the naming of libyuv.
+impl From<DecodedFormat> for Fourcc {
+    fn from(format: DecodedFormat) -> Self {
+        Fourcc::from(match format {
+            DecodedFormat::I420 => b"I420",
+            DecodedFormat::NV12 => b"NV12",
+            DecodedFormat::I422 => b"I422",
+            DecodedFormat::I444 => b"I444",
+            DecodedFormat::I010 => b"I010",
+            DecodedFormat::P010 => b"P010",
+            DecodedFormat::I012 => b"I012",
+            DecodedFormat::I210 => b"I210",
+            DecodedFormat::I212 => b"I212",
+            DecodedFormat::I410 => b"I410",
+            DecodedFormat::I412 => b"I412",
+        })
+    }
+}
+
 impl FromStr for DecodedFormat {
     type Err = &'static str;
 
@@ -171,20 +336,30 @@ impl FromStr for DecodedFormat {
             "i444" | "I444" => Ok(DecodedFormat::I444),
             "nv12" | "NV12" => Ok(DecodedFormat::NV12),
             "i010" | "I010" => Ok(DecodedFormat::I010),
+            "p010" | "P010" => Ok(DecodedFormat::P010),
             "i012" | "I012" => Ok(DecodedFormat::I012),
             "i210" | "I210" => Ok(DecodedFormat::I210),
             "i212" | "I212" => Ok(DecodedFormat::I212),
             "i410" | "I410" => Ok(DecodedFormat::I410),
             "i412" | "I412" => Ok(DecodedFormat::I412),
             _ => {
-                Err("unrecognized output format. Valid values: i420, nv12, i422, i444, i010, i012, i210, i212, i410, i412")
+                Err("unrecognized output format. Valid values: i420, nv12, i422, i444, i010, p010, i012, i210, i212, i410, i412")
             }
         }
     }
 }
 
+/// Reverse of the fourcc codes of the decoded formats.
+impl TryFrom<Fourcc> for DecodedFormat {
+    type Error = &'static str;
+
+    fn try_from(fourcc: Fourcc) -> Result<Self, Self::Error> {
+        DecodedFormat::from_str(&fourcc.to_string())
+    }
+}
+
 /// Describes the layout of a plane within a frame.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PlaneLayout {
     /// Index of the memory buffer the plane belongs to.
     pub buffer_index: usize,
@@ -192,13 +367,15 @@ pub struct PlaneLayout {
     pub offset: usize,
     /// Distance in bytes between two lines of data in this plane.
     pub stride: usize,
+    /// Size in bytes of the plane, starting from `offset`.
+    pub size: usize,
 }
 
 /// Unambiguously describes the layout of a frame.
 ///
 /// A frame can be made of one or several memory buffers, each containing one or several planes.
 /// For a given frame, this structure defines where each plane can be found.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FrameLayout {
     /// `(Fourcc, modifier)` tuple describing the arrangement of the planes.
     ///
@@ -211,6 +388,23 @@ pub struct FrameLayout {
     pub planes: Vec<PlaneLayout>,
 }
 
+impl FrameLayout {
+    /// Returns the lines of plane `plane` within `buffer`, the buffer this plane belongs to,
+    /// truncated to `line_size` bytes.
+    pub fn plane_lines<'a>(
+        &self,
+        buffer: &'a [u8],
+        plane: usize,
+        line_size: usize,
+    ) -> impl Iterator<Item = &'a [u8]> {
+        let plane = &self.planes[plane];
+
+        buffer[plane.offset..]
+            .chunks(plane.stride)
+            .map(move |line| &line[..line_size])
+    }
+}
+
 /// Build a frame memory descriptor enum that supports multiple descriptor types.
 ///
 /// This is useful for the case where the frames' memory backing is not decided at compile-time.
@@ -250,18 +444,15 @@ macro_rules! multiple_desc_type {
 }
 
 /// Copies `src` into `dst` as NV12, removing any extra padding.
-pub fn nv12_copy(
-    src: &[u8],
-    dst: &mut [u8],
-    width: usize,
-    height: usize,
-    strides: [usize; 3],
-    offsets: [usize; 3],
-) {
+///
+/// `src_layout` gives the size of the frame and the position of its planes in `src`. In `dst` the
+/// planes are laid out as given by [`decoded_frame_layout`].
+pub fn nv12_copy(src: &[u8], src_layout: &FrameLayout, dst: &mut [u8]) {
+    let width = src_layout.size.width as usize;
+    let height = src_layout.size.height as usize;
+
     // Copy Y.
-    let src_y_lines = src[offsets[0]..]
-        .chunks(strides[0])
-        .map(|line| &line[..width]);
+    let src_y_lines = src_layout.plane_lines(src, 0, width);
     let dst_y_lines = dst.chunks_mut(width);
 
     for (src_line, dst_line) in src_y_lines.zip(dst_y_lines).take(height) {
@@ -276,35 +467,56 @@ pub fn nv12_copy(
     let uv_height = if height % 2 == 1 { height + 1 } else { height } / 2;
 
     // Copy UV.
-    let src_uv_lines = src[offsets[1]..]
-        .chunks(strides[1])
-        .map(|line| &line[..uv_width]);
+    let src_uv_lines = src_layout.plane_lines(src, 1, uv_width);
     let dst_uv_lines = dst[dst_u_offset..].chunks_mut(uv_width);
     for (src_line, dst_line) in src_uv_lines.zip(dst_uv_lines).take(uv_height) {
         dst_line.copy_from_slice(src_line);
     }
 }
 
+/// Copies `src` into `dst` as P010, removing any extra padding.
+///
+/// `src_layout` gives the size of the frame and the position of its planes in `src`. In `dst` the
+/// planes are laid out as given by [`decoded_frame_layout`].
+pub fn p010_copy(src: &[u8], src_layout: &FrameLayout, dst: &mut [u8]) {
+    let dst_layout = decoded_frame_layout(
+        DecodedFormat::P010,
+        src_layout.size.width as usize,
+        src_layout.size.height as usize,
+    );
+
+    for (src_plane, dst_plane) in src_layout.planes.iter().zip(&dst_layout.planes) {
+        plane_copy(
+            &src[src_plane.offset..],
+            src_plane.stride,
+            &mut dst[dst_plane.offset..],
+            dst_plane.stride,
+            dst_plane.stride,
+            dst_plane.size.checked_div(dst_plane.stride).unwrap_or(0),
+        );
+    }
+}
+
 /// Copies `src` into `dst` as I4xx (YUV tri-planar).
 ///
 /// This function does not change the data layout beyond removing any padding in the source, i.e.
 /// both `src` and `dst` are 3-planar YUV buffers.
 ///
-/// `strides` and `offsets` give the stride and starting position of each plane in `src`. In `dst`
-/// each plane will be put sequentially one after the other.
+/// `src_layout` gives the size of the frame and the position of its planes in `src`. In `dst` each
+/// plane will be put sequentially one after the other.
 ///
 /// `sub_h` and `sub_v` enable horizontal and vertical sub-sampling, respectively. E.g, if both
 /// `sub_h` and `sub_v` are `true` the data will be `4:2:0`, if only `sub_v` is `true` then it will be
 /// `4:2:2`, and if both are `false` then we have `4:4:4`.
 pub fn i4xx_copy(
     src: &[u8],
+    src_layout: &FrameLayout,
     dst: &mut [u8],
-    width: usize,
-    height: usize,
-    strides: [usize; 3],
-    offsets: [usize; 3],
     (sub_h, sub_v): (bool, bool),
 ) {
+    let width = src_layout.size.width as usize;
+    let height = src_layout.size.height as usize;
+
     // Align width and height of UV planes to 2 if sub-sampling is used.
     let uv_width = if sub_h { (width + 1) / 2 } else { width };
     let uv_height = if sub_v { (height + 1) / 2 } else { height };
@@ -315,27 +527,21 @@ pub fn i4xx_copy(
     let (dst_u_plane, dst_v_plane) = dst_uv_planes.split_at_mut(dst_u_size);
 
     // Copy Y.
-    let src_y_lines = src[offsets[0]..]
-        .chunks(strides[0])
-        .map(|line| &line[..width]);
+    let src_y_lines = src_layout.plane_lines(src, 0, width);
     let dst_y_lines = dst_y_plane.chunks_mut(width);
     for (src_line, dst_line) in src_y_lines.zip(dst_y_lines).take(height) {
         dst_line.copy_from_slice(src_line);
     }
 
     // Copy U.
-    let src_u_lines = src[offsets[1]..]
-        .chunks(strides[1])
-        .map(|line| &line[..uv_width]);
+    let src_u_lines = src_layout.plane_lines(src, 1, uv_width);
     let dst_u_lines = dst_u_plane.chunks_mut(uv_width);
     for (src_line, dst_line) in src_u_lines.zip(dst_u_lines).take(uv_height) {
         dst_line.copy_from_slice(src_line);
     }
 
     // Copy V.
-    let src_v_lines = src[offsets[2]..]
-        .chunks(strides[2])
-        .map(|line| &line[..uv_width]);
+    let src_v_lines = src_layout.plane_lines(src, 2, uv_width);
     let dst_v_lines = dst_v_plane.chunks_mut(uv_width);
     for (src_line, dst_line) in src_v_lines.zip(dst_v_lines).take(uv_height) {
         dst_line.copy_from_slice(src_line);
@@ -346,49 +552,116 @@ pub fn i4xx_copy(
 /// padding. This is the minimum size of the destination buffer passed to `nv12_copy` or
 /// `i420_copy`.
 pub fn decoded_frame_size(format: DecodedFormat, width: usize, height: usize) -> usize {
-    match format {
-        DecodedFormat::I420 | DecodedFormat::NV12 => {
-            let u_size = width * height;
-            // U and V planes need to be aligned to 2.
-            let uv_size = ((width + 1) / 2) * ((height + 1) / 2) * 2;
+    decoded_frame_layout(format, width, height)
+        .planes
+        .iter()
+        .map(|plane| plane.size)
+        .sum()
+}
 
-            u_size + uv_size
-        }
-        DecodedFormat::I422 => {
-            let u_size = width * height;
-            // U and V planes need to be aligned to 2.
-            let uv_size = ((width + 1) / 2) * ((height + 1) / 2) * 2 * 2;
+/// Returns the layout of a frame of `format` with size `width`x`height`, without any padding and
+/// with its planes put sequentially one after the other in a single buffer.
+///
+/// This is the layout of the buffers filled by [`decoder::MappableHandle::read`].
+pub fn decoded_frame_layout(format: DecodedFormat, width: usize, height: usize) -> FrameLayout {
+    let mut offset = 0;
+    let planes = (0..)
+        .map_while(|plane| decoded_plane_size(format, plane, width, height))
+        .map(|(line_size, num_lines)| {
+            let plane = PlaneLayout {
+                buffer_index: 0,
+                offset,
+                stride: line_size,
+                size: line_size * num_lines,
+            };
+            offset += plane.size;
+
+            plane
+        })
+        .collect();
+
+    FrameLayout {
+        format: (Fourcc::from(format), 0),
+        size: Resolution::from((width as u32, height as u32)),
+        planes,
+    }
+}
 
-            u_size + uv_size
+/// Returns the size of a line in bytes and the number of lines of plane `plane` of a frame of
+/// `format` with size `width`x`height`, without any padding, or `None` if `format` has no such
+/// plane.
+///
+/// This is the layout of each plane in the buffers filled by [`decoder::MappableHandle::read`],
+/// where the planes are put sequentially one after the other.
+pub fn decoded_plane_size(
+    format: DecodedFormat,
+    plane: usize,
+    width: usize,
+    height: usize,
+) -> Option<(usize, usize)> {
+    let (num_planes, sub_h, sub_v, bytes_per_sample) = match format {
+        DecodedFormat::NV12 => {
+            // The interleaved UV plane has as many bytes per line as the Y plane, aligned to 2.
+            return match plane {
+                0 => Some((width, height)),
+                1 => Some((((width + 1) / 2) * 2, (height + 1) / 2)),
+                _ => None,
+            };
         }
-        DecodedFormat::I444 => (width * height) * 3,
-        DecodedFormat::I010 | DecodedFormat::I012 => {
-            decoded_frame_size(DecodedFormat::I420, width, height) * 2
+        DecodedFormat::P010 => {
+            // Same as NV12, with two bytes per sample.
+            return match plane {
+                0 => Some((width * 2, height)),
+                1 => Some((((width + 1) / 2) * 4, (height + 1) / 2)),
+                _ => None,
+            };
         }
-        DecodedFormat::I210 | DecodedFormat::I212 => {
-            let u_size = width * height * 2;
-            // U and V planes need to be aligned to 2.
-            let uv_size = ((width + 1) / 2) * ((height + 1) / 2) * 2 * 2;
-
-            u_size + uv_size
+        DecodedFormat::I420 => (3, true, true, 1),
+        DecodedFormat::I422 => (3, true, false, 1),
+        DecodedFormat::I444 => (3, false, false, 1),
+        DecodedFormat::I010 | DecodedFormat::I012 => (3, true, true, 2),
+        DecodedFormat::I210 | DecodedFormat::I212 => (3, true, false, 2),
+        DecodedFormat::I410 | DecodedFormat::I412 => (3, false, false, 2),
+    };
+
+    match plane {
+        0 => Some((width * bytes_per_sample, height)),
+        p if p < num_planes => {
+            let uv_width = if sub_h { (width + 1) / 2 } else { width };
+            let uv_height = if sub_v { (height + 1) / 2 } else { height };
+            Some((uv_width * bytes_per_sample, uv_height))
         }
-        DecodedFormat::I410 | DecodedFormat::I412 => (width * height * 2) * 3,
+        _ => None,
     }
 }
 
-/// Copies `src` into `dst` as I410, removing all padding and changing the layout from packed to
-/// triplanar. Also drops the alpha channel.
-fn y410_to_i410(
+/// Copies `num_lines` lines of `line_size` bytes from `src` to `dst`, using the respective strides
+/// of each buffer.
+fn plane_copy(
     src: &[u8],
+    src_stride: usize,
     dst: &mut [u8],
-    width: usize,
-    height: usize,
-    strides: [usize; 3],
-    offsets: [usize; 3],
+    dst_stride: usize,
+    line_size: usize,
+    num_lines: usize,
 ) {
-    let src_lines = src[offsets[0]..]
-        .chunks(strides[0])
-        .map(|line| &line[..width * 4]);
+    let src_lines = src.chunks(src_stride).map(|line| &line[..line_size]);
+    let dst_lines = dst
+        .chunks_mut(dst_stride)
+        .map(|line| &mut line[..line_size]);
+
+    for (src_line, dst_line) in src_lines.zip(dst_lines).take(num_lines) {
+        dst_line.copy_from_slice(src_line);
+    }
+}
+
+/// Copies `src` into `dst` as I410, removing all padding and changing the layout from packed to
+/// triplanar. Also drops the alpha channel.
+fn y410_to_i410(src: &[u8], src_layout: &FrameLayout, dst: &mut [u8]) {
+    let width = src_layout.size.width as usize;
+    let height = src_layout.size.height as usize;
+
+    let src_lines = src_layout.plane_lines(src, 0, width * 4);
 
     let dst_y_size = width * 2 * height;
     let dst_u_size = width * 2 * height;
@@ -420,7 +693,18 @@ fn y410_to_i410(
 
 #[cfg(test)]
 mod tests {
+    use super::decoded_frame_layout;
+    use super::decoded_frame_size;
+    use super::decoded_plane_size;
+    use super::p010_copy;
+    use super::plane_copy;
+    use super::AspectRatio;
+    use super::DecodedFormat;
     use super::Fourcc;
+    use super::FrameLayout;
+    use super::PlaneLayout;
+    use super::Resolution;
+    use super::ResolutionRoundMode;
 
     const NV12_FOURCC: u32 = 0x3231564E;
 
@@ -449,4 +733,156 @@ mod tests {
         let fourcc = Fourcc::from(NV12_FOURCC);
         assert_eq!(format!("{:?}", fourcc), "0x3231564e (NV12)");
     }
+
+    #[test]
+    fn resolution_alignment() {
+        let res = Resolution::from((1919, 1081));
+
+        assert_eq!(
+            res.round(ResolutionRoundMode::Even),
+            Resolution::from((1920, 1082))
+        );
+        assert_eq!(
+            res.round(ResolutionRoundMode::Macroblock),
+            Resolution::from((1920, 1088))
+        );
+        assert_eq!(
+            res.round(ResolutionRoundMode::Block(64)),
+            Resolution::from((1920, 1088))
+        );
+        assert_eq!(res.in_blocks(16), Resolution::from((120, 68)));
+        assert_eq!(res.in_blocks(128), Resolution::from((15, 9)));
+    }
+
+    #[test]
+    fn aspect_ratios() {
+        assert_eq!(
+            AspectRatio::from_h26x_idc(1, 0, 0),
+            Some(AspectRatio::SQUARE)
+        );
+        assert_eq!(
+            AspectRatio::from_h26x_idc(255, 64, 48),
+            Some(AspectRatio::new(4, 3))
+        );
+        assert_eq!(AspectRatio::from_h26x_idc(0, 0, 0), None);
+        assert_eq!(AspectRatio::from_h26x_idc(17, 0, 0), None);
+        assert_eq!(AspectRatio::from_h26x_idc(255, 0, 1), None);
+
+        // 704x576 PAL with 16:11 samples is displayed as 16:9.
+        let sar = AspectRatio::from_h26x_idc(4, 0, 0).unwrap();
+        assert_eq!(
+            Resolution::from((704, 576)).display_aspect_ratio(sar),
+            AspectRatio::new(16, 9)
+        );
+    }
+
+    #[test]
+    fn plane_sizes_match_frame_size() {
+        for format in [
+            DecodedFormat::I420,
+            DecodedFormat::NV12,
+            DecodedFormat::I422,
+            DecodedFormat::I444,
+            DecodedFormat::I010,
+            DecodedFormat::P010,
+            DecodedFormat::I012,
+            DecodedFormat::I210,
+            DecodedFormat::I212,
+            DecodedFormat::I410,
+            DecodedFormat::I412,
+        ] {
+            for (width, height) in [(64, 48), (63, 47)] {
+                let planes_size: usize = (0..)
+                    .map_while(|plane| decoded_plane_size(format, plane, width, height))
+                    .map(|(line_size, num_lines)| line_size * num_lines)
+                    .sum();
+
+                assert_eq!(
+                    planes_size,
+                    decoded_frame_size(format, width, height),
+                    "for format {:?}",
+                    format
+                );
+
+                // The planes follow each other without any gap.
+                let layout = decoded_frame_layout(format, width, height);
+                let mut offset = 0;
+                for plane in &layout.planes {
+                    assert_eq!(plane.offset, offset, "for format {:?}", format);
+                    offset += plane.size;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn p010_copy_removes_padding() {
+        // 3x3 frame: 6-byte luma lines in a stride of 8, and two 8-byte chroma lines in a stride
+        // of 10.
+        let src_layout = FrameLayout {
+            format: (Fourcc::from(DecodedFormat::P010), 0),
+            size: Resolution::from((3, 3)),
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: 8,
+                    size: 24,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 24,
+                    stride: 10,
+                    size: 20,
+                },
+            ],
+        };
+        let src: Vec<u8> = (0..44).collect();
+
+        let mut dst = vec![0u8; decoded_frame_size(DecodedFormat::P010, 3, 3)];
+        p010_copy(&src, &src_layout, &mut dst);
+
+        let expected: Vec<u8> = [0..6, 8..14, 16..22, 24..32, 34..42]
+            .into_iter()
+            .flatten()
+            .collect();
+        assert_eq!(dst, expected);
+    }
+
+    #[test]
+    fn plane_sizes() {
+        // 5x3 frame, so chroma dimensions are rounded up.
+        let sizes = |format| {
+            (0..4)
+                .map(|plane| decoded_plane_size(format, plane, 5, 3))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sizes(DecodedFormat::NV12),
+            [Some((5, 3)), Some((6, 2)), None, None]
+        );
+        assert_eq!(
+            sizes(DecodedFormat::I420),
+            [Some((5, 3)), Some((3, 2)), Some((3, 2)), None]
+        );
+        assert_eq!(
+            sizes(DecodedFormat::I210),
+            [Some((10, 3)), Some((6, 3)), Some((6, 3)), None]
+        );
+    }
+
+    #[test]
+    fn plane_copy_strides() {
+        // Two lines of 3 bytes in a stride of 4, copied into a stride of 5.
+        let src: Vec<u8> = (0..8).collect();
+        let mut dst = vec![0xff; 10];
+        plane_copy(&src, 4, &mut dst, 5, 3, 2);
+        assert_eq!(dst, [0, 1, 2, 0xff, 0xff, 4, 5, 6, 0xff, 0xff]);
+
+        // Only the requested number of lines is copied.
+        let mut dst = vec![0xff; 6];
+        plane_copy(&src, 4, &mut dst, 3, 3, 1);
+        assert_eq!(dst, [0, 1, 2, 0xff, 0xff, 0xff]);
+    }
 }
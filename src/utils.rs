@@ -7,15 +7,29 @@
 //! This module is for anything that doesn't fit into the other top-level modules. Try not to add
 //! new code here unless it really doesn't belong anywhere else.
 
+pub mod checksum;
+pub mod convert;
+#[cfg(feature = "debug-dump")]
+pub mod dump;
+pub mod ivf;
+pub mod metrics;
+
 use std::io::Cursor;
 use std::io::Seek;
 use std::marker::PhantomData;
+use std::os::fd::AsFd;
+use std::os::fd::BorrowedFd;
 use std::os::fd::OwnedFd;
 
+use anyhow::anyhow;
 use bytes::Buf;
 
+use crate::codec::av1::parser::ObuType;
+use crate::codec::av1::parser::Parser as Av1Parser;
+use crate::codec::av1::reader::Reader as Av1Reader;
 use crate::codec::h264::parser::Nalu as H264Nalu;
 use crate::codec::h265::parser::Nalu as H265Nalu;
+use crate::codec::index::Packet;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::StatelessVideoDecoder;
 use crate::decoder::BlockingMode;
@@ -42,20 +56,17 @@ impl<'a> IvfIterator<'a> {
 
         Self { cursor }
     }
-}
 
-impl<'a> Iterator for IvfIterator<'a> {
-    type Item = &'a [u8];
-
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Returns the next packet along with its offset in the stream and its timestamp.
+    pub fn next_packet(&mut self) -> Option<Packet<'a>> {
         // Make sure we have a header.
-        if self.cursor.remaining() < 6 {
+        if self.cursor.remaining() < 12 {
             return None;
         }
 
+        let offset = self.cursor.position() as usize;
         let len = self.cursor.get_u32_le() as usize;
-        // Skip PTS.
-        let _ = self.cursor.get_u64_le();
+        let timestamp = self.cursor.get_u64_le();
 
         if self.cursor.remaining() < len {
             return None;
@@ -65,7 +76,25 @@ impl<'a> Iterator for IvfIterator<'a> {
         let _ = self.cursor.seek(std::io::SeekFrom::Current(len as i64));
         let end = self.cursor.position() as usize;
 
-        Some(&self.cursor.get_ref()[start..end])
+        Some(Packet {
+            offset,
+            timestamp,
+            data: &self.cursor.get_ref()[start..end],
+        })
+    }
+
+    /// Turns this iterator into one returning the packets along with their offset and timestamp,
+    /// e.g. to build a [`crate::codec::index::StreamIndex`].
+    pub fn packets(mut self) -> impl Iterator<Item = Packet<'a>> {
+        std::iter::from_fn(move || self.next_packet())
+    }
+}
+
+impl<'a> Iterator for IvfIterator<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().map(|packet| packet.data)
     }
 }
 
@@ -106,7 +135,97 @@ impl<'a> Iterator for NalIterator<'a, H265Nalu<'a>> {
     }
 }
 
+/// Iterator over the temporal units of a raw AV1 stream.
+///
+/// The OBUs can be either in the low-overhead format, where each temporal unit starts with a
+/// temporal delimiter, or length-delimited as per Annex B. The format is detected from the start
+/// of the stream.
+pub struct Av1TemporalUnitIterator<'a> {
+    data: &'a [u8],
+    annexb: bool,
+}
+
+impl<'a> Av1TemporalUnitIterator<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        let annexb = matches!(Av1Parser::annexb_probe(data), Ok(true));
+
+        Self { data, annexb }
+    }
+
+    /// Returns the length of the low-overhead OBU at the start of `data`.
+    fn obu_length(data: &[u8]) -> Option<(ObuType, usize)> {
+        let mut r = Av1Reader::new(data);
+        let _obu_forbidden_bit = r.read_bit().ok()?;
+        let obu_type = ObuType::n(r.read_bits(4).ok()?)?;
+        let extension_flag = r.read_bit().ok()?;
+        let has_size_field = r.read_bit().ok()?;
+        let _obu_reserved_1bit = r.read_bit().ok()?;
+        if extension_flag {
+            r.read_bits(8).ok()?;
+        }
+
+        // Only the last OBU of the stream can omit its size.
+        if !has_size_field {
+            return Some((obu_type, data.len()));
+        }
+
+        let obu_size = r.read_leb128().ok()?;
+        let header_size = usize::try_from(r.position() / 8).ok()?;
+
+        Some((obu_type, header_size + usize::try_from(obu_size).ok()?))
+    }
+}
+
+impl<'a> Iterator for Av1TemporalUnitIterator<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let len = if self.annexb {
+            // Each temporal unit is prefixed by its size.
+            let mut r = Av1Reader::new(self.data);
+            let temporal_unit_size = r.read_leb128().ok()?;
+            usize::try_from(r.position() / 8).ok()? + usize::try_from(temporal_unit_size).ok()?
+        } else {
+            // The temporal unit extends until the next temporal delimiter.
+            let mut len = 0;
+            while len < self.data.len() {
+                let (obu_type, obu_length) = Self::obu_length(&self.data[len..])?;
+                if len > 0 && obu_type == ObuType::TemporalDelimiter {
+                    break;
+                }
+                len += obu_length;
+            }
+            len
+        };
+
+        if len > self.data.len() {
+            return None;
+        }
+
+        let (temporal_unit, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Some(temporal_unit)
+    }
+}
+
 /// Simple decoding loop that plays the stream once from start to finish.
+///
+/// `stream_iter` yields the decoding units of the stream, e.g. from an [`IvfIterator`] for VP8,
+/// VP9 and AV1 or a [`NalIterator`] for H.264 and H.265 Annex B streams. Each unit is submitted
+/// to `decoder`, processing its events and retrying the remainder of the unit whenever the
+/// decoder returns [`DecodeError::CheckEvents`] or [`DecodeError::NotEnoughOutputBuffers`].
+/// Format changes are accepted with `output_format`, using `allocate_new_frames` to add the
+/// missing frames to the decoder's pool, and `on_new_frame` is called for each decoded frame in
+/// display order. The decoder is flushed once the stream is exhausted, so all its frames are
+/// output before this returns.
+///
+/// Any other decoding error, as well as failures to negotiate the format or to allocate frames,
+/// stops the loop and is returned.
 pub fn simple_playback_loop<D, R, I, M>(
     decoder: &mut D,
     stream_iter: I,
@@ -129,7 +248,7 @@ where
                     on_new_frame(frame);
                 }
                 DecoderEvent::FormatChanged(mut format_setter) => {
-                    format_setter.try_format(output_format).unwrap();
+                    format_setter.try_format(output_format)?;
                     // Allocate the missing number of buffers in our pool for decoding to succeed.
                     let min_num_frames = format_setter.stream_info().min_num_frames;
                     let pool_num_frames = format_setter.frame_pool().num_managed_frames();
@@ -139,9 +258,17 @@ where
                             min_num_frames - pool_num_frames,
                         )?;
                         let pool = format_setter.frame_pool();
-                        pool.add_frames(frames).unwrap();
+                        pool.add_frames(frames)?;
                     }
                 }
+                DecoderEvent::OutputStalled(_)
+                | DecoderEvent::ResolutionChanged(_)
+                | DecoderEvent::ColorimetryChanged(_)
+                | DecoderEvent::FrameDropped(_)
+                | DecoderEvent::NonFatalError(_, _)
+                | DecoderEvent::DeviceReset
+                | DecoderEvent::DataSkipped(_)
+                | DecoderEvent::BackendSwitched(_) => (),
             }
         }
 
@@ -244,11 +371,13 @@ impl UserPtrFrame {
                         buffer_index: 0,
                         offset: 0,
                         stride,
+                        size: uv_start,
                     },
                     PlaneLayout {
                         buffer_index: 0,
                         offset: uv_start,
                         stride,
+                        size: uv_size,
                     },
                 ],
             },
@@ -261,6 +390,82 @@ pub struct DmabufFrame {
     pub layout: FrameLayout,
 }
 
+/// Plane of a frame, as passed to the `add` request of `zwp_linux_buffer_params_v1`.
+#[derive(Debug)]
+pub struct LinuxDmabufPlane<'a> {
+    /// DMA-BUF file descriptor of the buffer containing the plane.
+    pub fd: BorrowedFd<'a>,
+    /// Index of the plane within the frame.
+    pub plane_idx: u32,
+    /// Offset of the plane within its buffer, in bytes.
+    pub offset: u32,
+    /// Stride of the plane, in bytes.
+    pub stride: u32,
+    /// High 32 bits of the DRM format modifier of the frame.
+    pub modifier_hi: u32,
+    /// Low 32 bits of the DRM format modifier of the frame.
+    pub modifier_lo: u32,
+}
+
+/// Description of a frame in the terms of the `zwp_linux_dmabuf_v1` Wayland protocol.
+///
+/// Wayland clients can create a `wl_buffer` for the frame by sending one `add` request per plane
+/// of `planes` to a `zwp_linux_buffer_params_v1` object, followed by a `create` or
+/// `create_immed` request using `width`, `height` and `format`.
+#[derive(Debug)]
+pub struct LinuxDmabufParams<'a> {
+    /// Planes of the frame, in order.
+    pub planes: Vec<LinuxDmabufPlane<'a>>,
+    /// Width of the frame, in pixels. This is the coded width: the displayed part of the frame
+    /// can be selected e.g. using the `wp_viewporter` protocol.
+    pub width: i32,
+    /// Height of the frame, in pixels.
+    pub height: i32,
+    /// DRM fourcc of the frame.
+    pub format: u32,
+}
+
+impl DmabufFrame {
+    /// Returns the parameters needed to import this frame into a Wayland compositor using the
+    /// `zwp_linux_dmabuf_v1` protocol.
+    pub fn linux_dmabuf_params(&self) -> anyhow::Result<LinuxDmabufParams<'_>> {
+        let (fourcc, modifier) = self.layout.format;
+
+        let planes = self
+            .layout
+            .planes
+            .iter()
+            .enumerate()
+            .map(|(i, plane)| {
+                let fd = self.fds.get(plane.buffer_index).ok_or_else(|| {
+                    anyhow!(
+                        "plane {} refers to buffer {}, but frame has {} buffers",
+                        i,
+                        plane.buffer_index,
+                        self.fds.len()
+                    )
+                })?;
+
+                Ok(LinuxDmabufPlane {
+                    fd: fd.as_fd(),
+                    plane_idx: i as u32,
+                    offset: u32::try_from(plane.offset)?,
+                    stride: u32::try_from(plane.stride)?,
+                    modifier_hi: (modifier >> 32) as u32,
+                    modifier_lo: modifier as u32,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(LinuxDmabufParams {
+            planes,
+            width: i32::try_from(self.layout.size.width)?,
+            height: i32::try_from(self.layout.size.height)?,
+            format: u32::from(fourcc),
+        })
+    }
+}
+
 impl Drop for UserPtrFrame {
     fn drop(&mut self) {
         for buffer in std::mem::take(&mut self.buffers).into_iter() {
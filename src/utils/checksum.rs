@@ -0,0 +1,161 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Per-frame checksums of decoded frames.
+//!
+//! This is what the tests of this crate use to validate the output of the decoders: the checksum
+//! of each decoded frame is compared against a list of expected checksums, one per line, usually
+//! generated with `ffmpeg -f framehash` (see the `gen_crcs.sh` scripts next to the test data).
+//! Checksums are computed on the data returned by [`crate::decoder::DynHandle::map`], so the
+//! decoder must output the same format as the one the expected checksums were generated for.
+
+use std::str::Lines;
+
+use anyhow::anyhow;
+
+use crate::decoder::DecodedHandle;
+
+/// Algorithm used to compute the checksum of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// CRC32, formatted as 8 lowercase hexadecimal digits.
+    Crc32,
+    /// MD5, formatted as 32 lowercase hexadecimal digits.
+    Md5,
+}
+
+impl ChecksumAlgorithm {
+    /// Returns the checksum of `data`.
+    pub fn compute(&self, data: &[u8]) -> String {
+        match self {
+            ChecksumAlgorithm::Crc32 => format!("{:08x}", crc32fast::hash(data)),
+            ChecksumAlgorithm::Md5 => format!("{:x}", md5::compute(data)),
+        }
+    }
+}
+
+/// Records the checksum of each frame it consumes.
+pub struct ChecksumSink {
+    algorithm: ChecksumAlgorithm,
+    checksums: Vec<String>,
+}
+
+impl ChecksumSink {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Self {
+            algorithm,
+            checksums: Default::default(),
+        }
+    }
+
+    /// Waits for `handle` to be ready and records the checksum of its content, which is also
+    /// returned.
+    pub fn consume<H: DecodedHandle + ?Sized>(&mut self, handle: &H) -> anyhow::Result<&str> {
+        let frame = handle.to_owned_frame()?;
+        self.checksums.push(self.algorithm.compute(&frame.data));
+
+        Ok(self.checksums.last().unwrap())
+    }
+
+    /// Returns the checksums of all the frames consumed so far, in order.
+    pub fn checksums(&self) -> &[String] {
+        &self.checksums
+    }
+
+    /// Returns the checksums of all the frames consumed so far in the format of the expected
+    /// checksums, i.e. one per line.
+    pub fn to_lines(&self) -> String {
+        self.checksums.iter().map(|c| format!("{}\n", c)).collect()
+    }
+}
+
+/// Compares the checksum of each frame it consumes against a list of expected checksums.
+pub struct ChecksumChecker<'a> {
+    algorithm: ChecksumAlgorithm,
+    expected: Lines<'a>,
+    num_frames: usize,
+}
+
+impl<'a> ChecksumChecker<'a> {
+    /// Creates a new checker for the expected checksums `expected`, one per line.
+    pub fn new(algorithm: ChecksumAlgorithm, expected: &'a str) -> Self {
+        Self {
+            algorithm,
+            expected: expected.lines(),
+            num_frames: 0,
+        }
+    }
+
+    /// Checks the checksum of the frame with content `data` against the next expected one.
+    pub fn check(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let frame_num = self.num_frames;
+        self.num_frames += 1;
+
+        let expected = self
+            .expected
+            .next()
+            .ok_or_else(|| anyhow!("decoded more frames than expected"))?;
+        let checksum = self.algorithm.compute(data);
+
+        if checksum != expected.trim() {
+            return Err(anyhow!(
+                "checksum mismatch at frame {}: expected {}, got {}",
+                frame_num,
+                expected.trim(),
+                checksum
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Waits for `handle` to be ready and checks the checksum of its content against the next
+    /// expected one.
+    pub fn consume<H: DecodedHandle + ?Sized>(&mut self, handle: &H) -> anyhow::Result<()> {
+        let frame = handle.to_owned_frame()?;
+
+        self.check(&frame.data)
+    }
+
+    /// Returns the number of frames checked so far.
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    /// Makes sure that all the expected frames have been checked.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        match self.expected.next() {
+            None => Ok(()),
+            Some(_) => Err(anyhow!(
+                "decoded less frames than expected ({})",
+                self.num_frames
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChecksumAlgorithm;
+    use super::ChecksumChecker;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(ChecksumAlgorithm::Crc32.compute(b"123456789"), "cbf43926");
+        assert_eq!(
+            ChecksumAlgorithm::Md5.compute(b""),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+
+        let mut checker = ChecksumChecker::new(ChecksumAlgorithm::Crc32, "cbf43926\n00000000\n");
+        checker.check(b"123456789").unwrap();
+        assert!(checker.check(b"1").is_err());
+        assert!(checker.check(b"").is_err());
+        assert_eq!(checker.num_frames(), 3);
+
+        let mut checker = ChecksumChecker::new(ChecksumAlgorithm::Crc32, "cbf43926\n00000000\n");
+        checker.check(b"123456789").unwrap();
+        assert!(checker.finish().is_err());
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Conversions between pixel formats.
+//!
+//! All the functions of this module work on frames without any padding, with their planes put
+//! sequentially one after the other as described by [`crate::decoded_plane_size`], i.e. the layout
+//! of the buffers filled by [`crate::decoder::MappableHandle::read`]. YV12 is laid out like I420,
+//! with the U and V planes swapped.
+//!
+//! The inner loops iterate over exact chunks of the planes, which lets the compiler drop the
+//! bounds checks and vectorize them.
+
+use anyhow::anyhow;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use crate::decoded_frame_size;
+use crate::DecodedFormat;
+
+/// Number of bits P010 samples are shifted by compared to I010 ones.
+const P010_SHIFT: u32 = 6;
+
+/// Returns the sizes in bytes of the luma plane and of each chroma plane of a 4:2:0 frame of
+/// `width`x`height` with `bytes_per_sample` bytes per sample.
+fn i420_plane_sizes(width: usize, height: usize, bytes_per_sample: usize) -> (usize, usize) {
+    let y_size = width * height * bytes_per_sample;
+    let uv_size = width.div_ceil(2) * height.div_ceil(2) * bytes_per_sample;
+
+    (y_size, uv_size)
+}
+
+/// Converts `src` from NV12 to I420 into `dst`.
+pub fn nv12_to_i420(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    let (y_size, uv_size) = i420_plane_sizes(width, height, 1);
+    let (src_y, src_uv) = src.split_at(y_size);
+    let (dst_y, dst_uv) = dst.split_at_mut(y_size);
+    let (dst_u, dst_v) = dst_uv.split_at_mut(uv_size);
+
+    dst_y.copy_from_slice(src_y);
+    for (uv, (u, v)) in src_uv[..uv_size * 2]
+        .chunks_exact(2)
+        .zip(dst_u.iter_mut().zip(dst_v[..uv_size].iter_mut()))
+    {
+        *u = uv[0];
+        *v = uv[1];
+    }
+}
+
+/// Converts `src` from I420 to NV12 into `dst`.
+pub fn i420_to_nv12(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    let (y_size, uv_size) = i420_plane_sizes(width, height, 1);
+    let (src_y, src_uv) = src.split_at(y_size);
+    let (src_u, src_v) = src_uv.split_at(uv_size);
+    let (dst_y, dst_uv) = dst.split_at_mut(y_size);
+
+    dst_y.copy_from_slice(src_y);
+    for (uv, (u, v)) in dst_uv[..uv_size * 2]
+        .chunks_exact_mut(2)
+        .zip(src_u.iter().zip(src_v[..uv_size].iter()))
+    {
+        uv[0] = *u;
+        uv[1] = *v;
+    }
+}
+
+/// Converts `src` from I420 to YV12 into `dst`.
+pub fn i420_to_yv12(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    let (y_size, uv_size) = i420_plane_sizes(width, height, 1);
+    let (src_y, src_uv) = src.split_at(y_size);
+    let (src_u, src_v) = src_uv.split_at(uv_size);
+    let (dst_y, dst_uv) = dst.split_at_mut(y_size);
+    let (dst_v, dst_u) = dst_uv.split_at_mut(uv_size);
+
+    dst_y.copy_from_slice(src_y);
+    dst_u[..uv_size].copy_from_slice(src_u);
+    dst_v.copy_from_slice(&src_v[..uv_size]);
+}
+
+/// Converts `src` from YV12 to I420 into `dst`.
+pub fn yv12_to_i420(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    // Swapping the chroma planes works both ways.
+    i420_to_yv12(src, dst, width, height)
+}
+
+/// Converts `src` from P010 to I010 into `dst`, i.e. moves the 10 useful bits of each sample from
+/// the MSBs to the LSBs and de-interleaves the chroma plane.
+pub fn p010_to_i010(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    let (y_size, uv_size) = i420_plane_sizes(width, height, 2);
+    let (src_y, src_uv) = src.split_at(y_size);
+    let (dst_y, dst_uv) = dst.split_at_mut(y_size);
+    let (dst_u, dst_v) = dst_uv.split_at_mut(uv_size);
+
+    for (src, dst) in src_y.chunks_exact(2).zip(dst_y.chunks_exact_mut(2)) {
+        LittleEndian::write_u16(dst, LittleEndian::read_u16(src) >> P010_SHIFT);
+    }
+
+    for (uv, (u, v)) in src_uv[..uv_size * 2]
+        .chunks_exact(4)
+        .zip(dst_u.chunks_exact_mut(2).zip(dst_v.chunks_exact_mut(2)))
+    {
+        LittleEndian::write_u16(u, LittleEndian::read_u16(&uv[0..2]) >> P010_SHIFT);
+        LittleEndian::write_u16(v, LittleEndian::read_u16(&uv[2..4]) >> P010_SHIFT);
+    }
+}
+
+/// Converts `src` from I010 to P010 into `dst`, i.e. moves the 10 useful bits of each sample from
+/// the LSBs to the MSBs and interleaves the chroma planes.
+pub fn i010_to_p010(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    let (y_size, uv_size) = i420_plane_sizes(width, height, 2);
+    let (src_y, src_uv) = src.split_at(y_size);
+    let (src_u, src_v) = src_uv.split_at(uv_size);
+    let (dst_y, dst_uv) = dst.split_at_mut(y_size);
+
+    for (src, dst) in src_y.chunks_exact(2).zip(dst_y.chunks_exact_mut(2)) {
+        LittleEndian::write_u16(dst, LittleEndian::read_u16(src) << P010_SHIFT);
+    }
+
+    for (uv, (u, v)) in dst_uv[..uv_size * 2]
+        .chunks_exact_mut(4)
+        .zip(src_u.chunks_exact(2).zip(src_v[..uv_size].chunks_exact(2)))
+    {
+        LittleEndian::write_u16(&mut uv[0..2], LittleEndian::read_u16(u) << P010_SHIFT);
+        LittleEndian::write_u16(&mut uv[2..4], LittleEndian::read_u16(v) << P010_SHIFT);
+    }
+}
+
+/// Converts `src` from I420 to I422 into `dst`, by duplicating each line of the chroma planes.
+pub fn i420_to_i422(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    let uv_width = width.div_ceil(2);
+    let (y_size, src_uv_size) = i420_plane_sizes(width, height, 1);
+    let dst_uv_size = uv_width * height;
+    let (src_y, src_uv) = src.split_at(y_size);
+    let (dst_y, dst_uv) = dst.split_at_mut(y_size);
+
+    dst_y.copy_from_slice(src_y);
+    for (src_plane, dst_plane) in src_uv[..src_uv_size * 2]
+        .chunks_exact(src_uv_size)
+        .zip(dst_uv[..dst_uv_size * 2].chunks_exact_mut(dst_uv_size))
+    {
+        for (src_line, dst_lines) in src_plane
+            .chunks_exact(uv_width)
+            .zip(dst_plane.chunks_mut(uv_width * 2))
+        {
+            for dst_line in dst_lines.chunks_exact_mut(uv_width) {
+                dst_line.copy_from_slice(src_line);
+            }
+        }
+    }
+}
+
+/// Converts `src` from I422 to I420 into `dst`, by averaging each pair of lines of the chroma
+/// planes.
+pub fn i422_to_i420(src: &[u8], dst: &mut [u8], width: usize, height: usize) {
+    let uv_width = width.div_ceil(2);
+    let (y_size, dst_uv_size) = i420_plane_sizes(width, height, 1);
+    let src_uv_size = uv_width * height;
+    let (src_y, src_uv) = src.split_at(y_size);
+    let (dst_y, dst_uv) = dst.split_at_mut(y_size);
+
+    dst_y.copy_from_slice(src_y);
+    for (src_plane, dst_plane) in src_uv[..src_uv_size * 2]
+        .chunks_exact(src_uv_size)
+        .zip(dst_uv[..dst_uv_size * 2].chunks_exact_mut(dst_uv_size))
+    {
+        for (src_lines, dst_line) in src_plane
+            .chunks(uv_width * 2)
+            .zip(dst_plane.chunks_exact_mut(uv_width))
+        {
+            // The last line of a frame with an odd height has no pair.
+            let (top, bottom) = src_lines.split_at(uv_width);
+            let bottom = if bottom.is_empty() { top } else { bottom };
+
+            for (dst, (top, bottom)) in dst_line.iter_mut().zip(top.iter().zip(bottom)) {
+                *dst = (u16::from(*top) + u16::from(*bottom)).div_ceil(2) as u8;
+            }
+        }
+    }
+}
+
+/// Converts `src`, a `width`x`height` frame in `src_format`, into `dst` in `dst_format`.
+///
+/// Returns an error if the conversion is not supported or if the buffers are too small for the
+/// frame.
+pub fn convert(
+    src_format: DecodedFormat,
+    src: &[u8],
+    dst_format: DecodedFormat,
+    dst: &mut [u8],
+    width: usize,
+    height: usize,
+) -> anyhow::Result<()> {
+    let src_size = decoded_frame_size(src_format, width, height);
+    let dst_size = decoded_frame_size(dst_format, width, height);
+    if src.len() < src_size || dst.len() < dst_size {
+        return Err(anyhow!(
+            "buffers of size {} and {} cannot contain {}x{} frames in {:?} and {:?}",
+            src.len(),
+            dst.len(),
+            width,
+            height,
+            src_format,
+            dst_format
+        ));
+    }
+
+    let src = &src[..src_size];
+    let dst = &mut dst[..dst_size];
+
+    match (src_format, dst_format) {
+        (src_format, dst_format) if src_format == dst_format => dst.copy_from_slice(src),
+        (DecodedFormat::NV12, DecodedFormat::I420) => nv12_to_i420(src, dst, width, height),
+        (DecodedFormat::I420, DecodedFormat::NV12) => i420_to_nv12(src, dst, width, height),
+        (DecodedFormat::P010, DecodedFormat::I010) => p010_to_i010(src, dst, width, height),
+        (DecodedFormat::I010, DecodedFormat::P010) => i010_to_p010(src, dst, width, height),
+        (DecodedFormat::I420, DecodedFormat::I422) => i420_to_i422(src, dst, width, height),
+        (DecodedFormat::I422, DecodedFormat::I420) => i422_to_i420(src, dst, width, height),
+        _ => {
+            return Err(anyhow!(
+                "conversion from {:?} to {:?} is not supported",
+                src_format,
+                dst_format
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::convert;
+    use super::i420_to_yv12;
+    use super::yv12_to_i420;
+    use crate::decoded_frame_size;
+    use crate::DecodedFormat;
+
+    /// 3x3 I420 frame, so the chroma planes are rounded up.
+    const I420: [u8; 17] = [
+        1, 2, 3, 4, 5, 6, 7, 8, 9, // Y
+        10, 11, 12, 13, // U
+        20, 21, 22, 23, // V
+    ];
+
+    #[test]
+    fn nv12_i420_round_trip() {
+        let mut nv12 = [0u8; 17];
+        convert(
+            DecodedFormat::I420,
+            &I420,
+            DecodedFormat::NV12,
+            &mut nv12,
+            3,
+            3,
+        )
+        .unwrap();
+        assert_eq!(nv12[9..], [10, 20, 11, 21, 12, 22, 13, 23]);
+
+        let mut i420 = [0u8; 17];
+        convert(
+            DecodedFormat::NV12,
+            &nv12,
+            DecodedFormat::I420,
+            &mut i420,
+            3,
+            3,
+        )
+        .unwrap();
+        assert_eq!(i420, I420);
+    }
+
+    #[test]
+    fn yv12_i420_round_trip() {
+        let mut yv12 = [0u8; 17];
+        i420_to_yv12(&I420, &mut yv12, 3, 3);
+        assert_eq!(yv12[9..], [20, 21, 22, 23, 10, 11, 12, 13]);
+
+        let mut i420 = [0u8; 17];
+        yv12_to_i420(&yv12, &mut i420, 3, 3);
+        assert_eq!(i420, I420);
+    }
+
+    #[test]
+    fn p010_i010_round_trip() {
+        // 2x2 frame with the 10 useful bits of each sample in the LSBs.
+        let i010: [u8; 12] = [
+            0x01, 0x00, 0x02, 0x00, // Y line 0
+            0x03, 0x00, 0xff, 0x03, // Y line 1
+            0x40, 0x00, // U
+            0x80, 0x00, // V
+        ];
+
+        let mut p010 = [0u8; 12];
+        convert(
+            DecodedFormat::I010,
+            &i010,
+            DecodedFormat::P010,
+            &mut p010,
+            2,
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            p010,
+            [
+                0x40, 0x00, 0x80, 0x00, // Y line 0
+                0xc0, 0x00, 0xc0, 0xff, // Y line 1
+                0x00, 0x10, 0x00, 0x20, // UV line 0
+            ]
+        );
+
+        let mut converted = [0u8; 12];
+        convert(
+            DecodedFormat::P010,
+            &p010,
+            DecodedFormat::I010,
+            &mut converted,
+            2,
+            2,
+        )
+        .unwrap();
+        assert_eq!(converted, i010);
+    }
+
+    #[test]
+    fn i420_i422_round_trip() {
+        let mut i422 = vec![0u8; decoded_frame_size(DecodedFormat::I422, 3, 3)];
+        convert(
+            DecodedFormat::I420,
+            &I420,
+            DecodedFormat::I422,
+            &mut i422,
+            3,
+            3,
+        )
+        .unwrap();
+        assert_eq!(
+            i422[9..21],
+            [10, 11, 10, 11, 12, 13, 20, 21, 20, 21, 22, 23]
+        );
+
+        let mut i420 = [0u8; 17];
+        convert(
+            DecodedFormat::I422,
+            &i422,
+            DecodedFormat::I420,
+            &mut i420,
+            3,
+            3,
+        )
+        .unwrap();
+        assert_eq!(i420, I420);
+    }
+
+    #[test]
+    fn unsupported_conversion() {
+        let mut i444 = vec![0u8; decoded_frame_size(DecodedFormat::I444, 3, 3)];
+        assert!(convert(
+            DecodedFormat::I420,
+            &I420,
+            DecodedFormat::I444,
+            &mut i444,
+            3,
+            3
+        )
+        .is_err());
+        assert!(convert(
+            DecodedFormat::I420,
+            &I420[..8],
+            DecodedFormat::NV12,
+            &mut [0; 17],
+            3,
+            3
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Dumping of decoded frames as viewable images, for debugging.
+//!
+//! Frames are converted to RGB using the BT.601 limited range matrix, which is what most of our
+//! test streams use. The result is meant to be looked at, not to be bit-exact.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use crate::decoder::DecodedHandle;
+use crate::DecodedFormat;
+use crate::Resolution;
+
+/// Image format of the dumped frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    Png,
+    /// Binary PPM, which is trivial to parse but not compressed.
+    Ppm,
+}
+
+impl DumpFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            DumpFormat::Png => "png",
+            DumpFormat::Ppm => "ppm",
+        }
+    }
+}
+
+/// Converts the frame `data` of `format` and size `width`x`height`, laid out as described by
+/// [`crate::decoded_frame_layout`], into packed 8-bit RGB.
+pub fn to_rgb(
+    format: DecodedFormat,
+    data: &[u8],
+    width: usize,
+    height: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let layout = crate::decoded_frame_layout(format, width, height);
+    let frame_size = crate::decoded_frame_size(format, width, height);
+    if data.len() < frame_size {
+        return Err(anyhow!(
+            "{:?} frame of size {}x{} is {} bytes, but only {} were provided",
+            format,
+            width,
+            height,
+            frame_size,
+            data.len()
+        ));
+    }
+
+    // Chroma subsampling shifts, bytes per sample and shift to apply to get an 8-bit sample.
+    let (sub_h, sub_v, bytes_per_sample, shift) = match format {
+        DecodedFormat::NV12 | DecodedFormat::I420 => (1, 1, 1, 0),
+        DecodedFormat::I422 => (1, 0, 1, 0),
+        DecodedFormat::I444 => (0, 0, 1, 0),
+        // The 10 useful bits are the MSBs.
+        DecodedFormat::P010 => (1, 1, 2, 8),
+        DecodedFormat::I010 => (1, 1, 2, 2),
+        DecodedFormat::I012 => (1, 1, 2, 4),
+        DecodedFormat::I210 => (1, 0, 2, 2),
+        DecodedFormat::I212 => (1, 0, 2, 4),
+        DecodedFormat::I410 => (0, 0, 2, 2),
+        DecodedFormat::I412 => (0, 0, 2, 4),
+    };
+    let interleaved = matches!(format, DecodedFormat::NV12 | DecodedFormat::P010);
+
+    let sample = |plane: usize, line: usize, pos: usize| -> i32 {
+        let plane = &layout.planes[plane];
+        let offset = plane.offset + line * plane.stride + pos * bytes_per_sample;
+
+        match bytes_per_sample {
+            1 => i32::from(data[offset]),
+            _ => i32::from(LittleEndian::read_u16(&data[offset..]) >> shift),
+        }
+    };
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        let chroma_line = y >> sub_v;
+        for x in 0..width {
+            let (u, v) = if interleaved {
+                let pos = (x >> sub_h) * 2;
+                (sample(1, chroma_line, pos), sample(1, chroma_line, pos + 1))
+            } else {
+                let pos = x >> sub_h;
+                (sample(1, chroma_line, pos), sample(2, chroma_line, pos))
+            };
+
+            let c = 298 * (sample(0, y, x) - 16);
+            let d = u - 128;
+            let e = v - 128;
+
+            rgb.extend(
+                [c + 409 * e, c - 100 * d - 208 * e, c + 516 * d]
+                    .map(|val| ((val + 128) >> 8).clamp(0, 255) as u8),
+            );
+        }
+    }
+
+    Ok(rgb)
+}
+
+/// Writes decoded frames as numbered image files.
+pub struct FrameDumper {
+    dir: PathBuf,
+    prefix: String,
+    format: DumpFormat,
+    num_frames: usize,
+}
+
+impl FrameDumper {
+    /// Creates a dumper writing frames into `dir` as `<prefix>NNN.<extension>`, numbered from 0.
+    pub fn new<P: Into<PathBuf>>(dir: P, prefix: &str, format: DumpFormat) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: prefix.to_string(),
+            format,
+            num_frames: 0,
+        }
+    }
+
+    /// Writes the next frame, which contains `data` in `format`, as returned by
+    /// [`crate::decoder::MappableHandle::read`]. Returns the path of the written file.
+    pub fn dump(
+        &mut self,
+        format: DecodedFormat,
+        resolution: Resolution,
+        data: &[u8],
+    ) -> anyhow::Result<PathBuf> {
+        let width = resolution.width as usize;
+        let height = resolution.height as usize;
+        let rgb = to_rgb(format, data, width, height)?;
+
+        let path = self.dir.join(format!(
+            "{}{:03}.{}",
+            self.prefix,
+            self.num_frames,
+            self.format.extension()
+        ));
+        self.num_frames += 1;
+
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut file = BufWriter::new(file);
+
+        match self.format {
+            DumpFormat::Png => {
+                let mut encoder = png::Encoder::new(file, resolution.width, resolution.height);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.write_header()?.write_image_data(&rgb)?;
+            }
+            DumpFormat::Ppm => {
+                write!(file, "P6\n{} {}\n255\n", width, height)?;
+                file.write_all(&rgb)?;
+                file.flush()?;
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Waits for `handle` to be ready and writes its content as the next frame. Returns the path
+    /// of the written file.
+    pub fn consume<H: DecodedHandle + ?Sized>(&mut self, handle: &H) -> anyhow::Result<PathBuf> {
+        handle.sync()?;
+
+        let picture = handle.dyn_picture();
+        let mut mapping = picture.map()?;
+        let layout = mapping.layout()?;
+        let format = DecodedFormat::try_from(layout.format.0).map_err(|e| anyhow!(e))?;
+        let data = mapping.to_vec()?;
+
+        self.dump(format, layout.size, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_rgb;
+    use crate::DecodedFormat;
+
+    #[test]
+    fn yuv_to_rgb() {
+        // 2x2 frames of limited range red.
+        let i420 = [81, 81, 81, 81, 90, 240];
+        let nv12 = [81, 81, 81, 81, 90, 240];
+        let red = [255, 0, 0].repeat(4);
+
+        assert_eq!(to_rgb(DecodedFormat::I420, &i420, 2, 2).unwrap(), red);
+        assert_eq!(to_rgb(DecodedFormat::NV12, &nv12, 2, 2).unwrap(), red);
+        assert!(to_rgb(DecodedFormat::NV12, &nv12[..5], 2, 2).is_err());
+
+        // 2x1 frame with a black and a white pixel, with 10 bits per sample.
+        let i410 = [64, 0, 0xac, 0x03, 0, 2, 0, 2, 0, 2, 0, 2];
+        assert_eq!(
+            to_rgb(DecodedFormat::I410, &i410, 2, 1).unwrap(),
+            [0, 0, 0, 255, 255, 255]
+        );
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Writing of IVF files, the container read by [`crate::utils::IvfIterator`].
+//!
+//! The file header contains the number of frames of the stream, which is only known once the last
+//! frame has been written. [`IvfWriter`] patches it when it is finished or dropped, and can also
+//! refresh it periodically so a file left behind by a crashed process remains valid up to the last
+//! refresh. Frames written after the last refresh are still readable, only the count is stale.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+/// Size of the IVF file header.
+pub const IVF_FILE_HEADER_SIZE: usize = 32;
+/// Size of the header preceding each frame.
+pub const IVF_FRAME_HEADER_SIZE: usize = 12;
+
+/// Offset of the number of frames in the file header.
+const NUM_FRAMES_OFFSET: u64 = 24;
+
+/// Header of an IVF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IvfFileHeader {
+    /// Codec of the stream, e.g. `b"VP80"`, `b"VP90"` or `b"AV01"`.
+    pub fourcc: [u8; 4],
+    pub width: u16,
+    pub height: u16,
+    /// Denominator of the time base of the frame timestamps.
+    pub timebase_denominator: u32,
+    /// Numerator of the time base of the frame timestamps.
+    pub timebase_numerator: u32,
+    /// Number of frames in the file.
+    pub num_frames: u32,
+}
+
+impl IvfFileHeader {
+    pub fn new(fourcc: [u8; 4], width: u16, height: u16, framerate: u32) -> Self {
+        Self {
+            fourcc,
+            width,
+            height,
+            timebase_denominator: framerate,
+            timebase_numerator: 1,
+            num_frames: 0,
+        }
+    }
+
+    /// Parses the header at the start of `data`.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < IVF_FILE_HEADER_SIZE {
+            return Err(anyhow!(
+                "IVF header is {} bytes, got {}",
+                IVF_FILE_HEADER_SIZE,
+                data.len()
+            ));
+        }
+        if &data[0..4] != b"DKIF" {
+            return Err(anyhow!("invalid IVF signature {:?}", &data[0..4]));
+        }
+
+        let header_size = LittleEndian::read_u16(&data[6..8]);
+        if usize::from(header_size) != IVF_FILE_HEADER_SIZE {
+            return Err(anyhow!("unsupported IVF header size {}", header_size));
+        }
+
+        Ok(Self {
+            fourcc: [data[8], data[9], data[10], data[11]],
+            width: LittleEndian::read_u16(&data[12..14]),
+            height: LittleEndian::read_u16(&data[14..16]),
+            timebase_denominator: LittleEndian::read_u32(&data[16..20]),
+            timebase_numerator: LittleEndian::read_u32(&data[20..24]),
+            num_frames: LittleEndian::read_u32(&data[24..28]),
+        })
+    }
+
+    /// Returns the serialized header.
+    pub fn to_bytes(&self) -> [u8; IVF_FILE_HEADER_SIZE] {
+        let mut data = [0u8; IVF_FILE_HEADER_SIZE];
+
+        data[0..4].copy_from_slice(b"DKIF");
+        // Version 0, followed by the header size.
+        LittleEndian::write_u16(&mut data[6..8], IVF_FILE_HEADER_SIZE as u16);
+        data[8..12].copy_from_slice(&self.fourcc);
+        LittleEndian::write_u16(&mut data[12..14], self.width);
+        LittleEndian::write_u16(&mut data[14..16], self.height);
+        LittleEndian::write_u32(&mut data[16..20], self.timebase_denominator);
+        LittleEndian::write_u32(&mut data[20..24], self.timebase_numerator);
+        LittleEndian::write_u32(&mut data[24..28], self.num_frames);
+
+        data
+    }
+}
+
+/// Writes frames into an IVF stream, keeping the frame count of its header up-to-date.
+///
+/// The header is patched when the writer is finished, or dropped on a best-effort basis.
+pub struct IvfWriter<W: Write + Seek> {
+    /// Only `None` once the writer has been finished.
+    inner: Option<W>,
+    /// Position of the header of the stream in `inner`.
+    start: u64,
+    header: IvfFileHeader,
+    /// Number of frames after which the header is refreshed, if any.
+    header_refresh_interval: Option<u32>,
+    /// Number of frames written since the last header refresh.
+    frames_since_refresh: u32,
+}
+
+impl<W: Write + Seek> IvfWriter<W> {
+    /// Creates a writer starting a new stream with `header` at the current position of `inner`.
+    /// The frame count of `header` is ignored.
+    pub fn new(mut inner: W, header: IvfFileHeader) -> anyhow::Result<Self> {
+        let start = inner.stream_position()?;
+        let header = IvfFileHeader {
+            num_frames: 0,
+            ..header
+        };
+        inner.write_all(&header.to_bytes())?;
+
+        Ok(Self {
+            inner: Some(inner),
+            start,
+            header,
+            header_refresh_interval: None,
+            frames_since_refresh: 0,
+        })
+    }
+
+    /// Refreshes the frame count of the header every `interval` frames, so an interrupted stream
+    /// only lacks the frames written since the last refresh in its count. `None` or `Some(0)`
+    /// disables the periodic refresh, which is the default.
+    pub fn set_header_refresh_interval(&mut self, interval: Option<u32>) {
+        self.header_refresh_interval = interval.filter(|&interval| interval > 0);
+    }
+
+    /// Returns the header of the stream, with the number of frames written so far.
+    pub fn header(&self) -> &IvfFileHeader {
+        &self.header
+    }
+
+    fn inner(&mut self) -> &mut W {
+        // Cannot fail as `inner` is only taken by `finish`, which consumes the writer.
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Writes a frame with content `data` and timestamp `timestamp`.
+    pub fn write_frame(&mut self, data: &[u8], timestamp: u64) -> anyhow::Result<()> {
+        let len = u32::try_from(data.len())
+            .map_err(|_| anyhow!("frame of {} bytes is too large for IVF", data.len()))?;
+
+        let mut frame_header = [0u8; IVF_FRAME_HEADER_SIZE];
+        LittleEndian::write_u32(&mut frame_header[0..4], len);
+        LittleEndian::write_u64(&mut frame_header[4..12], timestamp);
+
+        let inner = self.inner();
+        inner.write_all(&frame_header)?;
+        inner.write_all(data)?;
+
+        self.header.num_frames += 1;
+        self.frames_since_refresh += 1;
+
+        if let Some(interval) = self.header_refresh_interval {
+            if self.frames_since_refresh >= interval {
+                self.refresh_header()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the current frame count into the header of the stream and flushes it.
+    pub fn refresh_header(&mut self) -> anyhow::Result<()> {
+        let mut num_frames = [0u8; 4];
+        LittleEndian::write_u32(&mut num_frames, self.header.num_frames);
+        let start = self.start;
+
+        let inner = self.inner();
+        let end = inner.stream_position()?;
+        inner.seek(SeekFrom::Start(start + NUM_FRAMES_OFFSET))?;
+        inner.write_all(&num_frames)?;
+        inner.seek(SeekFrom::Start(end))?;
+        inner.flush()?;
+
+        self.frames_since_refresh = 0;
+
+        Ok(())
+    }
+
+    /// Patches the header with the final frame count, and returns the underlying writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.refresh_header()?;
+
+        // Cannot fail for the same reason as in `inner`.
+        Ok(self.inner.take().unwrap())
+    }
+}
+
+impl<W: Read + Write + Seek> IvfWriter<W> {
+    /// Creates a writer appending frames to the stream starting at the current position of
+    /// `inner`.
+    ///
+    /// The frames are counted rather than trusting the header, which may be stale if the stream
+    /// was not finished properly. Writing starts after the last complete frame, so a frame
+    /// truncated by an interruption is overwritten. Returns the writer along with the position of
+    /// the end of the last complete frame, past which the content of `inner` is garbage if it is
+    /// not overwritten (see [`IvfWriter::open_append`] for files).
+    pub fn append(mut inner: W) -> anyhow::Result<(Self, u64)> {
+        let start = inner.stream_position()?;
+
+        let mut header = [0u8; IVF_FILE_HEADER_SIZE];
+        inner
+            .read_exact(&mut header)
+            .context("failed to read IVF header")?;
+        let mut header = IvfFileHeader::parse(&header)?;
+
+        let stream_end = inner.seek(SeekFrom::End(0))?;
+        let mut pos = start + IVF_FILE_HEADER_SIZE as u64;
+        let mut num_frames = 0u32;
+
+        loop {
+            if stream_end - pos < IVF_FRAME_HEADER_SIZE as u64 {
+                break;
+            }
+
+            inner.seek(SeekFrom::Start(pos))?;
+            let mut frame_header = [0u8; IVF_FRAME_HEADER_SIZE];
+            inner.read_exact(&mut frame_header)?;
+            let frame_end = pos
+                + IVF_FRAME_HEADER_SIZE as u64
+                + u64::from(LittleEndian::read_u32(&frame_header));
+            if frame_end > stream_end {
+                break;
+            }
+
+            pos = frame_end;
+            num_frames += 1;
+        }
+
+        inner.seek(SeekFrom::Start(pos))?;
+        header.num_frames = num_frames;
+
+        let mut writer = Self {
+            inner: Some(inner),
+            start,
+            header,
+            header_refresh_interval: None,
+            frames_since_refresh: 0,
+        };
+        // Fix the count right away in case the header was stale.
+        writer.refresh_header()?;
+
+        Ok((writer, pos))
+    }
+}
+
+impl IvfWriter<File> {
+    /// Creates a new IVF file at `path` with `header`, replacing any existing file.
+    pub fn create<P: AsRef<Path>>(path: P, header: IvfFileHeader) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+
+        Self::new(file, header)
+    }
+
+    /// Opens the IVF file at `path` for appending frames, creating it with `header` if it does not
+    /// exist. A truncated last frame, e.g. after a crash, is removed.
+    pub fn open_append<P: AsRef<Path>>(path: P, header: IvfFileHeader) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        if file.metadata()?.len() == 0 {
+            return Self::new(file, header);
+        }
+
+        let (writer, end) = Self::append(file)
+            .with_context(|| format!("failed to append to {}", path.display()))?;
+        // Cannot fail as `append` does not take `inner`.
+        writer.inner.as_ref().unwrap().set_len(end)?;
+
+        Ok(writer)
+    }
+}
+
+impl<W: Write + Seek> Drop for IvfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            if let Err(e) = self.refresh_header() {
+                log::warn!("failed to patch the IVF header: {:#}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::IvfFileHeader;
+    use super::IvfWriter;
+    use super::IVF_FILE_HEADER_SIZE;
+    use crate::utils::IvfIterator;
+
+    #[test]
+    fn write_and_append() {
+        let header = IvfFileHeader::new(*b"VP90", 320, 240, 30);
+
+        let mut writer = IvfWriter::new(Cursor::new(vec![]), header).unwrap();
+        writer.set_header_refresh_interval(Some(2));
+        writer.write_frame(&[1, 2, 3], 0).unwrap();
+        writer.write_frame(&[4], 1).unwrap();
+        writer.write_frame(&[5, 6], 2).unwrap();
+
+        // The header has been refreshed after the second frame only.
+        let data = writer.inner.as_ref().unwrap().get_ref().clone();
+        assert_eq!(IvfFileHeader::parse(&data).unwrap().num_frames, 2);
+
+        let data = writer.finish().unwrap().into_inner();
+        let parsed = IvfFileHeader::parse(&data).unwrap();
+        assert_eq!(
+            parsed,
+            IvfFileHeader {
+                num_frames: 3,
+                ..header
+            }
+        );
+        assert_eq!(&data[..IVF_FILE_HEADER_SIZE], &parsed.to_bytes());
+        assert_eq!(
+            IvfIterator::new(&data).collect::<Vec<_>>(),
+            [&[1, 2, 3][..], &[4], &[5, 6]]
+        );
+
+        // Simulate an interruption in the middle of the last frame, with a stale header.
+        let mut truncated = data[..data.len() - 1].to_vec();
+        truncated[24] = 0;
+
+        let (mut writer, end) = IvfWriter::append(Cursor::new(truncated)).unwrap();
+        assert_eq!(end as usize, data.len() - 14);
+        assert_eq!(writer.header().num_frames, 2);
+        writer.write_frame(&[7, 8, 9, 10], 3).unwrap();
+
+        let data = writer.finish().unwrap().into_inner();
+        assert_eq!(IvfFileHeader::parse(&data).unwrap().num_frames, 3);
+        assert_eq!(
+            IvfIterator::new(&data).collect::<Vec<_>>(),
+            [&[1, 2, 3][..], &[4], &[7, 8, 9, 10]]
+        );
+
+        assert!(IvfWriter::append(Cursor::new(b"DKIF".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Objective quality metrics between two frames.
+//!
+//! This is meant to validate lossy processes, e.g. encoding or format conversion, for which the
+//! output cannot be compared bit-exactly against a reference. PSNR and SSIM are computed
+//! separately for each plane, following the usual conventions of libvpx: PSNR is capped at
+//! [`MAX_PSNR`] for identical planes, and SSIM is computed on 8x8 windows spaced by 4 samples.
+
+use anyhow::anyhow;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use crate::DecodedFormat;
+
+/// PSNR reported for identical planes, in dB.
+pub const MAX_PSNR: f64 = 100.0;
+
+/// Returns the PSNR in dB between the planes `a` and `b`, which must have the same size.
+/// `max_value` is the maximum value of a sample, e.g. 255 for 8-bit samples.
+pub fn psnr<T: Copy + Into<f64>>(a: &[T], b: &[T], max_value: f64) -> f64 {
+    assert_eq!(a.len(), b.len());
+    if a.is_empty() {
+        return MAX_PSNR;
+    }
+
+    let sse = a
+        .iter()
+        .zip(b)
+        .map(|(&a, &b)| (a.into() - b.into()).powi(2))
+        .sum::<f64>();
+    let mse = sse / a.len() as f64;
+
+    if mse == 0.0 {
+        MAX_PSNR
+    } else {
+        (10.0 * (max_value * max_value / mse).log10()).min(MAX_PSNR)
+    }
+}
+
+/// Returns the SSIM of the window of `w`x`h` samples starting at `(x, y)`.
+fn window_ssim<T: Copy + Into<f64>>(
+    a: &[T],
+    b: &[T],
+    stride: usize,
+    (x, y): (usize, usize),
+    (w, h): (usize, usize),
+    max_value: f64,
+) -> f64 {
+    let c1 = (0.01 * max_value).powi(2);
+    let c2 = (0.03 * max_value).powi(2);
+
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for line in y..y + h {
+        let start = line * stride + x;
+        for (&a, &b) in a[start..start + w].iter().zip(&b[start..start + w]) {
+            let (a, b): (f64, f64) = (a.into(), b.into());
+            sum_a += a;
+            sum_b += b;
+            sum_aa += a * a;
+            sum_bb += b * b;
+            sum_ab += a * b;
+        }
+    }
+
+    let n = (w * h) as f64;
+    let (mu_a, mu_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mu_a * mu_a;
+    let var_b = sum_bb / n - mu_b * mu_b;
+    let cov = sum_ab / n - mu_a * mu_b;
+
+    ((2.0 * mu_a * mu_b + c1) * (2.0 * cov + c2))
+        / ((mu_a * mu_a + mu_b * mu_b + c1) * (var_a + var_b + c2))
+}
+
+/// Returns the SSIM between the planes `a` and `b` of size `width`x`height`, without padding.
+/// `max_value` is the maximum value of a sample, e.g. 255 for 8-bit samples.
+pub fn ssim<T: Copy + Into<f64>>(
+    a: &[T],
+    b: &[T],
+    width: usize,
+    height: usize,
+    max_value: f64,
+) -> f64 {
+    const WINDOW_SIZE: usize = 8;
+    const WINDOW_STEP: usize = 4;
+
+    assert!(a.len() >= width * height && b.len() >= width * height);
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+
+    // Planes smaller than a window are covered by a single smaller window.
+    let (w, h) = (width.min(WINDOW_SIZE), height.min(WINDOW_SIZE));
+    let positions = |size: usize, window: usize| (0..=size - window).step_by(WINDOW_STEP);
+
+    let mut sum = 0.0;
+    let mut num_windows = 0;
+    for y in positions(height, h) {
+        for x in positions(width, w) {
+            sum += window_ssim(a, b, width, (x, y), (w, h), max_value);
+            num_windows += 1;
+        }
+    }
+
+    sum / num_windows as f64
+}
+
+/// Quality metrics of each plane of a frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameMetrics {
+    /// PSNR in dB of the Y, U and V planes.
+    pub psnr: [f64; 3],
+    /// SSIM of the Y, U and V planes.
+    pub ssim: [f64; 3],
+}
+
+/// A plane of a frame, with its samples extracted.
+struct Plane {
+    samples: Vec<u16>,
+    width: usize,
+    height: usize,
+}
+
+/// Splits `data`, a frame of `format` laid out as described by [`crate::decoded_frame_layout`],
+/// into its Y, U and V planes. Also returns the maximum value of a sample.
+fn split_planes(
+    format: DecodedFormat,
+    data: &[u8],
+    width: usize,
+    height: usize,
+) -> anyhow::Result<([Plane; 3], f64)> {
+    let frame_size = crate::decoded_frame_size(format, width, height);
+    if data.len() < frame_size {
+        return Err(anyhow!(
+            "{:?} frame of size {}x{} is {} bytes, but only {} were provided",
+            format,
+            width,
+            height,
+            frame_size,
+            data.len()
+        ));
+    }
+
+    let (bytes_per_sample, bit_depth, shift) = match format {
+        DecodedFormat::I420 | DecodedFormat::NV12 | DecodedFormat::I422 | DecodedFormat::I444 => {
+            (1, 8, 0)
+        }
+        // The 10 useful bits are the MSBs.
+        DecodedFormat::P010 => (2, 10, 6),
+        DecodedFormat::I010 | DecodedFormat::I210 | DecodedFormat::I410 => (2, 10, 0),
+        DecodedFormat::I012 | DecodedFormat::I212 | DecodedFormat::I412 => (2, 12, 0),
+    };
+
+    let layout = crate::decoded_frame_layout(format, width, height);
+    let mut planes = layout.planes.iter().map(|plane| {
+        let data = &data[plane.offset..plane.offset + plane.size];
+        let samples: Vec<u16> = match bytes_per_sample {
+            1 => data.iter().map(|&s| u16::from(s)).collect(),
+            _ => data
+                .chunks_exact(2)
+                .map(|s| LittleEndian::read_u16(s) >> shift)
+                .collect(),
+        };
+
+        Plane {
+            width: plane.stride / bytes_per_sample,
+            height: plane.size.checked_div(plane.stride).unwrap_or(0),
+            samples,
+        }
+    });
+
+    let y = planes.next().unwrap();
+    let u = planes.next().unwrap();
+    let planes = match planes.next() {
+        Some(v) => [y, u, v],
+        // Split the interleaved UV plane.
+        None => {
+            let deinterleave = |start: usize| Plane {
+                samples: u.samples.iter().skip(start).step_by(2).copied().collect(),
+                width: u.width / 2,
+                height: u.height,
+            };
+
+            [y, deinterleave(0), deinterleave(1)]
+        }
+    };
+
+    Ok((planes, f64::from((1u32 << bit_depth) - 1)))
+}
+
+/// Returns the quality metrics of `frame` against the reference frame `reference`, both of
+/// `format` and size `width`x`height` and laid out as described by
+/// [`crate::decoded_frame_layout`].
+pub fn frame_metrics(
+    format: DecodedFormat,
+    reference: &[u8],
+    frame: &[u8],
+    width: usize,
+    height: usize,
+) -> anyhow::Result<FrameMetrics> {
+    let (ref_planes, max_value) = split_planes(format, reference, width, height)?;
+    let (planes, _) = split_planes(format, frame, width, height)?;
+
+    let mut metrics = FrameMetrics::default();
+    for (i, (a, b)) in ref_planes.iter().zip(&planes).enumerate() {
+        metrics.psnr[i] = psnr(&a.samples, &b.samples, max_value);
+        metrics.ssim[i] = ssim(&a.samples, &b.samples, a.width, a.height, max_value);
+    }
+
+    Ok(metrics)
+}
+
+/// Averages the quality metrics of the frames of a stream.
+#[derive(Debug, Clone, Default)]
+pub struct StreamMetrics {
+    sum: FrameMetrics,
+    num_frames: usize,
+}
+
+impl StreamMetrics {
+    /// Adds the metrics of a new frame.
+    pub fn add_frame(&mut self, metrics: &FrameMetrics) {
+        for i in 0..3 {
+            self.sum.psnr[i] += metrics.psnr[i];
+            self.sum.ssim[i] += metrics.ssim[i];
+        }
+        self.num_frames += 1;
+    }
+
+    /// Computes the metrics of `frame` against `reference` and adds them, returning them.
+    ///
+    /// See [`frame_metrics`] for the meaning of the arguments.
+    pub fn compare_frames(
+        &mut self,
+        format: DecodedFormat,
+        reference: &[u8],
+        frame: &[u8],
+        width: usize,
+        height: usize,
+    ) -> anyhow::Result<FrameMetrics> {
+        let metrics = frame_metrics(format, reference, frame, width, height)?;
+        self.add_frame(&metrics);
+
+        Ok(metrics)
+    }
+
+    /// Returns the number of frames added so far.
+    pub fn num_frames(&self) -> usize {
+        self.num_frames
+    }
+
+    /// Returns the average metrics of all the frames added so far, or `None` if there are none.
+    pub fn average(&self) -> Option<FrameMetrics> {
+        if self.num_frames == 0 {
+            return None;
+        }
+
+        let n = self.num_frames as f64;
+        Some(FrameMetrics {
+            psnr: self.sum.psnr.map(|psnr| psnr / n),
+            ssim: self.sum.ssim.map(|ssim| ssim / n),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::frame_metrics;
+    use super::psnr;
+    use super::ssim;
+    use super::StreamMetrics;
+    use super::MAX_PSNR;
+    use crate::DecodedFormat;
+
+    const EPSILON: f64 = 1e-6;
+
+    /// 16x16 plane with a non-trivial pattern.
+    fn pattern() -> Vec<u8> {
+        (0..256u32)
+            .map(|i| ((i * 37 + i / 16 * 11) % 256) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn psnr_reference_values() {
+        // An error of 1 on every sample is a MSE of 1.
+        assert!((psnr(&[0u8; 64], &[1u8; 64], 255.0) - 48.130803608679).abs() < EPSILON);
+        assert!((psnr(&[0u16; 64], &[4u16; 64], 1023.0) - 48.156312847684).abs() < EPSILON);
+        assert_eq!(psnr(&pattern(), &pattern(), 255.0), MAX_PSNR);
+    }
+
+    #[test]
+    fn ssim_reference_values() {
+        let a = pattern();
+        assert!((ssim(&a, &a, 16, 16, 255.0) - 1.0).abs() < EPSILON);
+
+        // Flat planes, for which SSIM only depends on the luminance term.
+        assert!((ssim(&[100u8; 64], &[110u8; 64], 8, 8, 255.0) - 0.995476444092).abs() < EPSILON);
+
+        // Reference value computed with an independent implementation of the same windowing.
+        let b: Vec<u8> = a
+            .iter()
+            .enumerate()
+            .map(|(i, &s)| s.saturating_add((i % 7) as u8 * 3))
+            .collect();
+        assert!((ssim(&a, &b, 16, 16, 255.0) - 0.994226022442).abs() < EPSILON);
+    }
+
+    #[test]
+    fn frame_metrics_are_per_plane() {
+        // 4x2 I420 frame, and the same frame with an error of 2 on the U samples.
+        let reference = [10u8, 20, 30, 40, 50, 60, 70, 80, 100, 110, 200, 210];
+        let mut frame = reference;
+        frame[8] += 2;
+        frame[9] += 2;
+
+        let metrics = frame_metrics(DecodedFormat::I420, &reference, &frame, 4, 2).unwrap();
+        assert_eq!(metrics.psnr[0], MAX_PSNR);
+        assert!((metrics.psnr[1] - 42.110203695399).abs() < EPSILON);
+        assert_eq!(metrics.psnr[2], MAX_PSNR);
+
+        // Same frames as NV12.
+        let nv12_reference = [10u8, 20, 30, 40, 50, 60, 70, 80, 100, 200, 110, 210];
+        let nv12_frame = [10u8, 20, 30, 40, 50, 60, 70, 80, 102, 200, 112, 210];
+        let nv12_metrics =
+            frame_metrics(DecodedFormat::NV12, &nv12_reference, &nv12_frame, 4, 2).unwrap();
+        assert_eq!(nv12_metrics, metrics);
+
+        let mut stream = StreamMetrics::default();
+        assert_eq!(stream.average(), None);
+        stream.add_frame(&metrics);
+        stream
+            .compare_frames(DecodedFormat::I420, &reference, &reference, 4, 2)
+            .unwrap();
+        assert_eq!(stream.num_frames(), 2);
+        let average = stream.average().unwrap();
+        assert!((average.psnr[1] - (42.110203695399 + MAX_PSNR) / 2.0).abs() < EPSILON);
+
+        assert!(frame_metrics(DecodedFormat::I420, &reference[..11], &frame, 4, 2).is_err());
+    }
+}